@@ -2,7 +2,7 @@ use camino::Utf8PathBuf;
 use serde::{Deserialize, Serialize};
 
 /// HashiCorp Consul client configuration
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct Config {
     /// Vault address
     #[serde(default = "default_consul_address")]
@@ -18,7 +18,7 @@ fn default_consul_address() -> String {
 }
 
 /// HashiCorp Consul client's TLS configuration
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct TlsConfig {
     /// CA (Certificate Authority) file
     pub ca_file: Utf8PathBuf,
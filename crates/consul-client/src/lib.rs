@@ -102,6 +102,11 @@ impl Client {
         self.request("/v1/agent/checks").await
     }
 
+    /// The local agent's own configuration
+    pub async fn agent_self(&self) -> ConsulResult<AgentSelf> {
+        self.request("/v1/agent/self").await
+    }
+
     async fn request<P: Display, T: DeserializeOwned>(&self, path: P) -> ConsulResult<T> {
         let res = match self
             .client
@@ -142,6 +147,20 @@ pub enum Error {
     Webpki(#[from] webpki::Error),
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub struct AgentSelf {
+    pub config: AgentConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "PascalCase"))]
+pub struct AgentConfig {
+    pub node_name: String,
+    #[serde(default)]
+    pub datacenter: String,
+}
+
 #[derive(Debug, Clone, Hash, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "PascalCase"))]
 pub struct AgentService {
@@ -0,0 +1,215 @@
+//! Consistent snapshots of the database, along w/ a manifest recording the
+//! replication watermark they were taken at.
+//!
+//! Snapshots are made generic: the snapshotted node's site id is demoted to a
+//! regular (non-local) site, so a node restoring it either gets a fresh site
+//! id or explicitly re-adopts the recorded one. Either way, the bookkeeping
+//! within the snapshot tells the restored node which versions it already has,
+//! so it only syncs changes newer than the watermark.
+
+use camino::{Utf8Path, Utf8PathBuf};
+use corro_types::{actor::ActorId, sqlite::SqlitePoolError};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("could not (de)serialize backup manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("snapshot does not match its manifest: {0}")]
+    Mismatch(String),
+}
+
+/// Written next to a snapshot, as `<snapshot>.manifest.json`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Site id of the node the snapshot was taken from
+    pub site_id: ActorId,
+    /// Highest db_version contained in the snapshot
+    pub db_version: i64,
+}
+
+pub fn manifest_path(snapshot: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{snapshot}.manifest.json"))
+}
+
+/// Snapshots the database `conn` is opened on into `path` and writes its
+/// manifest alongside it.
+///
+/// `VACUUM INTO` reads from a single transaction, so the snapshot is
+/// consistent even while writes are happening.
+pub fn backup(conn: &Connection, path: &Utf8Path) -> Result<BackupManifest, BackupError> {
+    // make sure parent path exists
+    if let Some(parent) = path.parent() {
+        if !parent.as_str().is_empty() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+
+    conn.execute("VACUUM INTO ?;", [path.as_str()])?;
+
+    let manifest = {
+        let conn = Connection::open(path)?;
+
+        let site_id: [u8; 16] = conn.query_row(
+            "DELETE FROM crsql_site_id WHERE ordinal = 0 RETURNING site_id;",
+            [],
+            |row| row.get(0),
+        )?;
+
+        let ordinal: i64 = conn.query_row(
+            "INSERT INTO crsql_site_id (site_id) VALUES (?) RETURNING ordinal;",
+            [&site_id],
+            |row| row.get(0),
+        )?;
+
+        for table in clock_tables(&conn)? {
+            let n = conn.execute(
+                &format!("UPDATE \"{table}\" SET site_id = ? WHERE site_id IS NULL"),
+                [ordinal],
+            )?;
+            debug!("updated {n} rows in {table}");
+        }
+
+        // clear __corro_members, this state is per actor
+        conn.execute("DELETE FROM __corro_members;", [])?;
+
+        conn.execute_batch(
+            r#"
+            PRAGMA journal_mode = WAL; -- so the restore can be done online
+            PRAGMA wal_checkpoint(TRUNCATE);
+            "#,
+        )?;
+
+        BackupManifest {
+            site_id: ActorId::from_bytes(site_id),
+            db_version: max_db_version(&conn)?,
+        }
+    };
+
+    std::fs::write(manifest_path(path), serde_json::to_vec_pretty(&manifest)?)?;
+
+    Ok(manifest)
+}
+
+/// Reads the manifest of the snapshot at `path`, if there's one
+pub fn read_manifest(path: &Utf8Path) -> Result<Option<BackupManifest>, BackupError> {
+    match std::fs::read(manifest_path(path)) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks the snapshot `conn` is opened on is the one `manifest` describes
+pub fn validate(conn: &Connection, manifest: &BackupManifest) -> Result<(), BackupError> {
+    let ordinal: Option<i64> = conn
+        .query_row(
+            "SELECT ordinal FROM crsql_site_id WHERE site_id = ?",
+            [manifest.site_id.as_bytes()],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    match ordinal {
+        None => {
+            return Err(BackupError::Mismatch(format!(
+                "site id {} is unknown to the snapshot",
+                manifest.site_id
+            )))
+        }
+        Some(0) => {
+            return Err(BackupError::Mismatch(
+                "snapshot still has a local site id, it wasn't made by `corrosion backup`".into(),
+            ))
+        }
+        Some(_) => {}
+    }
+
+    let db_version = max_db_version(conn)?;
+    if db_version != manifest.db_version {
+        return Err(BackupError::Mismatch(format!(
+            "expected db_version {}, snapshot is at {db_version}",
+            manifest.db_version
+        )));
+    }
+
+    Ok(())
+}
+
+/// Local site id of the database `conn` is opened on, if it has one
+pub fn local_site_id(conn: &Connection) -> rusqlite::Result<Option<ActorId>> {
+    conn.query_row(
+        "SELECT site_id FROM crsql_site_id WHERE ordinal = 0;",
+        [],
+        |row| row.get::<_, [u8; 16]>(0),
+    )
+    .optional()
+    .map(|site_id| site_id.map(ActorId::from_bytes))
+}
+
+/// Makes `site_id` the local site of the snapshot `conn` is opened on,
+/// attributing the changes it made to the local site again.
+pub fn adopt_site_id(conn: &Connection, site_id: ActorId) -> Result<(), BackupError> {
+    let ordinal: Option<i64> = conn
+        .query_row(
+            "DELETE FROM crsql_site_id WHERE site_id = ? RETURNING ordinal",
+            [site_id.as_bytes()],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if ordinal.is_none() {
+        tracing::warn!("snapshot database did not know about site id {site_id}");
+    }
+
+    let inserted = conn.execute(
+        "INSERT INTO crsql_site_id (ordinal, site_id) VALUES (0, ?)",
+        [site_id.as_bytes()],
+    )?;
+    if inserted != 1 {
+        return Err(BackupError::Mismatch(
+            "could not insert site id into crsql_site_id table".into(),
+        ));
+    }
+
+    if let Some(ordinal) = ordinal {
+        for table in clock_tables(conn)? {
+            let n = conn.execute(
+                &format!("UPDATE \"{table}\" SET site_id = NULL WHERE site_id = ?"),
+                [ordinal],
+            )?;
+            debug!("updated {n} rows in {table}");
+        }
+    }
+
+    Ok(())
+}
+
+fn clock_tables(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    conn.prepare(
+        "SELECT name FROM sqlite_schema WHERE type = 'table' AND name LIKE '%__crsql_clock'",
+    )?
+    .query_map([], |row| row.get(0))?
+    .collect()
+}
+
+// same as `crsql_db_version()`, w/o needing the extension loaded
+fn max_db_version(conn: &Connection) -> rusqlite::Result<i64> {
+    let mut max = 0;
+    for table in clock_tables(conn)? {
+        let db_version: Option<i64> = conn.query_row(
+            &format!("SELECT MAX(db_version) FROM \"{table}\""),
+            [],
+            |row| row.get(0),
+        )?;
+        max = max.max(db_version.unwrap_or(0));
+    }
+    Ok(max)
+}
@@ -4,7 +4,6 @@ use camino::Utf8PathBuf;
 use corro_types::{
     agent::{Agent, LockKind, LockMeta, LockState},
     broadcast::{FocaCmd, FocaInput},
-    sqlite::SqlitePoolError,
     sync::generate_sync,
 };
 use futures::{SinkExt, TryStreamExt};
@@ -20,6 +19,8 @@ use tokio_util::codec::LengthDelimitedCodec;
 use tracing::{debug, error, info, warn};
 use tripwire::Tripwire;
 
+pub mod backup;
+
 #[derive(Debug, thiserror::Error)]
 pub enum AdminError {
     #[error(transparent)]
@@ -85,6 +86,7 @@ pub enum Command {
     Sync(SyncCommand),
     Locks { top: usize },
     Cluster(ClusterCommand),
+    Backup { path: Utf8PathBuf },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -226,6 +228,22 @@ async fn handle_conn(
                     }
                     send_success(&mut stream).await;
                 }
+                Command::Backup { path } => {
+                    info_log(&mut stream, format!("backing up database to {path}")).await;
+
+                    let res = match agent.pool().read().await {
+                        Ok(conn) => tokio::task::block_in_place(|| backup::backup(&conn, &path)),
+                        Err(e) => Err(e.into()),
+                    };
+
+                    match res.and_then(|manifest| Ok(serde_json::to_value(manifest)?)) {
+                        Ok(json) => {
+                            send(&mut stream, Response::Json(json)).await;
+                            send_success(&mut stream).await;
+                        }
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
             },
             Ok(None) => {
                 debug!("done with admin conn");
@@ -242,14 +260,6 @@ async fn handle_conn(
     Ok(())
 }
 
-#[derive(Debug, thiserror::Error)]
-pub enum BackupError {
-    #[error(transparent)]
-    Pool(#[from] SqlitePoolError),
-    #[error(transparent)]
-    Sqlite(#[from] rusqlite::Error),
-}
-
 async fn send(stream: &mut FramedStream, res: Response) {
     if let Err(e) = stream.send(res).await {
         warn!("could not send response=: {e}");
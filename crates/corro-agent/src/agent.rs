@@ -5,18 +5,30 @@ use std::{
     net::SocketAddr,
     ops::RangeInclusive,
     sync::{atomic::AtomicI64, Arc},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
     api::{
         peer::{gossip_server_endpoint, parallel_sync, serve_sync, SyncError},
         public::{
-            api_v1_db_schema, api_v1_queries, api_v1_transactions,
+            api_v1_active_transactions, api_v1_config, api_v1_db_schema, api_v1_exec_body,
+            api_v1_explain, api_v1_kill_transaction, api_v1_queries, api_v1_queries_multi,
+            api_v1_quotas, api_v1_register_query, api_v1_schema, api_v1_schema_version,
+            authz::authorize_policy,
+            checkpoint::{api_v1_db_checkpoint, checkpoint_loop},
+            digest::api_v1_digests,
+            health::{api_v1_health, health_loop},
+            members::api_v1_cluster_members,
+            migrations::{api_v1_migrations_applied, api_v1_migrations_apply},
+            node_names::node_names_loop,
+            probe::{api_v1_cluster_latency, probe_loop},
             pubsub::{
-                api_v1_sub_by_id, api_v1_subs, process_sub_channel, MatcherBroadcastCache,
-                MatcherIdCache,
+                api_v1_sub_by_id, api_v1_sub_rebind, api_v1_subs, process_sub_channel,
+                MatcherBroadcastCache, MatcherIdCache, SharedMatcherIdCache,
             },
+            sampling::{error_summaries_loop, sample_query_errors},
+            usage::{api_v1_usage, usage_loop},
         },
     },
     broadcast::runtime_loop,
@@ -30,14 +42,18 @@ use corro_types::{
         Agent, AgentConfig, BookedVersions, Bookie, ChangeError, KnownDbVersion, PartialVersion,
         SplitPool,
     },
+    api::{sqlite::ChangeKindCounts, ApplyReport, TableName, SCHEMA_VERSION_HEADER},
     broadcast::{
         BiPayload, BiPayloadV1, BroadcastInput, BroadcastV1, ChangeSource, ChangeV1, Changeset,
         ChangesetParts, FocaInput, Timestamp, UniPayload, UniPayloadV1,
     },
     change::Change,
     config::{AuthzConfig, Config, DEFAULT_GOSSIP_PORT},
+    history,
     members::{MemberEvent, Members, Rtt},
-    pubsub::{migrate_subs, Matcher},
+    node_names::NODE_NAMES_TABLE,
+    probe::PROBE_TABLE,
+    pubsub::{migrate_subs, Matcher, TableChanges},
     schema::init_schema,
     sqlite::{CrConn, Migration, SqlitePoolError},
     sync::{generate_sync, SyncMessageDecodeError, SyncMessageEncodeError},
@@ -47,13 +63,14 @@ use axum::{
     error_handling::HandleErrorLayer,
     extract::DefaultBodyLimit,
     headers::{authorization::Bearer, Authorization},
-    routing::{get, post},
+    response::IntoResponse,
+    routing::{get, post, put},
     BoxError, Extension, Router, TypedHeader,
 };
 use bytes::Bytes;
 use foca::{Member, Notification};
 use futures::{FutureExt, StreamExt};
-use hyper::{server::conn::AddrIncoming, StatusCode};
+use hyper::{header::HeaderValue, server::conn::AddrIncoming, StatusCode};
 use itertools::Itertools;
 use metrics::{counter, gauge, histogram, increment_counter};
 use parking_lot::RwLock;
@@ -123,13 +140,24 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
         tokio::fs::create_dir_all(parent).await?;
     }
 
-    let pool = SplitPool::create(&conf.db.path, &subscriptions_db_path, tripwire.clone()).await?;
+    let pool = SplitPool::create(
+        &conf.db.path,
+        &subscriptions_db_path,
+        conf.db.read_pool_size,
+        tripwire.clone(),
+    )
+    .await?;
 
     let schema = {
         let mut conn = pool.write_priority().await?;
         migrate(&mut conn)?;
         let mut schema = init_schema(&conn)?;
         schema.constrain()?;
+
+        let tx = conn.transaction()?;
+        history::reconcile(&tx, &schema, conf.db.history_retention_secs.is_some())?;
+        tx.commit()?;
+
         schema
     };
 
@@ -307,13 +335,36 @@ pub async fn start(conf: Config, tripwire: Tripwire) -> eyre::Result<Agent> {
     Ok(agent)
 }
 
+// on top of the drain timeout, for the last events of ended streams
+const API_DRAIN_GRACE: Duration = Duration::from_secs(1);
+
+/// Waits for the API server to stop once tripped: it stops accepting
+/// connections right away, and waits for in-flight requests. Those still
+/// going after `drain_timeout` are cut.
+async fn drain_api<F>(server: F, tripwire: Tripwire, drain_timeout: Duration)
+where
+    F: std::future::Future<Output = hyper::Result<()>>,
+{
+    let deadline = tripwire.then(move |_| tokio::time::sleep(drain_timeout + API_DRAIN_GRACE));
+    tokio::select! {
+        res = server => {
+            if let Err(e) = res {
+                error!("api server failed: {e}");
+            }
+        }
+        _ = deadline => {
+            warn!("api connections did not drain within {drain_timeout:?}, closing them");
+        }
+    }
+}
+
 pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
     let AgentOptions {
         actor_id,
         gossip_server_endpoint,
         transport,
         api_listener,
-        mut tripwire,
+        tripwire,
         rx_bcast,
         rx_apply,
         rx_empty,
@@ -361,7 +412,14 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
         for (id, sql) in rows {
             let conn = block_in_place(|| agent.pool().dedicated())?;
             let (evt_tx, evt_rx) = channel(512);
-            match Matcher::restore(id, &agent.schema().read(), conn, evt_tx, &sql) {
+            match Matcher::restore(
+                id,
+                &agent.schema().read(),
+                conn,
+                evt_tx,
+                &sql,
+                agent.config().db.subscription_changes,
+            ) {
                 Ok(handle) => {
                     agent.matchers().write().insert(id, handle);
                     let (sub_tx, _) = tokio::sync::broadcast::channel(10240);
@@ -631,13 +689,22 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
                                                                     BiPayloadV1::SyncStart {
                                                                         actor_id,
                                                                         trace_ctx,
+                                                                        accepts_compression,
+                                                                        accepts_change_ts,
+                                                                        accepts_checksums,
                                                                     },
                                                                 ) => {
                                                                     trace!("framed read buffer len: {}", framed.read_buffer().len());
                                                                     // println!("got sync state: {state:?}");
                                                                     if let Err(e) = serve_sync(
-                                                                        &agent, actor_id,
-                                                                        trace_ctx, framed, tx,
+                                                                        &agent,
+                                                                        actor_id,
+                                                                        trace_ctx,
+                                                                        accepts_compression,
+                                                                        accepts_change_ts,
+                                                                        accepts_checksums,
+                                                                        framed,
+                                                                        tx,
                                                                     )
                                                                     .await
                                                                     {
@@ -797,7 +864,7 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
     let api = Router::new()
         .route(
             "/v1/transactions",
-            post(api_v1_transactions).route_layer(
+            post(api_v1_exec_body).route_layer(
                 tower::ServiceBuilder::new()
                     .layer(HandleErrorLayer::new(|_error: BoxError| async {
                         Ok::<_, Infallible>((
@@ -809,6 +876,48 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
                     .layer(ConcurrencyLimitLayer::new(128)),
             ),
         )
+        .route(
+            "/v1/transactions/active",
+            get(api_v1_active_transactions).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/quotas",
+            get(api_v1_quotas).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/transactions/active/:id/kill",
+            post(api_v1_kill_transaction).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
         .route(
             "/v1/queries",
             post(api_v1_queries).route_layer(
@@ -823,6 +932,34 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
                     .layer(ConcurrencyLimitLayer::new(128)),
             ),
         )
+        .route(
+            "/v1/queries/multi",
+            post(api_v1_queries_multi).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v1/queries/registered/:name",
+            put(api_v1_register_query).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
         .route(
             "/v1/subscriptions",
             post(api_v1_subs).route_layer(
@@ -851,6 +988,20 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
                     .layer(ConcurrencyLimitLayer::new(128)),
             ),
         )
+        .route(
+            "/v1/subscriptions/:id/rebind",
+            post(api_v1_sub_rebind).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
         .route(
             "/v1/migrations",
             post(api_v1_db_schema).route_layer(
@@ -865,6 +1016,176 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
                     .layer(ConcurrencyLimitLayer::new(4)),
             ),
         )
+        .route(
+            "/v1/migrations/apply",
+            post(api_v1_migrations_apply).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/migrations/applied",
+            get(api_v1_migrations_applied).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/explain",
+            post(api_v1_explain).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v1/health",
+            get(api_v1_health).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/db/checkpoint",
+            post(api_v1_db_checkpoint).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(2)),
+            ),
+        )
+        .route(
+            "/v1/cluster/latency",
+            get(api_v1_cluster_latency).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/cluster/members",
+            get(api_v1_cluster_members).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/schema",
+            get(api_v1_schema).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .route(
+            "/v1/config",
+            get(api_v1_config).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/usage",
+            get(api_v1_usage).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/digests",
+            post(api_v1_digests).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(2)),
+            ),
+        )
+        .route(
+            "/v1/schema/version",
+            get(api_v1_schema_version).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(16)),
+            ),
+        )
+        .layer(axum::middleware::from_fn(sample_query_errors))
+        .layer(axum::middleware::from_fn(schema_version_header))
         .layer(axum::middleware::from_fn(require_authz))
         .layer(
             tower::ServiceBuilder::new()
@@ -879,19 +1200,24 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
 
     let api_addr = api_listener.local_addr()?;
     info!("Starting public API server on tcp/{api_addr}");
+    let api_server = axum::Server::builder(AddrIncoming::from_listener(api_listener)?)
+        .executor(CountedExecutor)
+        .serve(
+            api.clone()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(
+            tripwire
+                .clone()
+                .inspect(move |_| info!("corrosion api http tripped {api_addr}")),
+        );
     spawn_counted(
-        axum::Server::builder(AddrIncoming::from_listener(api_listener)?)
-            .executor(CountedExecutor)
-            .serve(
-                api.clone()
-                    .into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .with_graceful_shutdown(
-                tripwire
-                    .clone()
-                    .inspect(move |_| info!("corrosion api http tripped {api_addr}")),
-            )
-            .inspect(|_| info!("corrosion api is done")),
+        drain_api(
+            api_server,
+            tripwire.clone(),
+            Duration::from_secs(agent.config().api.drain_timeout_secs),
+        )
+        .inspect(|_| info!("corrosion api is done")),
     );
 
     spawn_counted(handle_changes(agent.clone(), rx_changes, tripwire.clone()));
@@ -907,8 +1233,6 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
         .inspect(|_| info!("corrosion agent sync loop is done")),
     );
 
-    let mut db_cleanup_interval = tokio::time::interval(Duration::from_secs(60 * 15));
-
     tokio::spawn(handle_gossip_to_send(transport.clone(), to_send_rx));
     tokio::spawn(handle_notifications(
         agent.clone(),
@@ -916,35 +1240,56 @@ pub async fn run(agent: Agent, opts: AgentOptions) -> eyre::Result<()> {
         member_events_tx,
     ));
     tokio::spawn(metrics_loop(agent.clone(), transport));
+    tokio::spawn(health_loop(agent.clone(), tripwire.clone()));
+    tokio::spawn(checkpoint_loop(agent.clone(), tripwire.clone()));
+    if let Some(probe) = agent.config().gossip.probe {
+        tokio::spawn(probe_loop(agent.clone(), probe, tripwire.clone()));
+    }
+    tokio::spawn(node_names_loop(agent.clone(), tripwire.clone()));
+    tokio::spawn(error_summaries_loop(agent.clone(), tripwire.clone()));
+    // flushes once more on shutdown
+    spawn_counted(usage_loop(agent.clone(), tripwire.clone()));
+    if let Some(retention_secs) = agent.config().db.history_retention_secs {
+        tokio::spawn(prune_history_loop(
+            agent.pool().clone(),
+            retention_secs,
+            tripwire.clone(),
+        ));
+    }
 
     tokio::spawn(handle_broadcasts(agent.clone(), bcast_rx));
 
-    loop {
-        tokio::select! {
-            biased;
-            _ = db_cleanup_interval.tick() => {
-                tokio::spawn(handle_db_cleanup(agent.pool().clone()).preemptible(tripwire.clone()));
-            },
-            _ = &mut tripwire => {
-                debug!("tripped corrosion");
-                break;
-            }
-        }
-    }
+    tripwire.await;
+    debug!("tripped corrosion");
 
     Ok(())
 }
 
-async fn require_authz<B>(
+// tokens w/ an access policy are restricted to it, others must match
+// `api.authorization` if set
+async fn require_authz(
     Extension(agent): Extension<Agent>,
+    Extension(sub_cache): Extension<SharedMatcherIdCache>,
     maybe_authz_header: Option<TypedHeader<Authorization<Bearer>>>,
-    request: axum::http::Request<B>,
-    next: axum::middleware::Next<B>,
-) -> Result<axum::response::Response, axum::http::StatusCode> {
-    let passed = if let Some(ref authz) = agent.config().api.authorization {
+    request: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next<axum::body::Body>,
+) -> axum::response::Response {
+    let config = agent.config();
+
+    if let Some(policy) = maybe_authz_header
+        .as_ref()
+        .and_then(|h| config.api.policy(h.token()))
+    {
+        return match authorize_policy(&agent, &sub_cache, policy, request).await {
+            Ok(request) => next.run(request).await,
+            Err(res) => res,
+        };
+    }
+
+    let passed = if let Some(ref authz) = config.api.authorization {
         match authz {
             AuthzConfig::BearerToken(token) => maybe_authz_header
-                .map(|h| h.token() == token)
+                .map(|h| h.token() == token.expose())
                 .unwrap_or(false),
         }
     } else {
@@ -952,10 +1297,25 @@ async fn require_authz<B>(
     };
 
     if !passed {
-        return Err(axum::http::StatusCode::UNAUTHORIZED);
+        return axum::http::StatusCode::UNAUTHORIZED.into_response();
     }
 
-    Ok(next.run(request).await)
+    next.run(request).await
+}
+
+/// Sets the schema version header on every response, w/ the version after
+/// the request was handled
+async fn schema_version_header(
+    Extension(agent): Extension<Agent>,
+    request: axum::http::Request<axum::body::Body>,
+    next: axum::middleware::Next<axum::body::Body>,
+) -> axum::response::Response {
+    let mut res = next.run(request).await;
+    res.headers_mut().insert(
+        SCHEMA_VERSION_HEADER,
+        HeaderValue::from(agent.schema_version()),
+    );
+    res
 }
 
 async fn clear_overwritten_versions(agent: Agent) {
@@ -1100,6 +1460,17 @@ fn collect_metrics(agent: &Agent, transport: &Transport) {
     agent.pool().emit_metrics();
     transport.emit_metrics();
 
+    let (active, paused) = {
+        let matchers = agent.matchers().read();
+        let paused = matchers
+            .values()
+            .filter(|matcher| matcher.is_paused())
+            .count();
+        (matchers.len() - paused, paused)
+    };
+    gauge!("corro.subs.active", active as f64);
+    gauge!("corro.subs.paused", paused as f64);
+
     let schema = agent.schema().read();
 
     let conn = match agent.pool().read_blocking() {
@@ -1367,28 +1738,39 @@ async fn handle_notifications(
     }
 }
 
-async fn handle_db_cleanup(pool: SplitPool) -> eyre::Result<()> {
-    debug!("handling db_cleanup (WAL truncation)");
-    let conn = pool.write_low().await?;
-    block_in_place(move || {
-        let start = Instant::now();
+async fn prune_history_loop(pool: SplitPool, retention_secs: u64, mut tripwire: Tripwire) {
+    let mut prune_interval = tokio::time::interval(Duration::from_secs(60));
 
-        let busy: bool =
-            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| row.get(0))?;
-        if busy {
-            warn!("could not truncate sqlite WAL, database busy");
-            increment_counter!("corro.db.wal.truncate.busy");
-        } else {
-            debug!("successfully truncated sqlite WAL!");
-            histogram!(
-                "corro.db.wal.truncate.seconds",
-                start.elapsed().as_secs_f64()
-            );
+    loop {
+        tokio::select! {
+            _ = prune_interval.tick() => {},
+            _ = &mut tripwire => break,
+        }
+
+        if let Err(e) = prune_history(&pool, retention_secs).await {
+            error!("could not prune change history: {e}");
+        }
+    }
+}
+
+async fn prune_history(pool: &SplitPool, retention_secs: u64) -> eyre::Result<()> {
+    let before_ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .saturating_sub(retention_secs);
+
+    let mut conn = pool.write_low().await?;
+    block_in_place(|| {
+        let tx = conn.transaction()?;
+        let pruned = history::prune(&tx, before_ts as i64)?;
+        tx.commit()?;
+
+        if pruned > 0 {
+            debug!("pruned {pruned} change history entries");
+            counter!("corro.db.history.pruned", pruned as u64);
         }
         Ok::<_, eyre::Report>(())
-    })?;
-    debug!("done handling db_cleanup");
-    Ok(())
+    })
 }
 
 #[derive(Clone)]
@@ -1748,17 +2130,25 @@ async fn process_fully_buffered_changes(
 pub async fn process_multiple_changes(
     agent: &Agent,
     changes: Vec<(ChangeV1, ChangeSource)>,
-) -> Result<(), ChangeError> {
+) -> Result<ApplyReport, ChangeError> {
     debug!(self_actor_id = %agent.actor_id(), "processing multiple changes, len: {}", changes.iter().map(|(change, _)| cmp::max(change.len(), 1)).sum::<usize>());
 
     let bookie = agent.bookie();
 
+    let mut report = ApplyReport::default();
+    let mut known_tables: HashSet<String> = agent.schema().read().tables.keys().cloned().collect();
+    // replicated, but not part of the schema
+    known_tables.insert(PROBE_TABLE.to_owned());
+    known_tables.insert(NODE_NAMES_TABLE.to_owned());
+
     let mut seen = HashSet::new();
     let mut unknown_changes = Vec::with_capacity(changes.len());
     for (change, src) in changes {
         let versions = change.versions();
+        report.max_db_version_seen = cmp::max(report.max_db_version_seen, Some(*versions.end()));
         let seqs = change.seqs();
         if !seen.insert((change.actor_id, versions, seqs.cloned())) {
+            report.skipped_known += 1;
             continue;
         }
         if bookie
@@ -1775,6 +2165,7 @@ pub async fn process_multiple_changes(
             .await
             .contains_all(change.versions(), change.seqs())
         {
+            report.skipped_known += 1;
             continue;
         }
 
@@ -1785,9 +2176,10 @@ pub async fn process_multiple_changes(
 
     let mut conn = agent.pool().write_normal().await?;
 
-    block_in_place(|| {
+    let report = block_in_place(|| {
         let start = Instant::now();
         let tx = conn.transaction()?;
+        let config = agent.config();
 
         let mut knowns: BTreeMap<ActorId, Vec<_>> = BTreeMap::new();
         let mut changesets = vec![];
@@ -1822,6 +2214,7 @@ pub async fn process_multiple_changes(
                         trace!(
                             "previously unknown versions are now deemed known, aborting inserts"
                         );
+                        report.skipped_known += 1;
                         continue;
                     }
 
@@ -1840,6 +2233,7 @@ pub async fn process_multiple_changes(
                         },
                         None => seen.contains_key(&version),
                     }) {
+                        report.skipped_known += 1;
                         continue;
                     }
 
@@ -1856,6 +2250,45 @@ pub async fn process_multiple_changes(
                             },
                         )
                     } else {
+                        let tables: BTreeSet<TableName> =
+                            change.changes().iter().map(|c| c.table.clone()).collect();
+
+                        // these tables are never replicated to this node, whatever
+                        // the sender thinks
+                        let local_only = change
+                            .changes()
+                            .iter()
+                            .filter(|c| config.db.is_local_only(c.table.as_str()))
+                            .count();
+                        if local_only > 0 {
+                            warn!(%actor_id, ?versions, "rejecting changes for local-only tables");
+                            counter!("corro.agent.changes.rejected.local_only", local_only as u64);
+                            report.rejected.extend(
+                                tables
+                                    .into_iter()
+                                    .filter(|table| config.db.is_local_only(table.as_str()))
+                                    .map(|table| (table, "local-only table".to_owned())),
+                            );
+                            continue;
+                        }
+
+                        // applying these would fail, leave them unbooked until our schema
+                        // catches up w/ the other node's
+                        if tables
+                            .iter()
+                            .any(|table| !known_tables.contains(table.as_str()))
+                        {
+                            warn!(%actor_id, ?versions, "rejecting changes for unknown tables");
+                            report.rejected.extend(
+                                tables
+                                    .into_iter()
+                                    .filter(|table| !known_tables.contains(table.as_str()))
+                                    .map(|table| (table, "unknown table".to_owned())),
+                            );
+                            continue;
+                        }
+
+                        let kinds = ChangeKindCounts::tally(change.changes());
                         let (known, changeset) = match process_single_version(
                             &tx,
                             last_db_version,
@@ -1865,10 +2298,15 @@ pub async fn process_multiple_changes(
                                 if let KnownDbVersion::Current { db_version, .. } = &known {
                                     last_db_version = Some(*db_version);
                                 }
+                                report.kinds += kinds;
                                 (known, changeset)
                             }
                             Err(e) => {
                                 error!(%actor_id, ?versions, "could not process single change: {e}");
+                                let reason = e.to_string();
+                                report.rejected.extend(
+                                    tables.into_iter().map(|table| (table, reason.clone())),
+                                );
                                 continue;
                             }
                         };
@@ -1880,6 +2318,7 @@ pub async fn process_multiple_changes(
 
                     changesets.push((actor_id, changeset, src));
                     knowns.entry(actor_id).or_default().push((versions, known));
+                    report.applied += 1;
                 }
             }
         }
@@ -1964,7 +2403,22 @@ pub async fn process_multiple_changes(
         }
 
         for (actor_id, changeset, src) in changesets {
-            process_subs(agent, changeset.changes());
+            // cached queries reading these tables are stale now
+            if agent.query_cache().in_use() {
+                agent.query_cache().invalidate(
+                    changeset
+                        .changes()
+                        .iter()
+                        .map(|change| change.table.as_str())
+                        .unique(),
+                );
+            }
+            process_subs(agent, changeset.changes(), None);
+            // partial changes are buffered, they're committed w/ the rest later
+            if changeset.is_complete() {
+                agent.change_hooks().notify(changeset.changes());
+            }
+            record_subs_latency(agent, &changeset);
             if matches!(src, ChangeSource::Broadcast) && !changeset.is_empty() {
                 if let Err(_e) =
                     agent
@@ -1979,10 +2433,21 @@ pub async fn process_multiple_changes(
             }
         }
 
-        Ok::<_, ChangeError>(())
+        Ok::<_, ChangeError>(report)
     })?;
 
-    Ok(())
+    debug!(self_actor_id = %agent.actor_id(), "applied multiple changes: {report:?}");
+
+    counter!("corro.agent.changes.applied", report.applied as u64);
+    counter!(
+        "corro.agent.changes.skipped_known",
+        report.skipped_known as u64
+    );
+    for (table, _reason) in report.rejected.iter() {
+        increment_counter!("corro.agent.changes.rejected", "table" => table.to_string());
+    }
+
+    Ok(report)
 }
 
 #[tracing::instrument(skip(tx, parts), err)]
@@ -2218,15 +2683,23 @@ fn process_single_version(
     Ok((known, changeset))
 }
 
-pub fn process_subs(agent: &Agent, changeset: &[Change]) {
+/// Evaluates `changeset` against the subscriptions reading from the tables it
+/// changed, `source` is the `source_id` of the local transaction it came
+/// from, if tagged w/ one
+pub fn process_subs(agent: &Agent, changeset: &[Change], source: Option<uuid::Uuid>) {
     trace!("process subs...");
 
+    let changes = TableChanges::from_changes(changeset);
+    if changes.is_empty() {
+        return;
+    }
+
     let mut matchers_to_delete = vec![];
 
     {
         let matchers = agent.matchers().read();
         for (id, matcher) in matchers.iter() {
-            if let Err(e) = matcher.process_change(changeset) {
+            if let Err(e) = matcher.process_changes(&changes, source) {
                 error!("could not process change w/ matcher {id}, it is probably defunct! {e}");
                 matchers_to_delete.push(*id);
             }
@@ -2238,15 +2711,60 @@ pub fn process_subs(agent: &Agent, changeset: &[Change]) {
     }
 }
 
+/// Records how long after being made on their origin remote changes reached
+/// subscriptions, from the `ts` changes were sent w/ or their version's
+fn record_subs_latency(agent: &Agent, changeset: &Changeset) {
+    if agent.matchers().read().is_empty() {
+        return;
+    }
+
+    let origin = changeset
+        .changes()
+        .iter()
+        .find_map(|change| change.origin_time())
+        .or_else(|| {
+            changeset
+                .ts()
+                .filter(|ts| *ts != Timestamp::zero())
+                .map(|ts| SystemTime::from(ts.to_time()))
+        });
+
+    if let Some(Ok(latency)) = origin.map(|origin| SystemTime::now().duration_since(origin)) {
+        histogram!("corro.subs.changes.latency.seconds", latency.as_secs_f64());
+    }
+}
+
 pub fn process_subs_by_db_version(agent: &Agent, conn: &Connection, db_version: i64) {
     trace!("process subs by db version...");
 
+    if let Err(e) = agent.change_hooks().notify_db_version(conn, db_version) {
+        error!("could not notify change hooks for db_version {db_version}: {e}");
+    }
+
+    if agent.query_cache().in_use() {
+        if let Err(e) = agent.query_cache().invalidate_db_version(conn, db_version) {
+            error!("could not invalidate cached queries for db_version {db_version}: {e}");
+        }
+    }
+
+    if agent.matchers().read().is_empty() {
+        return;
+    }
+
+    let changes = match TableChanges::from_db_version(conn, db_version) {
+        Ok(changes) => changes,
+        Err(e) => {
+            error!("could not read changes of db_version {db_version} for subscriptions: {e}");
+            return;
+        }
+    };
+
     let mut matchers_to_delete = vec![];
 
     {
         let matchers = agent.matchers().read();
         for (id, matcher) in matchers.iter() {
-            if let Err(e) = matcher.process_changes_from_db_version(conn, db_version) {
+            if let Err(e) = matcher.process_changes(&changes, None) {
                 error!("could not process change w/ matcher {id}, it is probably defunct! {e}");
                 matchers_to_delete.push(*id);
             }
@@ -2366,8 +2884,22 @@ async fn handle_sync(agent: &Agent, transport: &Transport) -> Result<(), SyncCli
         return Ok(());
     }
 
+    agent
+        .members()
+        .write()
+        .syncing
+        .extend(chosen.iter().map(|(actor_id, _)| *actor_id));
+
     let start = Instant::now();
-    let n = parallel_sync(agent, transport, chosen.clone(), sync_state).await?;
+    let res = parallel_sync(agent, transport, chosen.clone(), sync_state).await;
+
+    {
+        let mut members = agent.members().write();
+        for (actor_id, _) in chosen.iter() {
+            members.syncing.remove(actor_id);
+        }
+    }
+    let n = res?;
 
     let elapsed = start.elapsed();
     if n > 0 {
@@ -2638,6 +3170,12 @@ pub fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
     let migrations: Vec<Box<dyn Migration>> = vec![
         Box::new(init_migration as fn(&Transaction) -> rusqlite::Result<()>),
         Box::new(v0_2_0_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(history_bounds_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(migrations_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(probe_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(usage_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(node_names_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(idempotency_keys_migration as fn(&Transaction) -> rusqlite::Result<()>),
     ];
 
     corro_types::sqlite::migrate(conn, migrations)
@@ -2735,6 +3273,90 @@ fn v0_2_0_migration(tx: &Transaction) -> rusqlite::Result<()> {
     )
 }
 
+fn history_bounds_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        -- per table, db_version after which the retained change history is complete
+        CREATE TABLE __corro_history_bounds (
+            tbl_name TEXT NOT NULL PRIMARY KEY,
+            since INTEGER NOT NULL
+        ) WITHOUT ROWID;
+    ",
+    )
+}
+
+fn migrations_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        -- migration steps applied through /v1/migrations/apply, by name
+        CREATE TABLE __corro_migrations (
+            name TEXT NOT NULL PRIMARY KEY,
+            applied_at INTEGER NOT NULL,
+            statements INTEGER NOT NULL
+        ) WITHOUT ROWID;
+    ",
+    )
+}
+
+fn probe_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        -- heartbeats replicated to measure change propagation, one per node
+        CREATE TABLE __corro_probe (
+            actor_id BLOB NOT NULL PRIMARY KEY,
+            counter INTEGER NOT NULL DEFAULT 0,
+            origin_ts INTEGER NOT NULL DEFAULT 0
+        );
+        SELECT crsql_as_crr('__corro_probe');
+    ",
+    )
+}
+
+fn usage_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        -- hourly usage of subscriptions and clients, local to each node
+        CREATE TABLE __corro_usage (
+            identity TEXT NOT NULL,
+            bucket INTEGER NOT NULL,
+            rows INTEGER NOT NULL DEFAULT 0,
+            changes INTEGER NOT NULL DEFAULT 0,
+            bytes INTEGER NOT NULL DEFAULT 0,
+            statements INTEGER NOT NULL DEFAULT 0,
+            changes_generated INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (identity, bucket)
+        ) WITHOUT ROWID;
+    ",
+    )
+}
+
+fn node_names_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        -- names of the cluster's nodes, one per node, replicated
+        CREATE TABLE __corro_node_names (
+            site_id BLOB NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL DEFAULT ''
+        );
+        SELECT crsql_as_crr('__corro_node_names');
+    ",
+    )
+}
+
+fn idempotency_keys_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        -- results of transactions applied w/ an idempotency-key header, local to each node
+        CREATE TABLE __corro_idempotency_keys (
+            key TEXT NOT NULL PRIMARY KEY,
+            results TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        ) WITHOUT ROWID;
+        CREATE INDEX __corro_idempotency_keys_applied_at ON __corro_idempotency_keys (applied_at);
+    ",
+    )
+}
+
 #[cfg(test)]
 pub mod tests {
     use std::{
@@ -2757,7 +3379,12 @@ pub mod tests {
 
     use super::*;
 
-    use corro_types::api::{ExecResponse, ExecResult, Statement};
+    use corro_types::api::{
+        AccessDenied, AppliedMigration, ChangeId, ColumnName, DeniedObject, ExecResponse,
+        ExecResult, MigrateResponse, QueryEvent, QuotaKind, RowId, SqliteValue, Statement,
+    };
+    use corro_types::config::{AccessPolicy, QuotaConfig, QuotaLimits};
+    use corro_types::pubsub::ChangeType;
 
     use corro_tests::*;
 
@@ -3292,130 +3919,210 @@ pub mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn large_tx_sync() -> eyre::Result<()> {
+    async fn apply_report_counts() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
         let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
-        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
 
-        let client = hyper::Client::builder()
-            .pool_max_idle_per_host(5)
-            .pool_idle_timeout(Duration::from_secs(300))
-            .build_http::<hyper::Body>();
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let ts: Timestamp = ta.agent.clock().new_timestamp().into();
 
-        let req_body: Vec<Statement> = serde_json::from_value(json!(["INSERT INTO tests  WITH RECURSIVE    cte(id) AS (       SELECT random()       UNION ALL       SELECT random()         FROM cte        LIMIT 10000  ) SELECT id, \"hello\" as text FROM cte;"]))?;
+        let change = |version: i64, table: &str| ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version,
+                changes: vec![Change {
+                    table: TableName(table.into()),
+                    pk: corro_types::pubsub::pack_columns(&[version.into()]).unwrap(),
+                    cid: corro_types::api::ColumnName("text".into()),
+                    val: "hello".into(),
+                    col_version: 1,
+                    db_version: version,
+                    seq: 0,
+                    site_id: actor_id.site_id(),
+                    cl: 1,
+                    compressed: None,
+                    ts: None,
+                }],
+                seqs: 0..=0,
+                last_seq: 0,
+                ts,
+            },
+        };
 
-        let res = timeout(
-            Duration::from_secs(5),
-            client.request(
-                hyper::Request::builder()
-                    .method(hyper::Method::POST)
-                    .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
-                    .header(hyper::header::CONTENT_TYPE, "application/json")
-                    .body(serde_json::to_vec(&req_body)?.into())?,
-            ),
+        let report = process_multiple_changes(
+            &ta.agent,
+            vec![
+                (change(1, "tests"), ChangeSource::Sync),
+                (change(1, "tests"), ChangeSource::Sync),
+                (change(2, "nope"), ChangeSource::Sync),
+            ],
         )
-        .await??;
+        .await?;
 
-        let body: ExecResponse =
-            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(
+            report,
+            ApplyReport {
+                applied: 1,
+                skipped_known: 1,
+                rejected: vec![(TableName("nope".into()), "unknown table".into())],
+                max_db_version_seen: Some(2),
+                kinds: ChangeKindCounts {
+                    inserts: 1,
+                    ..Default::default()
+                },
+            }
+        );
 
-        println!("body: {body:?}");
+        // the rejected version wasn't booked, the applied one was
+        let report = process_multiple_changes(
+            &ta.agent,
+            vec![
+                (change(1, "tests"), ChangeSource::Sync),
+                (change(2, "nope"), ChangeSource::Sync),
+            ],
+        )
+        .await?;
+        assert_eq!(report.applied, 0);
+        assert_eq!(report.skipped_known, 1);
+        assert_eq!(report.rejected.len(), 1);
 
-        let db_version: i64 =
-            ta1.agent
-                .pool()
-                .read()
-                .await?
-                .query_row("SELECT crsql_db_version();", (), |row| row.get(0))?;
-        assert_eq!(db_version, 1);
+        let conn = ta.agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT count(*) FROM tests", (), |row| row.get(0))?;
+        assert_eq!(count, 1);
 
-        sleep(Duration::from_secs(2)).await;
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
 
-        let ta2 = launch_test_agent(
-            |conf| {
-                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
-                    .build()
-            },
-            tripwire.clone(),
-        )
-        .await?;
-        let ta3 = launch_test_agent(
-            |conf| {
-                conf.bootstrap(vec![ta2.agent.gossip_addr().to_string()])
-                    .build()
-            },
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn local_only_tables() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(
+            |conf| conf.add_local_only_table("tmp_*").build(),
             tripwire.clone(),
         )
         .await?;
-        let ta4 = launch_test_agent(
+        let ta2 = launch_test_agent(
             |conf| {
-                conf.bootstrap(vec![ta3.agent.gossip_addr().to_string()])
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .add_local_only_table("tmp_*")
                     .build()
             },
             tripwire.clone(),
         )
         .await?;
 
-        sleep(Duration::from_secs(5)).await;
-
-        {
-            let conn = ta2.agent.pool().read().await?;
-
-            let count: i64 = conn
-                .prepare_cached("SELECT COUNT(*) FROM tests;")?
-                .query_row((), |row| row.get(0))?;
-
-            println!(
-                "{:#?}",
-                generate_sync(ta2.agent.bookie(), ta2.agent.actor_id()).await
-            );
+        let client = hyper::Client::builder().build_http::<hyper::Body>();
+        let post = |ta: &TestAgent, path: &str, body: serde_json::Value| {
+            client.request(
+                hyper::Request::builder()
+                    .method(hyper::Method::POST)
+                    .uri(format!("http://{}{path}", ta.agent.api_addr()))
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(&body).unwrap().into())
+                    .unwrap(),
+            )
+        };
 
-            assert_eq!(
-                count,
-                10000,
-                "actor {} did not reach 100K rows",
-                ta2.agent.actor_id()
-            );
+        let schema = json!(["CREATE TABLE tmp_scratch (id INTEGER NOT NULL PRIMARY KEY, text TEXT NOT NULL DEFAULT '');"]);
+        for ta in [&ta1, &ta2] {
+            let res = post(ta, "/v1/migrations", schema.clone()).await?;
+            assert_eq!(res.status(), hyper::StatusCode::OK);
+            assert!(!ta.agent.schema().read().tables.contains_key("tmp_scratch"));
         }
 
-        {
-            let conn = ta3.agent.pool().read().await?;
-
-            let count: i64 = conn
-                .prepare_cached("SELECT COUNT(*) FROM tests;")?
-                .query_row((), |row| row.get(0))?;
-
-            println!(
-                "{:#?}",
-                generate_sync(ta3.agent.bookie(), ta3.agent.actor_id()).await
-            );
-
-            assert_eq!(
-                count,
-                10000,
-                "actor {} did not reach 100K rows",
-                ta3.agent.actor_id()
-            );
+        let res = post(
+            &ta1,
+            "/v1/transactions",
+            json!([
+                [
+                    "INSERT INTO tmp_scratch (id,text) VALUES (?,?)",
+                    [1, "local"]
+                ],
+                [
+                    "INSERT INTO tests (id,text) VALUES (?,?)",
+                    [1, "replicated"]
+                ]
+            ]),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        async fn count(ta: &TestAgent, table: &str) -> eyre::Result<i64> {
+            Ok(ta.agent.pool().read().await?.query_row(
+                &format!("SELECT count(*) FROM {table}"),
+                [],
+                |row| row.get(0),
+            )?)
         }
-        {
-            let conn = ta4.agent.pool().read().await?;
+        assert_eq!(count(&ta1, "tmp_scratch").await?, 1);
 
-            let count: i64 = conn
-                .prepare_cached("SELECT COUNT(*) FROM tests;")?
-                .query_row((), |row| row.get(0))?;
+        // the table isn't a CRR, nothing was generated for it
+        let changes: i64 = ta1.agent.pool().read().await?.query_row(
+            "SELECT count(*) FROM crsql_changes WHERE \"table\" = 'tmp_scratch'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(changes, 0);
 
-            println!(
-                "{:#?}",
-                generate_sync(ta4.agent.bookie(), ta4.agent.actor_id()).await
-            );
+        timeout(Duration::from_secs(10), async {
+            while count(&ta2, "tests").await? == 0 {
+                sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+        assert_eq!(count(&ta2, "tmp_scratch").await?, 0);
 
-            assert_eq!(
-                count,
-                10000,
-                "actor {} did not reach 100K rows",
-                ta4.agent.actor_id()
-            );
-        }
+        // a forged change for the table is rejected
+        let actor_id = ta1.agent.actor_id();
+        let ts: Timestamp = ta2.agent.clock().new_timestamp().into();
+        let forged = ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version: 100,
+                changes: vec![Change {
+                    table: TableName("tmp_scratch".into()),
+                    pk: corro_types::pubsub::pack_columns(&[2i64.into()]).unwrap(),
+                    cid: corro_types::api::ColumnName("text".into()),
+                    val: "forged".into(),
+                    col_version: 1,
+                    db_version: 100,
+                    seq: 0,
+                    site_id: actor_id.site_id(),
+                    cl: 1,
+                    compressed: None,
+                    ts: None,
+                }],
+                seqs: 0..=0,
+                last_seq: 0,
+                ts,
+            },
+        };
+        let report =
+            process_multiple_changes(&ta2.agent, vec![(forged, ChangeSource::Sync)]).await?;
+        assert_eq!(report.applied, 0);
+        assert_eq!(
+            report.rejected,
+            vec![(TableName("tmp_scratch".into()), "local-only table".into())]
+        );
+        assert_eq!(count(&ta2, "tmp_scratch").await?, 0);
+
+        // and it can't be subscribed to
+        let res = post(
+            &ta1,
+            "/v1/subscriptions",
+            json!("SELECT * FROM tmp_scratch"),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::BAD_REQUEST);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert!(String::from_utf8_lossy(&body).contains("local-only"));
 
         tripwire_tx.send(()).await.ok();
         tripwire_worker.await;
@@ -3425,7 +4132,564 @@ pub mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
-    async fn many_small_changes() -> eyre::Result<()> {
+    async fn migrations_apply_once() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = hyper::Client::builder().build_http::<hyper::Body>();
+        let request =
+            |ta: &TestAgent, method: hyper::Method, path: &str, body: serde_json::Value| {
+                client.request(
+                    hyper::Request::builder()
+                        .method(method)
+                        .uri(format!("http://{}{path}", ta.agent.api_addr()))
+                        .header(hyper::header::CONTENT_TYPE, "application/json")
+                        .body(serde_json::to_vec(&body).unwrap().into())
+                        .unwrap(),
+                )
+            };
+        async fn migrate(
+            res: hyper::Response<hyper::Body>,
+        ) -> eyre::Result<(hyper::StatusCode, MigrateResponse)> {
+            let status = res.status();
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            Ok((status, serde_json::from_slice(&body)?))
+        }
+
+        let res = request(
+            &ta1,
+            hyper::Method::POST,
+            "/v1/transactions",
+            json!([
+                "INSERT INTO tests (id,text) VALUES (1,'one')",
+                "INSERT INTO tests (id,text) VALUES (2,'two')"
+            ]),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        let add_label = json!({
+            "name": "add_tests_label",
+            "statements": [
+                "ALTER TABLE tests ADD COLUMN label TEXT NOT NULL DEFAULT ''",
+                "CREATE INDEX tests_label ON tests (label)"
+            ]
+        });
+        let backfill_label = json!({
+            "name": "backfill_tests_label",
+            "statements": ["UPDATE tests SET label = 'label-' || id"]
+        });
+
+        // the schema change has to be applied everywhere before backfilling
+        let (status, res) = migrate(
+            request(
+                &ta2,
+                hyper::Method::POST,
+                "/v1/migrations/apply",
+                json!({ "steps": [add_label.clone()] }),
+            )
+            .await?,
+        )
+        .await?;
+        assert_eq!(status, hyper::StatusCode::OK);
+        assert_eq!(res.applied, vec!["add_tests_label".to_string()]);
+
+        let steps = json!({ "steps": [add_label, backfill_label] });
+        let (status, res) = migrate(
+            request(
+                &ta1,
+                hyper::Method::POST,
+                "/v1/migrations/apply",
+                steps.clone(),
+            )
+            .await?,
+        )
+        .await?;
+        assert_eq!(status, hyper::StatusCode::OK, "{res:?}");
+        assert_eq!(
+            res.applied,
+            vec![
+                "add_tests_label".to_string(),
+                "backfill_tests_label".to_string()
+            ]
+        );
+        assert!(res.skipped.is_empty());
+        {
+            let schema = ta1.agent.schema().read();
+            let tests = &schema.tables["tests"];
+            assert!(tests.columns.contains_key("label"));
+            assert!(tests.indexes.contains_key("tests_label"));
+        }
+
+        // applying it again is a no-op
+        let (status, res) =
+            migrate(request(&ta1, hyper::Method::POST, "/v1/migrations/apply", steps).await?)
+                .await?;
+        assert_eq!(status, hyper::StatusCode::OK);
+        assert!(res.applied.is_empty());
+        assert_eq!(
+            res.skipped,
+            vec![
+                "add_tests_label".to_string(),
+                "backfill_tests_label".to_string()
+            ]
+        );
+
+        let res = request(
+            &ta1,
+            hyper::Method::GET,
+            "/v1/migrations/applied",
+            json!(null),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let applied: Vec<AppliedMigration> =
+            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert_eq!(
+            applied
+                .iter()
+                .map(|step| (step.name.as_str(), step.statements))
+                .collect::<Vec<_>>(),
+            vec![("add_tests_label", 2), ("backfill_tests_label", 1)]
+        );
+
+        // the failing step is rolled back along w/ its schema change
+        let (status, res) = migrate(
+            request(
+                &ta1,
+                hyper::Method::POST,
+                "/v1/migrations/apply",
+                json!({ "steps": [{
+                    "name": "broken",
+                    "statements": [
+                        "ALTER TABLE tests ADD COLUMN broken TEXT",
+                        "UPDATE tests SET nope = 1"
+                    ]
+                }] }),
+            )
+            .await?,
+        )
+        .await?;
+        assert_eq!(status, hyper::StatusCode::BAD_REQUEST);
+        assert_eq!(res.failed.as_deref(), Some("broken"));
+        assert!(!ta1.agent.schema().read().tables["tests"]
+            .columns
+            .contains_key("broken"));
+
+        // the backfill replicated
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let labels = ta2
+                    .agent
+                    .pool()
+                    .read()
+                    .await?
+                    .prepare("SELECT label FROM tests ORDER BY id")?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                if labels == ["label-1", "label-2"] {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn no_replication_writes_stay_local() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(
+            |conf| {
+                conf.api_authorization("admin")
+                    .add_no_replication_table("tests")
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = hyper::Client::builder().build_http::<hyper::Body>();
+        let exec = |token: Option<&str>, body: serde_json::Value| {
+            let mut req = hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                req = req.header(hyper::header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            client.request(req.body(serde_json::to_vec(&body).unwrap().into()).unwrap())
+        };
+
+        let res = exec(
+            Some("admin"),
+            json!({
+                "statements": [["INSERT INTO tests (id,text) VALUES (?,?)", [1, "local"]]],
+                "no_replication": true
+            }),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        let res = exec(
+            Some("admin"),
+            json!([[
+                "INSERT INTO tests (id,text) VALUES (?,?)",
+                [2, "replicated"]
+            ]]),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        async fn ids(ta: &TestAgent) -> eyre::Result<Vec<i64>> {
+            Ok(ta
+                .agent
+                .pool()
+                .read()
+                .await?
+                .prepare("SELECT id FROM tests ORDER BY id")?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?)
+        }
+        assert_eq!(ids(&ta1).await?, vec![1, 2]);
+
+        // the replicated row shows up on the peer, the other one never does
+        timeout(Duration::from_secs(10), async {
+            while ids(&ta2).await?.is_empty() {
+                sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+        assert_eq!(ids(&ta2).await?, vec![2]);
+
+        // nothing was captured for the first row, and capture was back on
+        // for the next write
+        async fn changes(ta: &TestAgent, id: i64) -> eyre::Result<i64> {
+            let pk = corro_types::pubsub::pack_columns(&[id.into()])?;
+            Ok(ta.agent.pool().read().await?.query_row(
+                "SELECT count(*) FROM crsql_changes WHERE \"table\" = 'tests' AND pk = ?",
+                [pk],
+                |row| row.get(0),
+            )?)
+        }
+        assert_eq!(changes(&ta1, 1).await?, 0);
+        assert!(changes(&ta1, 2).await? > 0);
+
+        for (token, body) in [
+            // w/o the admin token
+            (
+                None,
+                json!({"statements": ["UPDATE tests SET text = 'x'"], "no_replication": true}),
+            ),
+            // w/ another token
+            (
+                Some("not-admin"),
+                json!({"statements": ["UPDATE tests SET text = 'x'"], "no_replication": true}),
+            ),
+        ] {
+            let res = exec(token, body).await?;
+            assert!(res.status().is_client_error(), "{}", res.status());
+        }
+
+        // a table which isn't allowed
+        let res = exec(
+            Some("admin"),
+            json!({"statements": ["UPDATE tests2 SET text = 'x'"], "no_replication": true}),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::FORBIDDEN);
+        let body: ExecResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+        assert!(matches!(
+            body.results.as_slice(),
+            [ExecResult::Error { error, .. }] if error.contains("tests2") && error.contains("diverges")
+        ));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn resurrected_rows_are_deleted_then_inserted() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = hyper::Client::builder().build_http::<hyper::Body>();
+        let post = |ta: &TestAgent, path: &str, body: serde_json::Value| {
+            client.request(
+                hyper::Request::builder()
+                    .method(hyper::Method::POST)
+                    .uri(format!("http://{}{path}", ta.agent.api_addr()))
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(&body).unwrap().into())
+                    .unwrap(),
+            )
+        };
+
+        let res = post(
+            &ta1,
+            "/v1/transactions",
+            json!([["INSERT INTO tests (id,text) VALUES (?,?)", [1, "hello"]]]),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let count: i64 = ta2.agent.pool().read().await?.query_row(
+                    "SELECT count(*) FROM tests",
+                    [],
+                    |row| row.get(0),
+                )?;
+                if count == 1 {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+
+        let res = post(
+            &ta2,
+            "/v1/subscriptions",
+            json!("SELECT id, text FROM tests"),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        let body = res
+            .into_body()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+        let mut events = FramedRead::new(
+            tokio_util::io::StreamReader::new(body),
+            tokio_util::codec::LinesCodec::new(),
+        )
+        .map(|line| Ok::<_, eyre::Report>(serde_json::from_str::<QueryEvent>(&line?)?));
+        async fn next(
+            events: &mut (impl futures::Stream<Item = eyre::Result<QueryEvent>> + Unpin),
+        ) -> eyre::Result<QueryEvent> {
+            timeout(Duration::from_secs(10), events.next())
+                .await?
+                .expect("subscription ended")
+        }
+
+        assert!(matches!(
+            next(&mut events).await?,
+            QueryEvent::Columns { .. }
+        ));
+        let cells = vec![SqliteValue::Integer(1), "hello".into()];
+        assert_eq!(
+            next(&mut events).await?,
+            QueryEvent::Row(RowId(1), cells.clone())
+        );
+        assert!(matches!(
+            next(&mut events).await?,
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // the row comes back as-is, it'd be invisible to subscribers as an
+        // update
+        let res = post(
+            &ta1,
+            "/v1/transactions",
+            json!([
+                "DELETE FROM tests WHERE id = 1",
+                "INSERT INTO tests (id,text) VALUES (1, 'hello')"
+            ]),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        assert_eq!(
+            next(&mut events).await?,
+            QueryEvent::Change(ChangeType::Delete, RowId(1), cells.clone(), ChangeId(1))
+        );
+        assert_eq!(
+            next(&mut events).await?,
+            QueryEvent::Change(ChangeType::Insert, RowId(2), cells, ChangeId(2))
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn large_tx_sync() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let client = hyper::Client::builder()
+            .pool_max_idle_per_host(5)
+            .pool_idle_timeout(Duration::from_secs(300))
+            .build_http::<hyper::Body>();
+
+        let req_body: Vec<Statement> = serde_json::from_value(json!(["INSERT INTO tests  WITH RECURSIVE    cte(id) AS (       SELECT random()       UNION ALL       SELECT random()         FROM cte        LIMIT 10000  ) SELECT id, \"hello\" as text FROM cte;"]))?;
+
+        let res = timeout(
+            Duration::from_secs(5),
+            client.request(
+                hyper::Request::builder()
+                    .method(hyper::Method::POST)
+                    .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(serde_json::to_vec(&req_body)?.into())?,
+            ),
+        )
+        .await??;
+
+        let body: ExecResponse =
+            serde_json::from_slice(&hyper::body::to_bytes(res.into_body()).await?)?;
+
+        println!("body: {body:?}");
+
+        let db_version: i64 =
+            ta1.agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT crsql_db_version();", (), |row| row.get(0))?;
+        assert_eq!(db_version, 1);
+
+        sleep(Duration::from_secs(2)).await;
+
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        let ta3 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta2.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        let ta4 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta3.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        sleep(Duration::from_secs(5)).await;
+
+        {
+            let conn = ta2.agent.pool().read().await?;
+
+            let count: i64 = conn
+                .prepare_cached("SELECT COUNT(*) FROM tests;")?
+                .query_row((), |row| row.get(0))?;
+
+            println!(
+                "{:#?}",
+                generate_sync(ta2.agent.bookie(), ta2.agent.actor_id()).await
+            );
+
+            assert_eq!(
+                count,
+                10000,
+                "actor {} did not reach 100K rows",
+                ta2.agent.actor_id()
+            );
+        }
+
+        {
+            let conn = ta3.agent.pool().read().await?;
+
+            let count: i64 = conn
+                .prepare_cached("SELECT COUNT(*) FROM tests;")?
+                .query_row((), |row| row.get(0))?;
+
+            println!(
+                "{:#?}",
+                generate_sync(ta3.agent.bookie(), ta3.agent.actor_id()).await
+            );
+
+            assert_eq!(
+                count,
+                10000,
+                "actor {} did not reach 100K rows",
+                ta3.agent.actor_id()
+            );
+        }
+        {
+            let conn = ta4.agent.pool().read().await?;
+
+            let count: i64 = conn
+                .prepare_cached("SELECT COUNT(*) FROM tests;")?
+                .query_row((), |row| row.get(0))?;
+
+            println!(
+                "{:#?}",
+                generate_sync(ta4.agent.bookie(), ta4.agent.actor_id()).await
+            );
+
+            assert_eq!(
+                count,
+                10000,
+                "actor {} did not reach 100K rows",
+                ta4.agent.actor_id()
+            );
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn many_small_changes() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
         let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
 
@@ -3518,7 +4782,7 @@ pub mod tests {
 
                             match &body.results[0] {
                                 ExecResult::Execute { .. } => {}
-                                ExecResult::Error { error } => {
+                                ExecResult::Error { error, .. } => {
                                     eyre::bail!("error: {error}");
                                 }
                             }
@@ -3553,4 +4817,570 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn access_policies_restrict_tokens_to_tables() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(
+            |conf| {
+                conf.add_access_policy(AccessPolicy {
+                    token: "services-reader".into(),
+                    tables: vec!["consul_services".into()],
+                    columns: Default::default(),
+                })
+                .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = hyper::Client::builder().build_http::<hyper::Body>();
+        let post = |path: &str, token: Option<&str>, body: serde_json::Value| {
+            let mut req = hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}{path}", ta.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                req = req.header(hyper::header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            client.request(req.body(serde_json::to_vec(&body).unwrap().into()).unwrap())
+        };
+        async fn denied(res: hyper::Response<hyper::Body>) -> eyre::Result<DeniedObject> {
+            assert_eq!(res.status(), hyper::StatusCode::FORBIDDEN);
+            let bytes = hyper::body::to_bytes(res.into_body()).await?;
+            Ok(serde_json::from_slice::<AccessDenied>(&bytes)?.denied)
+        }
+
+        let res = post(
+            "/v1/migrations",
+            None,
+            json!([
+                "CREATE TABLE consul_services (node TEXT NOT NULL, id TEXT NOT NULL, name TEXT NOT NULL DEFAULT '', PRIMARY KEY (node, id))",
+                "CREATE TABLE consul_checks (node TEXT NOT NULL, id TEXT NOT NULL, service_id TEXT NOT NULL DEFAULT '', status TEXT NOT NULL DEFAULT '', PRIMARY KEY (node, id))",
+            ]),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        let token = Some("services-reader");
+
+        let res = post(
+            "/v1/subscriptions",
+            token,
+            json!("SELECT node, id, name FROM consul_services"),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        assert!(res.headers().contains_key("corro-query-id"));
+
+        let res = post(
+            "/v1/subscriptions",
+            token,
+            json!("SELECT node, id, status FROM consul_checks"),
+        )
+        .await?;
+        assert_eq!(
+            denied(res).await?,
+            DeniedObject::Table {
+                table: "consul_checks".into()
+            }
+        );
+
+        let res = post(
+            "/v1/subscriptions",
+            token,
+            json!("SELECT s.node, s.id, c.id, c.status FROM consul_services s JOIN consul_checks c ON c.node = s.node AND c.service_id = s.id"),
+        )
+        .await?;
+        assert_eq!(
+            denied(res).await?,
+            DeniedObject::Table {
+                table: "consul_checks".into()
+            }
+        );
+
+        // policies only grant reads
+        let res = post(
+            "/v1/transactions",
+            token,
+            json!([[
+                "INSERT INTO consul_services (node, id) VALUES (?, ?)",
+                ["n1", "web"]
+            ]]),
+        )
+        .await?;
+        assert!(matches!(denied(res).await?, DeniedObject::Route { .. }));
+
+        // other requests are unrestricted w/o `api.authorization`
+        let res = post(
+            "/v1/queries",
+            None,
+            json!("SELECT node, id, status FROM consul_checks"),
+        )
+        .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn killed_transactions_roll_back() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(
+            |conf| conf.api_authorization("admin").build(),
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = hyper::Client::builder().build_http::<hyper::Body>();
+        let request = |method: hyper::Method, path: &str, token: Option<&str>| {
+            let mut req = hyper::Request::builder()
+                .method(method)
+                .uri(format!("http://{}{path}", ta.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json");
+            if let Some(token) = token {
+                req = req.header(hyper::header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            req
+        };
+
+        // scans a billion rows w/o inserting any
+        let slow = request(hyper::Method::POST, "/v1/transactions", Some("admin"))
+            .header(corro_types::api::IDEMPOTENCY_KEY_HEADER, "slow-batch")
+            .body(
+                serde_json::to_vec(&json!([
+                    "INSERT INTO tests (id, text) VALUES (1, 'rolled back')",
+                    "INSERT INTO tests (id, text) SELECT x, 'slow' FROM (WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 1000000000) SELECT x FROM c) WHERE x < 0",
+                ]))?
+                .into(),
+            )?;
+        let slow = tokio::spawn(client.request(slow));
+
+        let active = timeout(Duration::from_secs(5), async {
+            loop {
+                let res = client
+                    .request(
+                        request(hyper::Method::GET, "/v1/transactions/active", Some("admin"))
+                            .body(hyper::Body::empty())?,
+                    )
+                    .await?;
+                let bytes = hyper::body::to_bytes(res.into_body()).await?;
+                let active: Vec<corro_types::api::ActiveTransaction> =
+                    serde_json::from_slice(&bytes)?;
+                if let Some(tx) = active
+                    .into_iter()
+                    .find(|tx| tx.current_statement == Some(1))
+                {
+                    return Ok::<_, eyre::Report>(tx);
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await??;
+        assert_eq!(active.statements, 2);
+        assert_eq!(active.idempotency_key.as_deref(), Some("slow-batch"));
+        assert!(active.client_addr.is_some());
+        assert!(active
+            .sql
+            .unwrap()
+            .starts_with("INSERT INTO tests (id, text) SELECT"));
+
+        let kill_path = format!("/v1/transactions/active/{}/kill", active.id);
+        // every request needs the token once it's configured, the kill
+        // handler also refuses agents w/o one
+        for token in [None, Some("not-admin")] {
+            let res = client
+                .request(
+                    request(hyper::Method::POST, &kill_path, token).body(hyper::Body::empty())?,
+                )
+                .await?;
+            assert!(res.status().is_client_error(), "{}", res.status());
+        }
+        assert!(!ta.agent.exec_registry().list()[0].killed);
+
+        let res = client
+            .request(
+                request(hyper::Method::POST, &kill_path, Some("admin"))
+                    .body(hyper::Body::empty())?,
+            )
+            .await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+
+        let res = timeout(Duration::from_secs(5), slow).await???;
+        assert_eq!(res.status(), hyper::StatusCode::CONFLICT);
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+        let res: ExecResponse = serde_json::from_slice(&bytes)?;
+        assert!(matches!(
+            res.results.as_slice(),
+            [ExecResult::Error {
+                code: Some(corro_types::api::ExecErrorCode::Interrupted),
+                ..
+            }]
+        ));
+
+        let count: i64 =
+            ta.agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT count(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+        assert!(ta.agent.exec_registry().list().is_empty());
+
+        let res = client
+            .request(
+                request(hyper::Method::POST, &kill_path, Some("admin"))
+                    .body(hyper::Body::empty())?,
+            )
+            .await?;
+        assert_eq!(res.status(), hyper::StatusCode::NOT_FOUND);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    // splits the complete lines off `buf`
+    fn query_events(buf: &mut Vec<u8>) -> eyre::Result<Vec<QueryEvent>> {
+        let Some(end) = buf.iter().rposition(|b| *b == b'\n') else {
+            return Ok(vec![]);
+        };
+        let lines = buf.drain(..=end).collect::<Vec<_>>();
+        Ok(lines
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()?)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn api_ends_subscriptions_on_shutdown() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let client: hyper::Client<_, hyper::Body> = hyper::Client::builder().build_http();
+        let res = client
+            .request(
+                hyper::Request::builder()
+                    .method(hyper::Method::POST)
+                    .uri(format!("http://{}/v1/subscriptions", ta.agent.api_addr()))
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(
+                        serde_json::to_vec(&Statement::Simple("SELECT * FROM tests".into()))?
+                            .into(),
+                    )?,
+            )
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // the snapshot, after which the subscription waits for changes
+        let mut body = res.into_body();
+        let mut buf = vec![];
+        let mut events = vec![];
+        while !events
+            .iter()
+            .any(|evt| matches!(evt, QueryEvent::EndOfQuery { .. }))
+        {
+            let chunk = timeout(
+                Duration::from_secs(5),
+                hyper::body::HttpBody::data(&mut body),
+            )
+            .await?
+            .expect("subscription body ended early")?;
+            buf.extend_from_slice(&chunk);
+            events.extend(query_events(&mut buf)?);
+        }
+
+        tripwire_tx.send(()).await.ok();
+
+        // ended w/ the shutdown event rather than a reset connection
+        let rest = timeout(Duration::from_secs(5), hyper::body::to_bytes(body)).await??;
+        buf.extend_from_slice(&rest);
+        let events = query_events(&mut buf)?;
+        assert!(buf.is_empty());
+        assert_eq!(events, vec![QueryEvent::shutting_down()]);
+
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn client_schema_cache_follows_migrations() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        // only responses' schema versions can invalidate the cache
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr())
+            .with_schema_max_age(Duration::from_secs(3600));
+        let tests = TableName("tests".into());
+
+        let schema = client.schema_for(&tests).await?.expect("no tests schema");
+        assert_eq!(schema.pk, vec![ColumnName("id".into())]);
+        assert!(schema.column("added").is_none());
+        assert!(client
+            .schema_for(&TableName("nope".into()))
+            .await?
+            .is_none());
+
+        let version = client.schema_version().await?;
+        assert_eq!(version, ta.agent.schema_version());
+
+        client
+            .schema(&[Statement::Simple(
+                "CREATE TABLE tests (
+                    id INTEGER NOT NULL PRIMARY KEY,
+                    text TEXT NOT NULL DEFAULT \"\",
+                    added INTEGER
+                ) WITHOUT ROWID;"
+                    .into(),
+            )])
+            .await?;
+        assert_ne!(client.schema_version().await?, version);
+
+        let schema = client.schema_for(&tests).await?.expect("no tests schema");
+        let added = schema.column("added").expect("cached schema is stale");
+        assert_eq!(added.declared_type.as_deref(), Some("INTEGER"));
+        assert!(added.nullable);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn client_encrypts_columns() -> eyre::Result<()> {
+        use corro_client::crypto::{ColumnCrypto, ColumnKey, CryptoError};
+
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let key = ColumnKey::new(1, [7; 32]);
+        let plain = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+        let client = plain
+            .clone()
+            .with_column_crypto(ColumnCrypto::new().column("tests", "text", &key));
+
+        let select = Statement::Simple("SELECT id, text FROM tests".into());
+        let mut sub = client.subscribe(&select, None).await?.decrypted();
+
+        client
+            .execute(&[Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                vec![1i64.into(), "s3cr3t".into()],
+            )])
+            .await?;
+
+        // the agent only stores ciphertext
+        let stored: Vec<u8> = plain
+            .query_scalar(&Statement::Simple(
+                "SELECT text FROM tests WHERE id = 1".into(),
+            ))
+            .await?
+            .expect("no row");
+        assert!(stored.starts_with(b"CENC"));
+        assert!(!stored.windows(6).any(|w| w == b"s3cr3t"));
+
+        let (id, text): (i64, String) = client.query_one(&select).await?;
+        assert_eq!((id, text.as_str()), (1, "s3cr3t"));
+
+        let change = loop {
+            let evt = tokio::time::timeout(Duration::from_secs(5), sub.next())
+                .await?
+                .expect("subscription ended")?;
+            assert!(evt.errors.is_empty(), "{:?}", evt.errors);
+            if let QueryEvent::Change(_, _, cells, _) = evt.event {
+                break cells;
+            }
+        };
+        assert_eq!(change, [SqliteValue::Integer(1), "s3cr3t".into()]);
+
+        // w/ the wrong key, values are reported but the rows still stream
+        let wrong = plain.clone().with_column_crypto(ColumnCrypto::new().column(
+            "tests",
+            "text",
+            &ColumnKey::new(1, [8; 32]),
+        ));
+        let mut rows = wrong.query_decrypted(&select).await?;
+        let row = loop {
+            let evt = rows.next().await.expect("query ended")?;
+            if let QueryEvent::Row(..) = evt.event {
+                break evt;
+            }
+        };
+        assert_eq!(row.errors.len(), 1);
+        assert_eq!(row.errors[0].index, 1);
+        assert_eq!(row.errors[0].error, CryptoError::Decrypt);
+        assert!(matches!(
+            wrong.query_one::<(i64, String)>(&select).await,
+            Err(corro_client::Error::Decrypt(_))
+        ));
+
+        // literals would be stored in plaintext
+        assert!(matches!(
+            client
+                .execute(&[Statement::Simple(
+                    "UPDATE tests SET text = 'plaintext'".into()
+                )])
+                .await,
+            Err(corro_client::Error::Crypto(CryptoError::Unsupported { .. }))
+        ));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn throttles_clients_over_quota() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(
+            |conf| {
+                conf.quotas(QuotaConfig {
+                    limits: QuotaLimits {
+                        statements_per_sec: Some(5.0),
+                        ..Default::default()
+                    },
+                    burst_secs: 1.0,
+                    overrides: vec![],
+                })
+                .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+        let insert = |id: i64| {
+            Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?, 'quota')".into(),
+                vec![id.into()],
+            )
+        };
+
+        client
+            .execute(&(1..=5).map(insert).collect::<Vec<_>>())
+            .await?;
+
+        let err = client.execute(&[insert(6)]).await.unwrap_err();
+        let retry_after = err.retry_after().expect("not throttled");
+        assert!(
+            matches!(&err, corro_client::Error::Throttled(t) if t.throttled == QuotaKind::Statements),
+            "{err:?}"
+        );
+        assert!(err.is_retryable());
+        assert!(retry_after <= Duration::from_secs(1), "{retry_after:?}");
+
+        let usage = client.quotas().await?;
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].identity, "ip:127.0.0.1");
+        assert_eq!(usage[0].throttled, 1);
+
+        // the throttled statement wasn't run
+        let conn = ta.agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT count(*) FROM tests", (), |row| row.get(0))?;
+        assert_eq!(count, 5);
+        drop(conn);
+
+        sleep(retry_after).await;
+        client.execute(&[insert(6)]).await?;
+
+        // waits it out on its own when asked to
+        sleep(Duration::from_secs(1)).await;
+        client
+            .execute(&(7..=11).map(insert).collect::<Vec<_>>())
+            .await?;
+        assert!(client.execute(&[insert(12)]).await.is_err());
+        client
+            .clone()
+            .with_throttle_retries(1)
+            .execute(&[insert(12)])
+            .await?;
+
+        let conn = ta.agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT count(*) FROM tests", (), |row| row.get(0))?;
+        assert_eq!(count, 12);
+        drop(conn);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn change_hooks_receive_committed_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let mut all = ta.agent.changes(Vec::<String>::new());
+        let mut tests = ta.agent.changes(["tests"]);
+
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+        client
+            .execute(&[
+                Statement::WithParams(
+                    "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                    vec![1i64.into(), "hello".into()],
+                ),
+                Statement::WithParams(
+                    "INSERT INTO tests2 (id, text) VALUES (?, ?)".into(),
+                    vec![2i64.into(), "world".into()],
+                ),
+            ])
+            .await?;
+        client
+            .execute(&[Statement::WithParams(
+                "INSERT INTO tests2 (id, text) VALUES (?, ?)".into(),
+                vec![3i64.into(), "again".into()],
+            )])
+            .await?;
+
+        let batch = timeout(Duration::from_secs(5), all.recv()).await??;
+        let tables: Vec<&str> = batch.iter().map(|change| change.table.as_str()).collect();
+        assert_eq!(tables, vec!["tests", "tests2"]);
+        let batch = timeout(Duration::from_secs(5), all.recv()).await??;
+        assert!(batch.iter().all(|change| change.table.as_str() == "tests2"));
+
+        // the second transaction didn't touch `tests`
+        let batch = timeout(Duration::from_secs(5), tests.recv()).await??;
+        assert_eq!(batch.len(), 1);
+        let change = &batch[0];
+        assert_eq!(change.table.as_str(), "tests");
+        assert_eq!(change.cid.as_str(), "text");
+        assert_eq!(change.val, SqliteValue::Text("hello".into()));
+        assert_eq!(
+            corro_types::pubsub::unpack_columns(&change.pk)?,
+            vec![corro_types::api::SqliteValueRef::Integer(1)]
+        );
+        assert!(timeout(Duration::from_millis(200), tests.recv())
+            .await
+            .is_err());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
 }
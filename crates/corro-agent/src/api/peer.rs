@@ -14,8 +14,8 @@ use corro_types::broadcast::{
 use corro_types::change::{row_to_change, Change};
 use corro_types::config::{GossipConfig, TlsClientConfig};
 use corro_types::sync::{
-    generate_sync, SyncMessage, SyncMessageEncodeError, SyncMessageV1, SyncNeedV1, SyncRejectionV1,
-    SyncRequestV1, SyncStateV1, SyncTraceContextV1,
+    generate_sync, ChecksummedChangeV1, SyncMessage, SyncMessageEncodeError, SyncMessageV1,
+    SyncNeedV1, SyncRejectionV1, SyncRequestV1, SyncStateV1, SyncTraceContextV1,
 };
 use futures::stream::FuturesUnordered;
 use futures::{Future, Stream, TryFutureExt, TryStreamExt};
@@ -136,8 +136,13 @@ async fn build_quinn_server_config(config: &GossipConfig) -> eyre::Result<quinn:
             .as_ref()
             .ok_or_else(|| eyre::eyre!("either plaintext or a tls config is required"))?;
 
-        let key = tokio::fs::read(&tls.key_file).await?;
-        let key = if tls.key_file.extension().map_or(false, |x| x == "der") {
+        let key = tokio::fs::read(tls.key_file.expose()).await?;
+        let key = if tls
+            .key_file
+            .expose()
+            .extension()
+            .map_or(false, |x| x == "der")
+        {
             rustls::PrivateKey(key)
         } else {
             let pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut &*key)?;
@@ -232,7 +237,7 @@ fn client_cert_auth(
     let mut key_file = std::io::BufReader::new(
         std::fs::OpenOptions::new()
             .read(true)
-            .open(&config.key_file)?,
+            .open(config.key_file.expose())?,
     );
     let key = rustls_pemfile::pkcs8_private_keys(&mut key_file)?
         .into_iter()
@@ -345,6 +350,26 @@ const MIN_CHANGES_BYTES_PER_MESSAGE: usize = 1024;
 
 const ADAPT_CHUNK_SIZE_THRESHOLD: Duration = Duration::from_millis(500);
 
+/// How changes are sent to the peer on the other end of a sync, as
+/// negotiated in its `SyncStart`
+#[derive(Debug, Default, Clone, Copy)]
+struct SyncEncoding {
+    /// Compress values at least this many bytes large
+    compress_over: Option<usize>,
+    /// Send changes w/ their origin timestamp
+    change_ts: bool,
+    /// Send changesets checksummed
+    checksums: bool,
+}
+
+impl SyncEncoding {
+    fn chunked<I: Iterator>(&self, chunked: ChunkedChanges<I>, ts: Timestamp) -> ChunkedChanges<I> {
+        chunked
+            .compress_over(self.compress_over)
+            .origin_ts(self.change_ts.then(|| ts.to_ntp64().0))
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn handle_known_version(
     conn: &mut Connection,
@@ -357,6 +382,7 @@ fn handle_known_version(
     last_seq: i64,
     ts: Timestamp,
     sender: &Sender<SyncMessage>,
+    encoding: SyncEncoding,
 ) -> eyre::Result<()> {
     debug!(%actor_id, %version, "handle known version! known: {init_known:?}, seqs_needed: {seqs_needed:?}");
     let mut seqs_iter = seqs_needed.into_iter();
@@ -409,7 +435,15 @@ fn handle_known_version(
 
                 send_change_chunks(
                     sender,
-                    ChunkedChanges::new(rows, *start_seq, *end_seq, MAX_CHANGES_BYTES_PER_MESSAGE),
+                    encoding.chunked(
+                        ChunkedChanges::new(
+                            rows,
+                            *start_seq,
+                            *end_seq,
+                            MAX_CHANGES_BYTES_PER_MESSAGE,
+                        ),
+                        ts,
+                    ),
                     actor_id,
                     version,
                     last_seq,
@@ -497,6 +531,7 @@ fn handle_known_version(
                                 last_seq,
                                 ts,
                                 sender,
+                                encoding,
                             );
                         }
 
@@ -525,11 +560,14 @@ fn handle_known_version(
 
                         send_change_chunks(
                             sender,
-                            ChunkedChanges::new(
-                                rows,
-                                *start_seq,
-                                *end_seq,
-                                MAX_CHANGES_BYTES_PER_MESSAGE,
+                            encoding.chunked(
+                                ChunkedChanges::new(
+                                    rows,
+                                    *start_seq,
+                                    *end_seq,
+                                    MAX_CHANGES_BYTES_PER_MESSAGE,
+                                ),
+                                ts,
                             ),
                             actor_id,
                             version,
@@ -561,6 +599,7 @@ async fn process_version(
     booked: &Booked,
     mut seqs_needed: Vec<RangeInclusive<i64>>,
     sender: &Sender<SyncMessage>,
+    encoding: SyncEncoding,
 ) -> eyre::Result<()> {
     let mut conn = pool.read().await?;
 
@@ -589,6 +628,7 @@ async fn process_version(
             last_seq,
             ts,
             sender,
+            encoding,
         )
     })?;
 
@@ -661,6 +701,7 @@ async fn process_sync(
     bookie: Bookie,
     sender: Sender<SyncMessage>,
     recv: mpsc::Receiver<SyncRequestV1>,
+    encoding: SyncEncoding,
 ) -> eyre::Result<()> {
     let chunked_reqs = ReceiverStream::new(recv).chunks_timeout(10, Duration::from_millis(500));
     tokio::pin!(chunked_reqs);
@@ -775,6 +816,7 @@ async fn process_sync(
                             &booked,
                             vec![],
                             &sender,
+                            encoding,
                         )
                         .await
                     }))
@@ -799,6 +841,7 @@ async fn process_sync(
                             &booked,
                             seqs_needed,
                             &sender,
+                            encoding,
                         )
                         .await
                     }))
@@ -955,7 +998,7 @@ pub async fn parallel_sync(
                         &mut codec,
                         &mut encode_buf,
                         &mut send_buf,
-                        BiPayload::V1(BiPayloadV1::SyncStart {actor_id: agent.actor_id(), trace_ctx}),
+                        BiPayload::V1(BiPayloadV1::SyncStart {actor_id: agent.actor_id(), trace_ctx, accepts_compression: agent.config().gossip.compression.enabled, accepts_change_ts: true, accepts_checksums: agent.config().gossip.checksums}),
                         &mut tx,
                     ).instrument(info_span!("write_sync_start"))
                     .await?;
@@ -1050,7 +1093,7 @@ pub async fn parallel_sync(
                     trace!(%actor_id, "no needs!");
                     return (readers, servers);
                 }
-                readers.push((actor_id, read));
+                readers.push((actor_id, addr, read));
 
                 trace!(%actor_id, "needs: {needs:?}");
 
@@ -1217,7 +1260,7 @@ pub async fn parallel_sync(
 
     // now handle receiving changesets!
 
-    let counts = FuturesUnordered::from_iter(readers.into_iter().map(|(actor_id, mut read)| {
+    let counts = FuturesUnordered::from_iter(readers.into_iter().map(|(actor_id, addr, mut read)| {
         let tx_changes = agent.tx_changes().clone();
         async move {
             let mut count = 0;
@@ -1232,6 +1275,18 @@ pub async fn parallel_sync(
                         break;
                     }
                     Ok(Some(msg)) => match msg {
+                        SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(sealed)) => {
+                            let Some(change) = open_changeset(actor_id, addr, &sealed) else {
+                                continue;
+                            };
+                            let changes_len = cmp::max(change.len(), 1);
+                            count += changes_len;
+                            counter!("corro.sync.changes.recv", changes_len as u64, "actor_id" => actor_id.to_string());
+                            tx_changes
+                                .send((change, ChangeSource::Sync))
+                                .await
+                                .map_err(|_| SyncRecvError::ChangesChannelClosed)?;
+                        }
                         SyncMessage::V1(SyncMessageV1::Changeset(change)) => {
                             let changes_len = cmp::max(change.len(), 1);
                             // tracing::Span::current().record("changes_len", changes_len);
@@ -1278,11 +1333,33 @@ pub async fn parallel_sync(
     Ok(counts.into_iter().flatten().sum::<usize>())
 }
 
+/// Decodes a checksummed changeset received from `actor_id`, unless it was
+/// corrupted in transit. Corrupted changesets are dropped: their versions stay
+/// needed, the next sync requests them again.
+fn open_changeset(
+    actor_id: ActorId,
+    addr: SocketAddr,
+    sealed: &ChecksummedChangeV1,
+) -> Option<ChangeV1> {
+    match sealed.open() {
+        Ok(change) => Some(change),
+        Err(e) => {
+            warn!(%actor_id, %addr, "dropping changeset received via sync: {e}");
+            increment_counter!("corro.sync.checksum.mismatch", "actor_id" => actor_id.to_string(), "addr" => addr.to_string());
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(skip(agent, their_actor_id, read, write), fields(actor_id = %their_actor_id), err)]
 pub async fn serve_sync(
     agent: &Agent,
     their_actor_id: ActorId,
     trace_ctx: SyncTraceContextV1,
+    accepts_compression: bool,
+    accepts_change_ts: bool,
+    accepts_checksums: bool,
     mut read: FramedRead<RecvStream, LengthDelimitedCodec>,
     mut write: SendStream,
 ) -> Result<usize, SyncError> {
@@ -1357,6 +1434,16 @@ pub async fn serve_sync(
     let (tx_need, rx_need) = mpsc::channel(1024);
     let (tx, mut rx) = mpsc::channel::<SyncMessage>(256);
 
+    // only compress for peers which can decompress, extend changes for
+    // peers which can read the extensions and checksum for peers which verify
+    let encoding = SyncEncoding {
+        compress_over: accepts_compression
+            .then(|| agent.config().gossip.compression.threshold())
+            .flatten(),
+        change_ts: accepts_change_ts,
+        checksums: accepts_checksums,
+    };
+
     tokio::spawn(
         process_sync(
             agent.actor_id(),
@@ -1364,6 +1451,7 @@ pub async fn serve_sync(
             agent.bookie().clone(),
             tx,
             rx_need,
+            encoding,
         )
         .instrument(info_span!("process_sync"))
         .inspect_err(|e| error!("could not process sync request: {e}")),
@@ -1399,6 +1487,15 @@ pub async fn serve_sync(
                             if let SyncMessage::V1(SyncMessageV1::Changeset(change)) = &msg {
                                 count += change.len();
                             }
+                            let msg = match msg {
+                                SyncMessage::V1(SyncMessageV1::Changeset(change)) if encoding.checksums => {
+                                    SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(
+                                        ChecksummedChangeV1::seal(&change)
+                                            .map_err(|e| SyncSendError::from(SyncMessageEncodeError::from(e)))?,
+                                    ))
+                                }
+                                msg => msg,
+                            };
                             encode_sync_msg(&mut codec, &mut encode_buf, &mut send_buf, msg)?;
 
                             if send_buf.len() >= 16 * 1024 {
@@ -1460,7 +1557,9 @@ pub async fn serve_sync(
                                 .await
                                 .map_err(|_| SyncRecvError::RequestsChannelClosed)?;
                         }
-                        SyncMessage::V1(SyncMessageV1::Changeset(_)) => {
+                        SyncMessage::V1(
+                            SyncMessageV1::Changeset(_) | SyncMessageV1::ChecksummedChangeset(_),
+                        ) => {
                             warn!(actor_id = %their_actor_id, "received sync changeset message unexpectedly, ignoring");
                             continue;
                         }
@@ -1552,8 +1651,10 @@ mod tests {
             col_version: 1,
             db_version: 1,
             seq: 0,
-            site_id: actor_id.to_bytes(),
+            site_id: actor_id.site_id(),
             cl: 1,
+            compressed: None,
+            ts: None,
         };
 
         let change2 = Change {
@@ -1564,8 +1665,10 @@ mod tests {
             col_version: 1,
             db_version: 2,
             seq: 0,
-            site_id: actor_id.to_bytes(),
+            site_id: actor_id.site_id(),
             cl: 1,
+            compressed: None,
+            ts: None,
         };
 
         process_multiple_changes(
@@ -1658,6 +1761,7 @@ mod tests {
                     0,
                     ts,
                     &tx,
+                    SyncEncoding::default(),
                 )
             })?;
 
@@ -1688,6 +1792,7 @@ mod tests {
                     0,
                     ts,
                     &tx,
+                    SyncEncoding::default(),
                 )
             })?;
 
@@ -1718,6 +1823,114 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drops_corrupted_changesets_until_resent() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .checksums(true)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _res) =
+            api_v1_db_schema(Extension(agent.clone()), Json(vec![TEST_SCHEMA.to_owned()])).await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let addr: SocketAddr = "127.0.0.1:8787".parse()?;
+
+        let changeset = ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version: 1,
+                changes: vec![Change {
+                    table: TableName("tests".into()),
+                    pk: pack_columns(&vec![1i64.into()])?,
+                    cid: ColumnName("text".into()),
+                    val: "one".into(),
+                    col_version: 1,
+                    db_version: 1,
+                    seq: 0,
+                    site_id: actor_id.site_id(),
+                    cl: 1,
+                    compressed: None,
+                    ts: None,
+                }],
+                seqs: 0..=0,
+                last_seq: 0,
+                ts: agent.clock().new_timestamp().into(),
+            },
+        };
+
+        // the peer's first attempt gets corrupted on the way, then it's sent again
+        let sealed = ChecksummedChangeV1::seal(&changeset)?;
+        let mut corrupted = sealed.clone();
+        *corrupted.payload.last_mut().unwrap() ^= 0x01;
+
+        let mut codec = LengthDelimitedCodec::new();
+        let mut encode_buf = BytesMut::new();
+        let mut send_buf = BytesMut::new();
+        for sealed in [corrupted, sealed] {
+            encode_sync_msg(
+                &mut codec,
+                &mut encode_buf,
+                &mut send_buf,
+                SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(sealed)),
+            )?;
+        }
+        let mut read = FramedRead::new(&send_buf[..], LengthDelimitedCodec::new());
+
+        let Some(SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(sealed))) =
+            read_sync_msg(&mut read).await?
+        else {
+            panic!("expected a checksummed changeset");
+        };
+        assert!(open_changeset(actor_id, addr, &sealed).is_none());
+
+        // the version is still needed from the peer, so it's requested again
+        let their_state = SyncStateV1 {
+            actor_id,
+            heads: [(actor_id, 1)].into(),
+            ..Default::default()
+        };
+        let our_state = generate_sync(agent.bookie(), agent.actor_id()).await;
+        assert_eq!(
+            our_state.compute_available_needs(&their_state),
+            [(actor_id, vec![SyncNeedV1::Full { versions: 1..=1 }])].into()
+        );
+
+        let Some(SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(sealed))) =
+            read_sync_msg(&mut read).await?
+        else {
+            panic!("expected a checksummed changeset");
+        };
+        let resent = open_changeset(actor_id, addr, &sealed).expect("intact changeset dropped");
+        assert_eq!(resent, changeset);
+        process_multiple_changes(&agent, vec![(resent, ChangeSource::Sync)]).await?;
+
+        let text: String = agent.pool().read().await?.query_row(
+            "SELECT text FROM tests WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(text, "one");
+
+        let our_state = generate_sync(agent.bookie(), agent.actor_id()).await;
+        assert!(our_state.compute_available_needs(&their_state).is_empty());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_mutual_tls() -> eyre::Result<()> {
         let ca_cert = generate_ca()?;
@@ -1755,11 +1968,11 @@ mod tests {
             bootstrap: vec![],
             tls: Some(TlsConfig {
                 cert_file,
-                key_file,
+                key_file: key_file.into(),
                 ca_file: Some(ca_file),
                 client: Some(TlsClientConfig {
                     cert_file: client_cert_file,
-                    key_file: client_key_file,
+                    key_file: client_key_file.into(),
                 }),
                 insecure: false,
             }),
@@ -1767,6 +1980,10 @@ mod tests {
             plaintext: false,
             max_mtu: None,
             disable_gso: false,
+            compression: Default::default(),
+            checksums: false,
+            probe: None,
+            node_name: None,
         };
 
         let server = gossip_server_endpoint(&gossip_config).await?;
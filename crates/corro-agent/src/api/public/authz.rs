@@ -0,0 +1,137 @@
+//! Access policies: bearer tokens restricted to reading some tables and
+//! columns, see `AccessPolicy`. Statements are prepared w/ sqlite's
+//! authorizer installed to find out everything they'd read, before the
+//! request reaches its handler.
+
+use axum::{
+    body::Body,
+    http::{Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use corro_types::{
+    agent::Agent,
+    api::{AccessDenied, DeniedObject, Statement},
+    config::AccessPolicy,
+    sqlite::statement_access,
+};
+use metrics::increment_counter;
+use tokio::task::block_in_place;
+use tracing::debug;
+use uuid::Uuid;
+
+use super::pubsub::SharedMatcherIdCache;
+
+/// Checks `request` against `policy`, returning it intact when allowed.
+///
+/// Only reads are allowed: queries (single or multi-statement), subscriptions
+/// (new, by id and rebinds) and explaining a query. The health endpoint is always allowed.
+pub async fn authorize_policy(
+    agent: &Agent,
+    sub_cache: &SharedMatcherIdCache,
+    policy: &AccessPolicy,
+    request: Request<Body>,
+) -> Result<Request<Body>, Response> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    let (request, sqls) = match (method, segments.as_slice()) {
+        (Method::GET, ["v1", "health"]) => return Ok(request),
+        (Method::POST, ["v1", "queries" | "subscriptions" | "explain"])
+        | (Method::POST, ["v1", "subscriptions", _, "rebind"]) => {
+            let (parts, body) = request.into_parts();
+            let bytes = hyper::body::to_bytes(body)
+                .await
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+            // registered queries are checked against their template
+            match serde_json::from_slice::<Statement>(&bytes)
+                .ok()
+                .and_then(|stmt| agent.query_registry().resolve(stmt).ok())
+            {
+                Some((stmt, _)) => {
+                    let sql = stmt.query().to_owned();
+                    (Request::from_parts(parts, Body::from(bytes)), vec![sql])
+                }
+                // the handler rejects it
+                None => return Ok(Request::from_parts(parts, Body::from(bytes))),
+            }
+        }
+        (Method::POST, ["v1", "queries", "multi"]) => {
+            let (parts, body) = request.into_parts();
+            let bytes = hyper::body::to_bytes(body)
+                .await
+                .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+
+            // every statement has to be allowed
+            match serde_json::from_slice::<Vec<Statement>>(&bytes)
+                .ok()
+                .and_then(|stmts| {
+                    stmts
+                        .into_iter()
+                        .map(|stmt| {
+                            agent
+                                .query_registry()
+                                .resolve(stmt)
+                                .ok()
+                                .map(|(stmt, _)| stmt.query().to_owned())
+                        })
+                        .collect::<Option<Vec<_>>>()
+                }) {
+                Some(sqls) => (Request::from_parts(parts, Body::from(bytes)), sqls),
+                // the handler rejects it
+                None => return Ok(Request::from_parts(parts, Body::from(bytes))),
+            }
+        }
+        (Method::GET, ["v1", "subscriptions", id]) => {
+            let Ok(id) = id.parse::<Uuid>() else {
+                return Ok(request);
+            };
+            let sql = sub_cache
+                .read()
+                .await
+                .iter()
+                .find(|(_, matcher_id)| **matcher_id == id)
+                .map(|(sql, _)| sql.clone());
+            match sql {
+                Some(sql) => (request, vec![sql]),
+                // unknown subscriptions get a 404 from the handler
+                None if !agent.matchers().read().contains_key(&id) => return Ok(request),
+                None => return Err(denied(DeniedObject::Route { path: path.clone() })),
+            }
+        }
+        _ => return Err(denied(DeniedObject::Route { path: path.clone() })),
+    };
+
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())?;
+    for sql in sqls {
+        let access = block_in_place(|| statement_access(&conn, &sql))
+            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()).into_response())?;
+        if let Some(object) = policy.first_denied(&access) {
+            return Err(denied(object));
+        }
+    }
+
+    Ok(request)
+}
+
+fn denied(object: DeniedObject) -> Response {
+    let kind = match object {
+        DeniedObject::Table { .. } => "table",
+        DeniedObject::Column { .. } => "column",
+        DeniedObject::Action { .. } => "action",
+        DeniedObject::Route { .. } => "route",
+    };
+    increment_counter!("corro.api.access.denied", "kind" => kind);
+    debug!("denied access to {object}");
+
+    (
+        StatusCode::FORBIDDEN,
+        axum::Json(AccessDenied { denied: object }),
+    )
+        .into_response()
+}
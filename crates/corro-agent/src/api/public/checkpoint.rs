@@ -0,0 +1,257 @@
+//! WAL checkpoints: the WAL is truncated periodically, and as soon as it grows
+//! over `db.checkpoint.max_wal_bytes`. Readers holding a snapshot keep it from
+//! being truncated, subscriptions holding one for too long are ended.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::{http::StatusCode, Extension};
+use camino::Utf8PathBuf;
+use corro_types::{
+    agent::{Agent, PoolError},
+    api::{CheckpointResult, ExecResult, HeldSnapshot},
+};
+use metrics::{histogram, increment_counter};
+use tokio::task::block_in_place;
+use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CheckpointError {
+    #[error(transparent)]
+    Pool(#[from] PoolError),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// how often the WAL size and subscriptions' snapshots are checked, when
+// bounded
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn wal_size_bytes(agent: &Agent) -> std::io::Result<u64> {
+    match std::fs::metadata(Utf8PathBuf::from(format!("{}-wal", agent.db_path()))) {
+        Ok(meta) => Ok(meta.len()),
+        // there's no WAL file until something gets written
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Subscriptions holding a read snapshot, oldest first
+pub fn held_snapshots(agent: &Agent) -> Vec<HeldSnapshot> {
+    let mut held: Vec<_> = agent
+        .matchers()
+        .read()
+        .iter()
+        .filter_map(|(id, handle)| {
+            handle.snapshot_age().map(|age| HeldSnapshot {
+                subscription_id: *id,
+                held_secs: age.as_secs_f64(),
+            })
+        })
+        .collect();
+    held.sort_by(|a, b| b.held_secs.total_cmp(&a.held_secs));
+    held
+}
+
+/// Checkpoints the WAL and truncates it, unless readers are still using it.
+/// Subscriptions holding a snapshot at the time are named when that happens.
+pub async fn checkpoint(agent: &Agent) -> Result<CheckpointResult, CheckpointError> {
+    debug!("checkpointing and truncating the WAL");
+    let conn = agent.pool().write_low().await?;
+
+    let (busy, wal_frames, checkpointed_frames, wal_size_bytes) = block_in_place(|| {
+        let start = Instant::now();
+
+        let res = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| {
+            Ok((row.get::<_, bool>(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        if !res.0 {
+            histogram!(
+                "corro.db.wal.truncate.seconds",
+                start.elapsed().as_secs_f64()
+            );
+        }
+        // nothing else writes while the write connection is held
+        Ok::<_, CheckpointError>((res.0, res.1, res.2, wal_size_bytes(agent)?))
+    })?;
+    drop(conn);
+
+    let held_snapshots = held_snapshots(agent);
+    if busy {
+        increment_counter!("corro.db.wal.truncate.busy");
+        match held_snapshots.first() {
+            Some(oldest) => warn!(
+                "could not truncate sqlite WAL, database busy. {} subscription(s) hold a snapshot, {} for {:.1}s",
+                held_snapshots.len(),
+                oldest.subscription_id,
+                oldest.held_secs
+            ),
+            None => warn!("could not truncate sqlite WAL, database busy"),
+        }
+    } else {
+        debug!("successfully truncated sqlite WAL!");
+        agent.set_last_checkpoint_at(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        );
+    }
+
+    Ok(CheckpointResult {
+        busy,
+        wal_frames,
+        checkpointed_frames,
+        wal_size_bytes,
+        held_snapshots,
+    })
+}
+
+/// Ends the subscriptions holding a snapshot for longer than `max_age`,
+/// returning how many were
+pub fn expire_snapshots(agent: &Agent, max_age: Duration) -> usize {
+    let mut expired = 0;
+    for (id, handle) in agent.matchers().read().iter() {
+        match handle.snapshot_age() {
+            Some(age) if age > max_age => {
+                if handle.expire_snapshot() {
+                    info!(%id, "subscription held a snapshot for {age:?}, expiring it");
+                    increment_counter!("corro.subscriptions.snapshots.expired");
+                    expired += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    expired
+}
+
+pub async fn checkpoint_loop(agent: Agent, mut tripwire: Tripwire) {
+    let config = agent.config().db.checkpoint;
+
+    // the WAL was just opened, there's no point truncating it right away
+    let period = Duration::from_secs(config.interval_secs.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    let mut check_interval = tokio::time::interval(CHECK_INTERVAL);
+    let bounded = config.max_wal_bytes.is_some() || config.max_snapshot_secs.is_some();
+
+    loop {
+        enum Branch {
+            Checkpoint,
+            Check,
+        }
+
+        let branch = tokio::select! {
+            _ = interval.tick() => Branch::Checkpoint,
+            _ = check_interval.tick(), if bounded => Branch::Check,
+            _ = &mut tripwire => {
+                break;
+            }
+        };
+
+        if let Branch::Check = branch {
+            if let Some(max_snapshot_secs) = config.max_snapshot_secs {
+                expire_snapshots(&agent, Duration::from_secs(max_snapshot_secs));
+            }
+
+            let Some(max_wal_bytes) = config.max_wal_bytes else {
+                continue;
+            };
+            match wal_size_bytes(&agent) {
+                Ok(size) if size > max_wal_bytes => {
+                    debug!("WAL is {size} bytes, over {max_wal_bytes} bytes");
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("could not read WAL size: {e}");
+                    continue;
+                }
+            }
+        }
+
+        if let Err(e) = checkpoint(&agent).await {
+            error!("could not checkpoint WAL: {e}");
+        }
+    }
+}
+
+/// Forces a WAL checkpoint, see `checkpoint`
+pub async fn api_v1_db_checkpoint(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<CheckpointResult>, (StatusCode, axum::Json<ExecResult>)> {
+    checkpoint(&agent).await.map(axum::Json).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            }),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_tests::launch_test_agent;
+    use corro_types::api::Statement;
+    use spawn::wait_for_all_pending_handles;
+
+    use super::*;
+    use crate::api::public::health::check_health;
+
+    async fn insert(client: &corro_client::CorrosionApiClient, id: i64) -> eyre::Result<()> {
+        client
+            .execute(&[Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                vec![id.into(), "hello".into()],
+            )])
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_checkpoint_blocked_by_reader() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+
+        insert(&client, 1).await?;
+        assert_eq!(ta.agent.last_checkpoint_at(), 0);
+
+        let reader = ta.agent.pool().read().await?;
+        let read_tx = reader.unchecked_transaction()?;
+        let count: i64 = read_tx.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 1);
+
+        // past the reader's snapshot
+        insert(&client, 2).await?;
+
+        let res = checkpoint(&ta.agent).await?;
+        assert!(res.busy, "{res:?}");
+        assert!(res.wal_size_bytes > 0);
+        assert_eq!(ta.agent.last_checkpoint_at(), 0);
+        assert_eq!(check_health(&ta.agent).await?.last_checkpoint_at, 0);
+
+        drop(read_tx);
+        drop(reader);
+
+        let res = checkpoint(&ta.agent).await?;
+        assert!(!res.busy, "{res:?}");
+        assert_eq!(res.wal_size_bytes, 0);
+        let last_checkpoint_at = ta.agent.last_checkpoint_at();
+        assert!(last_checkpoint_at > 0);
+        assert_eq!(
+            check_health(&ta.agent).await?.last_checkpoint_at,
+            last_checkpoint_at
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
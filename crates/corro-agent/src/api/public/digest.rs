@@ -0,0 +1,439 @@
+//! Table digests, compared across nodes by `corrosion cluster check`: rows
+//! are hashed in primary key order and streamed back in chunks, see
+//! `corro_types::api::digest`.
+//!
+//! A digest is computed by a single statement on a read connection, so it
+//! hashes a consistent snapshot w/o blocking writes. It's paced to
+//! `api.digest_rows_per_sec`.
+
+use std::{
+    convert::Infallible,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    response::{IntoResponse, Response},
+    Extension,
+};
+use bytes::Bytes;
+use corro_types::{
+    agent::Agent,
+    api::{
+        digest::{Digest, DigestChunk, DigestEvent, DigestRequest, DEFAULT_DIGEST_CHUNK_ROWS},
+        ColumnName, ExecResult, SqliteValue,
+    },
+    schema::Table,
+};
+use hyper::StatusCode;
+use itertools::Itertools;
+use metrics::{counter, histogram};
+use rusqlite::{params_from_iter, Connection};
+use seahash::SeaHasher;
+use tokio::{sync::mpsc, task::block_in_place};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+// rows hashed between checks of the pace
+const PACE_EVERY_ROWS: u64 = 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DigestError {
+    #[error("table '{0}' isn't in the schema")]
+    UnknownTable(String),
+    #[error("`{bound}` has {actual} values, the primary key has {expected} columns")]
+    BoundWidth {
+        bound: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("chunk_rows must be at least 1")]
+    ChunkRows,
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("digest receiver is gone")]
+    Aborted,
+}
+
+/// Statement reading the rows to hash, primary key columns first
+struct DigestQuery {
+    sql: String,
+    params: Vec<SqliteValue>,
+    columns: Vec<ColumnName>,
+    pk: usize,
+}
+
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+impl DigestQuery {
+    fn new(table: &Table, req: &DigestRequest) -> Result<Self, DigestError> {
+        if req.chunk_rows == Some(0) {
+            return Err(DigestError::ChunkRows);
+        }
+
+        let pk: Vec<&String> = table.pk.iter().collect();
+        let columns: Vec<&String> = pk
+            .iter()
+            .copied()
+            .chain(
+                table
+                    .columns
+                    .keys()
+                    .filter(|name| !table.pk.contains(*name)),
+            )
+            .collect();
+        let key = format!("({})", pk.iter().map(|name| quoted(name)).join(", "));
+
+        let mut conditions = vec![];
+        let mut params = vec![];
+        for (bound, op, values) in [("from", ">=", &req.from), ("to", "<", &req.to)] {
+            let Some(values) = values else {
+                continue;
+            };
+            if values.len() != pk.len() {
+                return Err(DigestError::BoundWidth {
+                    bound,
+                    expected: pk.len(),
+                    actual: values.len(),
+                });
+            }
+            // sqlite compares row values in order, like `ORDER BY` sorts them
+            conditions.push(format!("{key} {op} ({})", vec!["?"; pk.len()].join(", ")));
+            params.extend(values.iter().cloned());
+        }
+
+        let mut sql = format!(
+            "SELECT {} FROM {}",
+            columns.iter().map(|name| quoted(name)).join(", "),
+            quoted(&table.name)
+        );
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql.push_str(" ORDER BY ");
+        sql.push_str(&pk.iter().map(|name| quoted(name)).join(", "));
+
+        Ok(Self {
+            sql,
+            params,
+            columns: columns
+                .into_iter()
+                .map(|name| ColumnName(name.as_str().into()))
+                .collect(),
+            pk: pk.len(),
+        })
+    }
+}
+
+/// Hash of a row's values, in the order they're read
+pub fn row_hash(values: &[SqliteValue]) -> u64 {
+    let mut hasher = SeaHasher::new();
+    for value in values {
+        value.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+// a chunk being hashed
+struct ChunkState {
+    first: Vec<SqliteValue>,
+    hasher: SeaHasher,
+    rows: u64,
+}
+
+impl ChunkState {
+    fn finish(self, last: Vec<SqliteValue>) -> DigestChunk {
+        DigestChunk {
+            first: self.first,
+            last,
+            rows: self.rows,
+            hash: self.hasher.finish(),
+        }
+    }
+}
+
+// sleeps as long as it takes to hash at most `rows_per_sec`, unlimited if 0
+fn pace(start: Instant, rows: u64, rows_per_sec: u64) {
+    if rows_per_sec == 0 {
+        return;
+    }
+    let due = Duration::from_secs_f64(rows as f64 / rows_per_sec as f64);
+    if let Some(wait) = due.checked_sub(start.elapsed()) {
+        std::thread::sleep(wait);
+    }
+}
+
+/// Hashes the rows `query` reads, sending them (if `req.rows` is set) and
+/// their chunks as they're hashed, then the digest of them all
+fn digest_rows<F>(
+    conn: &Connection,
+    query: &DigestQuery,
+    req: &DigestRequest,
+    rows_per_sec: u64,
+    mut send: F,
+) -> Result<Digest, DigestError>
+where
+    F: FnMut(DigestEvent) -> Result<(), DigestError>,
+{
+    let start = Instant::now();
+    let chunk_rows = req.chunk_rows.unwrap_or(DEFAULT_DIGEST_CHUNK_ROWS);
+
+    send(DigestEvent::Columns {
+        names: query.columns.clone(),
+        pk: query.pk,
+    })?;
+
+    let mut prepped = conn.prepare(&query.sql)?;
+    let mut rows = prepped.query(params_from_iter(query.params.iter()))?;
+
+    let mut total = SeaHasher::new();
+    let mut count = 0;
+    let mut chunk: Option<ChunkState> = None;
+    let mut last = vec![];
+
+    while let Some(row) = rows.next()? {
+        let values = (0..query.columns.len())
+            .map(|i| row.get::<_, SqliteValue>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let hash = row_hash(&values);
+        total.write_u64(hash);
+        count += 1;
+
+        let state = chunk.get_or_insert_with(|| ChunkState {
+            first: values[..query.pk].to_vec(),
+            hasher: SeaHasher::new(),
+            rows: 0,
+        });
+        state.hasher.write_u64(hash);
+        state.rows += 1;
+        last = values[..query.pk].to_vec();
+
+        let full = state.rows == chunk_rows;
+        if req.rows {
+            send(DigestEvent::Row(values))?;
+        }
+        if full {
+            if let Some(state) = chunk.take() {
+                send(DigestEvent::Chunk(state.finish(std::mem::take(&mut last))))?;
+            }
+        }
+
+        if count % PACE_EVERY_ROWS == 0 {
+            pace(start, count, rows_per_sec);
+        }
+    }
+
+    if let Some(state) = chunk.take() {
+        send(DigestEvent::Chunk(state.finish(last)))?;
+    }
+
+    let digest = Digest {
+        rows: count,
+        hash: total.finish(),
+        time: start.elapsed().as_secs_f64(),
+    };
+    send(DigestEvent::Done(digest))?;
+
+    Ok(digest)
+}
+
+/// Streams the digest of a table's rows, or of a range of its primary keys,
+/// as `DigestEvent`s
+pub async fn api_v1_digests(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(req): axum::extract::Json<DigestRequest>,
+) -> Response {
+    let error = |status: StatusCode, error: String| {
+        (status, axum::Json(ExecResult::Error { error, code: None })).into_response()
+    };
+
+    let query = {
+        let schema = agent.schema().read();
+        let table = schema.tables.get(req.table.as_str()).or_else(|| {
+            schema
+                .tables
+                .values()
+                .find(|table| table.name.eq_ignore_ascii_case(&req.table))
+        });
+        match table {
+            Some(table) => DigestQuery::new(table, &req),
+            None => Err(DigestError::UnknownTable(req.table.to_string())),
+        }
+    };
+    let query = match query {
+        Ok(query) => query,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let conn = match agent.pool().read().await {
+        Ok(conn) => conn,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    let rows_per_sec = agent.config().api.digest_rows_per_sec;
+    let (tx, rx) = mpsc::channel::<Result<Bytes, Infallible>>(64);
+
+    tokio::spawn(async move {
+        let line = |event: &DigestEvent| {
+            let mut line = serde_json::to_vec(event).expect("could not serialize digest event");
+            line.push(b'\n');
+            Ok::<_, Infallible>(Bytes::from(line))
+        };
+
+        let res = block_in_place(|| {
+            digest_rows(&conn, &query, &req, rows_per_sec, |event| {
+                tx.blocking_send(line(&event))
+                    .map_err(|_| DigestError::Aborted)
+            })
+        });
+
+        match res {
+            Ok(digest) => {
+                counter!("corro.api.digest.rows", digest.rows);
+                histogram!("corro.api.digest.seconds", digest.time);
+                debug!(
+                    table = %req.table.as_str(),
+                    rows = digest.rows,
+                    "computed digest in {}s",
+                    digest.time
+                );
+            }
+            Err(DigestError::Aborted) => {
+                debug!("digest of {} aborted by its client", req.table.as_str());
+            }
+            Err(e) => {
+                warn!("could not compute digest of {}: {e}", req.table.as_str());
+                _ = tx.send(line(&DigestEvent::Error(e.to_string()))).await;
+            }
+        }
+    });
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .body(hyper::Body::wrap_stream(ReceiverStream::new(rx)))
+        .expect("could not build digest response body")
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::schema::parse_sql;
+
+    use super::*;
+
+    const SCHEMA: &str = "CREATE TABLE kv (ns TEXT NOT NULL, id INTEGER NOT NULL, value TEXT, PRIMARY KEY (ns, id));";
+
+    fn digest(conn: &Connection, req: &DigestRequest) -> (Vec<DigestEvent>, Digest) {
+        let schema = parse_sql(SCHEMA).unwrap();
+        let query = DigestQuery::new(&schema.tables["kv"], req).unwrap();
+        let mut events = vec![];
+        let digest = digest_rows(conn, &query, req, 0, |event| {
+            events.push(event);
+            Ok(())
+        })
+        .unwrap();
+        (events, digest)
+    }
+
+    fn chunks(events: &[DigestEvent]) -> Vec<&DigestChunk> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                DigestEvent::Chunk(chunk) => Some(chunk),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn key(ns: &str, id: i64) -> Vec<SqliteValue> {
+        vec![SqliteValue::Text(ns.into()), SqliteValue::Integer(id)]
+    }
+
+    #[test]
+    fn digests_independently_of_chunks() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        for ns in ["a", "b"] {
+            for id in 1..=5 {
+                conn.execute(
+                    "INSERT INTO kv VALUES (?, ?, ?)",
+                    rusqlite::params![ns, id, format!("{ns}{id}")],
+                )
+                .unwrap();
+            }
+        }
+
+        let (events, whole) = digest(&conn, &DigestRequest::new("kv"));
+        assert_eq!(whole.rows, 10);
+        assert_eq!(
+            events[0],
+            DigestEvent::Columns {
+                names: vec![
+                    ColumnName("ns".into()),
+                    ColumnName("id".into()),
+                    ColumnName("value".into())
+                ],
+                pk: 2
+            }
+        );
+        assert_eq!(chunks(&events).len(), 1);
+
+        let (events, chunked) = digest(&conn, &DigestRequest::new("kv").chunk_rows(3));
+        assert_eq!(chunked, whole);
+        let chunks = chunks(&events);
+        assert_eq!(
+            chunks.iter().map(|chunk| chunk.rows).collect::<Vec<_>>(),
+            vec![3, 3, 3, 1]
+        );
+        assert_eq!(chunks[0].first, key("a", 1));
+        assert_eq!(chunks[0].last, key("a", 3));
+        assert_eq!(chunks[1].first, key("a", 4));
+        assert_eq!(chunks[1].last, key("b", 1));
+        assert_eq!(chunks[3].first, key("b", 5));
+
+        // the digest of a chunk's range is the chunk's hash
+        let (events, range) = digest(
+            &conn,
+            &DigestRequest::new("kv")
+                .range(Some(key("a", 4)), Some(key("b", 2)))
+                .with_rows(),
+        );
+        assert_eq!(range.rows, 3);
+        assert_eq!(range.hash, chunks[1].hash);
+        let rows = events
+            .iter()
+            .filter(|event| matches!(event, DigestEvent::Row(_)))
+            .count();
+        assert_eq!(rows, 3);
+
+        conn.execute("UPDATE kv SET value = 'x' WHERE ns = 'b' AND id = 1", [])
+            .unwrap();
+        let (_, changed) = digest(&conn, &DigestRequest::new("kv"));
+        assert_eq!(changed.rows, 10);
+        assert_ne!(changed, whole);
+    }
+
+    #[test]
+    fn rejects_bad_bounds() {
+        let schema = parse_sql(SCHEMA).unwrap();
+        let table = &schema.tables["kv"];
+        assert!(matches!(
+            DigestQuery::new(
+                table,
+                &DigestRequest::new("kv").range(Some(vec![SqliteValue::Integer(1)]), None)
+            ),
+            Err(DigestError::BoundWidth {
+                bound: "from",
+                expected: 2,
+                actual: 1
+            })
+        ));
+        assert!(matches!(
+            DigestQuery::new(table, &DigestRequest::new("kv").chunk_rows(0)),
+            Err(DigestError::ChunkRows)
+        ));
+    }
+}
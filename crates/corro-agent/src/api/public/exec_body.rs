@@ -0,0 +1,320 @@
+//! `/v1/transactions` bodies holding a plain array of statements are parsed
+//! one statement at a time as they're received, and each statement runs as
+//! soon as it's parsed: memory use is bounded by the largest statement rather
+//! than by the whole batch.
+
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+use bytes::{Buf, Bytes};
+use corro_types::{agent::ChangeError, api::Statement};
+use futures::StreamExt;
+use http_body::{Body as HttpBody, LengthLimitError, Limited};
+use serde::de::{Deserializer, SeqAccess, Visitor};
+use tokio::sync::mpsc;
+
+/// Statements parsed ahead of the one running
+const STATEMENTS_BUFFER: usize = 16;
+/// Body chunks received ahead of the parser
+const CHUNKS_BUFFER: usize = 4;
+
+/// A request body w/ its leading whitespace read, to tell what it holds
+pub struct PeekedBody {
+    /// Rest of the chunk w/ the first byte besides whitespace, if any
+    first: Option<Bytes>,
+    rest: hyper::Body,
+    /// Bytes of whitespace read before `first`
+    skipped: usize,
+}
+
+impl PeekedBody {
+    /// Reads `body` up to its first byte besides whitespace, or past
+    /// `max_bytes` of whitespace
+    pub async fn peek(mut body: hyper::Body, max_bytes: Option<usize>) -> hyper::Result<Self> {
+        let mut skipped = 0;
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk?;
+            match chunk.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(start) => {
+                    return Ok(Self {
+                        first: Some(chunk.slice(start..)),
+                        rest: body,
+                        skipped: skipped + start,
+                    })
+                }
+                None => skipped += chunk.len(),
+            }
+            if max_bytes.map_or(false, |max| skipped > max) {
+                break;
+            }
+        }
+
+        Ok(Self {
+            first: None,
+            rest: body,
+            skipped,
+        })
+    }
+
+    /// Whether the body holds a JSON array, as far as its first byte tells
+    pub fn is_array(&self) -> bool {
+        self.first
+            .as_ref()
+            .map_or(false, |chunk| chunk.first() == Some(&b'['))
+    }
+
+    /// The body w/o its leading whitespace, erroring w/ `LengthLimitError`
+    /// once the whole of it is over `max_bytes`
+    pub fn into_body(self, max_bytes: Option<usize>) -> Limited<hyper::Body> {
+        let first = futures::stream::iter(self.first.map(Ok::<_, hyper::Error>));
+        Limited::new(
+            hyper::Body::wrap_stream(first.chain(self.rest)),
+            max_bytes.map_or(usize::MAX, |max| max.saturating_sub(self.skipped)),
+        )
+    }
+}
+
+/// Parses the statements of `body`, a JSON array, on a blocking task. They're
+/// received in order, at most `STATEMENTS_BUFFER` of them ahead of the
+/// receiver, followed by the error ending the array early if there's one.
+pub fn stream_statements(
+    body: Limited<hyper::Body>,
+) -> mpsc::Receiver<Result<Statement, ChangeError>> {
+    let (chunks_tx, chunks_rx) = mpsc::channel(CHUNKS_BUFFER);
+    tokio::spawn(forward_chunks(body, chunks_tx));
+
+    let (tx, rx) = mpsc::channel(STATEMENTS_BUFFER);
+    tokio::task::spawn_blocking(move || parse_statements(BodyReader::new(chunks_rx), &tx));
+    rx
+}
+
+async fn forward_chunks(mut body: Limited<hyper::Body>, tx: mpsc::Sender<io::Result<Bytes>>) {
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        let failed = chunk.is_err();
+        if tx.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Reads the chunks of a body forwarded by `forward_chunks`, blocking until
+/// they're received
+struct BodyReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl BodyReader {
+    fn new(chunks: mpsc::Receiver<io::Result<Bytes>>) -> Self {
+        Self {
+            chunks,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.chunk.has_remaining() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.remaining());
+        self.chunk.copy_to_slice(&mut buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Sends each statement of `reader`'s top level array as soon as it's
+/// parsed, then the error ending the array early if there's one. Stops once
+/// nothing receives them anymore.
+fn parse_statements<R: Read>(reader: R, tx: &mpsc::Sender<Result<Statement, ChangeError>>) {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let mut parsed = 0;
+
+    let res = match (&mut de).deserialize_seq(Statements {
+        tx,
+        parsed: &mut parsed,
+    }) {
+        Ok(true) => de.end(),
+        // the receiver is gone, whatever's left doesn't matter
+        Ok(false) => return,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = res {
+        _ = tx.blocking_send(Err(body_error(parsed, e)));
+    }
+}
+
+// statement `index` is malformed, unless the body itself failed
+fn body_error(index: usize, e: serde_json::Error) -> ChangeError {
+    if e.is_io() {
+        let e = io::Error::from(e);
+        if e.get_ref().map_or(false, |e| e.is::<LengthLimitError>()) {
+            return ChangeError::BodyTooLarge("request body is over api.max_body_bytes".to_owned());
+        }
+        return ChangeError::Malformed {
+            index,
+            error: e.to_string(),
+        };
+    }
+
+    ChangeError::Malformed {
+        index,
+        error: e.to_string(),
+    }
+}
+
+// sends an array's statements as they're parsed, `false` if the receiver
+// went away before they all were
+struct Statements<'a> {
+    tx: &'a mpsc::Sender<Result<Statement, ChangeError>>,
+    parsed: &'a mut usize,
+}
+
+impl<'de> Visitor<'de> for Statements<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of statements")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<bool, A::Error> {
+        while let Some(stmt) = seq.next_element::<Statement>()? {
+            if self.tx.blocking_send(Ok(stmt)).is_err() {
+                return Ok(false);
+            }
+            *self.parsed += 1;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    // an array of `total` statements, generated as it's read
+    struct Generated {
+        total: usize,
+        generated: Arc<AtomicUsize>,
+        buf: Vec<u8>,
+        pos: usize,
+    }
+
+    impl Read for Generated {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.buf.len() {
+                let next = self.generated.load(Ordering::SeqCst);
+                self.buf = match next {
+                    0 => b"[".to_vec(),
+                    n if n == self.total + 1 => b"]".to_vec(),
+                    n if n > self.total => return Ok(0),
+                    n => format!(
+                        "{}[\"INSERT INTO tests (id, text) VALUES (?, ?)\", [{n}, \"{}\"]]",
+                        if n > 1 { "," } else { "" },
+                        "x".repeat(1024)
+                    )
+                    .into_bytes(),
+                };
+                self.pos = 0;
+                self.generated.fetch_add(1, Ordering::SeqCst);
+            }
+
+            let len = out.len().min(self.buf.len() - self.pos);
+            out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn parses_ahead_of_the_receiver_by_a_bounded_amount() {
+        let generated = Arc::new(AtomicUsize::new(0));
+        let reader = Generated {
+            total: 5000,
+            generated: generated.clone(),
+            buf: vec![],
+            pos: 0,
+        };
+
+        let (tx, mut rx) = mpsc::channel(STATEMENTS_BUFFER);
+        let parser = std::thread::spawn(move || parse_statements(reader, &tx));
+
+        let mut received = 0;
+        while let Some(stmt) = rx.blocking_recv() {
+            let stmt = stmt.unwrap();
+            assert_eq!(stmt.params().count(), 2);
+            received += 1;
+
+            if received % 500 == 0 {
+                // give the parser time to get as far ahead as it can
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                // the opening bracket aside
+                let ahead = generated.load(Ordering::SeqCst) - 1 - received;
+                assert!(
+                    ahead <= STATEMENTS_BUFFER + 3,
+                    "{ahead} statements generated ahead of the receiver"
+                );
+            }
+        }
+        assert_eq!(received, 5000);
+        parser.join().unwrap();
+    }
+
+    fn parse(json: &str) -> Vec<Result<Statement, ChangeError>> {
+        let (tx, mut rx) = mpsc::channel(STATEMENTS_BUFFER);
+        parse_statements(json.as_bytes(), &tx);
+        drop(tx);
+
+        let mut parsed = vec![];
+        while let Ok(res) = rx.try_recv() {
+            parsed.push(res);
+        }
+        parsed
+    }
+
+    #[test]
+    fn reports_the_malformed_statement() {
+        let parsed = parse(r#"["SELECT 1", ["SELECT ?", [1]], 42, "SELECT 3"]"#);
+        assert_eq!(parsed.len(), 3);
+        assert!(parsed[..2].iter().all(Result::is_ok));
+        assert!(
+            matches!(&parsed[2], Err(ChangeError::Malformed { index: 2, .. })),
+            "{:?}",
+            parsed[2]
+        );
+
+        // missing a comma, or cut short
+        for json in [r#"["SELECT 1" "SELECT 2"]"#, r#"["SELECT 1", "SELE"#] {
+            let parsed = parse(json);
+            assert_eq!(parsed.len(), 2, "{json}");
+            assert!(
+                matches!(&parsed[1], Err(ChangeError::Malformed { index: 1, .. })),
+                "{json}: {:?}",
+                parsed[1]
+            );
+        }
+
+        let parsed = parse(r#"["SELECT 1"] trailing"#);
+        assert!(
+            matches!(&parsed[1], Err(ChangeError::Malformed { index: 1, .. })),
+            "{:?}",
+            parsed[1]
+        );
+
+        assert!(parse("[]").is_empty());
+    }
+}
@@ -0,0 +1,127 @@
+//! Stream handshake: query and subscription requests list the capabilities
+//! they want in the `CAPABILITIES_HEADER`. Responses carry the agent's
+//! protocol version and the capabilities it enabled, and streams of requests
+//! which asked start w/ a `QueryEvent::Hello` saying the same.
+
+use bytes::Bytes;
+use compact_str::CompactString;
+use corro_types::api::{
+    parse_capabilities, QueryEvent, CAPABILITIES_HEADER, CAPABILITY_BATCH, CAPABILITY_ORIGIN,
+    PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+};
+use hyper::HeaderMap;
+use uuid::Uuid;
+
+/// Capabilities of subscription streams
+pub const SUBSCRIPTION_CAPABILITIES: &[&str] = &[CAPABILITY_BATCH, CAPABILITY_ORIGIN];
+
+/// Capabilities of query streams
+pub const QUERY_CAPABILITIES: &[&str] = &[];
+
+#[derive(Debug, Default)]
+pub struct Handshake {
+    /// Whether the request carried the `CAPABILITIES_HEADER`, clients
+    /// predating the handshake don't and wouldn't expect a `Hello`
+    hello: bool,
+    /// Requested capabilities the agent supports
+    enabled: Vec<CompactString>,
+}
+
+impl Handshake {
+    /// Enables which of the capabilities requested in `headers` are
+    /// `supported`, unknown ones are left out
+    pub fn new(headers: &HeaderMap, supported: &[&str]) -> Self {
+        let Some(value) = headers.get(CAPABILITIES_HEADER) else {
+            return Self::default();
+        };
+
+        let enabled = value
+            .to_str()
+            .map(parse_capabilities)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|capability| supported.contains(&capability.as_str()))
+            .collect();
+
+        Self {
+            hello: true,
+            enabled,
+        }
+    }
+
+    pub fn is_enabled(&self, capability: &str) -> bool {
+        self.enabled.iter().any(|enabled| enabled == capability)
+    }
+
+    /// The `QueryEvent::Hello` line to start the stream w/, if asked for
+    pub fn hello(&self, sub_id: Option<Uuid>) -> Option<Bytes> {
+        if !self.hello {
+            return None;
+        }
+
+        let mut line = serde_json::to_vec(&QueryEvent::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: self.enabled.clone(),
+            sub_id,
+        })
+        .expect("could not serialize hello event");
+        line.push(b'\n');
+        Some(line.into())
+    }
+
+    /// Adds the protocol version and enabled capabilities headers, sent even
+    /// to clients which didn't ask so they can tell how old the agent is
+    pub fn headers(
+        &self,
+        builder: hyper::http::response::Builder,
+    ) -> hyper::http::response::Builder {
+        builder
+            .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION.to_string())
+            .header(CAPABILITIES_HEADER, self.enabled.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enables_supported_capabilities() {
+        let handshake = Handshake::new(&HeaderMap::new(), SUBSCRIPTION_CAPABILITIES);
+        assert!(handshake.hello(None).is_none());
+        assert!(!handshake.is_enabled(CAPABILITY_BATCH));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CAPABILITIES_HEADER, "origin, pings".parse().unwrap());
+        let handshake = Handshake::new(&headers, SUBSCRIPTION_CAPABILITIES);
+        assert!(handshake.is_enabled(CAPABILITY_ORIGIN));
+        assert!(!handshake.is_enabled(CAPABILITY_BATCH));
+        assert!(!handshake.is_enabled("pings"));
+
+        let hello = handshake.hello(Some(Uuid::nil())).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<QueryEvent>(&hello).unwrap(),
+            QueryEvent::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![CAPABILITY_ORIGIN.into()],
+                sub_id: Some(Uuid::nil()),
+            }
+        );
+        assert!(hello.ends_with(b"\n"));
+
+        // asking for nothing still gets a hello
+        headers.insert(CAPABILITIES_HEADER, "".parse().unwrap());
+        let handshake = Handshake::new(&headers, QUERY_CAPABILITIES);
+        assert!(handshake.hello(None).is_some());
+
+        let response = handshake
+            .headers(hyper::Response::builder())
+            .body(())
+            .unwrap();
+        assert_eq!(
+            response.headers().get(PROTOCOL_VERSION_HEADER).unwrap(),
+            PROTOCOL_VERSION.to_string().as_str()
+        );
+        assert_eq!(response.headers().get(CAPABILITIES_HEADER).unwrap(), "");
+    }
+}
@@ -0,0 +1,301 @@
+//! Storage health: database and WAL sizes, free disk space and page cache
+//! stats, checked against the thresholds configured in `db.health`.
+
+use std::{io, time::Duration};
+
+use axum::{http::StatusCode, Extension};
+use camino::{Utf8Path, Utf8PathBuf};
+use corro_types::{
+    agent::Agent,
+    api::{ExecResult, HealthDetails, PageCacheStats},
+    config::DbHealthConfig,
+    sqlite::SqlitePoolError,
+};
+use metrics::gauge;
+use rusqlite::Connection;
+use tokio::task::block_in_place;
+use tracing::{error, info, warn};
+use tripwire::Tripwire;
+
+#[derive(Debug, thiserror::Error)]
+pub enum HealthError {
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("could not stat filesystem: {0}")]
+    Statvfs(#[from] nix::Error),
+}
+
+fn file_size(path: &Utf8Path) -> io::Result<u64> {
+    match std::fs::metadata(path) {
+        Ok(meta) => Ok(meta.len()),
+        // there's no WAL file until something gets written
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// the statvfs field types differ across platforms
+#[allow(clippy::unnecessary_cast)]
+fn free_disk_bytes(db_path: &Utf8Path) -> Result<u64, HealthError> {
+    let dir = match db_path.parent() {
+        Some(parent) if !parent.as_str().is_empty() => parent,
+        _ => Utf8Path::new("."),
+    };
+    let stat = nix::sys::statvfs::statvfs(dir.as_std_path())?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+fn page_cache_stats(conn: &Connection) -> rusqlite::Result<PageCacheStats> {
+    let pragma = |name: &str| {
+        conn.query_row(&format!("PRAGMA {name}"), [], |row| row.get::<_, i64>(0))
+            .map(|n| n.max(0) as u64)
+    };
+
+    let page_size = pragma("page_size")?;
+    let cache_size: i64 = conn.query_row("PRAGMA cache_size", [], |row| row.get(0))?;
+
+    Ok(PageCacheStats {
+        page_size,
+        page_count: pragma("page_count")?,
+        freelist_count: pragma("freelist_count")?,
+        // negative sizes are in KiB, positive ones in pages
+        cache_size_bytes: if cache_size < 0 {
+            cache_size.unsigned_abs() * 1024
+        } else {
+            cache_size as u64 * page_size
+        },
+    })
+}
+
+/// Gathers the storage health of the database at `db_path`, degraded if any
+/// of the thresholds is crossed
+pub fn storage_health(
+    conn: &Connection,
+    db_path: &Utf8Path,
+    config: &DbHealthConfig,
+) -> Result<HealthDetails, HealthError> {
+    let db_size_bytes = file_size(db_path)?;
+    let wal_size_bytes = file_size(&Utf8PathBuf::from(format!("{db_path}-wal")))?;
+    let free_disk_bytes = free_disk_bytes(db_path)?;
+    let page_cache = page_cache_stats(conn)?;
+
+    let mut reasons = vec![];
+    if wal_size_bytes > config.max_wal_bytes {
+        reasons.push(format!(
+            "WAL is {wal_size_bytes} bytes, over the limit of {} bytes",
+            config.max_wal_bytes
+        ));
+    }
+    if free_disk_bytes < config.min_free_disk_bytes {
+        reasons.push(format!(
+            "{free_disk_bytes} bytes free on disk, under the limit of {} bytes",
+            config.min_free_disk_bytes
+        ));
+    }
+
+    Ok(HealthDetails {
+        db_size_bytes,
+        wal_size_bytes,
+        free_disk_bytes,
+        page_cache,
+        degraded: !reasons.is_empty(),
+        reasons,
+        started_at: 0,
+        last_checkpoint_at: 0,
+    })
+}
+
+/// Checks the storage health and records it on the agent, which flags
+/// `/v1/transactions` responses while degraded
+pub async fn check_health(agent: &Agent) -> Result<HealthDetails, HealthError> {
+    let conn = agent.pool().read().await?;
+
+    let mut health = {
+        let config = agent.config();
+        block_in_place(|| storage_health(&conn, &config.db.path, &config.db.health))?
+    };
+    health.started_at = agent.started_at();
+    health.last_checkpoint_at = agent.last_checkpoint_at();
+
+    match (agent.is_degraded(), health.degraded) {
+        (false, true) => warn!("storage is degraded: {}", health.reasons.join(", ")),
+        (true, false) => info!("storage is not degraded anymore"),
+        _ => {}
+    }
+
+    gauge!("corro.db.size.bytes", health.db_size_bytes as f64);
+    gauge!("corro.db.wal.size.bytes", health.wal_size_bytes as f64);
+    gauge!("corro.db.free_disk.bytes", health.free_disk_bytes as f64);
+    gauge!("corro.db.degraded", if health.degraded { 1.0 } else { 0.0 });
+
+    agent.set_health(health.clone());
+
+    Ok(health)
+}
+
+pub async fn health_loop(agent: Agent, mut tripwire: Tripwire) {
+    let check_interval = agent.config().db.health.check_interval_secs.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(check_interval));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = check_health(&agent).await {
+                    error!("could not check storage health: {e}");
+                }
+            },
+            _ = &mut tripwire => {
+                break;
+            }
+        }
+    }
+}
+
+pub async fn api_v1_health(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<HealthDetails>, (StatusCode, axum::Json<ExecResult>)> {
+    check_health(&agent).await.map(axum::Json).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            }),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use corro_types::{
+        api::{Statement, DEGRADED_HEADER},
+        config::Config,
+    };
+    use hyper::HeaderMap;
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    use crate::{
+        agent::setup,
+        api::public::{api_v1_db_schema, api_v1_exec},
+    };
+
+    async fn setup_with_health(
+        dir: &tempfile::TempDir,
+        health: DbHealthConfig,
+        tripwire: Tripwire,
+    ) -> eyre::Result<Agent> {
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .db_health(health)
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        Ok(agent)
+    }
+
+    async fn insert(agent: &Agent, id: i64) -> axum::response::Response {
+        api_v1_exec(
+            Extension(agent.clone()),
+            None,
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), "hello".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await
+        .into_response()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_health_thresholds() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+        let agent = setup_with_health(
+            &dir,
+            DbHealthConfig {
+                max_wal_bytes: u64::MAX,
+                min_free_disk_bytes: 0,
+                ..Default::default()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let health = api_v1_health(Extension(agent.clone()))
+            .await
+            .map_err(|(_, e)| eyre::eyre!("{e:?}"))?
+            .0;
+        assert!(!health.degraded);
+        assert!(health.reasons.is_empty());
+        assert!(health.db_size_bytes > 0);
+        assert!(health.page_cache.page_size > 0);
+        assert!(health.page_cache.page_count > 0);
+        assert_eq!(health.started_at, agent.started_at());
+        assert!(health.started_at > 0);
+
+        let res = insert(&agent, 1).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(DEGRADED_HEADER).is_none());
+
+        let dir = tempfile::tempdir()?;
+        let agent = setup_with_health(
+            &dir,
+            DbHealthConfig {
+                max_wal_bytes: 1,
+                min_free_disk_bytes: u64::MAX,
+                ..Default::default()
+            },
+            tripwire,
+        )
+        .await?;
+
+        let res = insert(&agent, 1).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(DEGRADED_HEADER).is_none());
+
+        let health = check_health(&agent).await?;
+        assert!(health.degraded);
+        assert!(health.wal_size_bytes > 1);
+        assert_eq!(health.reasons.len(), 2, "{:?}", health.reasons);
+        assert!(health.reasons[0].starts_with("WAL is"));
+        assert!(agent.is_degraded());
+
+        // writes still go through, flagged as degraded
+        let res = insert(&agent, 2).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get(DEGRADED_HEADER)
+                .and_then(|v| v.to_str().ok()),
+            Some("true")
+        );
+
+        Ok(())
+    }
+}
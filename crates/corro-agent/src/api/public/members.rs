@@ -0,0 +1,159 @@
+//! The peers this node knows of and how far behind it is on each, from the
+//! gossip membership and the bookkeeping of the versions it applied.
+
+use std::{collections::HashMap, ops::RangeInclusive, time::UNIX_EPOCH};
+
+use axum::Extension;
+use corro_types::{
+    agent::Agent,
+    api::{MemberState, NeededVersions},
+    sync::generate_sync,
+};
+
+/// Summarizes the versions missing entirely, sorted, and the ones only partly
+/// received
+fn needed_versions(
+    need: Option<&Vec<RangeInclusive<i64>>>,
+    partial_need: Option<&HashMap<i64, Vec<RangeInclusive<i64>>>>,
+) -> NeededVersions {
+    let need = need.map(Vec::as_slice).unwrap_or_default();
+    NeededVersions {
+        count: need
+            .iter()
+            .map(|range| (range.end() - range.start() + 1) as u64)
+            .sum(),
+        min: need.iter().map(|range| *range.start()).min(),
+        max: need.iter().map(|range| *range.end()).max(),
+        partial: partial_need.map_or(0, |partials| partials.len() as u64),
+    }
+}
+
+/// Lists the cluster's members besides this node. Built from snapshots of
+/// the membership and of the bookkeeping, no lock is held while responding.
+pub async fn api_v1_cluster_members(
+    Extension(agent): Extension<Agent>,
+) -> axum::Json<Vec<MemberState>> {
+    let sync_state = generate_sync(agent.bookie(), agent.actor_id()).await;
+
+    let members: Vec<_> = {
+        let members = agent.members().read();
+        members
+            .states
+            .iter()
+            .filter(|(actor_id, _)| **actor_id != agent.actor_id())
+            .map(|(actor_id, state)| (*actor_id, state.clone(), members.syncing.contains(actor_id)))
+            .collect()
+    };
+
+    axum::Json(
+        members
+            .into_iter()
+            .map(|(actor_id, state, sync_in_flight)| MemberState {
+                site_id: actor_id.site_id(),
+                addr: state.addr,
+                last_seen: state
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+                ring: state.ring,
+                max_db_version_known: sync_state.heads.get(&actor_id).copied(),
+                sync_in_flight,
+                needed: needed_versions(
+                    sync_state.need.get(&actor_id),
+                    sync_state.partial_need.get(&actor_id),
+                ),
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use corro_tests::launch_test_agent;
+    use corro_types::api::Statement;
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    #[test]
+    fn summarizes_needed_versions() {
+        assert_eq!(needed_versions(None, None), NeededVersions::default());
+        assert!(needed_versions(None, None).is_caught_up());
+
+        let need = vec![2..=4, 10..=10];
+        let partial_need = HashMap::from([(12, vec![3..=5])]);
+        let needed = needed_versions(Some(&need), Some(&partial_need));
+        assert_eq!(
+            needed,
+            NeededVersions {
+                count: 4,
+                min: Some(2),
+                max: Some(10),
+                partial: 1,
+            }
+        );
+        assert!(!needed.is_caught_up());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn lists_members_and_their_lag() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client1 = corro_client::CorrosionApiClient::new(ta1.agent.api_addr());
+        for i in 0..5i64 {
+            client1
+                .execute(&[Statement::WithParams(
+                    "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                    vec![i.into(), format!("row {i}").into()],
+                )])
+                .await?;
+        }
+
+        // ta2 ends up w/ all of ta1's writes
+        for (ta, peer, known) in [(&ta1, &ta2, None), (&ta2, &ta1, Some(5))] {
+            let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+
+            let mut members = client.cluster_members().await?;
+            for _ in 0..100 {
+                let settled = members.iter().any(|member| {
+                    member.site_id == peer.agent.site_id()
+                        && member.max_db_version_known >= known
+                        && member.needed.is_caught_up()
+                });
+                if settled {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                members = client.cluster_members().await?;
+            }
+
+            assert_eq!(members.len(), 1, "{members:?}");
+            let member = &members[0];
+            assert_eq!(member.site_id, peer.agent.site_id());
+            assert_eq!(member.addr, peer.agent.gossip_addr());
+            assert!(member.last_seen > 0, "{member:?}");
+            assert!(member.max_db_version_known >= known, "{member:?}");
+            assert!(member.needed.is_caught_up(), "{member:?}");
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
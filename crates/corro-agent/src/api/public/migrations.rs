@@ -0,0 +1,279 @@
+//! Named migration steps, applied at most once per node w/
+//! `POST /v1/migrations/apply` and listed w/ `GET /v1/migrations/applied`.
+//!
+//! Each step runs in its own transaction. Its schema changes go through the
+//! same checks and cr-sqlite instrumentation as `POST /v1/migrations`, its
+//! other statements (e.g. backfills) are replicated like any other write.
+//! The step is recorded in `__corro_migrations` by that same transaction:
+//! concurrent applies serialize on the write connection, only the first one
+//! runs the step and the others skip it.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::Extension;
+use corro_types::{
+    agent::{Agent, ChangeError},
+    api::{AppliedMigration, ExecErrorCode, MigrateRequest, MigrateResponse, MigrationStep},
+    exec::ExecTracker,
+    history,
+    schema::{apply_schema, create_local_table, schema_after, Schema},
+};
+use hyper::{HeaderMap, StatusCode};
+use metrics::increment_counter;
+use parking_lot::Mutex;
+use rusqlite::Transaction;
+use tokio::task::block_in_place;
+use tracing::{error, info};
+
+use super::{
+    check_exec_statements, exec_origin, execute_statement, make_broadcastable_changes,
+    pubsub::reshape_subs,
+};
+
+/// Applies the request's steps in order, skipping the ones already applied
+/// on this node and stopping at the first one which fails
+pub async fn api_v1_migrations_apply(
+    Extension(agent): Extension<Agent>,
+    headers: HeaderMap,
+    axum::extract::Json(req): axum::extract::Json<MigrateRequest>,
+) -> (StatusCode, axum::Json<MigrateResponse>) {
+    let start = Instant::now();
+    let mut res = MigrateResponse::default();
+
+    if let Err((status, error)) = check_steps(&agent, &headers, &req.steps) {
+        res.error = Some(error);
+        return (status, axum::Json(res));
+    }
+
+    for step in req.steps {
+        match apply_step(&agent, &headers, &step).await {
+            Ok(true) => {
+                info!(name = %step.name, "applied migration step");
+                increment_counter!("corro.api.migrations.applied");
+                res.applied.push(step.name);
+            }
+            Ok(false) => res.skipped.push(step.name),
+            Err(e) => {
+                error!(name = %step.name, "could not apply migration step: {e}");
+                let status = match &e {
+                    ChangeError::Schema(_) | ChangeError::InvalidParams(_) => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    ChangeError::Rusqlite(e) if ExecErrorCode::from_sqlite(e).is_none() => {
+                        StatusCode::BAD_REQUEST
+                    }
+                    ChangeError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                    e => match e.exec_error_code() {
+                        Some(ExecErrorCode::Full) => StatusCode::INSUFFICIENT_STORAGE,
+                        Some(ExecErrorCode::Busy) => StatusCode::SERVICE_UNAVAILABLE,
+                        Some(ExecErrorCode::Interrupted) => StatusCode::CONFLICT,
+                        None => StatusCode::INTERNAL_SERVER_ERROR,
+                    },
+                };
+                res.failed = Some(step.name);
+                res.error = Some(e.to_string());
+                res.time = start.elapsed().as_secs_f64();
+                return (status, axum::Json(res));
+            }
+        }
+    }
+
+    res.time = start.elapsed().as_secs_f64();
+    (StatusCode::OK, axum::Json(res))
+}
+
+/// Lists the migration steps applied on this node, in the order they were
+/// applied
+pub async fn api_v1_migrations_applied(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<Vec<AppliedMigration>>, (StatusCode, String)> {
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+
+    block_in_place(|| {
+        conn.prepare_cached(
+            "SELECT name, applied_at, statements FROM __corro_migrations ORDER BY applied_at, name",
+        )?
+        .query_map([], |row| {
+            Ok(AppliedMigration {
+                name: row.get(0)?,
+                applied_at: row.get(1)?,
+                statements: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()
+    })
+    .map(axum::Json)
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+fn check_steps(
+    agent: &Agent,
+    headers: &HeaderMap,
+    steps: &[MigrationStep],
+) -> Result<(), (StatusCode, String)> {
+    if steps.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "at least 1 step is required".into(),
+        ));
+    }
+
+    let mut names = HashSet::new();
+    for step in steps {
+        if step.name.trim().is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "steps must be named".into()));
+        }
+        if !names.insert(step.name.as_str()) {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                format!("step '{}' is listed more than once", step.name),
+            ));
+        }
+        check_exec_statements(agent, headers, &step.statements)
+            .map_err(|(status, error)| (status, format!("step '{}': {error}", step.name)))?;
+    }
+
+    Ok(())
+}
+
+/// Applies `step` unless it already was, returns whether it was
+async fn apply_step(
+    agent: &Agent,
+    headers: &HeaderMap,
+    step: &MigrationStep,
+) -> Result<bool, ChangeError> {
+    let tracker = agent
+        .exec_registry()
+        .register(exec_origin(None, headers), step.statements.len());
+
+    // the in-memory schema is changed along w/ the transaction, it's put
+    // back if the transaction doesn't commit
+    let previous = Mutex::new(None);
+    let res = make_broadcastable_changes(agent, Default::default(), &tracker, |tx, tracker| {
+        run_step(agent, tx, tracker, step, &previous)
+    })
+    .await;
+
+    let previous = previous.into_inner();
+    match res {
+        Ok((applied, _, _)) => {
+            if previous.is_some() {
+                agent.schema_changed();
+                reshape_subs(agent).await;
+            }
+            Ok(applied)
+        }
+        Err(e) => {
+            if let Some(schema) = previous {
+                *agent.schema().write() = schema;
+            }
+            Err(e)
+        }
+    }
+}
+
+fn run_step(
+    agent: &Agent,
+    tx: &Transaction,
+    tracker: &ExecTracker,
+    step: &MigrationStep,
+    previous: &Mutex<Option<Schema>>,
+) -> Result<bool, ChangeError> {
+    let applied: bool = tx
+        .prepare_cached("SELECT EXISTS(SELECT 1 FROM __corro_migrations WHERE name = ?)")?
+        .query_row([&step.name], |row| row.get(0))?;
+    if applied {
+        return Ok(false);
+    }
+
+    let mut changed_tables = BTreeSet::new();
+    for (index, stmt) in step.statements.iter().enumerate() {
+        tracker.check()?;
+        tracker.statement(index, stmt.query());
+
+        let change = schema_after(&agent.schema().read(), stmt.query())
+            .map_err(|e| ChangeError::Schema(format!("statement {index}: {e}")))?;
+        match change {
+            Some((tbl_name, new_schema)) => {
+                if change_schema(agent, tx, &tbl_name, new_schema, previous)
+                    .map_err(|e| ChangeError::Schema(format!("statement {index}: {e}")))?
+                {
+                    changed_tables.insert(tbl_name);
+                }
+            }
+            None => {
+                execute_statement(
+                    tx,
+                    stmt,
+                    index,
+                    Duration::from_millis(agent.config().api.slow_statement_ms),
+                )?;
+            }
+        }
+    }
+
+    for tbl_name in changed_tables.iter() {
+        tx.execute("DELETE FROM __corro_schema WHERE tbl_name = ?", [tbl_name])?;
+        tx.execute("INSERT INTO __corro_schema SELECT tbl_name, type, name, sql, 'api' AS source FROM sqlite_schema WHERE tbl_name = ? AND type IN ('table', 'index') AND name IS NOT NULL AND sql IS NOT NULL", [tbl_name])?;
+    }
+
+    let applied_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    tx.prepare_cached(
+        "INSERT INTO __corro_migrations (name, applied_at, statements) VALUES (?, ?, ?)",
+    )?
+    .execute(rusqlite::params![
+        step.name,
+        applied_at,
+        step.statements.len()
+    ])?;
+
+    Ok(true)
+}
+
+/// Applies a change to table `tbl_name`, resulting in `new_schema`. Returns
+/// whether the replicated schema changed, local-only tables are created
+/// as-is.
+fn change_schema(
+    agent: &Agent,
+    tx: &Transaction,
+    tbl_name: &str,
+    mut new_schema: Schema,
+    previous: &Mutex<Option<Schema>>,
+) -> eyre::Result<bool> {
+    // only changed while holding the write conn, nothing else can change it
+    // in between
+    let mut schema_write = agent.schema().write();
+
+    if agent.config().db.is_local_only(tbl_name) {
+        if schema_write.tables.contains_key(tbl_name) {
+            eyre::bail!("table '{tbl_name}' is already replicated, it can't be made local-only");
+        }
+        if let Some(table) = new_schema.tables.get(tbl_name) {
+            create_local_table(tx, table)?;
+        }
+        return Ok(false);
+    }
+
+    new_schema.constrain()?;
+    apply_schema(tx, &schema_write, &mut new_schema)?;
+
+    if agent.config().db.history_retention_secs.is_some() {
+        if let Some(table) = new_schema.tables.get(tbl_name) {
+            history::install(tx, table)?;
+        }
+    }
+
+    previous.lock().get_or_insert_with(|| schema_write.clone());
+    *schema_write = new_schema;
+
+    Ok(true)
+}
@@ -1,24 +1,58 @@
 use std::{
+    collections::HashMap,
     iter::Peekable,
+    net::SocketAddr,
     ops::RangeInclusive,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use axum::{response::IntoResponse, Extension};
-use bytes::{BufMut, BytesMut};
-use compact_str::ToCompactString;
+use axum::{
+    extract::{ConnectInfo, FromRequest},
+    http::Request,
+    response::IntoResponse,
+    Extension,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use compact_str::{CompactString, ToCompactString};
 use corro_types::{
     agent::{Agent, ChangeError, KnownDbVersion},
-    api::{row_to_change, ExecResponse, ExecResult, QueryEvent, Statement},
+    api::{
+        row_to_change, ActiveTransaction, ChangesGenerated, Coercion, ColumnName, ColumnSchema,
+        ConfigResponse, ExecErrorCode, ExecEvent, ExecIsolation, ExecRequest, ExecResponse,
+        ExecResult, QueryEvent, QueryEventRef, QueryLimits, QueryPlan, QuotaUsage, RegisteredQuery,
+        SchemaResponse, SchemaVersion, SessionOptions, SqliteParam, Statement, TableName,
+        TableSchema, Throttled, DEGRADED_HEADER, IDEMPOTENCY_KEY_HEADER, QUERY_CACHE_HEADER,
+    },
     broadcast::{ChangeV1, Changeset, Timestamp},
     change::SqliteValue,
-    schema::{apply_schema, parse_sql},
-    sqlite::SqlitePoolError,
+    config::{AuthzConfig, JsonLimitsConfig},
+    exec::{ExecOrigin, ExecTracker},
+    history::{self, AsOfError},
+    query_cache::{Generation, QueryCache},
+    quota::ClientIdentity,
+    registry::RegistryError,
+    schema::{apply_schema, create_local_table, parse_sql, Table},
+    sqlite::{
+        explain_query_plan, interned_column_names, is_busy_snapshot, prepare_with_access,
+        statement_access, SqlitePoolError,
+    },
 };
-use hyper::StatusCode;
+use exec_body::{stream_statements, PeekedBody};
+use futures::{Future, FutureExt};
+use handshake::{Handshake, QUERY_CAPABILITIES};
+use http_body::Limited;
+use hyper::{header::HeaderValue, HeaderMap, StatusCode};
 use itertools::Itertools;
-use metrics::counter;
-use rusqlite::{named_params, params_from_iter, ToSql, Transaction};
+use metrics::{counter, gauge, histogram, increment_counter};
+use parking_lot::Mutex;
+use rusqlite::{
+    named_params, params, params_from_iter, OpenFlags, OptionalExtension, ToSql, Transaction,
+};
+use serde::Deserialize;
 use spawn::spawn_counted;
 use tokio::{
     sync::{
@@ -27,7 +61,7 @@ use tokio::{
     },
     task::block_in_place,
 };
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, field, info, trace, warn, Span};
 
 use corro_types::{
     broadcast::{BroadcastInput, BroadcastV1},
@@ -36,7 +70,19 @@ use corro_types::{
 
 use crate::agent::process_subs;
 
+pub mod authz;
+pub mod checkpoint;
+pub mod digest;
+pub mod exec_body;
+pub mod handshake;
+pub mod health;
+pub mod members;
+pub mod migrations;
+pub mod node_names;
+pub mod probe;
 pub mod pubsub;
+pub mod sampling;
+pub mod usage;
 
 pub struct ChunkedChanges<I: Iterator> {
     iter: Peekable<I>,
@@ -46,6 +92,8 @@ pub struct ChunkedChanges<I: Iterator> {
     last_seq: i64,
     max_buf_size: usize,
     buffered_size: usize,
+    compress_over: Option<usize>,
+    origin_ts: Option<u64>,
     done: bool,
 }
 
@@ -62,10 +110,25 @@ where
             last_seq,
             max_buf_size,
             buffered_size: 0,
+            compress_over: None,
+            origin_ts: None,
             done: false,
         }
     }
 
+    /// Compresses values at least `threshold` bytes large, before they're
+    /// accounted for in chunks
+    pub fn compress_over(mut self, threshold: Option<usize>) -> Self {
+        self.compress_over = threshold;
+        self
+    }
+
+    /// Sends changes w/ the NTP64 timestamp of their version on its origin
+    pub fn origin_ts(mut self, ts: Option<u64>) -> Self {
+        self.origin_ts = ts;
+        self
+    }
+
     pub fn max_buf_size(&self) -> usize {
         self.max_buf_size
     }
@@ -95,9 +158,19 @@ where
         loop {
             trace!("chunking through the rows iterator");
             match self.iter.next() {
-                Some(Ok(change)) => {
+                Some(Ok(mut change)) => {
                     trace!("got change: {change:?}");
 
+                    if let Some(ts) = self.origin_ts {
+                        change.ts = Some(ts);
+                    }
+
+                    if let Some(threshold) = self.compress_over {
+                        if let Err(e) = change.compress(threshold) {
+                            warn!("could not compress change value, sending it as-is: {e}");
+                        }
+                    }
+
                     self.last_pushed_seq = change.seq;
 
                     self.buffered_size += change.estimated_byte_size();
@@ -148,12 +221,111 @@ where
 
 const MAX_CHANGES_BYTE_SIZE: usize = 8 * 1024;
 
+// how much of a query is quoted in errors about its params
+const QUERY_EXCERPT_CHARS: usize = 32;
+// how much of a query is quoted in slow statement warnings
+const SLOW_STATEMENT_EXCERPT_CHARS: usize = 200;
+/// How long the results of transactions applied w/ an `idempotency-key` are
+/// kept, to respond w/ them again instead of applying them twice
+const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Changes generated by a local transaction, per table
+#[derive(Debug, Default)]
+pub struct ChangeTally {
+    // (changes, bytes)
+    tables: HashMap<String, (u64, u64)>,
+}
+
+// weight of the latest transaction in the amplification moving average
+const AMPLIFICATION_EWMA_ALPHA: f64 = 0.1;
+
+// f64 bits of the moving average of changes per statement
+static AMPLIFICATION: AtomicU64 = AtomicU64::new(0);
+
+impl ChangeTally {
+    fn add(&mut self, change: &Change) {
+        let (changes, bytes) = self.tables.entry(change.table.to_string()).or_default();
+        *changes += 1;
+        *bytes += change.estimated_byte_size() as u64;
+    }
+
+    pub fn changes(&self) -> u64 {
+        self.tables.values().map(|(changes, _)| changes).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.tables.values().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// Tables which changed
+    pub fn tables(&self) -> impl Iterator<Item = &str> {
+        self.tables.keys().map(String::as_str)
+    }
+
+    pub fn generated(&self, statements: usize) -> ChangesGenerated {
+        ChangesGenerated {
+            changes: self.changes(),
+            bytes: self.bytes(),
+            statements: statements as u64,
+        }
+    }
+
+    /// Records how many changes (and bytes) each statement generated.
+    /// cr-sqlite doesn't attribute changes to statements, so they're averaged
+    /// over all of the transaction's statements.
+    fn record(&self, statements: usize) {
+        if statements == 0 {
+            return;
+        }
+        let per_statement = |n: u64| n as f64 / statements as f64;
+
+        for (table, (changes, bytes)) in self.tables.iter() {
+            histogram!("corro.api.changes.generated", per_statement(*changes), "table" => table.clone());
+            histogram!("corro.api.changes.generated.bytes", per_statement(*bytes), "table" => table.clone());
+        }
+
+        let latest = per_statement(self.changes());
+        let ewma = |bits: u64| {
+            let avg = f64::from_bits(bits);
+            avg + AMPLIFICATION_EWMA_ALPHA * (latest - avg)
+        };
+        let prev = AMPLIFICATION
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some(ewma(bits).to_bits())
+            })
+            .expect("amplification update always succeeds");
+        gauge!("corro.api.changes.amplification", ewma(prev));
+    }
+}
+
+/// Runs `f` in a transaction on the write connection and books whatever
+/// changes it made for broadcasting. `tracker` lists the transaction while it
+/// runs and counts the statements `f` runs, to measure how many changes each
+/// of them generates. Once it's killed, the transaction rolls back.
 pub async fn make_broadcastable_changes<F, T>(
     agent: &Agent,
+    session: SessionOptions,
+    tracker: &ExecTracker,
     f: F,
-) -> Result<(T, Duration), ChangeError>
+) -> Result<(T, Duration, ChangeTally), ChangeError>
 where
-    F: Fn(&Transaction) -> Result<T, ChangeError>,
+    F: Fn(&Transaction, &ExecTracker) -> Result<T, ChangeError>,
+{
+    run_changes(agent, session, tracker, false, f).await
+}
+
+/// See `make_broadcastable_changes`. A `dry_run` always rolls the transaction
+/// back after `f` ran, tallying the changes it would have broadcast. Deferred
+/// foreign key checks never run then, since nothing commits.
+async fn run_changes<F, T>(
+    agent: &Agent,
+    session: SessionOptions,
+    tracker: &ExecTracker,
+    dry_run: bool,
+    f: F,
+) -> Result<(T, Duration, ChangeTally), ChangeError>
+where
+    F: Fn(&Transaction, &ExecTracker) -> Result<T, ChangeError>,
 {
     trace!("getting conn...");
     let mut conn = agent.pool().write_priority().await?;
@@ -173,12 +345,27 @@ where
         .write("make_broadcastable_changes(booked writer)")
         .await;
 
+    // the write conn is shared by every request, put it back as it was
+    // whatever happens to the transaction
+    let prior = block_in_place(|| apply_session(&conn, &session))?;
+
     let start = Instant::now();
-    block_in_place(move || {
+    let res = block_in_place(|| {
+        // kills interrupt whatever runs on the conn until the transaction is
+        // done, it might have been killed while waiting for the conn already
+        let _attached = tracker.attach(&conn);
+        tracker.check()?;
+
         let tx = conn.transaction()?;
+        if let Some(defer) = session.defer_foreign_keys {
+            // sqlite switches this off at the end of every transaction
+            tx.pragma_update(None, "defer_foreign_keys", defer)?;
+        }
 
         // Execute whatever might mutate state data
-        let ret = f(&tx)?;
+        let ret = f(&tx, tracker)?;
+        // killed after its last statement ran
+        tracker.check()?;
 
         let ts = Timestamp::from(agent.clock().new_timestamp());
 
@@ -186,6 +373,14 @@ where
             .prepare_cached("SELECT crsql_next_db_version()")?
             .query_row((), |row| row.get(0))?;
 
+        if dry_run {
+            // still fails like the real thing over oversized changes, but
+            // isn't recorded as amplification nor charged to any quota
+            let tally = tally_changes(&tx, db_version, agent.config().db.max_change_size)?;
+            tx.rollback()?;
+            return Ok((ret, start.elapsed(), tally));
+        }
+
         let has_changes: bool = tx
         .prepare_cached(
             "SELECT EXISTS(SELECT 1 FROM crsql_changes WHERE site_id IS NULL AND db_version = ?);",
@@ -194,9 +389,14 @@ where
 
         if !has_changes {
             tx.commit()?;
-            return Ok((ret, start.elapsed()));
+            let tally = ChangeTally::default();
+            tally.record(tracker.statements());
+            return Ok((ret, start.elapsed(), tally));
         }
 
+        // dropping the transaction rolls it back
+        let tally = tally_changes(&tx, db_version, agent.config().db.max_change_size)?;
+
         let last_version = book_writer.last().unwrap_or_default();
         trace!("last_version: {last_version}");
         let version = last_version + 1;
@@ -230,6 +430,12 @@ where
         };
 
         trace!("committed tx, db_version: {db_version}, last_seq: {last_seq:?}");
+        tally.record(tracker.statements());
+        // before responding, so the writer never reads its own write's
+        // previous result from the cache
+        if agent.query_cache().in_use() {
+            agent.query_cache().invalidate(tally.tables());
+        }
 
         book_writer.insert(
             version,
@@ -242,6 +448,7 @@ where
         drop(book_writer);
 
         let agent = agent.clone();
+        let source_id = tracker.source_id();
 
         spawn_counted(async move {
             let conn = agent.pool().read().await?;
@@ -265,7 +472,11 @@ where
                             {
                                 counter!("corro.changes.committed", count as u64, "table" => table_name.to_string(), "source" => "local");
                             }
-                            process_subs(&agent, &changes);
+                            for change in changes.iter() {
+                                histogram!("corro.changes.size.bytes", change.estimated_byte_size() as f64, "table" => change.table.to_string());
+                            }
+                            process_subs(&agent, &changes, source_id);
+                            agent.change_hooks().notify(&changes);
 
                             trace!("broadcasting changes: {changes:?} for seq: {seqs:?}");
 
@@ -302,15 +513,161 @@ where
             Ok::<_, eyre::Report>(())
         });
 
-        Ok::<_, ChangeError>((ret, elapsed))
-    })
+        Ok::<_, ChangeError>((ret, elapsed, tally))
+    });
+
+    if let Err(e) = block_in_place(|| apply_session(&conn, &prior)) {
+        error!("could not restore session options on the write conn: {e}");
+    }
+
+    res
 }
 
-#[tracing::instrument(skip_all, err)]
-fn execute_statement(tx: &Transaction, stmt: &Statement) -> rusqlite::Result<usize> {
-    let mut prepped = tx.prepare(stmt.query())?;
+/// Applies the connection-wide `options`, returning the values they replaced
+fn apply_session(
+    conn: &rusqlite::Connection,
+    options: &SessionOptions,
+) -> rusqlite::Result<SessionOptions> {
+    let mut prior = SessionOptions::default();
+
+    if let Some(timeout) = options.busy_timeout_ms {
+        let current: u64 = conn.pragma_query_value(None, "busy_timeout", |row| row.get(0))?;
+        conn.busy_timeout(Duration::from_millis(timeout))?;
+        prior.busy_timeout_ms = Some(current);
+    }
 
-    match stmt {
+    // sqlite ignores these within a transaction, they're set before it starts
+    for (pragma, value, prior) in [
+        (
+            "foreign_keys",
+            options.foreign_keys,
+            &mut prior.foreign_keys,
+        ),
+        (
+            "recursive_triggers",
+            options.recursive_triggers,
+            &mut prior.recursive_triggers,
+        ),
+    ] {
+        if let Some(value) = value {
+            let current: bool = conn.pragma_query_value(None, pragma, |row| row.get(0))?;
+            conn.pragma_update(None, pragma, value)?;
+            *prior = Some(current);
+        }
+    }
+
+    Ok(prior)
+}
+
+/// Tallies the changes generated for `db_version`, failing if any of them is
+/// estimated to be larger than `max` bytes. Only applies to locally
+/// originated changes.
+fn tally_changes(
+    tx: &Transaction,
+    db_version: i64,
+    max: Option<usize>,
+) -> Result<ChangeTally, ChangeError> {
+    let mut prepped = tx.prepare_cached(r#"
+        SELECT "table", pk, cid, val, col_version, db_version, seq, COALESCE(site_id, crsql_site_id()), cl
+            FROM crsql_changes
+            WHERE site_id IS NULL
+              AND db_version = ?
+    "#)?;
+
+    let mut tally = ChangeTally::default();
+    for change in prepped.query_map([db_version], row_to_change)? {
+        let change = change?;
+        let size = change.estimated_byte_size();
+        if let Some(max) = max.filter(|max| size > *max) {
+            return Err(ChangeError::TooLarge {
+                table: change.table.to_string(),
+                cid: change.cid.to_string(),
+                size,
+                max,
+            });
+        }
+        tally.add(&change);
+    }
+
+    Ok(tally)
+}
+
+/// Executes a group of statements within a savepoint, rolling back the whole
+/// group if any of them fails. The outer `Result` is only an error if the
+/// savepoint itself couldn't be managed.
+fn execute_group<'a>(
+    tx: &Transaction,
+    stmts: impl Iterator<Item = (usize, &'a Statement)>,
+    slow_after: Duration,
+) -> rusqlite::Result<Result<ExecResult, ChangeError>> {
+    let start = Instant::now();
+
+    tx.execute_batch("SAVEPOINT exec_group")?;
+
+    let mut rows_affected = 0;
+    for (index, stmt) in stmts {
+        match execute_statement(tx, stmt, index, slow_after) {
+            Ok(n) => rows_affected += n,
+            Err(e) => {
+                tx.execute_batch("ROLLBACK TO exec_group; RELEASE exec_group;")?;
+                return Ok(Err(e));
+            }
+        }
+    }
+
+    tx.execute_batch("RELEASE exec_group")?;
+
+    Ok(Ok(ExecResult::Execute {
+        rows_affected,
+        time: start.elapsed().as_secs_f64(),
+    }))
+}
+
+/// Applies the per-statement options of `Statement::Verbose`
+fn apply_statement_options(tx: &Transaction, stmt: &Statement) -> rusqlite::Result<()> {
+    if let Statement::Verbose {
+        defer_foreign_keys: Some(defer),
+        ..
+    } = stmt
+    {
+        tx.pragma_update(None, "defer_foreign_keys", defer)?;
+    }
+    Ok(())
+}
+
+/// Runs a statement of a transaction in a span w/ the table it writes (or
+/// else reads) first, the rows it affected and how long it took. Statements
+/// taking `slow_after` or more are logged as warnings too.
+#[tracing::instrument(
+    skip_all,
+    err,
+    fields(
+        index = index,
+        query_hash = seahash::hash(stmt.query().as_bytes()),
+        table = field::Empty,
+        rows_affected = field::Empty,
+        elapsed_ms = field::Empty,
+    )
+)]
+fn execute_statement(
+    tx: &Transaction,
+    stmt: &Statement,
+    index: usize,
+    slow_after: Duration,
+) -> Result<usize, ChangeError> {
+    let start = Instant::now();
+
+    check_not_registered(stmt, index)?;
+    apply_statement_options(tx, stmt)?;
+
+    let (mut prepped, access) = prepare_with_access(tx, stmt.query())?;
+    let table = access.primary_table();
+    if let Some(table) = table.as_ref() {
+        Span::current().record("table", table.as_str());
+    }
+    check_params(&prepped, stmt, index)?;
+
+    let res = match stmt {
         Statement::Simple(_)
         | Statement::Verbose {
             params: None,
@@ -318,6 +675,7 @@ fn execute_statement(tx: &Transaction, stmt: &Statement) -> rusqlite::Result<usi
             ..
         } => prepped.execute([]),
         Statement::WithParams(_, params)
+        | Statement::Registered { params, .. }
         | Statement::Verbose {
             params: Some(params),
             ..
@@ -333,436 +691,4490 @@ fn execute_statement(tx: &Transaction, stmt: &Statement) -> rusqlite::Result<usi
                 .collect::<Vec<(&str, &dyn ToSql)>>()
                 .as_slice(),
         ),
+    };
+
+    let elapsed = start.elapsed();
+    let span = Span::current();
+    span.record("elapsed_ms", elapsed.as_secs_f64() * 1000.0);
+    histogram!(
+        "corro.api.exec.statement.seconds",
+        elapsed.as_secs_f64(),
+        "table" => table.map(|table| table.0.to_string()).unwrap_or_default()
+    );
+    if elapsed >= slow_after {
+        warn!(
+            elapsed_ms = elapsed.as_millis() as u64,
+            "slow statement {index}: {}",
+            query_excerpt(stmt.query(), SLOW_STATEMENT_EXCERPT_CHARS)
+        );
     }
+
+    let rows_affected = res?;
+    span.record("rows_affected", rows_affected);
+    Ok(rows_affected)
 }
 
-#[tracing::instrument(skip_all)]
-pub async fn api_v1_transactions(
-    // axum::extract::RawQuery(raw_query): axum::extract::RawQuery,
-    Extension(agent): Extension<Agent>,
-    axum::extract::Json(statements): axum::extract::Json<Vec<Statement>>,
-) -> (StatusCode, axum::Json<ExecResponse>) {
-    if statements.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: "at least 1 statement is required".into(),
-                }],
-                time: 0.0,
-            }),
-        );
+/// The first `chars` characters of `query`, w/o leading whitespace
+fn query_excerpt(query: &str, chars: usize) -> &str {
+    let query = query.trim_start();
+    match query.char_indices().nth(chars) {
+        Some((end, _)) => &query[..end],
+        None => query,
     }
+}
 
-    let res = make_broadcastable_changes(&agent, move |tx| {
-        let mut total_rows_affected = 0;
+// registered queries only run through `/v1/queries(/multi)` and `/v1/subscriptions`
+fn check_not_registered(stmt: &Statement, index: usize) -> Result<(), ChangeError> {
+    match stmt {
+        Statement::Registered { name, .. } => Err(ChangeError::InvalidParams(format!(
+            "statement {index}: registered query '{name}' can't run in a transaction"
+        ))),
+        _ => Ok(()),
+    }
+}
 
-        let results = statements
-            .iter()
-            .map(|stmt| {
-                let start = Instant::now();
-                let res = execute_statement(tx, stmt);
-
-                match res {
-                    Ok(rows_affected) => {
-                        total_rows_affected += rows_affected;
-                        ExecResult::Execute {
-                            rows_affected,
-                            time: start.elapsed().as_secs_f64(),
-                        }
-                    }
-                    Err(e) => ExecResult::Error {
-                        error: e.to_string(),
-                    },
+/// Checks a statement's params match the placeholders of `prepped`, so a
+/// mismatch is reported along w/ the statement's `index` within the request
+/// and the start of its query.
+fn check_params(
+    prepped: &rusqlite::Statement,
+    stmt: &Statement,
+    index: usize,
+) -> Result<(), ChangeError> {
+    let invalid = |reason: String| {
+        let start = query_excerpt(stmt.query(), QUERY_EXCERPT_CHARS);
+        ChangeError::InvalidParams(format!(
+            "statement {index}: {reason} (query starts with '{start}')"
+        ))
+    };
+
+    let expected = prepped.parameter_count();
+    let got = match stmt {
+        Statement::Simple(_)
+        | Statement::Verbose {
+            params: None,
+            named_params: None,
+            ..
+        } => 0,
+        Statement::WithParams(_, params)
+        | Statement::Registered { params, .. }
+        | Statement::Verbose {
+            params: Some(params),
+            ..
+        } => params.len(),
+        Statement::WithNamedParams(_, params)
+        | Statement::Verbose {
+            named_params: Some(params),
+            ..
+        } => {
+            for name in params.keys() {
+                if prepped.parameter_index(name)?.is_none() {
+                    return Err(invalid(format!("unknown param '{name}'")));
                 }
-            })
-            .collect::<Vec<ExecResult>>();
+            }
+            params.len()
+        }
+    };
 
-        Ok(results)
-    })
-    .await;
+    if got != expected {
+        return Err(invalid(format!("expected {expected} params, got {got}")));
+    }
 
-    let (results, elapsed) = match res {
-        Ok(res) => res,
-        Err(e) => {
-            error!("could not execute statement(s): {e}");
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(ExecResponse {
-                    results: vec![ExecResult::Error {
-                        error: e.to_string(),
-                    }],
-                    time: 0.0,
-                }),
-            );
+    Ok(())
+}
+
+/// Binds a statement's positional or named params to `prepped`, for use w/
+/// `raw_execute` and `raw_query`
+fn bind_params(
+    prepped: &mut rusqlite::Statement,
+    stmt: &Statement,
+    index: usize,
+) -> Result<(), ChangeError> {
+    check_params(prepped, stmt, index)?;
+
+    match stmt {
+        Statement::Simple(_)
+        | Statement::Verbose {
+            params: None,
+            named_params: None,
+            ..
+        } => {}
+        Statement::WithParams(_, params)
+        | Statement::Registered { params, .. }
+        | Statement::Verbose {
+            params: Some(params),
+            ..
+        } => {
+            for (i, param) in params.iter().enumerate() {
+                prepped.raw_bind_parameter(i + 1, param)?;
+            }
+        }
+        Statement::WithNamedParams(_, params)
+        | Statement::Verbose {
+            named_params: Some(params),
+            ..
+        } => {
+            for (name, param) in params.iter() {
+                let idx = prepped
+                    .parameter_index(name)?
+                    .ok_or_else(|| rusqlite::Error::InvalidParameterName(name.clone()))?;
+                prepped.raw_bind_parameter(idx, param)?;
+            }
         }
+    }
+    Ok(())
+}
+
+/// Runs a statement, sending the rows it returns (if any) as they're
+/// produced. Fails if the statement does or if nobody is receiving the events
+/// anymore, either way the transaction is rolled back.
+fn stream_statement(
+    tx: &Transaction,
+    stmt: &Statement,
+    index: usize,
+    evt_tx: &mpsc::Sender<ExecEvent>,
+) -> Result<(), ChangeError> {
+    let send = |evt| {
+        evt_tx
+            .blocking_send(evt)
+            .map_err(|_| ChangeError::Aborted("exec events receiver is gone"))
     };
 
-    (
-        StatusCode::OK,
-        axum::Json(ExecResponse {
-            results,
-            time: elapsed.as_secs_f64(),
-        }),
-    )
-}
+    let start = Instant::now();
 
-#[derive(Debug, thiserror::Error)]
-pub enum QueryError {
-    #[error("pool connection acquisition error")]
-    Pool(#[from] SqlitePoolError),
-    #[error("sqlite error: {0}")]
-    Rusqlite(#[from] rusqlite::Error),
+    check_not_registered(stmt, index)?;
+    apply_statement_options(tx, stmt)?;
+
+    let mut prepped = tx.prepare(stmt.query())?;
+    bind_params(&mut prepped, stmt, index)?;
+
+    let col_count = prepped.column_count();
+    if col_count == 0 {
+        let rows_affected = prepped.raw_execute()?;
+        return send(ExecEvent::Execute {
+            rows_affected,
+            time: start.elapsed().as_secs_f64(),
+        });
+    }
+
+    send(ExecEvent::Columns(
+        prepped
+            .column_names()
+            .into_iter()
+            .map(|name| name.to_compact_string())
+            .collect(),
+    ))?;
+
+    let mut rows = prepped.raw_query();
+    let mut count = 0;
+    while let Some(row) = rows.next()? {
+        let cells = (0..col_count)
+            .map(|i| row.get::<_, SqliteValue>(i))
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        count += 1;
+        send(ExecEvent::Row((count as i64).into(), cells))?;
+    }
+
+    send(ExecEvent::EndOfQuery {
+        time: start.elapsed().as_secs_f64(),
+        rows: count,
+    })
 }
 
-async fn build_query_rows_response(
-    agent: &Agent,
-    data_tx: mpsc::Sender<QueryEvent>,
-    stmt: Statement,
-) -> Result<(), (StatusCode, ExecResult)> {
-    let (res_tx, res_rx) = oneshot::channel();
+pub const JSON_MAX_PARAM_BYTES_HEADER: &str = "corro-json-max-param-bytes";
+pub const JSON_MAX_TOTAL_BYTES_HEADER: &str = "corro-json-max-total-bytes";
+pub const JSON_MAX_DEPTH_HEADER: &str = "corro-json-max-depth";
+
+/// Applies per-request overrides of the configured JSON limits, meant for
+/// trusted internal callers.
+fn json_limits_from_headers(
+    mut limits: JsonLimitsConfig,
+    headers: &HeaderMap,
+) -> Result<JsonLimitsConfig, String> {
+    fn parse(headers: &HeaderMap, name: &str) -> Result<Option<usize>, String> {
+        headers
+            .get(name)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| format!("invalid {name} header value"))
+            })
+            .transpose()
+    }
+
+    if let Some(max) = parse(headers, JSON_MAX_PARAM_BYTES_HEADER)? {
+        limits.max_param_bytes = max;
+    }
+    if let Some(max) = parse(headers, JSON_MAX_TOTAL_BYTES_HEADER)? {
+        limits.max_total_bytes = max;
+    }
+    if let Some(max) = parse(headers, JSON_MAX_DEPTH_HEADER)? {
+        limits.max_depth = Some(max);
+    }
 
-    let pool = agent.pool().clone();
+    Ok(limits)
+}
 
-    tokio::spawn(async move {
-        let conn = match pool.read().await {
-            Ok(conn) => conn,
-            Err(e) => {
-                _ = res_tx.send(Err((
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    ExecResult::Error {
-                        error: e.to_string(),
-                    },
-                )));
-                return;
+/// Max nesting depth of a JSON document, without fully parsing it
+fn json_depth(json: &str) -> usize {
+    let mut depth = 0usize;
+    let mut max_depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for b in json.bytes() {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
             }
-        };
+            continue;
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
 
-        let prepped_res = block_in_place(|| conn.prepare(stmt.query()));
+    max_depth
+}
 
-        let mut prepped = match prepped_res {
-            Ok(prepped) => prepped,
-            Err(e) => {
-                _ = res_tx.send(Err((
-                    StatusCode::BAD_REQUEST,
-                    ExecResult::Error {
-                        error: e.to_string(),
-                    },
-                )));
-                return;
-            }
-        };
+/// Checks JSON params against the limits, returning the offending
+/// statement's index and a description of the violation
+fn check_json_limits(
+    limits: &JsonLimitsConfig,
+    statements: &[Statement],
+) -> Result<(), (usize, String)> {
+    let mut check = JsonLimitsCheck::new(*limits);
+    for (i, stmt) in statements.iter().enumerate() {
+        check.statement(stmt).map_err(|error| (i, error))?;
+    }
+    Ok(())
+}
 
-        if !prepped.readonly() {
-            _ = res_tx.send(Err((
-                StatusCode::BAD_REQUEST,
-                ExecResult::Error {
-                    error: "statement is not readonly".into(),
-                },
-            )));
-            return;
-        }
+/// Checks the JSON params of a request's statements one at a time, keeping
+/// count of their combined size
+struct JsonLimitsCheck {
+    limits: JsonLimitsConfig,
+    total: usize,
+}
 
-        block_in_place(|| {
-            let col_count = prepped.column_count();
-            trace!("inside block in place, col count: {col_count}");
+impl JsonLimitsCheck {
+    fn new(limits: JsonLimitsConfig) -> Self {
+        Self { limits, total: 0 }
+    }
 
-            if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(
-                prepped
-                    .columns()
-                    .into_iter()
-                    .map(|col| col.name().to_compact_string())
-                    .collect(),
-            )) {
-                error!("could not send back columns: {e}");
-                return;
+    fn statement(&mut self, stmt: &Statement) -> Result<(), String> {
+        let limits = &self.limits;
+        for param in stmt.params() {
+            let json = match param {
+                SqliteParam::Json(raw) => raw.get(),
+                _ => continue,
+            };
+
+            if json.len() > limits.max_param_bytes {
+                return Err(format!(
+                    "JSON param is {} bytes, over the limit of {} bytes",
+                    json.len(),
+                    limits.max_param_bytes
+                ));
             }
 
-            let start = Instant::now();
+            self.total += json.len();
+            if self.total > limits.max_total_bytes {
+                return Err(format!(
+                    "JSON params total {} bytes, over the limit of {} bytes",
+                    self.total, limits.max_total_bytes
+                ));
+            }
 
-            let query = match stmt {
-                Statement::Simple(_)
-                | Statement::Verbose {
-                    params: None,
-                    named_params: None,
-                    ..
-                } => prepped.query(()),
-                Statement::WithParams(_, params)
-                | Statement::Verbose {
-                    params: Some(params),
-                    ..
-                } => prepped.query(params_from_iter(params)),
-                Statement::WithNamedParams(_, params)
-                | Statement::Verbose {
-                    named_params: Some(params),
-                    ..
-                } => prepped.query(
-                    params
-                        .iter()
-                        .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
-                        .collect::<Vec<(&str, &dyn ToSql)>>()
-                        .as_slice(),
-                ),
-            };
-
-            let mut rows = match query {
-                Ok(rows) => rows,
-                Err(e) => {
-                    _ = res_tx.send(Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        ExecResult::Error {
-                            error: e.to_string(),
-                        },
-                    )));
-                    return;
+            if let Some(max_depth) = limits.max_depth {
+                let depth = json_depth(json);
+                if depth > max_depth {
+                    return Err(format!(
+                        "JSON param nesting depth is {depth}, over the limit of {max_depth}"
+                    ));
                 }
-            };
-            let elapsed = start.elapsed();
-
-            if let Err(_e) = res_tx.send(Ok(())) {
-                error!("could not send back response through oneshot channel, aborting");
-                return;
             }
+        }
+        Ok(())
+    }
+}
 
-            let mut rowid = 1;
-
-            trace!("about to loop through rows!");
-
-            loop {
-                match rows.next() {
-                    Ok(Some(row)) => {
-                        trace!("got a row: {row:?}");
-                        match (0..col_count)
-                            .map(|i| row.get::<_, SqliteValue>(i))
-                            .collect::<rusqlite::Result<Vec<_>>>()
-                        {
-                            Ok(cells) => {
-                                if let Err(e) =
-                                    data_tx.blocking_send(QueryEvent::Row(rowid.into(), cells))
-                                {
-                                    error!("could not send back row: {e}");
-                                    return;
-                                }
-                                rowid += 1;
-                            }
-                            Err(e) => {
-                                _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
-                                return;
-                            }
-                        }
-                    }
-                    Ok(None) => {
-                        // done!
-                        break;
-                    }
-                    Err(e) => {
-                        _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
-                        return;
-                    }
-                }
+/// Checks shared by buffered and streamed `/v1/transactions` requests
+fn check_exec_statements(
+    agent: &Agent,
+    headers: &HeaderMap,
+    statements: &[Statement],
+) -> Result<(), (StatusCode, String)> {
+    if statements.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "at least 1 statement is required".into(),
+        ));
+    }
+
+    let limits = json_limits_from_headers(agent.config().api.json_limits, headers)
+        .map_err(|error| (StatusCode::BAD_REQUEST, error))?;
+
+    check_json_limits(&limits, statements).map_err(|(i, error)| {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("statement {i}: {error}"),
+        )
+    })
+}
+
+const NO_REPLICATION_HAZARD: &str = "writes w/o replication never reach other nodes, nor \
+     are they recorded by cr-sqlite: this node's copy of the table diverges from other \
+     nodes' unless the exact same writes are applied on every one of them, and later \
+     replicated changes to the same rows are resolved against stale versions";
+
+/// Checks a `no_replication` request carries `api.authorization`'s token and
+/// only writes to `db.no_replication_tables`, returning the tables it writes
+/// to.
+async fn check_no_replication(
+    agent: &Agent,
+    headers: &HeaderMap,
+    statements: &[Statement],
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let config = agent.config();
+
+    if !has_admin_token(agent, headers) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("no_replication requires the api.authorization token; {NO_REPLICATION_HAZARD}"),
+        ));
+    }
+
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let mut tables: Vec<String> = vec![];
+    for (i, stmt) in statements.iter().enumerate() {
+        let access = block_in_place(|| statement_access(&conn, stmt.query()))
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("statement {i}: {e}")))?;
+        for table in access.writes {
+            if !config.db.allows_no_replication(&table) {
+                return Err((
+                    StatusCode::FORBIDDEN,
+                    format!(
+                        "no_replication refused, table {table} isn't in db.no_replication_tables; {NO_REPLICATION_HAZARD}"
+                    ),
+                ));
+            }
+            if !tables.contains(&table) {
+                tables.push(table);
             }
+        }
+    }
 
-            _ = data_tx.blocking_send(QueryEvent::EndOfQuery {
-                time: elapsed.as_secs_f64(),
-                change_id: None,
-            });
-        });
-    });
+    Ok(tables)
+}
 
-    match res_rx.await {
-        Ok(res) => res,
-        Err(e) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            ExecResult::Error {
-                error: e.to_string(),
-            },
-        )),
+/// Whether the request carries `api.authorization`'s token, never true when
+/// it isn't configured
+fn has_admin_token(agent: &Agent, headers: &HeaderMap) -> bool {
+    match (&agent.config().api.authorization, bearer_token(headers)) {
+        (Some(AuthzConfig::BearerToken(expected)), Some(token)) => expected.expose() == token,
+        _ => false,
     }
 }
 
-pub async fn api_v1_queries(
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Suspends cr-sqlite's change capture on a connection until dropped, its
+/// triggers skip recording writes while the sync bit is set
+struct CaptureSuspended<'a>(&'a rusqlite::Connection);
+
+impl<'a> CaptureSuspended<'a> {
+    fn new(conn: &'a rusqlite::Connection) -> rusqlite::Result<Self> {
+        conn.query_row("SELECT crsql_internal_sync_bit(1)", [], |_| Ok(()))?;
+        Ok(Self(conn))
+    }
+}
+
+impl Drop for CaptureSuspended<'_> {
+    fn drop(&mut self) {
+        // the write conn is shared, it can't be left w/o change capture
+        if let Err(e) = self
+            .0
+            .query_row("SELECT crsql_internal_sync_bit(0)", [], |_| Ok(()))
+        {
+            error!("could not resume change capture: {e}");
+        }
+    }
+}
+
+/// Routes `/v1/transactions` requests, streaming the response when the
+/// request has `stream_returning` set. Responses carry the `corro-degraded`
+/// header while the agent's storage is degraded. Clients over their
+/// `api.quotas` are throttled before anything runs.
+pub async fn api_v1_exec(
     Extension(agent): Extension<Agent>,
-    axum::extract::Json(stmt): axum::extract::Json<Statement>,
-) -> impl IntoResponse {
-    let (mut tx, body) = hyper::Body::channel();
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    axum::extract::Json(req): axum::extract::Json<ExecRequest>,
+) -> axum::response::Response {
+    let degraded = agent.is_degraded();
+    let mut origin = exec_origin(connect_info.map(|ConnectInfo(addr)| addr), &headers);
+
+    let identity = ClientIdentity::new(
+        bearer_token(&headers),
+        origin.client_addr.map(|addr| addr.ip()),
+    );
+    if let Some(quotas) = agent.config().api.quotas.as_ref() {
+        if let Err(throttled) = agent
+            .quotas()
+            .admit(quotas, &identity, req.statements().len())
+        {
+            return throttled_response(&identity, throttled);
+        }
+        origin.quota = Some(identity);
+    }
+    origin.usage = Some(identity);
 
-    // TODO: timeout on data send instead of infinitely waiting for channel space.
-    let (data_tx, mut data_rx) = channel(512);
+    let mut res = if req.is_stream_returning() {
+        exec_transactions_stream(agent, headers, req, origin).await
+    } else {
+        exec_transactions(agent, headers, req, origin)
+            .await
+            .into_response()
+    };
 
-    tokio::spawn(async move {
-        let mut buf = BytesMut::new();
+    if degraded {
+        res.headers_mut()
+            .insert(DEGRADED_HEADER, HeaderValue::from_static("true"));
+    }
 
-        while let Some(row_res) = data_rx.recv().await {
-            {
-                let mut writer = (&mut buf).writer();
-                if let Err(e) = serde_json::to_writer(&mut writer, &row_res) {
-                    _ = tx
-                        .send_data(
-                            serde_json::to_vec(&serde_json::json!(QueryEvent::Error(
-                                e.to_compact_string()
-                            )))
-                            .expect("could not serialize error json")
-                            .into(),
-                        )
-                        .await;
-                    return;
+    res
+}
+
+/// Routes `/v1/transactions` requests by their body: a plain array of
+/// statements runs while it's received, see `exec_body`, other bodies are
+/// buffered and handed to `api_v1_exec`. Arrays are buffered too when
+/// `api.quotas` applies, it counts statements upfront. Bodies over
+/// `api.max_body_bytes` are refused.
+pub async fn api_v1_exec_body(
+    Extension(agent): Extension<Agent>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request<hyper::Body>,
+) -> axum::response::Response {
+    let (max_body_bytes, has_quotas) = {
+        let config = agent.config();
+        (config.api.max_body_bytes, config.api.quotas.is_some())
+    };
+    let (parts, body) = request.into_parts();
+    let headers = parts.headers.clone();
+
+    // refused before reading anything when its size is known
+    let content_length = headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if let (Some(len), Some(max)) = (content_length, max_body_bytes) {
+        if len > max {
+            return exec_error_response(ChangeError::BodyTooLarge(format!(
+                "request body is {len} bytes, over api.max_body_bytes ({max})"
+            )))
+            .into_response();
+        }
+    }
+
+    let body = match PeekedBody::peek(body, max_body_bytes).await {
+        Ok(body) => body,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("could not read request body: {e}"),
+            )
+                .into_response()
+        }
+    };
+    let is_json = headers
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| value.starts_with("application/json"));
+    let streamed = body.is_array() && is_json && !has_quotas;
+    let body = body.into_body(max_body_bytes);
+
+    if !streamed {
+        return match axum::Json::<ExecRequest>::from_request(Request::from_parts(parts, body), &())
+            .await
+        {
+            Ok(req) => api_v1_exec(Extension(agent), connect_info, headers, req).await,
+            Err(rejection) => rejection.into_response(),
+        };
+    }
+
+    let degraded = agent.is_degraded();
+    let mut origin = exec_origin(connect_info.map(|ConnectInfo(addr)| addr), &headers);
+    origin.usage = Some(ClientIdentity::new(
+        bearer_token(&headers),
+        origin.client_addr.map(|addr| addr.ip()),
+    ));
+
+    let mut res = exec_streamed(agent, headers, body, origin)
+        .await
+        .into_response();
+    if degraded {
+        res.headers_mut()
+            .insert(DEGRADED_HEADER, HeaderValue::from_static("true"));
+    }
+
+    res
+}
+
+/// Executes the statements of `body`, a JSON array, in a single transaction
+/// as they're parsed out of it. The transaction begins once the first one
+/// is, a malformed statement rolls back the ones before it.
+async fn exec_streamed(
+    agent: Agent,
+    headers: HeaderMap,
+    body: Limited<hyper::Body>,
+    origin: ExecOrigin,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    let limits = match json_limits_from_headers(agent.config().api.json_limits, &headers) {
+        Ok(limits) => limits,
+        Err(error) => return exec_error_response(ChangeError::InvalidParams(error)),
+    };
+
+    let mut statements = stream_statements(body);
+    let first = match statements.recv().await {
+        Some(Ok(stmt)) => stmt,
+        Some(Err(e)) => return exec_error_response(e),
+        None => {
+            return exec_error_response(ChangeError::InvalidParams(
+                "at least 1 statement is required".into(),
+            ))
+        }
+    };
+
+    let slow_after = Duration::from_millis(agent.config().api.slow_statement_ms);
+    let usage = origin.usage;
+    let idempotency_key = origin.idempotency_key.clone();
+    let audited = origin.clone();
+    let tracker = agent.exec_registry().register(origin, 0);
+
+    // `run_changes` takes a `Fn`, it only calls it once though
+    let pending = Mutex::new((Some(first), statements, JsonLimitsCheck::new(limits)));
+    let res = run_changes(
+        &agent,
+        SessionOptions::default(),
+        &tracker,
+        false,
+        |tx, tracker| {
+            if let Some(key) = idempotency_key.as_deref() {
+                if let Some(results) = applied_results(tx, key)? {
+                    debug!(key, "already applied, responding w/ the same results");
+                    return Ok(results);
                 }
             }
 
-            buf.extend_from_slice(b"\n");
+            let mut pending = pending.lock();
+            let (first, statements, json_limits) = &mut *pending;
+
+            let mut results = vec![];
+            // blocks until the next statement is parsed, `None` once they all
+            // were
+            while let Some(stmt) = first.take().map(Ok).or_else(|| statements.blocking_recv()) {
+                let stmt = stmt?;
+                let index = results.len();
+                json_limits.statement(&stmt).map_err(|error| {
+                    ChangeError::BodyTooLarge(format!("statement {index}: {error}"))
+                })?;
+                audit_exec(&agent, &headers, &audited, [(index, &stmt)]);
+                results.push(exec_in_transaction(tx, tracker, &stmt, index, slow_after)?);
+            }
 
-            if let Err(e) = tx.send_data(buf.split().freeze()).await {
-                error!("could not send data through body's channel: {e}");
-                return;
+            if let Some(key) = idempotency_key.as_deref() {
+                record_applied(tx, key, &results)?;
             }
-        }
-        debug!("query body channel done");
-    });
 
-    trace!("building query rows response...");
+            Ok(results)
+        },
+    )
+    .await;
 
-    match build_query_rows_response(&agent, data_tx, stmt).await {
-        Ok(_) => {
-            #[allow(clippy::needless_return)]
-            return hyper::Response::builder()
-                .status(StatusCode::OK)
-                .body(body)
-                .expect("could not build query response body");
-        }
-        Err((status, res)) => {
-            #[allow(clippy::needless_return)]
-            return hyper::Response::builder()
-                .status(status)
-                .body(
-                    serde_json::to_vec(&res)
-                        .expect("could not serialize query error response")
-                        .into(),
-                )
-                .expect("could not build query response body");
+    match res {
+        Ok((results, elapsed, tally)) => {
+            record_usage(&agent, usage, tracker.statements(), &tally);
+            (
+                StatusCode::OK,
+                axum::Json(ExecResponse {
+                    results,
+                    time: elapsed.as_secs_f64(),
+                    changes_generated: None,
+                    dry_run: false,
+                }),
+            )
         }
+        Err(e) => exec_error_response(e),
     }
 }
 
-async fn execute_schema(agent: &Agent, statements: Vec<String>) -> eyre::Result<()> {
-    let new_sql: String = statements.join(";");
+// lists a request as its client's address and `idempotency-key` header
+fn exec_origin(client_addr: Option<SocketAddr>, headers: &HeaderMap) -> ExecOrigin {
+    ExecOrigin {
+        client_addr,
+        idempotency_key: headers
+            .get(IDEMPOTENCY_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned),
+        source_id: None,
+        quota: None,
+        usage: None,
+    }
+}
 
-    let partial_schema = parse_sql(&new_sql)?;
+fn throttled_response(identity: &ClientIdentity, throttled: Throttled) -> axum::response::Response {
+    increment_counter!("corro.api.quota.throttled", "quota" => throttled.throttled.to_string());
+    debug!(%identity, "throttled request: {throttled}");
 
-    let mut conn = agent.pool().write_priority().await?;
+    let retry_after_secs = throttled.retry_after_ms.div_ceil(1000);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(
+            hyper::header::RETRY_AFTER,
+            HeaderValue::from(retry_after_secs),
+        )],
+        axum::Json(throttled),
+    )
+        .into_response()
+}
 
-    // hold onto this lock so nothing else makes changes
-    let mut schema_write = agent.schema().write();
+/// Charges the bytes of changes a transaction generated to its client's quota
+fn charge_quota(agent: &Agent, identity: Option<ClientIdentity>, tally: &ChangeTally) {
+    let config = agent.config();
+    if let (Some(identity), Some(quotas)) = (identity, config.api.quotas.as_ref()) {
+        agent
+            .quotas()
+            .charge_change_bytes(quotas, &identity, tally.bytes());
+    }
+}
 
-    // clone the previous schema and apply
-    let mut new_schema = {
-        let mut schema = schema_write.clone();
-        for (name, def) in partial_schema.tables.iter() {
-            // overwrite table because users are expected to return a full table def
-            schema.tables.insert(name.clone(), def.clone());
-        }
-        schema
+/// Credits a committed transaction's statements and changes to its client
+fn record_usage(
+    agent: &Agent,
+    identity: Option<ClientIdentity>,
+    statements: usize,
+    tally: &ChangeTally,
+) {
+    if let Some(identity) = identity {
+        agent
+            .usage()
+            .client(&identity)
+            .exec(statements, tally.changes(), tally.bytes());
+    }
+}
+
+/// Logs a request's statements, by index, to the `corro::audit` target, w/
+/// who sent them, when `log.audit` is configured
+fn audit_exec<'a>(
+    agent: &Agent,
+    headers: &HeaderMap,
+    origin: &ExecOrigin,
+    statements: impl IntoIterator<Item = (usize, &'a Statement)>,
+) {
+    let config = agent.config();
+    let Some(audit) = config.log.audit.as_ref() else {
+        return;
     };
 
-    new_schema.constrain()?;
+    let admin = has_admin_token(agent, headers);
+    for (index, stmt) in statements {
+        let rendered = stmt.render_for_audit_masked(audit.max_value_len, |table, column| {
+            audit.is_sensitive(table, column)
+        });
+        info!(
+            target: "corro::audit",
+            client_addr = ?origin.client_addr,
+            idempotency_key = ?origin.idempotency_key,
+            source_id = ?origin.source_id,
+            admin,
+            index,
+            statement = %rendered,
+            "exec"
+        );
+    }
+}
 
-    block_in_place(|| {
-        let tx = conn.transaction()?;
+/// Executes all statements in a single transaction, streaming `ExecEvent`s
+/// as newline-delimited JSON. Errors detected before anything runs are
+/// responded to like `api_v1_transactions` does.
+pub async fn api_v1_transactions_stream(
+    Extension(agent): Extension<Agent>,
+    headers: HeaderMap,
+    axum::extract::Json(req): axum::extract::Json<ExecRequest>,
+) -> axum::response::Response {
+    let origin = exec_origin(None, &headers);
+    exec_transactions_stream(agent, headers, req, origin).await
+}
 
-        apply_schema(&tx, &schema_write, &mut new_schema)?;
+async fn exec_transactions_stream(
+    agent: Agent,
+    headers: HeaderMap,
+    req: ExecRequest,
+    mut origin: ExecOrigin,
+) -> axum::response::Response {
+    let (statements, isolation, defer_foreign_keys, session, no_replication, dry_run) = match req {
+        ExecRequest::Statements(statements) => (
+            statements,
+            ExecIsolation::default(),
+            false,
+            None,
+            false,
+            false,
+        ),
+        ExecRequest::WithOptions {
+            statements,
+            isolation,
+            defer_foreign_keys,
+            session,
+            no_replication,
+            source_id,
+            dry_run,
+            ..
+        } => {
+            origin.source_id = source_id;
+            (
+                statements,
+                isolation,
+                defer_foreign_keys,
+                session,
+                no_replication,
+                dry_run,
+            )
+        }
+    };
 
-        for tbl_name in partial_schema.tables.keys() {
-            tx.execute("DELETE FROM __corro_schema WHERE tbl_name = ?", [tbl_name])?;
+    audit_exec(&agent, &headers, &origin, statements.iter().enumerate());
 
-            let n = tx.execute("INSERT INTO __corro_schema SELECT tbl_name, type, name, sql, 'api' AS source FROM sqlite_schema WHERE tbl_name = ? AND type IN ('table', 'index') AND name IS NOT NULL AND sql IS NOT NULL", [tbl_name])?;
-            info!("Updated {n} rows in __corro_schema for table {tbl_name}");
+    let check = check_exec_statements(&agent, &headers, &statements).and_then(|_| {
+        if isolation != ExecIsolation::Transaction {
+            Err((
+                StatusCode::BAD_REQUEST,
+                "stream_returning requires transaction isolation".into(),
+            ))
+        } else if no_replication {
+            Err((
+                StatusCode::BAD_REQUEST,
+                "no_replication can't be combined w/ stream_returning".into(),
+            ))
+        } else {
+            Ok(())
         }
+    });
+    if let Err((status, error)) = check {
+        return (
+            status,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error { error, code: None }],
+                time: 0.0,
+                changes_generated: None,
+                dry_run: false,
+            }),
+        )
+            .into_response();
+    }
 
-        tx.commit()?;
+    let (mut body_tx, body) = hyper::Body::channel();
+    let (evt_tx, mut evt_rx) = channel::<ExecEvent>(512);
 
-        Ok::<_, eyre::Report>(())
-    })?;
+    tokio::spawn(async move {
+        let mut buf = BytesMut::new();
 
-    *schema_write = new_schema;
+        while let Some(evt) = evt_rx.recv().await {
+            serde_json::to_writer((&mut buf).writer(), &evt)
+                .expect("could not serialize exec event");
+            buf.extend_from_slice(b"\n");
 
-    Ok(())
+            if let Err(e) = body_tx.send_data(buf.split().freeze()).await {
+                debug!("could not send exec event, client is gone: {e}");
+                return;
+            }
+        }
+    });
+
+    let quota = origin.quota;
+    let usage = origin.usage;
+    let count = statements.len();
+    let tracker = agent.exec_registry().register(origin, count);
+    tokio::spawn(async move {
+        let res = run_changes(
+            &agent,
+            session.unwrap_or_default(),
+            &tracker,
+            dry_run,
+            |tx, tracker| {
+                if defer_foreign_keys {
+                    tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+                }
+                for (index, stmt) in statements.iter().enumerate() {
+                    tracker.check()?;
+                    tracker.statement(index, stmt.query());
+                    stream_statement(tx, stmt, index, &evt_tx)?;
+                }
+                Ok(())
+            },
+        )
+        .await;
+
+        let evt = match res {
+            Ok(((), elapsed, _)) if dry_run => ExecEvent::DryRun {
+                time: elapsed.as_secs_f64(),
+            },
+            Ok(((), elapsed, tally)) => {
+                charge_quota(&agent, quota, &tally);
+                record_usage(&agent, usage, count, &tally);
+                ExecEvent::Commit {
+                    time: elapsed.as_secs_f64(),
+                }
+            }
+            Err(e) => {
+                debug!("streamed transaction rolled back: {e}");
+                ExecEvent::Error(e.to_compact_string())
+            }
+        };
+        _ = evt_tx.send(evt).await;
+    });
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .body(body)
+        .expect("could not build exec response body")
+        .into_response()
 }
 
-pub async fn api_v1_db_schema(
+pub async fn api_v1_transactions(
+    // axum::extract::RawQuery(raw_query): axum::extract::RawQuery,
     Extension(agent): Extension<Agent>,
-    axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
+    headers: HeaderMap,
+    axum::extract::Json(req): axum::extract::Json<ExecRequest>,
 ) -> (StatusCode, axum::Json<ExecResponse>) {
-    if statements.is_empty() {
+    let origin = exec_origin(None, &headers);
+    exec_transactions(agent, headers, req, origin).await
+}
+
+#[tracing::instrument(skip_all)]
+async fn exec_transactions(
+    agent: Agent,
+    headers: HeaderMap,
+    req: ExecRequest,
+    mut origin: ExecOrigin,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    let (
+        statements,
+        isolation,
+        groups,
+        defer_foreign_keys,
+        session,
+        report_changes,
+        no_replication,
+        dry_run,
+    ) = match req {
+        ExecRequest::Statements(statements) => (
+            statements,
+            ExecIsolation::default(),
+            None,
+            false,
+            None,
+            false,
+            false,
+            false,
+        ),
+        ExecRequest::WithOptions {
+            statements,
+            isolation,
+            groups,
+            defer_foreign_keys,
+            session,
+            report_changes,
+            no_replication,
+            source_id,
+            dry_run,
+            ..
+        } => {
+            origin.source_id = source_id;
+            (
+                statements,
+                isolation,
+                groups,
+                defer_foreign_keys,
+                session,
+                report_changes,
+                no_replication,
+                dry_run,
+            )
+        }
+    };
+
+    audit_exec(&agent, &headers, &origin, statements.iter().enumerate());
+
+    if let Err((status, error)) = check_exec_statements(&agent, &headers, &statements) {
         return (
-            StatusCode::BAD_REQUEST,
+            status,
             axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: "at least 1 statement is required".into(),
-                }],
+                results: vec![ExecResult::Error { error, code: None }],
                 time: 0.0,
+                changes_generated: None,
+                dry_run: false,
             }),
         );
     }
 
-    let start = Instant::now();
+    let groups = groups.unwrap_or_else(|| vec![1; statements.len()]);
+    if isolation == ExecIsolation::Statement
+        && (groups.iter().sum::<usize>() != statements.len() || groups.contains(&0))
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error {
+                    error: "groups must be non-empty and add up to the number of statements".into(),
+                    code: None,
+                }],
+                time: 0.0,
+                changes_generated: None,
+                dry_run: false,
+            }),
+        );
+    }
+
+    let unreplicated = if no_replication {
+        match check_no_replication(&agent, &headers, &statements).await {
+            Ok(tables) => tables,
+            Err((status, error)) => {
+                return (
+                    status,
+                    axum::Json(ExecResponse {
+                        results: vec![ExecResult::Error { error, code: None }],
+                        time: 0.0,
+                        changes_generated: None,
+                        dry_run: false,
+                    }),
+                );
+            }
+        }
+    } else {
+        vec![]
+    };
+
+    let count = statements.len();
+    let slow_after = Duration::from_millis(agent.config().api.slow_statement_ms);
+    let quota = origin.quota;
+    let usage = origin.usage;
+    // dry runs apply nothing, they can be repeated
+    let idempotency_key = origin.idempotency_key.clone().filter(|_| !dry_run);
+    let tracker = agent.exec_registry().register(origin, count);
+    let res = run_changes(
+        &agent,
+        session.unwrap_or_default(),
+        &tracker,
+        dry_run,
+        move |tx, tracker| {
+            if let Some(key) = idempotency_key.as_deref() {
+                if let Some(results) = applied_results(tx, key)? {
+                    debug!(key, "already applied, responding w/ the same results");
+                    return Ok(results);
+                }
+            }
+
+            if defer_foreign_keys {
+                // sqlite switches this off at the end of every transaction, whether
+                // it commits or rolls back
+                tx.execute_batch("PRAGMA defer_foreign_keys = ON")?;
+            }
+
+            let _suspended = if no_replication {
+                Some(CaptureSuspended::new(tx)?)
+            } else {
+                None
+            };
+
+            let results = match isolation {
+                // mismatched params abort the whole transaction, unlike other
+                // errors which are reported per statement
+                ExecIsolation::Transaction => statements
+                    .iter()
+                    .enumerate()
+                    .map(|(index, stmt)| exec_in_transaction(tx, tracker, stmt, index, slow_after))
+                    .collect::<Result<Vec<ExecResult>, ChangeError>>()?,
+                ExecIsolation::Statement => {
+                    let mut stmts = statements.iter().enumerate();
+                    groups
+                        .iter()
+                        .map(|size| {
+                            tracker.check()?;
+                            let group = stmts
+                                .by_ref()
+                                .take(*size)
+                                .inspect(|(index, stmt)| tracker.statement(*index, stmt.query()));
+
+                            Ok(match execute_group(tx, group, slow_after)? {
+                                Ok(res) => res,
+                                Err(ChangeError::Rusqlite(e)) => ExecResult::Error {
+                                    error: e.to_string(),
+                                    code: ExecErrorCode::from_sqlite(&e),
+                                },
+                                Err(e) => ExecResult::Error {
+                                    error: e.to_string(),
+                                    code: e.exec_error_code(),
+                                },
+                            })
+                        })
+                        .collect::<Result<Vec<ExecResult>, ChangeError>>()?
+                }
+            };
+
+            if let Some(key) = idempotency_key.as_deref() {
+                record_applied(tx, key, &results)?;
+            }
+
+            Ok(results)
+        },
+    )
+    .await;
+
+    let (results, elapsed, tally) = match res {
+        Ok(res) if dry_run => res,
+        Ok(res) => {
+            charge_quota(&agent, quota, &res.2);
+            record_usage(&agent, usage, count, &res.2);
+            if no_replication {
+                warn!(
+                    tables = ?unreplicated,
+                    statements = count,
+                    "committed writes w/o replication"
+                );
+                for table in unreplicated {
+                    increment_counter!("corro.api.exec.no_replication", "table" => table);
+                }
+            }
+            res
+        }
+        Err(e) => return exec_error_response(e),
+    };
+
+    (
+        StatusCode::OK,
+        axum::Json(ExecResponse {
+            results,
+            time: elapsed.as_secs_f64(),
+            changes_generated: (report_changes || dry_run).then(|| tally.generated(count)),
+            dry_run,
+        }),
+    )
+}
+
+/// Runs statement `index` of a transaction isolated request. Its sqlite
+/// errors are its result, other errors abort the whole transaction.
+fn exec_in_transaction(
+    tx: &Transaction,
+    tracker: &ExecTracker,
+    stmt: &Statement,
+    index: usize,
+    slow_after: Duration,
+) -> Result<ExecResult, ChangeError> {
+    // an interrupted statement errors like any other, the kill is only
+    // noticed here
+    tracker.check()?;
+    tracker.statement(index, stmt.query());
+
+    let start = Instant::now();
+    match execute_statement(tx, stmt, index, slow_after) {
+        Ok(rows_affected) => Ok(ExecResult::Execute {
+            rows_affected,
+            time: start.elapsed().as_secs_f64(),
+        }),
+        Err(ChangeError::Rusqlite(e)) => Ok(ExecResult::Error {
+            error: e.to_string(),
+            code: ExecErrorCode::from_sqlite(&e),
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+/// Responds to a `/v1/transactions` request whose transaction failed, and
+/// was rolled back
+fn exec_error_response(e: ChangeError) -> (StatusCode, axum::Json<ExecResponse>) {
+    let (status, code, error) = match e {
+        ChangeError::InvalidParams(_) | ChangeError::Malformed { .. } => {
+            (StatusCode::BAD_REQUEST, None, e.to_string())
+        }
+        ChangeError::TooLarge { .. } | ChangeError::BodyTooLarge(_) => {
+            (StatusCode::PAYLOAD_TOO_LARGE, None, e.to_string())
+        }
+        // deferred foreign key violations surface when committing
+        ChangeError::Rusqlite(e)
+            if e.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation) =>
+        {
+            (StatusCode::BAD_REQUEST, None, e.to_string())
+        }
+        e => {
+            error!("could not execute statement(s): {e}");
+            let code = e.exec_error_code();
+            let status = match code {
+                Some(ExecErrorCode::Full) => StatusCode::INSUFFICIENT_STORAGE,
+                Some(ExecErrorCode::Busy) => StatusCode::SERVICE_UNAVAILABLE,
+                Some(ExecErrorCode::Interrupted) => StatusCode::CONFLICT,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, code, e.to_string())
+        }
+    };
+
+    (
+        status,
+        axum::Json(ExecResponse {
+            results: vec![ExecResult::Error { error, code }],
+            time: 0.0,
+            changes_generated: None,
+            dry_run: false,
+        }),
+    )
+}
+
+// results of the transaction applied w/ `key` if it was, within
+// `IDEMPOTENCY_KEY_TTL`
+fn applied_results(tx: &Transaction, key: &str) -> rusqlite::Result<Option<Vec<ExecResult>>> {
+    let results: Option<serde_json::Value> = tx
+        .prepare_cached("SELECT results FROM __corro_idempotency_keys WHERE key = ?")?
+        .query_row([key], |row| row.get(0))
+        .optional()?;
+    results
+        .map(|results| {
+            serde_json::from_value(results).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+            })
+        })
+        .transpose()
+}
+
+// in the same transaction as the statements, so they're never applied w/o
+// their key being recorded. Forgets the keys past their ttl along the way.
+fn record_applied(tx: &Transaction, key: &str, results: &[ExecResult]) -> rusqlite::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let expired = now.saturating_sub(IDEMPOTENCY_KEY_TTL);
+
+    tx.prepare_cached("DELETE FROM __corro_idempotency_keys WHERE applied_at < ?")?
+        .execute([expired.as_millis() as i64])?;
+    let results = serde_json::to_value(results)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+    tx.prepare_cached(
+        "INSERT INTO __corro_idempotency_keys (key, results, applied_at) VALUES (?, ?, ?)",
+    )?
+    .execute(params![key, results, now.as_millis() as i64])?;
+    Ok(())
+}
+
+/// Lists `/v1/transactions` requests running or waiting for the write
+/// connection, oldest first
+pub async fn api_v1_active_transactions(
+    Extension(agent): Extension<Agent>,
+) -> axum::Json<Vec<ActiveTransaction>> {
+    axum::Json(agent.exec_registry().list())
+}
+
+/// Lists what's left of the quotas of clients seen recently, empty when
+/// `api.quotas` isn't configured
+pub async fn api_v1_quotas(Extension(agent): Extension<Agent>) -> axum::Json<Vec<QuotaUsage>> {
+    let config = agent.config();
+    axum::Json(match config.api.quotas.as_ref() {
+        Some(quotas) => agent.quotas().usage(quotas),
+        None => vec![],
+    })
+}
+
+/// The agent's effective config w/ secrets redacted, and where it was loaded
+/// from
+pub async fn api_v1_config(
+    Extension(agent): Extension<Agent>,
+) -> Result<axum::Json<ConfigResponse>, (StatusCode, String)> {
+    agent
+        .config()
+        .to_response()
+        .map(axum::Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Kills an active transaction: its current statement is interrupted and it
+/// rolls back, its client gets an `Interrupted` error. Requires the
+/// `api.authorization` token.
+pub async fn api_v1_kill_transaction(
+    Extension(agent): Extension<Agent>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> axum::response::Response {
+    if !has_admin_token(&agent, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            axum::Json(ExecResult::Error {
+                error: "killing transactions requires the api.authorization token".into(),
+                code: None,
+            }),
+        )
+            .into_response();
+    }
+
+    match agent.exec_registry().kill(id) {
+        Some(killed) => {
+            warn!(id, sql = ?killed.sql, "killed transaction");
+            increment_counter!("corro.api.transactions.killed");
+            axum::Json(killed).into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            axum::Json(ExecResult::Error {
+                error: format!("no active transaction {id}"),
+                code: None,
+            }),
+        )
+            .into_response(),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("pool connection acquisition error")]
+    Pool(#[from] SqlitePoolError),
+    #[error("sqlite error: {0}")]
+    Rusqlite(#[from] rusqlite::Error),
+}
+
+/// Opens a dedicated read-only connection where tables changed after
+/// `db_version` are shadowed w/ their state as of that version. The read
+/// transaction is never committed, temp tables go away w/ the connection.
+fn open_as_of(
+    agent: &Agent,
+    db_version: i64,
+) -> Result<rusqlite::Connection, (StatusCode, ExecResult)> {
+    let internal = |e: rusqlite::Error| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            },
+        )
+    };
+
+    let conn = rusqlite::Connection::open_with_flags(
+        &agent.config().db.path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(internal)?;
+    conn.execute_batch("BEGIN").map_err(internal)?;
+
+    let shadowed =
+        history::shadow_as_of(&conn, &agent.schema().read(), db_version).map_err(|e| match e {
+            AsOfError::Rusqlite(e) => internal(e),
+            e => (
+                StatusCode::BAD_REQUEST,
+                ExecResult::Error {
+                    error: e.to_string(),
+                    code: None,
+                },
+            ),
+        })?;
+    debug!("reading as of db_version {db_version}, shadowed tables: {shadowed:?}");
+
+    Ok(conn)
+}
+
+// sqlite VM instructions between checks of a query's timeout
+const TIMEOUT_CHECK_INSTRUCTIONS: i32 = 1000;
+// times a query starting on a stale read snapshot is retried
+const MAX_BUSY_SNAPSHOT_RETRIES: u32 = 3;
+const BUSY_SNAPSHOT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// What `query_rows` sends back. Column names are interned, every run of the
+/// same query shares them.
+#[derive(Debug)]
+enum RowsEvent {
+    Columns(Arc<[CompactString]>),
+    Event(QueryEvent),
+}
+
+/// Runs a read-only statement, sending back whether it could start through
+/// `res_tx` and its events through `data_tx`.
+///
+/// A query going over `limits` is stopped w/ a `QueryEvent::Error`.
+fn query_rows(
+    conn: &rusqlite::Connection,
+    stmt: Statement,
+    limits: QueryLimits,
+    coerce: Option<Coercion>,
+    res_tx: oneshot::Sender<Result<(), (StatusCode, ExecResult)>>,
+    data_tx: &mpsc::Sender<RowsEvent>,
+) {
+    let timeout = limits.timeout_ms.map(Duration::from_millis);
+    if let Some(timeout) = timeout {
+        let started = Instant::now();
+        conn.progress_handler(
+            TIMEOUT_CHECK_INSTRUCTIONS,
+            Some(move || started.elapsed() > timeout),
+        );
+    }
+
+    query_rows_inner(conn, stmt, limits, coerce, res_tx, data_tx);
+
+    if timeout.is_some() {
+        conn.progress_handler(0, None::<fn() -> bool>);
+    }
+}
+
+fn bind_query<'a>(
+    prepped: &'a mut rusqlite::Statement<'_>,
+    stmt: &Statement,
+) -> rusqlite::Result<rusqlite::Rows<'a>> {
+    match stmt {
+        Statement::Simple(_)
+        | Statement::Verbose {
+            params: None,
+            named_params: None,
+            ..
+        } => prepped.query(()),
+        Statement::WithParams(_, params)
+        | Statement::Registered { params, .. }
+        | Statement::Verbose {
+            params: Some(params),
+            ..
+        } => prepped.query(params_from_iter(params)),
+        Statement::WithNamedParams(_, params)
+        | Statement::Verbose {
+            named_params: Some(params),
+            ..
+        } => prepped.query(
+            params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+                .collect::<Vec<(&str, &dyn ToSql)>>()
+                .as_slice(),
+        ),
+    }
+}
+
+fn query_error(e: rusqlite::Error, limits: QueryLimits) -> String {
+    match (e.sqlite_error_code(), limits.timeout_ms) {
+        (Some(rusqlite::ErrorCode::OperationInterrupted), Some(timeout_ms)) => {
+            format!("query timed out after {timeout_ms}ms")
+        }
+        _ => e.to_string(),
+    }
+}
+
+fn query_rows_inner(
+    conn: &rusqlite::Connection,
+    stmt: Statement,
+    limits: QueryLimits,
+    coerce: Option<Coercion>,
+    res_tx: oneshot::Sender<Result<(), (StatusCode, ExecResult)>>,
+    data_tx: &mpsc::Sender<RowsEvent>,
+) {
+    let prepped_res = conn.prepare(stmt.query());
+
+    let mut prepped = match prepped_res {
+        Ok(prepped) => prepped,
+        Err(e) => {
+            _ = res_tx.send(Err((
+                StatusCode::BAD_REQUEST,
+                ExecResult::Error {
+                    error: e.to_string(),
+                    code: None,
+                },
+            )));
+            return;
+        }
+    };
+
+    if !prepped.readonly() {
+        _ = res_tx.send(Err((
+            StatusCode::BAD_REQUEST,
+            ExecResult::Error {
+                error: "statement is not readonly".into(),
+                code: None,
+            },
+        )));
+        return;
+    }
+
+    let col_count = prepped.column_count();
+    trace!("inside block in place, col count: {col_count}");
+
+    let columns = interned_column_names(stmt.query(), &prepped);
+    if let Err(e) = data_tx.blocking_send(RowsEvent::Columns(columns.clone())) {
+        error!("could not send back columns: {e}");
+        return;
+    }
+
+    let start = Instant::now();
+
+    let mut rows = match bind_query(&mut prepped, &stmt) {
+        Ok(rows) => rows,
+        Err(e) => {
+            _ = res_tx.send(Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ExecResult::Error {
+                    error: query_error(e, limits),
+                    code: None,
+                },
+            )));
+            return;
+        }
+    };
+    let elapsed = start.elapsed();
+
+    if let Err(_e) = res_tx.send(Ok(())) {
+        error!("could not send back response through oneshot channel, aborting");
+        return;
+    }
+
+    let mut rowid = 1;
+    let mut busy_snapshot_retries = 0;
+    let mut coerced = vec![false; col_count];
+
+    trace!("about to loop through rows!");
+
+    loop {
+        match rows.next() {
+            Ok(Some(row)) => {
+                if !send_row(row, rowid, limits, coerce, &mut coerced, data_tx) {
+                    return;
+                }
+                rowid += 1;
+            }
+            Ok(None) => {
+                // done!
+                break;
+            }
+            // nothing was sent yet, start over from a fresh snapshot
+            Err(e)
+                if rowid == 1
+                    && is_busy_snapshot(&e)
+                    && busy_snapshot_retries < MAX_BUSY_SNAPSHOT_RETRIES =>
+            {
+                busy_snapshot_retries += 1;
+                increment_counter!("corro.api.queries.busy_snapshot.retries");
+                debug!("read snapshot went stale, retrying ({busy_snapshot_retries}/{MAX_BUSY_SNAPSHOT_RETRIES})");
+                drop(rows);
+                std::thread::sleep(BUSY_SNAPSHOT_BACKOFF * busy_snapshot_retries);
+                rows = match bind_query(&mut prepped, &stmt) {
+                    Ok(rows) => rows,
+                    Err(e) => {
+                        _ = data_tx.blocking_send(RowsEvent::Event(QueryEvent::Error(
+                            query_error(e, limits).into(),
+                        )));
+                        return;
+                    }
+                };
+            }
+            Err(e) => {
+                _ = data_tx.blocking_send(RowsEvent::Event(QueryEvent::Error(
+                    query_error(e, limits).into(),
+                )));
+                return;
+            }
+        }
+    }
+
+    _ = data_tx.blocking_send(RowsEvent::Event(end_of_query(elapsed, &columns, coerced)));
+}
+
+/// Sends `row` as the `rowid`th of a query, returns whether the query can go
+/// on. `coerced` tracks which columns had values converted by `coerce`.
+fn send_row(
+    row: &rusqlite::Row,
+    rowid: i64,
+    limits: QueryLimits,
+    coerce: Option<Coercion>,
+    coerced: &mut [bool],
+    data_tx: &mpsc::Sender<RowsEvent>,
+) -> bool {
+    trace!("got a row: {row:?}");
+    if let Some(max_rows) = limits.max_rows {
+        if rowid as u64 > max_rows {
+            _ = data_tx.blocking_send(RowsEvent::Event(QueryEvent::Error(
+                format!("query returned more than {max_rows} rows").into(),
+            )));
+            return false;
+        }
+    }
+    match (0..coerced.len())
+        .map(|i| row.get::<_, SqliteValue>(i))
+        .collect::<rusqlite::Result<Vec<_>>>()
+    {
+        Ok(mut cells) => {
+            if let Some(coerce) = coerce {
+                for (cell, coerced) in cells.iter_mut().zip(coerced.iter_mut()) {
+                    *coerced |= coerce.apply(cell);
+                }
+            }
+            if let Err(e) =
+                data_tx.blocking_send(RowsEvent::Event(QueryEvent::Row(rowid.into(), cells)))
+            {
+                error!("could not send back row: {e}");
+                return false;
+            }
+            true
+        }
+        Err(e) => {
+            _ = data_tx.blocking_send(RowsEvent::Event(QueryEvent::Error(e.to_compact_string())));
+            false
+        }
+    }
+}
+
+fn end_of_query(elapsed: Duration, columns: &[CompactString], coerced: Vec<bool>) -> QueryEvent {
+    QueryEvent::EndOfQuery {
+        time: elapsed.as_secs_f64(),
+        change_id: None,
+        coerced: columns
+            .iter()
+            .zip(coerced)
+            .filter_map(|(name, coerced)| coerced.then(|| name.clone()))
+            .collect(),
+    }
+}
+
+/// Runs read-only statements in a single read transaction, so they all see
+/// the same snapshot of the database. Each result set is preceded by a
+/// `QueryEvent::NextResultSet` marker.
+///
+/// Every statement is prepared before any runs, errors are sent back through
+/// `res_tx` then. The strictest of the statements' timeouts bounds the whole
+/// set, their `max_rows` apply to their own result set.
+fn query_rows_multi(
+    conn: &rusqlite::Connection,
+    stmts: Vec<(Statement, QueryLimits)>,
+    coerce: Option<Coercion>,
+    res_tx: oneshot::Sender<Result<(), (StatusCode, ExecResult)>>,
+    data_tx: &mpsc::Sender<RowsEvent>,
+) {
+    let timeout_ms = stmts
+        .iter()
+        .filter_map(|(_, limits)| limits.timeout_ms)
+        .min();
+    if let Some(timeout) = timeout_ms.map(Duration::from_millis) {
+        let started = Instant::now();
+        conn.progress_handler(
+            TIMEOUT_CHECK_INSTRUCTIONS,
+            Some(move || started.elapsed() > timeout),
+        );
+    }
+
+    query_rows_multi_inner(conn, stmts, timeout_ms, coerce, res_tx, data_tx);
+
+    if timeout_ms.is_some() {
+        conn.progress_handler(0, None::<fn() -> bool>);
+    }
+}
+
+fn query_rows_multi_inner(
+    conn: &rusqlite::Connection,
+    stmts: Vec<(Statement, QueryLimits)>,
+    timeout_ms: Option<u64>,
+    coerce: Option<Coercion>,
+    res_tx: oneshot::Sender<Result<(), (StatusCode, ExecResult)>>,
+    data_tx: &mpsc::Sender<RowsEvent>,
+) {
+    let bad_request = |error: String| {
+        Err((
+            StatusCode::BAD_REQUEST,
+            ExecResult::Error { error, code: None },
+        ))
+    };
+
+    let mut prepped = Vec::with_capacity(stmts.len());
+    for (i, (stmt, _)) in stmts.iter().enumerate() {
+        match conn.prepare(stmt.query()) {
+            Ok(p) if p.readonly() => prepped.push(p),
+            Ok(_) => {
+                _ = res_tx.send(bad_request(format!("statement {i} is not readonly")));
+                return;
+            }
+            Err(e) => {
+                _ = res_tx.send(bad_request(format!("statement {i}: {e}")));
+                return;
+            }
+        }
+    }
+
+    // deferred, the snapshot is taken when the first statement reads. Only
+    // ever rolled back, nothing was written.
+    let _tx = match conn.unchecked_transaction() {
+        Ok(tx) => tx,
+        Err(e) => {
+            _ = res_tx.send(Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ExecResult::Error {
+                    error: e.to_string(),
+                    code: None,
+                },
+            )));
+            return;
+        }
+    };
+
+    if let Err(_e) = res_tx.send(Ok(())) {
+        error!("could not send back response through oneshot channel, aborting");
+        return;
+    }
+
+    for (index, ((stmt, limits), mut prepped)) in stmts.into_iter().zip(prepped).enumerate() {
+        // the set's timeout is what interrupts queries
+        let limits = QueryLimits {
+            timeout_ms,
+            ..limits
+        };
+
+        if let Err(e) = data_tx.blocking_send(RowsEvent::Event(QueryEvent::NextResultSet { index }))
+        {
+            error!("could not send back result set marker: {e}");
+            return;
+        }
+
+        let columns = interned_column_names(stmt.query(), &prepped);
+        if let Err(e) = data_tx.blocking_send(RowsEvent::Columns(columns.clone())) {
+            error!("could not send back columns: {e}");
+            return;
+        }
+
+        let start = Instant::now();
+        let mut rows = match bind_query(&mut prepped, &stmt) {
+            Ok(rows) => rows,
+            Err(e) => {
+                _ = data_tx.blocking_send(RowsEvent::Event(QueryEvent::Error(
+                    query_error(e, limits).into(),
+                )));
+                return;
+            }
+        };
+        let elapsed = start.elapsed();
+
+        let mut rowid = 1;
+        let mut coerced = vec![false; columns.len()];
+        loop {
+            match rows.next() {
+                Ok(Some(row)) => {
+                    if !send_row(row, rowid, limits, coerce, &mut coerced, data_tx) {
+                        return;
+                    }
+                    rowid += 1;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    _ = data_tx.blocking_send(RowsEvent::Event(QueryEvent::Error(
+                        query_error(e, limits).into(),
+                    )));
+                    return;
+                }
+            }
+        }
+
+        if let Err(e) =
+            data_tx.blocking_send(RowsEvent::Event(end_of_query(elapsed, &columns, coerced)))
+        {
+            error!("could not send back end of query: {e}");
+            return;
+        }
+    }
+}
+
+async fn build_query_rows_response(
+    agent: &Agent,
+    data_tx: mpsc::Sender<RowsEvent>,
+    stmt: Statement,
+    limits: QueryLimits,
+    as_of_db_version: Option<i64>,
+    coerce: Option<Coercion>,
+) -> Result<(), (StatusCode, ExecResult)> {
+    run_read_query(agent, as_of_db_version, move |conn, res_tx| {
+        query_rows(conn, stmt, limits, coerce, res_tx, &data_tx)
+    })
+    .await
+}
+
+/// Runs `f` on a read connection, or one reading tables as of
+/// `as_of_db_version`, resolving once `f` sent back whether the query could
+/// start
+async fn run_read_query<F>(
+    agent: &Agent,
+    as_of_db_version: Option<i64>,
+    f: F,
+) -> Result<(), (StatusCode, ExecResult)>
+where
+    F: FnOnce(&rusqlite::Connection, oneshot::Sender<Result<(), (StatusCode, ExecResult)>>)
+        + Send
+        + 'static,
+{
+    let (res_tx, res_rx) = oneshot::channel();
+
+    let agent = agent.clone();
+
+    tokio::spawn(async move {
+        match as_of_db_version {
+            None => {
+                let conn = match agent.pool().read().await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        _ = res_tx.send(Err((
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            ExecResult::Error {
+                                error: e.to_string(),
+                                code: None,
+                            },
+                        )));
+                        return;
+                    }
+                };
+                block_in_place(|| f(&conn, res_tx));
+            }
+            Some(db_version) => block_in_place(|| match open_as_of(&agent, db_version) {
+                Ok(conn) => f(&conn, res_tx),
+                Err(e) => {
+                    _ = res_tx.send(Err(e));
+                }
+            }),
+        }
+    });
+
+    match res_rx.await {
+        Ok(res) => res,
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            },
+        )),
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryParams {
+    /// Reads tables as they were right after this db_version was applied
+    #[serde(default)]
+    as_of_db_version: Option<i64>,
+    /// Serves the same query from cache for up to this long, until a table it
+    /// reads changes
+    #[serde(default)]
+    cache_ttl_ms: Option<u64>,
+    /// Converts values stored w/ the wrong type, e.g. `numeric_text`
+    #[serde(default)]
+    coerce: Option<Coercion>,
+}
+
+// a query's result being collected for the cache
+struct CacheFill {
+    key: String,
+    ttl: Duration,
+    generation: Generation,
+    tables: Vec<String>,
+    body: BytesMut,
+}
+
+impl CacheFill {
+    /// Finds out which tables `stmt` reads, its result is only cached if
+    /// they're all replicated: changes to other tables wouldn't invalidate it
+    async fn new(agent: &Agent, stmt: &Statement, key: String, ttl: Duration) -> Option<Self> {
+        // before the query starts reading, in case a table changes meanwhile
+        let generation = agent.query_cache().generation();
+
+        let conn = agent.pool().read().await.ok()?;
+        let access = block_in_place(|| statement_access(&conn, stmt.query())).ok()?;
+        if !access.writes.is_empty() {
+            return None;
+        }
+
+        let tables = access
+            .reads
+            .into_iter()
+            .map(|(table, _)| table)
+            .unique()
+            .collect::<Vec<_>>();
+        {
+            let schema = agent.schema().read();
+            if let Some(table) = tables
+                .iter()
+                .find(|table| !schema.tables.contains_key(*table))
+            {
+                debug!("not caching a query reading '{table}', it isn't replicated");
+                return None;
+            }
+        }
+
+        Some(Self {
+            key,
+            ttl,
+            generation,
+            tables,
+            body: BytesMut::new(),
+        })
+    }
+}
+
+/// Resolves `api.drain_timeout_secs` after the agent starts shutting down,
+/// queries still streaming by then are ended
+fn drain_deadline(agent: &Agent) -> impl Future<Output = ()> {
+    let timeout = Duration::from_secs(agent.config().api.drain_timeout_secs);
+    agent
+        .tripwire()
+        .clone()
+        .then(move |_| tokio::time::sleep(timeout))
+}
+
+fn registry_error_status(e: &RegistryError) -> StatusCode {
+    match e {
+        RegistryError::UnknownName(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Sends what a query's rows are streamed as to the response body, after the
+/// `hello` if there's one, filling the cache w/ the response if `fill` is set
+/// and the query completes. The `hello` is left out of the cached response.
+async fn send_query_body(
+    agent: Agent,
+    hello: Option<Bytes>,
+    mut data_rx: mpsc::Receiver<RowsEvent>,
+    mut tx: hyper::body::Sender,
+    mut fill: Option<CacheFill>,
+) {
+    let mut buf = BytesMut::new();
+    let mut complete = false;
+
+    if let Some(hello) = hello {
+        if let Err(e) = tx.send_data(hello).await {
+            error!("could not send hello through body's channel: {e}");
+            return;
+        }
+    }
+
+    let drain = drain_deadline(&agent);
+    tokio::pin!(drain);
+
+    loop {
+        let row_res = tokio::select! {
+            row_res = data_rx.recv() => match row_res {
+                Some(row_res) => row_res,
+                None => break,
+            },
+            _ = &mut drain => {
+                debug!("shutting down, ending query body");
+                let mut shutting_down = serde_json::to_vec(&QueryEvent::shutting_down())
+                    .expect("could not serialize shutdown event");
+                shutting_down.push(b'\n');
+                _ = tx.send_data(shutting_down.into()).await;
+                return;
+            }
+        };
+        complete = matches!(row_res, RowsEvent::Event(QueryEvent::EndOfQuery { .. }));
+        {
+            let mut writer = (&mut buf).writer();
+            let written = match &row_res {
+                RowsEvent::Columns(names) => {
+                    serde_json::to_writer(&mut writer, &QueryEventRef::columns(names))
+                }
+                RowsEvent::Event(event) => serde_json::to_writer(&mut writer, event),
+            };
+            if let Err(e) = written {
+                _ = tx
+                    .send_data(
+                        serde_json::to_vec(&serde_json::json!(QueryEvent::Error(
+                            e.to_compact_string()
+                        )))
+                        .expect("could not serialize error json")
+                        .into(),
+                    )
+                    .await;
+                return;
+            }
+        }
+
+        buf.extend_from_slice(b"\n");
+        let chunk = buf.split().freeze();
+
+        if let Some(cache_fill) = fill.as_mut() {
+            if cache_fill.body.len() + chunk.len() > corro_types::query_cache::MAX_ENTRY_BYTES {
+                fill = None;
+            } else {
+                cache_fill.body.extend_from_slice(&chunk);
+            }
+        }
+
+        if let Err(e) = tx.send_data(chunk).await {
+            error!("could not send data through body's channel: {e}");
+            return;
+        }
+    }
+    debug!("query body channel done");
+
+    if let (Some(fill), true) = (fill, complete) {
+        let body: Bytes = fill.body.freeze();
+        agent
+            .query_cache()
+            .insert(fill.key, fill.generation, fill.tables, body, fill.ttl);
+    }
+}
+
+pub async fn api_v1_queries(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Query(params): axum::extract::Query<QueryParams>,
+    headers: HeaderMap,
+    axum::extract::Json(stmt): axum::extract::Json<Statement>,
+) -> impl IntoResponse {
+    let handshake = Handshake::new(&headers, QUERY_CAPABILITIES);
+    let (stmt, limits) = match agent.query_registry().resolve(stmt) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            return hyper::Response::builder()
+                .status(registry_error_status(&e))
+                .body(
+                    serde_json::to_vec(&ExecResult::Error {
+                        error: e.to_string(),
+                        code: None,
+                    })
+                    .expect("could not serialize query error response")
+                    .into(),
+                )
+                .expect("could not build query response body");
+        }
+    };
+
+    // only the current state of the tables, as stored, is cached
+    let cache = match params.cache_ttl_ms {
+        Some(ttl_ms) if params.as_of_db_version.is_none() && params.coerce.is_none() => {
+            QueryCache::key(&stmt).map(|key| (key, Duration::from_millis(ttl_ms)))
+        }
+        _ => None,
+    };
+    let cache_header = cache.as_ref().map(|_| "miss");
+
+    let mut fill = None;
+    if let Some((key, ttl)) = cache {
+        if let Some(body) = agent.query_cache().get(&key) {
+            let body: hyper::Body = match handshake.hello(None) {
+                Some(hello) => [hello, body].concat().into(),
+                None => body.into(),
+            };
+            return handshake
+                .headers(hyper::Response::builder())
+                .status(StatusCode::OK)
+                .header(QUERY_CACHE_HEADER, "hit")
+                .body(body)
+                .expect("could not build query response body");
+        }
+        fill = CacheFill::new(&agent, &stmt, key, ttl).await;
+    }
+
+    let (tx, body) = hyper::Body::channel();
+
+    // TODO: timeout on data send instead of infinitely waiting for channel space.
+    let (data_tx, data_rx) = channel(512);
+
+    tokio::spawn(send_query_body(
+        agent.clone(),
+        handshake.hello(None),
+        data_rx,
+        tx,
+        fill,
+    ));
+
+    trace!("building query rows response...");
+
+    match build_query_rows_response(
+        &agent,
+        data_tx,
+        stmt,
+        limits.unwrap_or_default(),
+        params.as_of_db_version,
+        params.coerce,
+    )
+    .await
+    {
+        Ok(_) => {
+            let mut builder = handshake
+                .headers(hyper::Response::builder())
+                .status(StatusCode::OK);
+            if let Some(cache_header) = cache_header {
+                builder = builder.header(QUERY_CACHE_HEADER, cache_header);
+            }
+            #[allow(clippy::needless_return)]
+            return builder
+                .body(body)
+                .expect("could not build query response body");
+        }
+        Err((status, res)) => {
+            #[allow(clippy::needless_return)]
+            return hyper::Response::builder()
+                .status(status)
+                .body(
+                    serde_json::to_vec(&res)
+                        .expect("could not serialize query error response")
+                        .into(),
+                )
+                .expect("could not build query response body");
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MultiQueryParams {
+    /// Reads tables as they were right after this db_version was applied
+    #[serde(default)]
+    as_of_db_version: Option<i64>,
+    /// Converts values stored w/ the wrong type, e.g. `numeric_text`
+    #[serde(default)]
+    coerce: Option<Coercion>,
+}
+
+/// Runs several read-only statements against the same snapshot of the
+/// database, streaming their result sets one after the other. See
+/// `query_rows_multi`.
+pub async fn api_v1_queries_multi(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Query(params): axum::extract::Query<MultiQueryParams>,
+    headers: HeaderMap,
+    axum::extract::Json(stmts): axum::extract::Json<Vec<Statement>>,
+) -> impl IntoResponse {
+    let handshake = Handshake::new(&headers, QUERY_CAPABILITIES);
+    let error_response = |status: StatusCode, error: String| {
+        hyper::Response::builder()
+            .status(status)
+            .body(
+                serde_json::to_vec(&ExecResult::Error { error, code: None })
+                    .expect("could not serialize query error response")
+                    .into(),
+            )
+            .expect("could not build query response body")
+    };
+
+    if stmts.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "no statements to run".into());
+    }
+
+    let stmts = match stmts
+        .into_iter()
+        .map(|stmt| {
+            agent
+                .query_registry()
+                .resolve(stmt)
+                .map(|(stmt, limits)| (stmt, limits.unwrap_or_default()))
+        })
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(stmts) => stmts,
+        Err(e) => return error_response(registry_error_status(&e), e.to_string()),
+    };
+
+    let (tx, body) = hyper::Body::channel();
+    let (data_tx, data_rx) = channel(512);
+
+    // result sets are never cached
+    tokio::spawn(send_query_body(
+        agent.clone(),
+        handshake.hello(None),
+        data_rx,
+        tx,
+        None,
+    ));
+
+    let coerce = params.coerce;
+    match run_read_query(&agent, params.as_of_db_version, move |conn, res_tx| {
+        query_rows_multi(conn, stmts, coerce, res_tx, &data_tx)
+    })
+    .await
+    {
+        Ok(()) => handshake
+            .headers(hyper::Response::builder())
+            .status(StatusCode::OK)
+            .body(body)
+            .expect("could not build query response body"),
+        Err((status, res)) => hyper::Response::builder()
+            .status(status)
+            .body(
+                serde_json::to_vec(&res)
+                    .expect("could not serialize query error response")
+                    .into(),
+            )
+            .expect("could not build query response body"),
+    }
+}
+
+pub async fn api_v1_explain(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(stmt): axum::extract::Json<Statement>,
+) -> Result<axum::Json<QueryPlan>, (StatusCode, axum::Json<ExecResult>)> {
+    let (stmt, _) = agent.query_registry().resolve(stmt).map_err(|e| {
+        (
+            registry_error_status(&e),
+            axum::Json(ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            }),
+        )
+    })?;
+
+    let conn = agent.pool().read().await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            }),
+        )
+    })?;
+
+    let large_table_rows = agent.config().api.query_plan.large_table_rows;
+
+    block_in_place(|| explain_query_plan(&conn, stmt.query(), large_table_rows))
+        .map(axum::Json)
+        .map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                axum::Json(ExecResult::Error {
+                    error: e.to_string(),
+                    code: None,
+                }),
+            )
+        })
+}
+
+/// Registers a named query template, replacing any previous one w/ the same
+/// name. Responds w/ 201 for new names, 200 for replaced ones.
+pub async fn api_v1_register_query(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    axum::extract::Json(registered): axum::extract::Json<RegisteredQuery>,
+) -> (StatusCode, axum::Json<ExecResult>) {
+    let conn = match agent.pool().read().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                axum::Json(ExecResult::Error {
+                    error: e.to_string(),
+                    code: None,
+                }),
+            )
+        }
+    };
+
+    let start = Instant::now();
+    match block_in_place(|| agent.query_registry().register(&conn, name, registered)) {
+        Ok(replaced) => (
+            if replaced {
+                StatusCode::OK
+            } else {
+                StatusCode::CREATED
+            },
+            axum::Json(ExecResult::Execute {
+                rows_affected: 0,
+                time: start.elapsed().as_secs_f64(),
+            }),
+        ),
+        Err(e) => (
+            registry_error_status(&e),
+            axum::Json(ExecResult::Error {
+                error: e.to_string(),
+                code: None,
+            }),
+        ),
+    }
+}
+
+async fn execute_schema(agent: &Agent, statements: Vec<String>) -> eyre::Result<()> {
+    let new_sql: String = statements.join(";");
+
+    let mut partial_schema = parse_sql(&new_sql)?;
+
+    // local-only tables are kept out of the replicated schema
+    let local_tables = {
+        let config = agent.config();
+        let names = partial_schema
+            .tables
+            .keys()
+            .filter(|name| config.db.is_local_only(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        names
+            .into_iter()
+            .filter_map(|name| partial_schema.tables.shift_remove(&name))
+            .collect::<Vec<_>>()
+    };
+
+    let mut conn = agent.pool().write_priority().await?;
+
+    // hold onto this lock so nothing else makes changes
+    let mut schema_write = agent.schema().write();
+
+    if let Some(table) = local_tables
+        .iter()
+        .find(|table| schema_write.tables.contains_key(&table.name))
+    {
+        eyre::bail!(
+            "table '{}' is already replicated, it can't be made local-only",
+            table.name
+        );
+    }
+
+    // clone the previous schema and apply
+    let mut new_schema = {
+        let mut schema = schema_write.clone();
+        for (name, def) in partial_schema.tables.iter() {
+            // overwrite table because users are expected to return a full table def
+            schema.tables.insert(name.clone(), def.clone());
+        }
+        schema
+    };
+
+    new_schema.constrain()?;
+
+    block_in_place(|| {
+        let tx = conn.transaction()?;
+
+        apply_schema(&tx, &schema_write, &mut new_schema)?;
+
+        for table in local_tables.iter() {
+            create_local_table(&tx, table)?;
+        }
+
+        if agent.config().db.history_retention_secs.is_some() {
+            for tbl_name in partial_schema.tables.keys() {
+                if let Some(table) = new_schema.tables.get(tbl_name) {
+                    history::install(&tx, table)?;
+                }
+            }
+        }
+
+        for tbl_name in partial_schema.tables.keys() {
+            tx.execute("DELETE FROM __corro_schema WHERE tbl_name = ?", [tbl_name])?;
+
+            let n = tx.execute("INSERT INTO __corro_schema SELECT tbl_name, type, name, sql, 'api' AS source FROM sqlite_schema WHERE tbl_name = ? AND type IN ('table', 'index') AND name IS NOT NULL AND sql IS NOT NULL", [tbl_name])?;
+            info!("Updated {n} rows in __corro_schema for table {tbl_name}");
+        }
+
+        tx.commit()?;
+
+        Ok::<_, eyre::Report>(())
+    })?;
+
+    *schema_write = new_schema;
+    agent.schema_changed();
+
+    Ok(())
+}
+
+pub async fn api_v1_db_schema(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    if statements.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error {
+                    error: "at least 1 statement is required".into(),
+                    code: None,
+                }],
+                time: 0.0,
+                changes_generated: None,
+                dry_run: false,
+            }),
+        );
+    }
+
+    let start = Instant::now();
+
+    if let Err(e) = execute_schema(&agent, statements).await {
+        error!("could not merge schemas: {e}");
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error {
+                    error: e.to_string(),
+                    code: None,
+                }],
+                time: 0.0,
+                changes_generated: None,
+                dry_run: false,
+            }),
+        );
+    }
+
+    pubsub::reshape_subs(&agent).await;
+
+    (
+        StatusCode::OK,
+        axum::Json(ExecResponse {
+            results: vec![],
+            time: start.elapsed().as_secs_f64(),
+            changes_generated: None,
+            dry_run: false,
+        }),
+    )
+}
+
+/// The replicated tables of the agent's schema, at its current version
+pub async fn api_v1_schema(Extension(agent): Extension<Agent>) -> axum::Json<SchemaResponse> {
+    // the version is bumped after the schema changes, it can't be newer
+    // than the tables
+    let schema = agent.schema().read();
+    axum::Json(SchemaResponse {
+        version: agent.schema_version(),
+        tables: schema.tables.values().map(table_schema).collect(),
+    })
+}
+
+pub async fn api_v1_schema_version(
+    Extension(agent): Extension<Agent>,
+) -> axum::Json<SchemaVersion> {
+    axum::Json(SchemaVersion {
+        version: agent.schema_version(),
+    })
+}
+
+fn table_schema(table: &Table) -> TableSchema {
+    TableSchema {
+        name: TableName(table.name.as_str().into()),
+        columns: table
+            .columns
+            .values()
+            .map(|col| ColumnSchema {
+                name: ColumnName(col.name.as_str().into()),
+                declared_type: col.sql_type.1.clone(),
+                nullable: col.nullable,
+                default_value: col.default_value.clone(),
+                generated: col.generated.is_some(),
+            })
+            .collect(),
+        pk: table
+            .pk
+            .iter()
+            .map(|name| ColumnName(name.as_str().into()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, AtomicUsize},
+        Arc,
+    };
+
+    use bytes::Bytes;
+    use corro_client::outbox::{Outbox, OutboxOptions};
+    use corro_types::{
+        api::{row::FromRow, Real, RowId, CAPABILITIES_HEADER, PROTOCOL_VERSION},
+        config::Config,
+        schema::SqliteType,
+    };
+    use futures::Stream;
+    use http_body::{combinators::UnsyncBoxBody, Body};
+    use tokio::sync::mpsc::error::TryRecvError;
+    use tokio_util::codec::{Decoder, LinesCodec};
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    use crate::agent::setup;
+
+    struct UnsyncBodyStream(std::pin::Pin<Box<UnsyncBoxBody<Bytes, axum::Error>>>);
+
+    impl Stream for UnsyncBodyStream {
+        type Item = Result<Bytes, axum::Error>;
+
+        fn poll_next(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Self::Item>> {
+            self.0.as_mut().poll_data(cx)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let rx_bcast = &mut agent_options.rx_bcast;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec!["service-id".into(), "service-name".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await;
+
+        println!("{body:?}");
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 1);
+
+        let msg = rx_bcast
+            .recv()
+            .await
+            .expect("not msg received on bcast channel");
+
+        assert!(matches!(
+            msg,
+            BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
+                changeset: Changeset::Full { version: 1, .. },
+                ..
+            }))
+        ));
+
+        assert_eq!(
+            agent
+                .bookie()
+                .write("test")
+                .await
+                .for_actor(agent.actor_id())
+                .read("test")
+                .await
+                .last(),
+            Some(1)
+        );
+
+        println!("second req...");
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::WithParams(
+                    "update tests SET text = ? where id = ?".into(),
+                    vec!["service-name".into(), "service-id".into()],
+                )]
+                .into(),
+            ),
+        )
+        .await;
+
+        println!("{body:?}");
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 1);
+
+        // no actual changes!
+        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_json_limits() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let doc = r#"{"a":[{"b":"c"}]}"#;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(JSON_MAX_PARAM_BYTES_HEADER, doc.len().into());
+        headers.insert(JSON_MAX_DEPTH_HEADER, 3.into());
+
+        let stmts = |id: &str, doc: &str| -> serde_json::Result<Vec<Statement>> {
+            Ok(vec![
+                Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![format!("{id}-plain").into(), "plain".into()],
+                ),
+                Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![
+                        id.into(),
+                        SqliteParam::Json(serde_json::value::RawValue::from_string(
+                            doc.to_owned(),
+                        )?),
+                    ],
+                ),
+            ])
+        };
+
+        // just under the limits
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            headers.clone(),
+            axum::Json(stmts("under", doc)?.into()),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body.0.results.len(), 2);
+
+        // one byte over the size limit
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            headers.clone(),
+            axum::Json(stmts("over", &format!("{doc} "))?.into()),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(matches!(
+            body.0.results.as_slice(),
+            [ExecResult::Error { error, .. }] if error.starts_with("statement 1:")
+        ));
+
+        // one level too deep
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            headers,
+            axum::Json(stmts("deep", r#"{"a":[[1]]}"#)?.into()),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let conn = agent.pool().read().await?;
+        let ids: Vec<String> = conn
+            .prepare("SELECT id FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        // nothing from the rejected requests was applied
+        assert_eq!(ids, vec!["under".to_string(), "under-plain".to_string()]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_statement_isolation() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |id: i64| {
+            Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec![id.into(), format!("text-{id}").into()],
+            )
+        };
+
+        // the 5th statement conflicts w/ the 3rd one
+        let statements = [1, 2, 3, 4, 3, 6, 7, 8, 9, 10]
+            .into_iter()
+            .map(insert)
+            .collect();
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::WithOptions {
+                statements,
+                isolation: ExecIsolation::Statement,
+                groups: None,
+                defer_foreign_keys: false,
+                stream_returning: false,
+                session: None,
+                report_changes: false,
+                no_replication: false,
+                source_id: None,
+                dry_run: false,
+            }),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body.0.results.len(), 10);
+
+        let errors = body
+            .0
+            .results
+            .iter()
+            .enumerate()
+            .filter(|(_, res)| matches!(res, ExecResult::Error { .. }))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(errors, vec![4]);
+
+        // a failure rolls back its whole group
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::grouped(vec![
+                vec![insert(11), insert(12)],
+                vec![insert(13), insert(1)],
+            ])),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(matches!(
+            body.0.results.as_slice(),
+            [
+                ExecResult::Execute {
+                    rows_affected: 2,
+                    ..
+                },
+                ExecResult::Error { .. }
+            ]
+        ));
+
+        let conn = agent.pool().read().await?;
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        assert_eq!(ids, vec![1, 2, 3, 4, 6, 7, 8, 9, 10, 11, 12]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_param_count() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |id: i64| {
+            Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                vec![id.into(), format!("text-{id}").into()],
+            )
+        };
+
+        let exec_error = |body: axum::Json<ExecResponse>| match body.0.results.as_slice() {
+            [ExecResult::Error { error, .. }] => error.clone(),
+            results => panic!("expected a single error, got {results:?}"),
+        };
+
+        // under-count
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::Statements(vec![
+                insert(1),
+                insert(2),
+                Statement::WithParams(
+                    "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                    vec![3i64.into()],
+                ),
+            ])),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            exec_error(body),
+            "statement 2: expected 2 params, got 1 (query starts with 'INSERT INTO tests (id, text) VAL')"
+        );
+
+        // over-count
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::Statements(vec![
+                Statement::WithParams(
+                    "  DELETE FROM tests WHERE id = ?".into(),
+                    vec![1i64.into(), 2i64.into()],
+                ),
+                insert(4),
+            ])),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            exec_error(body),
+            "statement 0: expected 1 params, got 2 (query starts with 'DELETE FROM tests WHERE id = ?')"
+        );
+
+        // named params which don't resolve
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::Statements(vec![
+                insert(5),
+                Statement::WithNamedParams(
+                    "INSERT INTO tests (id, text) VALUES (:id, :text)".into(),
+                    [
+                        (":id".to_string(), 6i64.into()),
+                        (":txt".to_string(), "text-6".into()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+            ])),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        let error = exec_error(body);
+        assert!(
+            error.starts_with("statement 1: unknown param ':txt'"),
+            "{error}"
+        );
+
+        // named params missing one
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::Statements(vec![Statement::WithNamedParams(
+                "INSERT INTO tests (id, text) VALUES (:id, :text)".into(),
+                [(":id".to_string(), 7i64.into())].into_iter().collect(),
+            )])),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        let error = exec_error(body);
+        assert!(
+            error.starts_with("statement 0: expected 2 params, got 1"),
+            "{error}"
+        );
+
+        // nothing from the failed batches was applied
+        let conn = agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        // only the group w/ mismatched params is rolled back w/ statement isolation
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::grouped(vec![
+                vec![insert(8)],
+                vec![
+                    insert(9),
+                    Statement::WithParams(
+                        "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                        vec![10i64.into()],
+                    ),
+                ],
+            ])),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+        match body.0.results.as_slice() {
+            [ExecResult::Execute {
+                rows_affected: 1, ..
+            }, ExecResult::Error { error, .. }] => {
+                assert!(
+                    error.starts_with("statement 2: expected 2 params, got 1"),
+                    "{error}"
+                );
+            }
+            results => panic!("unexpected results: {results:?}"),
+        }
+
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(ids, vec![8]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_max_change_size() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .max_change_size(1024)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let rx_bcast = &mut agent_options.rx_bcast;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |id: &str, size: usize| {
+            vec![
+                Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), "some text".into()],
+                ),
+                Statement::WithParams(
+                    "insert into testsblob (id, text) values (?,?)".into(),
+                    vec![vec![0u8; size].into(), id.into()],
+                ),
+            ]
+        };
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(insert("too-large", 2048).into()),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(matches!(
+            body.0.results.as_slice(),
+            [ExecResult::Error { error, .. }] if error.starts_with("change for testsblob.")
+        ));
+
+        // nothing was committed, nor broadcast
+        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(insert("small-enough", 128).into()),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let msg = rx_bcast
+            .recv()
+            .await
+            .expect("not msg received on bcast channel");
+
+        assert!(matches!(
+            msg,
+            BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
+                changeset: Changeset::Full { version: 1, .. },
+                ..
+            }))
+        ));
+
+        let conn = agent.pool().read().await?;
+        let ids: Vec<String> = conn
+            .prepare("SELECT id FROM tests ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        assert_eq!(ids, vec!["small-enough".to_string()]);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_report_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE wide (id INTEGER NOT NULL PRIMARY KEY, c1, c2, c3, c4, c5, c6, c7, c8);"
+                    .into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let exec = |statement: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(
+                    ExecRequest::from(vec![Statement::Simple(statement.into())]).report_changes(),
+                ),
+            )
+        };
+
+        let (status_code, body) = exec("INSERT INTO wide VALUES (1, 1, 1, 1, 1, 1, 1, 1, 1)").await;
+        assert_eq!(status_code, StatusCode::OK);
+        let inserted = body.0.changes_generated.expect("changes were asked for");
+        assert!(inserted.changes >= 8);
+
+        // every column of the row changes
+        let (status_code, body) = exec(
+            "INSERT INTO wide VALUES (1, 2, 2, 2, 2, 2, 2, 2, 2)
+                ON CONFLICT (id) DO UPDATE SET
+                    c1 = excluded.c1, c2 = excluded.c2, c3 = excluded.c3, c4 = excluded.c4,
+                    c5 = excluded.c5, c6 = excluded.c6, c7 = excluded.c7, c8 = excluded.c8",
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let upserted = body.0.changes_generated.expect("changes were asked for");
+        assert_eq!(upserted.changes, 8);
+        assert_eq!(upserted.statements, 1);
+        assert!(upserted.bytes > 0);
+
+        let (status_code, body) = exec("UPDATE wide SET c1 = 3, c2 = 3 WHERE id = 1").await;
+        assert_eq!(status_code, StatusCode::OK);
+        let updated = body.0.changes_generated.expect("changes were asked for");
+        assert_eq!(updated.changes, 2);
+        assert!(updated.bytes < upserted.bytes);
+
+        // only reported when asked for
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::Simple(
+                    "UPDATE wide SET c1 = 4 WHERE id = 1".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(body.0.changes_generated.is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_dry_run() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let statements = vec![
+            Statement::WithParams(
+                "insert into tests (id, text) values (?,?), (?,?) returning id".into(),
+                vec![1i64.into(), "one".into(), 2i64.into(), "two".into()],
+            ),
+            Statement::WithParams(
+                "update tests set text = ? where id = ?".into(),
+                vec!["uno".into(), 1i64.into()],
+            ),
+        ];
+
+        fn rows_affected(res: &ExecResponse) -> Vec<usize> {
+            res.results
+                .iter()
+                .map(|res| match res {
+                    ExecResult::Execute { rows_affected, .. } => *rows_affected,
+                    ExecResult::Error { error, .. } => panic!("statement failed: {error}"),
+                })
+                .collect()
+        }
+
+        async fn written(agent: &Agent) -> eyre::Result<(i64, i64)> {
+            let conn = agent.pool().read().await?;
+            Ok(conn.query_row(
+                "SELECT (SELECT COUNT(*) FROM tests), (SELECT COUNT(*) FROM crsql_changes)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?)
+        }
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::from(statements.clone()).dry_run()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(body.0.dry_run);
+        assert_eq!(rows_affected(&body.0), vec![2, 1]);
+        let would_generate = body
+            .0
+            .changes_generated
+            .expect("dry runs always report changes");
+        assert!(would_generate.changes > 0);
+
+        // rolled back, nothing to replicate
+        assert_eq!(written(&agent).await?, (0, 0));
+        assert_eq!(
+            agent
+                .bookie()
+                .write("test")
+                .await
+                .for_actor(agent.actor_id())
+                .read("test")
+                .await
+                .last(),
+            None
+        );
+
+        // rows are still returned when streamed
+        let res = api_v1_exec(
+            Extension(agent.clone()),
+            None,
+            HeaderMap::new(),
+            axum::Json(
+                ExecRequest::from(statements.clone())
+                    .dry_run()
+                    .stream_returning(),
+            ),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let events = std::str::from_utf8(&body)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<ExecEvent>, _>>()?;
+        assert_eq!(events[1], ExecEvent::Row(RowId(1), vec![1i64.into()]));
+        assert!(matches!(events.last(), Some(ExecEvent::DryRun { .. })));
+        assert_eq!(written(&agent).await?.0, 0);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::from(statements).report_changes()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(!body.0.dry_run);
+        assert_eq!(rows_affected(&body.0), vec![2, 1]);
+        assert_eq!(body.0.changes_generated, Some(would_generate));
+        assert_eq!(written(&agent).await?.0, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_idempotency_key() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let append = |suffix: &str| {
+            ExecRequest::from(vec![Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (1, ?)
+                    ON CONFLICT (id) DO UPDATE SET text = text || excluded.text"
+                    .into(),
+                vec![suffix.into()],
+            )])
+        };
+        let keyed = |key: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(IDEMPOTENCY_KEY_HEADER, HeaderValue::from_static(key));
+            headers
+        };
+
+        let (status_code, first) = api_v1_transactions(
+            Extension(agent.clone()),
+            keyed("a"),
+            axum::Json(append("a")),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // retried, e.g. after its response got lost
+        let (status_code, retried) = api_v1_transactions(
+            Extension(agent.clone()),
+            keyed("a"),
+            axum::Json(append("a")),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(
+            serde_json::to_value(&retried.0.results)?,
+            serde_json::to_value(&first.0.results)?
+        );
+
+        // dry runs and other keys still apply
+        for (headers, req) in [
+            (keyed("a"), append("x").dry_run()),
+            (keyed("b"), append("b")),
+            (HeaderMap::new(), append("c")),
+        ] {
+            let (status_code, _) =
+                api_v1_transactions(Extension(agent.clone()), headers, axum::Json(req)).await;
+            assert_eq!(status_code, StatusCode::OK);
+        }
+
+        let text: String = agent.pool().read().await?.query_row(
+            "SELECT text FROM tests WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(text, "abc");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_outbox_survives_agent_restarts() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let dir = tempfile::tempdir()?;
+        let db_path = dir.path().join("corrosion.db").display().to_string();
+        // the restarted agent listens on the same address
+        let api_addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        let launch = |tripwire: Tripwire| {
+            let db_path = db_path.clone();
+            corro_tests::launch_test_agent(
+                move |conf| conf.api_addr(api_addr).db_path(db_path).build(),
+                tripwire,
+            )
+        };
+
+        async fn drained(outbox: &Outbox) -> eyre::Result<()> {
+            for _ in 0..100 {
+                if outbox.is_empty()? {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            eyre::bail!("{} transactions still queued", outbox.len()?)
+        }
+
+        let append = |n: i64| {
+            vec![Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (1, ?)
+                    ON CONFLICT (id) DO UPDATE SET text = text || ',' || excluded.text"
+                    .into(),
+                vec![n.to_string().into()],
+            )]
+        };
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch(tripwire).await?;
+
+        let client = corro_client::CorrosionApiClient::new(api_addr).with_outbox(
+            dir.path().join("outbox.db"),
+            OutboxOptions::default().retry_interval(Duration::from_millis(100)),
+        )?;
+        let outbox = client.outbox().cloned().expect("client has an outbox");
+
+        client.execute_queued(&append(1)).await?;
+        drained(&outbox).await?;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        drop(ta);
+
+        for n in 2..=5 {
+            client.execute_queued(&append(n)).await?;
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(outbox.len()?, 4);
+        assert!(outbox.oldest_age()?.is_some());
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch(tripwire).await?;
+        drained(&outbox).await?;
+
+        // in order, each once
+        let text: String = ta.agent.pool().read().await?.query_row(
+            "SELECT text FROM tests WHERE id = 1",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(text, "1,2,3,4,5");
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_defer_foreign_keys() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        // cr-sqlite refuses foreign keys on CRRs, so these are local tables
+        {
+            let conn = agent.pool().write_priority().await?;
+            conn.execute_batch(
+                "
+                PRAGMA foreign_keys = ON;
+                CREATE TABLE parents (id INTEGER PRIMARY KEY);
+                CREATE TABLE children (
+                    id INTEGER PRIMARY KEY,
+                    parent_id INTEGER NOT NULL REFERENCES parents (id)
+                );
+                INSERT INTO parents VALUES (1);
+                INSERT INTO children VALUES (1, 1);
+            ",
+            )?;
+        }
+
+        let swap = || {
+            vec![
+                Statement::Simple("DELETE FROM parents WHERE id = 1".into()),
+                Statement::Simple("INSERT INTO parents VALUES (1)".into()),
+            ]
+        };
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(swap().into()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(matches!(body.0.results[0], ExecResult::Error { .. }));
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(ExecRequest::from(swap()).defer_foreign_keys()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(body
+            .0
+            .results
+            .iter()
+            .all(|res| matches!(res, ExecResult::Execute { .. })));
+
+        // per statement
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![
+                    Statement::Verbose {
+                        query: "DELETE FROM parents WHERE id = 1".into(),
+                        params: None,
+                        named_params: None,
+                        defer_foreign_keys: Some(true),
+                    },
+                    Statement::Simple("INSERT INTO parents VALUES (1)".into()),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(body
+            .0
+            .results
+            .iter()
+            .all(|res| matches!(res, ExecResult::Execute { .. })));
+
+        // still violated when committing
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                ExecRequest::from(vec![Statement::Simple(
+                    "DELETE FROM parents WHERE id = 1".into(),
+                )])
+                .defer_foreign_keys(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+
+        let conn = agent.pool().write_priority().await?;
+        let deferred: bool = conn.query_row("PRAGMA defer_foreign_keys", [], |row| row.get(0))?;
+        assert!(!deferred);
+        let parents: i64 = conn.query_row("SELECT COUNT(*) FROM parents", [], |row| row.get(0))?;
+        assert_eq!(parents, 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_session_options() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        {
+            let conn = agent.pool().write_priority().await?;
+            conn.execute_batch(
+                "
+                PRAGMA foreign_keys = ON;
+                PRAGMA recursive_triggers = OFF;
+                CREATE TABLE parents (id INTEGER PRIMARY KEY);
+                CREATE TABLE children (
+                    id INTEGER PRIMARY KEY,
+                    parent_id INTEGER NOT NULL REFERENCES parents (id)
+                );
+                INSERT INTO parents VALUES (1);
+                INSERT INTO children VALUES (1, 1);
+            ",
+            )?;
+        }
+
+        async fn pragmas(agent: &Agent) -> eyre::Result<(u64, bool, bool, bool)> {
+            let conn = agent.pool().write_priority().await?;
+            let get = |pragma: &str| {
+                conn.query_row(&format!("PRAGMA {pragma}"), [], |row| row.get::<_, i64>(0))
+            };
+            Ok((
+                get("busy_timeout")? as u64,
+                get("foreign_keys")? == 1,
+                get("recursive_triggers")? == 1,
+                get("defer_foreign_keys")? == 1,
+            ))
+        }
+
+        let before = pragmas(&agent).await?;
+        assert!(before.1);
+        assert!(!before.2);
+
+        // committed: an orphan is fine w/o foreign keys
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                ExecRequest::from(vec![Statement::Simple(
+                    "DELETE FROM parents WHERE id = 1".into(),
+                )])
+                .session(SessionOptions {
+                    busy_timeout_ms: Some(before.0 + 1234),
+                    foreign_keys: Some(false),
+                    recursive_triggers: Some(true),
+                    defer_foreign_keys: None,
+                }),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert!(matches!(body.0.results[0], ExecResult::Execute { .. }));
+        assert_eq!(pragmas(&agent).await?, before);
+
+        // rolled back: the deferred violation fails the commit
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                ExecRequest::from(vec![Statement::Simple(
+                    "INSERT INTO children VALUES (2, 42)".into(),
+                )])
+                .session(SessionOptions {
+                    busy_timeout_ms: Some(before.0 + 1234),
+                    recursive_triggers: Some(true),
+                    defer_foreign_keys: Some(true),
+                    ..Default::default()
+                }),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert_eq!(pragmas(&agent).await?, before);
+
+        let children: i64 =
+            agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT COUNT(*) FROM children", [], |row| row.get(0))?;
+        assert_eq!(children, 1);
+
+        // only allow-listed options are accepted
+        assert!(serde_json::from_value::<ExecRequest>(serde_json::json!({
+            "statements": ["SELECT 1"],
+            "session": {"busy_timeout_ms": 100, "journal_mode": "OFF"},
+        }))
+        .is_err());
+        assert!(serde_json::from_value::<ExecRequest>(serde_json::json!({
+            "statements": ["SELECT 1"],
+            "session": {"busy_timeout_ms": 100},
+        }))
+        .is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_execute_stream_returning() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        async fn exec_events(
+            agent: &Agent,
+            statements: Vec<Statement>,
+        ) -> eyre::Result<Vec<ExecEvent>> {
+            let res = api_v1_exec(
+                Extension(agent.clone()),
+                None,
+                HeaderMap::new(),
+                axum::Json(ExecRequest::from(statements).stream_returning()),
+            )
+            .await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            let events = std::str::from_utf8(&body)?
+                .lines()
+                .map(|line| {
+                    // timings differ
+                    Ok(match serde_json::from_str(line)? {
+                        ExecEvent::EndOfQuery { rows, .. } => {
+                            ExecEvent::EndOfQuery { time: 0.0, rows }
+                        }
+                        ExecEvent::Execute { rows_affected, .. } => ExecEvent::Execute {
+                            rows_affected,
+                            time: 0.0,
+                        },
+                        ExecEvent::Commit { .. } => ExecEvent::Commit { time: 0.0 },
+                        evt => evt,
+                    })
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            Ok(events)
+        }
+
+        let events = exec_events(
+            &agent,
+            vec![
+                Statement::WithParams(
+                    "insert into tests (id, text) values (?,?), (?,?) returning id, text".into(),
+                    vec![1i64.into(), "one".into(), 2i64.into(), "two".into()],
+                ),
+                Statement::WithParams(
+                    "update tests set text = ? where id = ?".into(),
+                    vec!["uno".into(), 1i64.into()],
+                ),
+            ],
+        )
+        .await?;
+
+        assert_eq!(
+            events,
+            vec![
+                ExecEvent::Columns(vec!["id".into(), "text".into()]),
+                ExecEvent::Row(RowId(1), vec![1i64.into(), "one".into()]),
+                ExecEvent::Row(RowId(2), vec![2i64.into(), "two".into()]),
+                ExecEvent::EndOfQuery { time: 0.0, rows: 2 },
+                ExecEvent::Execute {
+                    rows_affected: 1,
+                    time: 0.0
+                },
+                ExecEvent::Commit { time: 0.0 },
+            ]
+        );
+
+        // rows are streamed before a later statement fails, and rolled back
+        let events = exec_events(
+            &agent,
+            vec![
+                Statement::WithParams(
+                    "insert into tests (id, text) values (?,?) returning id".into(),
+                    vec![3i64.into(), "three".into()],
+                ),
+                Statement::Simple("insert into nope (id) values (1)".into()),
+            ],
+        )
+        .await?;
+
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[1], ExecEvent::Row(RowId(1), vec![3i64.into()]));
+        assert!(matches!(events[3], ExecEvent::Error(ref e) if e.contains("no such table")));
+
+        let conn = agent.pool().read().await?;
+        let texts = conn
+            .prepare("select text from tests order by id")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        assert_eq!(texts, vec!["uno", "two"]);
+
+        // streaming doesn't support per-statement isolation
+        let res = api_v1_exec(
+            Extension(agent.clone()),
+            None,
+            HeaderMap::new(),
+            axum::Json(
+                ExecRequest::grouped(vec![vec![Statement::Simple("select 1".into())]])
+                    .stream_returning(),
+            ),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_exec_streamed_body() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let mut config = Config::builder()
+            .db_path(dir.path().join("corrosion.db").display().to_string())
+            .gossip_addr("127.0.0.1:0".parse()?)
+            .api_addr("127.0.0.1:0".parse()?)
+            .build()?;
+        config.api.max_body_bytes = Some(1024 * 1024);
+        let (agent, _agent_options) = setup(config, tripwire).await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // sent a statement per chunk, w/o a content-length
+        fn inserts(ids: std::ops::Range<usize>, malformed_at: Option<usize>) -> hyper::Body {
+            let start = ids.start;
+            let chunks = ids
+                .map(move |id| {
+                    let sep = if id == start { "[" } else { "," };
+                    if Some(id - start) == malformed_at {
+                        format!(r#"{sep}{{"query": 42}}"#)
+                    } else {
+                        format!(
+                            r#"{sep}["INSERT INTO tests (id, text) VALUES (?, ?)", [{id}, "row {id}"]]"#
+                        )
+                    }
+                })
+                .chain(std::iter::once("]".to_string()))
+                .map(Ok::<_, std::io::Error>);
+            hyper::Body::wrap_stream(futures::stream::iter(chunks))
+        }
+
+        async fn exec(
+            agent: &Agent,
+            body: hyper::Body,
+        ) -> eyre::Result<(StatusCode, ExecResponse)> {
+            let req = Request::builder()
+                .method("POST")
+                .uri("/v1/transactions")
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(body)?;
+            let res = api_v1_exec_body(Extension(agent.clone()), None, req).await;
+            let status_code = res.status();
+            let body = hyper::body::to_bytes(res.into_body()).await?;
+            Ok((status_code, serde_json::from_slice(&body)?))
+        }
+
+        async fn count(agent: &Agent) -> eyre::Result<i64> {
+            Ok(agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?)
+        }
+
+        let (status_code, res) = exec(&agent, inserts(0..5000, None)).await?;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(res.results.len(), 5000);
+        assert!(res.results.iter().all(|res| matches!(
+            res,
+            ExecResult::Execute {
+                rows_affected: 1,
+                ..
+            }
+        )));
+        assert_eq!(count(&agent).await?, 5000);
+
+        // the statements before the malformed one are rolled back
+        let (status_code, res) = exec(&agent, inserts(5000..10000, Some(2500))).await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        match &res.results[..] {
+            [ExecResult::Error { error, .. }] => {
+                assert!(error.starts_with("statement 2500: "), "{error}")
+            }
+            results => panic!("unexpected results: {results:?}"),
+        }
+        assert_eq!(count(&agent).await?, 5000);
+
+        // over api.max_body_bytes while streaming in
+        let (status_code, res) = exec(&agent, inserts(5000..25000, None)).await?;
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE, "{res:?}");
+        assert_eq!(count(&agent).await?, 5000);
+
+        // refused upfront when the content-length tells
+        let body = hyper::body::to_bytes(inserts(5000..25000, None)).await?;
+        let req = Request::builder()
+            .method("POST")
+            .uri("/v1/transactions")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, body.len())
+            .body(hyper::Body::from(body))?;
+        let res = api_v1_exec_body(Extension(agent.clone()), None, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status_code, res) = exec(&agent, hyper::Body::from("  []")).await?;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST, "{res:?}");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_explain() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let mut config = Config::builder()
+            .db_path(dir.path().join("corrosion.db").display().to_string())
+            .gossip_addr("127.0.0.1:0".parse()?)
+            .api_addr("127.0.0.1:0".parse()?)
+            .build()?;
+        config.api.query_plan.large_table_rows = 2;
+
+        let (agent, _agent_options) = setup(config, tripwire).await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let explain = |sql: &str| {
+            api_v1_explain(
+                Extension(agent.clone()),
+                axum::Json(Statement::Simple(sql.into())),
+            )
+        };
+
+        // indexed predicate
+        let plan = explain("SELECT * FROM tests WHERE id = 1").await.unwrap().0;
+        assert!(plan.steps.iter().any(|step| step.uses_index));
+        assert!(plan.steps.iter().all(|step| step.scanned_table.is_none()));
+        assert!(plan.large_scans.is_empty());
+
+        // unindexed predicate, but the table is still small
+        let plan = explain("SELECT * FROM tests WHERE text = 'a'")
+            .await
+            .unwrap()
+            .0;
+        assert!(plan
+            .steps
+            .iter()
+            .any(|step| step.scanned_table.as_deref() == Some("tests")));
+        assert!(plan.large_scans.is_empty());
+
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::Simple(
+                    "INSERT INTO tests (id, text) VALUES (1, 'a'), (2, 'b'), (3, 'c')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let plan = explain("SELECT * FROM tests WHERE text = 'a'")
+            .await
+            .unwrap()
+            .0;
+        assert_eq!(plan.large_scans, vec!["tests".to_string()]);
+
+        let plan = explain("SELECT * FROM tests WHERE id = 1").await.unwrap().0;
+        assert!(plan.large_scans.is_empty());
+
+        let (status_code, body) = explain("SELECT * FROM nope").await.unwrap_err();
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
+        assert!(matches!(body.0, ExecResult::Error { .. }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_depth() {
+        assert_eq!(json_depth("1"), 0);
+        assert_eq!(json_depth(r#"{"a":1}"#), 1);
+        assert_eq!(json_depth(r#"[{"a":[1]},[]]"#), 3);
+        assert_eq!(json_depth(r#"{"a":"[[[\"]]]"}"#), 1);
+    }
+
+    type CapturedFields = HashMap<&'static str, String>;
+
+    /// Records the fields of every span and event, w/ their level
+    #[derive(Clone, Default)]
+    struct CapturingLayer {
+        spans: Arc<std::sync::Mutex<Vec<(tracing::span::Id, &'static str, CapturedFields)>>>,
+        events: Arc<std::sync::Mutex<Vec<(tracing::Level, CapturedFields)>>>,
+    }
+
+    impl CapturingLayer {
+        fn spans(&self, name: &str) -> Vec<CapturedFields> {
+            self.spans
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, span_name, _)| *span_name == name)
+                .map(|(_, _, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    struct FieldsVisitor<'a>(&'a mut CapturedFields);
+
+    impl tracing::field::Visit for FieldsVisitor<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name(), value.to_owned());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CapturingLayer {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldsVisitor(&mut fields));
+            self.spans
+                .lock()
+                .unwrap()
+                .push((id.clone(), attrs.metadata().name(), fields));
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            // ids are reused once spans close, the latest one is open
+            if let Some((_, _, fields)) = self
+                .spans
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .rev()
+                .find(|(span_id, _, _)| span_id == id)
+            {
+                values.record(&mut FieldsVisitor(fields));
+            }
+        }
+
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = HashMap::new();
+            event.record(&mut FieldsVisitor(&mut fields));
+            self.events
+                .lock()
+                .unwrap()
+                .push((*event.metadata().level(), fields));
+        }
+    }
+
+    #[test]
+    fn test_execute_statement_spans() -> eyre::Result<()> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let layer = CapturingLayer::default();
+        let subscriber = tracing_subscriber::registry().with(layer.clone());
+
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        conn.execute_batch("CREATE TABLE tests (id INTEGER NOT NULL PRIMARY KEY, text TEXT);")?;
+        let tx = conn.transaction()?;
+
+        // counting to 200k in sqlite takes well over the 1ms threshold
+        let slow = Statement::Simple(
+            "WITH RECURSIVE n(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM n WHERE x < 200000)
+                INSERT INTO tests (id, text) SELECT x, 'slow' FROM n"
+                .into(),
+        );
+        let fast = Statement::WithParams(
+            "UPDATE tests SET text = 'fast' WHERE id = ?".into(),
+            vec![1i64.into()],
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(
+                execute_statement(&tx, &slow, 0, Duration::from_millis(1))?,
+                200_000
+            );
+            assert_eq!(
+                execute_statement(&tx, &fast, 1, Duration::from_secs(3600))?,
+                1
+            );
+            Ok::<_, ChangeError>(())
+        })?;
+
+        let spans = layer.spans("execute_statement");
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0]["index"], "0");
+        assert_eq!(
+            spans[0]["query_hash"],
+            seahash::hash(slow.query().as_bytes()).to_string()
+        );
+        assert_eq!(spans[0]["table"], "tests");
+        assert_eq!(spans[0]["rows_affected"], "200000");
+        assert!(spans[0]["elapsed_ms"].parse::<f64>()? >= 1.0);
+        assert_eq!(spans[1]["index"], "1");
+        assert_eq!(spans[1]["table"], "tests");
+        assert_eq!(spans[1]["rows_affected"], "1");
+
+        // only the slow statement is warned about
+        let warnings = layer
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(level, _)| *level == tracing::Level::WARN)
+            .map(|(_, fields)| fields["message"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![format!(
+                "slow statement 0: {}",
+                query_excerpt(slow.query(), SLOW_STATEMENT_EXCERPT_CHARS)
+            )]
+        );
+        assert_eq!(query_excerpt("  SELECT 1", 6), "SELECT");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
+        )
+        .await;
+
+        // println!("{body:?}");
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(body.0.results.len() == 2);
+
+        println!("transaction body: {body:?}");
+
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            axum::extract::Query(Default::default()),
+            HeaderMap::new(),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut body = res.into_body();
+
+        let mut lines = LinesCodec::new();
+
+        let mut buf = BytesMut::new();
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let cols: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(cols, QueryEvent::columns(vec!["id".into(), "text".into()]));
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(
+            row,
+            QueryEvent::Row(RowId(1), vec!["service-id".into(), "service-name".into()])
+        );
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert_eq!(
+            row,
+            QueryEvent::Row(
+                RowId(2),
+                vec!["service-id-2".into(), "service-name-2".into()]
+            )
+        );
+
+        buf.extend_from_slice(&body.data().await.unwrap()?);
+
+        let s = lines.decode(&mut buf).unwrap().unwrap();
+
+        let query_evt: QueryEvent = serde_json::from_str(&s).unwrap();
+
+        assert!(matches!(query_evt, QueryEvent::EndOfQuery { .. }));
+
+        assert!(body.data().await.is_none());
+
+        Ok(())
+    }
+
+    // p99 latency of `samples` trivial queries, run one after the other
+    async fn read_p99(agent: &Agent, samples: usize) -> eyre::Result<Duration> {
+        let mut latencies = Vec::with_capacity(samples);
+        for _ in 0..samples {
+            let start = Instant::now();
+            let res = api_v1_queries(
+                Extension(agent.clone()),
+                axum::extract::Query(Default::default()),
+                HeaderMap::new(),
+                axum::Json(Statement::Simple("SELECT 1".into())),
+            )
+            .await
+            .into_response();
+            assert_eq!(res.status(), StatusCode::OK);
+            hyper::body::to_bytes(res.into_body()).await?;
+            latencies.push(start.elapsed());
+        }
+        latencies.sort();
+        Ok(latencies[samples * 99 / 100])
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_queries_during_sustained_writes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .read_pool_size(4)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let idle = read_p99(&agent, 100).await?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let batches = Arc::new(AtomicUsize::new(0));
+        let writer = tokio::spawn({
+            let agent = agent.clone();
+            let stop = stop.clone();
+            let batches = batches.clone();
+            async move {
+                let mut id = 0i64;
+                while !stop.load(Ordering::Relaxed) {
+                    let statements = (0..500)
+                        .map(|_| {
+                            id += 1;
+                            Statement::WithParams(
+                                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                                vec![id.into(), "x".repeat(256).into()],
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    let (status_code, _body) = api_v1_transactions(
+                        Extension(agent.clone()),
+                        HeaderMap::new(),
+                        axum::Json(statements.into()),
+                    )
+                    .await;
+                    assert_eq!(status_code, StatusCode::OK);
+                    batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        while batches.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let writing = read_p99(&agent, 100).await?;
+        stop.store(true, Ordering::Relaxed);
+        writer.await?;
+
+        println!(
+            "read p99 idle: {idle:?}, while writing: {writing:?} ({} write batches)",
+            batches.load(Ordering::Relaxed)
+        );
+        // generous: reads shouldn't wait for write transactions at all
+        assert!(writing < Duration::from_millis(500), "{writing:?}");
+
+        Ok(())
+    }
+
+    async fn query_multi(
+        agent: &Agent,
+        stmts: Vec<Statement>,
+    ) -> eyre::Result<(StatusCode, Vec<QueryEvent>)> {
+        let res = api_v1_queries_multi(
+            Extension(agent.clone()),
+            axum::extract::Query(MultiQueryParams::default()),
+            HeaderMap::new(),
+            axum::Json(stmts),
+        )
+        .await
+        .into_response();
+
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        if status != StatusCode::OK {
+            return Ok((status, vec![]));
+        }
+
+        let events = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<QueryEvent>, _>>()?;
+
+        Ok((status, events))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_queries_multi_share_a_snapshot() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let batches = Arc::new(AtomicUsize::new(0));
+        let writer = tokio::spawn({
+            let agent = agent.clone();
+            let stop = stop.clone();
+            let batches = batches.clone();
+            async move {
+                let mut id = 0i64;
+                while !stop.load(Ordering::Relaxed) {
+                    let statements = (0..10)
+                        .map(|_| {
+                            id += 1;
+                            Statement::WithParams(
+                                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                                vec![id.into(), "x".into()],
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    let (status_code, _body) = api_v1_transactions(
+                        Extension(agent.clone()),
+                        HeaderMap::new(),
+                        axum::Json(statements.into()),
+                    )
+                    .await;
+                    assert_eq!(status_code, StatusCode::OK);
+                    batches.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        while batches.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        let summary = Statement::Simple("SELECT count(*), coalesce(sum(id), 0) FROM tests".into());
+        let detail = Statement::Simple("SELECT id FROM tests".into());
+
+        for _ in 0..20 {
+            let (status, events) =
+                query_multi(&agent, vec![summary.clone(), detail.clone()]).await?;
+            assert_eq!(status, StatusCode::OK);
+
+            // rows by result set
+            let mut sets: Vec<Vec<Vec<SqliteValue>>> = vec![];
+            for event in events {
+                match event {
+                    QueryEvent::NextResultSet { index } => {
+                        assert_eq!(index, sets.len());
+                        sets.push(vec![]);
+                    }
+                    QueryEvent::Row(_, cells) => sets.last_mut().unwrap().push(cells),
+                    QueryEvent::Columns { .. } | QueryEvent::EndOfQuery { .. } => {}
+                    event => panic!("unexpected event: {event:?}"),
+                }
+            }
+            assert_eq!(sets.len(), 2);
+
+            let (count, sum) = match sets[0][0].as_slice() {
+                [SqliteValue::Integer(count), SqliteValue::Integer(sum)] => (*count, *sum),
+                cells => panic!("unexpected summary: {cells:?}"),
+            };
+            let ids = sets[1]
+                .iter()
+                .map(|cells| match cells[0] {
+                    SqliteValue::Integer(id) => id,
+                    ref cell => panic!("unexpected id: {cell:?}"),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(count, ids.len() as i64);
+            assert_eq!(sum, ids.iter().sum::<i64>());
+            // whole transactions or nothing
+            assert_eq!(count % 10, 0);
+        }
 
-    if let Err(e) = execute_schema(&agent, statements).await {
-        error!("could not merge schemas: {e}");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: e.to_string(),
-                }],
-                time: 0.0,
-            }),
-        );
-    }
+        stop.store(true, Ordering::Relaxed);
+        writer.await?;
 
-    (
-        StatusCode::OK,
-        axum::Json(ExecResponse {
-            results: vec![],
-            time: start.elapsed().as_secs_f64(),
-        }),
-    )
-}
+        // every statement is checked before any runs
+        let (status, _) = query_multi(
+            &agent,
+            vec![
+                detail.clone(),
+                Statement::Simple("DELETE FROM tests".into()),
+            ],
+        )
+        .await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
 
-#[cfg(test)]
-mod tests {
-    use bytes::Bytes;
-    use corro_types::{api::RowId, config::Config, schema::SqliteType};
-    use futures::Stream;
-    use http_body::{combinators::UnsyncBoxBody, Body};
-    use tokio::sync::mpsc::error::TryRecvError;
-    use tokio_util::codec::{Decoder, LinesCodec};
-    use tripwire::Tripwire;
+        let (status, _) = query_multi(&agent, vec![]).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
 
-    use super::*;
+        Ok(())
+    }
 
-    use crate::agent::setup;
+    async fn query_as_of(
+        agent: &Agent,
+        db_version: i64,
+    ) -> eyre::Result<(StatusCode, Vec<QueryEvent>)> {
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            axum::extract::Query(QueryParams {
+                as_of_db_version: Some(db_version),
+                ..Default::default()
+            }),
+            HeaderMap::new(),
+            axum::Json(Statement::Simple("SELECT id, text FROM tests".into())),
+        )
+        .await
+        .into_response();
 
-    struct UnsyncBodyStream(std::pin::Pin<Box<UnsyncBoxBody<Bytes, axum::Error>>>);
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        if status != StatusCode::OK {
+            return Ok((status, vec![]));
+        }
 
-    impl Stream for UnsyncBodyStream {
-        type Item = Result<Bytes, axum::Error>;
+        let events = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<QueryEvent>, _>>()?;
 
-        fn poll_next(
-            mut self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
-        ) -> std::task::Poll<Option<Self::Item>> {
-            self.0.as_mut().poll_data(cx)
+        Ok((status, events))
+    }
+
+    async fn query_cached(
+        agent: &Agent,
+        headers: HeaderMap,
+    ) -> eyre::Result<(Option<String>, Vec<QueryEvent>)> {
+        let res = api_v1_queries(
+            Extension(agent.clone()),
+            axum::extract::Query(QueryParams {
+                cache_ttl_ms: Some(60_000),
+                ..Default::default()
+            }),
+            headers,
+            axum::Json(Statement::Simple(
+                "SELECT count(*) FROM tests WHERE text != ''".into(),
+            )),
+        )
+        .await
+        .into_response();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let cache = res
+            .headers()
+            .get(QUERY_CACHE_HEADER)
+            .map(|value| value.to_str().unwrap().to_owned());
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+
+        let events = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<QueryEvent>, _>>()?;
+
+        // the body is stored once it's all been sent
+        for _ in 0..100 {
+            if !agent.query_cache().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
+
+        Ok((cache, events))
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_api_db_execute() -> eyre::Result<()> {
+    async fn test_api_v1_queries_cache() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
 
         let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
-
         let dir = tempfile::tempdir()?;
 
-        let (agent, mut agent_options) = setup(
+        let (agent, _agent_options) = setup(
             Config::builder()
                 .db_path(dir.path().join("corrosion.db").display().to_string())
                 .gossip_addr("127.0.0.1:0".parse()?)
@@ -772,81 +5184,174 @@ mod tests {
         )
         .await?;
 
-        let rx_bcast = &mut agent_options.rx_bcast;
-
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
-
         assert_eq!(status_code, StatusCode::OK);
 
-        let (status_code, body) = api_v1_transactions(
-            Extension(agent.clone()),
-            axum::Json(vec![Statement::WithParams(
-                "insert into tests (id, text) values (?,?)".into(),
-                vec!["service-id".into(), "service-name".into()],
-            )]),
-        )
-        .await;
+        let insert = |table: &str, id: i64| {
+            let agent = agent.clone();
+            let stmt = Statement::WithParams(
+                format!("INSERT INTO {table} (id, text) VALUES (?, 'hello')"),
+                vec![id.into()],
+            );
+            async move {
+                let (status_code, _) = api_v1_transactions(
+                    Extension(agent),
+                    HeaderMap::new(),
+                    axum::Json(vec![stmt].into()),
+                )
+                .await;
+                assert_eq!(status_code, StatusCode::OK);
+            }
+        };
+        let count = |events: &[QueryEvent]| match &events[..] {
+            [QueryEvent::Columns { .. }, QueryEvent::Row(_, cells), QueryEvent::EndOfQuery { .. }] => {
+                cells[0].clone()
+            }
+            events => panic!("unexpected events: {events:?}"),
+        };
 
-        println!("{body:?}");
+        insert("tests", 1).await;
+
+        let (cache, events) = query_cached(&agent, HeaderMap::new()).await?;
+        assert_eq!(cache.as_deref(), Some("miss"));
+        assert_eq!(count(&events), SqliteValue::Integer(1));
+        assert_eq!(agent.query_cache().len(), 1);
+
+        // same framing, straight from the cache
+        let (cache, cached) = query_cached(&agent, HeaderMap::new()).await?;
+        assert_eq!(cache.as_deref(), Some("hit"));
+        assert_eq!(cached, events);
+
+        // a table the query doesn't read
+        insert("tests2", 1).await;
+        let (cache, _) = query_cached(&agent, HeaderMap::new()).await?;
+        assert_eq!(cache.as_deref(), Some("hit"));
+
+        insert("tests", 2).await;
+        assert!(agent.query_cache().is_empty());
+        let (cache, events) = query_cached(&agent, HeaderMap::new()).await?;
+        assert_eq!(cache.as_deref(), Some("miss"));
+        assert_eq!(count(&events), SqliteValue::Integer(2));
+
+        let (cache, _) = query_cached(&agent, HeaderMap::new()).await?;
+        assert_eq!(cache.as_deref(), Some("hit"));
+
+        // the hello is only sent to clients asking for it, never cached
+        let mut handshake = HeaderMap::new();
+        handshake.insert(CAPABILITIES_HEADER, HeaderValue::from_static(""));
+        let hello = QueryEvent::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: vec![],
+            sub_id: None,
+        };
 
-        assert_eq!(status_code, StatusCode::OK);
+        let (cache, events) = query_cached(&agent, handshake.clone()).await?;
+        assert_eq!(cache.as_deref(), Some("hit"));
+        assert_eq!(events[0], hello);
+        assert_eq!(count(&events[1..]), SqliteValue::Integer(2));
 
-        assert!(body.0.results.len() == 1);
+        insert("tests", 3).await;
+        let (cache, events) = query_cached(&agent, handshake).await?;
+        assert_eq!(cache.as_deref(), Some("miss"));
+        assert_eq!(events[0], hello);
+        assert_eq!(count(&events[1..]), SqliteValue::Integer(3));
 
-        let msg = rx_bcast
-            .recv()
-            .await
-            .expect("not msg received on bcast channel");
+        let (cache, events) = query_cached(&agent, HeaderMap::new()).await?;
+        assert_eq!(cache.as_deref(), Some("hit"));
+        assert_eq!(count(&events), SqliteValue::Integer(3));
 
-        assert!(matches!(
-            msg,
-            BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
-                changeset: Changeset::Full { version: 1, .. },
-                ..
-            }))
-        ));
+        Ok(())
+    }
 
-        assert_eq!(
-            agent
-                .bookie()
-                .write("test")
-                .await
-                .for_actor(agent.actor_id())
-                .read("test")
-                .await
-                .last(),
-            Some(1)
-        );
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_queries_coerce() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
 
-        println!("second req...");
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+        let dir = tempfile::tempdir()?;
 
-        let (status_code, body) = api_v1_transactions(
-            Extension(agent.clone()),
-            axum::Json(vec![Statement::WithParams(
-                "update tests SET text = ? where id = ?".into(),
-                vec!["service-name".into(), "service-id".into()],
-            )]),
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
         )
-        .await;
-
-        println!("{body:?}");
-
-        assert_eq!(status_code, StatusCode::OK);
+        .await?;
 
-        assert!(body.0.results.len() == 1);
+        let query = |coerce: Option<Coercion>| {
+            let agent = agent.clone();
+            async move {
+                let res = api_v1_queries(
+                    Extension(agent),
+                    axum::extract::Query(QueryParams {
+                        coerce,
+                        ..Default::default()
+                    }),
+                    HeaderMap::new(),
+                    axum::Json(Statement::Simple(
+                        "SELECT '42' AS n, '1e5' AS r, 'NaN' AS nan, '0123' AS id, 7 AS i \
+                         UNION ALL SELECT 'abc', '-1.5', 'NaN', '0123', 8"
+                            .into(),
+                    )),
+                )
+                .await
+                .into_response();
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                body.split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice)
+                    .collect::<Result<Vec<QueryEvent>, _>>()
+                    .map_err(eyre::Report::from)
+            }
+        };
 
-        // no actual changes!
-        assert!(matches!(rx_bcast.try_recv(), Err(TryRecvError::Empty)));
+        let events = query(Some(Coercion::NumericText)).await?;
+        let [QueryEvent::Columns { names: columns, .. }, QueryEvent::Row(_, first), QueryEvent::Row(_, second), QueryEvent::EndOfQuery { coerced, .. }] =
+            &events[..]
+        else {
+            panic!("unexpected events: {events:?}");
+        };
+        assert_eq!(
+            first,
+            &vec![
+                SqliteValue::Integer(42),
+                SqliteValue::Real(Real(100000.0)),
+                SqliteValue::Text("NaN".into()),
+                SqliteValue::Text("0123".into()),
+                SqliteValue::Integer(7),
+            ]
+        );
+        assert_eq!(second[0], SqliteValue::Text("abc".into()));
+        assert_eq!(second[1], SqliteValue::Real(Real(-1.5)));
+        assert_eq!(coerced, &vec![CompactString::from("n"), "r".into()]);
+
+        // typed rows can be extracted from what's coerced
+        let (n,): (i64,) = FromRow::from_values(&first[..1], &columns[..1])?;
+        assert_eq!(n, 42);
+
+        // values are returned as stored otherwise
+        let events = query(None).await?;
+        let [_, QueryEvent::Row(_, first), _, QueryEvent::EndOfQuery { coerced, .. }] = &events[..]
+        else {
+            panic!("unexpected events: {events:?}");
+        };
+        assert_eq!(first[0], SqliteValue::Text("42".into()));
+        assert!(coerced.is_empty());
+        assert!(<(i64,)>::from_values(&first[..1], &columns[..1]).is_err());
 
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_api_db_query() -> eyre::Result<()> {
+    async fn test_api_v1_queries_as_of() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
 
         let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
@@ -856,6 +5361,7 @@ mod tests {
         let (agent, _agent_options) = setup(
             Config::builder()
                 .db_path(dir.path().join("corrosion.db").display().to_string())
+                .history_retention_secs(3600)
                 .gossip_addr("127.0.0.1:0".parse()?)
                 .api_addr("127.0.0.1:0".parse()?)
                 .build()?,
@@ -868,89 +5374,221 @@ mod tests {
             axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
-
         assert_eq!(status_code, StatusCode::OK);
 
-        let (status_code, body) = api_v1_transactions(
-            Extension(agent.clone()),
-            axum::Json(vec![
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id".into(), "service-name".into()],
-                ),
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-2".into(), "service-name-2".into()],
+        let before: i64 =
+            agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT crsql_db_version()", [], |row| row.get(0))?;
+
+        let mut versions = vec![];
+        for text in ["one", "two", "three"] {
+            let (status_code, _body) = api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "INSERT INTO tests (id, text) VALUES (1, ?) ON CONFLICT (id) DO UPDATE SET text = excluded.text".into(),
+                        vec![text.into()],
+                    )]
+                    .into(),
                 ),
-            ]),
-        )
-        .await;
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+
+            let db_version: i64 =
+                agent
+                    .pool()
+                    .read()
+                    .await?
+                    .query_row("SELECT crsql_db_version()", [], |row| row.get(0))?;
+            versions.push((db_version, text));
+        }
 
-        // println!("{body:?}");
+        for (db_version, text) in versions.iter() {
+            let (status, events) = query_as_of(&agent, *db_version).await?;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                events[1],
+                QueryEvent::Row(RowId(1), vec![1i64.into(), (*text).into()])
+            );
+            assert!(matches!(events[2], QueryEvent::EndOfQuery { .. }));
+        }
 
-        assert_eq!(status_code, StatusCode::OK);
+        // the row didn't exist yet
+        let (status, events) = query_as_of(&agent, before).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(matches!(events[1], QueryEvent::EndOfQuery { .. }));
 
-        assert!(body.0.results.len() == 2);
+        // current state is untouched
+        let (status, events) = query_as_of(&agent, versions[2].0 + 100).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            events[1],
+            QueryEvent::Row(RowId(1), vec![1i64.into(), "three".into()])
+        );
 
-        println!("transaction body: {body:?}");
+        // past the retention window
+        {
+            let conn = agent.pool().write_priority().await?;
+            history::prune(&conn, i64::MAX)?;
+        }
+        let (status, _) = query_as_of(&agent, versions[1].0).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = query_as_of(&agent, versions[2].0).await?;
+        assert_eq!(status, StatusCode::OK);
+
+        Ok(())
+    }
 
+    async fn query_registered(
+        agent: &Agent,
+        name: &str,
+        params: Vec<SqliteParam>,
+    ) -> eyre::Result<(StatusCode, Vec<QueryEvent>)> {
         let res = api_v1_queries(
             Extension(agent.clone()),
-            axum::Json(Statement::Simple("select * from tests".into())),
+            axum::extract::Query(Default::default()),
+            HeaderMap::new(),
+            axum::Json(Statement::Registered {
+                name: name.into(),
+                params,
+            }),
         )
         .await
         .into_response();
 
-        assert_eq!(res.status(), StatusCode::OK);
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        if status != StatusCode::OK {
+            return Ok((status, vec![]));
+        }
 
-        let mut body = res.into_body();
+        let events = body
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<Result<Vec<QueryEvent>, _>>()?;
 
-        let mut lines = LinesCodec::new();
+        Ok((status, events))
+    }
 
-        let mut buf = BytesMut::new();
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_registered_queries() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
 
-        let s = lines.decode(&mut buf).unwrap().unwrap();
+        let dir = tempfile::tempdir()?;
 
-        let cols: QueryEvent = serde_json::from_str(&s).unwrap();
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
 
-        assert_eq!(cols, QueryEvent::Columns(vec!["id".into(), "text".into()]));
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::Simple(
+                    "insert into tests (id, text) values (1, 'a'), (2, 'b'), (3, 'c')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
+        let (status, _) = query_registered(&agent, "tests_by_id", vec![1i64.into()]).await?;
+        assert_eq!(status, StatusCode::NOT_FOUND);
 
-        let s = lines.decode(&mut buf).unwrap().unwrap();
+        let register = |name: &str, registered: RegisteredQuery| {
+            api_v1_register_query(
+                Extension(agent.clone()),
+                axum::extract::Path(name.into()),
+                axum::Json(registered),
+            )
+        };
 
-        let row: QueryEvent = serde_json::from_str(&s).unwrap();
+        let (status, _) = register(
+            "tests_by_id",
+            RegisteredQuery::new("select id, text from tests where id = ?"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
 
+        let (status, events) = query_registered(&agent, "tests_by_id", vec![2i64.into()]).await?;
+        assert_eq!(status, StatusCode::OK);
         assert_eq!(
-            row,
-            QueryEvent::Row(RowId(1), vec!["service-id".into(), "service-name".into()])
+            events[..2],
+            [
+                QueryEvent::columns(vec!["id".into(), "text".into()]),
+                QueryEvent::Row(RowId(1), vec![2i64.into(), "b".into()]),
+            ]
         );
+        assert!(matches!(events[2], QueryEvent::EndOfQuery { .. }));
+
+        // params are checked against the template's placeholders
+        let (status, _) = query_registered(&agent, "tests_by_id", vec![]).await?;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = register("delete_tests", RegisteredQuery::new("delete from tests")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // updating a template applies to the next queries
+        let (status, _) = register(
+            "tests_by_id",
+            RegisteredQuery {
+                query: "select text from tests where id >= ?".into(),
+                limits: QueryLimits {
+                    max_rows: Some(1),
+                    timeout_ms: None,
+                },
+            },
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
-
-        let s = lines.decode(&mut buf).unwrap().unwrap();
-
-        let row: QueryEvent = serde_json::from_str(&s).unwrap();
-
+        let (status, events) = query_registered(&agent, "tests_by_id", vec![1i64.into()]).await?;
+        assert_eq!(status, StatusCode::OK);
         assert_eq!(
-            row,
-            QueryEvent::Row(
-                RowId(2),
-                vec!["service-id-2".into(), "service-name-2".into()]
-            )
+            events,
+            vec![
+                QueryEvent::columns(vec!["text".into()]),
+                QueryEvent::Row(RowId(1), vec!["a".into()]),
+                QueryEvent::Error("query returned more than 1 rows".into()),
+            ]
         );
 
-        buf.extend_from_slice(&body.data().await.unwrap()?);
-
-        let s = lines.decode(&mut buf).unwrap().unwrap();
-
-        let query_evt: QueryEvent = serde_json::from_str(&s).unwrap();
-
-        assert!(matches!(query_evt, QueryEvent::EndOfQuery { .. }));
-
-        assert!(body.data().await.is_none());
+        // registered queries don't run in transactions
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::Registered {
+                    name: "tests_by_id".into(),
+                    params: vec![1i64.into()],
+                }]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::BAD_REQUEST);
 
         Ok(())
     }
@@ -0,0 +1,202 @@
+//! Node names replicated through the `__corro_node_names` table, see
+//! `corro_types::node_names`.
+
+use corro_types::{
+    agent::{Agent, ChangeError, PoolError},
+    hooks::ChangeRecvError,
+    node_names::NODE_NAMES_TABLE,
+};
+use rusqlite::{params, OptionalExtension};
+use tokio::task::block_in_place;
+use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
+
+use super::make_broadcastable_changes;
+
+/// Records `name` as this node's, replicated like any other write. Returns
+/// whether it changed.
+pub async fn write_node_name(agent: &Agent, name: &str) -> Result<bool, ChangeError> {
+    let site_id = agent.site_id();
+    let stored: Option<String> = {
+        let conn = agent.pool().read().await.map_err(PoolError::from)?;
+        block_in_place(|| {
+            conn.prepare_cached("SELECT name FROM __corro_node_names WHERE site_id = ?")?
+                .query_row([site_id], |row| row.get(0))
+                .optional()
+        })?
+    };
+    if stored.as_deref() == Some(name) {
+        return Ok(false);
+    }
+
+    let tracker = agent.exec_registry().register(Default::default(), 1);
+    make_broadcastable_changes(agent, Default::default(), &tracker, |tx, _| {
+        tx.prepare_cached(
+            "INSERT INTO __corro_node_names (site_id, name) VALUES (?, ?)
+                ON CONFLICT (site_id) DO UPDATE SET name = excluded.name",
+        )?
+        .execute(params![site_id, name])?;
+        Ok(())
+    })
+    .await?;
+
+    Ok(true)
+}
+
+async fn reload_node_names(agent: &Agent) {
+    let res = match agent.pool().read().await {
+        Ok(conn) => block_in_place(|| agent.node_names().reload(&conn)),
+        Err(e) => {
+            error!("could not get a read conn to load node names: {e}");
+            return;
+        }
+    };
+    match res {
+        Ok(count) => debug!("loaded the names of {count} nodes"),
+        Err(e) => error!("could not load node names: {e}"),
+    }
+}
+
+/// Records this node's `gossip.node_name`, if it has one, then keeps the cached names of the nodes up to
+/// date w/ the `__corro_node_names` table
+pub async fn node_names_loop(agent: Agent, mut tripwire: Tripwire) {
+    // before writing, not to miss changes made in between
+    let mut changes = agent.changes([NODE_NAMES_TABLE]);
+
+    if let Some(name) = agent.config().gossip.node_name.clone() {
+        match write_node_name(&agent, &name).await {
+            Ok(true) => info!("this node is now named '{name}'"),
+            Ok(false) => {}
+            Err(e) => error!("could not record this node's name: {e}"),
+        }
+    }
+    reload_node_names(&agent).await;
+
+    loop {
+        tokio::select! {
+            res = changes.recv() => match res {
+                Ok(_) => reload_node_names(&agent).await,
+                Err(ChangeRecvError::Lagged(n)) => {
+                    warn!("missed {n} batches of node name changes, reloading them all");
+                    reload_node_names(&agent).await;
+                }
+                Err(ChangeRecvError::Closed) => break,
+            },
+            _ = &mut tripwire => {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use corro_tests::launch_test_agent;
+    use corro_types::api::{QueryEvent, SqliteValue, Statement};
+    use futures::StreamExt;
+    use spawn::wait_for_all_pending_handles;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn attributes_changes_to_their_origin() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_test_agent(|conf| conf.node_name("a").build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .node_name("b")
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (a, b) = (ta1.agent.site_id(), ta2.agent.site_id());
+        for _ in 0..100 {
+            if ta1.agent.node_names().get(&b).is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(ta1.agent.node_names().get(&a).as_deref(), Some("a"));
+        assert_eq!(ta1.agent.node_names().get(&b).as_deref(), Some("b"));
+
+        // unchanged
+        assert!(!write_node_name(&ta1.agent, "a").await?);
+
+        let client1 = corro_client::CorrosionApiClient::new(ta1.agent.api_addr());
+        let client2 = corro_client::CorrosionApiClient::new(ta2.agent.api_addr());
+
+        let mut sub = client1
+            .subscribe_with_origins(
+                &Statement::Simple("SELECT id, text FROM tests".into()),
+                None,
+            )
+            .await?;
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::EndOfQuery { .. }) => break,
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+
+        let insert = |id: i64| {
+            Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                vec![id.into(), format!("row {id}").into()],
+            )
+        };
+        client1.execute(&[insert(1)]).await?;
+        client2.execute(&[insert(2)]).await?;
+
+        // by inserted id
+        let mut origins = HashMap::new();
+        let mut pending = HashMap::new();
+        while origins.len() < 2 {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::Origin(origin)) => {
+                    pending.insert(origin.change_id.0, origin);
+                }
+                Some(QueryEvent::Change(_, _, cells, change_id)) => {
+                    let SqliteValue::Integer(id) = cells[0] else {
+                        eyre::bail!("unexpected id {:?}", cells[0]);
+                    };
+                    let origin = pending
+                        .remove(&change_id.0)
+                        .ok_or_else(|| eyre::eyre!("no origin for change {change_id:?}"))?;
+                    origins.insert(id, origin);
+                }
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+
+        assert_eq!(origins[&1].site_id, a);
+        assert_eq!(origins[&1].node_name.as_deref(), Some("a"));
+        assert_eq!(origins[&2].site_id, b);
+        assert_eq!(origins[&2].node_name.as_deref(), Some("b"));
+
+        // renamed
+        assert!(write_node_name(&ta2.agent, "b2").await?);
+        for _ in 0..100 {
+            if ta1.agent.node_names().get(&b).as_deref() == Some("b2") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(ta1.agent.node_names().get(&b).as_deref(), Some("b2"));
+
+        drop(sub);
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
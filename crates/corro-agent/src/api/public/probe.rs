@@ -0,0 +1,266 @@
+//! Change propagation probes, see `ProbeConfig`: heartbeats written to the
+//! replicated `__corro_probe` table and the latencies of the peers' ones.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::Extension;
+use corro_types::{
+    agent::{Agent, ChangeError, PoolError},
+    api::{ProbeLatencies, SiteId},
+    config::ProbeConfig,
+    hooks::ChangeRecvError,
+    probe::PROBE_TABLE,
+};
+use metrics::{histogram, increment_counter};
+use rusqlite::{params, Connection};
+use tokio::task::block_in_place;
+use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
+
+use super::make_broadcastable_changes;
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Bumps this node's heartbeat, replicated like any other write
+pub async fn write_heartbeat(agent: &Agent) -> Result<(), ChangeError> {
+    let site_id = agent.site_id();
+    let tracker = agent.exec_registry().register(Default::default(), 1);
+
+    make_broadcastable_changes(agent, Default::default(), &tracker, |tx, _| {
+        tx.prepare_cached(
+            "INSERT INTO __corro_probe (actor_id, counter, origin_ts) VALUES (?, 1, ?)
+                ON CONFLICT (actor_id) DO UPDATE SET
+                    counter = counter + 1,
+                    origin_ts = excluded.origin_ts",
+        )?
+        .execute(params![site_id, now_ms() as i64])?;
+        Ok(())
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Other nodes whose last heartbeat is older than `before_ms`
+pub fn stale_heartbeats(
+    conn: &Connection,
+    own: SiteId,
+    before_ms: u64,
+) -> rusqlite::Result<Vec<SiteId>> {
+    conn.prepare_cached("SELECT actor_id FROM __corro_probe WHERE actor_id != ? AND origin_ts < ?")?
+        .query_map(params![own, before_ms as i64], |row| row.get(0))?
+        .collect()
+}
+
+/// Deletes the heartbeats of nodes which haven't written one for
+/// `retention_secs`, they likely left the cluster for good. Returns their
+/// site ids.
+pub async fn cleanup_heartbeats(
+    agent: &Agent,
+    retention_secs: u64,
+) -> Result<Vec<SiteId>, ChangeError> {
+    let before_ms = now_ms().saturating_sub(retention_secs.saturating_mul(1000));
+    let stale = {
+        let conn = agent.pool().read().await.map_err(PoolError::from)?;
+        block_in_place(|| stale_heartbeats(&conn, agent.site_id(), before_ms))?
+    };
+    if stale.is_empty() {
+        return Ok(stale);
+    }
+
+    let tracker = agent.exec_registry().register(Default::default(), 1);
+    make_broadcastable_changes(agent, Default::default(), &tracker, |tx, _| {
+        let mut stmt =
+            tx.prepare_cached("DELETE FROM __corro_probe WHERE actor_id = ? AND origin_ts < ?")?;
+        for site_id in stale.iter() {
+            stmt.execute(params![site_id, before_ms as i64])?;
+        }
+        Ok(())
+    })
+    .await?;
+
+    for site_id in stale.iter() {
+        agent.probe().forget(site_id);
+    }
+
+    Ok(stale)
+}
+
+/// Writes heartbeats at the configured interval, cleans up stale ones and
+/// records the latencies of the peers' heartbeats as they're applied
+pub async fn probe_loop(agent: Agent, config: ProbeConfig, mut tripwire: Tripwire) {
+    info!(
+        "probing change propagation every {}ms",
+        config.interval_ms.max(1)
+    );
+
+    let mut changes = agent.changes([PROBE_TABLE]);
+    let mut interval = tokio::time::interval(Duration::from_millis(config.interval_ms.max(1)));
+    let site_id = agent.site_id();
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(e) = write_heartbeat(&agent).await {
+                    increment_counter!("corro.probe.heartbeat.errors");
+                    error!("could not write heartbeat: {e}");
+                }
+                match cleanup_heartbeats(&agent, config.retention_secs).await {
+                    Ok(stale) if !stale.is_empty() => {
+                        debug!("deleted the stale heartbeats of {} nodes", stale.len());
+                    }
+                    Ok(_) => {}
+                    Err(e) => error!("could not clean up stale heartbeats: {e}"),
+                }
+            },
+            res = changes.recv() => match res {
+                Ok(batch) => {
+                    for (origin, latency) in agent.probe().record(site_id, &batch, now_ms()) {
+                        histogram!("corro.probe.propagation.seconds", latency, "origin" => origin.to_string());
+                    }
+                }
+                Err(ChangeRecvError::Lagged(n)) => {
+                    warn!("missed {n} batches of heartbeats");
+                }
+                Err(ChangeRecvError::Closed) => break,
+            },
+            _ = &mut tripwire => {
+                break;
+            }
+        }
+    }
+}
+
+pub async fn api_v1_cluster_latency(
+    Extension(agent): Extension<Agent>,
+) -> axum::Json<ProbeLatencies> {
+    axum::Json(ProbeLatencies {
+        enabled: agent.config().gossip.probe.is_some(),
+        peers: agent.probe().latencies(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn records_peer_latencies() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let probe = ProbeConfig {
+            interval_ms: 100,
+            ..Default::default()
+        };
+        let ta1 = launch_test_agent(|conf| conf.probe(probe).build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .probe(probe)
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        for (ta, peer) in [(&ta1, &ta2), (&ta2, &ta1)] {
+            let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+
+            let mut latencies = client.cluster_latency().await?;
+            for _ in 0..100 {
+                if latencies.peers.iter().any(|peer| peer.samples >= 5) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                latencies = client.cluster_latency().await?;
+            }
+
+            assert!(latencies.enabled);
+            assert_eq!(latencies.peers.len(), 1, "{latencies:?}");
+            let latency = &latencies.peers[0];
+            assert_eq!(latency.site_id, peer.agent.site_id());
+            assert!(latency.samples >= 5, "{latency:?}");
+            assert!(latency.counter >= 5, "{latency:?}");
+            // both nodes share a clock, heartbeats are broadcast right away
+            assert!(latency.p50 <= latency.p99, "{latency:?}");
+            assert!(latency.p99 <= latency.max, "{latency:?}");
+            assert!(latency.p99 < 5.0, "{latency:?}");
+            assert!(latency.last_seen_at > 0, "{latency:?}");
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn cleans_up_stale_heartbeats() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        // not probing, heartbeats are written by hand
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let agent = &ta.agent;
+
+        let gone = SiteId([2; 16]);
+        let alive = SiteId([3; 16]);
+        {
+            let conn = agent.pool().write_priority().await?;
+            let mut stmt = conn.prepare(
+                "INSERT INTO __corro_probe (actor_id, counter, origin_ts) VALUES (?, 1, ?)",
+            )?;
+            stmt.execute(params![gone, 1_000])?;
+            stmt.execute(params![alive, now_ms() as i64])?;
+        }
+        write_heartbeat(agent).await?;
+        write_heartbeat(agent).await?;
+
+        {
+            let conn = agent.pool().read().await?;
+            assert_eq!(
+                stale_heartbeats(&conn, agent.site_id(), now_ms() - 60_000)?,
+                [gone]
+            );
+            let counter: i64 = conn.query_row(
+                "SELECT counter FROM __corro_probe WHERE actor_id = ?",
+                [agent.site_id()],
+                |row| row.get(0),
+            )?;
+            assert_eq!(counter, 2);
+        }
+
+        assert_eq!(cleanup_heartbeats(agent, 60).await?, [gone]);
+        assert!(cleanup_heartbeats(agent, 60).await?.is_empty());
+
+        let conn = agent.pool().read().await?;
+        let mut remaining: Vec<SiteId> = conn
+            .prepare("SELECT actor_id FROM __corro_probe")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        remaining.sort();
+        let mut expected = vec![alive, agent.site_id()];
+        expected.sort();
+        assert_eq!(remaining, expected);
+
+        // not probing
+        let latencies = api_v1_cluster_latency(Extension(agent.clone())).await.0;
+        assert!(!latencies.enabled);
+        assert!(latencies.peers.is_empty());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
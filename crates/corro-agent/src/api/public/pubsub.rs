@@ -11,12 +11,22 @@ use bytes::{BufMut, Bytes, BytesMut};
 use compact_str::{format_compact, ToCompactString};
 use corro_types::{
     agent::Agent,
-    api::{ChangeId, QueryEvent, QueryEventMeta, RowId, Statement},
+    api::{
+        ChangeId, ChangeOrigin, QueryEvent, QueryEventMeta, ResumeGap, RowId, Statement,
+        CAPABILITY_BATCH, CAPABILITY_ORIGIN, SHUTTING_DOWN,
+    },
     change::SqliteValue,
-    pubsub::{Matcher, MatcherError, MatcherHandle, NormalizeStatementError},
-    sqlite::SqlitePoolError,
+    config::ScanPolicy,
+    pubsub::{
+        select_tables, shared_plan, Matcher, MatcherError, MatcherHandle, NormalizeStatementError,
+    },
+    registry::RegistryError,
+    sqlite::{explain_query_plan, SqlitePoolError},
+    usage::UsageCounters,
 };
 use futures::{future::poll_fn, ready, Stream};
+use hyper::HeaderMap;
+use metrics::increment_counter;
 use rusqlite::{Connection, Transaction};
 use serde::Deserialize;
 use tokio::{
@@ -26,12 +36,40 @@ use tokio::{
 use tokio_stream::{wrappers::errors::BroadcastStreamRecvError, StreamExt};
 use tokio_util::sync::PollSender;
 use tracing::{debug, error, info, warn};
+use tripwire::Tripwire;
 use uuid::Uuid;
 
+use super::handshake::{Handshake, SUBSCRIPTION_CAPABILITIES};
+
 #[derive(Default, Deserialize)]
 pub struct SubParams {
     #[serde(default)]
     from: Option<ChangeId>,
+    /// Share a matcher w/ subscriptions to the same query w/ other params
+    #[serde(default)]
+    shared: bool,
+    /// Leave out changes from transactions tagged w/ this `source_id`
+    #[serde(default)]
+    skip_source_id: Option<Uuid>,
+    /// Send bursts of changes as `QueryEvent::ChangeBatch` events, superseded
+    /// by the `batch` capability
+    #[serde(default)]
+    batch: bool,
+    /// Send a `QueryEvent::Origin` before each change, attributing it to the
+    /// node it originates from, superseded by the `origin` capability
+    #[serde(default)]
+    origin: bool,
+}
+
+impl SubParams {
+    // the legacy params still work for clients predating the handshake
+    fn origin(&self, handshake: &Handshake) -> bool {
+        self.origin || handshake.is_enabled(CAPABILITY_ORIGIN)
+    }
+
+    fn batch(&self, handshake: &Handshake) -> bool {
+        self.batch || handshake.is_enabled(CAPABILITY_BATCH)
+    }
 }
 
 pub async fn api_v1_sub_by_id(
@@ -39,14 +77,31 @@ pub async fn api_v1_sub_by_id(
     Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
     axum::extract::Path(id): axum::extract::Path<Uuid>,
     axum::extract::Query(params): axum::extract::Query<SubParams>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    sub_by_id(agent, id, params.from, &bcast_cache).await
+    let handshake = Handshake::new(&headers, SUBSCRIPTION_CAPABILITIES);
+    sub_by_id(
+        agent,
+        id,
+        params.from,
+        params.skip_source_id,
+        params.origin(&handshake),
+        params.batch(&handshake),
+        &handshake,
+        &bcast_cache,
+    )
+    .await
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn sub_by_id(
     agent: Agent,
     id: Uuid,
     from: Option<ChangeId>,
+    skip_source_id: Option<Uuid>,
+    origin: bool,
+    batch: bool,
+    handshake: &Handshake,
     bcast_cache: &SharedMatcherBroadcastCache,
 ) -> hyper::Response<hyper::Body> {
     let (matcher, rx) = match bcast_cache.read().await.get(&id).and_then(|tx| {
@@ -74,15 +129,34 @@ async fn sub_by_id(
         }
     };
 
+    if let Err(e) = matcher.touch() {
+        warn!(%id, "could not resume subscription: {e}");
+    }
+
+    if let Err(e) = check_resume_gap(&agent, &matcher, from).await {
+        return e.into();
+    }
+
     let (evt_tx, evt_rx) = mpsc::channel(512);
 
-    tokio::spawn(catch_up_sub(agent, matcher, from, rx, evt_tx));
+    let skip = SourceSkip::new(&matcher, skip_source_id);
+    let origins = OriginTags::new(&agent, &matcher, origin);
+    tokio::spawn(catch_up_sub(
+        agent, matcher, from, rx, evt_tx, None, skip, origins, batch,
+    ));
 
     let (tx, body) = hyper::Body::channel();
 
-    tokio::spawn(forward_bytes_to_body_sender(evt_rx, tx));
+    let tripwire = agent.tripwire().clone();
+    tokio::spawn(forward_bytes_to_body_sender(
+        handshake.hello(Some(id)),
+        evt_rx,
+        tx,
+        tripwire,
+    ));
 
-    hyper::Response::builder()
+    handshake
+        .headers(hyper::Response::builder())
         .status(StatusCode::OK)
         .header("corro-query-id", id.to_string())
         .body(body)
@@ -91,11 +165,11 @@ async fn sub_by_id(
 
 fn make_query_event_bytes(
     buf: &mut BytesMut,
-    query_evt: QueryEvent,
+    query_evt: &QueryEvent,
 ) -> serde_json::Result<(Bytes, QueryEventMeta)> {
     {
         let mut writer = buf.writer();
-        serde_json::to_writer(&mut writer, &query_evt)?;
+        serde_json::to_writer(&mut writer, query_evt)?;
 
         // NOTE: I think that's infaillible...
         writer
@@ -106,6 +180,15 @@ fn make_query_event_bytes(
     Ok((buf.split().freeze(), query_evt.meta()))
 }
 
+// credits the subscription w/ the rows and changes serialized for it
+fn count_usage(usage: &UsageCounters, query_evt: &QueryEvent, bytes: usize) {
+    match query_evt {
+        QueryEvent::Row(..) => usage.row(bytes),
+        QueryEvent::Change(..) => usage.change(bytes),
+        _ => {}
+    }
+}
+
 const MAX_UNSUB_TIME: Duration = Duration::from_secs(300);
 // this should be a fraction of the MAX_UNSUB_TIME
 const RECEIVERS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
@@ -113,10 +196,11 @@ const RECEIVERS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
 pub async fn process_sub_channel(
     agent: Agent,
     id: Uuid,
-    tx: broadcast::Sender<(Bytes, QueryEventMeta)>,
+    tx: broadcast::Sender<SubEvent>,
     mut evt_rx: mpsc::Receiver<QueryEvent>,
 ) {
     let mut buf = BytesMut::new();
+    let usage = agent.usage().subscription(id);
 
     let mut deadline = None;
 
@@ -153,12 +237,16 @@ pub async fn process_sub_channel(
             }
         };
 
-        let is_still_active = match make_query_event_bytes(&mut buf, query_evt) {
-            Ok(b) => tx.send(b).is_ok(),
+        let is_still_active = match make_query_event_bytes(&mut buf, &query_evt) {
+            Ok((bytes, meta)) => {
+                count_usage(&usage, &query_evt, bytes.len());
+                tx.send((bytes, meta, Arc::new(query_evt))).is_ok()
+            }
             Err(e) => {
                 _ = tx.send((
-                    error_to_query_event_bytes(&mut buf, e),
+                    error_to_query_event_bytes(&mut buf, &e),
                     QueryEventMeta::Error,
+                    Arc::new(QueryEvent::Error(e.to_compact_string())),
                 ));
                 break;
             }
@@ -207,8 +295,13 @@ fn expanded_statement(conn: &Connection, stmt: &Statement) -> rusqlite::Result<O
             query,
             params: None,
             named_params: None,
+            ..
         } => conn.prepare(query)?.expanded_sql(),
         Statement::WithParams(query, params)
+        | Statement::Registered {
+            name: query,
+            params,
+        }
         | Statement::Verbose {
             query,
             params: Some(params),
@@ -258,6 +351,16 @@ pub enum MatcherUpsertError {
     Matcher(#[from] MatcherError),
     #[error("a `from` query param was supplied, but no existing subscription found")]
     SubFromWithoutMatcher,
+    #[error("query fully scans large table(s): {}", .0.join(", "))]
+    LargeTableScan(Vec<String>),
+    #[error("table '{0}' is local-only and can't be subscribed to")]
+    LocalOnlyTable(String),
+    #[error("could not find subscription with id {0}")]
+    SubNotFound(Uuid),
+    #[error(transparent)]
+    Registry(#[from] RegistryError),
+    #[error(transparent)]
+    ResumeGap(ResumeGap),
 }
 
 impl MatcherUpsertError {
@@ -269,18 +372,27 @@ impl MatcherUpsertError {
             MatcherUpsertError::Sqlite(_)
             | MatcherUpsertError::NormalizeStatement(_)
             | MatcherUpsertError::Matcher(_)
-            | MatcherUpsertError::SubFromWithoutMatcher => StatusCode::BAD_REQUEST,
+            | MatcherUpsertError::SubFromWithoutMatcher
+            | MatcherUpsertError::LargeTableScan(_)
+            | MatcherUpsertError::LocalOnlyTable(_) => StatusCode::BAD_REQUEST,
+            MatcherUpsertError::SubNotFound(_)
+            | MatcherUpsertError::Registry(RegistryError::UnknownName(_)) => StatusCode::NOT_FOUND,
+            MatcherUpsertError::Registry(_) => StatusCode::BAD_REQUEST,
+            MatcherUpsertError::ResumeGap(_) => StatusCode::GONE,
         }
     }
 }
 
 impl From<MatcherUpsertError> for hyper::Response<hyper::Body> {
     fn from(value: MatcherUpsertError) -> Self {
+        let body = match &value {
+            MatcherUpsertError::ResumeGap(gap) => serde_json::to_vec(gap),
+            _ => serde_json::to_vec(&QueryEvent::Error(value.to_compact_string())),
+        };
         hyper::Response::builder()
             .status(value.status_code())
             .body(
-                serde_json::to_vec(&QueryEvent::Error(value.to_compact_string()))
-                    .expect("could not serialize queries stream error")
+                body.expect("could not serialize queries stream error")
                     .into(),
             )
             .expect("could not build error response")
@@ -288,9 +400,133 @@ impl From<MatcherUpsertError> for hyper::Response<hyper::Body> {
 }
 pub type MatcherIdCache = HashMap<String, Uuid>;
 pub type SharedMatcherIdCache = Arc<TokioRwLock<MatcherIdCache>>;
-pub type MatcherBroadcastCache = HashMap<Uuid, broadcast::Sender<(Bytes, QueryEventMeta)>>;
+/// A serialized query event, along w/ the event to filter it by
+pub type SubEvent = (Bytes, QueryEventMeta, Arc<QueryEvent>);
+pub type MatcherBroadcastCache = HashMap<Uuid, broadcast::Sender<SubEvent>>;
 pub type SharedMatcherBroadcastCache = Arc<TokioRwLock<MatcherBroadcastCache>>;
 
+/// The params of a subscriber to a shared plan, w/ the index of the result
+/// column each one constrains. Rows are only forwarded when they match.
+#[derive(Debug, Clone)]
+pub struct ParamFilter(Arc<Vec<(usize, SqliteValue)>>);
+
+impl ParamFilter {
+    fn matches_cells(&self, cells: &[SqliteValue]) -> bool {
+        self.0.iter().all(|(i, param)| {
+            cells
+                .get(*i)
+                .map_or(false, |cell| sqlite_value_eq(cell, param))
+        })
+    }
+
+    fn matches(&self, evt: &QueryEvent) -> bool {
+        match evt {
+            QueryEvent::Row(_, cells) | QueryEvent::Change(_, _, cells, _) => {
+                self.matches_cells(cells)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Leaves out the changes of transactions tagged w/ a subscriber's
+/// `skip_source_id`, a `QueryEvent::Skipped` marker is sent in their place
+#[derive(Clone)]
+pub struct SourceSkip {
+    matcher: MatcherHandle,
+    source_id: Uuid,
+}
+
+impl SourceSkip {
+    fn new(matcher: &MatcherHandle, source_id: Option<Uuid>) -> Option<Self> {
+        source_id.map(|source_id| Self {
+            matcher: matcher.clone(),
+            source_id,
+        })
+    }
+
+    fn skips(&self, change_id: ChangeId) -> bool {
+        self.matcher.change_source(change_id) == Some(self.source_id)
+    }
+
+    // the change `meta` is about, if it's to be skipped
+    fn skipped(&self, meta: &QueryEventMeta) -> Option<ChangeId> {
+        match meta {
+            QueryEventMeta::Change(change_id) if self.skips(*change_id) => Some(*change_id),
+            _ => None,
+        }
+    }
+}
+
+// appends the marker standing in for skipped change `change_id`
+fn put_skipped(buf: &mut BytesMut, change_id: ChangeId) {
+    let mut writer = buf.writer();
+    serde_json::to_writer(&mut writer, &QueryEvent::Skipped { change_id })
+        .expect("could not serialize skipped change marker");
+
+    // NOTE: I think that's infaillible...
+    writer
+        .write_all(b"\n")
+        .expect("could not write new line to BytesMut Writer");
+}
+
+/// Attributes changes to the node they originate from, w/ a
+/// `QueryEvent::Origin` sent right before each change. Changes too old for
+/// the matcher to remember their origin are sent without one.
+#[derive(Clone)]
+pub struct OriginTags {
+    agent: Agent,
+    matcher: MatcherHandle,
+}
+
+impl OriginTags {
+    fn new(agent: &Agent, matcher: &MatcherHandle, origin: bool) -> Option<Self> {
+        origin.then(|| Self {
+            agent: agent.clone(),
+            matcher: matcher.clone(),
+        })
+    }
+
+    fn origin(&self, change_id: ChangeId) -> Option<ChangeOrigin> {
+        let site_id = self.matcher.change_origin(change_id)?;
+        Some(ChangeOrigin {
+            change_id,
+            site_id,
+            // unknown until the node's name is replicated here
+            node_name: self.agent.node_names().get(&site_id),
+        })
+    }
+
+    // appends the origin of the change `meta` is about, if it's known
+    fn put_origin(&self, buf: &mut BytesMut, meta: &QueryEventMeta) {
+        let QueryEventMeta::Change(change_id) = meta else {
+            return;
+        };
+        let Some(origin) = self.origin(*change_id) else {
+            return;
+        };
+
+        let mut writer = buf.writer();
+        serde_json::to_writer(&mut writer, &QueryEvent::Origin(origin))
+            .expect("could not serialize change origin");
+
+        // NOTE: I think that's infaillible...
+        writer
+            .write_all(b"\n")
+            .expect("could not write new line to BytesMut Writer");
+    }
+}
+
+// `=` as sqlite compares values, minus column affinity conversions
+fn sqlite_value_eq(a: &SqliteValue, b: &SqliteValue) -> bool {
+    match (a, b) {
+        (SqliteValue::Null, _) | (_, SqliteValue::Null) => false,
+        (SqliteValue::Integer(a), SqliteValue::Real(b))
+        | (SqliteValue::Real(b), SqliteValue::Integer(a)) => *a as f64 == b.0,
+        (a, b) => a == b,
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CatchUpError {
     #[error(transparent)]
@@ -299,6 +535,8 @@ pub enum CatchUpError {
     Send(#[from] mpsc::error::SendError<Bytes>),
     #[error(transparent)]
     SerdeJson(#[from] serde_json::Error),
+    #[error(transparent)]
+    ResumeGap(ResumeGap),
 }
 
 fn error_to_query_event_bytes<E: ToCompactString>(buf: &mut BytesMut, e: E) -> Bytes {
@@ -319,11 +557,14 @@ fn error_to_query_event_bytes<E: ToCompactString>(buf: &mut BytesMut, e: E) -> B
 fn catch_up_sub_anew(
     tx: &Transaction,
     matcher: MatcherHandle,
+    filter: Option<&ParamFilter>,
+    usage: &UsageCounters,
     buf: &mut BytesMut,
     evt_tx: &mpsc::Sender<Bytes>,
 ) -> Result<(), CatchUpError> {
+    let columns = matcher.columns();
     let mut query_cols = vec![];
-    for i in 0..(matcher.parsed_columns().len()) {
+    for i in 0..columns.count() {
         query_cols.push(format!("col_{i}"));
     }
     let mut prepped = tx.prepare_cached(&format!(
@@ -333,9 +574,7 @@ fn catch_up_sub_anew(
     ))?;
     let col_count = prepped.column_count();
 
-    evt_tx.blocking_send(
-        make_query_event_bytes(buf, QueryEvent::Columns(matcher.col_names().to_vec()))?.0,
-    )?;
+    evt_tx.blocking_send(make_query_event_bytes(buf, &columns.to_event())?.0)?;
 
     let start = Instant::now();
     let mut rows = prepped.query(())?;
@@ -352,13 +591,19 @@ fn catch_up_sub_anew(
             .map(|i| row.get::<_, SqliteValue>(i))
             .collect::<rusqlite::Result<Vec<_>>>()?;
 
-        evt_tx.blocking_send(make_query_event_bytes(buf, QueryEvent::Row(rowid, cells))?.0)?;
+        if filter.map_or(false, |filter| !filter.matches_cells(&cells)) {
+            continue;
+        }
+
+        let (bytes, _) = make_query_event_bytes(buf, &QueryEvent::Row(rowid, cells))?;
+        usage.row(bytes.len());
+        evt_tx.blocking_send(bytes)?;
     }
 
     evt_tx.blocking_send(
         make_query_event_bytes(
             buf,
-            QueryEvent::EndOfQuery {
+            &QueryEvent::EndOfQuery {
                 time: elapsed.as_secs_f64(),
                 change_id: Some(
                     tx.prepare(&format!(
@@ -367,6 +612,7 @@ fn catch_up_sub_anew(
                     ))?
                     .query_row([], |row| row.get(0))?,
                 ),
+                coerced: vec![],
             },
         )?
         .0,
@@ -379,11 +625,15 @@ fn catch_up_sub_from(
     tx: &Transaction, // read transaction
     matcher: MatcherHandle,
     from: ChangeId,
+    filter: Option<&ParamFilter>,
+    skip: Option<&SourceSkip>,
+    origins: Option<&OriginTags>,
+    usage: &UsageCounters,
     buf: &mut BytesMut,
     evt_tx: &mpsc::Sender<Bytes>,
 ) -> Result<(), CatchUpError> {
     let mut query_cols = vec![];
-    for i in 0..(matcher.parsed_columns().len()) {
+    for i in 0..matcher.columns().count() {
         query_cols.push(format!("col_{i}"));
     }
 
@@ -402,7 +652,7 @@ fn catch_up_sub_from(
             Some(row) => row,
             None => break,
         };
-        let id = row.get(0)?;
+        let id: ChangeId = row.get(0)?;
         let change_type = row.get(1)?;
         let rowid = row.get(2)?;
 
@@ -410,9 +660,23 @@ fn catch_up_sub_from(
             .map(|i| row.get::<_, SqliteValue>(i))
             .collect::<rusqlite::Result<Vec<_>>>()?;
 
-        evt_tx.blocking_send(
-            make_query_event_bytes(buf, QueryEvent::Change(change_type, rowid, cells, id))?.0,
-        )?;
+        if filter.map_or(false, |filter| !filter.matches_cells(&cells)) {
+            continue;
+        }
+
+        if skip.map_or(false, |skip| skip.skips(id)) {
+            put_skipped(buf, id);
+            evt_tx.blocking_send(buf.split().freeze())?;
+            continue;
+        }
+
+        if let Some(origins) = origins {
+            origins.put_origin(buf, &QueryEventMeta::Change(id));
+        }
+        let (bytes, _) =
+            make_query_event_bytes(buf, &QueryEvent::Change(change_type, rowid, cells, id))?;
+        usage.change(bytes.len());
+        evt_tx.blocking_send(bytes)?;
     }
 
     Ok(())
@@ -428,8 +692,12 @@ pub async fn catch_up_sub(
     agent: Agent,
     matcher: MatcherHandle,
     from: Option<ChangeId>,
-    sub_rx: broadcast::Receiver<(Bytes, QueryEventMeta)>,
+    sub_rx: broadcast::Receiver<SubEvent>,
     evt_tx: mpsc::Sender<Bytes>,
+    filter: Option<ParamFilter>,
+    skip: Option<SourceSkip>,
+    origins: Option<OriginTags>,
+    batch: bool,
 ) -> eyre::Result<()> {
     debug!("catching up sub {} from: {from:?}", matcher.id());
     let (ready_tx, ready_rx) = oneshot::channel();
@@ -438,10 +706,15 @@ pub async fn catch_up_sub(
         Some(ready_rx),
         sub_rx,
         evt_tx.clone(),
+        filter.clone(),
+        skip.clone(),
+        origins.clone(),
+        batch,
     ));
 
     let last_query_event = {
         let mut buf = BytesMut::new();
+        let usage = agent.usage().subscription(matcher.id());
 
         let mut conn = match agent.pool().dedicated() {
             Ok(conn) => conn,
@@ -454,25 +727,56 @@ pub async fn catch_up_sub(
         let res = block_in_place(|| {
             let tx = conn.transaction()?; // read transaction
             let last_query_event = match from {
-                Some(from) => {
+                Some(from)
+                    if matcher
+                        .rebound_at()
+                        .map_or(true, |rebound_at| from > rebound_at) =>
+                {
                     let max_change_id: ChangeId = tx
                         .prepare(&format!(
                             "SELECT COALESCE(MAX(id), 0) FROM {}",
                             matcher.changes_table_name()
                         ))?
                         .query_row([], |row| row.get(0))?;
-                    catch_up_sub_from(&tx, matcher, from, &mut buf, &evt_tx)?;
+                    // purged since the request was checked
+                    if let Some(gap) = matcher.resume_gap(&tx, from)? {
+                        return Err(CatchUpError::ResumeGap(gap));
+                    }
+                    catch_up_sub_from(
+                        &tx,
+                        matcher,
+                        from,
+                        filter.as_ref(),
+                        skip.as_ref(),
+                        origins.as_ref(),
+                        &usage,
+                        &mut buf,
+                        &evt_tx,
+                    )?;
                     debug!("sub caught up to their 'from' of {from:?}");
                     LastQueryEvent::Change(max_change_id)
                 }
-                None => {
+                _ => {
+                    if let (Some(from), Some(rebound_at)) = (from, matcher.rebound_at()) {
+                        // the subscriber's state is from a previous binding, start over
+                        debug!("sub's 'from' of {from:?} predates rebind at {rebound_at:?}");
+                        evt_tx.blocking_send(
+                            make_query_event_bytes(
+                                &mut buf,
+                                &QueryEvent::Rebound {
+                                    change_id: rebound_at,
+                                },
+                            )?
+                            .0,
+                        )?;
+                    }
                     let max_row_id: RowId = tx
                         .prepare(&format!(
                             "SELECT MAX(__corro_rowid) FROM {}",
                             matcher.table_name()
                         ))?
                         .query_row([], |row| row.get(0))?;
-                    catch_up_sub_anew(&tx, matcher, &mut buf, &evt_tx)?;
+                    catch_up_sub_anew(&tx, matcher, filter.as_ref(), &usage, &mut buf, &evt_tx)?;
                     debug!("sub caught up from scratch");
                     LastQueryEvent::Row(max_row_id)
                 }
@@ -490,6 +794,9 @@ pub async fn catch_up_sub(
                     CatchUpError::SerdeJson(e) => {
                         _ = evt_tx.send(error_to_query_event_bytes(&mut buf, e)).await;
                     }
+                    CatchUpError::ResumeGap(gap) => {
+                        _ = evt_tx.send(error_to_query_event_bytes(&mut buf, gap)).await;
+                    }
                     CatchUpError::Send(_) => {
                         // can't send
                     }
@@ -510,16 +817,215 @@ pub async fn catch_up_sub(
     Ok(())
 }
 
+/// Fails w/ a `ResumeGap` if changes after `from` were already purged, the
+/// subscriber has to start over instead of silently missing them
+async fn check_resume_gap(
+    agent: &Agent,
+    matcher: &MatcherHandle,
+    from: Option<ChangeId>,
+) -> Result<(), MatcherUpsertError> {
+    let Some(from) = from else {
+        return Ok(());
+    };
+    // subscribers from before a rebind get a fresh snapshot anyway
+    if matcher
+        .rebound_at()
+        .map_or(false, |rebound_at| from <= rebound_at)
+    {
+        return Ok(());
+    }
+
+    // subscriptions are only attached to dedicated conns
+    let gap = block_in_place(|| {
+        let conn = agent.pool().dedicated()?;
+        matcher.resume_gap(&conn, from)
+    })?;
+    match gap {
+        Some(gap) => {
+            increment_counter!("corro.subs.resume_gaps");
+            Err(MatcherUpsertError::ResumeGap(gap))
+        }
+        None => Ok(()),
+    }
+}
+
+// local-only tables never get changes, a subscription to them would be stale
+fn check_local_only(agent: &Agent, sql: &str) -> Result<(), MatcherUpsertError> {
+    let config = agent.config();
+    match select_tables(sql)?
+        .into_iter()
+        .find(|table| config.db.is_local_only(table))
+    {
+        Some(table) => Err(MatcherUpsertError::LocalOnlyTable(table)),
+        None => Ok(()),
+    }
+}
+
+fn check_query_plan(agent: &Agent, conn: &Connection, sql: &str) -> Result<(), MatcherUpsertError> {
+    let config = agent.config().api.query_plan;
+    if config.subscription_scans == ScanPolicy::Ignore {
+        return Ok(());
+    }
+
+    let plan = block_in_place(|| explain_query_plan(conn, sql, config.large_table_rows))?;
+    if plan.large_scans.is_empty() {
+        return Ok(());
+    }
+
+    if config.subscription_scans == ScanPolicy::Reject {
+        return Err(MatcherUpsertError::LargeTableScan(plan.large_scans));
+    }
+
+    warn!(
+        "subscription query fully scans large table(s) {}: {sql}",
+        plan.large_scans.join(", ")
+    );
+    Ok(())
+}
+
 pub async fn upsert_sub(
     agent: &Agent,
     cache: &SharedMatcherIdCache,
     bcast_cache: &SharedMatcherBroadcastCache,
     stmt: Statement,
     from: Option<ChangeId>,
+    skip_source_id: Option<Uuid>,
+    origin: bool,
+    batch: bool,
     tx: mpsc::Sender<Bytes>,
 ) -> Result<Uuid, MatcherUpsertError> {
+    // registered queries are resolved once, later updates to their template
+    // don't affect this subscription
+    let (stmt, _) = agent.query_registry().resolve(stmt)?;
     let stmt = expand_sql(agent, &stmt).await?;
+    upsert_matcher(
+        agent,
+        cache,
+        bcast_cache,
+        stmt,
+        None,
+        from,
+        skip_source_id,
+        origin,
+        batch,
+        tx,
+    )
+    .await
+}
 
+/// Subscribes to `stmt` through its shared plan, when it has one: all param
+/// sets of the query share a single matcher, and only get the rows matching
+/// their own params. Otherwise falls back to a matcher for `stmt`'s params.
+///
+/// Returns the matcher's id and whether it's shared.
+pub async fn upsert_shared_sub(
+    agent: &Agent,
+    cache: &SharedMatcherIdCache,
+    bcast_cache: &SharedMatcherBroadcastCache,
+    stmt: Statement,
+    from: Option<ChangeId>,
+    skip_source_id: Option<Uuid>,
+    origin: bool,
+    batch: bool,
+    tx: mpsc::Sender<Bytes>,
+) -> Result<(Uuid, bool), MatcherUpsertError> {
+    let (stmt, _) = agent.query_registry().resolve(stmt)?;
+    match shared_sub_plan(agent, &stmt).await? {
+        Some((sql, filter)) => upsert_matcher(
+            agent,
+            cache,
+            bcast_cache,
+            sql,
+            Some(filter),
+            from,
+            skip_source_id,
+            origin,
+            batch,
+            tx,
+        )
+        .await
+        .map(|id| (id, true)),
+        None => {
+            increment_counter!("corro.subs.shared.rejected");
+            upsert_sub(
+                agent,
+                cache,
+                bcast_cache,
+                stmt,
+                from,
+                skip_source_id,
+                origin,
+                batch,
+                tx,
+            )
+            .await
+            .map(|id| (id, false))
+        }
+    }
+}
+
+// the expanded shared query and the filter for `stmt`'s params
+async fn shared_sub_plan(
+    agent: &Agent,
+    stmt: &Statement,
+) -> Result<Option<(String, ParamFilter)>, MatcherUpsertError> {
+    let (query, params) = match stmt {
+        Statement::WithParams(query, params)
+        | Statement::Verbose {
+            query,
+            params: Some(params),
+            named_params: None,
+            ..
+        } => (query, params),
+        _ => return Ok(None),
+    };
+
+    let plan = match shared_plan(&agent.schema().read(), query)? {
+        Some(plan) if plan.param_columns.len() == params.len() => plan,
+        _ => return Ok(None),
+    };
+
+    let conn = agent.pool().read().await?;
+
+    let sql = {
+        let prepped = conn.prepare(&plan.sql)?;
+        // params outside of the shared terms
+        if prepped.parameter_count() > 0 {
+            return Ok(None);
+        }
+        prepped
+            .expanded_sql()
+            .ok_or(MatcherUpsertError::CouldNotExpand)?
+    };
+
+    // bound params are compared to cells, have sqlite convert them the same way
+    let mut prepped = conn.prepare("SELECT ?")?;
+    let mut values = Vec::with_capacity(params.len());
+    for (i, param) in plan.param_columns.into_iter().zip(params) {
+        prepped.raw_bind_parameter(1, param)?;
+        let mut rows = prepped.raw_query();
+        let value = match rows.next()? {
+            Some(row) => row.get::<_, SqliteValue>(0)?,
+            None => SqliteValue::Null,
+        };
+        values.push((i, value));
+    }
+
+    Ok(Some((sql, ParamFilter(Arc::new(values)))))
+}
+
+async fn upsert_matcher(
+    agent: &Agent,
+    cache: &SharedMatcherIdCache,
+    bcast_cache: &SharedMatcherBroadcastCache,
+    stmt: String,
+    filter: Option<ParamFilter>,
+    from: Option<ChangeId>,
+    skip_source_id: Option<Uuid>,
+    origin: bool,
+    batch: bool,
+    tx: mpsc::Sender<Bytes>,
+) -> Result<Uuid, MatcherUpsertError> {
     let mut cache_write = cache.write().await;
     let mut bcast_write = bcast_cache.write().await;
 
@@ -534,8 +1040,27 @@ pub async fn upsert_sub(
             .flatten();
         if let Some(matcher) = maybe_matcher {
             debug!("found matcher handle");
+            if filter.is_some() {
+                increment_counter!("corro.subs.shared.hits");
+            }
+            if let Err(e) = matcher.touch() {
+                warn!(id = %matcher_id, "could not resume subscription: {e}");
+            }
+            check_resume_gap(agent, &matcher, from).await?;
             let rx = sender.subscribe();
-            tokio::spawn(catch_up_sub(agent.clone(), matcher, from, rx, tx));
+            let skip = SourceSkip::new(&matcher, skip_source_id);
+            let origins = OriginTags::new(agent, &matcher, origin);
+            tokio::spawn(catch_up_sub(
+                agent.clone(),
+                matcher,
+                from,
+                rx,
+                tx,
+                filter,
+                skip,
+                origins,
+                batch,
+            ));
             return Ok(matcher_id);
         } else {
             cache_write.remove(&stmt);
@@ -547,24 +1072,44 @@ pub async fn upsert_sub(
         return Err(MatcherUpsertError::SubFromWithoutMatcher);
     }
 
+    check_local_only(agent, &stmt)?;
+
     let conn = agent.pool().dedicated()?;
 
+    check_query_plan(agent, &conn, &stmt)?;
+
     let (evt_tx, evt_rx) = mpsc::channel(512);
 
     let matcher_id = Uuid::new_v4();
 
-    let matcher = Matcher::create(matcher_id, &agent.schema().read(), conn, evt_tx, &stmt)?;
+    let matcher = Matcher::create(
+        matcher_id,
+        &agent.schema().read(),
+        conn,
+        evt_tx,
+        &stmt,
+        agent.config().db.subscription_changes,
+    )?;
+
+    if filter.is_some() {
+        increment_counter!("corro.subs.shared.misses");
+    }
 
     let (sub_tx, sub_rx) = broadcast::channel(10240);
 
     cache_write.insert(stmt, matcher_id);
     bcast_write.insert(matcher_id, sub_tx.clone());
 
+    let skip = SourceSkip::new(&matcher, skip_source_id);
+    let origins = OriginTags::new(agent, &matcher, origin);
+
     {
         agent.matchers().write().insert(matcher_id, matcher);
     }
 
-    tokio::spawn(forward_sub_to_sender(None, sub_rx, tx));
+    tokio::spawn(forward_sub_to_sender(
+        None, sub_rx, tx, filter, skip, origins, batch,
+    ));
 
     tokio::spawn(process_sub_channel(
         agent.clone(),
@@ -581,40 +1126,152 @@ pub async fn api_v1_subs(
     Extension(sub_cache): Extension<SharedMatcherIdCache>,
     Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
     axum::extract::Query(params): axum::extract::Query<SubParams>,
+    headers: HeaderMap,
     axum::extract::Json(stmt): axum::extract::Json<Statement>,
 ) -> impl IntoResponse {
+    let handshake = Handshake::new(&headers, SUBSCRIPTION_CAPABILITIES);
+    let (origin, batch) = (params.origin(&handshake), params.batch(&handshake));
     let (tx, body) = hyper::Body::channel();
     let (forward_tx, forward_rx) = mpsc::channel(10240);
 
-    let matcher_id = match upsert_sub(
-        &agent,
-        &sub_cache,
-        &bcast_cache,
-        stmt,
-        params.from,
-        forward_tx,
-    )
-    .await
-    {
-        Ok(id) => id,
+    let res = if params.shared {
+        upsert_shared_sub(
+            &agent,
+            &sub_cache,
+            &bcast_cache,
+            stmt,
+            params.from,
+            params.skip_source_id,
+            origin,
+            batch,
+            forward_tx,
+        )
+        .await
+    } else {
+        upsert_sub(
+            &agent,
+            &sub_cache,
+            &bcast_cache,
+            stmt,
+            params.from,
+            params.skip_source_id,
+            origin,
+            batch,
+            forward_tx,
+        )
+        .await
+        .map(|id| (id, false))
+    };
+
+    let (matcher_id, shared) = match res {
+        Ok(res) => res,
         Err(e) => return hyper::Response::<hyper::Body>::from(e),
     };
 
-    tokio::spawn(forward_bytes_to_body_sender(forward_rx, tx));
+    tokio::spawn(forward_bytes_to_body_sender(
+        handshake.hello(Some(matcher_id)),
+        forward_rx,
+        tx,
+        agent.tripwire().clone(),
+    ));
 
-    hyper::Response::builder()
+    let mut builder = handshake
+        .headers(hyper::Response::builder())
         .status(StatusCode::OK)
-        .header("corro-query-id", matcher_id.to_string())
+        .header("corro-query-id", matcher_id.to_string());
+    if shared {
+        builder = builder.header("corro-query-shared", "true");
+    }
+
+    builder
         .body(body)
         .expect("could not generate ok http response for query request")
 }
 
+/// Re-prepares every subscription against the current schema after it
+/// changed, see `MatcherHandle::reshape`
+pub async fn reshape_subs(agent: &Agent) {
+    let schema = Arc::new(agent.schema().read().clone());
+    let matchers: Vec<MatcherHandle> = agent.matchers().read().values().cloned().collect();
+
+    for matcher in matchers {
+        match matcher.reshape(schema.clone()).await {
+            Ok(Some(change_id)) => {
+                info!(id = %matcher.id(), "subscription columns changed w/ the schema, snapshot refreshed at change {change_id}")
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(id = %matcher.id(), "could not reshape subscription after schema change: {e}")
+            }
+        }
+    }
+}
+
+/// Rebinds an existing subscription to `stmt`, which must only differ from
+/// the subscription's query by its params. Subscribers keep their stream and
+/// receive a `QueryEvent::Rebound` marker followed by a fresh snapshot.
+pub async fn rebind_sub(
+    agent: &Agent,
+    cache: &SharedMatcherIdCache,
+    id: Uuid,
+    stmt: Statement,
+) -> Result<ChangeId, MatcherUpsertError> {
+    let matcher = agent
+        .matchers()
+        .read()
+        .get(&id)
+        .cloned()
+        .ok_or(MatcherUpsertError::SubNotFound(id))?;
+
+    let (stmt, _) = agent.query_registry().resolve(stmt)?;
+    let sql = expand_sql(agent, &stmt).await?;
+    check_local_only(agent, &sql)?;
+
+    let rebind = {
+        let conn = agent.pool().read().await?;
+        check_query_plan(agent, &conn, &sql)?;
+        let schema = agent.schema().read();
+        block_in_place(|| matcher.prepare_rebind(&schema, &conn, &sql))?
+    };
+
+    let change_id = matcher.rebind(rebind).await?;
+
+    let mut cache_write = cache.write().await;
+    cache_write.retain(|_, matcher_id| *matcher_id != id);
+    cache_write.entry(sql).or_insert(id);
+
+    Ok(change_id)
+}
+
+pub async fn api_v1_sub_rebind(
+    Extension(agent): Extension<Agent>,
+    Extension(sub_cache): Extension<SharedMatcherIdCache>,
+    axum::extract::Path(id): axum::extract::Path<Uuid>,
+    axum::extract::Json(stmt): axum::extract::Json<Statement>,
+) -> impl IntoResponse {
+    match rebind_sub(&agent, &sub_cache, id, stmt).await {
+        Ok(change_id) => hyper::Response::builder()
+            .status(StatusCode::OK)
+            .body(
+                serde_json::to_vec(&QueryEvent::Rebound { change_id })
+                    .expect("could not serialize rebound event")
+                    .into(),
+            )
+            .expect("could not build rebind response"),
+        Err(e) => hyper::Response::<hyper::Body>::from(e),
+    }
+}
+
 const MAX_EVENTS_BUFFER_SIZE: usize = 1024;
 
 async fn forward_sub_to_sender(
     ready: Option<oneshot::Receiver<LastQueryEvent>>,
-    mut sub_rx: broadcast::Receiver<(Bytes, QueryEventMeta)>,
+    mut sub_rx: broadcast::Receiver<SubEvent>,
     tx: mpsc::Sender<Bytes>,
+    filter: Option<ParamFilter>,
+    skip: Option<SourceSkip>,
+    origins: Option<OriginTags>,
+    batch: bool,
 ) {
     let mut buf = BytesMut::new();
     if let Some(mut ready) = ready {
@@ -653,7 +1310,14 @@ async fn forward_sub_to_sender(
         let mut skipped = 0;
         let mut sent = 0;
 
-        for (bytes, meta) in events_buf {
+        for (bytes, meta, evt) in events_buf {
+            if filter
+                .as_ref()
+                .map_or(false, |filter| !filter.matches(&evt))
+            {
+                skipped += 1;
+                continue;
+            }
             // prevent sending duplicate query events by comparing the last sent event
             // w/ buffered events
             match (meta, last_query_event) {
@@ -681,6 +1345,20 @@ async fn forward_sub_to_sender(
                     // nothing to do.
                 }
             }
+            let bytes = match skip.as_ref().and_then(|skip| skip.skipped(&meta)) {
+                Some(change_id) => {
+                    put_skipped(&mut buf, change_id);
+                    buf.split().freeze()
+                }
+                None => match origins.as_ref() {
+                    Some(origins) => {
+                        origins.put_origin(&mut buf, &meta);
+                        buf.extend_from_slice(&bytes);
+                        buf.split().freeze()
+                    }
+                    None => bytes,
+                },
+            };
             if let Err(_e) = tx.send(bytes).await {
                 warn!("could not send buffered events to subscriber, receiver must be gone!");
                 return;
@@ -691,8 +1369,14 @@ async fn forward_sub_to_sender(
         debug!("sent {sent} buffered events, skipped: {skipped}");
     }
 
-    let chunker = tokio_stream::wrappers::BroadcastStream::new(sub_rx)
-        .chunks_timeout(10, Duration::from_millis(10));
+    // a chunk's first event waits at most for the timeout
+    let (max_len, max_delay) = if batch {
+        (CHANGE_BATCH_MAX_LEN, CHANGE_BATCH_MAX_DELAY)
+    } else {
+        (10, Duration::from_millis(10))
+    };
+    let chunker =
+        tokio_stream::wrappers::BroadcastStream::new(sub_rx).chunks_timeout(max_len, max_delay);
 
     tokio::pin!(chunker);
 
@@ -705,10 +1389,37 @@ async fn forward_sub_to_sender(
             }
             match ready!(chunker.as_mut().poll_next(cx)) {
                 Some(chunks) => {
+                    let mut changes = vec![];
                     for chunk_res in chunks {
-                        let (chunk, _) = chunk_res?;
-                        buf.extend_from_slice(&chunk);
+                        let (chunk, meta, evt) = chunk_res?;
+                        if filter
+                            .as_ref()
+                            .map_or(false, |filter| !filter.matches(&evt))
+                        {
+                            continue;
+                        }
+                        match skip.as_ref().and_then(|skip| skip.skipped(&meta)) {
+                            Some(change_id) => {
+                                put_change_batch(&mut buf, &mut changes);
+                                put_skipped(&mut buf, change_id)
+                            }
+                            // origins precede the batch their change is part of
+                            None if batch && matches!(meta, QueryEventMeta::Change(_)) => {
+                                if let Some(origins) = origins.as_ref() {
+                                    origins.put_origin(&mut buf, &meta);
+                                }
+                                changes.push((chunk, evt))
+                            }
+                            None => {
+                                put_change_batch(&mut buf, &mut changes);
+                                if let Some(origins) = origins.as_ref() {
+                                    origins.put_origin(&mut buf, &meta);
+                                }
+                                buf.extend_from_slice(&chunk)
+                            }
+                        }
                     }
+                    put_change_batch(&mut buf, &mut changes);
                     Poll::Ready(Ok(Some(buf.split().freeze())))
                 }
                 None => Poll::Ready(Ok(None)),
@@ -717,6 +1428,8 @@ async fn forward_sub_to_sender(
         .await;
 
         match res {
+            // everything was filtered out, keep the reserved slot for later
+            Ok(Some(b)) if b.is_empty() => continue,
             Ok(Some(b)) => {
                 if let Err(_e) = tx.send_item(b) {
                     error!("could not forward subscription query event to receiver, channel is closed!");
@@ -740,14 +1453,76 @@ async fn forward_sub_to_sender(
     }
 }
 
-async fn forward_bytes_to_body_sender(mut rx: mpsc::Receiver<Bytes>, mut tx: hyper::body::Sender) {
+/// Most changes sent in a single `QueryEvent::ChangeBatch`
+const CHANGE_BATCH_MAX_LEN: usize = 512;
+/// Longest a change waits for others to be batched w/
+const CHANGE_BATCH_MAX_DELAY: Duration = Duration::from_millis(5);
+
+// appends `changes` as a single batch event, or as is for a lone change
+fn put_change_batch(buf: &mut BytesMut, changes: &mut Vec<(Bytes, Arc<QueryEvent>)>) {
+    if changes.len() <= 1 {
+        if let Some((bytes, _)) = changes.pop() {
+            buf.extend_from_slice(&bytes);
+        }
+        return;
+    }
+
+    // serialized like a `QueryEvent::ChangeBatch`, w/o cloning the cells
+    let mut writer = buf.writer();
+    writer
+        .write_all(br#"{"change_batch":["#)
+        .expect("could not write to BytesMut Writer");
+    let mut first = true;
+    for (_, evt) in changes.drain(..) {
+        if let QueryEvent::Change(change_type, rowid, cells, change_id) = evt.as_ref() {
+            if !first {
+                writer
+                    .write_all(b",")
+                    .expect("could not write to BytesMut Writer");
+            }
+            first = false;
+            serde_json::to_writer(&mut writer, &(change_type, rowid, cells, change_id))
+                .expect("could not serialize change");
+        }
+    }
+    writer
+        .write_all(b"]}\n")
+        .expect("could not write to BytesMut Writer");
+}
+
+/// Streams a subscription's events, after the `hello` if there's one, until
+/// its subscriber goes away, or the agent shuts down: the body then ends w/ a
+/// `SHUTTING_DOWN` error, so the subscriber can tell it apart from a
+/// connection reset and resume elsewhere.
+async fn forward_bytes_to_body_sender(
+    hello: Option<Bytes>,
+    mut rx: mpsc::Receiver<Bytes>,
+    mut tx: hyper::body::Sender,
+    mut tripwire: Tripwire,
+) {
+    if let Some(hello) = hello {
+        if let Err(e) = tx.send_data(hello).await {
+            debug!("could not send hello event through body: {e}");
+            return;
+        }
+    }
+
     loop {
-        let res = {
-            poll_fn(|cx| {
+        let res = tokio::select! {
+            biased;
+            _ = &mut tripwire => {
+                debug!("shutting down, ending subscription body");
+                let shutting_down =
+                    error_to_query_event_bytes(&mut BytesMut::new(), SHUTTING_DOWN);
+                if let Err(e) = tx.send_data(shutting_down).await {
+                    debug!("could not send shutdown event through body: {e}");
+                }
+                break;
+            }
+            res = poll_fn(|cx| {
                 ready!(tx.poll_ready(cx))?;
                 Poll::Ready(Ok::<_, hyper::Error>(ready!(rx.poll_recv(cx))))
-            })
-            .await
+            }) => res,
         };
         match res {
             Ok(Some(b)) => {
@@ -770,12 +1545,18 @@ async fn forward_bytes_to_body_sender(mut rx: mpsc::Receiver<Bytes>, mut tx: hyp
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use corro_types::{
-        api::{ChangeId, RowId},
-        config::Config,
+        api::{
+            ChangeId, ExecRequest, RegisteredQuery, RowId, CAPABILITIES_HEADER, PROTOCOL_VERSION,
+            PROTOCOL_VERSION_HEADER,
+        },
+        config::{Config, SubscriptionChangesConfig},
         pubsub::ChangeType,
     };
     use http_body::Body;
+    use hyper::HeaderMap;
     use tokio_util::codec::{Decoder, LinesCodec};
     use tripwire::Tripwire;
 
@@ -814,16 +1595,20 @@ mod tests {
 
         let (status_code, body) = api_v1_transactions(
             Extension(agent.clone()),
-            axum::Json(vec![
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id".into(), "service-name".into()],
-                ),
-                Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-2".into(), "service-name-2".into()],
-                ),
-            ]),
+            HeaderMap::new(),
+            axum::Json(
+                vec![
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id".into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-2".into(), "service-name-2".into()],
+                    ),
+                ]
+                .into(),
+            ),
         )
         .await;
 
@@ -840,6 +1625,7 @@ mod tests {
                 Extension(cache.clone()),
                 Extension(bcast_cache.clone()),
                 axum::extract::Query(SubParams::default()),
+                HeaderMap::new(),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
             .await
@@ -854,10 +1640,14 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-3".into(), "service-name-3".into()],
-                )]),
+                HeaderMap::new(),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-3".into(), "service-name-3".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -872,7 +1662,7 @@ mod tests {
 
             assert_eq!(
                 rows.recv().await.unwrap().unwrap(),
-                QueryEvent::Columns(vec!["id".into(), "text".into()])
+                QueryEvent::columns(vec!["id".into(), "text".into()])
             );
 
             assert_eq!(
@@ -905,10 +1695,14 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-4".into(), "service-name-4".into()],
-                )]),
+                HeaderMap::new(),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-4".into(), "service-name-4".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -930,7 +1724,9 @@ mod tests {
                 Extension(bcast_cache.clone()),
                 axum::extract::Query(SubParams {
                     from: Some(1.into()),
+                    ..Default::default()
                 }),
+                HeaderMap::new(),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
             .await
@@ -962,10 +1758,14 @@ mod tests {
 
             let (status_code, _) = api_v1_transactions(
                 Extension(agent.clone()),
-                axum::Json(vec![Statement::WithParams(
-                    "insert into tests (id, text) values (?,?)".into(),
-                    vec!["service-id-5".into(), "service-name-5".into()],
-                )]),
+                HeaderMap::new(),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec!["service-id-5".into(), "service-name-5".into()],
+                    )]
+                    .into(),
+                ),
             )
             .await;
 
@@ -989,6 +1789,7 @@ mod tests {
                 Extension(cache.clone()),
                 Extension(bcast_cache.clone()),
                 axum::extract::Query(SubParams::default()),
+                HeaderMap::new(),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
             .await
@@ -1010,7 +1811,7 @@ mod tests {
 
             assert_eq!(
                 rows.recv().await.unwrap().unwrap(),
-                QueryEvent::Columns(vec!["id".into(), "text".into()])
+                QueryEvent::columns(vec!["id".into(), "text".into()])
             );
 
             assert_eq!(
@@ -1059,7 +1860,9 @@ mod tests {
             Extension(bcast_cache.clone()),
             axum::extract::Query(SubParams {
                 from: Some(1.into()),
+                ..Default::default()
             }),
+            HeaderMap::new(),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
         .await
@@ -1102,6 +1905,1468 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_large_table_scan() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let mut config = Config::builder()
+            .db_path(dir.path().join("corrosion.db").display().to_string())
+            .gossip_addr("127.0.0.1:0".parse()?)
+            .api_addr("127.0.0.1:0".parse()?)
+            .build()?;
+        config.api.query_plan.large_table_rows = 1;
+        config.api.query_plan.subscription_scans = ScanPolicy::Reject;
+
+        let (agent, _agent_options) = setup(config, tripwire).await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::Simple(
+                    "insert into tests (id, text) values (1, 'a')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let subscribe = |sql: &str| {
+            api_v1_subs(
+                Extension(agent.clone()),
+                Extension(cache.clone()),
+                Extension(bcast_cache.clone()),
+                axum::extract::Query(SubParams::default()),
+                HeaderMap::new(),
+                axum::Json(Statement::Simple(sql.into())),
+            )
+        };
+
+        let res = subscribe("select * from tests where text = 'a'")
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(cache.read().await.is_empty());
+
+        let res = subscribe("select * from tests where id = 1")
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_sub_rebind() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |sql: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(vec![Statement::Simple(sql.into())].into()),
+            )
+        };
+
+        let (status_code, _) =
+            insert("insert into tests (id, text) values (1, 'a'), (2, 'b')").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let query = "select * from tests where text = ?";
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(cache.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Query(SubParams::default()),
+            HeaderMap::new(),
+            axum::Json(Statement::WithParams(query.into(), vec!["a".into()])),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap();
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::columns(vec!["id".into(), "text".into()])
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(1), vec![SqliteValue::Integer(1), "a".into()])
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let (status_code, _) = insert("insert into tests (id, text) values (3, 'a')").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(2),
+                vec![SqliteValue::Integer(3), "a".into()],
+                ChangeId(1)
+            )
+        );
+
+        let rebind = |id: Uuid, stmt: Statement| {
+            api_v1_sub_rebind(
+                Extension(agent.clone()),
+                Extension(cache.clone()),
+                axum::extract::Path(id),
+                axum::Json(stmt),
+            )
+        };
+
+        // must select the same columns
+        let res = rebind(
+            id,
+            Statement::WithParams(
+                "select id from tests where text = ?".into(),
+                vec!["b".into()],
+            ),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = rebind(
+            Uuid::new_v4(),
+            Statement::WithParams(query.into(), vec!["b".into()]),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = rebind(id, Statement::WithParams(query.into(), vec!["b".into()]))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(
+            serde_json::from_slice::<QueryEvent>(&body)?,
+            QueryEvent::Rebound {
+                change_id: ChangeId(1)
+            }
+        );
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Rebound {
+                change_id: ChangeId(1)
+            }
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::columns(vec!["id".into(), "text".into()])
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(3), vec![SqliteValue::Integer(2), "b".into()])
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery {
+                change_id: Some(ChangeId(1)),
+                ..
+            }
+        ));
+
+        // the old binding doesn't match anymore, only the new one does
+        let (status_code, _) = insert("insert into tests (id, text) values (4, 'a')").await;
+        assert_eq!(status_code, StatusCode::OK);
+        let (status_code, _) = insert("insert into tests (id, text) values (5, 'b')").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(4),
+                vec![SqliteValue::Integer(5), "b".into()],
+                ChangeId(2)
+            )
+        );
+
+        // the rebound query is now the one identifying the subscription
+        assert_eq!(
+            cache.read().await.values().copied().collect::<Vec<_>>(),
+            vec![id]
+        );
+        assert_eq!(
+            agent.matchers().read().get(&id).unwrap().rebound_at(),
+            Some(ChangeId(1))
+        );
+
+        // catching up from before the rebind starts over w/ the new binding
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+            axum::extract::Query(SubParams {
+                from: Some(ChangeId(1)),
+                ..Default::default()
+            }),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows_from = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows_from.recv().await.unwrap().unwrap(),
+            QueryEvent::Rebound {
+                change_id: ChangeId(1)
+            }
+        );
+        assert_eq!(
+            rows_from.recv().await.unwrap().unwrap(),
+            QueryEvent::columns(vec!["id".into(), "text".into()])
+        );
+        assert_eq!(
+            rows_from.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(3), vec![SqliteValue::Integer(2), "b".into()])
+        );
+        assert_eq!(
+            rows_from.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(4), vec![SqliteValue::Integer(5), "b".into()])
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_reshape() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |sql: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(vec![Statement::Simple(sql.into())].into()),
+            )
+        };
+
+        let (status_code, _) = insert("insert into tests (id, text) values (1, 'a')").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(cache.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Query(SubParams::default()),
+            HeaderMap::new(),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap();
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::columns(vec!["id".into(), "text".into()])
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(1), vec![SqliteValue::Integer(1), "a".into()])
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let (status_code, _) = insert("insert into tests (id, text) values (2, 'b')").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(2),
+                vec![SqliteValue::Integer(2), "b".into()],
+                ChangeId(1)
+            )
+        );
+
+        // `*` now expands to one more column
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![r#"
+                CREATE TABLE tests (
+                    id INTEGER NOT NULL PRIMARY KEY,
+                    text TEXT NOT NULL DEFAULT "",
+                    num INTEGER NOT NULL DEFAULT 0
+                ) WITHOUT ROWID;
+            "#
+            .into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let reshaped = QueryEvent::Columns {
+            names: vec!["id".into(), "text".into(), "num".into()],
+            schema_generation: 1,
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Rebound {
+                change_id: ChangeId(1)
+            }
+        );
+        assert_eq!(rows.recv().await.unwrap().unwrap(), reshaped);
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(
+                RowId(3),
+                vec![SqliteValue::Integer(1), "a".into(), SqliteValue::Integer(0)]
+            )
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(
+                RowId(4),
+                vec![SqliteValue::Integer(2), "b".into(), SqliteValue::Integer(0)]
+            )
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery {
+                change_id: Some(ChangeId(1)),
+                ..
+            }
+        ));
+
+        // changes have the new column as well
+        let (status_code, _) = insert("insert into tests (id, text, num) values (3, 'c', 7)").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(5),
+                vec![SqliteValue::Integer(3), "c".into(), SqliteValue::Integer(7)],
+                ChangeId(2)
+            )
+        );
+
+        // re-applying the same schema doesn't touch the subscription
+        let matcher = agent.matchers().read().get(&id).cloned().unwrap();
+        assert_eq!(
+            matcher
+                .reshape(Arc::new(agent.schema().read().clone()))
+                .await?,
+            None
+        );
+
+        // new subscribers get the current columns
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+            axum::extract::Query(SubParams::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows_anew = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(rows_anew.recv().await.unwrap().unwrap(), reshaped);
+        for _ in 0..3 {
+            match rows_anew.recv().await.unwrap().unwrap() {
+                QueryEvent::Row(_, cells) => assert_eq!(cells.len(), 3),
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_without_rowid() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![r#"
+                CREATE TABLE services (
+                    node TEXT NOT NULL,
+                    id TEXT NOT NULL,
+                    name TEXT NOT NULL DEFAULT '',
+                    PRIMARY KEY (node, id)
+                ) WITHOUT ROWID;
+            "#
+            .into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let exec = |sql: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(vec![Statement::Simple(sql.into())].into()),
+            )
+        };
+
+        let (status_code, _) = exec(
+            "INSERT INTO services (node, id, name) VALUES ('n1', 's1', 'a'), ('n1', 's2', 'b')",
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(SharedMatcherIdCache::default()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Query(SubParams::default()),
+            HeaderMap::new(),
+            axum::Json(Statement::Simple(
+                "SELECT node, id, name FROM services".into(),
+            )),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap();
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        let row = |node: &str, id: &str, name: &str| -> Vec<SqliteValue> {
+            vec![node.into(), id.into(), name.into()]
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::columns(vec!["node".into(), "id".into(), "name".into()])
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(1), row("n1", "s1", "a"))
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(2), row("n1", "s2", "b"))
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // every event about a primary key refers to the row id it got in the
+        // snapshot, a key sharing one of its values is another row
+        let steps = [
+            (
+                "UPDATE services SET name = 'c' WHERE node = 'n1' AND id = 's1'",
+                ChangeType::Update,
+                RowId(1),
+                row("n1", "s1", "c"),
+            ),
+            (
+                "INSERT INTO services (node, id, name) VALUES ('n2', 's1', 'd')",
+                ChangeType::Insert,
+                RowId(3),
+                row("n2", "s1", "d"),
+            ),
+            (
+                "DELETE FROM services WHERE node = 'n1' AND id = 's1'",
+                ChangeType::Delete,
+                RowId(1),
+                row("n1", "s1", "c"),
+            ),
+            (
+                "UPDATE services SET name = 'e' WHERE node = 'n2' AND id = 's1'",
+                ChangeType::Update,
+                RowId(3),
+                row("n2", "s1", "e"),
+            ),
+        ];
+        for (i, (sql, change_type, rowid, cells)) in steps.into_iter().enumerate() {
+            let (status_code, _) = exec(sql).await;
+            assert_eq!(status_code, StatusCode::OK);
+            assert_eq!(
+                rows.recv().await.unwrap().unwrap(),
+                QueryEvent::Change(change_type, rowid, cells, ChangeId(i as i64 + 1)),
+                "{sql}"
+            );
+        }
+
+        // later subscribers get the same row ids in their snapshot
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+            axum::extract::Query(SubParams::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Columns { .. }
+        ));
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(2), row("n1", "s2", "b"))
+        );
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Row(RowId(3), row("n2", "s1", "e"))
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery {
+                change_id: Some(ChangeId(4)),
+                ..
+            }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_registered() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        async fn register(agent: &Agent, query: &str) -> eyre::Result<bool> {
+            let conn = agent.pool().read().await?;
+            Ok(block_in_place(|| {
+                agent.query_registry().register(
+                    &conn,
+                    "test_by_id".into(),
+                    RegisteredQuery::new(query),
+                )
+            })?)
+        }
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let subscribe = |name: &str| {
+            api_v1_subs(
+                Extension(agent.clone()),
+                Extension(cache.clone()),
+                Extension(bcast_cache.clone()),
+                axum::extract::Query(SubParams::default()),
+                HeaderMap::new(),
+                axum::Json(Statement::Registered {
+                    name: name.into(),
+                    params: vec![1i64.into()],
+                }),
+            )
+        };
+
+        let res = subscribe("test_by_id").await.into_response();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        assert!(!register(&agent, "select id, text from tests where id = ?").await?);
+
+        let res = subscribe("test_by_id").await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let old_id = res.headers().get("corro-query-id").cloned().unwrap();
+
+        // subscribing by name again reuses the matcher
+        let res = subscribe("test_by_id").await.into_response();
+        assert_eq!(res.headers().get("corro-query-id"), Some(&old_id));
+
+        assert!(register(&agent, "select text from tests where id = ?").await?);
+
+        // the existing subscription keeps the old text until resubscribed
+        let old_id: Uuid = old_id.to_str()?.parse()?;
+        assert!(agent.matchers().read().contains_key(&old_id));
+        assert_eq!(
+            cache
+                .read()
+                .await
+                .get("select id, text from tests where id = 1"),
+            Some(&old_id)
+        );
+
+        let res = subscribe("test_by_id").await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let new_id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .unwrap()
+            .to_str()?
+            .parse()?;
+        assert_ne!(new_id, old_id);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_shared() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec!["CREATE TABLE services (node TEXT NOT NULL, id TEXT NOT NULL, name TEXT NOT NULL DEFAULT '', PRIMARY KEY (node, id));".into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |sql: String| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(vec![Statement::Simple(sql)].into()),
+            )
+        };
+
+        let values = (0..10)
+            .map(|i| format!("('node-{i}', 'web', 'web-{i}'), ('node-{i}', 'db', 'db-{i}')"))
+            .collect::<Vec<_>>();
+        let (status_code, _) = insert(format!(
+            "INSERT INTO services (node, id, name) VALUES {}",
+            values.join(",")
+        ))
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let subscribe = |query: &str, param: String| {
+            api_v1_subs(
+                Extension(agent.clone()),
+                Extension(cache.clone()),
+                Extension(bcast_cache.clone()),
+                axum::extract::Query(SubParams {
+                    shared: true,
+                    ..Default::default()
+                }),
+                HeaderMap::new(),
+                axum::Json(Statement::WithParams(query.into(), vec![param.into()])),
+            )
+        };
+
+        let query = "SELECT node, id, name FROM services WHERE node = ?";
+
+        // one subscription per node, most of them w/o any rows
+        let mut subs = vec![];
+        let mut matcher_ids = HashSet::new();
+        for i in 0..1000 {
+            let res = subscribe(query, format!("node-{i}")).await.into_response();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers()
+                    .get("corro-query-shared")
+                    .and_then(|v| v.to_str().ok()),
+                Some("true")
+            );
+            matcher_ids.insert(res.headers().get("corro-query-id").cloned().unwrap());
+
+            let mut rows = RowsIter {
+                body: res.into_body(),
+                codec: LinesCodec::new(),
+                buf: BytesMut::new(),
+                done: false,
+            };
+
+            assert_eq!(
+                rows.recv().await.unwrap().unwrap(),
+                QueryEvent::columns(vec!["node".into(), "id".into(), "name".into()])
+            );
+            let mut names = vec![];
+            loop {
+                match rows.recv().await.unwrap().unwrap() {
+                    QueryEvent::Row(_, cells) => {
+                        assert_eq!(cells[0].as_text(), Some(format!("node-{i}").as_str()));
+                        names.push(cells[2].as_text().unwrap().to_owned());
+                    }
+                    QueryEvent::EndOfQuery { .. } => break,
+                    evt => panic!("unexpected event: {evt:?}"),
+                }
+            }
+            names.sort();
+            if i < 10 {
+                assert_eq!(names, vec![format!("db-{i}"), format!("web-{i}")]);
+            } else {
+                assert!(names.is_empty());
+            }
+
+            subs.push(rows);
+        }
+
+        // all evaluated by a single matcher
+        assert_eq!(matcher_ids.len(), 1);
+        assert_eq!(agent.matchers().read().len(), 1);
+
+        let (status_code, _) = insert(
+            "INSERT INTO services (node, id, name) VALUES ('node-500', 'web', 'web-500')".into(),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let (status_code, _) = insert(
+            "UPDATE services SET name = 'web-7-bis' WHERE node = 'node-7' AND id = 'web'".into(),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let (status_code, _) =
+            insert("DELETE FROM services WHERE node = 'node-3' AND id = 'db'".into()).await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // each subscriber only gets its own node's changes
+        assert!(matches!(
+            subs[500].recv().await.unwrap().unwrap(),
+            QueryEvent::Change(ChangeType::Insert, _, cells, ChangeId(1)) if cells[2].as_text() == Some("web-500")
+        ));
+        assert!(matches!(
+            subs[7].recv().await.unwrap().unwrap(),
+            QueryEvent::Change(ChangeType::Update, _, cells, ChangeId(2)) if cells[2].as_text() == Some("web-7-bis")
+        ));
+        assert!(matches!(
+            subs[3].recv().await.unwrap().unwrap(),
+            QueryEvent::Change(ChangeType::Delete, _, cells, ChangeId(3)) if cells[2].as_text() == Some("db-3")
+        ));
+
+        // other shapes don't share a plan
+        let res = subscribe(
+            "SELECT node, id, name FROM services WHERE name = ?",
+            "web-1".into(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("corro-query-shared").is_none());
+        assert_eq!(agent.matchers().read().len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_resume_gap() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .subscription_changes(SubscriptionChangesConfig {
+                    retention: 1,
+                    ..Default::default()
+                })
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let query = Statement::Simple("select * from tests".into());
+        let subscribe = |from: Option<ChangeId>| {
+            api_v1_subs(
+                Extension(agent.clone()),
+                Extension(cache.clone()),
+                Extension(bcast_cache.clone()),
+                axum::extract::Query(SubParams {
+                    from,
+                    ..Default::default()
+                }),
+                HeaderMap::new(),
+                axum::Json(query.clone()),
+            )
+        };
+
+        let res = subscribe(None).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap();
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::columns(vec!["id".into(), "text".into()])
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        for i in 1..=3 {
+            let (status_code, _) = api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(
+                    vec![Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![i.into(), format!("service-{i}").into()],
+                    )]
+                    .into(),
+                ),
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+
+            assert!(matches!(
+                rows.recv().await.unwrap().unwrap(),
+                QueryEvent::Change(ChangeType::Insert, _, _, change_id) if change_id == ChangeId(i)
+            ));
+        }
+
+        let matcher = agent.matchers().read().get(&id).cloned().unwrap();
+        assert_eq!(matcher.purge_changes().await?, 2);
+
+        let gap = ResumeGap {
+            requested: ChangeId(1),
+            earliest_available: ChangeId(3),
+        };
+
+        let resume = |from: i64| {
+            api_v1_sub_by_id(
+                Extension(agent.clone()),
+                Extension(bcast_cache.clone()),
+                axum::extract::Path(id),
+                axum::extract::Query(SubParams {
+                    from: Some(ChangeId(from)),
+                    ..Default::default()
+                }),
+                HeaderMap::new(),
+            )
+        };
+
+        let res = resume(1).await.into_response();
+        assert_eq!(res.status(), StatusCode::GONE);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(serde_json::from_slice::<ResumeGap>(&body)?, gap);
+
+        // reusing the matcher checks for gaps too
+        let res = subscribe(Some(ChangeId(1))).await.into_response();
+        assert_eq!(res.status(), StatusCode::GONE);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        assert_eq!(serde_json::from_slice::<ResumeGap>(&body)?, gap);
+
+        // the next change is still there
+        let res = resume(2).await.into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Change(ChangeType::Insert, _, _, change_id) if change_id == ChangeId(3)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_skip_source_id() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, text TEXT NOT NULL DEFAULT '');"
+                    .into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let own = Uuid::new_v4();
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(cache.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Query(SubParams {
+                skip_source_id: Some(own),
+                ..Default::default()
+            }),
+            HeaderMap::new(),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap();
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::Columns { .. }
+        ));
+        assert!(matches!(
+            rows.recv().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let write = |sql: &str, source_id: Option<Uuid>| {
+            let req = ExecRequest::from(vec![Statement::Simple(sql.into())]);
+            api_v1_transactions(
+                Extension(agent.clone()),
+                HeaderMap::new(),
+                axum::Json(match source_id {
+                    Some(source_id) => req.source_id(source_id),
+                    None => req,
+                }),
+            )
+        };
+
+        let expect_event = |evt: QueryEvent, change_id: i64, text: Option<&str>| match text {
+            None => assert_eq!(
+                evt,
+                QueryEvent::Skipped {
+                    change_id: ChangeId(change_id)
+                }
+            ),
+            Some(text) => assert!(
+                matches!(
+                    &evt,
+                    QueryEvent::Change(ChangeType::Insert, _, cells, id) if *id == ChangeId(change_id) && cells[1].as_text() == Some(text)
+                ),
+                "unexpected event: {evt:?}"
+            ),
+        };
+
+        // the subscriber's own write, a second writer's and an untagged one
+        let writes = [
+            (1, "mine", Some(own)),
+            (2, "theirs", Some(Uuid::new_v4())),
+            (3, "untagged", None),
+        ];
+        for (i, text, source_id) in writes {
+            let (status_code, _) = write(
+                &format!("INSERT INTO tests VALUES ({i}, '{text}')"),
+                source_id,
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+            expect_event(
+                rows.recv().await.unwrap()?,
+                i,
+                (source_id != Some(own)).then_some(text),
+            );
+        }
+
+        // resuming skips them the same way
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+            axum::extract::Query(SubParams {
+                from: Some(ChangeId(0)),
+                skip_source_id: Some(own),
+                ..Default::default()
+            }),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        for (i, text, source_id) in writes {
+            expect_event(
+                rows.recv().await.unwrap()?,
+                i,
+                (source_id != Some(own)).then_some(text),
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_handshake() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let cache: SharedMatcherIdCache = Default::default();
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let header = |res: &hyper::Response<_>, name: &str| {
+            res.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+        };
+
+        // a client asking for origins and a capability this agent doesn't know
+        let mut headers = HeaderMap::new();
+        headers.insert(CAPABILITIES_HEADER, "origin,pings".parse()?);
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(cache.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Query(SubParams::default()),
+            headers,
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            header(&res, PROTOCOL_VERSION_HEADER),
+            Some(PROTOCOL_VERSION.to_string())
+        );
+        assert_eq!(
+            header(&res, CAPABILITIES_HEADER).as_deref(),
+            Some(CAPABILITY_ORIGIN)
+        );
+        let id: Uuid = header(&res, "corro-query-id").unwrap().parse()?;
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert_eq!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![CAPABILITY_ORIGIN.into()],
+                sub_id: Some(id),
+            }
+        );
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Columns { .. }
+        ));
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent.clone()),
+            HeaderMap::new(),
+            axum::Json(
+                vec![Statement::Simple(
+                    "INSERT INTO tests VALUES (1, 'one')".into(),
+                )]
+                .into(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Origin(ChangeOrigin {
+                change_id: ChangeId(1),
+                ..
+            })
+        ));
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Change(ChangeType::Insert, _, _, ChangeId(1))
+        ));
+
+        // a client predating the handshake, w/ the legacy param
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+            axum::extract::Query(SubParams {
+                from: Some(ChangeId(0)),
+                origin: true,
+                ..Default::default()
+            }),
+            HeaderMap::new(),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        // still told how old the agent is
+        assert_eq!(
+            header(&res, PROTOCOL_VERSION_HEADER),
+            Some(PROTOCOL_VERSION.to_string())
+        );
+        assert_eq!(header(&res, CAPABILITIES_HEADER).as_deref(), Some(""));
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Origin(ChangeOrigin {
+                change_id: ChangeId(1),
+                ..
+            })
+        ));
+        assert!(matches!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Change(ChangeType::Insert, _, _, ChangeId(1))
+        ));
+
+        // resuming w/ the handshake
+        let mut headers = HeaderMap::new();
+        headers.insert(CAPABILITIES_HEADER, "batch".parse()?);
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            axum::extract::Path(id),
+            axum::extract::Query(SubParams {
+                from: Some(ChangeId(1)),
+                ..Default::default()
+            }),
+            headers,
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            header(&res, CAPABILITIES_HEADER).as_deref(),
+            Some(CAPABILITY_BATCH)
+        );
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert_eq!(
+            rows.recv().await.unwrap()?,
+            QueryEvent::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![CAPABILITY_BATCH.into()],
+                sub_id: Some(id),
+            }
+        );
+
+        Ok(())
+    }
+
+    fn sub_event(evt: QueryEvent) -> SubEvent {
+        let (bytes, meta) = make_query_event_bytes(&mut BytesMut::new(), &evt).unwrap();
+        (bytes, meta, Arc::new(evt))
+    }
+
+    fn change(i: i64) -> QueryEvent {
+        QueryEvent::Change(
+            ChangeType::Update,
+            RowId(i),
+            vec![SqliteValue::Integer(i)],
+            ChangeId(i),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn batches_bursts_of_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (sub_tx, sub_rx) = broadcast::channel(1024);
+        let (tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(forward_sub_to_sender(
+            None, sub_rx, tx, None, None, None, true,
+        ));
+
+        // a lone change isn't held up, nor batched
+        let start = Instant::now();
+        let (bytes, _, _) = sub_event(change(1));
+        sub_tx.send(sub_event(change(1)))?;
+        let received = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await?
+            .unwrap();
+        assert!(start.elapsed() < CHANGE_BATCH_MAX_DELAY + Duration::from_millis(100));
+        assert_eq!(received, bytes);
+
+        // bursts are, but not across other events
+        let mut expected = vec![];
+        for i in 2..=100 {
+            expected.push(change(i));
+        }
+        expected.push(QueryEvent::Rebound {
+            change_id: ChangeId(101),
+        });
+        for i in 102..=200 {
+            expected.push(change(i));
+        }
+        for evt in expected.iter() {
+            sub_tx.send(sub_event(evt.clone()))?;
+        }
+
+        let mut lines = 0;
+        let mut events = vec![];
+        while events.len() < expected.len() {
+            let bytes = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+                .await?
+                .unwrap();
+            for line in bytes.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+                lines += 1;
+                match serde_json::from_slice(line)? {
+                    QueryEvent::ChangeBatch(changes) => events.extend(changes.into_iter().map(
+                        |(change_type, rowid, cells, change_id)| {
+                            QueryEvent::Change(change_type, rowid, cells, change_id)
+                        },
+                    )),
+                    evt => events.push(evt),
+                }
+            }
+        }
+        assert_eq!(events, expected);
+        assert!(lines < expected.len(), "{lines} lines");
+
+        Ok(())
+    }
+
     struct RowsIter {
         body: axum::body::BoxBody,
         codec: LinesCodec,
@@ -0,0 +1,150 @@
+//! Logging of the errors queries and subscriptions respond w/, sampled by
+//! `corro_types::sampling::ErrorSampler`: a client retrying a bad query in
+//! a loop would flood the logs otherwise. Responses are left untouched.
+
+use std::{net::SocketAddr, time::Instant};
+
+use axum::{
+    body::{boxed, Body, Full},
+    extract::ConnectInfo,
+    http::Request,
+    middleware::Next,
+    response::Response,
+    Extension,
+};
+use corro_types::{
+    agent::Agent,
+    quota::ClientIdentity,
+    sampling::{ErrorKey, Sample},
+};
+use hyper::StatusCode;
+use metrics::increment_counter;
+use serde::Deserialize;
+use tracing::{error, warn};
+use tripwire::Tripwire;
+
+use super::bearer_token;
+
+// error bodies are `QueryEvent::Error`s or `ExecResult::Error`s
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+fn error_message(body: &[u8]) -> String {
+    match serde_json::from_slice::<ErrorBody>(body) {
+        Ok(body) => body.error,
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
+    }
+}
+
+fn is_sampled(path: &str) -> bool {
+    path.starts_with("/v1/queries") || path.starts_with("/v1/subscriptions")
+}
+
+/// Logs query and subscription error responses, sampling identical ones
+/// from the same client
+pub async fn sample_query_errors(
+    Extension(agent): Extension<Agent>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    if !is_sampled(request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let path = request.uri().path().to_owned();
+    let client = ClientIdentity::new(
+        bearer_token(request.headers()),
+        connect_info.map(|ConnectInfo(addr)| addr.ip()),
+    );
+
+    let res = next.run(request).await;
+    let status = res.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return res;
+    }
+
+    // error bodies are small and complete, nothing is streamed
+    let (parts, body) = res.into_parts();
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("could not read {path} error response: {e}");
+            return Response::from_parts(parts, boxed(Full::default()));
+        }
+    };
+    let message = error_message(&bytes);
+
+    increment_counter!("corro.api.errors", "status" => status.as_u16().to_string());
+
+    let key = ErrorKey::new(status.as_str(), &message, Some(client));
+    let (sample, summary) = agent.error_sampler().record(key, &message, Instant::now());
+    if let Some(summary) = summary {
+        log_error(status, &path, &summary.to_string());
+    }
+    match sample {
+        Sample::Log => log_error(
+            status,
+            &path,
+            &format!("responded {status} to {client}: {message}"),
+        ),
+        Sample::Suppressed => increment_counter!("corro.api.errors.suppressed"),
+    }
+
+    Response::from_parts(parts, boxed(Full::from(bytes)))
+}
+
+fn log_error(status: StatusCode, path: &str, message: &str) {
+    if status.is_server_error() {
+        error!(path, "{message}");
+    } else {
+        warn!(path, "{message}");
+    }
+}
+
+/// Logs the summaries of errors suppressed in windows which ended, even if
+/// no identical error came after them
+pub async fn error_summaries_loop(agent: Agent, mut tripwire: Tripwire) {
+    let mut interval = tokio::time::interval(agent.error_sampler().window());
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = &mut tripwire => {
+                break;
+            }
+        }
+
+        for summary in agent.error_sampler().flush(Instant::now()) {
+            warn!(code = %summary.key.code, "{summary}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_error_messages() {
+        let query_error = serde_json::to_vec(&corro_types::api::QueryEvent::Error(
+            "no such table: foo".into(),
+        ))
+        .unwrap();
+        assert_eq!(error_message(&query_error), "no such table: foo");
+
+        let exec_error = serde_json::to_vec(&corro_types::api::ExecResult::Error {
+            error: "statement is not readonly".into(),
+            code: None,
+        })
+        .unwrap();
+        assert_eq!(error_message(&exec_error), "statement is not readonly");
+
+        assert_eq!(
+            error_message(b"max concurrency limit reached"),
+            "max concurrency limit reached"
+        );
+    }
+}
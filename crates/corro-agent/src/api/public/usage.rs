@@ -0,0 +1,469 @@
+//! Usage attribution, see `corro_types::usage`: counters are periodically
+//! added to the local `__corro_usage` table, one row per identity and hour,
+//! so restarts only lose what was counted since the last flush.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::Extension;
+use corro_types::{
+    agent::Agent,
+    api::UsageBucket,
+    usage::{usage_bucket, UsageCounts},
+};
+use hyper::StatusCode;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use tokio::task::block_in_place;
+use tracing::{debug, error};
+use tripwire::Tripwire;
+
+/// Usage is flushed to the database this often
+pub const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Buckets older than this are deleted
+pub const USAGE_RETENTION_SECS: u64 = 90 * 24 * 3600;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// Adds usage to the bucket starting at `bucket`
+pub fn write_usage(
+    conn: &Connection,
+    bucket: u64,
+    usage: &[(String, UsageCounts)],
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO __corro_usage (identity, bucket, rows, changes, bytes, statements, changes_generated)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (identity, bucket) DO UPDATE SET
+                rows = rows + excluded.rows,
+                changes = changes + excluded.changes,
+                bytes = bytes + excluded.bytes,
+                statements = statements + excluded.statements,
+                changes_generated = changes_generated + excluded.changes_generated",
+    )?;
+    for (identity, counts) in usage {
+        stmt.execute(params![
+            identity,
+            bucket as i64,
+            counts.rows as i64,
+            counts.changes as i64,
+            counts.bytes as i64,
+            counts.statements as i64,
+            counts.changes_generated as i64,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Buckets of the hour `since` (in seconds since the epoch) falls in and
+/// later ones, ordered by hour then identity
+pub fn read_usage(conn: &Connection, since: u64) -> rusqlite::Result<Vec<UsageBucket>> {
+    conn.prepare_cached(
+        "SELECT identity, bucket, rows, changes, bytes, statements, changes_generated
+            FROM __corro_usage WHERE bucket >= ? ORDER BY bucket, identity",
+    )?
+    .query_map([usage_bucket(since) as i64], |row| {
+        Ok(UsageBucket {
+            identity: row.get(0)?,
+            hour: row.get::<_, i64>(1)? as u64,
+            rows: row.get::<_, i64>(2)? as u64,
+            changes: row.get::<_, i64>(3)? as u64,
+            bytes: row.get::<_, i64>(4)? as u64,
+            statements: row.get::<_, i64>(5)? as u64,
+            changes_generated: row.get::<_, i64>(6)? as u64,
+        })
+    })?
+    .collect()
+}
+
+/// Deletes the buckets which started before `before` (in seconds since the
+/// epoch)
+pub fn prune_usage(conn: &Connection, before: u64) -> rusqlite::Result<usize> {
+    conn.prepare_cached("DELETE FROM __corro_usage WHERE bucket < ?")?
+        .execute([before as i64])
+}
+
+/// Adds what was counted since the last flush to the current hour's
+/// buckets. Usage which couldn't be written is kept for the next flush.
+pub async fn flush_usage(agent: &Agent) -> eyre::Result<usize> {
+    let taken = agent.usage().take();
+    if taken.is_empty() {
+        return Ok(0);
+    }
+
+    let res = async {
+        let mut conn = agent.pool().write_low().await?;
+        block_in_place(|| {
+            let tx = conn.transaction()?;
+            write_usage(&tx, usage_bucket(now_secs()), &taken)?;
+            tx.commit()?;
+            Ok::<_, eyre::Report>(())
+        })
+    }
+    .await;
+
+    match res {
+        Ok(()) => Ok(taken.len()),
+        Err(e) => {
+            agent.usage().restore(&taken);
+            Err(e)
+        }
+    }
+}
+
+async fn prune_old_usage(agent: &Agent) -> eyre::Result<()> {
+    let before = usage_bucket(now_secs()).saturating_sub(USAGE_RETENTION_SECS);
+    let conn = agent.pool().write_low().await?;
+    let pruned = block_in_place(|| prune_usage(&conn, before))?;
+    if pruned > 0 {
+        debug!("pruned {pruned} usage buckets");
+    }
+    Ok(())
+}
+
+/// Flushes usage every `USAGE_FLUSH_INTERVAL`, and once more on shutdown
+pub async fn usage_loop(agent: Agent, mut tripwire: Tripwire) {
+    let mut interval = tokio::time::interval(USAGE_FLUSH_INTERVAL);
+    // the first tick is immediate, nothing was counted yet
+    interval.tick().await;
+
+    loop {
+        let stopping = tokio::select! {
+            _ = interval.tick() => false,
+            _ = &mut tripwire => true,
+        };
+
+        match flush_usage(&agent).await {
+            Ok(0) => {}
+            Ok(flushed) => debug!("flushed the usage of {flushed} identities"),
+            Err(e) => error!("could not flush usage: {e}"),
+        }
+        if stopping {
+            break;
+        }
+
+        if let Err(e) = prune_old_usage(&agent).await {
+            error!("could not prune usage: {e}");
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UsageParams {
+    /// Unix timestamp in seconds, defaults to the last 24 hours
+    #[serde(default)]
+    pub since: Option<u64>,
+}
+
+/// Hourly usage of every subscription and `/v1/transactions` client since
+/// the hour `since` falls in, including what was counted since the last
+/// flush
+pub async fn api_v1_usage(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Query(params): axum::extract::Query<UsageParams>,
+) -> Result<axum::Json<Vec<UsageBucket>>, (StatusCode, String)> {
+    flush_usage(&agent)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let since = params
+        .since
+        .unwrap_or_else(|| now_secs().saturating_sub(24 * 3600));
+    let conn = agent
+        .pool()
+        .read()
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    block_in_place(|| read_usage(&conn, since))
+        .map(axum::Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use corro_tests::launch_test_agent;
+    use corro_types::{
+        api::{QueryEvent, Statement},
+        quota::ClientIdentity,
+        usage::{subscription_identity, USAGE_BUCKET_SECS},
+    };
+    use futures::StreamExt;
+    use spawn::wait_for_all_pending_handles;
+
+    use super::*;
+
+    // usage summed over every bucket, by identity
+    async fn usage_by_identity(agent: &Agent) -> eyre::Result<HashMap<String, UsageCounts>> {
+        let buckets = api_v1_usage(
+            Extension(agent.clone()),
+            axum::extract::Query(UsageParams { since: Some(0) }),
+        )
+        .await
+        .map_err(|(_, e)| eyre::eyre!(e))?
+        .0;
+
+        let mut usage: HashMap<String, UsageCounts> = HashMap::new();
+        for bucket in buckets {
+            let counts = usage.entry(bucket.identity).or_default();
+            counts.rows += bucket.rows;
+            counts.changes += bucket.changes;
+            counts.bytes += bucket.bytes;
+            counts.statements += bucket.statements;
+            counts.changes_generated += bucket.changes_generated;
+        }
+        Ok(usage)
+    }
+
+    fn insert(id: i64) -> Statement {
+        Statement::WithParams(
+            "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+            vec![id.into(), format!("row {id}").into()],
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn counts_usage_per_identity() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let agent = &ta.agent;
+
+        let client = corro_client::CorrosionApiClient::new(agent.api_addr());
+        let team_a = client.clone().with_bearer_token("team-a");
+        let team_b = client.clone().with_bearer_token("team-b");
+
+        // 3 transactions of 2 statements
+        for i in 0..3 {
+            team_a.execute(&[insert(i * 2), insert(i * 2 + 1)]).await?;
+        }
+        team_b.execute(&[insert(100)]).await?;
+
+        let mut sub = client
+            .subscribe(
+                &Statement::Simple("SELECT id, text FROM tests".into()),
+                None,
+            )
+            .await?;
+        let sub_id = sub.id();
+        let mut rows = 0;
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::Row(..)) => rows += 1,
+                Some(QueryEvent::EndOfQuery { .. }) => break,
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+        assert_eq!(rows, 7);
+
+        team_b.execute(&[insert(101)]).await?;
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::Change(..)) => break,
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+
+        let usage = usage_by_identity(agent).await?;
+
+        let a = usage[&ClientIdentity::new(Some("team-a"), None).to_string()];
+        assert_eq!(a.statements, 6);
+        // a change per inserted row's column, at least
+        assert!(a.changes_generated >= 6, "{a:?}");
+        assert!(a.bytes > 0, "{a:?}");
+        assert_eq!((a.rows, a.changes), (0, 0));
+
+        let b = usage[&ClientIdentity::new(Some("team-b"), None).to_string()];
+        assert_eq!(b.statements, 2);
+        assert!(b.changes_generated >= 2, "{b:?}");
+
+        let sub_usage = usage[&subscription_identity(sub_id)];
+        assert_eq!(sub_usage.rows, 7);
+        assert_eq!(sub_usage.changes, 1);
+        assert!(sub_usage.bytes > 0, "{sub_usage:?}");
+        assert_eq!(sub_usage.statements, 0);
+
+        // nothing is counted twice
+        assert_eq!(usage_by_identity(agent).await?, usage);
+
+        drop(sub);
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    // rows streamed to every subscription so far
+    async fn rows_streamed(agent: &Agent) -> eyre::Result<u64> {
+        Ok(usage_by_identity(agent)
+            .await?
+            .iter()
+            .filter(|(identity, _)| identity.starts_with("sub:"))
+            .map(|(_, counts)| counts.rows)
+            .sum())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn snapshots_skip_streaming_rows_again() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let agent = &ta.agent;
+        let client = corro_client::CorrosionApiClient::new(agent.api_addr());
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("sub.snap");
+        let stmt = Statement::Simple("SELECT id, text FROM tests".into());
+
+        client
+            .execute(&(0..5).map(insert).collect::<Vec<_>>())
+            .await?;
+
+        // no snapshot yet
+        let mut sub = client.subscribe_with_snapshot(&path, &stmt).await?;
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::EndOfQuery { .. }) => break,
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+        assert_eq!(sub.state().rows().len(), 5);
+        sub.persist(&path).await?;
+        let sub_id = sub.id();
+        drop(sub);
+        assert_eq!(rows_streamed(agent).await?, 5);
+
+        // written while the consumer restarts
+        client.execute(&[insert(100)]).await?;
+
+        let mut sub = client.subscribe_with_snapshot(&path, &stmt).await?;
+        assert_eq!(sub.id(), sub_id);
+        assert_eq!(sub.state().rows().len(), 5);
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::Change(..)) => break,
+                Some(QueryEvent::Row(..)) => eyre::bail!("rows streamed again"),
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+        assert_eq!(sub.state().rows().len(), 6);
+        sub.persist(&path).await?;
+        drop(sub);
+
+        // only the change was streamed
+        let usage = usage_by_identity(agent).await?;
+        assert_eq!(usage[&subscription_identity(sub_id)].changes, 1);
+        assert_eq!(rows_streamed(agent).await?, 5);
+
+        // a corrupt snapshot falls back to streaming every row
+        let mut bytes = tokio::fs::read(&path).await?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        tokio::fs::write(&path, bytes).await?;
+
+        let mut sub = client.subscribe_with_snapshot(&path, &stmt).await?;
+        assert!(sub.state().rows().is_empty());
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::EndOfQuery { .. }) => break,
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+        assert_eq!(sub.state().rows().len(), 6);
+        assert_eq!(rows_streamed(agent).await?, 5 + 6);
+
+        // as does a snapshot of another query
+        sub.persist(&path).await?;
+        drop(sub);
+        let other = Statement::Simple("SELECT id FROM tests".into());
+        let mut sub = client.subscribe_with_snapshot(&path, &other).await?;
+        assert!(sub.state().rows().is_empty());
+        loop {
+            match sub.next().await.transpose()? {
+                Some(QueryEvent::EndOfQuery { .. }) => break,
+                Some(_) => {}
+                None => eyre::bail!("subscription ended early"),
+            }
+        }
+        assert_eq!(sub.state().columns(), ["id"]);
+        assert_eq!(rows_streamed(agent).await?, 5 + 6 + 6);
+
+        drop(sub);
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn adds_up_buckets() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let conn = ta.agent.pool().write_priority().await?;
+
+        let counts = UsageCounts {
+            rows: 1,
+            changes: 2,
+            bytes: 3,
+            statements: 4,
+            changes_generated: 5,
+        };
+        let usage = [("sub:a".to_owned(), counts)];
+        write_usage(&conn, USAGE_BUCKET_SECS, &usage)?;
+        write_usage(&conn, USAGE_BUCKET_SECS, &usage)?;
+        write_usage(&conn, USAGE_BUCKET_SECS * 2, &usage)?;
+
+        let buckets = read_usage(&conn, 0)?;
+        assert_eq!(
+            buckets,
+            [
+                UsageBucket {
+                    identity: "sub:a".into(),
+                    hour: USAGE_BUCKET_SECS,
+                    rows: 2,
+                    changes: 4,
+                    bytes: 6,
+                    statements: 8,
+                    changes_generated: 10,
+                },
+                UsageBucket {
+                    identity: "sub:a".into(),
+                    hour: USAGE_BUCKET_SECS * 2,
+                    rows: 1,
+                    changes: 2,
+                    bytes: 3,
+                    statements: 4,
+                    changes_generated: 5,
+                },
+            ]
+        );
+        // from the start of the hour
+        assert_eq!(read_usage(&conn, USAGE_BUCKET_SECS * 2 + 10)?.len(), 1);
+
+        assert_eq!(prune_usage(&conn, USAGE_BUCKET_SECS * 2)?, 1);
+        assert_eq!(read_usage(&conn, 0)?.len(), 1);
+
+        drop(conn);
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,384 @@
+//! `proptest` generators for the wire types, to fuzz their serde and speedy
+//! codecs. Enabled by the `arbitrary` feature.
+//!
+//! Sizes are bounded to keep cases fast, and floats are generated w/ short
+//! decimal representations so they survive a JSON round trip exactly.
+
+use std::collections::HashMap;
+
+use compact_str::CompactString;
+use proptest::{
+    arbitrary::{any, Arbitrary},
+    collection::{hash_map, vec},
+    option, prop_oneof,
+    strategy::{BoxedStrategy, Just, Strategy},
+};
+use uuid::Uuid;
+
+use crate::{
+    Change, ChangeId, ChangeOrigin, ChangeType, ColumnName, QueryEvent, Real, ResumeGap, RowId,
+    SiteId, SqliteParam, SqliteValue, Statement, TableName,
+};
+
+/// Longest generated text, in chars
+pub const MAX_TEXT_LEN: usize = 64;
+/// Longest generated blob, in bytes
+pub const MAX_BLOB_LEN: usize = 256;
+/// Most generated values per row, params per statement, etc.
+pub const MAX_ITEMS: usize = 8;
+
+fn text(max_len: usize) -> impl Strategy<Value = CompactString> {
+    vec(any::<char>(), 0..=max_len).prop_map(|chars| chars.into_iter().collect())
+}
+
+fn blob() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=MAX_BLOB_LEN)
+}
+
+// 1/16th increments can be written out exactly in decimal
+fn finite_f64() -> impl Strategy<Value = f64> {
+    (any::<i32>(), 0u32..=4).prop_map(|(n, exp)| n as f64 / (1u32 << exp) as f64)
+}
+
+impl Arbitrary for Real {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        finite_f64().prop_map(Real).boxed()
+    }
+}
+
+impl Arbitrary for SqliteValue {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(SqliteValue::Null),
+            any::<i64>().prop_map(SqliteValue::Integer),
+            any::<Real>().prop_map(SqliteValue::Real),
+            text(MAX_TEXT_LEN).prop_map(SqliteValue::Text),
+            blob().prop_map(|b| SqliteValue::Blob(b.into())),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for SqliteParam {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    // raw JSON isn't generated: once serialized, it can't be told apart from
+    // the other variants
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(SqliteParam::Null),
+            any::<bool>().prop_map(SqliteParam::Bool),
+            any::<i64>().prop_map(SqliteParam::Integer),
+            finite_f64().prop_map(SqliteParam::Real),
+            text(MAX_TEXT_LEN).prop_map(SqliteParam::Text),
+            blob().prop_map(|b| SqliteParam::Blob(b.into())),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for TableName {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        text(MAX_TEXT_LEN).prop_map(TableName).boxed()
+    }
+}
+
+impl Arbitrary for ColumnName {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        text(MAX_TEXT_LEN).prop_map(ColumnName).boxed()
+    }
+}
+
+impl Arbitrary for RowId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<i64>().prop_map(RowId).boxed()
+    }
+}
+
+impl Arbitrary for ChangeId {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        any::<i64>().prop_map(ChangeId).boxed()
+    }
+}
+
+impl Arbitrary for ChangeType {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        prop_oneof![
+            Just(ChangeType::Insert),
+            Just(ChangeType::Update),
+            Just(ChangeType::Delete),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for Change {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        (
+            any::<TableName>(),
+            blob(),
+            any::<ColumnName>(),
+            any::<SqliteValue>(),
+            any::<i64>(),
+            any::<i64>(),
+            any::<i64>(),
+            any::<[u8; 16]>(),
+            any::<i64>(),
+            option::of(any::<u64>()),
+        )
+            .prop_map(
+                |(table, pk, cid, val, col_version, db_version, seq, site_id, cl, ts)| Change {
+                    table,
+                    pk,
+                    cid,
+                    val,
+                    col_version,
+                    db_version,
+                    seq,
+                    site_id: site_id.into(),
+                    cl,
+                    compressed: None,
+                    ts,
+                },
+            )
+            .boxed()
+    }
+}
+
+fn params() -> impl Strategy<Value = Vec<SqliteParam>> {
+    vec(any::<SqliteParam>(), 0..=MAX_ITEMS)
+}
+
+fn named_params() -> impl Strategy<Value = HashMap<String, SqliteParam>> {
+    hash_map(
+        text(MAX_TEXT_LEN).prop_map(String::from),
+        any::<SqliteParam>(),
+        0..=MAX_ITEMS,
+    )
+}
+
+impl Arbitrary for Statement {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let query = || text(MAX_TEXT_LEN).prop_map(String::from);
+        prop_oneof![
+            (
+                query(),
+                option::of(params()),
+                option::of(named_params()),
+                option::of(any::<bool>()),
+            )
+                .prop_map(|(query, params, named_params, defer_foreign_keys)| {
+                    Statement::Verbose {
+                        query,
+                        params,
+                        named_params,
+                        defer_foreign_keys,
+                    }
+                }),
+            query().prop_map(Statement::Simple),
+            (query(), params()).prop_map(|(query, params)| Statement::WithParams(query, params)),
+            (query(), named_params())
+                .prop_map(|(query, params)| Statement::WithNamedParams(query, params)),
+            (query(), params()).prop_map(|(name, params)| Statement::Registered { name, params }),
+        ]
+        .boxed()
+    }
+}
+
+impl Arbitrary for QueryEvent {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        let values = || vec(any::<SqliteValue>(), 0..=MAX_ITEMS);
+        prop_oneof![
+            (vec(text(MAX_TEXT_LEN), 0..=MAX_ITEMS), any::<u64>()).prop_map(
+                |(names, schema_generation)| QueryEvent::Columns {
+                    names,
+                    schema_generation
+                }
+            ),
+            (any::<RowId>(), values()).prop_map(|(rowid, cells)| QueryEvent::Row(rowid, cells)),
+            (finite_f64(), option::of(any::<ChangeId>())).prop_map(|(time, change_id)| {
+                QueryEvent::EndOfQuery {
+                    time,
+                    change_id,
+                    coerced: vec![],
+                }
+            }),
+            (
+                any::<ChangeType>(),
+                any::<RowId>(),
+                values(),
+                any::<ChangeId>()
+            )
+                .prop_map(|(change_type, rowid, cells, change_id)| QueryEvent::Change(
+                    change_type,
+                    rowid,
+                    cells,
+                    change_id
+                )),
+            vec(
+                (
+                    any::<ChangeType>(),
+                    any::<RowId>(),
+                    values(),
+                    any::<ChangeId>()
+                ),
+                0..=MAX_ITEMS
+            )
+            .prop_map(QueryEvent::ChangeBatch),
+            any::<ChangeId>().prop_map(|change_id| QueryEvent::Rebound { change_id }),
+            any::<ChangeId>().prop_map(|change_id| QueryEvent::Skipped { change_id }),
+            (
+                any::<ChangeId>(),
+                any::<[u8; 16]>(),
+                option::of(text(MAX_TEXT_LEN))
+            )
+                .prop_map(|(change_id, site_id, node_name)| QueryEvent::Origin(
+                    ChangeOrigin {
+                        change_id,
+                        site_id: SiteId(site_id),
+                        node_name,
+                    }
+                )),
+            any::<u16>().prop_map(|index| QueryEvent::NextResultSet {
+                index: index as usize
+            }),
+            (any::<ChangeId>(), any::<ChangeId>()).prop_map(|(requested, earliest_available)| {
+                QueryEvent::Rebootstrapped(ResumeGap {
+                    requested,
+                    earliest_available,
+                })
+            }),
+            (
+                any::<u32>(),
+                vec(text(MAX_TEXT_LEN), 0..=MAX_ITEMS),
+                option::of(any::<[u8; 16]>())
+            )
+                .prop_map(|(protocol_version, capabilities, sub_id)| {
+                    QueryEvent::Hello {
+                        protocol_version,
+                        capabilities,
+                        sub_id: sub_id.map(Uuid::from_bytes),
+                    }
+                }),
+            text(MAX_TEXT_LEN).prop_map(QueryEvent::Error),
+        ]
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use proptest::{prop_assert_eq, proptest, test_runner::Config};
+    use speedy::{LittleEndian, Readable, Writable};
+
+    use super::*;
+
+    // a quick smoke run by default, set `PROPTEST_CASES` to fuzz for longer
+    const SMOKE_CASES: u32 = 64;
+
+    fn config() -> Config {
+        Config {
+            cases: std::env::var("PROPTEST_CASES")
+                .ok()
+                .and_then(|cases| cases.parse().ok())
+                .unwrap_or(SMOKE_CASES),
+            ..Config::default()
+        }
+    }
+
+    fn speedy_round_trip<T>(value: &T) -> Result<(), proptest::test_runner::TestCaseError>
+    where
+        T: for<'a> Readable<'a, LittleEndian> + Writable<LittleEndian> + PartialEq + Debug,
+    {
+        let bytes = value.write_to_vec().unwrap();
+        prop_assert_eq!(
+            Writable::<LittleEndian>::bytes_needed(value).unwrap(),
+            bytes.len()
+        );
+        prop_assert_eq!(&T::read_from_buffer(&bytes).unwrap(), value);
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(config())]
+
+        #[test]
+        fn sqlite_value_round_trips(value in any::<SqliteValue>()) {
+            speedy_round_trip(&value)?;
+
+            let json = serde_json::to_string(&value).unwrap();
+            prop_assert_eq!(serde_json::from_str::<SqliteValue>(&json).unwrap(), value);
+        }
+
+        #[test]
+        fn change_round_trips(change in any::<Change>()) {
+            speedy_round_trip(&change)?;
+
+            let json = serde_json::to_string(&change).unwrap();
+            prop_assert_eq!(serde_json::from_str::<Change>(&json).unwrap(), change.clone());
+
+            // and w/ its value compressed in transport
+            let mut compressed = change.clone();
+            compressed.compress(0).unwrap();
+            let bytes = compressed.write_to_vec().unwrap();
+            prop_assert_eq!(
+                Writable::<LittleEndian>::bytes_needed(&compressed).unwrap(),
+                bytes.len()
+            );
+            prop_assert_eq!(Change::read_from_buffer(&bytes).unwrap(), change);
+        }
+
+        #[test]
+        fn names_round_trip(table in any::<TableName>(), column in any::<ColumnName>()) {
+            speedy_round_trip(&table)?;
+            speedy_round_trip(&column)?;
+        }
+
+        // `Statement` isn't `PartialEq`, compare its JSON instead
+        #[test]
+        fn statement_round_trips(stmt in any::<Statement>()) {
+            let json = serde_json::to_value(&stmt).unwrap();
+            let read: Statement = serde_json::from_value(json.clone()).unwrap();
+            prop_assert_eq!(serde_json::to_value(&read).unwrap(), json);
+        }
+
+        #[test]
+        fn query_event_round_trips(event in any::<QueryEvent>()) {
+            let json = serde_json::to_string(&event).unwrap();
+            prop_assert_eq!(serde_json::from_str::<QueryEvent>(&json).unwrap(), event);
+        }
+    }
+}
@@ -0,0 +1,623 @@
+//! Renders statements w/ their params substituted as SQL literals, for audit
+//! logs. Unlike `Debug`, the rendering is stable and safe to log: long values
+//! are truncated and params bound to sensitive columns are masked.
+
+use std::collections::HashMap;
+
+use crate::{SqliteParam, Statement};
+
+/// Replaces the params bound to sensitive columns
+const MASKED: &str = "'<redacted>'";
+
+impl Statement {
+    /// The statement's SQL w/ each placeholder replaced by its param as a SQL
+    /// literal (see `SqliteParam::to_sql_literal`). Values longer than
+    /// `max_value_len` chars (or bytes, for blobs) are truncated and followed
+    /// by a comment w/ their full length. Placeholders w/o a param are left
+    /// as they are.
+    ///
+    /// ```
+    /// use corro_api_types::Statement;
+    ///
+    /// let stmt = Statement::WithParams(
+    ///     "INSERT INTO users (id, name) VALUES (?, ?)".into(),
+    ///     vec![1i64.into(), "it's me".into()],
+    /// );
+    /// assert_eq!(
+    ///     stmt.render_for_audit(64),
+    ///     "INSERT INTO users (id, name) VALUES (1, 'it''s me')"
+    /// );
+    /// ```
+    pub fn render_for_audit(&self, max_value_len: usize) -> String {
+        self.render_for_audit_masked(max_value_len, |_, _| false)
+    }
+
+    /// Like `render_for_audit`, masking the params `is_sensitive` returns
+    /// true for. It's passed the table (if known) and column each param is
+    /// bound to, as told by the statement's SQL: an `INSERT`'s column list,
+    /// or a comparison or assignment like `column = ?`.
+    pub fn render_for_audit_masked<F>(&self, max_value_len: usize, is_sensitive: F) -> String
+    where
+        F: Fn(Option<&str>, &str) -> bool,
+    {
+        let (sql, params) = match self {
+            Statement::Registered { name, params } => {
+                let params = params
+                    .iter()
+                    .map(|param| render_param(param, max_value_len))
+                    .collect::<Vec<_>>();
+                return format!("{name}({})", params.join(", "));
+            }
+            Statement::Simple(sql)
+            | Statement::Verbose {
+                query: sql,
+                params: None,
+                named_params: None,
+                ..
+            } => (sql, Params::None),
+            Statement::WithParams(sql, params)
+            | Statement::Verbose {
+                query: sql,
+                params: Some(params),
+                ..
+            } => (sql, Params::Positional(params)),
+            Statement::WithNamedParams(sql, params)
+            | Statement::Verbose {
+                query: sql,
+                named_params: Some(params),
+                ..
+            } => (sql, Params::Named(params)),
+        };
+
+        let tokens = tokenize(sql);
+        let table = target_table(&tokens);
+        let insert_columns = insert_columns(&tokens);
+
+        let mut rendered = String::with_capacity(sql.len());
+        let mut copied = 0;
+        let mut indexes = Indexes::default();
+        let mut values = ValuesTracker::default();
+
+        for (i, token) in tokens.iter().enumerate() {
+            let in_values = values.track(&tokens, i);
+            let Kind::Param = token.kind else {
+                continue;
+            };
+            let placeholder = &sql[token.start..token.end];
+            let index = indexes.resolve(placeholder);
+
+            let Some(param) = params.get(placeholder, index) else {
+                continue;
+            };
+
+            let column = match in_values {
+                Some(pos) => insert_columns
+                    .as_ref()
+                    .and_then(|columns| columns.get(pos))
+                    .map(|column| (table, *column)),
+                None => compared_column(&tokens, i)
+                    .map(|(qualifier, column)| (qualifier.or(table), column)),
+            };
+
+            rendered.push_str(&sql[copied..token.start]);
+            match column {
+                Some((table, column)) if is_sensitive(table, column) => rendered.push_str(MASKED),
+                _ => rendered.push_str(&render_param(param, max_value_len)),
+            }
+            copied = token.end;
+        }
+
+        rendered.push_str(&sql[copied..]);
+        rendered
+    }
+}
+
+enum Params<'a> {
+    None,
+    Positional(&'a [SqliteParam]),
+    Named(&'a HashMap<String, SqliteParam>),
+}
+
+impl<'a> Params<'a> {
+    fn get(&self, placeholder: &str, index: usize) -> Option<&'a SqliteParam> {
+        match self {
+            Params::None => None,
+            Params::Positional(params) => params.get(index.checked_sub(1)?),
+            Params::Named(params) => params.get(placeholder),
+        }
+    }
+}
+
+/// Numbers placeholders the way sqlite does: `?NNN` gets `NNN`, a named
+/// param keeps the number it got the first time, others get one more than
+/// the largest number so far
+#[derive(Default)]
+struct Indexes<'a> {
+    max: usize,
+    named: HashMap<&'a str, usize>,
+}
+
+impl<'a> Indexes<'a> {
+    fn resolve(&mut self, placeholder: &'a str) -> usize {
+        let index = match placeholder.strip_prefix('?') {
+            Some("") => self.max + 1,
+            Some(digits) => digits.parse().unwrap_or(0),
+            None => match self.named.get(placeholder) {
+                Some(index) => *index,
+                None => {
+                    self.named.insert(placeholder, self.max + 1);
+                    self.max + 1
+                }
+            },
+        };
+        self.max = self.max.max(index);
+        index
+    }
+}
+
+fn render_param(param: &SqliteParam, max_value_len: usize) -> String {
+    match param {
+        SqliteParam::Text(s) => render_text(s, max_value_len),
+        SqliteParam::Json(json) => render_text(json.get(), max_value_len),
+        SqliteParam::Blob(b) => render_blob(b, max_value_len),
+        param => param.to_sql_literal(),
+    }
+}
+
+fn render_text(s: &str, max_value_len: usize) -> String {
+    match s.char_indices().nth(max_value_len) {
+        Some((end, _)) => format!(
+            "{} /* truncated, {} chars */",
+            text_literal(&s[..end]),
+            s.chars().count()
+        ),
+        None => text_literal(s),
+    }
+}
+
+fn render_blob(b: &[u8], max_value_len: usize) -> String {
+    if b.len() > max_value_len {
+        format!(
+            "{} /* truncated, {} bytes */",
+            blob_literal(&b[..max_value_len]),
+            b.len()
+        )
+    } else {
+        blob_literal(b)
+    }
+}
+
+pub(crate) fn text_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+pub(crate) fn blob_literal(b: &[u8]) -> String {
+    format!("X'{}'", hex::encode_upper(b))
+}
+
+pub(crate) fn integer_literal(i: i64) -> String {
+    if i == i64::MIN {
+        // its absolute value is out of range, `-9223372036854775808` would
+        // be a REAL
+        "(-9223372036854775807 - 1)".into()
+    } else {
+        i.to_string()
+    }
+}
+
+pub(crate) fn real_literal(f: f64) -> String {
+    if f.is_nan() {
+        // sqlite stores NaN as NULL
+        "NULL".into()
+    } else if f == f64::INFINITY {
+        // out of range literals parse as infinities
+        "9e999".into()
+    } else if f == f64::NEG_INFINITY {
+        "-9e999".into()
+    } else {
+        // `Debug` keeps a fractional part or an exponent, so the literal
+        // stays a REAL
+        format!("{f:?}")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A keyword or identifier, possibly quoted
+    Word,
+    /// A string, number or blob literal
+    Literal,
+    Param,
+    Punct(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: Kind,
+    start: usize,
+    end: usize,
+    /// Unquoted text of a word
+    text: &'a str,
+}
+
+impl Token<'_> {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == Kind::Word && self.text.eq_ignore_ascii_case(keyword)
+    }
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b >= 0x80
+}
+
+// splits `sql` into tokens, skipping whitespace and comments
+fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    // index right after the closing `quote`, or the end of `sql`
+    let closing = |from: usize, quote: u8| {
+        let mut j = from;
+        while j < bytes.len() {
+            if bytes[j] == quote {
+                // doubled quotes are escaped ones
+                if quote != b']' && bytes.get(j + 1) == Some(&quote) {
+                    j += 2;
+                    continue;
+                }
+                return j + 1;
+            }
+            j += 1;
+        }
+        bytes.len()
+    };
+
+    while i < bytes.len() {
+        let start = i;
+        let b = bytes[i];
+        let kind = match b {
+            _ if b.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = sql[i..].find('\n').map_or(bytes.len(), |end| i + end);
+                continue;
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = sql[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + 2 + end + 2);
+                continue;
+            }
+            b'\'' => {
+                i = closing(i + 1, b'\'');
+                Kind::Literal
+            }
+            b'"' | b'`' | b'[' => {
+                let quote = if b == b'[' { b']' } else { b };
+                i = closing(i + 1, quote);
+                let end = if i > start + 1 { i - 1 } else { i };
+                tokens.push(Token {
+                    kind: Kind::Word,
+                    start,
+                    end: i,
+                    text: &sql[start + 1..end],
+                });
+                continue;
+            }
+            b'?' => {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                Kind::Param
+            }
+            b':' | b'@' | b'$' if bytes.get(i + 1).copied().is_some_and(is_word_byte) => {
+                i += 1;
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+                Kind::Param
+            }
+            b'x' | b'X' if bytes.get(i + 1) == Some(&b'\'') => {
+                i = closing(i + 2, b'\'');
+                Kind::Literal
+            }
+            _ if b.is_ascii_digit()
+                || (b == b'.' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit)) =>
+            {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'.') {
+                    // exponents may be signed
+                    if matches!(bytes[i], b'e' | b'E')
+                        && matches!(bytes.get(i + 1), Some(b'+' | b'-'))
+                    {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                Kind::Literal
+            }
+            _ if is_word_byte(b) => {
+                while i < bytes.len() && is_word_byte(bytes[i]) {
+                    i += 1;
+                }
+                Kind::Word
+            }
+            _ => {
+                i += 1;
+                Kind::Punct(b)
+            }
+        };
+
+        tokens.push(Token {
+            kind,
+            start,
+            end: i,
+            text: &sql[start..i],
+        });
+    }
+
+    tokens
+}
+
+// skips a `schema.` qualifier, returning the index of the table's name
+fn qualified_name(tokens: &[Token<'_>], i: usize) -> Option<usize> {
+    match (tokens.get(i), tokens.get(i + 1), tokens.get(i + 2)) {
+        (Some(t), Some(dot), Some(name))
+            if t.kind == Kind::Word && dot.kind == Kind::Punct(b'.') && name.kind == Kind::Word =>
+        {
+            Some(i + 2)
+        }
+        (Some(t), ..) if t.kind == Kind::Word => Some(i),
+        _ => None,
+    }
+}
+
+// index of the table name following the statement's first `INTO`, `UPDATE`
+// (and its `OR <resolution>`) or `FROM`
+fn target_table_index(tokens: &[Token<'_>]) -> Option<usize> {
+    let i = tokens
+        .iter()
+        .position(|t| t.is_keyword("into") || t.is_keyword("update") || t.is_keyword("from"))?;
+    let mut name = i + 1;
+    if tokens[i].is_keyword("update") && tokens.get(name).is_some_and(|t| t.is_keyword("or")) {
+        name += 2;
+    }
+    qualified_name(tokens, name)
+}
+
+fn target_table<'a>(tokens: &[Token<'a>]) -> Option<&'a str> {
+    target_table_index(tokens).map(|i| tokens[i].text)
+}
+
+// columns listed by an `INSERT INTO table (columns...)`
+fn insert_columns<'a>(tokens: &[Token<'a>]) -> Option<Vec<&'a str>> {
+    let i = tokens.iter().position(|t| t.is_keyword("into"))?;
+    let table = qualified_name(tokens, i + 1)?;
+    let mut rest = tokens[table + 1..].iter();
+    if rest.next()?.kind != Kind::Punct(b'(') {
+        return None;
+    }
+
+    let mut columns = vec![];
+    for token in rest {
+        match token.kind {
+            Kind::Word => columns.push(token.text),
+            Kind::Punct(b',') => {}
+            Kind::Punct(b')') => return Some(columns),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Tracks the position of params within the tuples of an `INSERT`'s `VALUES`
+#[derive(Default)]
+struct ValuesTracker {
+    active: bool,
+    depth: usize,
+    position: usize,
+}
+
+impl ValuesTracker {
+    // the position of token `i` within its tuple, if it's directly in one
+    fn track(&mut self, tokens: &[Token<'_>], i: usize) -> Option<usize> {
+        let token = &tokens[i];
+        if token.is_keyword("values") {
+            self.active = true;
+            self.depth = 0;
+            return None;
+        }
+        if !self.active {
+            return None;
+        }
+
+        match token.kind {
+            Kind::Punct(b'(') => {
+                self.depth += 1;
+                if self.depth == 1 {
+                    self.position = 0;
+                }
+            }
+            Kind::Punct(b')') => self.depth = self.depth.saturating_sub(1),
+            Kind::Punct(b',') if self.depth == 1 => self.position += 1,
+            // e.g. `ON CONFLICT` or `RETURNING` after the last tuple
+            Kind::Word if self.depth == 0 => self.active = false,
+            Kind::Param if self.depth == 1 => return Some(self.position),
+            _ => {}
+        }
+        None
+    }
+}
+
+// the column a param is compared to or assigned to, as in `column = ?`,
+// `t.column LIKE ?` or `column IS NOT ?`, w/ its qualifier if any
+fn compared_column<'a>(tokens: &[Token<'a>], param: usize) -> Option<(Option<&'a str>, &'a str)> {
+    let mut i = param.checked_sub(1)?;
+
+    // at most 2 operator chars, e.g. `>=` or `!=`
+    let mut ops = 0;
+    while let Kind::Punct(b'=' | b'<' | b'>' | b'!') = tokens[i].kind {
+        ops += 1;
+        i = i.checked_sub(1)?;
+    }
+    if ops > 2 {
+        return None;
+    }
+    if ops == 0 {
+        let mut words = 0;
+        while ["like", "glob", "is", "not", "match", "regexp"]
+            .iter()
+            .any(|keyword| tokens[i].is_keyword(keyword))
+        {
+            words += 1;
+            i = i.checked_sub(1)?;
+        }
+        if words == 0 {
+            return None;
+        }
+    }
+
+    let column = tokens[i];
+    if column.kind != Kind::Word {
+        return None;
+    }
+
+    let qualifier = match (i.checked_sub(2), i.checked_sub(1)) {
+        (Some(q), Some(dot))
+            if tokens[dot].kind == Kind::Punct(b'.') && tokens[q].kind == Kind::Word =>
+        {
+            Some(tokens[q].text)
+        }
+        _ => None,
+    };
+
+    Some((qualifier, column.text))
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    fn sensitive(table: Option<&str>, column: &str) -> bool {
+        matches!((table, column), (Some("users"), "password") | (_, "token"))
+    }
+
+    #[test]
+    fn renders_literals() {
+        let stmt = Statement::WithParams(
+            "SELECT ?, ?, ?, ?, ?, ?2".into(),
+            vec![
+                SqliteParam::Null,
+                SqliteParam::Bool(true),
+                SqliteParam::Real(1.0),
+                SqliteParam::Text("a'b".into()),
+                SqliteParam::Blob([0xde, 0xad].as_slice().into()),
+            ],
+        );
+        assert_eq!(
+            stmt.render_for_audit(16),
+            "SELECT NULL, 1, 1.0, 'a''b', X'DEAD', 1"
+        );
+
+        // placeholders within strings, identifiers and comments are left alone
+        let stmt = Statement::WithParams(
+            "SELECT '?', \"?\", ? -- ?\n, /* ? */ ?".into(),
+            vec![1i64.into(), 2i64.into()],
+        );
+        assert_eq!(
+            stmt.render_for_audit(16),
+            "SELECT '?', \"?\", 1 -- ?\n, /* ? */ 2"
+        );
+
+        let stmt = Statement::WithNamedParams(
+            "SELECT :a, @b, :a, $c".into(),
+            [(":a".into(), 1i64.into()), ("@b".into(), "x".into())].into(),
+        );
+        assert_eq!(stmt.render_for_audit(16), "SELECT 1, 'x', 1, $c");
+    }
+
+    #[test]
+    fn truncates_long_values() {
+        let stmt = Statement::WithParams(
+            "INSERT INTO blobs (id, data) VALUES (?, ?)".into(),
+            vec!["héllo world".into(), vec![1u8; 10].into()],
+        );
+        assert_eq!(
+            stmt.render_for_audit(4),
+            "INSERT INTO blobs (id, data) VALUES ('héll' /* truncated, 11 chars */, X'01010101' /* truncated, 10 bytes */)"
+        );
+    }
+
+    #[test]
+    fn masks_sensitive_columns() {
+        let stmt = Statement::WithParams(
+            "INSERT INTO users (id, password, name) VALUES (?, ?, ?), (?, ?, ?) \
+                ON CONFLICT (id) DO UPDATE SET password = ?"
+                .into(),
+            (1..=7).map(SqliteParam::Integer).collect(),
+        );
+        assert_eq!(
+            stmt.render_for_audit_masked(16, sensitive),
+            "INSERT INTO users (id, password, name) VALUES (1, '<redacted>', 3), (4, '<redacted>', 6) \
+                ON CONFLICT (id) DO UPDATE SET password = '<redacted>'"
+        );
+
+        let stmt = Statement::WithParams(
+            "UPDATE OR REPLACE main.users SET name = ?, password=? WHERE u.token IS NOT ? AND id >= ?"
+                .into(),
+            (1..=4).map(SqliteParam::Integer).collect(),
+        );
+        assert_eq!(
+            stmt.render_for_audit_masked(16, sensitive),
+            "UPDATE OR REPLACE main.users SET name = 1, password='<redacted>' WHERE u.token IS NOT '<redacted>' AND id >= 4"
+        );
+
+        // only masked in the configured table
+        let stmt = Statement::WithNamedParams(
+            "DELETE FROM accounts WHERE password = :password".into(),
+            [(":password".into(), "hunter2".into())].into(),
+        );
+        assert_eq!(
+            stmt.render_for_audit_masked(16, sensitive),
+            "DELETE FROM accounts WHERE password = 'hunter2'"
+        );
+    }
+
+    #[test]
+    fn rendered_sql_is_executable() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "CREATE TABLE tests (id INTEGER PRIMARY KEY, text TEXT, real REAL, blob BLOB, nothing ANY);",
+        )?;
+
+        let params = |id: i64| -> Vec<SqliteParam> {
+            vec![
+                id.into(),
+                "it's \"quoted\" -- not a comment /*".into(),
+                SqliteParam::Real(-0.000123),
+                vec![0u8, 1, 255].into(),
+                SqliteParam::Null,
+            ]
+        };
+        let sql = "INSERT INTO tests (id, text, real, blob, nothing) VALUES (?, ?, ?, ?, ?)";
+
+        conn.execute_batch(&Statement::WithParams(sql.into(), params(1)).render_for_audit(64))?;
+        conn.execute(sql, rusqlite::params_from_iter(params(2).iter()))?;
+
+        let rendered: Vec<(String, f64, Vec<u8>, Option<String>)> = conn
+            .prepare("SELECT text, real, blob, nothing FROM tests ORDER BY id")?
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        assert_eq!(rendered.len(), 2);
+        assert_eq!(rendered[0], rendered[1]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+//! Conversions of the values in a query's rows, for data stored w/ the
+//! wrong type, e.g. numbers replicated as TEXT.
+//!
+//! Opted into per query, they change the type of values in the output.
+//! Only unambiguous values are converted: `'42'` becomes `42`, but `'0123'`
+//! stays a text since it could be an identifier rather than a number.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Real, SqliteValue};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Coercion {
+    /// Texts spelling a number the way JSON does become integers or reals,
+    /// e.g. `'42'`, `'-1.5'` and `'1e5'`. Leading zeros or `+` signs,
+    /// whitespace, `'NaN'`, `'inf'` and numbers out of range stay texts.
+    NumericText,
+}
+
+impl Coercion {
+    /// How it's named in query strings
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Coercion::NumericText => "numeric_text",
+        }
+    }
+
+    /// Converts `value` in place, returns whether it was converted
+    pub fn apply(self, value: &mut SqliteValue) -> bool {
+        match self {
+            Coercion::NumericText => {
+                let coerced = match value {
+                    SqliteValue::Text(text) => numeric_text(text),
+                    _ => None,
+                };
+                match coerced {
+                    Some(coerced) => {
+                        *value = coerced;
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+fn numeric_text(text: &str) -> Option<SqliteValue> {
+    if !is_json_number(text) {
+        return None;
+    }
+
+    let integral = !text.contains(['.', 'e', 'E']);
+    if integral {
+        // out of range fails, `-0` doesn't spell the integer back
+        return text
+            .parse::<i64>()
+            .ok()
+            .filter(|i| i.to_string() == text)
+            .map(SqliteValue::Integer);
+    }
+
+    let f = text.parse::<f64>().ok().filter(|f| f.is_finite())?;
+
+    // underflowed to zero
+    let mantissa = text.split(['e', 'E']).next().unwrap_or_default();
+    if f == 0.0 && mantissa.contains(|c: char| ('1'..='9').contains(&c)) {
+        return None;
+    }
+
+    Some(SqliteValue::Real(Real(f)))
+}
+
+// -?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?
+fn is_json_number(text: &str) -> bool {
+    let mut bytes = text.as_bytes();
+
+    if let [b'-', rest @ ..] = bytes {
+        bytes = rest;
+    }
+
+    match bytes {
+        [b'0', rest @ ..] => bytes = rest,
+        [b'1'..=b'9', ..] => {
+            skip_digits(&mut bytes);
+        }
+        _ => return false,
+    }
+
+    if let [b'.', rest @ ..] = bytes {
+        bytes = rest;
+        if skip_digits(&mut bytes) == 0 {
+            return false;
+        }
+    }
+
+    if let [b'e' | b'E', rest @ ..] = bytes {
+        bytes = rest;
+        if let [b'+' | b'-', rest @ ..] = bytes {
+            bytes = rest;
+        }
+        if skip_digits(&mut bytes) == 0 {
+            return false;
+        }
+    }
+
+    bytes.is_empty()
+}
+
+// skips leading digits, returns how many
+fn skip_digits(bytes: &mut &[u8]) -> usize {
+    let rest: &[u8] = bytes;
+    let n = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    *bytes = &rest[n..];
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coerce(text: &str) -> SqliteValue {
+        let mut value = SqliteValue::Text(text.into());
+        let coerced = Coercion::NumericText.apply(&mut value);
+        assert_eq!(coerced, !matches!(value, SqliteValue::Text(_)));
+        value
+    }
+
+    #[test]
+    fn coerces_canonical_numbers() {
+        assert_eq!(coerce("42"), SqliteValue::Integer(42));
+        assert_eq!(coerce("-42"), SqliteValue::Integer(-42));
+        assert_eq!(coerce("0"), SqliteValue::Integer(0));
+        assert_eq!(
+            coerce("9223372036854775807"),
+            SqliteValue::Integer(i64::MAX)
+        );
+
+        assert_eq!(coerce("1e5"), SqliteValue::Real(Real(100000.0)));
+        assert_eq!(coerce("-1.5"), SqliteValue::Real(Real(-1.5)));
+        assert_eq!(coerce("0.25E-2"), SqliteValue::Real(Real(0.0025)));
+        assert_eq!(coerce("0.0"), SqliteValue::Real(Real(0.0)));
+    }
+
+    #[test]
+    fn leaves_ambiguous_texts() {
+        for text in [
+            "0123",
+            "-0",
+            "+1",
+            " 42",
+            "42 ",
+            "NaN",
+            "nan",
+            "inf",
+            "-Infinity",
+            "1.",
+            ".5",
+            "1e",
+            "1e+",
+            "0x10",
+            "1_000",
+            "",
+            "-",
+            "9223372036854775808",
+            "1e999",
+            "1e-999",
+            "12abc",
+        ] {
+            assert_eq!(coerce(text), SqliteValue::Text(text.into()), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn names_match_serde() {
+        let coercion = Coercion::NumericText;
+        assert_eq!(
+            serde_json::to_value(coercion).unwrap(),
+            serde_json::json!(coercion.as_str())
+        );
+    }
+
+    #[test]
+    fn leaves_other_types() {
+        for value in [
+            SqliteValue::Null,
+            SqliteValue::Integer(1),
+            SqliteValue::Blob(b"42".to_vec().into()),
+        ] {
+            let mut coerced = value.clone();
+            assert!(!Coercion::NumericText.apply(&mut coerced));
+            assert_eq!(coerced, value);
+        }
+    }
+}
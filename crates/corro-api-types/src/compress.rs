@@ -0,0 +1,245 @@
+//! zstd compression of large `Text` and `Blob` change values, for transport
+//! between peers which both accept it.
+//!
+//! A compressed value replaces the value in a `Change`'s wire encoding, under
+//! its own type tag. It's decompressed as soon as it's read off the wire, so
+//! values are never stored or applied compressed.
+
+use compact_str::CompactString;
+use speedy::{Context, Reader, Writable, Writer};
+
+use crate::{Change, SqliteValue};
+
+/// Values at least this large (in bytes) are compressed, by default
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+// wire tags, following `SqliteValue`'s own
+pub(crate) const TAG_ZSTD_TEXT: u8 = 5;
+pub(crate) const TAG_ZSTD_BLOB: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompressedValue {
+    // wire tag, for `Text` or `Blob`
+    tag: u8,
+    len: usize,
+    data: Vec<u8>,
+}
+
+impl CompressedValue {
+    /// Size of the original value, in bytes
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Size of the compressed value, in bytes
+    pub fn compressed_len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub(crate) fn estimated_byte_size(&self) -> usize {
+        // tag + original len + compressed len prefix
+        1 + 4 + 4 + self.data.len()
+    }
+
+    pub(crate) fn write_to<C: Context, T: ?Sized + Writer<C>>(
+        &self,
+        writer: &mut T,
+    ) -> Result<(), C::Error> {
+        self.tag.write_to(writer)?;
+        (self.len as u32).write_to(writer)?;
+        self.data.as_slice().write_to(writer)
+    }
+
+    pub(crate) fn bytes_needed<C: Context>(&self) -> Result<usize, C::Error> {
+        Ok(1 + 4 + <[u8] as Writable<C>>::bytes_needed(self.data.as_slice())?)
+    }
+
+    /// Reads a compressed value following its `tag` and decompresses it
+    pub(crate) fn read_decompressed<'a, C: Context, R: Reader<'a, C>>(
+        tag: u8,
+        reader: &mut R,
+    ) -> Result<SqliteValue, C::Error> {
+        let len = reader.read_u32()? as usize;
+        let data_len = reader.read_u32()? as usize;
+        let data = reader.read_vec(data_len)?;
+
+        let bytes = zstd::bulk::decompress(&data, len)
+            .map_err(|e| speedy::Error::custom(format!("could not decompress value: {e}")))?;
+        if bytes.len() != len {
+            return Err(speedy::Error::custom(format!(
+                "decompressed value is {} bytes, expected {len}",
+                bytes.len()
+            ))
+            .into());
+        }
+
+        Ok(match tag {
+            TAG_ZSTD_TEXT => SqliteValue::Text(CompactString::from_utf8(bytes).map_err(|e| {
+                speedy::Error::custom(format!("decompressed text isn't utf-8: {e}"))
+            })?),
+            _ => SqliteValue::Blob(bytes.into()),
+        })
+    }
+}
+
+impl Change {
+    /// Compresses `val` for transport when it's a `Text` or `Blob` of at
+    /// least `threshold` bytes that actually shrinks. `val` is left as-is.
+    ///
+    /// Returns whether the value is compressed.
+    pub fn compress(&mut self, threshold: usize) -> std::io::Result<bool> {
+        if self.compressed.is_some() {
+            return Ok(true);
+        }
+
+        let (tag, bytes) = match &self.val {
+            SqliteValue::Text(s) => (TAG_ZSTD_TEXT, s.as_bytes()),
+            SqliteValue::Blob(b) => (TAG_ZSTD_BLOB, b.as_slice()),
+            _ => return Ok(false),
+        };
+        if bytes.len() < threshold {
+            return Ok(false);
+        }
+
+        let data = zstd::bulk::compress(bytes, COMPRESSION_LEVEL)?;
+        if data.len() >= bytes.len() {
+            // incompressible, not worth decompressing on the other end
+            return Ok(false);
+        }
+
+        self.compressed = Some(CompressedValue {
+            tag,
+            len: bytes.len(),
+            data,
+        });
+
+        Ok(true)
+    }
+
+    /// `val`, compressed for transport, if it was
+    pub fn compressed(&self) -> Option<&CompressedValue> {
+        self.compressed.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use speedy::Readable;
+
+    use super::*;
+
+    fn change(val: SqliteValue) -> Change {
+        Change {
+            table: crate::TableName("services".into()),
+            pk: vec![1, 2, 3],
+            cid: crate::ColumnName("output".into()),
+            val,
+            col_version: 1,
+            db_version: 2,
+            seq: 3,
+            site_id: crate::SiteId([7; 16]),
+            cl: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn round_trips_compressed_values() {
+        let json = serde_json::json!({
+            "checks": (0..100).map(|i| serde_json::json!({"name": format!("check-{i}"), "status": "passing", "output": "HTTP GET http://127.0.0.1:8080/health: 200 OK"})).collect::<Vec<_>>()
+        })
+        .to_string();
+
+        for val in [
+            SqliteValue::Text(json.as_str().into()),
+            SqliteValue::Blob(json.as_bytes().into()),
+        ] {
+            let mut compressed = change(val.clone());
+            let uncompressed_size = compressed.estimated_byte_size();
+
+            assert!(compressed.compress(DEFAULT_COMPRESSION_THRESHOLD).unwrap());
+            assert!(compressed.estimated_byte_size() * 5 < uncompressed_size);
+
+            let bytes = compressed.write_to_vec().unwrap();
+            assert!(bytes.len() * 5 < uncompressed_size);
+
+            let read = Change::read_from_buffer(&bytes).unwrap();
+            assert!(read.compressed().is_none());
+            assert_eq!(read, change(val));
+        }
+    }
+
+    #[test]
+    fn skips_small_and_incompressible_values() {
+        let mut small = change(SqliteValue::Text("hello".into()));
+        assert!(!small.compress(DEFAULT_COMPRESSION_THRESHOLD).unwrap());
+
+        let mut not_text = change(SqliteValue::Integer(42));
+        assert!(!not_text.compress(0).unwrap());
+
+        // pseudo-random bytes don't compress
+        let mut state = 0x2545f4914f6cdd1du64;
+        let noise = (0..4096)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<u8>>();
+        let val = SqliteValue::Blob(noise.as_slice().into());
+
+        let mut incompressible = change(val.clone());
+        assert!(!incompressible
+            .compress(DEFAULT_COMPRESSION_THRESHOLD)
+            .unwrap());
+        assert_eq!(
+            incompressible.estimated_byte_size(),
+            change(val.clone()).estimated_byte_size()
+        );
+
+        let bytes = incompressible.write_to_vec().unwrap();
+        assert_eq!(Change::read_from_buffer(&bytes).unwrap(), change(val));
+    }
+
+    #[test]
+    fn uncompressed_encoding_is_unchanged() {
+        // what peers w/o compression support expect: the derived encoding
+        #[derive(speedy::Writable)]
+        struct LegacyChange {
+            table: crate::TableName,
+            pk: Vec<u8>,
+            cid: crate::ColumnName,
+            val: SqliteValue,
+            col_version: i64,
+            db_version: i64,
+            seq: i64,
+            site_id: [u8; 16],
+            cl: i64,
+        }
+
+        let plain = change(SqliteValue::Text("x".repeat(4096).into()));
+        let legacy = LegacyChange {
+            table: plain.table.clone(),
+            pk: plain.pk.clone(),
+            cid: plain.cid.clone(),
+            val: plain.val.clone(),
+            col_version: plain.col_version,
+            db_version: plain.db_version,
+            seq: plain.seq,
+            site_id: plain.site_id.to_bytes(),
+            cl: plain.cl,
+        };
+
+        assert_eq!(
+            plain.write_to_vec().unwrap(),
+            legacy.write_to_vec().unwrap()
+        );
+    }
+}
@@ -0,0 +1,367 @@
+//! Column by column differences between two versions of a row, e.g. what
+//! corrosion stores vs what it should.
+//!
+//! Columns are matched by name, ignoring ASCII case like sqlite does. Values
+//! are compared strictly by default: `1` and `1.0` differ, as do JSON texts
+//! w/ their keys in a different order. Both can be relaxed w/ `DiffOptions`.
+//!
+//! Rendered as `address: '10.0.0.1' → '10.0.0.2'`, blobs as their length
+//! and hash rather than their contents.
+
+use std::fmt;
+
+use compact_str::CompactString;
+use serde::{ser::SerializeMap, Serialize, Serializer};
+
+use crate::{ColumnName, ColumnType, SqliteValue};
+
+/// How values are compared
+#[derive(Debug, Default, Clone)]
+pub struct DiffOptions {
+    numeric_coercion: bool,
+    json_columns: Vec<CompactString>,
+}
+
+impl DiffOptions {
+    /// Considers integers and reals of the same value equal, e.g. `1` and
+    /// `1.0`, like sqlite's own comparisons do
+    pub fn numeric_coercion(mut self, enabled: bool) -> Self {
+        self.numeric_coercion = enabled;
+        self
+    }
+
+    /// Compares `column`'s texts as JSON documents when they both parse as
+    /// such, ignoring formatting and key order
+    pub fn json_column(mut self, column: &str) -> Self {
+        self.json_columns.push(column.into());
+        self
+    }
+
+    fn is_json(&self, column: &str) -> bool {
+        self.json_columns
+            .iter()
+            .any(|json| json.eq_ignore_ascii_case(column))
+    }
+
+    fn values_eq(&self, column: &str, old: &SqliteValue, new: &SqliteValue) -> bool {
+        if old == new {
+            return true;
+        }
+
+        match (old, new) {
+            (SqliteValue::Integer(i), SqliteValue::Real(r))
+            | (SqliteValue::Real(r), SqliteValue::Integer(i))
+                if self.numeric_coercion =>
+            {
+                let r = **r;
+                r.fract() == 0.0 && r == *i as f64 && r as i64 == *i
+            }
+            (SqliteValue::Text(old), SqliteValue::Text(new)) if self.is_json(column) => {
+                match (
+                    serde_json::from_str::<serde_json::Value>(old),
+                    serde_json::from_str::<serde_json::Value>(new),
+                ) {
+                    (Ok(old), Ok(new)) => old == new,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Differences between an old and a new row, in column order
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct RowDiff {
+    pub columns: Vec<ColumnDiff>,
+}
+
+impl RowDiff {
+    /// Diffs two rows given as their column names and values, columns only
+    /// present in one of them are reported as differences
+    pub fn between(
+        old: (&[ColumnName], &[SqliteValue]),
+        new: (&[ColumnName], &[SqliteValue]),
+        options: &DiffOptions,
+    ) -> Self {
+        let find = |(names, values): (&[ColumnName], &[SqliteValue]), name: &str| {
+            names
+                .iter()
+                .position(|other| other.eq_ignore_ascii_case(name))
+                .and_then(|i| values.get(i))
+        };
+
+        let mut columns = vec![];
+
+        for (name, old_value) in old.0.iter().zip(old.1) {
+            match find(new, name) {
+                Some(new_value) if options.values_eq(name, old_value, new_value) => {}
+                new_value => columns.push(ColumnDiff {
+                    column: name.clone(),
+                    old: Some(DiffValue::new(old_value)),
+                    new: new_value.map(DiffValue::new),
+                }),
+            }
+        }
+
+        for (name, new_value) in new.0.iter().zip(new.1) {
+            if find(old, name).is_none() {
+                columns.push(ColumnDiff {
+                    column: name.clone(),
+                    old: None,
+                    new: Some(DiffValue::new(new_value)),
+                });
+            }
+        }
+
+        Self { columns }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+}
+
+impl fmt::Display for RowDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, column) in self.columns.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            column.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// A differing column, `None` when the column is missing from that row
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColumnDiff {
+    pub column: ColumnName,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<DiffValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub new: Option<DiffValue>,
+}
+
+impl fmt::Display for ColumnDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let render = |value: &Option<DiffValue>| match value {
+            Some(value) => value.to_string(),
+            None => "(missing)".into(),
+        };
+        write!(
+            f,
+            "{}: {} → {}",
+            self.column.as_str(),
+            render(&self.old),
+            render(&self.new)
+        )
+    }
+}
+
+/// A value as reported in diffs, blobs are only summarized
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffValue {
+    Value(SqliteValue),
+    Blob { len: usize, hash: u64 },
+}
+
+impl DiffValue {
+    fn new(value: &SqliteValue) -> Self {
+        match value {
+            SqliteValue::Blob(blob) => DiffValue::Blob {
+                len: blob.len(),
+                hash: fnv1a(blob),
+            },
+            value => DiffValue::Value(value.clone()),
+        }
+    }
+
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            DiffValue::Value(value) => value.column_type(),
+            DiffValue::Blob { .. } => ColumnType::Blob,
+        }
+    }
+
+    fn is_blob(&self) -> bool {
+        matches!(self, DiffValue::Blob { .. })
+    }
+}
+
+impl fmt::Display for DiffValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffValue::Value(value) => f.write_str(&value.to_sql_literal()),
+            DiffValue::Blob { len, hash } => write!(f, "<blob, {len} bytes, hash {hash:016x}>"),
+        }
+    }
+}
+
+// {"type": "TEXT", "value": "..."} or {"type": "BLOB", "len": 16, "hash": "..."}
+impl Serialize for DiffValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(if self.is_blob() { 3 } else { 2 }))?;
+        map.serialize_entry("type", &self.column_type().to_string())?;
+        match self {
+            DiffValue::Value(value) => map.serialize_entry("value", value)?,
+            DiffValue::Blob { len, hash } => {
+                map.serialize_entry("len", len)?;
+                map.serialize_entry("hash", &format!("{hash:016x}"))?;
+            }
+        }
+        map.end()
+    }
+}
+
+// stable across builds, unlike std's hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Real;
+
+    fn row(columns: &[(&str, SqliteValue)]) -> (Vec<ColumnName>, Vec<SqliteValue>) {
+        columns
+            .iter()
+            .map(|(name, value)| (ColumnName((*name).into()), value.clone()))
+            .unzip()
+    }
+
+    fn diff(
+        old: &(Vec<ColumnName>, Vec<SqliteValue>),
+        new: &(Vec<ColumnName>, Vec<SqliteValue>),
+        options: &DiffOptions,
+    ) -> RowDiff {
+        RowDiff::between((&old.0, &old.1), (&new.0, &new.1), options)
+    }
+
+    #[test]
+    fn diffs_values_strictly() {
+        let old = row(&[
+            ("id", SqliteValue::Text("web".into())),
+            ("address", SqliteValue::Text("10.0.0.1".into())),
+            ("port", SqliteValue::Integer(80)),
+            ("weight", SqliteValue::Integer(1)),
+        ]);
+        let new = row(&[
+            ("ID", SqliteValue::Text("web".into())),
+            ("address", SqliteValue::Text("10.0.0.2".into())),
+            ("port", SqliteValue::Integer(80)),
+            ("weight", SqliteValue::Real(Real(1.0))),
+        ]);
+
+        let diff = diff(&old, &new, &DiffOptions::default());
+        assert_eq!(
+            diff.to_string(),
+            "address: '10.0.0.1' → '10.0.0.2'\nweight: 1 → 1.0"
+        );
+        assert_eq!(
+            diff.columns[1].new.as_ref().unwrap().column_type(),
+            ColumnType::Float
+        );
+
+        assert!(
+            RowDiff::between((&old.0, &old.1), (&old.0, &old.1), &Default::default()).is_empty()
+        );
+    }
+
+    #[test]
+    fn coerces_numbers() {
+        let options = DiffOptions::default().numeric_coercion(true);
+
+        let old = row(&[("weight", SqliteValue::Integer(1))]);
+        assert!(diff(
+            &old,
+            &row(&[("weight", SqliteValue::Real(Real(1.0)))]),
+            &options
+        )
+        .is_empty());
+        assert!(!diff(
+            &old,
+            &row(&[("weight", SqliteValue::Real(Real(1.5)))]),
+            &options
+        )
+        .is_empty());
+        assert!(!diff(
+            &old,
+            &row(&[("weight", SqliteValue::Text("1".into()))]),
+            &options
+        )
+        .is_empty());
+
+        // beyond what reals represent exactly
+        let old = row(&[("big", SqliteValue::Integer(i64::MAX))]);
+        let new = row(&[("big", SqliteValue::Real(Real(i64::MAX as f64)))]);
+        assert!(!diff(&old, &new, &options).is_empty());
+    }
+
+    #[test]
+    fn compares_json_structurally() {
+        let old = row(&[
+            ("meta", SqliteValue::Text(r#"{"a": 1, "b": [1, 2]}"#.into())),
+            ("notes", SqliteValue::Text(r#"{"a": 1, "b": 2}"#.into())),
+        ]);
+        let new = row(&[
+            ("meta", SqliteValue::Text(r#"{"b":[1,2],"a":1}"#.into())),
+            ("notes", SqliteValue::Text(r#"{"b":2,"a":1}"#.into())),
+        ]);
+
+        let options = DiffOptions::default().json_column("META");
+        let diff = diff(&old, &new, &options);
+        assert_eq!(diff.columns.len(), 1);
+        assert_eq!(diff.columns[0].column.as_str(), "notes");
+
+        // texts which aren't JSON are compared as-is
+        let old = row(&[("meta", SqliteValue::Text("{".into()))]);
+        let new = row(&[("meta", SqliteValue::Text("{ ".into()))]);
+        assert!(!self::diff(&old, &new, &options).is_empty());
+    }
+
+    #[test]
+    fn diffs_column_sets_and_nulls() {
+        let old = row(&[
+            ("name", SqliteValue::Null),
+            ("status", SqliteValue::Text("passing".into())),
+        ]);
+        let new = row(&[("name", SqliteValue::Null), ("output", SqliteValue::Null)]);
+
+        let diff = diff(&old, &new, &DiffOptions::default());
+        assert_eq!(
+            diff.to_string(),
+            "status: 'passing' → (missing)\noutput: (missing) → NULL"
+        );
+        assert_eq!(
+            serde_json::to_value(&diff).unwrap(),
+            serde_json::json!([
+                {"column": "status", "old": {"type": "TEXT", "value": "passing"}},
+                {"column": "output", "new": {"type": "NULL", "value": null}},
+            ])
+        );
+    }
+
+    #[test]
+    fn summarizes_blobs() {
+        let old = row(&[("hash", SqliteValue::Blob(vec![0; 8].into()))]);
+        let new = row(&[("hash", SqliteValue::Blob(vec![1; 16].into()))]);
+
+        let diff = diff(&old, &new, &DiffOptions::default());
+        let hash = fnv1a(&[1; 16]);
+        assert_eq!(diff.columns[0].new, Some(DiffValue::Blob { len: 16, hash }));
+        assert_eq!(
+            diff.columns[0].new.as_ref().unwrap().to_string(),
+            format!("<blob, 16 bytes, hash {hash:016x}>")
+        );
+        assert_eq!(
+            serde_json::to_value(&diff.columns[0].old).unwrap(),
+            serde_json::json!({"type": "BLOB", "len": 8, "hash": format!("{:016x}", fnv1a(&[0; 8]))})
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Digests of a table's contents, to compare nodes w/o sending their rows
+//! over. Rows are hashed one by one in primary key order, the digest of a
+//! range of keys combines the hashes of its rows.
+//!
+//! Nodes which converged have the same digest for the same range, whatever
+//! chunks it was computed in. Ranges which differ can be split and compared
+//! again, down to the rows which diverged.
+
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+
+use crate::{ColumnName, SqliteValue, TableName};
+
+/// Rows per `DigestEvent::Chunk` unless `DigestRequest::chunk_rows` is set
+pub const DEFAULT_DIGEST_CHUNK_ROWS: u64 = 10_000;
+
+/// Body of `POST /v1/digests`, primary keys are given as the values of their
+/// columns, in the key's order
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DigestRequest {
+    pub table: TableName,
+    /// Only hashes rows w/ a primary key at least this, from the first row if
+    /// unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Vec<SqliteValue>>,
+    /// Only hashes rows w/ a primary key under this, up to the last row if
+    /// unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<Vec<SqliteValue>>,
+    /// Rows per chunk, `DEFAULT_DIGEST_CHUNK_ROWS` if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_rows: Option<u64>,
+    /// Also sends the rows hashed, meant for small ranges
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rows: bool,
+}
+
+impl DigestRequest {
+    /// Digest of the whole table
+    pub fn new(table: impl Into<CompactString>) -> Self {
+        Self {
+            table: TableName(table.into()),
+            from: None,
+            to: None,
+            chunk_rows: None,
+            rows: false,
+        }
+    }
+
+    /// Only rows w/ a primary key from `from` (inclusive) to `to` (exclusive)
+    pub fn range(mut self, from: Option<Vec<SqliteValue>>, to: Option<Vec<SqliteValue>>) -> Self {
+        self.from = from;
+        self.to = to;
+        self
+    }
+
+    pub fn chunk_rows(mut self, rows: u64) -> Self {
+        self.chunk_rows = Some(rows);
+        self
+    }
+
+    pub fn with_rows(mut self) -> Self {
+        self.rows = true;
+        self
+    }
+}
+
+/// Events of a `POST /v1/digests` response, sent as newline-delimited JSON:
+/// the columns, then rows (if requested) and chunks in primary key order,
+/// then the digest of the whole range. An error ends the response early.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestEvent {
+    /// Columns rows are hashed in, the first `pk` of them being the primary
+    /// key's
+    Columns {
+        names: Vec<ColumnName>,
+        pk: usize,
+    },
+    Row(Vec<SqliteValue>),
+    Chunk(DigestChunk),
+    Done(Digest),
+    Error(String),
+}
+
+/// Digest of consecutive rows
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DigestChunk {
+    /// Primary key of the chunk's first row
+    pub first: Vec<SqliteValue>,
+    /// Primary key of the chunk's last row
+    pub last: Vec<SqliteValue>,
+    pub rows: u64,
+    pub hash: u64,
+}
+
+/// Digest of a range of rows, equal on nodes which have the same rows
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Digest {
+    pub rows: u64,
+    pub hash: u64,
+    /// Seconds it took to compute
+    pub time: f64,
+}
+
+impl Digest {
+    /// Whether both digests are of the same rows, however long they took
+    pub fn matches(&self, other: &Digest) -> bool {
+        self.rows == other.rows && self.hash == other.hash
+    }
+}
+
+impl PartialEq for Digest {
+    fn eq(&self, other: &Self) -> bool {
+        self.matches(other)
+    }
+}
+
+/// A `POST /v1/digests` response, as collected by clients
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TableDigest {
+    /// Primary key columns first
+    pub columns: Vec<ColumnName>,
+    /// Number of primary key columns
+    pub pk: usize,
+    pub chunks: Vec<DigestChunk>,
+    /// Only w/ `DigestRequest::rows` set
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rows: Vec<Vec<SqliteValue>>,
+    pub digest: Digest,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_as_json() {
+        let req = DigestRequest::new("tests")
+            .range(Some(vec![SqliteValue::Integer(1)]), None)
+            .chunk_rows(100);
+        assert_eq!(
+            serde_json::to_value(&req).unwrap(),
+            serde_json::json!({"table": "tests", "from": [1], "chunk_rows": 100})
+        );
+        assert_eq!(
+            serde_json::from_str::<DigestRequest>(r#"{"table": "tests"}"#).unwrap(),
+            DigestRequest::new("tests")
+        );
+
+        let done = DigestEvent::Done(Digest {
+            rows: 2,
+            hash: 42,
+            time: 0.5,
+        });
+        let json = serde_json::to_string(&done).unwrap();
+        assert_eq!(json, r#"{"done":{"rows":2,"hash":42,"time":0.5}}"#);
+        // only the rows and their hash are compared
+        assert_eq!(
+            serde_json::from_str::<DigestEvent>(&json).unwrap(),
+            DigestEvent::Done(Digest {
+                rows: 2,
+                hash: 42,
+                time: 3.0,
+            })
+        );
+    }
+}
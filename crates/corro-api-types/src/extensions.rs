@@ -0,0 +1,246 @@
+//! Fields added to `Change` after its wire format was set, like the origin
+//! timestamp of the change.
+//!
+//! They're written in a length-prefixed record before the value, under their
+//! own type tag. Readers skip what they don't know at the end of the record,
+//! so fields can be appended to it w/o breaking them. Peers predating the
+//! record can't read it at all: it's only sent to peers which accept it, and
+//! only when one of its fields is set.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use speedy::{Context, Readable, Reader, Writable, Writer};
+
+use crate::Change;
+
+// wire tag, following `compress`' own
+pub(crate) const TAG_EXTENSIONS: u8 = 7;
+
+/// The record's fields, in wire order. Fields must be appended w/
+/// `#[speedy(default_on_eof)]`, records written before they were added end
+/// before them.
+#[derive(Debug, Default, Clone, PartialEq, Readable, Writable)]
+pub(crate) struct ChangeExtensions {
+    pub ts: Option<u64>,
+}
+
+impl ChangeExtensions {
+    pub(crate) fn of(change: &Change) -> Option<Self> {
+        change.ts.map(|ts| Self { ts: Some(ts) })
+    }
+
+    pub(crate) fn estimated_byte_size(&self) -> usize {
+        // tag + record len + ts
+        1 + 4 + 9
+    }
+
+    pub(crate) fn write_to<C: Context, T: ?Sized + Writer<C>>(
+        &self,
+        writer: &mut T,
+    ) -> Result<(), C::Error> {
+        TAG_EXTENSIONS.write_to(writer)?;
+        (Writable::<C>::bytes_needed(self)? as u32).write_to(writer)?;
+        Writable::<C>::write_to(self, writer)
+    }
+
+    pub(crate) fn bytes_needed<C: Context>(&self) -> Result<usize, C::Error> {
+        Ok(1 + 4 + Writable::<C>::bytes_needed(self)?)
+    }
+
+    /// Reads a record following its tag, ignoring the fields it doesn't know
+    pub(crate) fn read_tagged<'a, C: Context, R: Reader<'a, C>>(
+        reader: &mut R,
+    ) -> Result<Self, C::Error> {
+        let len = reader.read_u32()? as usize;
+        let record = reader.read_vec(len)?;
+        let endianness = reader.context().endianness();
+        Ok(Self::read_from_buffer_with_ctx(endianness, &record)?)
+    }
+}
+
+impl Change {
+    /// When the change was made on its origin, as told by `ts`
+    pub fn origin_time(&self) -> Option<SystemTime> {
+        // NTP64: seconds since the unix epoch in the high 32 bits, the
+        // fraction of a second in the low 32 bits
+        let ts = self.ts?;
+        let nanos = ((ts & 0xffff_ffff) * 1_000_000_000) >> 32;
+        Some(UNIX_EPOCH + Duration::new(ts >> 32, nanos as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use speedy::{Readable, Writable};
+
+    use super::*;
+    use crate::{compress::TAG_ZSTD_BLOB, compress::TAG_ZSTD_TEXT, CompressedValue, SqliteValue};
+
+    fn change(ts: Option<u64>) -> Change {
+        Change {
+            table: crate::TableName("services".into()),
+            pk: vec![1, 2, 3],
+            cid: crate::ColumnName("name".into()),
+            val: SqliteValue::Text("web".into()),
+            col_version: 1,
+            db_version: 2,
+            seq: 3,
+            site_id: crate::SiteId([7; 16]),
+            cl: 1,
+            ts,
+            ..Default::default()
+        }
+    }
+
+    /// `Change` as read and written by peers predating the extensions
+    #[derive(Debug, PartialEq)]
+    struct OldChange {
+        table: crate::TableName,
+        pk: Vec<u8>,
+        cid: crate::ColumnName,
+        val: SqliteValue,
+        col_version: i64,
+        db_version: i64,
+        seq: i64,
+        site_id: [u8; 16],
+        cl: i64,
+    }
+
+    impl<'a, C: Context> Readable<'a, C> for OldChange {
+        fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+            let table = crate::TableName::read_from(reader)?;
+            let pk = Vec::<u8>::read_from(reader)?;
+            let cid = crate::ColumnName::read_from(reader)?;
+            let val = match u8::read_from(reader)? {
+                tag @ (TAG_ZSTD_TEXT | TAG_ZSTD_BLOB) => {
+                    CompressedValue::read_decompressed(tag, reader)?
+                }
+                tag => SqliteValue::read_tagged(tag, reader)?,
+            };
+
+            Ok(OldChange {
+                table,
+                pk,
+                cid,
+                val,
+                col_version: i64::read_from(reader)?,
+                db_version: i64::read_from(reader)?,
+                seq: i64::read_from(reader)?,
+                site_id: <[u8; 16]>::read_from(reader)?,
+                cl: i64::read_from(reader)?,
+            })
+        }
+    }
+
+    impl<C: Context> Writable<C> for OldChange {
+        fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
+            self.table.write_to(writer)?;
+            self.pk.write_to(writer)?;
+            self.cid.write_to(writer)?;
+            self.val.write_to(writer)?;
+            self.col_version.write_to(writer)?;
+            self.db_version.write_to(writer)?;
+            self.seq.write_to(writer)?;
+            self.site_id.write_to(writer)?;
+            self.cl.write_to(writer)
+        }
+    }
+
+    impl From<Change> for OldChange {
+        fn from(change: Change) -> Self {
+            OldChange {
+                table: change.table,
+                pk: change.pk,
+                cid: change.cid,
+                val: change.val,
+                col_version: change.col_version,
+                db_version: change.db_version,
+                seq: change.seq,
+                site_id: change.site_id.to_bytes(),
+                cl: change.cl,
+            }
+        }
+    }
+
+    #[test]
+    fn plain_changes_are_compatible_w_old_peers() -> Result<(), speedy::Error> {
+        // what's sent to peers which don't accept the extensions
+        let bytes = change(None).write_to_vec()?;
+        assert_eq!(
+            OldChange::read_from_buffer(&bytes)?,
+            OldChange::from(change(None))
+        );
+
+        // and what they send
+        let bytes = OldChange::from(change(None)).write_to_vec()?;
+        assert_eq!(Change::read_from_buffer(&bytes)?, change(None));
+
+        Ok(())
+    }
+
+    #[test]
+    fn extended_changes_round_trip() -> Result<(), speedy::Error> {
+        let ts = (1_700_000_000u64 << 32) | (1 << 31);
+        let extended = change(Some(ts));
+
+        let bytes = extended.write_to_vec()?;
+        assert_eq!(
+            bytes.len(),
+            Writable::<speedy::LittleEndian>::bytes_needed(&extended)?
+        );
+        assert_eq!(Change::read_from_buffer(&bytes)?, extended);
+
+        assert_eq!(
+            extended.origin_time(),
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500))
+        );
+
+        // old peers can't read them, the extensions must be negotiated
+        assert!(OldChange::read_from_buffer(&bytes).is_err());
+
+        // w/ a compressed value
+        let mut compressed = change(Some(ts));
+        compressed.val = SqliteValue::Text("x".repeat(4096).into());
+        assert!(compressed.compress(1024).unwrap());
+        let read = Change::read_from_buffer(&compressed.write_to_vec()?)?;
+        assert_eq!(read.ts, Some(ts));
+        assert_eq!(read.val, compressed.val);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_unknown_extensions() -> Result<(), speedy::Error> {
+        // a record written by a newer peer, w/ a field appended after `ts`
+        #[derive(Readable, Writable)]
+        struct NewerExtensions {
+            ts: Option<u64>,
+            origin: [u8; 16],
+        }
+
+        let plain = change(None);
+        let record = NewerExtensions {
+            ts: Some(42),
+            origin: [1; 16],
+        }
+        .write_to_vec()?;
+
+        let mut bytes = vec![];
+        bytes.extend(plain.table.write_to_vec()?);
+        bytes.extend(plain.pk.write_to_vec()?);
+        bytes.extend(plain.cid.write_to_vec()?);
+        bytes.push(TAG_EXTENSIONS);
+        bytes.extend((record.len() as u32).to_le_bytes());
+        bytes.extend(record);
+        bytes.extend(plain.val.write_to_vec()?);
+        bytes.extend(plain.col_version.write_to_vec()?);
+        bytes.extend(plain.db_version.write_to_vec()?);
+        bytes.extend(plain.seq.write_to_vec()?);
+        bytes.extend(plain.site_id.write_to_vec()?);
+        bytes.extend(plain.cl.write_to_vec()?);
+
+        assert_eq!(Change::read_from_buffer(&bytes)?, change(Some(42)));
+
+        Ok(())
+    }
+}
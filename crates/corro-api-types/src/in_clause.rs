@@ -0,0 +1,378 @@
+//! Expands a marker in a query into as many `?` placeholders as there are
+//! values, for `IN (...)` lists of arbitrary length. Lists which would take
+//! the query over SQLite's parameter limit are split over several statements.
+
+use crate::{SqliteParam, Statement};
+
+/// SQLite's default limit on the params bound to a single statement
+/// (`SQLITE_MAX_VARIABLE_NUMBER`)
+pub const MAX_SQL_PARAMS: usize = 32766;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InClauseError {
+    #[error("marker {0:?} not found outside of string literals and comments")]
+    MissingMarker(String),
+    #[error("marker {0:?} found more than once")]
+    RepeatedMarker(String),
+    #[error("unsupported placeholder {0:?}, only anonymous `?` placeholders can be used w/ an expanded marker")]
+    UnsupportedPlaceholder(String),
+    #[error("query has {expected} placeholders besides the marker, got {got} params")]
+    ParamCount { expected: usize, got: usize },
+    #[error("query has {0} params, leaving no room for values under the limit of {1}")]
+    TooManyParams(usize, usize),
+}
+
+impl Statement {
+    /// Replaces `marker` in `query_template` w/ a placeholder per value, e.g.
+    /// `DELETE FROM t WHERE node = ? AND id IN ({{ids}})`. `params` bind the
+    /// template's other `?` placeholders, values are bound in between the
+    /// ones before and after the marker.
+    ///
+    /// When all the params wouldn't fit under `MAX_SQL_PARAMS`, the values are
+    /// split over several statements, each binding all of `params`.
+    /// Empty `values` produce no statements: nothing could match the `IN`
+    /// list, so running the query would be a no-op.
+    ///
+    /// The marker isn't expanded inside string literals, quoted identifiers
+    /// or comments, and must be found exactly once outside of them.
+    pub fn with_in_clause(
+        query_template: &str,
+        marker: &str,
+        params: Vec<SqliteParam>,
+        values: Vec<SqliteParam>,
+    ) -> Result<Vec<Statement>, InClauseError> {
+        expand(query_template, marker, params, values, MAX_SQL_PARAMS)
+    }
+}
+
+fn expand(
+    query_template: &str,
+    marker: &str,
+    params: Vec<SqliteParam>,
+    values: Vec<SqliteParam>,
+    max_params: usize,
+) -> Result<Vec<Statement>, InClauseError> {
+    let template = Template::parse(query_template, marker)?;
+    if params.len() != template.placeholders {
+        return Err(InClauseError::ParamCount {
+            expected: template.placeholders,
+            got: params.len(),
+        });
+    }
+    if params.len() >= max_params {
+        return Err(InClauseError::TooManyParams(params.len(), max_params));
+    }
+
+    let (before, after) = query_template.split_at(template.marker_at);
+    let after = &after[marker.len()..];
+
+    Ok(values
+        .chunks(max_params - params.len())
+        .map(|chunk| {
+            let query = format!("{before}{}{after}", vec!["?"; chunk.len()].join(", "));
+            let bound = params[..template.placeholders_before]
+                .iter()
+                .chain(chunk)
+                .chain(&params[template.placeholders_before..])
+                .cloned()
+                .collect();
+            Statement::WithParams(query, bound)
+        })
+        .collect())
+}
+
+struct Template {
+    /// Byte offset of the marker
+    marker_at: usize,
+    /// `?` placeholders before the marker
+    placeholders_before: usize,
+    /// All `?` placeholders
+    placeholders: usize,
+}
+
+impl Template {
+    fn parse(sql: &str, marker: &str) -> Result<Self, InClauseError> {
+        let bytes = sql.as_bytes();
+        let mut marker_at = None;
+        let mut placeholders_before = 0;
+        let mut placeholders = 0;
+
+        let mut i = 0;
+        while i < bytes.len() {
+            if !marker.is_empty() && sql.is_char_boundary(i) && sql[i..].starts_with(marker) {
+                if marker_at.is_some() {
+                    return Err(InClauseError::RepeatedMarker(marker.to_owned()));
+                }
+                marker_at = Some(i);
+                placeholders_before = placeholders;
+                i += marker.len();
+                continue;
+            }
+
+            match bytes[i] {
+                // string literals and quoted identifiers, doubled quotes
+                // escape them
+                quote @ (b'\'' | b'"' | b'`') => {
+                    i += 1;
+                    while i < bytes.len() {
+                        if bytes[i] == quote {
+                            if bytes.get(i + 1) == Some(&quote) {
+                                i += 1;
+                            } else {
+                                break;
+                            }
+                        }
+                        i += 1;
+                    }
+                    i += 1;
+                    continue;
+                }
+                b'[' => {
+                    i = find(bytes, i + 1, b"]").map_or(bytes.len(), |end| end + 1);
+                    continue;
+                }
+                b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                    i = find(bytes, i + 2, b"\n").map_or(bytes.len(), |end| end + 1);
+                    continue;
+                }
+                b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                    i = find(bytes, i + 2, b"*/").map_or(bytes.len(), |end| end + 2);
+                    continue;
+                }
+                b'?' => {
+                    if bytes.get(i + 1).map_or(false, u8::is_ascii_digit) {
+                        return Err(InClauseError::UnsupportedPlaceholder(placeholder_at(
+                            sql, i,
+                        )));
+                    }
+                    placeholders += 1;
+                    i += 1;
+                    continue;
+                }
+                b':' | b'@' | b'$'
+                    if bytes
+                        .get(i + 1)
+                        .map_or(false, |b| b.is_ascii_alphanumeric() || *b == b'_') =>
+                {
+                    return Err(InClauseError::UnsupportedPlaceholder(placeholder_at(
+                        sql, i,
+                    )));
+                }
+                _ => {}
+            }
+
+            i += 1;
+        }
+
+        let Some(marker_at) = marker_at else {
+            return Err(InClauseError::MissingMarker(marker.to_owned()));
+        };
+
+        Ok(Self {
+            marker_at,
+            placeholders_before,
+            placeholders,
+        })
+    }
+}
+
+// offset of the first `needle` in `bytes` from `from`
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| from + pos)
+}
+
+// the placeholder starting at `at`, for errors
+fn placeholder_at(sql: &str, at: usize) -> String {
+    let end = sql[at + 1..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(sql.len(), |len| at + 1 + len);
+    sql[at..end].to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ints(range: std::ops::Range<i64>) -> Vec<SqliteParam> {
+        range.map(SqliteParam::Integer).collect()
+    }
+
+    // query and integer params of each statement
+    fn flatten(statements: Vec<Statement>) -> Vec<(String, Vec<i64>)> {
+        statements
+            .into_iter()
+            .map(|statement| match statement {
+                Statement::WithParams(query, params) => (
+                    query,
+                    params
+                        .into_iter()
+                        .map(|param| match param {
+                            SqliteParam::Integer(i) => i,
+                            other => panic!("unexpected param {other:?}"),
+                        })
+                        .collect(),
+                ),
+                other => panic!("unexpected statement {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn expands_marker() -> Result<(), InClauseError> {
+        let statements = Statement::with_in_clause(
+            "DELETE FROM t WHERE a = ? AND id IN ({{ids}}) AND b = ?",
+            "{{ids}}",
+            vec![SqliteParam::Integer(-1), SqliteParam::Integer(-2)],
+            ints(0..3),
+        )?;
+        assert_eq!(
+            flatten(statements),
+            [(
+                "DELETE FROM t WHERE a = ? AND id IN (?, ?, ?) AND b = ?".to_owned(),
+                vec![-1, 0, 1, 2, -2]
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn splits_at_the_limit() -> Result<(), InClauseError> {
+        let template = "DELETE FROM t WHERE a = ? AND id IN ({{ids}})";
+
+        // exactly at the limit
+        let statements = expand(
+            template,
+            "{{ids}}",
+            vec![SqliteParam::Integer(-1)],
+            ints(0..4),
+            5,
+        )?;
+        assert_eq!(
+            flatten(statements),
+            [(
+                "DELETE FROM t WHERE a = ? AND id IN (?, ?, ?, ?)".to_owned(),
+                vec![-1, 0, 1, 2, 3]
+            )]
+        );
+
+        // one over
+        let statements = expand(
+            template,
+            "{{ids}}",
+            vec![SqliteParam::Integer(-1)],
+            ints(0..5),
+            5,
+        )?;
+        assert_eq!(
+            flatten(statements),
+            [
+                (
+                    "DELETE FROM t WHERE a = ? AND id IN (?, ?, ?, ?)".to_owned(),
+                    vec![-1, 0, 1, 2, 3]
+                ),
+                (
+                    "DELETE FROM t WHERE a = ? AND id IN (?)".to_owned(),
+                    vec![-1, 4]
+                ),
+            ]
+        );
+
+        // w/ SQLite's own limit
+        let statements = Statement::with_in_clause(
+            template,
+            "{{ids}}",
+            vec![SqliteParam::Integer(-1)],
+            ints(0..MAX_SQL_PARAMS as i64),
+        )?;
+        let flattened = flatten(statements);
+        assert_eq!(
+            flattened
+                .iter()
+                .map(|(_, params)| params.len())
+                .collect::<Vec<_>>(),
+            [MAX_SQL_PARAMS, 2]
+        );
+        assert_eq!(flattened[1].1, [-1, MAX_SQL_PARAMS as i64 - 1]);
+
+        assert_eq!(
+            expand(template, "{{ids}}", vec![SqliteParam::Null], ints(0..1), 1).unwrap_err(),
+            InClauseError::TooManyParams(1, 1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn empty_values_are_a_no_op() -> Result<(), InClauseError> {
+        assert!(Statement::with_in_clause(
+            "DELETE FROM t WHERE id IN ({{ids}})",
+            "{{ids}}",
+            vec![],
+            vec![]
+        )?
+        .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn skips_literals_and_comments() -> Result<(), InClauseError> {
+        let template = "SELECT '{{ids}}', 'it''s ? {{ids}}', \"{{ids}}\", [{{ids}}] -- {{ids}} ?
+            FROM t /* ? {{ids}} */ WHERE id IN ({{ids}}) AND meta = '$.a ?'";
+        let statements = Statement::with_in_clause(template, "{{ids}}", vec![], ints(0..2))?;
+        let flattened = flatten(statements);
+        assert_eq!(flattened.len(), 1);
+        assert_eq!(
+            flattened[0].0,
+            template.replace("id IN ({{ids}})", "id IN (?, ?)")
+        );
+        assert_eq!(flattened[0].1, [0, 1]);
+
+        assert_eq!(
+            Statement::with_in_clause("SELECT '{{ids}}'", "{{ids}}", vec![], ints(0..1))
+                .unwrap_err(),
+            InClauseError::MissingMarker("{{ids}}".into())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        let err = |template: &str, params: Vec<SqliteParam>| {
+            Statement::with_in_clause(template, "{{ids}}", params, ints(0..1)).unwrap_err()
+        };
+
+        assert_eq!(
+            err(
+                "SELECT * FROM t WHERE id IN ({{ids}}) OR id IN ({{ids}})",
+                vec![]
+            ),
+            InClauseError::RepeatedMarker("{{ids}}".into())
+        );
+        assert_eq!(
+            err(
+                "SELECT * FROM t WHERE a = ?1 AND id IN ({{ids}})",
+                vec![SqliteParam::Null]
+            ),
+            InClauseError::UnsupportedPlaceholder("?1".into())
+        );
+        assert_eq!(
+            err(
+                "SELECT * FROM t WHERE a = :a AND id IN ({{ids}})",
+                vec![SqliteParam::Null]
+            ),
+            InClauseError::UnsupportedPlaceholder(":a".into())
+        );
+        assert_eq!(
+            err("SELECT * FROM t WHERE a = ? AND id IN ({{ids}})", vec![]),
+            InClauseError::ParamCount {
+                expected: 1,
+                got: 0
+            }
+        );
+    }
+}
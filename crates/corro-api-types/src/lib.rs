@@ -1,59 +1,412 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fmt::{self, Write},
-    hash::Hash,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    net::SocketAddr,
     ops::Deref,
 };
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use compact_str::{CompactString, ToCompactString};
 use rusqlite::{
     types::{FromSql, FromSqlError, ToSqlOutput, Value, ValueRef},
     Row, ToSql,
 };
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize, Deserializer, Serialize,
+};
 use serde_json::value::RawValue;
 use smallvec::{SmallVec, ToSmallVec};
 use speedy::{Context, Readable, Reader, Writable, Writer};
-use sqlite::ChangeType;
-
+use uuid::Uuid;
+
+#[cfg(any(test, feature = "arbitrary"))]
+pub mod arbitrary;
+mod audit;
+mod coerce;
+pub mod compress;
+pub mod diff;
+pub mod digest;
+mod extensions;
+pub mod in_clause;
+pub mod row;
+mod site;
 pub mod sqlite;
 
+pub use coerce::Coercion;
+pub use compress::CompressedValue;
+pub use site::{ParseSiteIdError, SiteId};
+pub use sqlite::ChangeType;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryEvent {
-    Columns(Vec<CompactString>),
+    /// First event of query and subscription streams requested w/ the
+    /// `CAPABILITIES_HEADER`: the agent's protocol version and which of the
+    /// requested capabilities it enabled. `sub_id` is the subscription's.
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<CompactString>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_id: Option<Uuid>,
+    },
+    /// Names of the columns of the rows which follow. A subscription sends
+    /// them again, w/ the next `schema_generation`, when a schema change
+    /// altered them: a `Rebound` marker and a fresh snapshot follow.
+    #[serde(
+        serialize_with = "serialize_columns",
+        deserialize_with = "deserialize_columns"
+    )]
+    Columns {
+        names: Vec<CompactString>,
+        schema_generation: u64,
+    },
     Row(RowId, Vec<SqliteValue>),
     #[serde(rename = "eoq")]
     EndOfQuery {
         time: f64,
         #[serde(skip_serializing_if = "Option::is_none")]
         change_id: Option<ChangeId>,
+        /// Columns which had values converted by the query's `Coercion`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        coerced: Vec<CompactString>,
     },
     Change(ChangeType, RowId, Vec<SqliteValue>, ChangeId),
+    /// Consecutive changes sent at once, when the subscriber asked for
+    /// batches. corro-client hands them out one `Change` at a time.
+    ChangeBatch(Vec<(ChangeType, RowId, Vec<SqliteValue>, ChangeId)>),
+    /// The subscription was rebound to new params, or its columns changed w/
+    /// the schema: a fresh snapshot (Columns, Rows, EndOfQuery) for the new
+    /// binding follows. Changes after this marker only concern the new
+    /// binding.
+    Rebound {
+        change_id: ChangeId,
+    },
+    /// Change `change_id` was left out, e.g. it came from a transaction w/
+    /// the subscription's `skip_source_id`. Stands in for the change so
+    /// change ids stay contiguous.
+    Skipped {
+        change_id: ChangeId,
+    },
+    /// Node change `change_id` originates from, sent right before the change
+    /// (or the batch holding it) to subscribers which asked for origins
+    Origin(ChangeOrigin),
+    /// Starts the result set of the `index`th statement of a multi-statement
+    /// query: its Columns, Rows and EndOfQuery follow
+    NextResultSet {
+        index: usize,
+    },
+    /// The subscription couldn't be resumed, see `ResumeGap`, so the client
+    /// started over: a fresh snapshot (Columns, Rows, EndOfQuery) follows.
+    /// Only emitted by clients, never sent by the agent.
+    Rebootstrapped(ResumeGap),
     Error(CompactString),
 }
 
+/// Error ending query and subscription streams when the agent shuts down.
+/// The stream ended cleanly, reconnecting right away, to another agent if
+/// there's one, picks up where it left off.
+pub const SHUTTING_DOWN: &str = "shutting down";
+
+/// Error ending subscription streams whose subscription held a read snapshot
+/// for longer than `db.checkpoint.max_snapshot_secs`, usually because its
+/// subscribers were not keeping up. Resubscribing starts from a fresh one.
+pub const SNAPSHOT_EXPIRED: &str = "snapshot held too long";
+
+/// Version of the query and subscription stream protocol the agent speaks,
+/// see `QueryEvent::Hello`. Features are added as capabilities, it only
+/// changes when what every stream looks like does.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Request header listing the stream capabilities a client asks for,
+/// separated by commas. Streams of requests carrying it, even empty, start
+/// w/ a `QueryEvent::Hello`. Responses carry it w/ the ones enabled,
+/// alongside the `PROTOCOL_VERSION_HEADER`, whether it was sent or not.
+pub const CAPABILITIES_HEADER: &str = "corro-capabilities";
+
+/// Response header w/ the agent's `PROTOCOL_VERSION`, missing from the
+/// responses of agents predating the stream handshake
+pub const PROTOCOL_VERSION_HEADER: &str = "corro-protocol-version";
+
+/// Subscription changes sent in bursts, as `QueryEvent::ChangeBatch` events
+pub const CAPABILITY_BATCH: &str = "batch";
+
+/// Subscription changes preceded by a `QueryEvent::Origin`
+pub const CAPABILITY_ORIGIN: &str = "origin";
+
+/// Capabilities listed in a `CAPABILITIES_HEADER` value, w/o duplicates
+pub fn parse_capabilities(value: &str) -> Vec<CompactString> {
+    let mut capabilities: Vec<CompactString> = vec![];
+    for capability in value.split(',').map(str::trim) {
+        if !capability.is_empty() && !capabilities.iter().any(|c| c == capability) {
+            capabilities.push(capability.into());
+        }
+    }
+    capabilities
+}
+
 impl QueryEvent {
+    /// Columns of the schema's first generation, i.e. of any query
+    pub fn columns(names: Vec<CompactString>) -> Self {
+        QueryEvent::Columns {
+            names,
+            schema_generation: 0,
+        }
+    }
+
+    /// The last event of streams the agent ended to shut down
+    pub fn shutting_down() -> Self {
+        QueryEvent::Error(SHUTTING_DOWN.into())
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        matches!(self, QueryEvent::Error(e) if e.as_str() == SHUTTING_DOWN)
+    }
+
+    /// The last event of subscriptions ended to release their snapshot
+    pub fn snapshot_expired() -> Self {
+        QueryEvent::Error(SNAPSHOT_EXPIRED.into())
+    }
+
+    pub fn is_snapshot_expired(&self) -> bool {
+        matches!(self, QueryEvent::Error(e) if e.as_str() == SNAPSHOT_EXPIRED)
+    }
+
     pub fn meta(&self) -> QueryEventMeta {
         match self {
-            QueryEvent::Columns(_) => QueryEventMeta::Columns,
+            QueryEvent::Hello { .. } => QueryEventMeta::Hello,
+            QueryEvent::Columns { .. } => QueryEventMeta::Columns,
             QueryEvent::Row(rowid, _) => QueryEventMeta::Row(*rowid),
             QueryEvent::EndOfQuery { .. } => QueryEventMeta::EndOfQuery,
             QueryEvent::Change(_, _, _, id) => QueryEventMeta::Change(*id),
+            // the last change is where a resumed subscription picks up
+            QueryEvent::ChangeBatch(changes) => {
+                QueryEventMeta::Change(changes.last().map(|(_, _, _, id)| *id).unwrap_or_default())
+            }
+            QueryEvent::Rebound { change_id } => QueryEventMeta::Rebound(*change_id),
+            QueryEvent::Skipped { change_id } => QueryEventMeta::Skipped(*change_id),
+            QueryEvent::Origin(origin) => QueryEventMeta::Origin(origin.change_id),
+            QueryEvent::NextResultSet { index } => QueryEventMeta::NextResultSet(*index),
+            QueryEvent::Rebootstrapped(_) => QueryEventMeta::Rebootstrapped,
             QueryEvent::Error(_) => QueryEventMeta::Error,
         }
     }
 }
 
+/// Borrowing mirror of `QueryEvent`, (de)serialized the same way. Column
+/// names and errors borrow from what's serialized, or deserialized when they
+/// don't need unescaping, instead of allocating a string each.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryEventRef<'a> {
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<CompactString>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sub_id: Option<Uuid>,
+    },
+    #[serde(
+        serialize_with = "serialize_columns",
+        deserialize_with = "deserialize_columns"
+    )]
+    Columns {
+        #[serde(borrow)]
+        names: Vec<ColumnNameRef<'a>>,
+        schema_generation: u64,
+    },
+    Row(RowId, Vec<SqliteValue>),
+    #[serde(rename = "eoq")]
+    EndOfQuery {
+        time: f64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        change_id: Option<ChangeId>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        coerced: Vec<CompactString>,
+    },
+    Change(ChangeType, RowId, Vec<SqliteValue>, ChangeId),
+    ChangeBatch(Vec<(ChangeType, RowId, Vec<SqliteValue>, ChangeId)>),
+    Rebound {
+        change_id: ChangeId,
+    },
+    Skipped {
+        change_id: ChangeId,
+    },
+    Origin(ChangeOrigin),
+    NextResultSet {
+        index: usize,
+    },
+    Rebootstrapped(ResumeGap),
+    Error(#[serde(borrow)] Cow<'a, str>),
+}
+
+impl<'a> QueryEventRef<'a> {
+    /// Columns event borrowing `names`
+    pub fn columns<S: AsRef<str>>(names: &'a [S]) -> Self {
+        QueryEventRef::Columns {
+            names: names
+                .iter()
+                .map(|name| ColumnNameRef(Cow::Borrowed(name.as_ref())))
+                .collect(),
+            schema_generation: 0,
+        }
+    }
+
+    pub fn into_owned(self) -> QueryEvent {
+        match self {
+            QueryEventRef::Hello {
+                protocol_version,
+                capabilities,
+                sub_id,
+            } => QueryEvent::Hello {
+                protocol_version,
+                capabilities,
+                sub_id,
+            },
+            QueryEventRef::Columns {
+                names,
+                schema_generation,
+            } => QueryEvent::Columns {
+                names: names
+                    .into_iter()
+                    .map(|name| CompactString::new(name.0))
+                    .collect(),
+                schema_generation,
+            },
+            QueryEventRef::Row(rowid, cells) => QueryEvent::Row(rowid, cells),
+            QueryEventRef::EndOfQuery {
+                time,
+                change_id,
+                coerced,
+            } => QueryEvent::EndOfQuery {
+                time,
+                change_id,
+                coerced,
+            },
+            QueryEventRef::Change(change_type, rowid, cells, change_id) => {
+                QueryEvent::Change(change_type, rowid, cells, change_id)
+            }
+            QueryEventRef::ChangeBatch(changes) => QueryEvent::ChangeBatch(changes),
+            QueryEventRef::Rebound { change_id } => QueryEvent::Rebound { change_id },
+            QueryEventRef::Skipped { change_id } => QueryEvent::Skipped { change_id },
+            QueryEventRef::Origin(origin) => QueryEvent::Origin(origin),
+            QueryEventRef::NextResultSet { index } => QueryEvent::NextResultSet { index },
+            QueryEventRef::Rebootstrapped(gap) => QueryEvent::Rebootstrapped(gap),
+            QueryEventRef::Error(e) => QueryEvent::Error(CompactString::new(e)),
+        }
+    }
+}
+
+// `Columns` are serialized as a plain list of names, as they always were,
+// unless a schema change altered them: w/ their `schema_generation` then
+#[allow(clippy::ptr_arg)]
+fn serialize_columns<S, N>(
+    names: &Vec<N>,
+    schema_generation: &u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+    N: Serialize,
+{
+    if *schema_generation == 0 {
+        return names.serialize(serializer);
+    }
+
+    let mut columns = serializer.serialize_struct("Columns", 2)?;
+    columns.serialize_field("names", names)?;
+    columns.serialize_field("schema_generation", schema_generation)?;
+    columns.end()
+}
+
+fn deserialize_columns<'de, D, N>(deserializer: D) -> Result<(Vec<N>, u64), D::Error>
+where
+    D: Deserializer<'de>,
+    N: Deserialize<'de>,
+{
+    deserializer.deserialize_any(ColumnsVisitor(PhantomData))
+}
+
+struct ColumnsVisitor<N>(PhantomData<N>);
+
+impl<'de, N: Deserialize<'de>> Visitor<'de> for ColumnsVisitor<N> {
+    type Value = (Vec<N>, u64);
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("column names, w/ or w/o their schema generation")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let names = Vec::deserialize(de::value::SeqAccessDeserializer::new(seq))?;
+        Ok((names, 0))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut names = None;
+        let mut schema_generation = 0;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "names" => names = Some(map.next_value()?),
+                "schema_generation" => schema_generation = map.next_value()?,
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+        let names = names.ok_or_else(|| de::Error::missing_field("names"))?;
+        Ok((names, schema_generation))
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum QueryEventMeta {
+    Hello,
     Columns,
     Row(RowId),
     EndOfQuery,
     Change(ChangeId),
+    Rebound(ChangeId),
+    Skipped(ChangeId),
+    Origin(ChangeId),
+    NextResultSet(usize),
+    Rebootstrapped,
     Error,
 }
 
+/// Node a subscription's change originates from, see `QueryEvent::Origin`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChangeOrigin {
+    pub change_id: ChangeId,
+    pub site_id: SiteId,
+    /// `None` for nodes whose name isn't known (yet)
+    pub node_name: Option<CompactString>,
+}
+
+/// Body of a `410 Gone` response to resuming a subscription from a change
+/// the agent already purged. The subscriber missed changes and has to start
+/// over from a fresh snapshot.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResumeGap {
+    pub requested: ChangeId,
+    /// Oldest change the subscription still has
+    pub earliest_available: ChangeId,
+}
+
+impl fmt::Display for ResumeGap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can't resume from change {}, the earliest available is {}",
+            self.requested, self.earliest_available
+        )
+    }
+}
+
+impl std::error::Error for ResumeGap {}
+
 /// RowId newtype to differentiate from ChangeId
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Ord, PartialOrd)]
 #[serde(transparent)]
@@ -121,10 +474,23 @@ impl ToSql for ChangeId {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Statement {
+    /// A query registered on the agent under `name`, run w/ positional
+    /// `params`. Serialized w/ a `registered` key holding the name, which
+    /// tells it apart from the other shapes.
+    Registered {
+        #[serde(rename = "registered")]
+        name: String,
+        #[serde(default)]
+        params: Vec<SqliteParam>,
+    },
     Verbose {
         query: String,
         params: Option<Vec<SqliteParam>>,
         named_params: Option<HashMap<String, SqliteParam>>,
+        /// Sets `PRAGMA defer_foreign_keys` before running the statement, it
+        /// stays in effect for the rest of the transaction
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        defer_foreign_keys: Option<bool>,
     },
     Simple(String),
     WithParams(String, Vec<SqliteParam>),
@@ -132,14 +498,67 @@ pub enum Statement {
 }
 
 impl Statement {
+    /// The statement's SQL, or the name of a registered query
     pub fn query(&self) -> &str {
         match self {
+            Statement::Registered { name, .. } => name,
             Statement::Verbose { query, .. }
             | Statement::Simple(query)
             | Statement::WithParams(query, _)
             | Statement::WithNamedParams(query, _) => query,
         }
     }
+
+    /// Iterates over all bound params, positional and named
+    pub fn params(&self) -> Box<dyn Iterator<Item = &SqliteParam> + '_> {
+        match self {
+            Statement::Verbose {
+                params,
+                named_params,
+                ..
+            } => Box::new(
+                params
+                    .iter()
+                    .flatten()
+                    .chain(named_params.iter().flat_map(|named| named.values())),
+            ),
+            Statement::Simple(_) => Box::new(std::iter::empty()),
+            Statement::WithParams(_, params) | Statement::Registered { params, .. } => {
+                Box::new(params.iter())
+            }
+            Statement::WithNamedParams(_, params) => Box::new(params.values()),
+        }
+    }
+}
+
+/// Body of `PUT /v1/queries/registered/{name}`: a read-only query template,
+/// run by name w/ `Statement::Registered`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RegisteredQuery {
+    pub query: String,
+    #[serde(flatten)]
+    pub limits: QueryLimits,
+}
+
+impl RegisteredQuery {
+    pub fn new<S: Into<String>>(query: S) -> Self {
+        Self {
+            query: query.into(),
+            limits: QueryLimits::default(),
+        }
+    }
+}
+
+/// Limits the agent enforces when running a registered query through
+/// `/v1/queries`
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueryLimits {
+    /// Fails the query once it returns more rows
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rows: Option<u64>,
+    /// Interrupts the query once it ran for longer, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
 }
 
 impl From<&str> for Statement {
@@ -148,20 +567,803 @@ impl From<&str> for Statement {
     }
 }
 
+/// Body of a `/v1/transactions` request, either a plain list of statements
+/// or statements along with execution options.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ExecRequest {
+    Statements(Vec<Statement>),
+    WithOptions {
+        statements: Vec<Statement>,
+        #[serde(default)]
+        isolation: ExecIsolation,
+        /// Sizes of consecutive groups of statements applied atomically when
+        /// using `ExecIsolation::Statement`, defaults to 1 statement per group
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        groups: Option<Vec<usize>>,
+        /// Defers foreign key checks until the transaction commits
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        defer_foreign_keys: bool,
+        /// Streams the rows returned by statements as `ExecEvent`s instead of
+        /// responding w/ an `ExecResponse` once everything ran
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        stream_returning: bool,
+        /// Connection settings for the duration of the request
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<SessionOptions>,
+        /// Reports the changes generated for replication in the response's
+        /// `changes_generated`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        report_changes: bool,
+        /// Writes w/o generating changes, so they're never replicated. Only
+        /// allowed w/ the `api.authorization` token, for tables in
+        /// `db.no_replication_tables`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        no_replication: bool,
+        /// Tags the transaction's changes, subscriptions w/ the same
+        /// `skip_source_id` don't get them back
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source_id: Option<Uuid>,
+        /// Runs the statements and reports their results as usual, then rolls
+        /// the transaction back: nothing is written nor replicated
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        dry_run: bool,
+    },
+}
+
+/// Connection settings applied when an exec request's transaction starts and
+/// restored to their prior values once it's done, whether it committed or
+/// not. Unset options are left as-is and unknown ones are rejected.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SessionOptions {
+    /// `PRAGMA busy_timeout`, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub busy_timeout_ms: Option<u64>,
+    /// `PRAGMA foreign_keys`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub foreign_keys: Option<bool>,
+    /// `PRAGMA recursive_triggers`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recursive_triggers: Option<bool>,
+    /// `PRAGMA defer_foreign_keys`, which sqlite resets at the end of every
+    /// transaction anyway
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub defer_foreign_keys: Option<bool>,
+}
+
+impl ExecRequest {
+    /// Statement isolation w/ each `Vec<Statement>` applied as a group
+    pub fn grouped(groups: Vec<Vec<Statement>>) -> Self {
+        let sizes = groups.iter().map(|group| group.len()).collect();
+        ExecRequest::WithOptions {
+            statements: groups.into_iter().flatten().collect(),
+            isolation: ExecIsolation::Statement,
+            groups: Some(sizes),
+            defer_foreign_keys: false,
+            stream_returning: false,
+            session: None,
+            report_changes: false,
+            no_replication: false,
+            source_id: None,
+            dry_run: false,
+        }
+    }
+
+    fn with_options(self) -> Self {
+        match self {
+            ExecRequest::Statements(statements) => ExecRequest::WithOptions {
+                statements,
+                isolation: ExecIsolation::default(),
+                groups: None,
+                defer_foreign_keys: false,
+                stream_returning: false,
+                session: None,
+                report_changes: false,
+                no_replication: false,
+                source_id: None,
+                dry_run: false,
+            },
+            req => req,
+        }
+    }
+
+    /// Defers foreign key checks until the transaction commits, allowing
+    /// rows to be deleted and reinserted in any order
+    pub fn defer_foreign_keys(self) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions {
+            defer_foreign_keys, ..
+        } = &mut req
+        {
+            *defer_foreign_keys = true;
+        }
+        req
+    }
+
+    /// Streams rows returned by the statements (e.g. `INSERT ... RETURNING`)
+    /// as they're produced, see `ExecEvent`
+    pub fn stream_returning(self) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions {
+            stream_returning, ..
+        } = &mut req
+        {
+            *stream_returning = true;
+        }
+        req
+    }
+
+    /// Applies connection settings for the duration of the request
+    pub fn session(self, options: SessionOptions) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions { session, .. } = &mut req {
+            *session = Some(options);
+        }
+        req
+    }
+
+    /// Reports the changes the transaction generated for replication, see
+    /// `ExecResponse::changes_generated`
+    pub fn report_changes(self) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions { report_changes, .. } = &mut req {
+            *report_changes = true;
+        }
+        req
+    }
+
+    /// Suspends change capture for the transaction: its writes are local to
+    /// this node, never replicated. Meant for maintenance applied identically
+    /// on every node, e.g. backfilling a column.
+    pub fn no_replication(self) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions { no_replication, .. } = &mut req {
+            *no_replication = true;
+        }
+        req
+    }
+
+    /// Tags the transaction's changes w/ `id`, so subscriptions made w/
+    /// `skip_source_id` set to it skip them, e.g. to not get a client's own
+    /// writes back
+    pub fn source_id(self, id: Uuid) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions { source_id, .. } = &mut req {
+            *source_id = Some(id);
+        }
+        req
+    }
+
+    /// Rolls the transaction back once its statements ran, to find out what
+    /// they would do, see `ExecResponse::dry_run`
+    pub fn dry_run(self) -> Self {
+        let mut req = self.with_options();
+        if let ExecRequest::WithOptions { dry_run, .. } = &mut req {
+            *dry_run = true;
+        }
+        req
+    }
+
+    pub fn is_stream_returning(&self) -> bool {
+        matches!(
+            self,
+            ExecRequest::WithOptions {
+                stream_returning: true,
+                ..
+            }
+        )
+    }
+
+    pub fn statements(&self) -> &[Statement] {
+        match self {
+            ExecRequest::Statements(statements) | ExecRequest::WithOptions { statements, .. } => {
+                statements
+            }
+        }
+    }
+
+    pub fn statements_mut(&mut self) -> &mut Vec<Statement> {
+        match self {
+            ExecRequest::Statements(statements) | ExecRequest::WithOptions { statements, .. } => {
+                statements
+            }
+        }
+    }
+}
+
+impl From<Vec<Statement>> for ExecRequest {
+    fn from(value: Vec<Statement>) -> Self {
+        ExecRequest::Statements(value)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecIsolation {
+    /// All statements share the transaction, results are per statement
+    #[default]
+    Transaction,
+    /// Each group of statements is wrapped in a savepoint and rolled back on
+    /// error without affecting the other groups, results are per group
+    Statement,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ExecResponse {
     pub results: Vec<ExecResult>,
     pub time: f64,
+    /// Only set when asked for w/ `ExecRequest::report_changes`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes_generated: Option<ChangesGenerated>,
+    /// The transaction was rolled back after running, as asked w/
+    /// `ExecRequest::dry_run`. Its `changes_generated` are always reported.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+}
+
+/// Column-level changes a transaction generated for replication: one per
+/// column changed in each row, plus cr-sqlite's row-level ones for
+/// creations and deletions
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChangesGenerated {
+    pub changes: u64,
+    /// Sum of the changes' `Change::estimated_byte_size`
+    pub bytes: u64,
+    /// Statements the transaction ran
+    pub statements: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum ExecResult {
-    Execute { rows_affected: usize, time: f64 },
-    Error { error: String },
+    Execute {
+        rows_affected: usize,
+        time: f64,
+    },
+    Error {
+        error: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<ExecErrorCode>,
+    },
+}
+
+/// Conditions an `ExecResult::Error` can be attributed to, so they
+/// can be told apart w/o matching on sqlite's messages
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecErrorCode {
+    /// The disk or database is full (`SQLITE_FULL`)
+    Full,
+    /// The database is busy or locked (`SQLITE_BUSY`, `SQLITE_LOCKED`)
+    Busy,
+    /// The transaction was killed while it ran (`SQLITE_INTERRUPT`) and
+    /// rolled back, see `ActiveTransaction`
+    Interrupted,
+}
+
+impl ExecErrorCode {
+    pub fn from_sqlite(e: &rusqlite::Error) -> Option<Self> {
+        match e.sqlite_error_code()? {
+            rusqlite::ErrorCode::DiskFull => Some(Self::Full),
+            rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => {
+                Some(Self::Busy)
+            }
+            rusqlite::ErrorCode::OperationInterrupted => Some(Self::Interrupted),
+            _ => None,
+        }
+    }
+}
+
+/// A `/v1/transactions` request which is running or waiting for the write
+/// connection, as listed by `GET /v1/transactions/active`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ActiveTransaction {
+    /// Only valid until the agent restarts
+    pub id: u64,
+    /// Unix timestamp in milliseconds
+    pub started_at: u64,
+    /// Seconds since it started
+    pub elapsed: f64,
+    /// Statements in the request
+    pub statements: usize,
+    /// Index of the statement being run, unset until the first one starts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_statement: Option<usize>,
+    /// Start of the current statement's SQL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sql: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_addr: Option<SocketAddr>,
+    /// The request's `idempotency-key` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Whether it was killed, and is rolling back
+    pub killed: bool,
+}
+
+/// Header naming a `/v1/transactions` request, listed as its
+/// `ActiveTransaction::idempotency_key`
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// A named migration step, applied at most once per node by
+/// `POST /v1/migrations/apply`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationStep {
+    /// Identifies the step, it's skipped once a step by that name was applied
+    pub name: String,
+    /// Schema changes (`CREATE TABLE`, `CREATE INDEX`, `ALTER TABLE ... ADD
+    /// COLUMN`) and backfills, run in a single transaction
+    pub statements: Vec<Statement>,
+}
+
+/// Body of `POST /v1/migrations/apply`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrateRequest {
+    /// Applied in order, stopping at the first one which fails
+    pub steps: Vec<MigrationStep>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct MigrateResponse {
+    /// Names of the steps applied by this request
+    pub applied: Vec<String>,
+    /// Names of the steps which were already applied
+    pub skipped: Vec<String>,
+    /// Name of the step which failed, later steps weren't attempted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failed: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub time: f64,
+}
+
+/// A migration step applied on the node, as listed by
+/// `GET /v1/migrations/applied`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AppliedMigration {
+    pub name: String,
+    /// Unix timestamp in milliseconds
+    pub applied_at: u64,
+    /// Statements in the step
+    pub statements: usize,
+}
+
+/// Body of a `403 Forbidden` response, when the request's token has an
+/// access policy which doesn't allow something the statement reads
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccessDenied {
+    pub denied: DeniedObject,
+}
+
+impl fmt::Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "access denied to {}", self.denied)
+    }
+}
+
+impl std::error::Error for AccessDenied {}
+
+/// First object an access policy didn't allow
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeniedObject {
+    Table {
+        table: String,
+    },
+    Column {
+        table: String,
+        column: String,
+    },
+    /// Something besides reading tables, e.g. a pragma or a write
+    Action {
+        action: String,
+    },
+    /// An endpoint policies don't apply to, e.g. `/v1/transactions`
+    Route {
+        path: String,
+    },
+}
+
+impl fmt::Display for DeniedObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeniedObject::Table { table } => write!(f, "table '{table}'"),
+            DeniedObject::Column { table, column } => write!(f, "column '{table}.{column}'"),
+            DeniedObject::Action { action } => write!(f, "action {action}"),
+            DeniedObject::Route { path } => write!(f, "route {path}"),
+        }
+    }
+}
+
+/// Body of a `429 Too Many Requests` response, when the client is over one
+/// of its quotas. The `retry-after` header holds the same wait in seconds,
+/// rounded up.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Throttled {
+    pub throttled: QuotaKind,
+    pub retry_after_ms: u64,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "over the {} quota, retry after {}ms",
+            self.throttled, self.retry_after_ms
+        )
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    Requests,
+    Statements,
+    ChangeBytes,
+}
+
+impl fmt::Display for QuotaKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QuotaKind::Requests => "requests",
+            QuotaKind::Statements => "statements",
+            QuotaKind::ChangeBytes => "change bytes",
+        })
+    }
+}
+
+/// What's left of a client's quotas, listed by `GET /v1/quotas`. Each is
+/// unset when unlimited, and negative when the client is in debt.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct QuotaUsage {
+    /// `token:` followed by a hash of the client's token, or `ip:` and its
+    /// address
+    pub identity: String,
+    /// Of the override the client's limits come from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub requests: Option<f64>,
+    pub statements: Option<f64>,
+    pub change_bytes: Option<f64>,
+    /// Requests throttled since the client was first seen
+    pub throttled: u64,
+}
+
+/// Header set on `/v1/transactions` responses while the agent's storage is
+/// degraded, see `HealthDetails`
+pub const DEGRADED_HEADER: &str = "corro-degraded";
+
+/// Header set on `/v1/queries` responses to queries made w/ `cache_ttl_ms`:
+/// `hit` when the result came from the agent's query cache, `miss` otherwise
+pub const QUERY_CACHE_HEADER: &str = "corro-query-cache";
+
+/// Header set on every API response w/ the agent's schema version, which
+/// changes whenever its schema does, restarts included
+pub const SCHEMA_VERSION_HEADER: &str = "corro-schema-version";
+
+/// The agent's schema, as returned by `GET /v1/schema`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SchemaResponse {
+    /// Schema version the tables are at, see `SCHEMA_VERSION_HEADER`
+    pub version: u64,
+    pub tables: Vec<TableSchema>,
+}
+
+/// Current schema version, as returned by `GET /v1/schema/version`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SchemaVersion {
+    pub version: u64,
+}
+
+/// A replicated table of the agent's schema
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TableSchema {
+    pub name: TableName,
+    /// Columns in the order they're declared
+    pub columns: Vec<ColumnSchema>,
+    /// Primary key columns, in the key's order
+    pub pk: Vec<ColumnName>,
+}
+
+impl TableSchema {
+    /// Looks up a column ignoring ASCII case, like sqlite does
+    pub fn column(&self, name: &str) -> Option<&ColumnSchema> {
+        self.columns
+            .iter()
+            .find(|col| col.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ColumnSchema {
+    pub name: ColumnName,
+    /// Declared type, uppercased, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub declared_type: Option<String>,
+    pub nullable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_value: Option<String>,
+    /// Generated columns can't be written to
+    #[serde(default)]
+    pub generated: bool,
+}
+
+/// Storage health of an agent, as returned by `GET /v1/health`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HealthDetails {
+    pub db_size_bytes: u64,
+    pub wal_size_bytes: u64,
+    /// Space available to the agent on the database's filesystem
+    pub free_disk_bytes: u64,
+    pub page_cache: PageCacheStats,
+    /// Whether a configured threshold was crossed
+    pub degraded: bool,
+    /// Thresholds which were crossed, if degraded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reasons: Vec<String>,
+    /// When the agent started, in milliseconds since the epoch. Changes
+    /// whenever the agent restarts, 0 if unknown.
+    #[serde(default)]
+    pub started_at: u64,
+    /// When the WAL was last checkpointed and truncated, in milliseconds
+    /// since the epoch. 0 if it wasn't since the agent started.
+    #[serde(default)]
+    pub last_checkpoint_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PageCacheStats {
+    pub page_size: u64,
+    pub page_count: u64,
+    /// Unused pages, reclaimable by a vacuum
+    pub freelist_count: u64,
+    /// Max size of a connection's page cache
+    pub cache_size_bytes: u64,
+}
+
+/// Outcome of a WAL checkpoint, as returned by `POST /v1/db/checkpoint`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CheckpointResult {
+    /// Whether readers kept the WAL from being truncated
+    pub busy: bool,
+    /// Frames in the WAL before the checkpoint
+    pub wal_frames: i64,
+    /// Frames written back to the database
+    pub checkpointed_frames: i64,
+    /// Size of the WAL file after the checkpoint
+    pub wal_size_bytes: u64,
+    /// Subscriptions which held a read snapshot at the time, the likely
+    /// culprits if busy
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub held_snapshots: Vec<HeldSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HeldSnapshot {
+    pub subscription_id: Uuid,
+    /// How long it's been held for
+    pub held_secs: f64,
+}
+
+/// How long changes from each peer took to be applied locally, as returned by
+/// `GET /v1/cluster/latency`. Measured from the heartbeats nodes write to the
+/// `__corro_probe` table when `gossip.probe` is configured.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ProbeLatencies {
+    /// Whether this node writes heartbeats and records its peers'
+    pub enabled: bool,
+    pub peers: Vec<PeerLatency>,
+}
+
+/// Propagation latency of a peer's heartbeats, over its last samples.
+/// Computed from wall clocks, so off by however far both nodes' clocks are.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PeerLatency {
+    pub site_id: SiteId,
+    /// Samples the quantiles are computed over
+    pub samples: u64,
+    /// Last heartbeat counter received
+    pub counter: i64,
+    /// Latencies, in seconds
+    pub p50: f64,
+    pub p99: f64,
+    pub max: f64,
+    /// When the last heartbeat was applied, in milliseconds since the epoch
+    pub last_seen_at: u64,
+}
+
+/// A peer of the node listing it, as returned by `GET /v1/cluster/members`:
+/// what the node knows of it from gossip, and how far behind the node is on
+/// the changes it originated.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MemberState {
+    pub site_id: SiteId,
+    /// Gossip address
+    pub addr: SocketAddr,
+    /// When the peer was last heard from over gossip, in milliseconds since
+    /// the epoch
+    pub last_seen: u64,
+    /// Round-trip time bucket, 0 for the closest peers, unset until measured
+    #[serde(default)]
+    pub ring: Option<u8>,
+    /// Highest version of the peer's changes the node knows of, even if it
+    /// misses some before it. Unset when it knows of none.
+    #[serde(default)]
+    pub max_db_version_known: Option<i64>,
+    /// Whether the node is syncing w/ the peer right now
+    pub sync_in_flight: bool,
+    /// Versions of the peer's changes the node still needs
+    pub needed: NeededVersions,
+}
+
+/// Versions of a peer's changes a node misses, up to the highest it knows of
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NeededVersions {
+    /// Versions missing entirely
+    pub count: u64,
+    /// Lowest and highest versions missing entirely, unset if none is
+    #[serde(default)]
+    pub min: Option<i64>,
+    #[serde(default)]
+    pub max: Option<i64>,
+    /// Versions only partly received, w/ some of their changes missing
+    #[serde(default)]
+    pub partial: u64,
+}
+
+impl NeededVersions {
+    pub fn is_caught_up(&self) -> bool {
+        self.count == 0 && self.partial == 0
+    }
+}
+
+/// What a subscription or `/v1/transactions` client consumed over an hour,
+/// listed by `GET /v1/usage`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct UsageBucket {
+    /// `sub:` followed by the subscription's id, or the client's identity as
+    /// listed by `GET /v1/quotas`
+    pub identity: String,
+    /// Unix timestamp in seconds of the start of the hour
+    pub hour: u64,
+    /// Rows streamed to subscribers
+    pub rows: u64,
+    /// Change events delivered to subscribers
+    pub changes: u64,
+    /// Bytes of the rows and change events serialized for subscribers, or of
+    /// the changes generated by transactions
+    pub bytes: u64,
+    /// Statements executed by committed transactions
+    pub statements: u64,
+    /// Changes generated by committed transactions
+    pub changes_generated: u64,
+}
+
+/// Effective config of an agent, as returned by `GET /v1/config`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConfigResponse {
+    /// Config file the agent loaded, unset if it was configured in-process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Unix timestamp in milliseconds of the last (re)load of the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loaded_at: Option<u64>,
+    /// Hash of the file's raw contents when loaded, compare w/ the file on
+    /// disk to tell whether it changed since
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Settings after defaults and environment overrides, w/ tokens and
+    /// keys redacted
+    pub config: serde_json::Value,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize, Readable, Writable, PartialEq)]
+/// Events of a `/v1/transactions` request w/ `stream_returning` set, sent as
+/// newline-delimited JSON.
+///
+/// Statements returning rows produce `Columns`, `Row`s and `EndOfQuery`,
+/// other statements a single `Execute`. The transaction only commits once all
+/// statements ran: rows are streamed before that, so if an `Error` comes
+/// later, everything streamed so far was rolled back. `Commit` is always the
+/// last event of a successful transaction, or `DryRun` for a dry run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecEvent {
+    Columns(Vec<CompactString>),
+    Row(RowId, Vec<SqliteValue>),
+    #[serde(rename = "eoq")]
+    EndOfQuery {
+        time: f64,
+        rows: usize,
+    },
+    Execute {
+        rows_affected: usize,
+        time: f64,
+    },
+    Error(CompactString),
+    Commit {
+        time: f64,
+    },
+    /// Ends a dry run instead of `Commit`, the transaction was rolled back
+    DryRun {
+        time: f64,
+    },
+}
+
+/// What became of a batch of changes received from other nodes
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// Changesets applied, or buffered if they were incomplete
+    pub applied: usize,
+    /// Changesets skipped because their versions were already known
+    pub skipped_known: usize,
+    /// Tables of the changesets which could not be applied, and why. These
+    /// versions weren't booked and will be synced again.
+    pub rejected: Vec<(TableName, String)>,
+    /// Highest version seen in the batch, applied or not
+    pub max_db_version_seen: Option<i64>,
+    /// Changes of the changesets applied, by what they do to their rows
+    #[serde(default)]
+    pub kinds: sqlite::ChangeKindCounts,
+}
+
+impl ApplyReport {
+    pub fn is_empty(&self) -> bool {
+        self.applied == 0 && self.skipped_known == 0 && self.rejected.is_empty()
+    }
+}
+
+/// Result of `EXPLAIN QUERY PLAN` for a statement
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub steps: Vec<QueryPlanStep>,
+    /// Tables fully scanned by the plan which have at least the configured
+    /// threshold of rows
+    pub large_scans: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueryPlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+    pub uses_index: bool,
+    /// Table fully scanned by this step (even through an index), if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scanned_table: Option<String>,
+}
+
+impl QueryPlanStep {
+    pub fn new(id: i64, parent: i64, detail: String) -> Self {
+        let uses_index = [
+            "USING INDEX",
+            "USING COVERING INDEX",
+            "PRIMARY KEY",
+            "USING ROWID",
+        ]
+        .iter()
+        .any(|needle| detail.contains(needle));
+
+        let scanned_table = detail
+            .strip_prefix("SCAN ")
+            .map(|rest| rest.strip_prefix("TABLE ").unwrap_or(rest))
+            .and_then(|rest| rest.split_whitespace().next())
+            .filter(|table| !matches!(*table, "CONSTANT" | "SUBQUERY") && !table.starts_with('('))
+            .map(|table| table.trim_matches('"').to_owned());
+
+        Self {
+            id,
+            parent,
+            detail,
+            uses_index,
+            scanned_table,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Change {
     pub table: TableName,
     pub pk: Vec<u8>,
@@ -170,15 +1372,28 @@ pub struct Change {
     pub col_version: i64,
     pub db_version: i64,
     pub seq: i64,
-    pub site_id: [u8; 16],
+    pub site_id: SiteId,
     pub cl: i64,
+    /// `val` compressed for transport, sent in its place
+    #[serde(skip)]
+    pub compressed: Option<CompressedValue>,
+    /// NTP64 timestamp of the change on its origin, when it was sent along
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ts: Option<u64>,
 }
 
 impl Change {
     // this is an ESTIMATE, it should give a rough idea of how many bytes will
     // be required on the wire
     pub fn estimated_byte_size(&self) -> usize {
-        self.table.len() + self.pk.len() + self.cid.len() + self.val.estimated_byte_size() +
+        let val_size = match &self.compressed {
+            Some(compressed) => compressed.estimated_byte_size(),
+            None => self.val.estimated_byte_size(),
+        };
+        let extensions_size = extensions::ChangeExtensions::of(self)
+            .map(|ext| ext.estimated_byte_size())
+            .unwrap_or_default();
+        self.table.len() + self.pk.len() + self.cid.len() + extensions_size + val_size +
         // col_version
         8 +
         // db_version
@@ -190,6 +1405,129 @@ impl Change {
         // cl
         8
     }
+
+    /// Checks the change could have come out of `crsql_changes`, before
+    /// trying to apply it
+    pub fn validate(&self) -> Result<(), InvalidChange> {
+        if self.table.is_empty() {
+            return Err(InvalidChange::EmptyTable);
+        }
+        if self.pk.is_empty() {
+            return Err(InvalidChange::EmptyPk);
+        }
+        if self.cid.is_empty() {
+            return Err(InvalidChange::EmptyCid);
+        }
+        if self.db_version < 1 {
+            return Err(InvalidChange::DbVersion(self.db_version));
+        }
+        if self.col_version < 1 {
+            return Err(InvalidChange::ColVersion(self.col_version));
+        }
+        if self.seq < 0 {
+            return Err(InvalidChange::Seq(self.seq));
+        }
+        if self.cl < 1 {
+            return Err(InvalidChange::CausalLength(self.cl));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidChange {
+    #[error("empty table name")]
+    EmptyTable,
+    #[error("empty primary key")]
+    EmptyPk,
+    #[error("empty column name")]
+    EmptyCid,
+    #[error("invalid db_version: {0}")]
+    DbVersion(i64),
+    #[error("invalid col_version: {0}")]
+    ColVersion(i64),
+    #[error("invalid seq: {0}")]
+    Seq(i64),
+    #[error("invalid causal length: {0}")]
+    CausalLength(i64),
+}
+
+// same layout as a derived impl, except `val` may be replaced by its
+// compressed form, and preceded by the extensions record
+impl<'a, C> Readable<'a, C> for Change
+where
+    C: Context,
+{
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        let table = TableName::read_from(reader)?;
+        let pk = Vec::<u8>::read_from(reader)?;
+        let cid = ColumnName::read_from(reader)?;
+        let mut tag = u8::read_from(reader)?;
+        let mut ext = extensions::ChangeExtensions::default();
+        if tag == extensions::TAG_EXTENSIONS {
+            ext = extensions::ChangeExtensions::read_tagged(reader)?;
+            tag = u8::read_from(reader)?;
+        }
+        let val = match tag {
+            compress::TAG_ZSTD_TEXT | compress::TAG_ZSTD_BLOB => {
+                CompressedValue::read_decompressed(tag, reader)?
+            }
+            tag => SqliteValue::read_tagged(tag, reader)?,
+        };
+
+        Ok(Change {
+            table,
+            pk,
+            cid,
+            val,
+            col_version: i64::read_from(reader)?,
+            db_version: i64::read_from(reader)?,
+            seq: i64::read_from(reader)?,
+            site_id: SiteId::read_from(reader)?,
+            cl: i64::read_from(reader)?,
+            compressed: None,
+            ts: ext.ts,
+        })
+    }
+}
+
+impl<C> Writable<C> for Change
+where
+    C: Context,
+{
+    fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
+        self.table.write_to(writer)?;
+        self.pk.write_to(writer)?;
+        self.cid.write_to(writer)?;
+        if let Some(ext) = extensions::ChangeExtensions::of(self) {
+            ext.write_to(writer)?;
+        }
+        match &self.compressed {
+            Some(compressed) => compressed.write_to(writer)?,
+            None => self.val.write_to(writer)?,
+        }
+        self.col_version.write_to(writer)?;
+        self.db_version.write_to(writer)?;
+        self.seq.write_to(writer)?;
+        self.site_id.write_to(writer)?;
+        self.cl.write_to(writer)
+    }
+
+    fn bytes_needed(&self) -> Result<usize, C::Error> {
+        Ok(Writable::<C>::bytes_needed(&self.table)?
+            + Writable::<C>::bytes_needed(&self.pk)?
+            + Writable::<C>::bytes_needed(&self.cid)?
+            + match extensions::ChangeExtensions::of(self) {
+                Some(ext) => ext.bytes_needed::<C>()?,
+                None => 0,
+            }
+            + match &self.compressed {
+                Some(compressed) => compressed.bytes_needed::<C>()?,
+                None => Writable::<C>::bytes_needed(&self.val)?,
+            }
+            + 8 * 4
+            + 16)
+    }
 }
 
 pub fn row_to_change(row: &Row) -> Result<Change, rusqlite::Error> {
@@ -203,6 +1541,8 @@ pub fn row_to_change(row: &Row) -> Result<Change, rusqlite::Error> {
         seq: row.get(6)?,
         site_id: row.get(7)?,
         cl: row.get(8)?,
+        compressed: None,
+        ts: None,
     })
 }
 
@@ -217,6 +1557,16 @@ pub enum SqliteValueRef<'a> {
 }
 
 impl<'a> SqliteValueRef<'a> {
+    pub fn column_type(&self) -> ColumnType {
+        match self {
+            SqliteValueRef::Null => ColumnType::Null,
+            SqliteValueRef::Integer(_) => ColumnType::Integer,
+            SqliteValueRef::Real(_) => ColumnType::Float,
+            SqliteValueRef::Text(_) => ColumnType::Text,
+            SqliteValueRef::Blob(_) => ColumnType::Blob,
+        }
+    }
+
     pub fn is_null(&self) -> bool {
         matches!(self, SqliteValueRef::Null)
     }
@@ -281,46 +1631,202 @@ impl ColumnType {
         })
     }
 
-    pub fn from_sqlite_name(s: &str) -> Option<Self> {
-        Some(match s {
-            "INTEGER" => Self::Integer,
-            "REAL" => Self::Float,
-            "TEXT" => Self::Text,
-            "BLOB" => Self::Blob,
-            _ => return None,
-        })
+    pub fn from_sqlite_name(s: &str) -> Option<Self> {
+        Some(match s {
+            "INTEGER" => Self::Integer,
+            "REAL" => Self::Float,
+            "TEXT" => Self::Text,
+            "BLOB" => Self::Blob,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColumnType::Integer => "INTEGER",
+            ColumnType::Float => "REAL",
+            ColumnType::Text => "TEXT",
+            ColumnType::Blob => "BLOB",
+            ColumnType::Null => "NULL",
+        })
+    }
+}
+
+impl FromSql for ColumnType {
+    fn column_result(value: ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(s) => Ok(match String::from_utf8_lossy(s).as_ref() {
+                "INTEGER" => Self::Integer,
+                "REAL" => Self::Float,
+                "TEXT" => Self::Text,
+                "BLOB" => Self::Blob,
+                _ => {
+                    return Err(FromSqlError::InvalidType);
+                }
+            }),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A statement parameter.
+///
+/// Deserialized from any JSON value: numbers as integers or reals (see
+/// `SqliteValue` for out of range ones), arrays of bytes as blobs and other
+/// arrays or objects as `Json`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(untagged)]
+pub enum SqliteParam {
+    #[default]
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Real(f64),
+    Text(CompactString),
+    Blob(SmallVec<[u8; 512]>),
+    Json(Box<RawValue>),
+}
+
+impl<'de> Deserialize<'de> for SqliteParam {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ParamVisitor { lossy: false })
+    }
+}
+
+impl SqliteParam {
+    /// The param as a SQL literal evaluating to what it binds, see
+    /// `SqliteValue::to_sql_literal`. Booleans are integers and JSON is text.
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            SqliteParam::Null => "NULL".into(),
+            SqliteParam::Bool(b) => (*b as i64).to_string(),
+            SqliteParam::Integer(i) => audit::integer_literal(*i),
+            SqliteParam::Real(f) => audit::real_literal(*f),
+            SqliteParam::Text(s) => audit::text_literal(s),
+            SqliteParam::Blob(b) => audit::blob_literal(b),
+            SqliteParam::Json(json) => audit::text_literal(json.get()),
+        }
+    }
+
+    /// Like `deserialize`, except integers beyond `i64`'s range become text
+    /// instead of failing. Meant for `#[serde(deserialize_with)]`.
+    pub fn deserialize_lossy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ParamVisitor { lossy: true })
+    }
+}
+
+impl From<Numeric> for SqliteParam {
+    fn from(value: Numeric) -> Self {
+        match value {
+            Numeric::Integer(i) => SqliteParam::Integer(i),
+            Numeric::Real(f) => SqliteParam::Real(f),
+            Numeric::Text(s) => SqliteParam::Text(s),
+        }
+    }
+}
+
+struct ParamVisitor {
+    lossy: bool,
+}
+
+impl<'de> Visitor<'de> for ParamVisitor {
+    type Value = SqliteParam;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sqlite param")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Integer(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Numeric::integer(v, self.lossy).map(SqliteParam::from)
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<Self::Value, E> {
+        Numeric::integer(v, self.lossy).map(SqliteParam::from)
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
+        Numeric::integer(v, self.lossy).map(SqliteParam::from)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Numeric::real(v).map(SqliteParam::from)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Text(v.into()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Text(v.into()))
     }
-}
 
-impl FromSql for ColumnType {
-    fn column_result(value: ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
-        match value {
-            ValueRef::Text(s) => Ok(match String::from_utf8_lossy(s).as_ref() {
-                "INTEGER" => Self::Integer,
-                "REAL" => Self::Float,
-                "TEXT" => Self::Text,
-                "BLOB" => Self::Blob,
-                _ => {
-                    return Err(FromSqlError::InvalidType);
-                }
-            }),
-            _ => Err(FromSqlError::InvalidType),
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(SqliteParam::Blob(v.into()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values: Vec<serde_json::Value> = vec![];
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+
+        // arrays of bytes are blobs, anything else is JSON
+        let bytes: Option<SmallVec<[u8; 512]>> = values
+            .iter()
+            .map(|value| value.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect();
+        match bytes {
+            Some(bytes) => Ok(SqliteParam::Blob(bytes)),
+            None => serde_json::value::to_raw_value(&values)
+                .map(SqliteParam::Json)
+                .map_err(de::Error::custom),
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut object = serde_json::Map::new();
+        while let Some((key, value)) = map.next_entry::<String, serde_json::Value>()? {
+            object.insert(key, value);
         }
+        serde_json::value::to_raw_value(&object)
+            .map(SqliteParam::Json)
+            .map_err(de::Error::custom)
     }
 }
 
-#[allow(clippy::large_enum_variant)]
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum SqliteParam {
-    #[default]
-    Null,
-    Bool(bool),
-    Integer(i64),
-    Real(f64),
-    Text(CompactString),
-    Blob(SmallVec<[u8; 512]>),
-    Json(Box<RawValue>),
+impl From<SqliteValue> for SqliteParam {
+    fn from(value: SqliteValue) -> Self {
+        match value {
+            SqliteValue::Null => Self::Null,
+            SqliteValue::Integer(i) => Self::Integer(i),
+            SqliteValue::Real(f) => Self::Real(f.0),
+            SqliteValue::Text(s) => Self::Text(s),
+            SqliteValue::Blob(b) => Self::Blob(b),
+        }
+    }
 }
 
 impl From<&str> for SqliteParam {
@@ -379,8 +1885,23 @@ impl<'a> ToSql for SqliteValueRef<'a> {
     }
 }
 
+/// A sqlite value.
+///
+/// JSON numbers are deserialized as sqlite would store them, w/o silently
+/// losing precision: integers outside of `i64`'s range fail (unless
+/// deserialized w/ `deserialize_lossy`) and so do integral reals outside of
+/// it, instead of becoming rounded reals.
+///
+/// ```
+/// use corro_api_types::SqliteValue;
+///
+/// let value: SqliteValue = serde_json::from_str("9223372036854775807").unwrap();
+/// assert_eq!(value, SqliteValue::Integer(i64::MAX));
+/// assert!(serde_json::from_str::<SqliteValue>("9223372036854775808").is_err());
+/// assert!(serde_json::from_str::<SqliteValue>("1e20").is_err());
+/// ```
 #[allow(clippy::large_enum_variant)]
-#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Hash)]
+#[derive(Debug, Default, Clone, Serialize, PartialEq, Hash)]
 #[serde(untagged)]
 pub enum SqliteValue {
     #[default]
@@ -391,6 +1912,132 @@ pub enum SqliteValue {
     Blob(SmallVec<[u8; 512]>),
 }
 
+impl<'de> Deserialize<'de> for SqliteValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor { lossy: false })
+    }
+}
+
+impl SqliteValue {
+    /// Like `deserialize`, except integers beyond `i64`'s range become text
+    /// instead of failing. Meant for `#[serde(deserialize_with)]`.
+    pub fn deserialize_lossy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor { lossy: true })
+    }
+}
+
+impl From<Numeric> for SqliteValue {
+    fn from(value: Numeric) -> Self {
+        match value {
+            Numeric::Integer(i) => SqliteValue::Integer(i),
+            Numeric::Real(f) => SqliteValue::Real(Real(f)),
+            Numeric::Text(s) => SqliteValue::Text(s),
+        }
+    }
+}
+
+/// A JSON number, as sqlite stores it
+#[derive(Debug, Clone, PartialEq)]
+enum Numeric {
+    Integer(i64),
+    Real(f64),
+    /// An integer beyond `i64`, deserialized lossily
+    Text(CompactString),
+}
+
+// `i64::MAX + 1`, the first real beyond `i64` (`i64::MAX` itself isn't
+// representable, it rounds up to this)
+const I64_END: f64 = 9223372036854775808.0;
+
+impl Numeric {
+    fn integer<I, E>(v: I, lossy: bool) -> Result<Self, E>
+    where
+        I: TryInto<i64> + fmt::Display + Copy,
+        E: de::Error,
+    {
+        match v.try_into() {
+            Ok(i) => Ok(Numeric::Integer(i)),
+            Err(_) if lossy => Ok(Numeric::Text(v.to_compact_string())),
+            Err(_) => Err(E::custom(format_args!(
+                "integer {v} is out of range of 64-bit signed integers"
+            ))),
+        }
+    }
+
+    fn real<E: de::Error>(v: f64) -> Result<Self, E> {
+        if v.fract() == 0.0 && !(-I64_END..I64_END).contains(&v) {
+            return Err(E::custom(format_args!(
+                "integral number {v} is out of range of 64-bit signed integers"
+            )));
+        }
+        Ok(Numeric::Real(v))
+    }
+}
+
+struct ValueVisitor {
+    lossy: bool,
+}
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = SqliteValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("null, a number, a string or an array of bytes")
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(SqliteValue::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(SqliteValue::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(SqliteValue::Integer(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Numeric::integer(v, self.lossy).map(SqliteValue::from)
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<Self::Value, E> {
+        Numeric::integer(v, self.lossy).map(SqliteValue::from)
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
+        Numeric::integer(v, self.lossy).map(SqliteValue::from)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Numeric::real(v).map(SqliteValue::from)
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(SqliteValue::Text(v.into()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        Ok(SqliteValue::Text(v.into()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(SqliteValue::Blob(v.into()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut blob = SmallVec::new();
+        while let Some(b) = seq.next_element::<u8>()? {
+            blob.push(b);
+        }
+        Ok(SqliteValue::Blob(blob))
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
 #[serde(transparent)]
 pub struct Real(pub f64);
@@ -434,6 +2081,18 @@ impl SqliteValue {
         }
     }
 
+    /// The value as a SQL literal evaluating to it, e.g. `'it''s'` or
+    /// `X'00FF'`. NaN, stored as NULL by sqlite, is rendered as `NULL`.
+    pub fn to_sql_literal(&self) -> String {
+        match self {
+            SqliteValue::Null => "NULL".into(),
+            SqliteValue::Integer(i) => audit::integer_literal(*i),
+            SqliteValue::Real(f) => audit::real_literal(f.0),
+            SqliteValue::Text(s) => audit::text_literal(s),
+            SqliteValue::Blob(b) => audit::blob_literal(b),
+        }
+    }
+
     pub fn as_str(&self) -> Option<&str> {
         if let Self::Text(ref s) = self {
             Some(s)
@@ -484,6 +2143,32 @@ impl SqliteValue {
         }
     }
 
+    /// The whole blob, hex-encoded
+    pub fn to_hex(&self) -> Option<String> {
+        self.as_blob().map(hex::encode)
+    }
+
+    /// The whole blob, base64-encoded
+    pub fn to_base64(&self) -> Option<String> {
+        self.as_blob().map(|b| BASE64.encode(b))
+    }
+
+    pub fn blob_from_hex(s: &str) -> Result<Self, BlobDecodeError> {
+        Ok(SqliteValue::Blob(hex::decode(s)?.into()))
+    }
+
+    pub fn blob_from_base64(s: &str) -> Result<Self, BlobDecodeError> {
+        Ok(SqliteValue::Blob(BASE64.decode(s)?.into()))
+    }
+
+    /// Writes the value like its `Display` impl, w/o truncating blobs
+    pub fn fmt_full<W: Write>(&self, w: &mut W) -> fmt::Result {
+        match self {
+            SqliteValue::Blob(v) => write!(w, "x'{}'", hex::encode(v)),
+            v => write!(w, "{v}"),
+        }
+    }
+
     pub fn estimated_byte_size(&self) -> usize {
         1 + match self {
             SqliteValue::Null => 1,
@@ -525,22 +2210,62 @@ impl From<i64> for SqliteValue {
     }
 }
 
-impl FromSql for SqliteValue {
-    fn column_result(value: ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+impl From<Value> for SqliteValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => SqliteValue::Null,
+            Value::Integer(i) => SqliteValue::Integer(i),
+            Value::Real(f) => SqliteValue::Real(Real(f)),
+            Value::Text(t) => SqliteValue::Text(t.into()),
+            Value::Blob(b) => SqliteValue::Blob(b.into()),
+        }
+    }
+}
+
+impl From<SqliteValue> for Value {
+    fn from(value: SqliteValue) -> Self {
+        match value {
+            SqliteValue::Null => Value::Null,
+            SqliteValue::Integer(i) => Value::Integer(i),
+            SqliteValue::Real(f) => Value::Real(f.0),
+            SqliteValue::Text(t) => Value::Text(t.into()),
+            SqliteValue::Blob(b) => Value::Blob(b.into_vec()),
+        }
+    }
+}
+
+impl<'a> TryFrom<ValueRef<'a>> for SqliteValueRef<'a> {
+    type Error = std::str::Utf8Error;
+
+    fn try_from(value: ValueRef<'a>) -> Result<Self, Self::Error> {
         Ok(match value {
-            ValueRef::Null => SqliteValue::Null,
-            ValueRef::Integer(i) => SqliteValue::Integer(i),
-            ValueRef::Real(f) => SqliteValue::Real(Real(f)),
-            ValueRef::Text(t) => SqliteValue::Text(
-                std::str::from_utf8(t)
-                    .map_err(|e| FromSqlError::Other(Box::new(e)))?
-                    .into(),
-            ),
-            ValueRef::Blob(b) => SqliteValue::Blob(b.into()),
+            ValueRef::Null => SqliteValueRef::Null,
+            ValueRef::Integer(i) => SqliteValueRef::Integer(i),
+            ValueRef::Real(f) => SqliteValueRef::Real(f),
+            ValueRef::Text(t) => SqliteValueRef::Text(std::str::from_utf8(t)?),
+            ValueRef::Blob(b) => SqliteValueRef::Blob(b),
         })
     }
 }
 
+/// Converts a whole row or parameter list of rusqlite values
+pub fn from_rusqlite_values<I: IntoIterator<Item = Value>>(values: I) -> Vec<SqliteValue> {
+    values.into_iter().map(SqliteValue::from).collect()
+}
+
+/// Converts a whole row or parameter list into rusqlite values
+pub fn to_rusqlite_values<I: IntoIterator<Item = SqliteValue>>(values: I) -> Vec<Value> {
+    values.into_iter().map(Value::from).collect()
+}
+
+impl FromSql for SqliteValue {
+    fn column_result(value: ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        SqliteValueRef::try_from(value)
+            .map(|value| value.to_owned())
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
 impl ToSql for SqliteValue {
     fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
         Ok(match self {
@@ -553,6 +2278,18 @@ impl ToSql for SqliteValue {
     }
 }
 
+/// Blobs longer than this (in bytes) are truncated by `SqliteValue`'s
+/// `Display` impl, so logging a row can't produce a huge line
+pub const BLOB_DISPLAY_MAX_BYTES: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlobDecodeError {
+    #[error("invalid hex blob: {0}")]
+    Hex(#[from] hex::FromHexError),
+    #[error("invalid base64 blob: {0}")]
+    Base64(#[from] base64::DecodeError),
+}
+
 impl fmt::Display for SqliteValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -560,6 +2297,12 @@ impl fmt::Display for SqliteValue {
             SqliteValue::Integer(v) => v.fmt(f),
             SqliteValue::Real(v) => v.fmt(f),
             SqliteValue::Text(v) => v.fmt(f),
+            SqliteValue::Blob(v) if v.len() > BLOB_DISPLAY_MAX_BYTES => write!(
+                f,
+                "x'{}…' ({} bytes)",
+                hex::encode(&v[..BLOB_DISPLAY_MAX_BYTES]),
+                v.len()
+            ),
             SqliteValue::Blob(v) => {
                 f.write_str("x'")?;
                 f.write_str(&hex::encode(v))?;
@@ -575,7 +2318,23 @@ where
 {
     #[inline]
     fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
-        Ok(match u8::read_from(reader)? {
+        let tag = u8::read_from(reader)?;
+        Self::read_tagged(tag, reader)
+    }
+
+    #[inline]
+    fn minimum_bytes_needed() -> usize {
+        1
+    }
+}
+
+impl SqliteValue {
+    // reads the value following its already read type tag
+    fn read_tagged<'a, C: Context, R: Reader<'a, C>>(
+        tag: u8,
+        reader: &mut R,
+    ) -> Result<Self, C::Error> {
+        Ok(match tag {
             0 => SqliteValue::Null,
             1 => SqliteValue::Integer(i64::read_from(reader)?),
             2 => SqliteValue::Real(Real(f64::read_from(reader)?)),
@@ -588,20 +2347,12 @@ where
             }
             4 => {
                 let len = reader.read_u32()? as usize;
-                let mut vec = SmallVec::with_capacity(len);
-
-                reader.read_bytes(&mut vec)?;
 
-                SqliteValue::Blob(vec)
+                SqliteValue::Blob(reader.read_vec(len)?.into())
             }
             _ => return Err(speedy::Error::custom("unknown SqliteValue variant").into()),
         })
     }
-
-    #[inline]
-    fn minimum_bytes_needed() -> usize {
-        1
-    }
 }
 
 impl<C> Writable<C> for SqliteValue
@@ -651,6 +2402,56 @@ pub struct TableName(pub CompactString);
 #[serde(transparent)]
 pub struct ColumnName(pub CompactString);
 
+/// Borrowed `TableName`, deserialized w/o allocating when possible
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct TableNameRef<'a>(#[serde(borrow)] pub Cow<'a, str>);
+
+/// Borrowed `ColumnName`, deserialized w/o allocating when possible
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct ColumnNameRef<'a>(#[serde(borrow)] pub Cow<'a, str>);
+
+impl TableNameRef<'_> {
+    pub fn into_owned(self) -> TableName {
+        TableName(CompactString::new(self.0))
+    }
+}
+
+impl<'a> From<&'a TableName> for TableNameRef<'a> {
+    fn from(name: &'a TableName) -> Self {
+        Self(Cow::Borrowed(name.0.as_str()))
+    }
+}
+
+impl Deref for TableNameRef<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl ColumnNameRef<'_> {
+    pub fn into_owned(self) -> ColumnName {
+        ColumnName(CompactString::new(self.0))
+    }
+}
+
+impl<'a> From<&'a ColumnName> for ColumnNameRef<'a> {
+    fn from(name: &'a ColumnName) -> Self {
+        Self(Cow::Borrowed(name.0.as_str()))
+    }
+}
+
+impl Deref for ColumnNameRef<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 impl Deref for TableName {
     type Target = CompactString;
 
@@ -705,6 +2506,40 @@ impl Deref for ColumnName {
     }
 }
 
+impl ColumnName {
+    /// Compares names the way sqlite compares identifiers, ignoring ASCII case
+    pub fn eq_ignore_ascii_case(&self, other: &str) -> bool {
+        self.0.eq_ignore_ascii_case(other)
+    }
+
+    /// Borrows the name as a key ignoring ASCII case
+    pub fn case_insensitive(&self) -> CaseInsensitive<&str> {
+        CaseInsensitive(self.0.as_str())
+    }
+}
+
+/// Wraps a name so it compares and hashes ignoring ASCII case, like sqlite
+/// identifiers. Meant for map keys, e.g. `HashMap<CaseInsensitive<CompactString>, _>`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaseInsensitive<S>(pub S);
+
+impl<S: AsRef<str>> PartialEq for CaseInsensitive<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_ref().eq_ignore_ascii_case(other.0.as_ref())
+    }
+}
+
+impl<S: AsRef<str>> Eq for CaseInsensitive<S> {}
+
+impl<S: AsRef<str>> Hash for CaseInsensitive<S> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for b in self.0.as_ref().bytes() {
+            state.write_u8(b.to_ascii_lowercase());
+        }
+        state.write_u8(0xff);
+    }
+}
+
 impl<C> Writable<C> for ColumnName
 where
     C: Context,
@@ -747,6 +2582,154 @@ impl ToSql for ColumnName {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_query_event_ref_mirrors_query_event() {
+        let names: Vec<CompactString> = vec!["id".into(), "text".into()];
+        let events = [
+            (
+                QueryEventRef::columns(&names),
+                QueryEvent::columns(names.clone()),
+            ),
+            (
+                QueryEventRef::Columns {
+                    names: names
+                        .iter()
+                        .map(|name| ColumnNameRef(Cow::Borrowed(name.as_str())))
+                        .collect(),
+                    schema_generation: 2,
+                },
+                QueryEvent::Columns {
+                    names: names.clone(),
+                    schema_generation: 2,
+                },
+            ),
+            (
+                QueryEventRef::Row(RowId(1), vec![SqliteValue::Integer(1)]),
+                QueryEvent::Row(RowId(1), vec![SqliteValue::Integer(1)]),
+            ),
+            (
+                QueryEventRef::EndOfQuery {
+                    time: 0.5,
+                    change_id: None,
+                    coerced: vec!["n".into()],
+                },
+                QueryEvent::EndOfQuery {
+                    time: 0.5,
+                    change_id: None,
+                    coerced: vec!["n".into()],
+                },
+            ),
+            (
+                QueryEventRef::Skipped {
+                    change_id: ChangeId(3),
+                },
+                QueryEvent::Skipped {
+                    change_id: ChangeId(3),
+                },
+            ),
+            (
+                QueryEventRef::Origin(ChangeOrigin {
+                    change_id: ChangeId(4),
+                    site_id: SiteId([1; 16]),
+                    node_name: Some("node-a".into()),
+                }),
+                QueryEvent::Origin(ChangeOrigin {
+                    change_id: ChangeId(4),
+                    site_id: SiteId([1; 16]),
+                    node_name: Some("node-a".into()),
+                }),
+            ),
+            (
+                QueryEventRef::NextResultSet { index: 1 },
+                QueryEvent::NextResultSet { index: 1 },
+            ),
+            (
+                QueryEventRef::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: vec![CAPABILITY_BATCH.into()],
+                    sub_id: Some(Uuid::nil()),
+                },
+                QueryEvent::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: vec![CAPABILITY_BATCH.into()],
+                    sub_id: Some(Uuid::nil()),
+                },
+            ),
+            (
+                QueryEventRef::Error("nope".into()),
+                QueryEvent::Error("nope".into()),
+            ),
+        ];
+        assert_eq!(
+            serde_json::to_string(&QueryEvent::Skipped {
+                change_id: ChangeId(3)
+            })
+            .unwrap(),
+            r#"{"skipped":{"change_id":3}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&QueryEvent::Origin(ChangeOrigin {
+                change_id: ChangeId(4),
+                site_id: SiteId([1; 16]),
+                node_name: None,
+            }))
+            .unwrap(),
+            r#"{"origin":{"change_id":4,"site_id":"01010101-0101-0101-0101-010101010101","node_name":null}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&QueryEvent::NextResultSet { index: 1 }).unwrap(),
+            r#"{"next_result_set":{"index":1}}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&QueryEvent::Hello {
+                protocol_version: 1,
+                capabilities: vec!["batch".into(), "origin".into()],
+                sub_id: None,
+            })
+            .unwrap(),
+            r#"{"hello":{"protocol_version":1,"capabilities":["batch","origin"]}}"#
+        );
+
+        for (borrowed, owned) in events {
+            let json = serde_json::to_string(&borrowed).unwrap();
+            assert_eq!(json, serde_json::to_string(&owned).unwrap());
+            let parsed: QueryEventRef = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed.into_owned(), owned);
+        }
+
+        // the first generation's columns are serialized as they always were
+        assert_eq!(
+            serde_json::to_string(&QueryEvent::columns(names.clone())).unwrap(),
+            r#"{"columns":["id","text"]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&QueryEvent::Columns {
+                names: names.clone(),
+                schema_generation: 2
+            })
+            .unwrap(),
+            r#"{"columns":{"names":["id","text"],"schema_generation":2}}"#
+        );
+
+        let json = r#"{"columns":["id","te\"xt"]}"#;
+        let QueryEventRef::Columns { names: cols, .. } = serde_json::from_str(json).unwrap() else {
+            panic!("expected columns");
+        };
+        assert!(matches!(&cols[0].0, Cow::Borrowed("id")));
+        // unescaping needs an allocation
+        assert!(matches!(&cols[1].0, Cow::Owned(name) if name == "te\"xt"));
+        assert_eq!(cols[1].clone().into_owned(), ColumnName("te\"xt".into()));
+    }
+
+    #[test]
+    fn test_parse_capabilities() {
+        assert_eq!(
+            parse_capabilities(" batch,origin , ,batch,"),
+            [CAPABILITY_BATCH, CAPABILITY_ORIGIN]
+        );
+        assert!(parse_capabilities("").is_empty());
+    }
+
     #[test]
     fn test_statement_serialization() {
         let s = serde_json::to_string(&vec![Statement::WithParams(
@@ -777,4 +2760,281 @@ mod tests {
         let stmts: Vec<Statement> = serde_json::from_str(json).unwrap();
         println!("stmts: {stmts:?}");
     }
+
+    #[test]
+    fn test_blob_display_and_encodings() {
+        let small = SqliteValue::Blob(vec![0xab, 0xcd].into());
+        assert_eq!(small.to_string(), "x'abcd'");
+        assert_eq!(small.to_base64().as_deref(), Some("q80="));
+
+        let large = SqliteValue::Blob(vec![0xab; 1024 * 1024].into());
+        assert_eq!(
+            large.to_string(),
+            format!(
+                "x'{}…' (1048576 bytes)",
+                "ab".repeat(BLOB_DISPLAY_MAX_BYTES)
+            )
+        );
+
+        let mut full = String::new();
+        large.fmt_full(&mut full).unwrap();
+        assert_eq!(full, format!("x'{}'", large.to_hex().unwrap()));
+
+        for blob in [small, large] {
+            assert_eq!(
+                SqliteValue::blob_from_hex(&blob.to_hex().unwrap()).unwrap(),
+                blob
+            );
+            assert_eq!(
+                SqliteValue::blob_from_base64(&blob.to_base64().unwrap()).unwrap(),
+                blob
+            );
+        }
+
+        assert!(SqliteValue::Text("ab".into()).to_hex().is_none());
+        assert!(SqliteValue::blob_from_hex("xyz").is_err());
+        assert!(SqliteValue::blob_from_base64("!!").is_err());
+    }
+
+    #[test]
+    fn test_rusqlite_value_conversions() {
+        let values = vec![
+            SqliteValue::Null,
+            SqliteValue::Integer(-42),
+            SqliteValue::Real(Real(1.5)),
+            SqliteValue::Text("hello 🌍".into()),
+            SqliteValue::Blob(vec![0, 1, 255].into()),
+        ];
+
+        let rusqlite_values = to_rusqlite_values(values.clone());
+        assert_eq!(
+            rusqlite_values,
+            vec![
+                Value::Null,
+                Value::Integer(-42),
+                Value::Real(1.5),
+                Value::Text("hello 🌍".into()),
+                Value::Blob(vec![0, 1, 255]),
+            ]
+        );
+        assert_eq!(from_rusqlite_values(rusqlite_values), values);
+
+        for value in values.iter() {
+            let value_ref = ValueRef::from(&Value::from(value.clone()));
+            assert_eq!(
+                SqliteValueRef::try_from(value_ref).unwrap().to_owned(),
+                *value
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_text() {
+        let invalid = [b'a', 0xff, 0xfe];
+        assert!(SqliteValueRef::try_from(ValueRef::Text(&invalid)).is_err());
+        assert!(SqliteValue::column_result(ValueRef::Text(&invalid)).is_err());
+    }
+
+    #[test]
+    fn test_json_numbers_out_of_range() {
+        #[derive(Deserialize)]
+        struct Lossy {
+            #[serde(deserialize_with = "SqliteValue::deserialize_lossy")]
+            value: SqliteValue,
+            #[serde(deserialize_with = "SqliteParam::deserialize_lossy")]
+            param: SqliteParam,
+        }
+
+        let value = |json: &str| serde_json::from_str::<SqliteValue>(json);
+        let param = |json: &str| serde_json::from_str::<SqliteParam>(json);
+
+        // 2^63 - 1
+        assert_eq!(
+            value("9223372036854775807").unwrap(),
+            SqliteValue::Integer(i64::MAX)
+        );
+        assert!(matches!(
+            param("9223372036854775807").unwrap(),
+            SqliteParam::Integer(i64::MAX)
+        ));
+        assert_eq!(
+            value("-9223372036854775808").unwrap(),
+            SqliteValue::Integer(i64::MIN)
+        );
+
+        // 2^63
+        for json in ["9223372036854775808", "18446744073709551615"] {
+            let e = value(json).unwrap_err();
+            assert!(e.to_string().contains("out of range"), "{e}");
+            let e = param(json).unwrap_err();
+            assert!(e.to_string().contains("out of range"), "{e}");
+        }
+        let lossy: Lossy =
+            serde_json::from_str(r#"{"value": 9223372036854775808, "param": 9223372036854775808}"#)
+                .unwrap();
+        assert_eq!(lossy.value, SqliteValue::Text("9223372036854775808".into()));
+        assert!(matches!(lossy.param, SqliteParam::Text(ref s) if s == "9223372036854775808"));
+
+        // integral reals beyond i64 are rejected, even in lossy mode
+        for json in ["1e20", "-1e20", "9223372036854775807.0"] {
+            assert!(value(json).is_err(), "{json}");
+            assert!(param(json).is_err(), "{json}");
+        }
+        assert!(serde_json::from_str::<Lossy>(r#"{"value": 1e20, "param": 1}"#).is_err());
+        assert_eq!(value("1.5").unwrap(), SqliteValue::Real(Real(1.5)));
+        assert_eq!(value("1e15").unwrap(), SqliteValue::Real(Real(1e15)));
+
+        // -0.0 keeps its sign
+        let SqliteValue::Real(zero) = value("-0.0").unwrap() else {
+            panic!("expected a real");
+        };
+        assert!(zero.0 == 0.0 && zero.is_sign_negative());
+        assert!(matches!(param("-0.0").unwrap(), SqliteParam::Real(f) if f.is_sign_negative()));
+
+        // other variants are unaffected
+        assert_eq!(value("null").unwrap(), SqliteValue::Null);
+        assert_eq!(value(r#""a""#).unwrap(), SqliteValue::Text("a".into()));
+        assert_eq!(
+            value("[1, 2]").unwrap(),
+            SqliteValue::Blob(vec![1u8, 2].into())
+        );
+        assert!(value("true").is_err());
+        assert!(matches!(param("true").unwrap(), SqliteParam::Bool(true)));
+        assert!(matches!(param("[1, 2]").unwrap(), SqliteParam::Blob(b) if b.as_slice() == [1, 2]));
+        assert!(
+            matches!(param(r#"{"a": [1, 256]}"#).unwrap(), SqliteParam::Json(raw) if raw.get() == r#"{"a":[1,256]}"#)
+        );
+        assert!(
+            matches!(param("[1, 256]").unwrap(), SqliteParam::Json(raw) if raw.get() == "[1,256]")
+        );
+    }
+
+    #[test]
+    fn test_json_and_speedy_round_trips_hash_alike() {
+        fn hash(value: &SqliteValue) -> u64 {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        for json in [
+            "null",
+            "9223372036854775807",
+            "-9223372036854775808",
+            "-0.0",
+            "0.1",
+            "1e15",
+            r#""text""#,
+            "[0, 255]",
+        ] {
+            let value: SqliteValue = serde_json::from_str(json).unwrap();
+
+            let from_json: SqliteValue =
+                serde_json::from_str(&serde_json::to_string(&value).unwrap()).unwrap();
+            let from_speedy =
+                SqliteValue::read_from_buffer(&value.write_to_vec().unwrap()).unwrap();
+
+            assert_eq!(hash(&from_json), hash(&value), "{json}");
+            assert_eq!(hash(&from_speedy), hash(&value), "{json}");
+        }
+    }
+
+    #[test]
+    fn test_query_plan_step() {
+        let step = QueryPlanStep::new(2, 0, "SCAN tests".into());
+        assert!(!step.uses_index);
+        assert_eq!(step.scanned_table.as_deref(), Some("tests"));
+
+        let step = QueryPlanStep::new(2, 0, "SCAN TABLE tests AS t".into());
+        assert_eq!(step.scanned_table.as_deref(), Some("tests"));
+
+        let step = QueryPlanStep::new(2, 0, "SEARCH tests USING PRIMARY KEY (id=?)".into());
+        assert!(step.uses_index);
+        assert_eq!(step.scanned_table, None);
+
+        // scanning a whole index still visits every row
+        let step = QueryPlanStep::new(2, 0, "SCAN tests USING COVERING INDEX idx".into());
+        assert!(step.uses_index);
+        assert_eq!(step.scanned_table.as_deref(), Some("tests"));
+
+        let step = QueryPlanStep::new(2, 0, "SCAN CONSTANT ROW".into());
+        assert_eq!(step.scanned_table, None);
+    }
+
+    #[test]
+    fn test_exec_request_serialization() {
+        let req: ExecRequest = serde_json::from_str(r#"["select 1", "select 2"]"#).unwrap();
+        assert!(matches!(req, ExecRequest::Statements(ref stmts) if stmts.len() == 2));
+
+        let req: ExecRequest = serde_json::from_str(
+            r#"{"statements": ["select 1", "select 2", "select 3"], "isolation": "statement", "groups": [2, 1]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            req,
+            ExecRequest::WithOptions {
+                isolation: ExecIsolation::Statement,
+                groups: Some(ref groups),
+                ..
+            } if groups == &[2, 1]
+        ));
+        assert_eq!(req.statements().len(), 3);
+
+        let req: ExecRequest = serde_json::from_str(r#"{"statements": ["select 1"]}"#).unwrap();
+        assert!(matches!(
+            req,
+            ExecRequest::WithOptions {
+                isolation: ExecIsolation::Transaction,
+                groups: None,
+                defer_foreign_keys: false,
+                ..
+            }
+        ));
+
+        let req =
+            ExecRequest::from(vec![Statement::Simple("select 1".into())]).defer_foreign_keys();
+        let json = serde_json::to_string(&req).unwrap();
+        assert_eq!(
+            json,
+            r#"{"statements":["select 1"],"isolation":"transaction","defer_foreign_keys":true}"#
+        );
+        assert!(matches!(
+            serde_json::from_str(&json).unwrap(),
+            ExecRequest::WithOptions {
+                defer_foreign_keys: true,
+                ..
+            }
+        ));
+
+        let req = ExecRequest::from(vec![Statement::Simple("select 1".into())]).report_changes();
+        assert_eq!(
+            serde_json::to_string(&req).unwrap(),
+            r#"{"statements":["select 1"],"isolation":"transaction","report_changes":true}"#
+        );
+
+        let req = ExecRequest::from(vec![Statement::Simple("select 1".into())]).dry_run();
+        assert_eq!(
+            serde_json::to_string(&req).unwrap(),
+            r#"{"statements":["select 1"],"isolation":"transaction","dry_run":true}"#
+        );
+
+        let req = ExecRequest::from(vec![Statement::Simple("select 1".into())])
+            .defer_foreign_keys()
+            .stream_returning();
+        assert!(req.is_stream_returning());
+        assert_eq!(
+            serde_json::to_string(&req).unwrap(),
+            r#"{"statements":["select 1"],"isolation":"transaction","defer_foreign_keys":true,"stream_returning":true}"#
+        );
+
+        let stmt: Statement =
+            serde_json::from_str(r#"{"query": "select 1", "defer_foreign_keys": true}"#).unwrap();
+        assert!(matches!(
+            stmt,
+            Statement::Verbose {
+                defer_foreign_keys: Some(true),
+                ..
+            }
+        ));
+    }
 }
@@ -0,0 +1,626 @@
+//! Typed extraction of rows, whether they were read from a local connection
+//! or received as `QueryEvent::Row`s from the API.
+//!
+//! Mismatches are reported w/ the column they happened in, e.g.
+//! `column 1 (hash): expected BLOB[8], got NULL`.
+//!
+//! Columns are extracted in order, or by name w/ `NamedRow`. Names are
+//! matched ignoring ASCII case, like sqlite does, unless exact matching is
+//! opted into.
+
+use std::borrow::Cow;
+
+use compact_str::CompactString;
+use rusqlite::{Connection, Params};
+
+use crate::{ColumnName, ColumnType, Real, SqliteValue, SqliteValueRef};
+
+/// A value which can be extracted from a single column
+pub trait FromValue: Sized {
+    /// What the column should hold, as reported in errors
+    fn expected() -> Cow<'static, str>;
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self>;
+}
+
+impl FromValue for i64 {
+    fn expected() -> Cow<'static, str> {
+        "INTEGER".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_integer().copied()
+    }
+}
+
+impl FromValue for f64 {
+    fn expected() -> Cow<'static, str> {
+        "REAL".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_real().copied()
+    }
+}
+
+impl FromValue for String {
+    fn expected() -> Cow<'static, str> {
+        "TEXT".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_text().map(str::to_owned)
+    }
+}
+
+impl FromValue for CompactString {
+    fn expected() -> Cow<'static, str> {
+        "TEXT".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_text().map(CompactString::from)
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn expected() -> Cow<'static, str> {
+        "BLOB".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_blob().map(<[u8]>::to_vec)
+    }
+}
+
+impl<const N: usize> FromValue for [u8; N] {
+    fn expected() -> Cow<'static, str> {
+        format!("BLOB[{N}]").into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_blob().and_then(|b| b.try_into().ok())
+    }
+}
+
+impl FromValue for ColumnName {
+    fn expected() -> Cow<'static, str> {
+        "TEXT".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_text().map(|name| ColumnName(name.into()))
+    }
+}
+
+impl FromValue for ColumnType {
+    fn expected() -> Cow<'static, str> {
+        "TEXT naming a column type".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        value.as_text().and_then(ColumnType::from_sqlite_name)
+    }
+}
+
+impl FromValue for SqliteValue {
+    fn expected() -> Cow<'static, str> {
+        "any value".into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        Some(value.to_owned())
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn expected() -> Cow<'static, str> {
+        format!("{} or NULL", T::expected()).into()
+    }
+
+    fn from_value(value: SqliteValueRef<'_>) -> Option<Self> {
+        if value.is_null() {
+            Some(None)
+        } else {
+            T::from_value(value).map(Some)
+        }
+    }
+}
+
+/// A single value converted more leniently than `FromValue` extracts row
+/// columns, for scalar lookups (counts, existence checks, etc.):
+///
+/// - `i64` from INTEGER, or REAL w/o a fractional part
+/// - `f64` from REAL or INTEGER
+/// - `bool` from INTEGER, 0 being false and anything else true, like sqlite's
+///   own boolean expressions
+/// - `String` from TEXT and `Vec<u8>` from BLOB
+/// - `SqliteValue` from any value
+/// - `Option<T>` from NULL, or from whatever `T` converts from
+pub trait FromSqliteValue: Sized {
+    /// What the value should be, as reported in errors
+    fn expected() -> Cow<'static, str>;
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self>;
+}
+
+impl FromSqliteValue for i64 {
+    fn expected() -> Cow<'static, str> {
+        "INTEGER".into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        match value {
+            SqliteValue::Integer(i) => Some(i),
+            SqliteValue::Real(Real(f))
+                if f.fract() == 0.0 && f >= i64::MIN as f64 && f < i64::MAX as f64 =>
+            {
+                Some(f as i64)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl FromSqliteValue for f64 {
+    fn expected() -> Cow<'static, str> {
+        "REAL".into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        match value {
+            SqliteValue::Real(Real(f)) => Some(f),
+            SqliteValue::Integer(i) => Some(i as f64),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqliteValue for bool {
+    fn expected() -> Cow<'static, str> {
+        "INTEGER as a boolean".into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        value.as_integer().map(|i| *i != 0)
+    }
+}
+
+impl FromSqliteValue for String {
+    fn expected() -> Cow<'static, str> {
+        "TEXT".into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        match value {
+            SqliteValue::Text(s) => Some(s.into()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqliteValue for Vec<u8> {
+    fn expected() -> Cow<'static, str> {
+        "BLOB".into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        match value {
+            SqliteValue::Blob(b) => Some(b.into_vec()),
+            _ => None,
+        }
+    }
+}
+
+impl FromSqliteValue for SqliteValue {
+    fn expected() -> Cow<'static, str> {
+        "any value".into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl<T: FromSqliteValue> FromSqliteValue for Option<T> {
+    fn expected() -> Cow<'static, str> {
+        format!("{} or NULL", T::expected()).into()
+    }
+
+    fn from_sqlite_value(value: SqliteValue) -> Option<Self> {
+        if value.is_null() {
+            Some(None)
+        } else {
+            T::from_sqlite_value(value).map(Some)
+        }
+    }
+}
+
+/// Converts the value of a query's only column named `name`, see
+/// `FromSqliteValue`
+pub fn from_scalar<T: FromSqliteValue>(value: SqliteValue, name: &str) -> Result<T, RowError> {
+    let actual = actual_type(value.as_ref());
+    T::from_sqlite_value(value).ok_or_else(|| RowError::Mismatch {
+        index: 0,
+        name: name.to_owned(),
+        expected: T::expected(),
+        actual,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum RowError {
+    #[error("column {index} ({name}): expected {expected}, got {actual}")]
+    Mismatch {
+        index: usize,
+        name: String,
+        expected: Cow<'static, str>,
+        actual: String,
+    },
+    #[error("expected {expected} columns, got {actual}")]
+    ColumnCount { expected: usize, actual: usize },
+    #[error("no column named {0}")]
+    NoSuchColumn(String),
+    #[error("column name {0} is ambiguous when ignoring case, match it exactly")]
+    AmbiguousColumn(String),
+}
+
+/// How column names are matched when extracting by name
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnMatching {
+    /// Ignores ASCII case, preferring an exact match, like sqlite does
+    #[default]
+    IgnoreAsciiCase,
+    /// Only matches names w/ the same case, e.g. to tell apart quoted aliases
+    /// differing by case
+    Exact,
+}
+
+/// Index of the column named `name`
+pub fn column_index<S: AsRef<str>>(
+    names: &[S],
+    name: &str,
+    matching: ColumnMatching,
+) -> Result<usize, RowError> {
+    if let Some(index) = names.iter().position(|n| n.as_ref() == name) {
+        return Ok(index);
+    }
+    if matching == ColumnMatching::Exact {
+        return Err(RowError::NoSuchColumn(name.to_owned()));
+    }
+
+    let mut matches = names
+        .iter()
+        .enumerate()
+        .filter(|(_, n)| n.as_ref().eq_ignore_ascii_case(name))
+        .map(|(index, _)| index);
+    match (matches.next(), matches.next()) {
+        (Some(index), None) => Ok(index),
+        (Some(_), Some(_)) => Err(RowError::AmbiguousColumn(name.to_owned())),
+        (None, _) => Err(RowError::NoSuchColumn(name.to_owned())),
+    }
+}
+
+/// A row whose columns are extracted by name, e.g. to implement `FromRow`
+/// for a struct
+pub struct NamedRow<'a, 'r, S> {
+    row: &'a [SqliteValueRef<'r>],
+    names: &'a [S],
+    matching: ColumnMatching,
+}
+
+impl<'a, 'r, S: AsRef<str>> NamedRow<'a, 'r, S> {
+    pub fn new(row: &'a [SqliteValueRef<'r>], names: &'a [S]) -> Self {
+        Self {
+            row,
+            names,
+            matching: ColumnMatching::default(),
+        }
+    }
+
+    pub fn matching(mut self, matching: ColumnMatching) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    pub fn get<T: FromValue>(&self, name: &str) -> Result<T, RowError> {
+        let index = column_index(self.names, name, self.matching)?;
+        if index >= self.row.len() {
+            return Err(RowError::NoSuchColumn(name.to_owned()));
+        }
+        column(self.row, self.names, index)
+    }
+}
+
+/// A whole row, extracted column by column in order
+pub trait FromRow: Sized {
+    /// `names` are the column names, only used to report errors
+    fn from_row<S: AsRef<str>>(row: &[SqliteValueRef<'_>], names: &[S]) -> Result<Self, RowError>;
+
+    /// Extracts a row received as `QueryEvent::Row`
+    fn from_values<S: AsRef<str>>(row: &[SqliteValue], names: &[S]) -> Result<Self, RowError> {
+        Self::from_row(
+            &row.iter().map(SqliteValue::as_ref).collect::<Vec<_>>(),
+            names,
+        )
+    }
+}
+
+fn column<T: FromValue, S: AsRef<str>>(
+    row: &[SqliteValueRef<'_>],
+    names: &[S],
+    index: usize,
+) -> Result<T, RowError> {
+    let value = row[index].clone();
+    T::from_value(value.clone()).ok_or_else(|| RowError::Mismatch {
+        index,
+        name: names
+            .get(index)
+            .map(|name| name.as_ref().to_owned())
+            .unwrap_or_default(),
+        expected: T::expected(),
+        actual: actual_type(value),
+    })
+}
+
+// blobs are reported w/ their length, to tell apart `[u8; N]` mismatches
+fn actual_type(value: SqliteValueRef<'_>) -> String {
+    match value {
+        SqliteValueRef::Blob(b) => format!("{}[{}]", ColumnType::Blob, b.len()),
+        value => value.column_type().to_string(),
+    }
+}
+
+macro_rules! impl_from_row {
+    ($len:literal: $($t:ident $i:tt),+) => {
+        impl<$($t: FromValue),+> FromRow for ($($t,)+) {
+            fn from_row<S: AsRef<str>>(row: &[SqliteValueRef<'_>], names: &[S]) -> Result<Self, RowError> {
+                if row.len() != $len {
+                    return Err(RowError::ColumnCount {
+                        expected: $len,
+                        actual: row.len(),
+                    });
+                }
+                Ok(($(column::<$t, S>(row, names, $i)?,)+))
+            }
+        }
+    };
+}
+
+impl_from_row!(1: A 0);
+impl_from_row!(2: A 0, B 1);
+impl_from_row!(3: A 0, B 1, C 2);
+impl_from_row!(4: A 0, B 1, C 2, D 3);
+impl_from_row!(5: A 0, B 1, C 2, D 3, E 4);
+impl_from_row!(6: A 0, B 1, C 2, D 3, E 4, F 5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    #[error(transparent)]
+    Row(#[from] RowError),
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Runs a query and extracts each of its rows
+pub trait QueryMapInto {
+    fn query_map_into<T: FromRow, P: Params>(
+        &self,
+        sql: &str,
+        params: P,
+    ) -> Result<Vec<T>, ReadError>;
+}
+
+impl QueryMapInto for Connection {
+    fn query_map_into<T: FromRow, P: Params>(
+        &self,
+        sql: &str,
+        params: P,
+    ) -> Result<Vec<T>, ReadError> {
+        let mut prepped = self.prepare(sql)?;
+        let names: Vec<String> = prepped
+            .column_names()
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+
+        let mut rows = prepped.query(params)?;
+        let mut extracted = vec![];
+        while let Some(row) = rows.next()? {
+            let values = (0..names.len())
+                .map(|i| {
+                    SqliteValueRef::try_from(row.get_ref(i)?).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            i,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            extracted.push(T::from_row(&values, &names)?);
+        }
+
+        Ok(extracted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE hashes (id TEXT, hash BLOB, n INTEGER);
+            INSERT INTO hashes VALUES ('a', x'0000000000000001', 1);
+            ",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn mismatch(sql: &str) -> String {
+        match conn().query_map_into::<(String, [u8; 8], i64), _>(sql, []) {
+            Err(ReadError::Row(e)) => e.to_string(),
+            res => panic!("expected a row error, got: {res:?}"),
+        }
+    }
+
+    #[test]
+    fn extracts_rows() {
+        let rows = conn()
+            .query_map_into::<(String, [u8; 8], Option<i64>), _>(
+                "SELECT id, hash, n FROM hashes",
+                [],
+            )
+            .unwrap();
+        assert_eq!(rows, vec![("a".to_string(), 1u64.to_be_bytes(), Some(1))]);
+
+        let rows = conn()
+            .query_map_into::<(Option<String>,), _>("SELECT NULL", [])
+            .unwrap();
+        assert_eq!(rows, vec![(None,)]);
+
+        let values = vec![SqliteValue::Text("b".into()), SqliteValue::Integer(2)];
+        assert_eq!(
+            <(String, i64)>::from_values(&values, &["id", "n"]).unwrap(),
+            ("b".to_string(), 2)
+        );
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Service {
+        node_name: String,
+        port: i64,
+    }
+
+    impl FromRow for Service {
+        fn from_row<S: AsRef<str>>(
+            row: &[SqliteValueRef<'_>],
+            names: &[S],
+        ) -> Result<Self, RowError> {
+            let row = NamedRow::new(row, names);
+            Ok(Self {
+                node_name: row.get("node_name")?,
+                port: row.get("port")?,
+            })
+        }
+    }
+
+    #[test]
+    fn extracts_by_name_ignoring_case() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE services (Node_Name TEXT, PORT INTEGER);
+            INSERT INTO services VALUES ('a', 80);
+            ",
+        )
+        .unwrap();
+
+        let rows = conn
+            .query_map_into::<Service, _>("SELECT PORT, NODE_NAME FROM services", [])
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![Service {
+                node_name: "a".into(),
+                port: 80
+            }]
+        );
+
+        // declared names are reported, whatever the query's case
+        let rows = conn
+            .query_map_into::<(ColumnName,), _>(
+                "SELECT name FROM pragma_table_info('services')",
+                [],
+            )
+            .unwrap();
+        assert!(rows[0].0.eq_ignore_ascii_case("node_name"));
+        assert_eq!(rows[0].0 .0, "Node_Name");
+
+        let values = [SqliteValueRef::Integer(1), SqliteValueRef::Integer(2)];
+        let names = ["X", "x"];
+        let row = NamedRow::new(&values, &names);
+        assert_eq!(row.get::<i64>("X").unwrap(), 1);
+        assert_eq!(row.get::<i64>("x").unwrap(), 2);
+
+        let names = ["Ab", "aB"];
+        let row = NamedRow::new(&values, &names);
+        assert_eq!(
+            row.get::<i64>("ab").unwrap_err(),
+            RowError::AmbiguousColumn("ab".into())
+        );
+        let row = row.matching(ColumnMatching::Exact);
+        assert_eq!(row.get::<i64>("aB").unwrap(), 2);
+        assert_eq!(
+            row.get::<i64>("ab").unwrap_err(),
+            RowError::NoSuchColumn("ab".into())
+        );
+    }
+
+    #[test]
+    fn coerces_scalars() {
+        assert_eq!(from_scalar::<i64>(SqliteValue::Real(Real(3.0)), "n"), Ok(3));
+        assert_eq!(from_scalar::<f64>(SqliteValue::Integer(3), "n"), Ok(3.0));
+        assert_eq!(from_scalar::<bool>(SqliteValue::Integer(2), "n"), Ok(true));
+        assert_eq!(from_scalar::<bool>(SqliteValue::Integer(0), "n"), Ok(false));
+        assert_eq!(from_scalar::<Option<i64>>(SqliteValue::Null, "n"), Ok(None));
+
+        assert_eq!(
+            from_scalar::<i64>(SqliteValue::Real(Real(1.5)), "n")
+                .unwrap_err()
+                .to_string(),
+            "column 0 (n): expected INTEGER, got REAL"
+        );
+        assert_eq!(
+            from_scalar::<i64>(SqliteValue::Null, "n")
+                .unwrap_err()
+                .to_string(),
+            "column 0 (n): expected INTEGER, got NULL"
+        );
+        assert_eq!(
+            from_scalar::<bool>(SqliteValue::Text("true".into()), "n")
+                .unwrap_err()
+                .to_string(),
+            "column 0 (n): expected INTEGER as a boolean, got TEXT"
+        );
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        assert_eq!(
+            mismatch("SELECT id, NULL AS hash, n FROM hashes"),
+            "column 1 (hash): expected BLOB[8], got NULL"
+        );
+        assert_eq!(
+            mismatch("SELECT id, x'01' AS hash, n FROM hashes"),
+            "column 1 (hash): expected BLOB[8], got BLOB[1]"
+        );
+        assert_eq!(
+            mismatch("SELECT n AS id, hash, n FROM hashes"),
+            "column 0 (id): expected TEXT, got INTEGER"
+        );
+        assert_eq!(
+            mismatch("SELECT id, hash, 1.5 AS n FROM hashes"),
+            "column 2 (n): expected INTEGER, got REAL"
+        );
+        assert_eq!(
+            mismatch("SELECT id, hash, id AS n FROM hashes"),
+            "column 2 (n): expected INTEGER, got TEXT"
+        );
+        assert_eq!(
+            mismatch("SELECT id, hash FROM hashes"),
+            "expected 3 columns, got 2"
+        );
+
+        assert_eq!(
+            <(Option<i64>,)>::from_values(&[SqliteValue::Text("x".into())], &["n"])
+                .unwrap_err()
+                .to_string(),
+            "column 0 (n): expected INTEGER or NULL, got TEXT"
+        );
+    }
+}
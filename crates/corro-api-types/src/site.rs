@@ -0,0 +1,259 @@
+use std::{fmt, str::FromStr};
+
+use rusqlite::{
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    ToSql,
+};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use speedy::{Context, Readable, Reader, Writable, Writer};
+use uuid::Uuid;
+
+const SITE_ID_SIZE: usize = 16;
+// hex chars of `SiteId::short`, 2 per byte
+const SHORT_SITE_ID_LEN: usize = 8;
+
+/// The cr-sqlite site id of the node a change originates from, stored as a
+/// 16 bytes BLOB. Displayed and serialized as a hyphenated UUID.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SiteId(pub [u8; 16]);
+
+impl SiteId {
+    pub const NIL: SiteId = SiteId([0; 16]);
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        self.0
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+
+    /// First hex chars of the id, to tag logs and metrics w/ a readable
+    /// (if not unique) form of it
+    pub fn short(&self) -> String {
+        hex::encode(&self.0[..SHORT_SITE_ID_LEN / 2])
+    }
+}
+
+impl From<[u8; 16]> for SiteId {
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<SiteId> for [u8; 16] {
+    fn from(site_id: SiteId) -> Self {
+        site_id.0
+    }
+}
+
+impl From<Uuid> for SiteId {
+    fn from(uuid: Uuid) -> Self {
+        Self(uuid.into_bytes())
+    }
+}
+
+impl From<SiteId> for Uuid {
+    fn from(site_id: SiteId) -> Self {
+        Uuid::from_bytes(site_id.0)
+    }
+}
+
+impl PartialEq<[u8; 16]> for SiteId {
+    fn eq(&self, other: &[u8; 16]) -> bool {
+        &self.0 == other
+    }
+}
+
+impl fmt::Display for SiteId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Uuid::from_bytes(self.0).hyphenated().fmt(f)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid site id, expected a UUID or 32 hex chars: {0}")]
+pub struct ParseSiteIdError(#[from] uuid::Error);
+
+impl FromStr for SiteId {
+    type Err = ParseSiteIdError;
+
+    /// Parses a UUID, hyphenated or not, which covers plain hex
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Uuid::parse_str(s)?.into())
+    }
+}
+
+impl Serialize for SiteId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SiteId {
+    /// Also accepts the array of 16 bytes site ids were serialized as before
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(SiteIdVisitor)
+    }
+}
+
+struct SiteIdVisitor;
+
+impl<'de> Visitor<'de> for SiteIdVisitor {
+    type Value = SiteId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a site id, as a UUID or an array of 16 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        <[u8; 16]>::try_from(v)
+            .map(SiteId)
+            .map_err(|_| E::invalid_length(v.len(), &self))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = [0; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(17, &self));
+        }
+        Ok(SiteId(bytes))
+    }
+}
+
+impl ToSql for SiteId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Borrowed(ValueRef::Blob(&self.0)))
+    }
+}
+
+impl FromSql for SiteId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        <[u8; 16]>::try_from(blob)
+            .map(SiteId)
+            .map_err(|_| FromSqlError::InvalidBlobSize {
+                expected_size: SITE_ID_SIZE,
+                blob_size: blob.len(),
+            })
+    }
+}
+
+// encoded as its 16 raw bytes, like the `[u8; 16]` it replaced
+impl<'a, C> Readable<'a, C> for SiteId
+where
+    C: Context,
+{
+    #[inline]
+    fn read_from<R: Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
+        Ok(SiteId(reader.read_value()?))
+    }
+
+    #[inline]
+    fn minimum_bytes_needed() -> usize {
+        SITE_ID_SIZE
+    }
+}
+
+impl<C> Writable<C> for SiteId
+where
+    C: Context,
+{
+    #[inline]
+    fn write_to<T: ?Sized + Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
+        writer.write_bytes(&self.0)
+    }
+
+    #[inline]
+    fn bytes_needed(&self) -> Result<usize, C::Error> {
+        Ok(SITE_ID_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    const BYTES: [u8; 16] = [
+        0x67, 0xe5, 0x50, 0x44, 0x10, 0xb1, 0x42, 0x6f, 0x92, 0x47, 0xbb, 0x68, 0x0e, 0x5f, 0xe0,
+        0xc8,
+    ];
+
+    #[test]
+    fn displays_and_parses() {
+        let site_id = SiteId(BYTES);
+        assert_eq!(site_id.to_string(), "67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(site_id.short(), "67e55044");
+
+        for s in [
+            "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "67e5504410b1426f9247bb680e5fe0c8",
+            "67E5504410B1426F9247BB680E5FE0C8",
+        ] {
+            assert_eq!(s.parse::<SiteId>().unwrap(), site_id);
+        }
+        assert!("67e55044".parse::<SiteId>().is_err());
+
+        assert!(SiteId::NIL.is_nil());
+        assert!(SiteId::default().is_nil());
+        assert!(!site_id.is_nil());
+    }
+
+    #[test]
+    fn serde_as_string_reads_arrays() {
+        let site_id = SiteId(BYTES);
+        let json = serde_json::to_string(&site_id).unwrap();
+        assert_eq!(json, "\"67e55044-10b1-426f-9247-bb680e5fe0c8\"");
+        assert_eq!(serde_json::from_str::<SiteId>(&json).unwrap(), site_id);
+
+        // as serialized when site ids were plain arrays
+        let array = serde_json::to_string(&BYTES).unwrap();
+        assert_eq!(serde_json::from_str::<SiteId>(&array).unwrap(), site_id);
+        assert!(serde_json::from_str::<SiteId>("[1,2,3]").is_err());
+    }
+
+    #[test]
+    fn same_bytes_as_arrays() {
+        let site_id = SiteId(BYTES);
+        assert_eq!(
+            site_id.write_to_vec().unwrap(),
+            BYTES.write_to_vec().unwrap()
+        );
+        assert_eq!(SiteId::read_from_buffer(&BYTES).unwrap(), site_id);
+
+        let conn = Connection::open_in_memory().unwrap();
+        let blob: Vec<u8> = conn
+            .query_row("SELECT ?", [site_id], |row| row.get(0))
+            .unwrap();
+        assert_eq!(blob, BYTES);
+        let read: SiteId = conn
+            .query_row("SELECT ?", [BYTES.as_slice()], |row| row.get(0))
+            .unwrap();
+        assert_eq!(read, site_id);
+        assert!(conn
+            .query_row("SELECT x'0102'", [], |row| row.get::<_, SiteId>(0))
+            .is_err());
+    }
+}
@@ -1,15 +1,70 @@
-use rusqlite::types::{FromSql, FromSqlError};
+use std::{fmt, ops::AddAssign};
+
+use rusqlite::{
+    ffi,
+    types::{FromSql, FromSqlError},
+};
 use serde::{Deserialize, Serialize};
 
+use crate::{Change, ColumnName};
+
+/// What happened to a subscription's row.
+///
+/// Serialized as `"insert"`, `"update"` or `"delete"`. These names are part
+/// of the subscriptions API and must not change.
+///
+/// ```
+/// use corro_api_types::{ChangeType, QueryEvent};
+///
+/// let event: QueryEvent = serde_json::from_str(r#"{"change": ["update", 1, ["a"], 2]}"#).unwrap();
+/// let QueryEvent::Change(change_type, ..) = event else {
+///     unreachable!()
+/// };
+/// assert_eq!(change_type, ChangeType::Update);
+/// assert_eq!(change_type.as_str(), "update");
+///
+/// // sqlite's codes, as passed to update and preupdate hooks
+/// assert_eq!(ChangeType::from_sqlite_action_code(rusqlite::ffi::SQLITE_DELETE), Some(ChangeType::Delete));
+/// ```
 #[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, strum::FromRepr)]
-#[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum ChangeType {
+    #[serde(rename = "insert")]
     Insert,
+    #[serde(rename = "update")]
     Update,
+    #[serde(rename = "delete")]
     Delete,
 }
 
+impl ChangeType {
+    /// Maps sqlite's `SQLITE_INSERT`, `SQLITE_UPDATE` and `SQLITE_DELETE`
+    /// action codes, `None` for any other code
+    pub fn from_sqlite_action_code(code: i32) -> Option<Self> {
+        match code {
+            ffi::SQLITE_INSERT => Some(ChangeType::Insert),
+            ffi::SQLITE_UPDATE => Some(ChangeType::Update),
+            ffi::SQLITE_DELETE => Some(ChangeType::Delete),
+            _ => None,
+        }
+    }
+
+    /// Same as the serialized name
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChangeType::Insert => "insert",
+            ChangeType::Update => "update",
+            ChangeType::Delete => "delete",
+        }
+    }
+}
+
+impl fmt::Display for ChangeType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 impl FromSql for ChangeType {
     fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
         match value {
@@ -21,3 +76,276 @@ impl FromSql for ChangeType {
         }
     }
 }
+
+/// Column under which cr-sqlite records row-level events: deletions,
+/// resurrections and the creation of rows w/o other columns
+pub const SENTINEL_CID: &str = "-1";
+
+// what cr-sqlite versions before 0.15 named the sentinel column
+const LEGACY_SENTINEL_CID: &str = "__crsql_del";
+
+/// Whether `cid` is cr-sqlite's sentinel column rather than one of the
+/// table's. A sentinel change w/ an even causal length deletes its row.
+pub fn is_delete_sentinel(cid: &ColumnName) -> bool {
+    is_sentinel_cid(cid.as_str())
+}
+
+fn is_sentinel_cid(cid: &str) -> bool {
+    cid == SENTINEL_CID || cid == LEGACY_SENTINEL_CID
+}
+
+/// What a change recorded by cr-sqlite does to its row.
+///
+/// A row's causal length (`cl`) is odd while the row exists: 1 once created,
+/// incremented by each deletion and resurrection. Each column's `col_version`
+/// starts at 1 when the column is first written for the row's current
+/// causal length.
+///
+/// ```
+/// use corro_api_types::sqlite::{row_change_kind, ChangeKind};
+///
+/// assert_eq!(row_change_kind("-1", 2, 2), ChangeKind::Delete);
+/// assert_eq!(row_change_kind("-1", 3, 3), ChangeKind::Resurrect);
+/// assert_eq!(row_change_kind("text", 1, 4), ChangeKind::Update);
+/// ```
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    /// A column written for the first time since the row was created
+    Insert,
+    /// A column written over
+    Update,
+    /// The row was deleted
+    Delete,
+    /// A deleted row was inserted again
+    Resurrect,
+    /// A row was created w/o other columns than its primary key
+    PkOnly,
+}
+
+impl ChangeKind {
+    /// What the change looks like to subscribers: resurrections and
+    /// primary key only rows are inserts
+    pub fn change_type(&self) -> ChangeType {
+        match self {
+            ChangeKind::Insert | ChangeKind::Resurrect | ChangeKind::PkOnly => ChangeType::Insert,
+            ChangeKind::Update => ChangeType::Update,
+            ChangeKind::Delete => ChangeType::Delete,
+        }
+    }
+}
+
+/// What `change` does to its row, see `ChangeKind`
+pub fn change_kind(change: &Change) -> ChangeKind {
+    row_change_kind(change.cid.as_str(), change.cl, change.col_version)
+}
+
+/// Same as `change_kind`, from the `cid`, `cl` and `col_version` columns of
+/// `crsql_changes`
+pub fn row_change_kind(cid: &str, cl: i64, col_version: i64) -> ChangeKind {
+    if is_sentinel_cid(cid) {
+        if cl % 2 == 0 {
+            ChangeKind::Delete
+        } else if cl > 1 {
+            ChangeKind::Resurrect
+        } else {
+            ChangeKind::PkOnly
+        }
+    } else if col_version > 1 {
+        ChangeKind::Update
+    } else {
+        ChangeKind::Insert
+    }
+}
+
+/// Changes counted by kind
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChangeKindCounts {
+    pub inserts: usize,
+    pub updates: usize,
+    pub deletes: usize,
+    pub resurrections: usize,
+    pub pk_only: usize,
+}
+
+impl ChangeKindCounts {
+    pub fn tally<'a>(changes: impl IntoIterator<Item = &'a Change>) -> Self {
+        let mut counts = ChangeKindCounts::default();
+        for change in changes {
+            counts.add(change_kind(change));
+        }
+        counts
+    }
+
+    pub fn add(&mut self, kind: ChangeKind) {
+        match kind {
+            ChangeKind::Insert => self.inserts += 1,
+            ChangeKind::Update => self.updates += 1,
+            ChangeKind::Delete => self.deletes += 1,
+            ChangeKind::Resurrect => self.resurrections += 1,
+            ChangeKind::PkOnly => self.pk_only += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.inserts + self.updates + self.deletes + self.resurrections + self.pk_only
+    }
+}
+
+impl AddAssign for ChangeKindCounts {
+    fn add_assign(&mut self, other: Self) {
+        self.inserts += other.inserts;
+        self.updates += other.updates;
+        self.deletes += other.deletes;
+        self.resurrections += other.resurrections;
+        self.pk_only += other.pk_only;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use compact_str::CompactString;
+
+    use super::*;
+    use crate::{SiteId, SqliteValue, TableName};
+
+    // changes as selected from `crsql_changes` by cr-sqlite 0.16, for a
+    // `tests (id INTEGER PRIMARY KEY, text TEXT)` table: `(cid, val,
+    // col_version, db_version, cl)`
+    fn captured(rows: &[(&str, SqliteValue, i64, i64, i64)]) -> Vec<Change> {
+        rows.iter()
+            .enumerate()
+            .map(|(seq, (cid, val, col_version, db_version, cl))| Change {
+                table: TableName(CompactString::new("tests")),
+                pk: vec![0x01, 0x09, 0x01],
+                cid: ColumnName(CompactString::new(cid)),
+                val: val.clone(),
+                col_version: *col_version,
+                db_version: *db_version,
+                seq: seq as i64,
+                site_id: SiteId([7; 16]),
+                cl: *cl,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn kinds(changes: &[Change]) -> Vec<ChangeKind> {
+        changes.iter().map(change_kind).collect()
+    }
+
+    #[test]
+    fn change_kinds_of_captured_flows() {
+        // INSERT INTO tests VALUES (1, 'hello')
+        let insert = captured(&[("text", "hello".into(), 1, 1, 1)]);
+        assert_eq!(kinds(&insert), [ChangeKind::Insert]);
+
+        // UPDATE tests SET text = 'world' WHERE id = 1, twice
+        let update = captured(&[
+            ("text", "world".into(), 2, 2, 1),
+            ("text", "!".into(), 3, 3, 1),
+        ]);
+        assert_eq!(kinds(&update), [ChangeKind::Update, ChangeKind::Update]);
+
+        // DELETE FROM tests WHERE id = 1
+        let delete = captured(&[("-1", SqliteValue::Null, 2, 4, 2)]);
+        assert_eq!(kinds(&delete), [ChangeKind::Delete]);
+
+        // INSERT INTO tests VALUES (1, 'again'), its columns start over
+        let reinsert = captured(&[
+            ("-1", SqliteValue::Null, 3, 5, 3),
+            ("text", "again".into(), 1, 5, 3),
+        ]);
+        assert_eq!(
+            kinds(&reinsert),
+            [ChangeKind::Resurrect, ChangeKind::Insert]
+        );
+
+        // deleted and resurrected again
+        let churn = captured(&[
+            ("-1", SqliteValue::Null, 4, 6, 4),
+            ("-1", SqliteValue::Null, 5, 7, 5),
+        ]);
+        assert_eq!(kinds(&churn), [ChangeKind::Delete, ChangeKind::Resurrect]);
+
+        // INSERT INTO pks_only VALUES (1)
+        let pk_only = captured(&[("-1", SqliteValue::Null, 1, 8, 1)]);
+        assert_eq!(kinds(&pk_only), [ChangeKind::PkOnly]);
+
+        // older cr-sqlite versions
+        let legacy = captured(&[("__crsql_del", SqliteValue::Null, 2, 9, 2)]);
+        assert_eq!(kinds(&legacy), [ChangeKind::Delete]);
+
+        let flows = [insert, update, delete, reinsert, churn, pk_only, legacy];
+        let counts = ChangeKindCounts::tally(flows.iter().flatten());
+        assert_eq!(
+            counts,
+            ChangeKindCounts {
+                inserts: 2,
+                updates: 2,
+                deletes: 3,
+                resurrections: 2,
+                pk_only: 1,
+            }
+        );
+        assert_eq!(counts.total(), 10);
+    }
+
+    #[test]
+    fn change_kinds_as_change_types() {
+        for (kind, change_type) in [
+            (ChangeKind::Insert, ChangeType::Insert),
+            (ChangeKind::Update, ChangeType::Update),
+            (ChangeKind::Delete, ChangeType::Delete),
+            (ChangeKind::Resurrect, ChangeType::Insert),
+            (ChangeKind::PkOnly, ChangeType::Insert),
+        ] {
+            assert_eq!(kind.change_type(), change_type);
+        }
+
+        assert!(is_delete_sentinel(&ColumnName(CompactString::new("-1"))));
+        assert!(is_delete_sentinel(&ColumnName(CompactString::new(
+            "__crsql_del"
+        ))));
+        assert!(!is_delete_sentinel(&ColumnName(CompactString::new("text"))));
+        // a column named like a negative number isn't the sentinel
+        assert!(!is_delete_sentinel(&ColumnName(CompactString::new("-2"))));
+    }
+
+    #[test]
+    fn change_type_wire_names_are_stable() {
+        for (change_type, name) in [
+            (ChangeType::Insert, "insert"),
+            (ChangeType::Update, "update"),
+            (ChangeType::Delete, "delete"),
+        ] {
+            let json = serde_json::to_string(&change_type).unwrap();
+            assert_eq!(json, format!("\"{name}\""));
+            assert_eq!(
+                serde_json::from_str::<ChangeType>(&json).unwrap(),
+                change_type
+            );
+            assert_eq!(change_type.as_str(), name);
+            assert_eq!(change_type.to_string(), name);
+        }
+    }
+
+    #[test]
+    fn change_type_from_action_codes() {
+        assert_eq!(
+            ChangeType::from_sqlite_action_code(18),
+            Some(ChangeType::Insert)
+        );
+        assert_eq!(
+            ChangeType::from_sqlite_action_code(23),
+            Some(ChangeType::Update)
+        );
+        assert_eq!(
+            ChangeType::from_sqlite_action_code(9),
+            Some(ChangeType::Delete)
+        );
+        assert_eq!(
+            ChangeType::from_sqlite_action_code(ffi::SQLITE_SELECT),
+            None
+        );
+    }
+}
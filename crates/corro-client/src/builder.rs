@@ -0,0 +1,248 @@
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hyper::{
+    client::HttpConnector,
+    header::{InvalidHeaderValue, USER_AGENT},
+    http::{HeaderMap, HeaderName, HeaderValue},
+    Body,
+};
+use hyper_rustls::HttpsConnector;
+use rustls::{ClientConfig, RootCertStore};
+
+use crate::{pool::LocalPool, CorrosionApiClient, CorrosionClient};
+
+/// Sent as `user-agent` unless `ClientBuilder::user_agent` replaces it
+pub const DEFAULT_USER_AGENT: &str = concat!("corro-client/", env!("CARGO_PKG_VERSION"));
+
+const DEFAULT_LOCAL_POOL_SIZE: usize = 5;
+
+/// HTTP client shared by all requests of a `CorrosionApiClient`, and of the
+/// subscriptions it starts
+pub(crate) type HttpClient = hyper::Client<HttpsConnector<HttpConnector>, Body>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error("no endpoint to connect to")]
+    NoEndpoint,
+    #[error("no local database path")]
+    NoDbPath,
+    #[error("invalid user-agent: {0}")]
+    InvalidUserAgent(#[from] InvalidHeaderValue),
+    #[error("could not build local pool: {0}")]
+    Pool(#[from] sqlite_pool::CreatePoolError),
+}
+
+/// Options of a `CorrosionClient`, or of a `CorrosionApiClient` when built w/
+/// `build_api`
+#[derive(Clone, Default)]
+pub struct ClientBuilder {
+    endpoints: Vec<SocketAddr>,
+    db_path: Option<PathBuf>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    headers: HeaderMap,
+    bearer_token: Option<String>,
+    user_agent: Option<String>,
+    tls: Option<Arc<ClientConfig>>,
+    pool_idle_timeout: Option<Duration>,
+    local_pool_size: Option<usize>,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an agent API address to send requests to. Requests go to the
+    /// first one until connecting to it fails, then to the next one, and so on.
+    pub fn endpoint(mut self, addr: SocketAddr) -> Self {
+        self.endpoints.push(addr);
+        self
+    }
+
+    /// Adds several agent API addresses, see `endpoint`
+    pub fn endpoints(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.endpoints.extend(addrs);
+        self
+    }
+
+    /// Path of the agent's database file, read from directly by
+    /// `CorrosionClient::read`. Required by `build`.
+    pub fn db_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.db_path = Some(path.into());
+        self
+    }
+
+    /// Fails connecting to an endpoint w/ `Error::Connect` after `timeout`,
+    /// instead of waiting on the OS
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// See `CorrosionApiClient::with_timeout`
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sends `value` as `name` w/ every request which doesn't set that header
+    /// itself
+    pub fn default_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Sends `headers` w/ every request, see `default_header`
+    pub fn default_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    /// See `CorrosionApiClient::with_bearer_token`
+    pub fn bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    /// Identifies requests as coming from `user_agent`, instead of
+    /// `DEFAULT_USER_AGENT`
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Connects to endpoints over TLS, e.g. through a proxy terminating TLS in
+    /// front of the agent's API. Offers `h2` over ALPN unless `config`
+    /// already sets protocols.
+    pub fn tls(mut self, mut config: ClientConfig) -> Self {
+        if config.alpn_protocols.is_empty() {
+            config.alpn_protocols = vec![b"h2".to_vec()];
+        }
+        self.tls = Some(Arc::new(config));
+        self
+    }
+
+    /// Closes connections to endpoints after `timeout` w/o any request
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Opens up to `size` connections to the local database file, 5 by
+    /// default
+    pub fn local_pool_size(mut self, size: usize) -> Self {
+        self.local_pool_size = Some(size);
+        self
+    }
+
+    pub fn build(self) -> Result<CorrosionClient, BuildError> {
+        let db_path = self.db_path.clone().ok_or(BuildError::NoDbPath)?;
+        let pool = sqlite_pool::Config::new(&db_path)
+            .max_size(self.local_pool_size.unwrap_or(DEFAULT_LOCAL_POOL_SIZE))
+            .create_pool()?;
+
+        Ok(CorrosionClient {
+            api_client: self.build_api()?,
+            db_path,
+            pool: LocalPool::new(pool),
+        })
+    }
+
+    /// Builds a client for the agent's API only, ignoring `db_path` and
+    /// `local_pool_size`
+    pub fn build_api(self) -> Result<CorrosionApiClient, BuildError> {
+        if self.endpoints.is_empty() {
+            return Err(BuildError::NoEndpoint);
+        }
+
+        let mut headers = self.headers;
+        headers.insert(
+            USER_AGENT,
+            match self.user_agent {
+                Some(user_agent) => HeaderValue::try_from(user_agent)?,
+                None => HeaderValue::from_static(DEFAULT_USER_AGENT),
+            },
+        );
+
+        let mut http = HttpConnector::new();
+        // `https` URIs are handled by the TLS connector wrapping this one
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connect_timeout);
+
+        let (scheme, tls) = match self.tls {
+            Some(tls) => ("https", tls),
+            // only ever used for `https` URIs, there are none w/o TLS settings
+            None => ("http", Arc::new(plaintext_tls_config())),
+        };
+
+        let mut builder = hyper::Client::builder();
+        builder.http2_only(true);
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder.pool_idle_timeout(timeout);
+        }
+
+        let mut client = CorrosionApiClient::from_parts(
+            Endpoints::new(self.endpoints),
+            scheme,
+            builder.build(HttpsConnector::from((http, tls))),
+            headers,
+        );
+        if let Some(timeout) = self.request_timeout {
+            client = client.with_timeout(timeout);
+        }
+        if let Some(token) = self.bearer_token {
+            client = client.with_bearer_token(token);
+        }
+        Ok(client)
+    }
+}
+
+fn plaintext_tls_config() -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth()
+}
+
+/// Agent API addresses of a client, shared by its clones
+#[derive(Debug)]
+pub(crate) struct Endpoints {
+    addrs: Vec<SocketAddr>,
+    current: AtomicUsize,
+}
+
+impl Endpoints {
+    fn new(addrs: Vec<SocketAddr>) -> Self {
+        Self {
+            addrs,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn current(&self) -> SocketAddr {
+        self.addrs[self.current.load(Ordering::Relaxed) % self.addrs.len()]
+    }
+
+    /// Moves on to the next endpoint after failing to connect to `addr`,
+    /// unless a concurrent request already did
+    pub(crate) fn failed(&self, addr: SocketAddr) {
+        let current = self.current.load(Ordering::Relaxed);
+        if self.addrs[current % self.addrs.len()] == addr {
+            let _ = self.current.compare_exchange(
+                current,
+                current.wrapping_add(1),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+}
@@ -0,0 +1,1092 @@
+//! Client-side encryption of columns agents' operators mustn't be able to
+//! read, e.g. credentials in service meta.
+//!
+//! Values bound to an encrypted column are encrypted w/ AES-256-GCM before
+//! statements are sent, and stored as blobs: the agent only ever sees, stores
+//! and replicates ciphertext, never plaintext nor keys. Blobs read back are
+//! decrypted to the value they were, w/ any of the keys the client holds.
+//!
+//! An encrypted value is laid out as a magic, a format version and the id of
+//! the key it was encrypted w/, which are authenticated, followed by a random
+//! nonce and the ciphertext of the value's type and bytes.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use compact_str::CompactString;
+use corro_api_types::{QueryEvent, Real, RowId, SqliteParam, SqliteValue, Statement};
+use futures::{ready, Stream};
+use pin_project_lite::pin_project;
+
+const MAGIC: &[u8; 4] = b"CENC";
+const VERSION: u8 = 1;
+// magic, version and key id
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// types of encrypted values
+const TYPE_INTEGER: u8 = 1;
+const TYPE_REAL: u8 = 2;
+const TYPE_TEXT: u8 = 3;
+const TYPE_BLOB: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CryptoError {
+    #[error("no key w/ id {0}")]
+    UnknownKey(u32),
+    #[error("unsupported encryption version {0}")]
+    UnsupportedVersion(u8),
+    #[error("malformed encrypted value")]
+    Malformed,
+    #[error("could not encrypt value")]
+    Encrypt,
+    /// The key doesn't match or the value was tampered w/
+    #[error("could not decrypt value")]
+    Decrypt,
+    /// A statement writes an encrypted column in a way its value can't be
+    /// told apart, it would be sent in plaintext
+    #[error("{table}.{column} is encrypted, {reason}")]
+    Unsupported {
+        table: String,
+        column: String,
+        reason: &'static str,
+    },
+}
+
+/// A value of a query event which couldn't be decrypted, it's left as the
+/// encrypted blob
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("could not decrypt column {index} of row {}: {error}", .rowid.0)]
+pub struct ValueError {
+    pub rowid: RowId,
+    /// Index of the value in the row
+    pub index: usize,
+    pub error: CryptoError,
+}
+
+/// A 256-bit AES key, identified by `id` in the values it encrypts
+#[derive(Clone)]
+pub struct ColumnKey {
+    id: u32,
+    key: [u8; 32],
+}
+
+impl ColumnKey {
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        Self { id, key }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl fmt::Debug for ColumnKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ColumnKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Which columns are encrypted and the keys to do it, see
+/// `CorrosionApiClient::with_column_crypto`.
+///
+/// Keys are rotated by encrypting columns w/ a new key while keeping the
+/// previous ones around w/ `key`, to decrypt values written before.
+#[derive(Clone, Default)]
+pub struct ColumnCrypto {
+    // id of the key each (table, column) is encrypted w/, lowercased
+    columns: HashMap<(String, String), u32>,
+    keys: HashMap<u32, Aes256Gcm>,
+}
+
+impl fmt::Debug for ColumnCrypto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ColumnCrypto")
+            .field("columns", &self.columns)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl ColumnCrypto {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encrypts values written to `column` of `table` w/ `key`
+    pub fn column(mut self, table: &str, column: &str, key: &ColumnKey) -> Self {
+        self.columns
+            .insert((table.to_lowercase(), column.to_lowercase()), key.id);
+        self.key(key)
+    }
+
+    /// Decrypts values encrypted w/ `key`, e.g. before it was rotated. Keys
+    /// are told apart by id, adding another key w/ the same id replaces it.
+    pub fn key(mut self, key: &ColumnKey) -> Self {
+        self.keys.insert(key.id, Aes256Gcm::new((&key.key).into()));
+        self
+    }
+
+    pub fn is_encrypted(&self, table: &str, column: &str) -> bool {
+        self.columns
+            .contains_key(&(table.to_lowercase(), column.to_lowercase()))
+    }
+
+    /// Encrypts `param` w/ key `key_id`. `NULL`s are left as-is, booleans
+    /// are encrypted as integers and JSON as text.
+    pub fn encrypt(&self, key_id: u32, param: &SqliteParam) -> Result<SqliteParam, CryptoError> {
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or(CryptoError::UnknownKey(key_id))?;
+
+        let typed = |ty: u8, bytes: &[u8]| {
+            let mut plaintext = Vec::with_capacity(1 + bytes.len());
+            plaintext.push(ty);
+            plaintext.extend_from_slice(bytes);
+            plaintext
+        };
+        let plaintext = match param {
+            SqliteParam::Null => return Ok(SqliteParam::Null),
+            SqliteParam::Bool(b) => typed(TYPE_INTEGER, &(*b as i64).to_le_bytes()),
+            SqliteParam::Integer(i) => typed(TYPE_INTEGER, &i.to_le_bytes()),
+            SqliteParam::Real(f) => typed(TYPE_REAL, &f.to_le_bytes()),
+            SqliteParam::Text(s) => typed(TYPE_TEXT, s.as_bytes()),
+            SqliteParam::Blob(b) => typed(TYPE_BLOB, b),
+            SqliteParam::Json(json) => typed(TYPE_TEXT, json.get().as_bytes()),
+        };
+
+        let mut encrypted = Vec::with_capacity(HEADER_LEN + NONCE_LEN + plaintext.len() + TAG_LEN);
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.push(VERSION);
+        encrypted.extend_from_slice(&key_id.to_be_bytes());
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: &encrypted,
+                },
+            )
+            .map_err(|_| CryptoError::Encrypt)?;
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+
+        Ok(SqliteParam::Blob(encrypted.into()))
+    }
+
+    /// Whether `bytes` look like an encrypted value
+    pub fn is_encrypted_value(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Decrypts an encrypted value to the value it was
+    pub fn decrypt(&self, bytes: &[u8]) -> Result<SqliteValue, CryptoError> {
+        if bytes.len() < HEADER_LEN || !Self::is_encrypted_value(bytes) {
+            return Err(CryptoError::Malformed);
+        }
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(CryptoError::UnsupportedVersion(version));
+        }
+        let key_id = u32::from_be_bytes(
+            bytes[MAGIC.len() + 1..HEADER_LEN]
+                .try_into()
+                .expect("key id is 4 bytes"),
+        );
+        let cipher = self
+            .keys
+            .get(&key_id)
+            .ok_or(CryptoError::UnknownKey(key_id))?;
+
+        let (header, rest) = bytes.split_at(HEADER_LEN);
+        if rest.len() < NONCE_LEN + TAG_LEN {
+            return Err(CryptoError::Malformed);
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| CryptoError::Decrypt)?;
+
+        let Some((ty, bytes)) = plaintext.split_first() else {
+            return Err(CryptoError::Malformed);
+        };
+        Ok(match *ty {
+            TYPE_INTEGER => SqliteValue::Integer(i64::from_le_bytes(
+                bytes.try_into().map_err(|_| CryptoError::Malformed)?,
+            )),
+            TYPE_REAL => SqliteValue::Real(Real(f64::from_le_bytes(
+                bytes.try_into().map_err(|_| CryptoError::Malformed)?,
+            ))),
+            TYPE_TEXT => SqliteValue::Text(
+                CompactString::from_utf8(bytes).map_err(|_| CryptoError::Malformed)?,
+            ),
+            TYPE_BLOB => SqliteValue::Blob(bytes.into()),
+            _ => return Err(CryptoError::Malformed),
+        })
+    }
+
+    /// Decrypts the encrypted values of `evt`'s row in place, returning the
+    /// ones which couldn't be
+    pub fn decrypt_event(&self, evt: &mut QueryEvent) -> Vec<ValueError> {
+        let (rowid, cells) = match evt {
+            QueryEvent::Row(rowid, cells) | QueryEvent::Change(_, rowid, cells, _) => {
+                (*rowid, cells)
+            }
+            _ => return vec![],
+        };
+
+        let mut errors = vec![];
+        for (index, cell) in cells.iter_mut().enumerate() {
+            let SqliteValue::Blob(bytes) = cell else {
+                continue;
+            };
+            if !Self::is_encrypted_value(bytes) {
+                continue;
+            }
+            match self.decrypt(bytes) {
+                Ok(value) => *cell = value,
+                Err(error) => errors.push(ValueError {
+                    rowid,
+                    index,
+                    error,
+                }),
+            }
+        }
+        errors
+    }
+
+    /// Encrypts the params `statements` bind to encrypted columns in place.
+    ///
+    /// Only the params of `INSERT ... (columns) VALUES (...)` and of
+    /// `UPDATE ... SET column = ...`, upserts included, can be told apart.
+    /// Statements writing an encrypted column any other way, e.g. w/ a
+    /// literal or from a `SELECT`, fail w/ `CryptoError::Unsupported`.
+    pub fn encrypt_statements(&self, statements: &mut [Statement]) -> Result<(), CryptoError> {
+        if self.columns.is_empty() {
+            return Ok(());
+        }
+        for statement in statements {
+            self.encrypt_statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn encrypt_statement(&self, statement: &mut Statement) -> Result<(), CryptoError> {
+        // registered queries are read-only
+        if matches!(statement, Statement::Registered { .. }) {
+            return Ok(());
+        }
+
+        let (table, assigned) = match parse_write(&tokenize(statement.query())) {
+            Write::Columns(table, assigned) => (table, assigned),
+            Write::Opaque(table, reason) => {
+                return match self.columns.keys().find(|(t, _)| *t == table) {
+                    Some((_, column)) => Err(CryptoError::Unsupported {
+                        table,
+                        column: column.clone(),
+                        reason,
+                    }),
+                    None => Ok(()),
+                };
+            }
+            Write::Other => return Ok(()),
+        };
+
+        let mut encrypted = vec![];
+        for (column, value) in assigned {
+            let Some(key_id) = self.columns.get(&(table.clone(), column.clone())) else {
+                continue;
+            };
+            let param = match value {
+                Value::Param(param) => param,
+                Value::Null => continue,
+                // already encrypted
+                Value::Excluded(excluded) if excluded == column => continue,
+                Value::Excluded(_) | Value::Other => {
+                    return Err(CryptoError::Unsupported {
+                        table,
+                        column,
+                        reason: "its values must be bound as params",
+                    })
+                }
+            };
+            if encrypted.contains(&param) {
+                continue;
+            }
+            if let Some(bound) = bound_param(statement, &param) {
+                *bound = self.encrypt(*key_id, bound)?;
+            }
+            encrypted.push(param);
+        }
+
+        Ok(())
+    }
+}
+
+// the param bound to `param`, positionally or by name
+fn bound_param<'a>(statement: &'a mut Statement, param: &ParamRef) -> Option<&'a mut SqliteParam> {
+    match (statement, &param.name) {
+        (Statement::WithNamedParams(_, params), Some(name))
+        | (
+            Statement::Verbose {
+                named_params: Some(params),
+                ..
+            },
+            Some(name),
+        ) => params.get_mut(name),
+        (Statement::WithParams(_, params), None)
+        | (
+            Statement::Verbose {
+                params: Some(params),
+                ..
+            },
+            None,
+        ) => params.get_mut(param.index.checked_sub(1)?),
+        _ => None,
+    }
+}
+
+/// A query event w/ its values decrypted
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecryptedEvent {
+    pub event: QueryEvent,
+    /// Values which couldn't be decrypted, left encrypted in `event`
+    pub errors: Vec<ValueError>,
+}
+
+pin_project! {
+    /// Decrypts the values of a stream of query events, e.g. a subscription
+    /// or a query's rows. Values which can't be decrypted are reported along
+    /// w/ their event instead of ending the stream.
+    pub struct Decrypted<S> {
+        #[pin]
+        stream: S,
+        crypto: Option<Arc<ColumnCrypto>>,
+    }
+}
+
+impl<S> Decrypted<S> {
+    pub fn new(stream: S, crypto: Option<Arc<ColumnCrypto>>) -> Self {
+        Self { stream, crypto }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S, E> Stream for Decrypted<S>
+where
+    S: Stream<Item = Result<QueryEvent, E>>,
+{
+    type Item = Result<DecryptedEvent, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let res = ready!(this.stream.poll_next(cx));
+        Poll::Ready(res.map(|res| {
+            res.map(|mut event| {
+                let errors = match this.crypto {
+                    Some(crypto) => crypto.decrypt_event(&mut event),
+                    None => vec![],
+                };
+                DecryptedEvent { event, errors }
+            })
+        }))
+    }
+}
+
+// A param of a statement, by its index and its name, if it has one
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ParamRef {
+    index: usize,
+    name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    // unquoted, lowercased
+    Word(String),
+    // quoted identifier, lowercased
+    Quoted(String),
+    Param(ParamRef),
+    Literal,
+    Punct(u8),
+}
+
+impl Token {
+    fn is_word(&self, word: &str) -> bool {
+        matches!(self, Token::Word(w) if w == word)
+    }
+
+    fn ident(&self) -> Option<&str> {
+        match self {
+            Token::Word(w) | Token::Quoted(w) => Some(w),
+            _ => None,
+        }
+    }
+}
+
+// splits `sql` into tokens, numbering params like SQLite does
+fn tokenize(sql: &str) -> Vec<Token> {
+    let bytes = sql.as_bytes();
+    let mut tokens = vec![];
+    let mut max_index = 0;
+    let mut named: HashMap<String, usize> = HashMap::new();
+
+    let is_ident = |b: u8| b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b >= 0x80;
+    let ident_end = |from: usize| {
+        (from..bytes.len())
+            .find(|i| !is_ident(bytes[*i]))
+            .unwrap_or(bytes.len())
+    };
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        match b {
+            b if b.is_ascii_whitespace() => i += 1,
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                i = find(bytes, i + 2, b"\n").map_or(bytes.len(), |end| end + 1);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = find(bytes, i + 2, b"*/").map_or(bytes.len(), |end| end + 2);
+            }
+            quote @ (b'\'' | b'"' | b'`') => {
+                let mut value = vec![];
+                i += 1;
+                while i < bytes.len() {
+                    if bytes[i] == quote {
+                        if bytes.get(i + 1) == Some(&quote) {
+                            i += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    value.push(bytes[i]);
+                    i += 1;
+                }
+                i += 1;
+                tokens.push(if quote == b'\'' {
+                    Token::Literal
+                } else {
+                    Token::Quoted(String::from_utf8_lossy(&value).to_lowercase())
+                });
+            }
+            b'[' => {
+                let end = find(bytes, i + 1, b"]").unwrap_or(bytes.len());
+                tokens.push(Token::Quoted(
+                    String::from_utf8_lossy(&bytes[i + 1..end]).to_lowercase(),
+                ));
+                i = end + 1;
+            }
+            b'?' => {
+                let end = (i + 1..bytes.len())
+                    .find(|j| !bytes[*j].is_ascii_digit())
+                    .unwrap_or(bytes.len());
+                let index = match sql[i + 1..end].parse::<usize>() {
+                    Ok(index) => index,
+                    Err(_) => max_index + 1,
+                };
+                max_index = max_index.max(index);
+                tokens.push(Token::Param(ParamRef { index, name: None }));
+                i = end;
+            }
+            b':' | b'@' | b'$' if bytes.get(i + 1).map_or(false, |b| is_ident(*b)) => {
+                let end = ident_end(i + 1);
+                let name = sql[i..end].to_owned();
+                let index = *named.entry(name.clone()).or_insert_with(|| {
+                    max_index += 1;
+                    max_index
+                });
+                tokens.push(Token::Param(ParamRef {
+                    index,
+                    name: Some(name),
+                }));
+                i = end;
+            }
+            b if b.is_ascii_digit()
+                || (b == b'.' && bytes.get(i + 1).map_or(false, u8::is_ascii_digit)) =>
+            {
+                i = (i + 1..bytes.len())
+                    .find(|j| !(bytes[*j].is_ascii_alphanumeric() || bytes[*j] == b'.'))
+                    .unwrap_or(bytes.len());
+                tokens.push(Token::Literal);
+            }
+            b if is_ident(b) => {
+                let end = ident_end(i);
+                tokens.push(Token::Word(sql[i..end].to_lowercase()));
+                i = end;
+            }
+            b => {
+                tokens.push(Token::Punct(b));
+                i += 1;
+            }
+        }
+    }
+
+    tokens
+}
+
+// offset of the first `needle` in `bytes` from `from`
+fn find(bytes: &[u8], from: usize, needle: &[u8]) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| from + pos)
+}
+
+// what a column is assigned
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Param(ParamRef),
+    Null,
+    // `excluded.column` in an upsert
+    Excluded(String),
+    Other,
+}
+
+impl Value {
+    fn parse(mut expr: &[Token]) -> Self {
+        // `(?)`
+        while expr.len() > 2
+            && expr[0] == Token::Punct(b'(')
+            && expr[expr.len() - 1] == Token::Punct(b')')
+        {
+            expr = &expr[1..expr.len() - 1];
+        }
+        match expr {
+            [Token::Param(param)] => Value::Param(param.clone()),
+            [token] if token.is_word("null") => Value::Null,
+            [excluded, Token::Punct(b'.'), column] if excluded.is_word("excluded") => column
+                .ident()
+                .map_or(Value::Other, |column| Value::Excluded(column.to_owned())),
+            _ => Value::Other,
+        }
+    }
+}
+
+// what a statement writes
+#[derive(Debug, PartialEq, Eq)]
+enum Write {
+    // values assigned to columns of a table
+    Columns(String, Vec<(String, Value)>),
+    // writes to a table which can't be told apart
+    Opaque(String, &'static str),
+    Other,
+}
+
+fn parse_write(tokens: &[Token]) -> Write {
+    // past a `WITH` clause
+    let start = if tokens.first().map_or(false, |t| t.is_word("with")) {
+        let mut depth = 0i32;
+        tokens.iter().position(|token| {
+            match token {
+                Token::Punct(b'(') => depth += 1,
+                Token::Punct(b')') => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+                && (token.is_word("insert") || token.is_word("replace") || token.is_word("update"))
+        })
+    } else {
+        Some(0)
+    };
+    let Some(start) = start else {
+        return Write::Other;
+    };
+
+    let mut cursor = Cursor { tokens, pos: start };
+    match cursor.next() {
+        Some(token) if token.is_word("insert") || token.is_word("replace") => cursor.insert(),
+        Some(token) if token.is_word("update") => cursor.update(),
+        _ => Write::Other,
+    }
+}
+
+struct Cursor<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat_word(&mut self, word: &str) -> bool {
+        if self.peek().map_or(false, |t| t.is_word(word)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_punct(&mut self, punct: u8) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    // `[schema.]table`, w/o the schema
+    fn table(&mut self) -> Option<String> {
+        let mut table = self.next()?.ident()?.to_owned();
+        if self.eat_punct(b'.') {
+            table = self.next()?.ident()?.to_owned();
+        }
+        Some(table)
+    }
+
+    // tokens up to a `,` or `)` at depth 0, or one of the `until` words
+    fn expr(&mut self, until: &[&str]) -> &'a [Token] {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Punct(b'(') => depth += 1,
+                Token::Punct(b')') if depth == 0 => break,
+                Token::Punct(b')') => depth -= 1,
+                Token::Punct(b',') if depth == 0 => break,
+                token if depth == 0 && until.iter().any(|word| token.is_word(word)) => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        &self.tokens[start..self.pos]
+    }
+
+    // `INSERT [OR ...] INTO table [AS alias] (columns) VALUES (...), ...`
+    fn insert(&mut self) -> Write {
+        if self.eat_word("or") {
+            self.next();
+        }
+        if !self.eat_word("into") {
+            return Write::Other;
+        }
+        let Some(table) = self.table() else {
+            return Write::Other;
+        };
+        if self.eat_word("as") {
+            self.next();
+        }
+
+        if self.eat_word("default") {
+            return Write::Columns(table, vec![]);
+        }
+        if !self.eat_punct(b'(') {
+            return Write::Opaque(table, "inserts must list their columns");
+        }
+        let mut columns = vec![];
+        loop {
+            match self.next().and_then(Token::ident) {
+                Some(column) => columns.push(column.to_owned()),
+                None => return Write::Opaque(table, "inserts must list their columns"),
+            }
+            if self.eat_punct(b')') {
+                break;
+            }
+            if !self.eat_punct(b',') {
+                return Write::Opaque(table, "inserts must list their columns");
+            }
+        }
+
+        if !self.eat_word("values") {
+            // e.g. `INSERT ... SELECT`
+            return Write::Columns(
+                table,
+                columns.into_iter().map(|c| (c, Value::Other)).collect(),
+            );
+        }
+        let mut assigned = vec![];
+        loop {
+            if !self.eat_punct(b'(') {
+                return Write::Opaque(table, "its values must be bound as params");
+            }
+            let mut values = vec![];
+            loop {
+                values.push(Value::parse(self.expr(&[])));
+                if self.eat_punct(b')') {
+                    break;
+                }
+                if !self.eat_punct(b',') {
+                    return Write::Opaque(table, "its values must be bound as params");
+                }
+            }
+            if values.len() != columns.len() {
+                return Write::Opaque(table, "inserts must have a value per column");
+            }
+            assigned.extend(columns.iter().cloned().zip(values));
+            if !self.eat_punct(b',') {
+                break;
+            }
+        }
+
+        // upserts
+        while self.eat_word("on") {
+            if !self.eat_word("conflict") {
+                return Write::Other;
+            }
+            while let Some(token) = self.next() {
+                if token.is_word("do") {
+                    break;
+                }
+            }
+            if self.eat_word("update") {
+                if !self.eat_word("set") {
+                    return Write::Other;
+                }
+                if let Err(reason) = self.assignments(&mut assigned) {
+                    return Write::Opaque(table, reason);
+                }
+                // `WHERE` of the update
+                self.expr(&["on", "returning"]);
+            } else {
+                self.eat_word("nothing");
+            }
+        }
+
+        Write::Columns(table, assigned)
+    }
+
+    // `UPDATE [OR ...] table [AS alias] SET column = ..., ...`
+    fn update(&mut self) -> Write {
+        if self.eat_word("or") {
+            self.next();
+        }
+        let Some(table) = self.table() else {
+            return Write::Other;
+        };
+        // alias, `INDEXED BY` and such
+        while let Some(token) = self.next() {
+            if token.is_word("set") {
+                let mut assigned = vec![];
+                return match self.assignments(&mut assigned) {
+                    Ok(()) => Write::Columns(table, assigned),
+                    Err(reason) => Write::Opaque(table, reason),
+                };
+            }
+        }
+        Write::Other
+    }
+
+    fn assignments(&mut self, assigned: &mut Vec<(String, Value)>) -> Result<(), &'static str> {
+        loop {
+            if self.peek() == Some(&Token::Punct(b'(')) {
+                return Err("it can't be assigned as part of a row value");
+            }
+            let Some(column) = self.next().and_then(Token::ident) else {
+                return Err("its values must be bound as params");
+            };
+            if !self.eat_punct(b'=') {
+                return Err("its values must be bound as params");
+            }
+            let value = Value::parse(self.expr(&["from", "where", "returning", "on"]));
+            assigned.push((column.to_owned(), value));
+            if !self.eat_punct(b',') {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: u32) -> ColumnKey {
+        ColumnKey::new(id, [id as u8; 32])
+    }
+
+    fn crypto() -> ColumnCrypto {
+        ColumnCrypto::new().column("services", "meta", &key(1))
+    }
+
+    #[test]
+    fn round_trips_values() -> Result<(), CryptoError> {
+        let crypto = crypto();
+        for (param, value) in [
+            (SqliteParam::Integer(-42), SqliteValue::Integer(-42)),
+            (SqliteParam::Bool(true), SqliteValue::Integer(1)),
+            (SqliteParam::Real(1.5), SqliteValue::Real(Real(1.5))),
+            (
+                SqliteParam::Text("secret".into()),
+                SqliteValue::Text("secret".into()),
+            ),
+            (
+                SqliteParam::Blob(vec![1, 2, 3].into()),
+                SqliteValue::Blob(vec![1, 2, 3].into()),
+            ),
+            (SqliteParam::Text("".into()), SqliteValue::Text("".into())),
+        ] {
+            let SqliteParam::Blob(encrypted) = crypto.encrypt(1, &param)? else {
+                panic!("{param:?} wasn't encrypted as a blob");
+            };
+            assert!(ColumnCrypto::is_encrypted_value(&encrypted));
+            assert_eq!(crypto.decrypt(&encrypted)?, value);
+        }
+
+        assert_eq!(crypto.encrypt(1, &SqliteParam::Null)?, SqliteParam::Null);
+        assert_eq!(
+            crypto.encrypt(2, &SqliteParam::Integer(1)),
+            Err(CryptoError::UnknownKey(2))
+        );
+
+        // nonces are random
+        let text = SqliteParam::Text("secret".into());
+        assert_ne!(crypto.encrypt(1, &text)?, crypto.encrypt(1, &text)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_w_rotated_keys() -> Result<(), CryptoError> {
+        let old = crypto();
+        let SqliteParam::Blob(before) = old.encrypt(1, &SqliteParam::Integer(1))? else {
+            unreachable!()
+        };
+
+        // encrypts w/ key 2, still decrypts w/ key 1
+        let rotated = ColumnCrypto::new()
+            .column("services", "meta", &key(2))
+            .key(&key(1));
+        let SqliteParam::Blob(after) = rotated.encrypt(2, &SqliteParam::Integer(2))? else {
+            unreachable!()
+        };
+        assert_eq!(rotated.decrypt(&before)?, SqliteValue::Integer(1));
+        assert_eq!(rotated.decrypt(&after)?, SqliteValue::Integer(2));
+
+        assert_eq!(old.decrypt(&after), Err(CryptoError::UnknownKey(2)));
+
+        // a key w/ the same id but other bytes
+        let wrong = ColumnCrypto::new().key(&ColumnKey::new(1, [0xff; 32]));
+        assert_eq!(wrong.decrypt(&before), Err(CryptoError::Decrypt));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_tampered_values() -> Result<(), CryptoError> {
+        let crypto = ColumnCrypto::new().key(&key(1)).key(&key(2));
+        let SqliteParam::Blob(encrypted) = crypto.encrypt(1, &SqliteParam::Integer(1))? else {
+            unreachable!()
+        };
+
+        let mut flipped = encrypted.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert_eq!(crypto.decrypt(&flipped), Err(CryptoError::Decrypt));
+
+        // the key id is authenticated
+        let mut rekeyed = encrypted.clone();
+        rekeyed[HEADER_LEN - 1] = 2;
+        assert_eq!(crypto.decrypt(&rekeyed), Err(CryptoError::Decrypt));
+
+        let mut versioned = encrypted.clone();
+        versioned[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            crypto.decrypt(&versioned),
+            Err(CryptoError::UnsupportedVersion(VERSION + 1))
+        );
+
+        assert_eq!(
+            crypto.decrypt(&encrypted[..HEADER_LEN + 4]),
+            Err(CryptoError::Malformed)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_events() -> Result<(), CryptoError> {
+        let crypto = crypto();
+        let SqliteParam::Blob(encrypted) =
+            crypto.encrypt(1, &SqliteParam::Text("s3cr3t".into()))?
+        else {
+            unreachable!()
+        };
+
+        let mut evt = QueryEvent::Row(
+            RowId(1),
+            vec![
+                SqliteValue::Text("web".into()),
+                SqliteValue::Blob(encrypted.clone()),
+                SqliteValue::Blob(vec![1, 2].into()),
+                SqliteValue::Blob(b"CENC\x01\0\0\0\x01".to_vec().into()),
+            ],
+        );
+        let errors = crypto.decrypt_event(&mut evt);
+        assert_eq!(
+            errors,
+            [ValueError {
+                rowid: RowId(1),
+                index: 3,
+                error: CryptoError::Malformed
+            }]
+        );
+        let QueryEvent::Row(_, cells) = evt else {
+            unreachable!()
+        };
+        assert_eq!(
+            cells,
+            [
+                SqliteValue::Text("web".into()),
+                SqliteValue::Text("s3cr3t".into()),
+                SqliteValue::Blob(vec![1, 2].into()),
+                SqliteValue::Blob(b"CENC\x01\0\0\0\x01".to_vec().into()),
+            ]
+        );
+
+        Ok(())
+    }
+
+    // params of the statement which were encrypted
+    fn encrypted_params(statement: Statement) -> Result<Vec<usize>, CryptoError> {
+        let mut statements = [statement];
+        crypto().encrypt_statements(&mut statements)?;
+        Ok(statements[0]
+            .params()
+            .enumerate()
+            .filter(|(_, param)| {
+                matches!(param, SqliteParam::Blob(b) if ColumnCrypto::is_encrypted_value(b))
+            })
+            .map(|(i, _)| i)
+            .collect())
+    }
+
+    fn with_params(query: &str, count: usize) -> Statement {
+        Statement::WithParams(
+            query.into(),
+            (0..count).map(|i| SqliteParam::Integer(i as i64)).collect(),
+        )
+    }
+
+    #[test]
+    fn encrypts_bound_params() -> Result<(), CryptoError> {
+        assert_eq!(
+            encrypted_params(with_params(
+                "INSERT INTO services (id, meta) VALUES (?, ?), (?, ?)",
+                4
+            ))?,
+            [1, 3]
+        );
+        assert_eq!(
+            encrypted_params(with_params(
+                r#"INSERT OR REPLACE INTO main."Services" ("ID", [Meta]) VALUES (?2, ?1)"#,
+                2
+            ))?,
+            [0]
+        );
+        assert_eq!(
+            encrypted_params(with_params(
+                "INSERT INTO services (id, meta) VALUES (?, ?)
+                    ON CONFLICT (id) DO UPDATE SET meta = excluded.meta WHERE meta != ?",
+                3
+            ))?,
+            [1]
+        );
+        assert_eq!(
+            encrypted_params(with_params(
+                "INSERT INTO services (id, meta) VALUES (?, NULL)
+                    ON CONFLICT (id) DO UPDATE SET meta = ?",
+                2
+            ))?,
+            [1]
+        );
+        assert_eq!(
+            encrypted_params(with_params(
+                "UPDATE services SET name = ?, meta = ? WHERE id = ?",
+                3
+            ))?,
+            [1]
+        );
+        assert_eq!(
+            encrypted_params(with_params(
+                "WITH x AS (SELECT ?) UPDATE services AS s SET meta = (?) WHERE id IN (SELECT * FROM x)",
+                2
+            ))?,
+            [1]
+        );
+        assert_eq!(
+            encrypted_params(Statement::WithNamedParams(
+                "INSERT INTO services (id, meta) VALUES (:id, :meta)".into(),
+                [
+                    (":id".to_owned(), SqliteParam::Integer(1)),
+                    (":meta".to_owned(), SqliteParam::Text("{}".into())),
+                ]
+                .into_iter()
+                .collect()
+            ))?
+            .len(),
+            1
+        );
+
+        // other tables and columns, and statements not writing
+        for query in [
+            "INSERT INTO checks (id, meta) VALUES (?, ?)",
+            "UPDATE services SET name = ? WHERE id = ?",
+            "DELETE FROM services WHERE meta = ?",
+            "SELECT * FROM services WHERE meta = ? AND id = ?",
+            "INSERT INTO services DEFAULT VALUES",
+        ] {
+            assert!(
+                encrypted_params(with_params(query, 2))?.is_empty(),
+                "{query}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unsupported_writes() {
+        for query in [
+            "INSERT INTO services VALUES (?, ?)",
+            "INSERT INTO services (id, meta) SELECT id, meta FROM old",
+            "INSERT INTO services (id, meta) VALUES (?, 'plaintext')",
+            "INSERT INTO services (id, meta) VALUES (?, ? || '')",
+            "UPDATE services SET meta = 'plaintext'",
+            "UPDATE services SET (name, meta) = (?, ?)",
+            "INSERT INTO services (id, meta) VALUES (?, ?) ON CONFLICT DO UPDATE SET meta = excluded.id",
+        ] {
+            assert!(
+                matches!(
+                    encrypted_params(with_params(query, 2)),
+                    Err(CryptoError::Unsupported { .. })
+                ),
+                "{query}"
+            );
+        }
+    }
+}
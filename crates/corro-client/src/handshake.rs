@@ -0,0 +1,136 @@
+//! Client side of the stream handshake: subscriptions ask for the
+//! capabilities they use w/ the `CAPABILITIES_HEADER` and fail fast when the
+//! agent doesn't enable one they can't do without.
+
+use std::fmt;
+
+use compact_str::CompactString;
+use corro_api_types::{
+    parse_capabilities, CAPABILITIES_HEADER, CAPABILITY_BATCH, CAPABILITY_ORIGIN,
+    PROTOCOL_VERSION_HEADER,
+};
+use hyper::{http::HeaderValue, HeaderMap};
+
+/// The agent doesn't support stream capabilities the client requires
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub struct UnsupportedCapabilities {
+    /// The agent's protocol version, `None` if it predates the handshake
+    pub protocol_version: Option<u32>,
+    pub missing: Vec<CompactString>,
+}
+
+impl fmt::Display for UnsupportedCapabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let missing = self.missing.join(", ");
+        match self.protocol_version {
+            None => write!(
+                f,
+                "corrosion agent predates the stream handshake and can't be relied on for: {missing}, upgrade it"
+            ),
+            Some(version) => write!(
+                f,
+                "corrosion agent (protocol version {version}) doesn't support: {missing}, upgrade it"
+            ),
+        }
+    }
+}
+
+/// Capabilities a subscription can't do without. Batches are handed out one
+/// change at a time, agents which don't batch are fine too.
+pub(crate) fn required(origins: bool) -> &'static [&'static str] {
+    if origins {
+        &[CAPABILITY_ORIGIN]
+    } else {
+        &[]
+    }
+}
+
+/// Asks for a subscription's capabilities in `headers`
+pub(crate) fn request(headers: &mut HeaderMap, origins: bool) {
+    let value = if origins {
+        "batch,origin"
+    } else {
+        CAPABILITY_BATCH
+    };
+    headers.insert(CAPABILITIES_HEADER, HeaderValue::from_static(value));
+}
+
+/// Checks a response's headers enable every `required` capability
+pub(crate) fn check(headers: &HeaderMap, required: &[&str]) -> Result<(), UnsupportedCapabilities> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let protocol_version = header(PROTOCOL_VERSION_HEADER).and_then(|value| value.parse().ok());
+    let enabled = match protocol_version {
+        Some(_) => header(CAPABILITIES_HEADER)
+            .map(parse_capabilities)
+            .unwrap_or_default(),
+        None => vec![],
+    };
+
+    let missing: Vec<CompactString> = required
+        .iter()
+        .filter(|required| !enabled.iter().any(|enabled| enabled == *required))
+        .map(|required| CompactString::from(*required))
+        .collect();
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(UnsupportedCapabilities {
+            protocol_version,
+            missing,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(version: Option<&'static str>, capabilities: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(version) = version {
+            headers.insert(PROTOCOL_VERSION_HEADER, HeaderValue::from_static(version));
+            headers.insert(CAPABILITIES_HEADER, HeaderValue::from_static(capabilities));
+        }
+        headers
+    }
+
+    #[test]
+    fn checks_required_capabilities() {
+        let mut requested = HeaderMap::new();
+        request(&mut requested, true);
+        assert_eq!(
+            parse_capabilities(requested[CAPABILITIES_HEADER].to_str().unwrap()),
+            [CAPABILITY_BATCH, CAPABILITY_ORIGIN]
+        );
+        request(&mut requested, false);
+        assert_eq!(requested[CAPABILITIES_HEADER], CAPABILITY_BATCH);
+
+        // a plain subscription doesn't care how old the agent is
+        assert_eq!(check(&headers(None, ""), required(false)), Ok(()));
+        assert_eq!(check(&headers(Some("1"), ""), required(false)), Ok(()));
+
+        assert_eq!(
+            check(&headers(Some("1"), "batch,origin"), required(true)),
+            Ok(())
+        );
+
+        let err = check(&headers(Some("2"), "batch"), required(true)).unwrap_err();
+        assert_eq!(
+            err,
+            UnsupportedCapabilities {
+                protocol_version: Some(2),
+                missing: vec![CAPABILITY_ORIGIN.into()],
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "corrosion agent (protocol version 2) doesn't support: origin, upgrade it"
+        );
+
+        let err = check(&headers(None, ""), required(true)).unwrap_err();
+        assert_eq!(err.protocol_version, None);
+        assert_eq!(err.missing, [CAPABILITY_ORIGIN]);
+    }
+}
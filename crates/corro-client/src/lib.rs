@@ -1,65 +1,505 @@
+pub mod builder;
+pub mod crypto;
+pub mod handshake;
+pub mod outbox;
+pub mod pool;
+pub mod read;
+pub mod schema;
+pub mod snapshot;
 pub mod sub;
 
-use std::{net::SocketAddr, ops::Deref, path::Path};
+use std::{
+    borrow::Cow,
+    error::Error as _,
+    net::SocketAddr,
+    ops::Deref,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use corro_api_types::{ChangeId, ExecResponse, ExecResult, Statement};
-use http::uri::PathAndQuery;
-use hyper::{client::HttpConnector, http::HeaderName, Body, StatusCode};
-use sub::SubscriptionStream;
+pub use builder::{BuildError, ClientBuilder};
+use builder::{Endpoints, HttpClient};
+use corro_api_types::{
+    digest::{DigestEvent, DigestRequest, TableDigest},
+    row::{from_scalar, FromRow, FromSqliteValue, RowError},
+    AccessDenied, ActiveTransaction, AppliedMigration, ChangeId, CheckpointResult, Coercion,
+    ConfigResponse, ExecErrorCode, ExecEvent, ExecRequest, ExecResponse, ExecResult, HealthDetails,
+    MemberState, MigrateRequest, MigrateResponse, ProbeLatencies, QueryEvent, QueryPlan,
+    QuotaUsage, RegisteredQuery, ResumeGap, SchemaResponse, SchemaVersion, SessionOptions,
+    SqliteParam, SqliteValue, Statement, TableName, TableSchema, Throttled, UsageBucket,
+    DEGRADED_HEADER, IDEMPOTENCY_KEY_HEADER,
+};
+use crypto::{ColumnCrypto, CryptoError, Decrypted, ValueError};
+use futures::{Stream, StreamExt};
+use handshake::UnsupportedCapabilities;
+use http::{header::Entry, uri::PathAndQuery};
+use hyper::{
+    http::{HeaderMap, HeaderName, HeaderValue},
+    Body, StatusCode,
+};
+use outbox::{Outbox, OutboxError, OutboxOptions};
+use pool::{LocalConn, LocalPool};
+use read::{ndjson_events, read_local, ReadPreference, RowStream};
+use schema::{SchemaCache, DEFAULT_SCHEMA_MAX_AGE};
+use serde::Serialize;
+use snapshot::{MaterializedSubscription, SnapshotError};
+use sub::{SubscriptionHandle, SubscriptionStream};
 use tracing::{debug, warn};
 use uuid::Uuid;
 
+/// Events of a transaction executed w/ `CorrosionApiClient::execute_streaming`
+pub type ExecStream = Pin<Box<dyn Stream<Item = Result<ExecEvent, Error>> + Send>>;
+
+/// Columns and rows of one of the statements of
+/// `CorrosionApiClient::query_result_sets`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<SqliteValue>>,
+}
+
 #[derive(Clone)]
 pub struct CorrosionApiClient {
-    api_addr: SocketAddr,
-    api_client: hyper::Client<HttpConnector, Body>,
+    endpoints: Arc<Endpoints>,
+    scheme: &'static str,
+    api_client: HttpClient,
+    headers: Arc<HeaderMap>,
+    timeout: Option<Duration>,
+    bearer_token: Option<String>,
+    throttle_retries: u32,
+    degraded: Arc<AtomicBool>,
+    schema_cache: Arc<SchemaCache>,
+    crypto: Option<Arc<ColumnCrypto>>,
+    outbox: Option<Arc<Outbox>>,
 }
 
 impl CorrosionApiClient {
     pub fn new(api_addr: SocketAddr) -> Self {
+        Self::builder()
+            .endpoint(api_addr)
+            .build_api()
+            .expect("could not build client, this can't fail w/ an endpoint and default options")
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    pub(crate) fn from_parts(
+        endpoints: Endpoints,
+        scheme: &'static str,
+        api_client: HttpClient,
+        headers: HeaderMap,
+    ) -> Self {
         Self {
-            api_addr,
-            api_client: hyper::Client::builder().http2_only(true).build_http(),
+            endpoints: Arc::new(endpoints),
+            scheme,
+            api_client,
+            headers: Arc::new(headers),
+            timeout: None,
+            bearer_token: None,
+            throttle_retries: 0,
+            degraded: Arc::new(AtomicBool::new(false)),
+            schema_cache: Arc::new(SchemaCache::new(DEFAULT_SCHEMA_MAX_AGE)),
+            crypto: None,
+            outbox: None,
         }
     }
 
+    /// Fails requests w/ `Error::Timeout` if the agent doesn't respond within
+    /// `timeout`. Only covers receiving the response head, not streaming the
+    /// body of queries or subscriptions.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Authenticates requests w/ `token`, either the agent's
+    /// `api.authorization` token or one of its access policies' tokens
+    pub fn with_bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    /// Retries transactions throttled by the agent's quotas up to `retries`
+    /// times, each after waiting as long as the agent asked. They fail w/
+    /// `Error::Throttled` afterwards, or right away by default.
+    pub fn with_throttle_retries(mut self, retries: u32) -> Self {
+        self.throttle_retries = retries;
+        self
+    }
+
+    /// Uses cached table schemas for up to `max_age` before checking whether
+    /// the agent's schema changed, see `schema_for`. Responses carrying a
+    /// different schema version invalidate them right away.
+    pub fn with_schema_max_age(mut self, max_age: Duration) -> Self {
+        self.schema_cache = Arc::new(SchemaCache::new(max_age));
+        self
+    }
+
+    /// Encrypts the values statements write to `crypto`'s columns before
+    /// sending them, so agents only ever store ciphertext. `query_one`,
+    /// `query_scalar`, `query_decrypted` and `SubscriptionStream::decrypted`
+    /// decrypt them back.
+    ///
+    /// Encrypted values can't be compared, sorted or indexed by the agent,
+    /// e.g. `WHERE meta = ?` never matches. Statements writing an encrypted
+    /// column w/ anything else than a bound param fail w/ `Error::Crypto`.
+    pub fn with_column_crypto(mut self, crypto: ColumnCrypto) -> Self {
+        self.crypto = Some(Arc::new(crypto));
+        self
+    }
+
+    pub fn column_crypto(&self) -> Option<&Arc<ColumnCrypto>> {
+        self.crypto.as_ref()
+    }
+
+    /// Queues transactions passed to `execute_queued` to the journal at
+    /// `path`, executing them in the background, in order, whenever the
+    /// agent is reachable. Transactions queued before a restart are executed
+    /// too. Has to be called from within a tokio runtime.
+    ///
+    /// Each transaction is sent w/ its own `idempotency-key` header, so one
+    /// retried after its response got lost isn't applied twice. Agents only
+    /// remember the keys of transactions they applied themselves, retries
+    /// can't fail over to another endpoint w/o that guarantee.
+    pub fn with_outbox(
+        mut self,
+        path: impl AsRef<Path>,
+        options: OutboxOptions,
+    ) -> Result<Self, OutboxError> {
+        let outbox = Arc::new(Outbox::open(path, options)?);
+        // w/o the outbox, which it'd keep alive otherwise
+        tokio::spawn(outbox::drain(Arc::downgrade(&outbox), self.clone()));
+        self.outbox = Some(outbox);
+        Ok(self)
+    }
+
+    pub fn outbox(&self) -> Option<&Arc<Outbox>> {
+        self.outbox.as_ref()
+    }
+
+    /// A client sharing this one's connections, authenticating as `token`
+    /// instead. Cheap enough to call per request.
+    pub fn for_token(&self, token: impl Into<String>) -> Self {
+        self.clone().with_bearer_token(token)
+    }
+
+    /// Address of the agent API requests currently go to
+    pub fn api_addr(&self) -> SocketAddr {
+        self.endpoints.current()
+    }
+
+    fn url(&self, path_and_query: &str) -> String {
+        self.url_at(self.api_addr(), path_and_query)
+    }
+
+    pub(crate) fn url_at(&self, addr: SocketAddr, path_and_query: &str) -> String {
+        format!("{}://{addr}{path_and_query}", self.scheme)
+    }
+
+    /// Adds the default headers and authorization to a request's `headers`,
+    /// unless it already sets them
+    pub(crate) fn authorize(&self, headers: &mut HeaderMap) -> Result<(), http::Error> {
+        for (name, value) in self.headers.iter() {
+            if let Entry::Vacant(entry) = headers.entry(name) {
+                entry.insert(value.clone());
+            }
+        }
+        if let Some(token) = self.bearer_token.as_deref() {
+            if !headers.contains_key(hyper::header::AUTHORIZATION) {
+                headers.insert(hyper::header::AUTHORIZATION, bearer_header(token)?);
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn http(&self) -> &HttpClient {
+        &self.api_client
+    }
+
+    async fn send(&self, mut req: hyper::Request<Body>) -> Result<hyper::Response<Body>, Error> {
+        self.authorize(req.headers_mut())?;
+
+        let addr = req
+            .uri()
+            .authority()
+            .and_then(|authority| authority.as_str().parse().ok());
+        let res = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.api_client.request(req)).await?,
+            None => self.api_client.request(req).await,
+        };
+        let res = match (res, addr) {
+            (Err(e), Some(addr)) if e.is_connect() => {
+                // retrying the request, if the caller does, tries the next endpoint
+                self.endpoints.failed(addr);
+                Err(e)
+            }
+            (res, _) => res,
+        }?;
+        self.schema_cache.observe(&res);
+        Ok(res)
+    }
+
+    /// Whether the agent flagged its storage as degraded (e.g. low on disk
+    /// space or w/ an oversized WAL) in its last response to a write or
+    /// health check
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    fn track_degraded(&self, res: &hyper::Response<Body>) {
+        self.degraded.store(
+            res.headers().contains_key(DEGRADED_HEADER),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns the agent's storage health, checked on the spot
+    pub async fn health_details(&self) -> Result<HealthDetails, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/health"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        let health: HealthDetails = serde_json::from_slice(&bytes)?;
+        self.degraded.store(health.degraded, Ordering::Relaxed);
+
+        Ok(health)
+    }
+
     pub async fn query(&self, statement: &Statement) -> Result<hyper::Body, Error> {
+        self.queries(statement, "/v1/queries").await
+    }
+
+    /// Like `query`, but reads tables as they were right after `db_version`
+    /// was applied. Fails for versions older than the agent retains the
+    /// change history for.
+    pub async fn query_as_of(
+        &self,
+        statement: &Statement,
+        db_version: i64,
+    ) -> Result<hyper::Body, Error> {
+        self.queries(
+            statement,
+            &format!("/v1/queries?as_of_db_version={db_version}"),
+        )
+        .await
+    }
+
+    /// Like `query`, but converts values stored w/ the wrong type as told by
+    /// `coerce`. The `EndOfQuery` event lists the columns which were.
+    pub async fn query_coerced(
+        &self,
+        statement: &Statement,
+        coerce: Coercion,
+    ) -> Result<hyper::Body, Error> {
+        self.queries(
+            statement,
+            &format!("/v1/queries?coerce={}", coerce.as_str()),
+        )
+        .await
+    }
+
+    /// Runs `statements` against the same snapshot of the database, e.g. a
+    /// summary and the rows it sums up. Their result sets follow each other
+    /// in the response, each preceded by a `QueryEvent::NextResultSet`.
+    pub async fn query_multi(&self, statements: &[Statement]) -> Result<hyper::Body, Error> {
+        self.queries(statements, "/v1/queries/multi").await
+    }
+
+    /// Like `query_multi`, but collects every result set. A statement's
+    /// failure is returned as `Error::Statement`.
+    pub async fn query_result_sets(
+        &self,
+        statements: &[Statement],
+    ) -> Result<Vec<ResultSet>, Error> {
+        let mut events = ndjson_events::<QueryEvent>(self.query_multi(statements).await?);
+        let mut sets: Vec<ResultSet> = Vec::with_capacity(statements.len());
+        while let Some(event) = events.next().await {
+            let mut event = event?;
+            if let Some(crypto) = &self.crypto {
+                if let Some(error) = crypto.decrypt_event(&mut event).into_iter().next() {
+                    return Err(Error::Decrypt(error));
+                }
+            }
+            match event {
+                QueryEvent::NextResultSet { .. } => sets.push(ResultSet::default()),
+                QueryEvent::Columns { names, .. } => {
+                    if let Some(set) = sets.last_mut() {
+                        set.columns = names.into_iter().map(String::from).collect();
+                    }
+                }
+                QueryEvent::Row(_, cells) => {
+                    if let Some(set) = sets.last_mut() {
+                        set.rows.push(cells);
+                    }
+                }
+                event if event.is_shutting_down() => return Err(Error::ShuttingDown),
+                QueryEvent::Error(e) => {
+                    return Err(Error::Statement {
+                        index: sets.len().saturating_sub(1),
+                        message: e.into(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        if sets.len() < statements.len() {
+            return Err(Error::ResponseError(format!(
+                "response ended after {} of {} result sets",
+                sets.len(),
+                statements.len()
+            )));
+        }
+        Ok(sets)
+    }
+
+    async fn queries<T: Serialize + ?Sized>(
+        &self,
+        body: &T,
+        path: &str,
+    ) -> Result<hyper::Body, Error> {
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(format!("http://{}/v1/queries", self.api_addr))
+            .uri(self.url(path))
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
-            .body(Body::from(serde_json::to_vec(statement)?))?;
+            .body(Body::from(serde_json::to_vec(body)?))?;
 
-        let res = self.api_client.request(req).await?;
+        let res = error_for_status(self.send(req).await?).await?;
 
-        if !res.status().is_success() {
-            let status = res.status();
-            match hyper::body::to_bytes(res.into_body()).await {
-                Ok(b) => match serde_json::from_slice(&b) {
-                    Ok(res) => match res {
-                        ExecResult::Error { error } => return Err(Error::ResponseError(error)),
-                        res => return Err(Error::UnexpectedResult(res)),
-                    },
-                    Err(e) => {
-                        debug!(
-                            error = %e,
-                            "could not deserialize response body, sending generic error..."
-                        );
-                        return Err(Error::UnexpectedStatusCode(status));
+        Ok(res.into_body())
+    }
+
+    /// Returns the first column of the first row `statement` returns, or
+    /// `None` if it returns no rows. See `FromSqliteValue` for conversions.
+    pub async fn query_scalar<T: FromSqliteValue>(
+        &self,
+        statement: &Statement,
+    ) -> Result<Option<T>, Error> {
+        let (columns, mut rows) = self.query_rows(statement, 1).await?;
+        let Some(row) = rows.pop() else {
+            return Ok(None);
+        };
+        let name = columns.first().map(String::as_str).unwrap_or_default();
+        match row.into_iter().next() {
+            Some(value) => Ok(Some(from_scalar(value, name)?)),
+            None => Err(RowError::ColumnCount {
+                expected: 1,
+                actual: 0,
+            }
+            .into()),
+        }
+    }
+
+    /// Extracts the only row `statement` returns, erroring if it returns none
+    /// or more than one
+    pub async fn query_one<T: FromRow>(&self, statement: &Statement) -> Result<T, Error> {
+        let (columns, mut rows) = self.query_rows(statement, 2).await?;
+        match rows.len() {
+            0 => Err(Error::NoRows),
+            1 => Ok(T::from_values(&rows.remove(0), &columns)?),
+            _ => Err(Error::TooManyRows),
+        }
+    }
+
+    /// Whether `statement` returns any row. The query is wrapped in
+    /// `SELECT EXISTS(...)` so rows aren't sent back.
+    pub async fn exists(&self, statement: &Statement) -> Result<bool, Error> {
+        Ok(self
+            .query_scalar(&exists_statement(statement)?)
+            .await?
+            .unwrap_or(false))
+    }
+
+    /// Like `query`, but decrypts the values of encrypted columns w/ the keys
+    /// set w/ `with_column_crypto`. Values which can't be are reported along
+    /// w/ their row.
+    pub async fn query_decrypted(
+        &self,
+        statement: &Statement,
+    ) -> Result<Decrypted<RowStream>, Error> {
+        Ok(Decrypted::new(
+            RowStream::api(self.query(statement).await?),
+            self.crypto.clone(),
+        ))
+    }
+
+    // the columns and up to `limit` rows of a query, the rest isn't read
+    async fn query_rows(
+        &self,
+        statement: &Statement,
+        limit: usize,
+    ) -> Result<(Vec<String>, Vec<Vec<SqliteValue>>), Error> {
+        let mut events = ndjson_events::<QueryEvent>(self.query(statement).await?);
+        let mut columns = vec![];
+        let mut rows = vec![];
+        while let Some(event) = events.next().await {
+            let mut event = event?;
+            if let Some(crypto) = &self.crypto {
+                if let Some(error) = crypto.decrypt_event(&mut event).into_iter().next() {
+                    return Err(Error::Decrypt(error));
+                }
+            }
+            match event {
+                QueryEvent::Columns { names: cols, .. } => {
+                    columns = cols.into_iter().map(String::from).collect()
+                }
+                QueryEvent::Row(_, cells) => {
+                    rows.push(cells);
+                    if rows.len() >= limit {
+                        break;
                     }
-                },
-                Err(e) => {
-                    debug!(
-                        error = %e,
-                        "could not aggregate response body bytes, sending generic error..."
-                    );
-                    return Err(Error::UnexpectedStatusCode(status));
                 }
+                QueryEvent::EndOfQuery { .. } => break,
+                event if event.is_shutting_down() => return Err(Error::ShuttingDown),
+                QueryEvent::Error(e) => return Err(Error::ResponseError(e.into())),
+                _ => {}
             }
         }
+        Ok((columns, rows))
+    }
 
-        Ok(res.into_body())
+    /// Runs the query registered as `name` w/ `params`
+    pub async fn query_registered(
+        &self,
+        name: impl Into<String>,
+        params: Vec<SqliteParam>,
+    ) -> Result<hyper::Body, Error> {
+        self.query(&Statement::Registered {
+            name: name.into(),
+            params,
+        })
+        .await
+    }
+
+    /// Registers a named query template, replacing any previous one. Requires
+    /// the agent's admin token when it has one.
+    pub async fn register_query(
+        &self,
+        name: &str,
+        registered: &RegisteredQuery,
+    ) -> Result<(), Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::PUT)
+            .uri(self.url(&format!("/v1/queries/registered/{name}")))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(registered)?))?;
+
+        error_for_status(self.send(req).await?).await?;
+
+        Ok(())
     }
 
     pub async fn subscribe(
@@ -67,119 +507,696 @@ impl CorrosionApiClient {
         statement: &Statement,
         from: Option<ChangeId>,
     ) -> Result<SubscriptionStream, Error> {
+        self.subscribe_with(statement, from, false, None, false)
+            .await
+    }
+
+    /// Subscribes to `statement`, leaving out changes from transactions
+    /// tagged w/ `source_id` (see `ExecRequest::source_id`), e.g. the
+    /// subscriber's own writes. `QueryEvent::Skipped` markers are received
+    /// in their place.
+    pub async fn subscribe_skipping_source(
+        &self,
+        statement: &Statement,
+        from: Option<ChangeId>,
+        source_id: Uuid,
+    ) -> Result<SubscriptionStream, Error> {
+        self.subscribe_with(statement, from, false, Some(source_id), false)
+            .await
+    }
+
+    /// Subscribes to `statement` w/ a matcher shared by all subscriptions to
+    /// the same query w/ other params, when the agent supports the query's
+    /// shape. A shared stream only gets the changes of its own rows, so its
+    /// change ids aren't contiguous.
+    pub async fn subscribe_shared(
+        &self,
+        statement: &Statement,
+        from: Option<ChangeId>,
+    ) -> Result<SubscriptionStream, Error> {
+        self.subscribe_with(statement, from, true, None, false)
+            .await
+    }
+
+    /// Subscribes to `statement`, receiving a `QueryEvent::Origin` before
+    /// each change w/ the site id and name of the node it originates from.
+    pub async fn subscribe_with_origins(
+        &self,
+        statement: &Statement,
+        from: Option<ChangeId>,
+    ) -> Result<SubscriptionStream, Error> {
+        self.subscribe_with(statement, from, false, None, true)
+            .await
+    }
+
+    async fn subscribe_with(
+        &self,
+        statement: &Statement,
+        from: Option<ChangeId>,
+        shared: bool,
+        skip_source_id: Option<Uuid>,
+        origins: bool,
+    ) -> Result<SubscriptionStream, Error> {
+        // changes after `from` were purged, start over from a fresh snapshot
+        let (res, from, gap) = match self
+            .subscribe_request(statement, from, shared, skip_source_id, origins)
+            .await
+        {
+            Err(Error::ResumeGap(gap)) => (
+                self.subscribe_request(statement, None, shared, skip_source_id, origins)
+                    .await?,
+                None,
+                Some(gap),
+            ),
+            res => (res?, from, None),
+        };
+        handshake::check(res.headers(), handshake::required(origins))?;
+
+        // TODO: make that header name a const in corro-types
+        let id = res
+            .headers()
+            .get(HeaderName::from_static("corro-query-id"))
+            .and_then(|v| v.to_str().ok().and_then(|v| v.parse().ok()))
+            .ok_or(Error::ExpectedQueryId)?;
+
+        // the agent falls back to a dedicated matcher for unsupported shapes
+        let shared = res
+            .headers()
+            .contains_key(HeaderName::from_static("corro-query-shared"));
+
+        let stream =
+            SubscriptionStream::new(id, from, self.clone(), self.api_addr(), res.into_body())
+                .skip_source_id(skip_source_id)
+                .origins(origins)
+                .rebootstrapped(gap);
+
+        Ok(if shared {
+            stream.shared(statement.clone())
+        } else {
+            stream
+        })
+    }
+
+    async fn subscribe_request(
+        &self,
+        statement: &Statement,
+        from: Option<ChangeId>,
+        shared: bool,
+        skip_source_id: Option<Uuid>,
+        origins: bool,
+    ) -> Result<hyper::Response<Body>, Error> {
+        // `SubscriptionStream` hands out batched changes one at a time
+        let mut query = vec!["batch=true".to_owned()];
+        if shared {
+            query.push("shared=true".to_owned());
+        }
+        if let Some(change_id) = from {
+            query.push(format!("from={}", change_id.0));
+        }
+        if let Some(source_id) = skip_source_id {
+            query.push(format!("skip_source_id={source_id}"));
+        }
+        let p_and_q: PathAndQuery = format!("/v1/subscriptions?{}", query.join("&")).try_into()?;
+        let url = hyper::Uri::builder()
+            .scheme(self.scheme)
+            .authority(self.api_addr().to_string())
+            .path_and_query(p_and_q)
+            .build()?;
+
+        let mut req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(statement)?))?;
+        handshake::request(req.headers_mut(), origins);
+
+        error_for_status(self.send(req).await?).await
+    }
+
+    /// Resumes subscription `id` after `from`. If the agent already purged
+    /// changes after `from`, the stream starts over w/ a
+    /// `QueryEvent::Rebootstrapped` event followed by a fresh snapshot.
+    pub async fn subscription(
+        &self,
+        id: Uuid,
+        from: Option<ChangeId>,
+    ) -> Result<SubscriptionStream, Error> {
+        let (res, from, gap) = match self.subscription_request(id, from).await {
+            Err(Error::ResumeGap(gap)) => {
+                (self.subscription_request(id, None).await?, None, Some(gap))
+            }
+            res => (res?, from, None),
+        };
+
+        Ok(
+            SubscriptionStream::new(id, from, self.clone(), self.api_addr(), res.into_body())
+                .rebootstrapped(gap),
+        )
+    }
+
+    async fn subscription_request(
+        &self,
+        id: Uuid,
+        from: Option<ChangeId>,
+    ) -> Result<hyper::Response<Body>, Error> {
         let p_and_q: PathAndQuery = if let Some(change_id) = from {
-            format!("/v1/subscriptions?from={}", change_id.0).try_into()?
+            format!("/v1/subscriptions/{id}?batch=true&from={}", change_id.0).try_into()?
         } else {
-            PathAndQuery::from_static("/v1/subscriptions")
+            format!("/v1/subscriptions/{id}?batch=true").try_into()?
         };
         let url = hyper::Uri::builder()
-            .scheme("http")
-            .authority(self.api_addr.to_string())
+            .scheme(self.scheme)
+            .authority(self.api_addr().to_string())
             .path_and_query(p_and_q)
             .build()?;
 
+        let mut req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(url)
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(hyper::Body::empty())?;
+        handshake::request(req.headers_mut(), false);
+
+        error_for_status(self.send(req).await?).await
+    }
+
+    /// Subscribes to `statement`, materializing its rows so they can be
+    /// persisted w/ `MaterializedSubscription::persist` and handed over to
+    /// the next instance of the consumer.
+    ///
+    /// When `path` holds a snapshot of the same statement, the rows are
+    /// restored from it and the subscription is resumed after its last
+    /// change, only starting over from a fresh snapshot if the agent already
+    /// purged the changes since (see `subscription`). A missing, corrupt or
+    /// stale snapshot, or one of a subscription the agent doesn't have
+    /// anymore, is ignored in favor of subscribing anew.
+    pub async fn subscribe_with_snapshot(
+        &self,
+        path: impl AsRef<Path>,
+        statement: &Statement,
+    ) -> Result<MaterializedSubscription, Error> {
+        let path = path.as_ref();
+        let query_hash = snapshot::query_hash(statement)?;
+
+        match snapshot::restore(path, query_hash).await {
+            Ok(restored) => {
+                let from = restored.state.change_id();
+                match self.subscription(restored.id, Some(from)).await {
+                    Ok(stream) => {
+                        debug!(
+                            "resumed subscription {} from {} w/ {} rows",
+                            restored.id,
+                            from.0,
+                            restored.state.rows().len()
+                        );
+                        return Ok(MaterializedSubscription::new(
+                            stream,
+                            query_hash,
+                            restored.state,
+                        ));
+                    }
+                    Err(e) => {
+                        warn!(
+                            "could not resume subscription {} from {}, subscribing anew: {e}",
+                            restored.id,
+                            path.display()
+                        );
+                    }
+                }
+            }
+            Err(SnapshotError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no snapshot at {}, subscribing anew", path.display());
+            }
+            Err(e) => {
+                warn!(
+                    "ignoring snapshot at {}, subscribing anew: {e}",
+                    path.display()
+                );
+            }
+        }
+
+        let stream = self.subscribe(statement, None).await?;
+        Ok(MaterializedSubscription::new(
+            stream,
+            query_hash,
+            Default::default(),
+        ))
+    }
+
+    /// Handle to rebind an existing subscription to new params of `query`
+    pub fn subscription_handle(&self, id: Uuid, query: impl Into<String>) -> SubscriptionHandle {
+        SubscriptionHandle::new(id, query.into(), self.clone())
+    }
+
+    /// Rebinds subscription `id` to `statement`, which must only differ from
+    /// the subscription's query by its params. Returns the change id of the
+    /// `QueryEvent::Rebound` marker sent to subscribers.
+    pub async fn rebind_subscription(
+        &self,
+        id: Uuid,
+        statement: &Statement,
+    ) -> Result<ChangeId, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(self.url(&format!("/v1/subscriptions/{id}/rebind")))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(statement)?))?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        match serde_json::from_slice(&bytes)? {
+            QueryEvent::Rebound { change_id } => Ok(change_id),
+            evt => Err(Error::ResponseError(format!(
+                "unexpected rebind response: {evt:?}"
+            ))),
+        }
+    }
+
+    pub async fn execute(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+        self.transactions(&*self.encrypted(statements)?).await
+    }
+
+    /// Queues statements to be executed in a transaction by the outbox, see
+    /// `with_outbox`. Returns once they're synced to its journal, w/ their
+    /// position in it. Fails w/ `OutboxError::Full` when the outbox is full
+    /// and set to fail fast.
+    pub async fn execute_queued(&self, statements: &[Statement]) -> Result<i64, Error> {
+        let outbox = self.outbox.as_ref().ok_or(OutboxError::NotConfigured)?;
+        let body = serde_json::to_vec(&*self.encrypted(statements)?)?;
+        Ok(outbox::queue(outbox, body).await?)
+    }
+
+    /// Like `execute`, but fails w/ `Error::Statement` for the first statement
+    /// which failed. The other statements of the transaction still applied.
+    pub async fn execute_strict(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+        let res = self.transactions(&*self.encrypted(statements)?).await?;
+
+        if let Some((index, message)) =
+            res.results
+                .iter()
+                .enumerate()
+                .find_map(|(index, result)| match result {
+                    ExecResult::Error { error, .. } => Some((index, error.clone())),
+                    ExecResult::Execute { .. } => None,
+                })
+        {
+            return Err(Error::Statement { index, message });
+        }
+
+        Ok(res)
+    }
+
+    /// Executes each group of statements atomically in its own savepoint, a
+    /// failing group doesn't prevent the others from being applied. Results
+    /// are returned per group.
+    pub async fn execute_grouped(
+        &self,
+        groups: Vec<Vec<Statement>>,
+    ) -> Result<ExecResponse, Error> {
+        self.transactions(&*self.encrypted_request(&ExecRequest::grouped(groups))?)
+            .await
+    }
+
+    /// Executes statements in a transaction w/ connection settings such as
+    /// `PRAGMA busy_timeout` applied for its duration only
+    pub async fn execute_with_options(
+        &self,
+        statements: &[Statement],
+        session: SessionOptions,
+    ) -> Result<ExecResponse, Error> {
+        let statements = self.encrypted(statements)?.into_owned();
+        self.transactions(&ExecRequest::from(statements).session(session))
+            .await
+    }
+
+    /// Runs statements in a transaction which is always rolled back, to find
+    /// out what they'd do: results and `changes_generated` are reported as if
+    /// it committed, w/ `ExecResponse::dry_run` set
+    pub async fn execute_dry_run(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+        let statements = self.encrypted(statements)?.into_owned();
+        self.transactions(&ExecRequest::from(statements).dry_run())
+            .await
+    }
+
+    /// Executes statements along with execution options, e.g. deferring
+    /// foreign key checks w/ `ExecRequest::defer_foreign_keys`
+    pub async fn execute_request(&self, req: &ExecRequest) -> Result<ExecResponse, Error> {
+        self.transactions(&*self.encrypted_request(req)?).await
+    }
+
+    /// Executes statements in a single transaction, streaming the rows they
+    /// return (e.g. w/ `RETURNING`) as they're produced. The transaction only
+    /// commits after the last statement: rows streamed before an
+    /// `ExecEvent::Error` were rolled back.
+    pub async fn execute_streaming(&self, req: &ExecRequest) -> Result<ExecStream, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(self.url("/v1/transactions"))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(
+                &self.encrypted_request(req)?.into_owned().stream_returning(),
+            )?))?;
+
+        let res = self.send(req).await?;
+        self.track_degraded(&res);
+        let res = error_for_status(res).await?;
+
+        Ok(Box::pin(ndjson_events(res.into_body())))
+    }
+
+    // `statements` w/ the values they write to encrypted columns encrypted
+    fn encrypted<'a>(&self, statements: &'a [Statement]) -> Result<Cow<'a, [Statement]>, Error> {
+        let Some(crypto) = &self.crypto else {
+            return Ok(Cow::Borrowed(statements));
+        };
+        let mut statements = statements.to_vec();
+        crypto.encrypt_statements(&mut statements)?;
+        Ok(Cow::Owned(statements))
+    }
+
+    fn encrypted_request<'a>(&self, req: &'a ExecRequest) -> Result<Cow<'a, ExecRequest>, Error> {
+        let Some(crypto) = &self.crypto else {
+            return Ok(Cow::Borrowed(req));
+        };
+        let mut req = req.clone();
+        crypto.encrypt_statements(req.statements_mut())?;
+        Ok(Cow::Owned(req))
+    }
+
+    async fn transactions<B: Serialize + ?Sized>(&self, body: &B) -> Result<ExecResponse, Error> {
+        self.transactions_bytes(serde_json::to_vec(body)?.into(), None)
+            .await
+    }
+
+    pub(crate) async fn transactions_bytes(
+        &self,
+        body: bytes::Bytes,
+        idempotency_key: Option<&str>,
+    ) -> Result<ExecResponse, Error> {
+        let mut retries = self.throttle_retries;
+
+        loop {
+            let mut req = hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(self.url("/v1/transactions"))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .header(hyper::header::ACCEPT, "application/json");
+            if let Some(key) = idempotency_key {
+                req = req.header(IDEMPOTENCY_KEY_HEADER, key);
+            }
+            let req = req.body(Body::from(body.clone()))?;
+
+            let res = self.send(req).await?;
+            self.track_degraded(&res);
+            let res = match error_for_status(res).await {
+                Err(Error::Throttled(throttled)) if retries > 0 => {
+                    retries -= 1;
+                    debug!("throttled by corrosion, retrying: {throttled}");
+                    tokio::time::sleep(Duration::from_millis(throttled.retry_after_ms)).await;
+                    continue;
+                }
+                res => res?,
+            };
+
+            let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+            return Ok(serde_json::from_slice(&bytes)?);
+        }
+    }
+
+    /// Lists transactions running or waiting for the agent's write
+    /// connection, oldest first
+    pub async fn active_transactions(&self) -> Result<Vec<ActiveTransaction>, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/transactions/active"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Lists what's left of the quotas of the agent's recent clients
+    pub async fn quotas(&self) -> Result<Vec<QuotaUsage>, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/quotas"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Kills active transaction `id`, it rolls back and its client gets
+    /// `Error::Interrupted`. Requires the agent's admin token.
+    pub async fn kill_transaction(&self, id: u64) -> Result<ActiveTransaction, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(self.url(&format!("/v1/transactions/active/{id}/kill")))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Returns sqlite's query plan for a statement, without running it
+    pub async fn explain(&self, statement: &Statement) -> Result<QueryPlan, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(self.url("/v1/explain"))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(statement)?))?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// The agent's replicated tables, fetched on the spot
+    pub async fn current_schema(&self) -> Result<SchemaResponse, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/schema"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// The agent's schema version, which changes whenever its schema does
+    pub async fn schema_version(&self) -> Result<u64, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/schema/version"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice::<SchemaVersion>(&bytes)?.version)
+    }
+
+    /// The schema of `table`, `None` if the agent has no such replicated
+    /// table. Cached until the agent's schema version changes, e.g. after a
+    /// migration, so callers don't have to invalidate anything.
+    pub async fn schema_for(&self, table: &TableName) -> Result<Option<TableSchema>, Error> {
+        self.schema_cache.get(self, table).await
+    }
+
+    pub async fn schema(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(self.url("/v1/migrations"))
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::from(serde_json::to_vec(statements)?))?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Applies migration steps in order, skipping the ones already applied
+    /// on the node. A failing step is returned as `Error::Migration`, along
+    /// w/ the steps applied before it.
+    pub async fn migrate(&self, req: &MigrateRequest) -> Result<MigrateResponse, Error> {
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(url)
+            .uri(self.url("/v1/migrations/apply"))
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
-            .body(Body::from(serde_json::to_vec(statement)?))?;
+            .body(Body::from(serde_json::to_vec(req)?))?;
 
-        let res = self.api_client.request(req).await?;
+        let res = self.send(req).await?;
+        let status = res.status();
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
 
-        if !res.status().is_success() {
-            return Err(Error::UnexpectedStatusCode(res.status()));
+        if status.is_success() {
+            return Ok(serde_json::from_slice(&bytes)?);
         }
 
-        // TODO: make that header name a const in corro-types
-        let id = res
-            .headers()
-            .get(HeaderName::from_static("corro-query-id"))
-            .and_then(|v| v.to_str().ok().and_then(|v| v.parse().ok()))
-            .ok_or(Error::ExpectedQueryId)?;
-
-        Ok(SubscriptionStream::new(
-            id,
-            from,
-            self.api_client.clone(),
-            self.api_addr,
-            res.into_body(),
-        ))
+        match serde_json::from_slice::<MigrateResponse>(&bytes) {
+            Ok(res) if res.failed.is_some() => Err(Error::Migration(Box::new(res))),
+            Ok(MigrateResponse {
+                error: Some(body), ..
+            }) => Err(Error::Http { status, body }),
+            _ => Err(Error::Http {
+                status,
+                body: error_message(&bytes),
+            }),
+        }
     }
 
-    pub async fn subscription(
-        &self,
-        id: Uuid,
-        from: Option<ChangeId>,
-    ) -> Result<SubscriptionStream, Error> {
-        let p_and_q: PathAndQuery = if let Some(change_id) = from {
-            format!("/v1/subscriptions/{id}?from={}", change_id.0).try_into()?
-        } else {
-            format!("/v1/subscriptions/{id}").try_into()?
-        };
-        let url = hyper::Uri::builder()
-            .scheme("http")
-            .authority(self.api_addr.to_string())
-            .path_and_query(p_and_q)
-            .build()?;
+    /// Migration steps applied on the node, in the order they were applied
+    pub async fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/migrations/applied"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
 
+    /// The agent's effective config, w/ its tokens and keys redacted
+    pub async fn config(&self) -> Result<ConfigResponse, Error> {
         let req = hyper::Request::builder()
             .method(hyper::Method::GET)
-            .uri(url)
+            .uri(self.url("/v1/config"))
             .header(hyper::header::ACCEPT, "application/json")
-            .body(hyper::Body::empty())?;
+            .body(Body::empty())?;
 
-        let res = self.api_client.request(req).await?;
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
 
-        if !res.status().is_success() {
-            return Err(Error::UnexpectedStatusCode(res.status()));
-        }
+        Ok(serde_json::from_slice(&bytes)?)
+    }
 
-        Ok(SubscriptionStream::new(
-            id,
-            from,
-            self.api_client.clone(),
-            self.api_addr,
-            res.into_body(),
-        ))
+    /// Propagation latencies of the agent's peers' heartbeats, empty unless
+    /// it's configured w/ `gossip.probe`
+    pub async fn cluster_latency(&self) -> Result<ProbeLatencies, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/cluster/latency"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
     }
 
-    pub async fn execute(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+    /// The agent's peers and how far behind it is on each one's changes
+    /// Checkpoints the WAL and truncates it, unless readers are still using
+    /// it, see `CheckpointResult::busy`
+    pub async fn checkpoint(&self) -> Result<CheckpointResult, Error> {
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(format!("http://{}/v1/transactions", self.api_addr))
-            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .uri(self.url("/v1/db/checkpoint"))
             .header(hyper::header::ACCEPT, "application/json")
-            .body(Body::from(serde_json::to_vec(statements)?))?;
+            .body(Body::empty())?;
 
-        let res = self.api_client.request(req).await?;
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
 
-        if !res.status().is_success() {
-            return Err(Error::UnexpectedStatusCode(res.status()));
-        }
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    pub async fn cluster_members(&self) -> Result<Vec<MemberState>, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url("/v1/cluster/members"))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
 
+        let res = error_for_status(self.send(req).await?).await?;
         let bytes = hyper::body::to_bytes(res.into_body()).await?;
 
         Ok(serde_json::from_slice(&bytes)?)
     }
 
-    pub async fn schema(&self, statements: &[Statement]) -> Result<ExecResponse, Error> {
+    /// Hourly usage of the agent's subscriptions and clients since the hour
+    /// `since` (a unix timestamp in seconds) falls in
+    pub async fn usage(&self, since: u64) -> Result<Vec<UsageBucket>, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(self.url(&format!("/v1/usage?since={since}")))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = error_for_status(self.send(req).await?).await?;
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Digest of a table's rows, or of a range of them, see
+    /// `corro_api_types::digest`
+    pub async fn digest(&self, req: &DigestRequest) -> Result<TableDigest, Error> {
         let req = hyper::Request::builder()
             .method(hyper::Method::POST)
-            .uri(format!("http://{}/v1/migrations", self.api_addr))
+            .uri(self.url("/v1/digests"))
             .header(hyper::header::CONTENT_TYPE, "application/json")
             .header(hyper::header::ACCEPT, "application/json")
-            .body(Body::from(serde_json::to_vec(statements)?))?;
+            .body(Body::from(serde_json::to_vec(req)?))?;
 
-        let res = self.api_client.request(req).await?;
+        let res = error_for_status(self.send(req).await?).await?;
+        let mut events = Box::pin(ndjson_events::<DigestEvent>(res.into_body()));
 
-        if !res.status().is_success() {
-            return Err(Error::UnexpectedStatusCode(res.status()));
+        let mut columns = vec![];
+        let mut pk = 0;
+        let mut chunks = vec![];
+        let mut rows = vec![];
+        while let Some(event) = events.next().await {
+            match event? {
+                DigestEvent::Columns { names, pk: n } => {
+                    columns = names;
+                    pk = n;
+                }
+                DigestEvent::Row(row) => rows.push(row),
+                DigestEvent::Chunk(chunk) => chunks.push(chunk),
+                DigestEvent::Done(digest) => {
+                    return Ok(TableDigest {
+                        columns,
+                        pk,
+                        chunks,
+                        rows,
+                        digest,
+                    })
+                }
+                DigestEvent::Error(e) => return Err(Error::ResponseError(e)),
+            }
         }
 
-        let bytes = hyper::body::to_bytes(res.into_body()).await?;
-
-        Ok(serde_json::from_slice(&bytes)?)
+        Err(Error::ResponseError(
+            "digest ended before it was done".into(),
+        ))
     }
 
     pub async fn schema_from_paths<P: AsRef<Path>>(
@@ -272,23 +1289,74 @@ impl CorrosionApiClient {
 #[derive(Clone)]
 pub struct CorrosionClient {
     api_client: CorrosionApiClient,
-    pool: sqlite_pool::RusqlitePool,
+    db_path: PathBuf,
+    pool: LocalPool,
 }
 
 impl CorrosionClient {
     pub fn new<P: AsRef<Path>>(api_addr: SocketAddr, db_path: P) -> Self {
-        Self {
-            api_client: CorrosionApiClient::new(api_addr),
-            pool: sqlite_pool::Config::new(db_path.as_ref())
-                .max_size(5)
-                .create_pool()
-                .expect("could not build pool, this can't fail because we specified a runtime"),
-        }
+        Self::builder()
+            .endpoint(api_addr)
+            .db_path(db_path.as_ref())
+            .build()
+            .expect("could not build client, this can't fail because we specified a runtime")
+    }
+
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::new()
+    }
+
+    /// Logs local statements taking longer than `threshold`, see
+    /// `LocalConn::timed`
+    pub fn with_slow_statements(self, threshold: Duration) -> Self {
+        self.pool.set_slow_threshold(Some(threshold));
+        self
     }
 
-    pub fn pool(&self) -> &sqlite_pool::RusqlitePool {
+    pub fn pool(&self) -> &LocalPool {
         &self.pool
     }
+
+    /// Runs a read-only statement against the local database file or through
+    /// the agent's HTTP API, depending on `pref`.
+    ///
+    /// Registered queries are only known to the agent, they're always read
+    /// through its API.
+    pub async fn read(&self, stmt: &Statement, pref: ReadPreference) -> Result<RowStream, Error> {
+        if let Statement::Registered { name, .. } = stmt {
+            if pref == ReadPreference::Local {
+                return Err(Error::ResponseError(format!(
+                    "registered query '{name}' can't be read locally"
+                )));
+            }
+            return Ok(RowStream::api(self.api_client.query(stmt).await?));
+        }
+
+        if pref != ReadPreference::Api {
+            match self.local_conn().await {
+                Ok(conn) => return read_local(conn, stmt.clone()).await,
+                Err(e) if pref == ReadPreference::Local => return Err(e),
+                Err(e) => {
+                    debug!("local database is not readable, falling back to the api: {e}");
+                }
+            }
+        }
+
+        Ok(RowStream::api(self.api_client.query(stmt).await?))
+    }
+
+    async fn local_conn(&self) -> Result<LocalConn, Error> {
+        // opening the pool's connection would create a missing file
+        let meta = tokio::fs::metadata(&self.db_path).await?;
+        if !meta.is_file() {
+            return Err(Error::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a file", self.db_path.display()),
+            )));
+        }
+
+        Ok(self.pool.get().await?)
+    }
 }
 
 impl Deref for CorrosionClient {
@@ -299,26 +1367,985 @@ impl Deref for CorrosionClient {
     }
 }
 
+const HTTP_BODY_EXCERPT_LEN: usize = 512;
+
+pub(crate) fn bearer_header(token: &str) -> Result<HeaderValue, http::Error> {
+    Ok(HeaderValue::try_from(format!("Bearer {token}"))?)
+}
+
+/// Passes successful responses through, turns others into `Error::Http`
+async fn error_for_status(res: hyper::Response<Body>) -> Result<hyper::Response<Body>, Error> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+
+    let body = match hyper::body::to_bytes(res.into_body()).await {
+        Ok(bytes) if status == StatusCode::FORBIDDEN => {
+            match serde_json::from_slice::<AccessDenied>(&bytes) {
+                Ok(denied) => return Err(Error::AccessDenied(denied)),
+                Err(_) => error_message(&bytes),
+            }
+        }
+        Ok(bytes) if status == StatusCode::GONE => {
+            match serde_json::from_slice::<ResumeGap>(&bytes) {
+                Ok(gap) => return Err(Error::ResumeGap(gap)),
+                Err(_) => error_message(&bytes),
+            }
+        }
+        Ok(bytes) if status == StatusCode::TOO_MANY_REQUESTS => {
+            match serde_json::from_slice::<Throttled>(&bytes) {
+                Ok(throttled) => return Err(Error::Throttled(throttled)),
+                Err(_) => error_message(&bytes),
+            }
+        }
+        Ok(bytes) if status == StatusCode::CONFLICT && is_interrupted(&bytes) => {
+            return Err(Error::Interrupted(error_message(&bytes)));
+        }
+        Ok(bytes) => error_message(&bytes),
+        Err(e) => {
+            debug!(error = %e, "could not aggregate error response body");
+            String::new()
+        }
+    };
+
+    Err(Error::Http { status, body })
+}
+
+/// Whether the agent responded w/ a transaction it interrupted
+fn is_interrupted(body: &[u8]) -> bool {
+    serde_json::from_slice::<ExecResponse>(body).is_ok_and(|res| {
+        res.results.iter().any(|result| {
+            matches!(
+                result,
+                ExecResult::Error {
+                    code: Some(ExecErrorCode::Interrupted),
+                    ..
+                }
+            )
+        })
+    })
+}
+
+/// The error the agent responded w/, or an excerpt of the body if it isn't one
+fn error_message(body: &[u8]) -> String {
+    if let Ok(ExecResult::Error { error, .. }) = serde_json::from_slice(body) {
+        return error;
+    }
+    if let Ok(res) = serde_json::from_slice::<ExecResponse>(body) {
+        if let Some(error) = res.results.into_iter().find_map(|result| match result {
+            ExecResult::Error { error, .. } => Some(error),
+            ExecResult::Execute { .. } => None,
+        }) {
+            return error;
+        }
+    }
+
+    let body = String::from_utf8_lossy(body);
+    match body.char_indices().nth(HTTP_BODY_EXCERPT_LEN) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.into_owned(),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
+    /// The agent could not be reached
+    #[error("could not connect to corrosion: {0}")]
+    Connect(std::io::Error),
+    /// The agent didn't respond in time, see `CorrosionApiClient::with_timeout`
+    #[error("timed out waiting for corrosion")]
+    Timeout,
+    /// The agent responded w/ a non-success status, `body` is the error it
+    /// sent back or an excerpt of the response body
+    #[error("corrosion responded w/ {status}: {body}")]
+    Http { status: StatusCode, body: String },
+    /// A statement failed, only returned by methods checking every result
+    #[error("statement {index} failed: {message}")]
+    Statement { index: usize, message: String },
+    /// The token's access policy doesn't allow something the statement
+    /// reads, or the endpoint itself
+    #[error(transparent)]
+    AccessDenied(AccessDenied),
+    /// Changes a subscription was resumed from were already purged, it has
+    /// to start over from a fresh snapshot
+    #[error(transparent)]
+    ResumeGap(ResumeGap),
+    /// The transaction was killed, e.g. w/ `corrosion ops kill`, and rolled
+    /// back
+    #[error("transaction interrupted: {0}")]
+    Interrupted(String),
+    /// A migration step failed and was rolled back, later steps weren't
+    /// attempted. The response lists the steps applied before it.
+    #[error(
+        "migration step '{}' failed: {}",
+        .0.failed.as_deref().unwrap_or_default(),
+        .0.error.as_deref().unwrap_or_default()
+    )]
+    Migration(Box<MigrateResponse>),
+    /// The agent ended the response to shut down, the call can be retried
+    /// right away against another agent
+    #[error("corrosion is shutting down")]
+    ShuttingDown,
+    /// The client is over one of the agent's quotas, see `Error::retry_after`
+    #[error(transparent)]
+    Throttled(Throttled),
+    /// The agent is too old for a capability the subscription requires,
+    /// e.g. origins
     #[error(transparent)]
-    Hyper(#[from] hyper::Error),
+    UnsupportedCapabilities(#[from] UnsupportedCapabilities),
+
+    #[error(transparent)]
+    Hyper(hyper::Error),
     #[error(transparent)]
     InvalidUri(#[from] http::uri::InvalidUri),
     #[error(transparent)]
-    Http(#[from] hyper::http::Error),
+    InvalidRequest(#[from] hyper::http::Error),
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
-
-    #[error("received unexpected response code: {0}")]
-    UnexpectedStatusCode(StatusCode),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Pool(#[from] sqlite_pool::PoolError),
+    #[error(transparent)]
+    Sqlite(#[from] sqlite_pool::rusqlite::Error),
 
     #[error("{0}")]
     ResponseError(String),
 
-    #[error("unexpected result: {0:?}")]
-    UnexpectedResult(ExecResult),
-
     #[error("could not retrieve subscription id from headers")]
     ExpectedQueryId,
+
+    /// `query_one`'s query returned no rows
+    #[error("query returned no rows")]
+    NoRows,
+    /// `query_one`'s query returned more than one row
+    #[error("query returned more than one row")]
+    TooManyRows,
+    #[error(transparent)]
+    Row(#[from] RowError),
+    /// A statement couldn't be encrypted, see
+    /// `CorrosionApiClient::with_column_crypto`
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    /// A value read back couldn't be decrypted, e.g. w/o the key it was
+    /// encrypted w/
+    #[error(transparent)]
+    Decrypt(ValueError),
+    /// A transaction couldn't be queued, see
+    /// `CorrosionApiClient::with_outbox`
+    #[error(transparent)]
+    Outbox(#[from] OutboxError),
+}
+
+impl Error {
+    /// Whether the same call could succeed if retried later, as opposed to
+    /// failing again until something changes on the caller's side
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Connect(_)
+            | Error::Timeout
+            | Error::ShuttingDown
+            | Error::Throttled(_)
+            | Error::Hyper(_)
+            | Error::Io(_) => true,
+            Error::Http { status, .. } => {
+                status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS
+            }
+            Error::Pool(e) => matches!(e, sqlite_pool::PoolError::Timeout(_)),
+            Error::Outbox(e) => matches!(e, OutboxError::Full(_)),
+            Error::Statement { .. }
+            | Error::AccessDenied(_)
+            | Error::ResumeGap(_)
+            | Error::Interrupted(_)
+            | Error::Migration(_)
+            | Error::UnsupportedCapabilities(_)
+            | Error::InvalidUri(_)
+            | Error::InvalidRequest(_)
+            | Error::Serde(_)
+            | Error::Sqlite(_)
+            | Error::ResponseError(_)
+            | Error::ExpectedQueryId
+            | Error::NoRows
+            | Error::TooManyRows
+            | Error::Row(_)
+            | Error::Crypto(_)
+            | Error::Decrypt(_) => false,
+        }
+    }
+
+    /// How long the agent asked to wait before retrying, when throttled
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Error::Throttled(throttled) => Some(Duration::from_millis(throttled.retry_after_ms)),
+            _ => None,
+        }
+    }
+}
+
+// wraps `statement`'s query in `SELECT EXISTS(...)`, keeping its params
+fn exists_statement(statement: &Statement) -> Result<Statement, Error> {
+    let wrap = |query: String| {
+        format!(
+            "SELECT EXISTS({})",
+            query.trim().trim_end_matches(';').trim_end()
+        )
+    };
+    Ok(match statement.clone() {
+        Statement::Simple(query) => Statement::Simple(wrap(query)),
+        Statement::WithParams(query, params) => Statement::WithParams(wrap(query), params),
+        Statement::WithNamedParams(query, params) => {
+            Statement::WithNamedParams(wrap(query), params)
+        }
+        Statement::Verbose {
+            query,
+            params,
+            named_params,
+            defer_foreign_keys,
+        } => Statement::Verbose {
+            query: wrap(query),
+            params,
+            named_params,
+            defer_foreign_keys,
+        },
+        Statement::Registered { name, .. } => {
+            return Err(Error::ResponseError(format!(
+                "registered query '{name}' can't be wrapped in EXISTS"
+            )))
+        }
+    })
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        if e.is_connect() {
+            let io_err = e
+                .source()
+                .and_then(|source| source.downcast_ref::<std::io::Error>())
+                .map(|io_err| std::io::Error::from(io_err.kind()))
+                .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, e));
+            return Error::Connect(io_err);
+        }
+        if e.is_timeout() {
+            return Error::Timeout;
+        }
+        Error::Hyper(e)
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for Error {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        Error::Timeout
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::TcpListener};
+
+    use bytes::Bytes;
+    use corro_api_types::{
+        parse_capabilities, ChangeType, QuotaKind, RowId, CAPABILITIES_HEADER, CAPABILITY_BATCH,
+        CAPABILITY_ORIGIN, PROTOCOL_VERSION, PROTOCOL_VERSION_HEADER,
+    };
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Response, Server,
+    };
+
+    use super::*;
+
+    /// Serves every request w/ `status` and `body` over h2, like the agent
+    fn serve(status: StatusCode, body: &'static str) -> SocketAddr {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_: hyper::Request<Body>| async move {
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(status)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn classifies_errors() {
+        let stmts = [Statement::Simple("SELECT 1".into())];
+
+        // nothing listening
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = CorrosionApiClient::new(addr)
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Connect(_)), "{err:?}");
+        assert!(err.is_retryable());
+
+        // accepts connections, never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        let err = CorrosionApiClient::new(addr)
+            .with_timeout(Duration::from_millis(100))
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout), "{err:?}");
+        assert!(err.is_retryable());
+
+        let addr = serve(
+            StatusCode::BAD_REQUEST,
+            r#"{"results":[{"error":"no such table: nope"}],"time":0.0}"#,
+        );
+        let err = CorrosionApiClient::new(addr)
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        match &err {
+            Error::Http { status, body } => {
+                assert_eq!(*status, StatusCode::BAD_REQUEST);
+                assert_eq!(body, "no such table: nope");
+            }
+            _ => panic!("unexpected error: {err:?}"),
+        }
+        assert!(!err.is_retryable());
+
+        let addr = serve(StatusCode::SERVICE_UNAVAILABLE, "overloaded");
+        let err = CorrosionApiClient::new(addr)
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        match &err {
+            Error::Http { status, body } => {
+                assert_eq!(*status, StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(body, "overloaded");
+            }
+            _ => panic!("unexpected error: {err:?}"),
+        }
+        assert!(err.is_retryable());
+
+        let addr = serve(
+            StatusCode::CONFLICT,
+            r#"{"results":[{"error":"transaction interrupted: it was killed and rolled back","code":"interrupted"}],"time":0.0}"#,
+        );
+        let err = CorrosionApiClient::new(addr)
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Interrupted(message) if message.contains("killed")),
+            "{err:?}"
+        );
+        assert!(!err.is_retryable());
+
+        let addr = serve(
+            StatusCode::TOO_MANY_REQUESTS,
+            r#"{"throttled":"statements","retry_after_ms":250}"#,
+        );
+        let err = CorrosionApiClient::new(addr)
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, Error::Throttled(t) if t.throttled == QuotaKind::Statements),
+            "{err:?}"
+        );
+        assert!(err.is_retryable());
+        assert_eq!(err.retry_after(), Some(Duration::from_millis(250)));
+
+        let addr = serve(StatusCode::OK, "not json");
+        let err = CorrosionApiClient::new(addr)
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Serde(_)), "{err:?}");
+        assert!(!err.is_retryable());
+
+        let addr = serve(
+            StatusCode::OK,
+            r#"{"results":[{"rows_affected":1,"time":0.0},{"error":"UNIQUE constraint failed"}],"time":0.0}"#,
+        );
+        let client = CorrosionApiClient::new(addr);
+        assert_eq!(client.execute(&stmts).await.unwrap().results.len(), 2);
+        let err = client.execute_strict(&stmts).await.unwrap_err();
+        match &err {
+            Error::Statement { index, message } => {
+                assert_eq!(*index, 1);
+                assert_eq!(message, "UNIQUE constraint failed");
+            }
+            _ => panic!("unexpected error: {err:?}"),
+        }
+        assert!(!err.is_retryable());
+    }
+
+    /// Serves every transaction w/ an empty result over h2, sending the
+    /// headers of each request to the returned receiver
+    fn serve_capturing() -> (SocketAddr, tokio::sync::mpsc::UnboundedReceiver<HeaderMap>) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let make_svc = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| {
+                    let _ = tx.send(req.headers().clone());
+                    async move {
+                        Ok::<_, Infallible>(Response::new(Body::from(
+                            r#"{"results":[],"time":0.0}"#,
+                        )))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn applies_builder_options() {
+        let stmts = [Statement::Simple("SELECT 1".into())];
+        let (addr, mut headers) = serve_capturing();
+
+        CorrosionApiClient::new(addr).execute(&stmts).await.unwrap();
+        let sent = headers.recv().await.unwrap();
+        assert_eq!(
+            sent.get(hyper::header::USER_AGENT).unwrap(),
+            builder::DEFAULT_USER_AGENT
+        );
+        assert!(!sent.contains_key(hyper::header::AUTHORIZATION));
+
+        let client = CorrosionApiClient::builder()
+            .endpoint(addr)
+            .user_agent("my-app/1.2.3")
+            .default_header(
+                HeaderName::from_static("x-tenant"),
+                HeaderValue::from_static("acme"),
+            )
+            .bearer_token("s3cr3t")
+            .build_api()
+            .unwrap();
+        client.execute(&stmts).await.unwrap();
+        let sent = headers.recv().await.unwrap();
+        assert_eq!(sent.get(hyper::header::USER_AGENT).unwrap(), "my-app/1.2.3");
+        assert_eq!(sent.get("x-tenant").unwrap(), "acme");
+        assert_eq!(
+            sent.get(hyper::header::AUTHORIZATION).unwrap(),
+            "Bearer s3cr3t"
+        );
+
+        // per-request tokens replace the default one, other headers stay
+        client.for_token("other").execute(&stmts).await.unwrap();
+        let sent = headers.recv().await.unwrap();
+        assert_eq!(sent.get("x-tenant").unwrap(), "acme");
+        assert_eq!(
+            sent.get(hyper::header::AUTHORIZATION).unwrap(),
+            "Bearer other"
+        );
+
+        assert!(matches!(
+            CorrosionApiClient::builder().build_api(),
+            Err(BuildError::NoEndpoint)
+        ));
+        assert!(matches!(
+            CorrosionApiClient::builder()
+                .endpoint(addr)
+                .user_agent("bad\nagent")
+                .build_api(),
+            Err(BuildError::InvalidUserAgent(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn applies_builder_timeouts_and_fails_over() {
+        let stmts = [Statement::Simple("SELECT 1".into())];
+
+        // accepts connections, never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut conns = vec![];
+            while let Ok((conn, _)) = listener.accept().await {
+                conns.push(conn);
+            }
+        });
+        let err = CorrosionApiClient::builder()
+            .endpoint(silent)
+            .request_timeout(Duration::from_millis(100))
+            .connect_timeout(Duration::from_millis(100))
+            .build_api()
+            .unwrap()
+            .execute(&stmts)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Timeout), "{err:?}");
+
+        // nothing listening on the first endpoint
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (live, mut headers) = serve_capturing();
+        let client = CorrosionApiClient::builder()
+            .endpoints([dead, live])
+            .build_api()
+            .unwrap();
+        assert_eq!(client.api_addr(), dead);
+
+        let err = client.execute(&stmts).await.unwrap_err();
+        assert!(matches!(err, Error::Connect(_)), "{err:?}");
+        assert_eq!(client.api_addr(), live);
+
+        client.execute(&stmts).await.unwrap();
+        assert!(headers.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn queries_scalars_and_single_rows() {
+        let stmt = Statement::Simple("SELECT n FROM t".into());
+
+        let addr = serve(
+            StatusCode::OK,
+            "{\"columns\":[\"n\"]}\n{\"row\":[1,[null]]}\n{\"eoq\":{\"time\":0.0}}\n",
+        );
+        let client = CorrosionApiClient::new(addr);
+        assert_eq!(
+            client.query_scalar::<Option<i64>>(&stmt).await.unwrap(),
+            Some(None)
+        );
+        let err = client.query_scalar::<i64>(&stmt).await.unwrap_err();
+        assert_eq!(err.to_string(), "column 0 (n): expected INTEGER, got NULL");
+
+        let addr = serve(
+            StatusCode::OK,
+            "{\"columns\":[\"n\"]}\n{\"eoq\":{\"time\":0.0}}\n",
+        );
+        let client = CorrosionApiClient::new(addr);
+        assert_eq!(client.query_scalar::<i64>(&stmt).await.unwrap(), None);
+        let err = client.query_one::<(i64,)>(&stmt).await.unwrap_err();
+        assert!(matches!(err, Error::NoRows), "{err:?}");
+
+        let addr = serve(
+            StatusCode::OK,
+            "{\"columns\":[\"n\"]}\n{\"row\":[1,[1]]}\n{\"row\":[2,[2]]}\n{\"eoq\":{\"time\":0.0}}\n",
+        );
+        let client = CorrosionApiClient::new(addr);
+        assert_eq!(client.query_scalar::<i64>(&stmt).await.unwrap(), Some(1));
+        let err = client.query_one::<(i64,)>(&stmt).await.unwrap_err();
+        assert!(matches!(err, Error::TooManyRows), "{err:?}");
+
+        let addr = serve(
+            StatusCode::OK,
+            "{\"columns\":[\"EXISTS(SELECT n FROM t)\"]}\n{\"row\":[1,[1]]}\n{\"eoq\":{\"time\":0.0}}\n",
+        );
+        let client = CorrosionApiClient::new(addr);
+        assert!(client.exists(&stmt).await.unwrap());
+    }
+
+    #[test]
+    fn wraps_statements_in_exists() {
+        let stmt = exists_statement(&Statement::WithParams(
+            "SELECT 1 FROM consul_services WHERE node = ?; ".into(),
+            vec!["node-1".into()],
+        ))
+        .unwrap();
+        assert_eq!(
+            stmt.query(),
+            "SELECT EXISTS(SELECT 1 FROM consul_services WHERE node = ?)"
+        );
+        assert_eq!(stmt.params().count(), 1);
+
+        assert!(exists_statement(&Statement::Registered {
+            name: "by_node".into(),
+            params: vec![],
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn truncates_error_bodies() {
+        let body = "x".repeat(HTTP_BODY_EXCERPT_LEN * 2);
+        let msg = error_message(body.as_bytes());
+        assert_eq!(msg.len(), HTTP_BODY_EXCERPT_LEN + 3);
+        assert!(msg.ends_with("..."));
+
+        assert_eq!(error_message(br#"{"error":"bad"}"#), "bad");
+    }
+
+    #[tokio::test]
+    async fn retries_streams_ended_by_shutdowns() {
+        let addr = serve(
+            StatusCode::OK,
+            "{\"columns\":[\"n\"]}\n{\"eoq\":{\"time\":0.0,\"change_id\":0}}\n{\"error\":\"shutting down\"}\n",
+        );
+        let client = CorrosionApiClient::new(addr);
+
+        let err = client
+            .query_scalar::<i64>(&Statement::Simple("SELECT n FROM t".into()))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ShuttingDown), "{err:?}");
+        assert!(err.is_retryable());
+
+        let mut sub = client.subscription(Uuid::nil(), None).await.unwrap();
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(QueryEvent::Columns { .. }))
+        ));
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(QueryEvent::EndOfQuery { .. }))
+        ));
+        assert!(matches!(
+            sub.next().await,
+            Some(Err(sub::SubscriptionError::ShuttingDown))
+        ));
+        assert!(sub.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn unbatches_subscription_changes() {
+        let addr = serve(
+            StatusCode::OK,
+            concat!(
+                "{\"columns\":[\"n\"]}\n{\"eoq\":{\"time\":0.0,\"change_id\":0}}\n",
+                "{\"change_batch\":[[\"insert\",1,[1],1],[\"update\",1,[2],2]]}\n",
+                "{\"change\":[\"delete\",1,[2],3]}\n",
+                "{\"change_batch\":[[\"insert\",2,[1],4],[\"insert\",3,[1],6]]}\n",
+            ),
+        );
+        let client = CorrosionApiClient::new(addr);
+
+        let change = |change_type, n, change_id| {
+            QueryEvent::Change(
+                change_type,
+                RowId(1),
+                vec![SqliteValue::Integer(n)],
+                ChangeId(change_id),
+            )
+        };
+
+        let mut sub = client.subscription(Uuid::nil(), None).await.unwrap();
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(QueryEvent::Columns { .. }))
+        ));
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(QueryEvent::EndOfQuery { .. }))
+        ));
+        for evt in [
+            change(ChangeType::Insert, 1, 1),
+            change(ChangeType::Update, 2, 2),
+            change(ChangeType::Delete, 2, 3),
+        ] {
+            assert_eq!(sub.next().await.unwrap().unwrap(), evt);
+        }
+
+        // batched changes are checked for gaps one by one
+        assert!(matches!(sub.next().await, Some(Ok(QueryEvent::Change(..)))));
+        assert!(matches!(
+            sub.next().await,
+            Some(Err(sub::SubscriptionError::MissedChange))
+        ));
+    }
+
+    #[tokio::test]
+    async fn decodes_typed_rows_across_schema_changes() {
+        #[derive(Debug, PartialEq)]
+        struct Item {
+            n: i64,
+            text: String,
+        }
+
+        impl FromRow for Item {
+            fn from_row<S: AsRef<str>>(
+                row: &[corro_api_types::SqliteValueRef<'_>],
+                names: &[S],
+            ) -> Result<Self, RowError> {
+                let row = corro_api_types::row::NamedRow::new(row, names);
+                Ok(Self {
+                    n: row.get("n")?,
+                    text: row.get("text")?,
+                })
+            }
+        }
+
+        // a column was added in front of the ones already there
+        let addr = serve(
+            StatusCode::OK,
+            concat!(
+                "{\"columns\":[\"n\",\"text\"]}\n{\"row\":[1,[1,\"a\"]]}\n",
+                "{\"eoq\":{\"time\":0.0,\"change_id\":0}}\n",
+                "{\"rebound\":{\"change_id\":0}}\n",
+                "{\"columns\":{\"names\":[\"extra\",\"n\",\"text\"],\"schema_generation\":1}}\n",
+                "{\"row\":[2,[null,1,\"a\"]]}\n{\"eoq\":{\"time\":0.0,\"change_id\":0}}\n",
+                "{\"change\":[\"insert\",3,[null,2,\"b\"],1]}\n",
+            ),
+        );
+        let client = CorrosionApiClient::new(addr);
+
+        let item = |n, text: &str| Item {
+            n,
+            text: text.into(),
+        };
+
+        let mut sub = client
+            .subscription(Uuid::nil(), None)
+            .await
+            .unwrap()
+            .typed::<Item>();
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(sub::TypedEvent::Event(QueryEvent::Columns { .. })))
+        ));
+        assert_eq!(
+            sub.next().await.unwrap().unwrap(),
+            sub::TypedEvent::Row(RowId(1), item(1, "a"))
+        );
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(sub::TypedEvent::Event(QueryEvent::EndOfQuery { .. })))
+        ));
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(sub::TypedEvent::Event(QueryEvent::Rebound { .. })))
+        ));
+        assert_eq!(
+            sub.next().await.unwrap().unwrap(),
+            sub::TypedEvent::Event(QueryEvent::Columns {
+                names: vec!["extra".into(), "n".into(), "text".into()],
+                schema_generation: 1,
+            })
+        );
+        assert_eq!(sub.columns(), ["extra", "n", "text"]);
+        assert_eq!(
+            sub.next().await.unwrap().unwrap(),
+            sub::TypedEvent::Row(RowId(2), item(1, "a"))
+        );
+        assert!(matches!(
+            sub.next().await,
+            Some(Ok(sub::TypedEvent::Event(QueryEvent::EndOfQuery { .. })))
+        ));
+        assert_eq!(
+            sub.next().await.unwrap().unwrap(),
+            sub::TypedEvent::Change(ChangeType::Insert, RowId(3), item(2, "b"), ChangeId(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn rebootstraps_subscriptions_after_resume_gaps() {
+        const ID: &str = "00000000-0000-0000-0000-000000000001";
+
+        // resuming always hits purged changes, the first snapshot's body
+        // breaks off to make the stream reconnect
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| async move {
+                let res = Response::builder().header("corro-query-id", ID);
+                if req.uri().query().unwrap_or_default().contains("from=") {
+                    return Ok::<_, Infallible>(
+                        res.status(StatusCode::GONE)
+                            .body(Body::from(r#"{"requested":1,"earliest_available":3}"#))
+                            .unwrap(),
+                    );
+                }
+                let (mut tx, body) = Body::channel();
+                let method = req.method().clone();
+                tokio::spawn(async move {
+                    _ = tx
+                        .send_data(Bytes::from_static(
+                            b"{\"columns\":[\"n\"]}\n{\"row\":[1,[1]]}\n",
+                        ))
+                        .await;
+                    _ = tx
+                        .send_data(Bytes::from(format!(
+                            "{{\"eoq\":{{\"time\":0.0,\"change_id\":{}}}}}\n",
+                            if method == hyper::Method::POST { 1 } else { 3 }
+                        )))
+                        .await;
+                    if method == hyper::Method::POST {
+                        // let the snapshot through before resetting
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        tx.abort();
+                    }
+                });
+                Ok(res.body(body).unwrap())
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let gap = ResumeGap {
+            requested: ChangeId(1),
+            earliest_available: ChangeId(3),
+        };
+        let snapshot = |change_id| {
+            vec![
+                QueryEvent::columns(vec!["n".into()]),
+                QueryEvent::Row(corro_api_types::RowId(1), vec![SqliteValue::Integer(1)]),
+                QueryEvent::EndOfQuery {
+                    time: 0.0,
+                    change_id: Some(ChangeId(change_id)),
+                    coerced: vec![],
+                },
+            ]
+        };
+
+        let client = CorrosionApiClient::new(addr);
+
+        // resumed from a purged change
+        let mut sub = client
+            .subscription(ID.parse().unwrap(), Some(ChangeId(1)))
+            .await
+            .unwrap();
+        let mut expected = vec![QueryEvent::Rebootstrapped(gap)];
+        expected.extend(snapshot(3));
+        for evt in expected {
+            assert_eq!(sub.next().await.unwrap().unwrap(), evt);
+        }
+        assert!(sub.next().await.is_none());
+
+        // reconnected after a purge
+        let mut sub = client
+            .subscribe(&Statement::Simple("SELECT n FROM t".into()), None)
+            .await
+            .unwrap();
+        let mut expected = snapshot(1);
+        expected.push(QueryEvent::Rebootstrapped(gap));
+        expected.extend(snapshot(3));
+        for evt in expected {
+            assert_eq!(sub.next().await.unwrap().unwrap(), evt);
+        }
+        assert!(sub.next().await.is_none());
+
+        let err = error_for_status(
+            Response::builder()
+                .status(StatusCode::GONE)
+                .body(Body::from(serde_json::to_vec(&gap).unwrap()))
+                .unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, Error::ResumeGap(g) if g == gap), "{err:?}");
+        assert!(!err.is_retryable());
+    }
+
+    /// Serves subscriptions as an agent supporting the `supported` stream
+    /// capabilities would, or one predating the handshake if `None`
+    fn serve_handshake(supported: Option<&'static [&'static str]>) -> SocketAddr {
+        let make_svc = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req: hyper::Request<Body>| async move {
+                let mut res = Response::builder()
+                    .header("corro-query-id", "00000000-0000-0000-0000-000000000001");
+                let mut body = String::new();
+                if let Some(supported) = supported {
+                    let enabled: Vec<_> = req
+                        .headers()
+                        .get(CAPABILITIES_HEADER)
+                        .and_then(|value| value.to_str().ok())
+                        .map(parse_capabilities)
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter(|capability| supported.contains(&capability.as_str()))
+                        .collect();
+                    res = res
+                        .header(PROTOCOL_VERSION_HEADER, PROTOCOL_VERSION.to_string())
+                        .header(CAPABILITIES_HEADER, enabled.join(","));
+                    if req.headers().contains_key(CAPABILITIES_HEADER) {
+                        let hello = QueryEvent::Hello {
+                            protocol_version: PROTOCOL_VERSION,
+                            capabilities: enabled,
+                            sub_id: None,
+                        };
+                        body = format!("{}\n", serde_json::to_string(&hello).unwrap());
+                    }
+                }
+                body.push_str("{\"columns\":[\"n\"]}\n{\"eoq\":{\"time\":0.0,\"change_id\":0}}\n");
+                Ok::<_, Infallible>(res.body(Body::from(body)).unwrap())
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .http2_only(true)
+            .serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn negotiates_subscription_capabilities() {
+        let stmt = Statement::Simple("SELECT n FROM t".into());
+        let expect_snapshot = |mut sub: SubscriptionStream| async move {
+            assert!(matches!(
+                sub.next().await,
+                Some(Ok(QueryEvent::Columns { .. }))
+            ));
+            assert!(matches!(
+                sub.next().await,
+                Some(Ok(QueryEvent::EndOfQuery { .. }))
+            ));
+        };
+
+        // an agent predating the handshake still serves plain subscriptions
+        let old = CorrosionApiClient::new(serve_handshake(None));
+        expect_snapshot(old.subscribe(&stmt, None).await.unwrap()).await;
+        let err = old.subscribe_with_origins(&stmt, None).await.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::UnsupportedCapabilities(UnsupportedCapabilities {
+                    protocol_version: None,
+                    missing,
+                }) if missing == &[CAPABILITY_ORIGIN]
+            ),
+            "{err:?}"
+        );
+        assert!(!err.is_retryable());
+
+        // an agent w/ the handshake, but not origins
+        let batching = CorrosionApiClient::new(serve_handshake(Some(&[CAPABILITY_BATCH])));
+        expect_snapshot(batching.subscribe(&stmt, None).await.unwrap()).await;
+        let err = batching
+            .subscribe_with_origins(&stmt, None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::UnsupportedCapabilities(UnsupportedCapabilities {
+                    protocol_version: Some(PROTOCOL_VERSION),
+                    ..
+                })
+            ),
+            "{err:?}"
+        );
+
+        // the hello isn't handed out
+        let new = CorrosionApiClient::new(serve_handshake(Some(&[
+            CAPABILITY_BATCH,
+            CAPABILITY_ORIGIN,
+            "pings",
+        ])));
+        expect_snapshot(new.subscribe_with_origins(&stmt, None).await.unwrap()).await;
+        expect_snapshot(new.subscription(Uuid::nil(), None).await.unwrap()).await;
+    }
 }
@@ -0,0 +1,405 @@
+//! Outbox of transactions executed in the background, for writers which
+//! shouldn't have to wait for the agent to be reachable, see
+//! `CorrosionApiClient::with_outbox`.
+//!
+//! Transactions are appended to a small SQLite journal, synced to disk
+//! before `execute_queued` returns. A background task executes them in the
+//! order they were queued, each w/ its own `idempotency-key`: one the agent
+//! applied but whose response got lost is only applied once when retried.
+//! Acknowledged transactions are deleted from the journal.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use metrics::{counter, gauge, increment_counter};
+use sqlite_pool::rusqlite::{self, params, Connection, OptionalExtension};
+use tokio::sync::Notify;
+use tracing::{debug, error, warn};
+use uuid::Uuid;
+
+use crate::CorrosionApiClient;
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum OutboxError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error("outbox is full, w/ {0} queued transactions")]
+    Full(usize),
+    #[error("client has no outbox, see `CorrosionApiClient::with_outbox`")]
+    NotConfigured,
+    #[error("outbox task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// What to do w/ a transaction queued to a full outbox
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Fail w/ `OutboxError::Full`
+    #[default]
+    FailFast,
+    /// Drop the oldest queued transaction to make room for it
+    DropOldest,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxOptions {
+    max_entries: usize,
+    overflow: Overflow,
+    retry_interval: Duration,
+}
+
+impl Default for OutboxOptions {
+    fn default() -> Self {
+        Self {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            overflow: Overflow::default(),
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+        }
+    }
+}
+
+impl OutboxOptions {
+    /// Transactions the outbox holds at most, 10000 by default
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// What to do once `max_entries` are queued, fail fast by default
+    pub fn overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// How long to wait before retrying a transaction the agent couldn't
+    /// execute, 1s by default
+    pub fn retry_interval(mut self, retry_interval: Duration) -> Self {
+        self.retry_interval = retry_interval;
+        self
+    }
+}
+
+/// A queued transaction
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    pub id: i64,
+    pub key: String,
+    // the JSON encoded statements
+    pub body: Vec<u8>,
+}
+
+pub struct Outbox {
+    conn: Mutex<Connection>,
+    options: OutboxOptions,
+    queued: Notify,
+}
+
+impl Outbox {
+    /// Opens the journal at `path`, creating it if needed. Transactions
+    /// queued before a restart are still in it.
+    pub fn open(path: impl AsRef<Path>, options: OutboxOptions) -> Result<Self, OutboxError> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "wal")?;
+        // fsyncs on every commit, queued transactions survive crashes
+        conn.pragma_update(None, "synchronous", "full")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS outbox (
+                -- never reused, keeps the order of transactions
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                key TEXT NOT NULL,
+                body BLOB NOT NULL,
+                queued_at INTEGER NOT NULL
+            );",
+        )?;
+
+        let outbox = Self {
+            conn: Mutex::new(conn),
+            options,
+            queued: Notify::new(),
+        };
+        outbox.record_metrics(&outbox.conn())?;
+        Ok(outbox)
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // a panic can't leave the connection in a bad state, whatever it
+        // was doing was rolled back
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Transactions waiting to be executed
+    pub fn len(&self) -> Result<usize, OutboxError> {
+        Ok(count(&self.conn())?)
+    }
+
+    pub fn is_empty(&self) -> Result<bool, OutboxError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// How long the oldest queued transaction has been waiting
+    pub fn oldest_age(&self) -> Result<Option<Duration>, OutboxError> {
+        Ok(oldest_age(&self.conn())?)
+    }
+
+    /// Appends a transaction, returning its position in the outbox
+    pub(crate) fn push(&self, body: &[u8]) -> Result<i64, OutboxError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let queued = count(&tx)?;
+        if queued >= self.options.max_entries {
+            match self.options.overflow {
+                Overflow::FailFast => return Err(OutboxError::Full(queued)),
+                Overflow::DropOldest => {
+                    let dropped = tx
+                        .prepare_cached(
+                            "DELETE FROM outbox WHERE id IN
+                                (SELECT id FROM outbox ORDER BY id LIMIT ?)",
+                        )?
+                        .execute([queued + 1 - self.options.max_entries])?;
+                    warn!("outbox is full, dropped its {dropped} oldest transaction(s)");
+                    counter!("corro.client.outbox.dropped", dropped as u64);
+                }
+            }
+        }
+
+        tx.prepare_cached("INSERT INTO outbox (key, body, queued_at) VALUES (?, ?, ?)")?
+            .execute(params![
+                format!("outbox-{}", Uuid::new_v4()),
+                body,
+                unix_millis()
+            ])?;
+        let id = tx.last_insert_rowid();
+        tx.commit()?;
+
+        self.record_metrics(&conn)?;
+        drop(conn);
+
+        self.queued.notify_one();
+        Ok(id)
+    }
+
+    // the oldest queued transaction
+    fn front(&self) -> Result<Option<Entry>, OutboxError> {
+        Ok(self
+            .conn()
+            .prepare_cached("SELECT id, key, body FROM outbox ORDER BY id LIMIT 1")?
+            .query_row([], |row| {
+                Ok(Entry {
+                    id: row.get(0)?,
+                    key: row.get(1)?,
+                    body: row.get(2)?,
+                })
+            })
+            .optional()?)
+    }
+
+    // the transaction was executed, or rejected for good
+    fn ack(&self, id: i64) -> Result<(), OutboxError> {
+        let conn = self.conn();
+        conn.prepare_cached("DELETE FROM outbox WHERE id = ?")?
+            .execute([id])?;
+        self.record_metrics(&conn)?;
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    fn record_metrics(&self, conn: &Connection) -> rusqlite::Result<()> {
+        gauge!("corro.client.outbox.depth", count(conn)? as f64);
+        gauge!(
+            "corro.client.outbox.oldest.seconds",
+            oldest_age(conn)?.unwrap_or_default().as_secs_f64()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+}
+
+fn count(conn: &Connection) -> rusqlite::Result<usize> {
+    conn.prepare_cached("SELECT COUNT(*) FROM outbox")?
+        .query_row([], |row| row.get(0))
+}
+
+fn oldest_age(conn: &Connection) -> rusqlite::Result<Option<Duration>> {
+    let queued_at: Option<u64> = conn
+        .prepare_cached("SELECT MIN(queued_at) FROM outbox")?
+        .query_row([], |row| row.get(0))?;
+    Ok(queued_at.map(|queued_at| Duration::from_millis(unix_millis().saturating_sub(queued_at))))
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+async fn blocking<T, F>(outbox: &Arc<Outbox>, f: F) -> Result<T, OutboxError>
+where
+    F: FnOnce(&Outbox) -> Result<T, OutboxError> + Send + 'static,
+    T: Send + 'static,
+{
+    let outbox = outbox.clone();
+    tokio::task::spawn_blocking(move || f(&outbox)).await?
+}
+
+/// Queues `body` to `outbox`, off the async runtime since it waits for the
+/// journal to be synced
+pub(crate) async fn queue(outbox: &Arc<Outbox>, body: Vec<u8>) -> Result<i64, OutboxError> {
+    blocking(outbox, move |outbox| outbox.push(&body)).await
+}
+
+/// Executes the outbox's transactions in order w/ `client`, until the
+/// outbox is dropped
+pub(crate) async fn drain(outbox: Weak<Outbox>, client: CorrosionApiClient) {
+    loop {
+        let Some(outbox) = outbox.upgrade() else {
+            break;
+        };
+        let retry_interval = outbox.options.retry_interval;
+
+        let entry = match blocking(&outbox, |outbox| outbox.front()).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => {
+                // checks whether the outbox was dropped in between
+                _ = tokio::time::timeout(retry_interval, outbox.queued.notified()).await;
+                continue;
+            }
+            Err(e) => {
+                error!("could not read the outbox: {e}");
+                tokio::time::sleep(retry_interval).await;
+                continue;
+            }
+        };
+
+        match client
+            .transactions_bytes(entry.body.into(), Some(&entry.key))
+            .await
+        {
+            Ok(_) => {}
+            Err(e) if e.is_retryable() => {
+                debug!(
+                    "could not execute queued transaction {}, retrying: {e}",
+                    entry.id
+                );
+                tokio::time::sleep(e.retry_after().unwrap_or(retry_interval)).await;
+                continue;
+            }
+            Err(e) => {
+                // would block every transaction queued after it otherwise
+                error!(
+                    "dropping queued transaction {}, it was rejected: {e}",
+                    entry.id
+                );
+                increment_counter!("corro.client.outbox.rejected");
+            }
+        }
+
+        let id = entry.id;
+        if let Err(e) = blocking(&outbox, move |outbox| outbox.ack(id)).await {
+            // executed again w/ the same key, which the agent won't apply twice
+            error!("could not remove transaction {id} from the outbox: {e}");
+            tokio::time::sleep(retry_interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tmp_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("corro-outbox-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn open(dir: &Path, options: OutboxOptions) -> Outbox {
+        Outbox::open(dir.join("outbox.db"), options).unwrap()
+    }
+
+    fn bodies(outbox: &Outbox) -> Vec<Vec<u8>> {
+        let mut bodies = vec![];
+        while let Some(entry) = outbox.front().unwrap() {
+            bodies.push(entry.body);
+            outbox.ack(entry.id).unwrap();
+        }
+        bodies
+    }
+
+    #[test]
+    fn keeps_transactions_in_order_across_reopens() -> Result<(), OutboxError> {
+        let dir = tmp_dir();
+
+        {
+            let outbox = open(&dir, OutboxOptions::default());
+            assert!(outbox.is_empty()?);
+            assert_eq!(outbox.oldest_age()?, None);
+
+            let first = outbox.push(b"1")?;
+            let second = outbox.push(b"2")?;
+            assert!(first < second);
+            assert_eq!(outbox.len()?, 2);
+            assert!(outbox.oldest_age()?.is_some());
+        }
+
+        let outbox = open(&dir, OutboxOptions::default());
+        let front = outbox.front()?.unwrap();
+        assert_eq!(front.body, b"1");
+        // same until acknowledged
+        assert_eq!(outbox.front()?, Some(front.clone()));
+
+        outbox.ack(front.id)?;
+        outbox.push(b"3")?;
+        assert_eq!(bodies(&outbox), [b"2".to_vec(), b"3".to_vec()]);
+
+        // ids aren't reused, nor keys
+        let next = outbox.push(b"4")?;
+        assert!(next > front.id + 2);
+        assert_ne!(outbox.front()?.unwrap().key, front.key);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn fails_fast_when_full() -> Result<(), OutboxError> {
+        let dir = tmp_dir();
+        let outbox = open(&dir, OutboxOptions::default().max_entries(2));
+
+        outbox.push(b"1")?;
+        outbox.push(b"2")?;
+        assert!(matches!(outbox.push(b"3"), Err(OutboxError::Full(2))));
+        assert_eq!(bodies(&outbox), [b"1".to_vec(), b"2".to_vec()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn drops_oldest_when_full() -> Result<(), OutboxError> {
+        let dir = tmp_dir();
+        let outbox = open(
+            &dir,
+            OutboxOptions::default()
+                .max_entries(2)
+                .overflow(Overflow::DropOldest),
+        );
+
+        for body in [b"1", b"2", b"3", b"4"] {
+            outbox.push(body)?;
+        }
+        assert_eq!(outbox.len()?, 2);
+        assert_eq!(bodies(&outbox), [b"3".to_vec(), b"4".to_vec()]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Pool of connections to the agent's database file, reporting how long
+//! callers wait for a connection and how long their statements take.
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use metrics::{gauge, histogram};
+use sqlite_pool::{
+    rusqlite::{self, CachedStatement},
+    PoolError, RusqliteConnection, RusqlitePool,
+};
+use tracing::warn;
+
+/// Statement metrics are labelled w/ this many chars of their SQL, at most
+const SQL_LABEL_LEN: usize = 48;
+
+#[derive(Clone)]
+pub struct LocalPool {
+    pool: RusqlitePool,
+    state: Arc<PoolState>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    in_use: AtomicUsize,
+    waiting: AtomicUsize,
+    acquired: AtomicU64,
+    wait_nanos: AtomicU64,
+    max_wait_nanos: AtomicU64,
+    statements: AtomicU64,
+    slow_statements: AtomicU64,
+    // 0 disables the slow statements log
+    slow_threshold_nanos: AtomicU64,
+}
+
+/// Snapshot of a `LocalPool`, counters are totals since it was created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub max_size: usize,
+    /// Open connections
+    pub size: usize,
+    /// Connections checked out
+    pub in_use: usize,
+    /// Callers waiting for a connection
+    pub waiting: usize,
+    /// Connections handed out
+    pub acquired: u64,
+    /// Time spent waiting for connections
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    /// Statements run w/ `LocalConn::timed`, or to read locally
+    pub statements: u64,
+    /// Statements slower than the slow statements threshold
+    pub slow_statements: u64,
+}
+
+impl LocalPool {
+    pub fn new(pool: RusqlitePool) -> Self {
+        Self {
+            pool,
+            state: Default::default(),
+        }
+    }
+
+    /// Logs statements taking longer than `threshold`, `None` turns it off
+    pub fn set_slow_threshold(&self, threshold: Option<Duration>) {
+        let nanos = threshold.map_or(0, |threshold| threshold.as_nanos().max(1) as u64);
+        self.state
+            .slow_threshold_nanos
+            .store(nanos, Ordering::Relaxed);
+    }
+
+    pub async fn get(&self) -> Result<LocalConn, PoolError> {
+        let start = Instant::now();
+        let res = {
+            let _waiting = Waiting::new(&self.state);
+            self.pool.get().await
+        };
+        let wait = start.elapsed();
+
+        let conn = match res {
+            Ok(conn) => conn,
+            Err(e) => {
+                self.report();
+                return Err(e);
+            }
+        };
+
+        histogram!("corro.client.pool.acquire.seconds", wait.as_secs_f64());
+        let wait_nanos = wait.as_nanos() as u64;
+        self.state.acquired.fetch_add(1, Ordering::Relaxed);
+        self.state
+            .wait_nanos
+            .fetch_add(wait_nanos, Ordering::Relaxed);
+        self.state
+            .max_wait_nanos
+            .fetch_max(wait_nanos, Ordering::Relaxed);
+        self.state.in_use.fetch_add(1, Ordering::Relaxed);
+        self.report();
+
+        Ok(LocalConn {
+            conn,
+            state: self.state.clone(),
+        })
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let status = self.pool.status();
+        PoolStats {
+            max_size: status.max_size,
+            size: status.size,
+            in_use: self.state.in_use.load(Ordering::Relaxed),
+            waiting: self.state.waiting.load(Ordering::Relaxed),
+            acquired: self.state.acquired.load(Ordering::Relaxed),
+            total_wait: Duration::from_nanos(self.state.wait_nanos.load(Ordering::Relaxed)),
+            max_wait: Duration::from_nanos(self.state.max_wait_nanos.load(Ordering::Relaxed)),
+            statements: self.state.statements.load(Ordering::Relaxed),
+            slow_statements: self.state.slow_statements.load(Ordering::Relaxed),
+        }
+    }
+
+    fn report(&self) {
+        let stats = self.stats();
+        gauge!("corro.client.pool.connections", stats.size as f64);
+        gauge!("corro.client.pool.connections.in_use", stats.in_use as f64);
+        gauge!(
+            "corro.client.pool.connections.waiting",
+            stats.waiting as f64
+        );
+    }
+}
+
+// counts a caller waiting for a connection, until it gets one or gives up
+struct Waiting<'a>(&'a PoolState);
+
+impl<'a> Waiting<'a> {
+    fn new(state: &'a PoolState) -> Self {
+        let waiting = state.waiting.fetch_add(1, Ordering::Relaxed) + 1;
+        gauge!("corro.client.pool.connections.waiting", waiting as f64);
+        Self(state)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl PoolState {
+    fn record_statement(&self, sql: &str, elapsed: Duration) {
+        let label = sql_label(sql);
+        histogram!("corro.client.statement.seconds", elapsed.as_secs_f64(), "sql" => label.clone());
+        self.statements.fetch_add(1, Ordering::Relaxed);
+
+        let threshold = self.slow_threshold_nanos.load(Ordering::Relaxed);
+        if threshold > 0 && elapsed >= Duration::from_nanos(threshold) {
+            self.slow_statements.fetch_add(1, Ordering::Relaxed);
+            warn!("slow local statement took {elapsed:?}: {label}");
+        }
+    }
+}
+
+/// A connection checked out of a `LocalPool`
+pub struct LocalConn {
+    conn: RusqliteConnection,
+    state: Arc<PoolState>,
+}
+
+impl LocalConn {
+    /// Prepares `sql` and runs `f` w/ the prepared statement, recording how
+    /// long both took labelled w/ the start of `sql`
+    pub fn timed<T, E, F>(&self, sql: &str, f: F) -> Result<T, E>
+    where
+        E: From<rusqlite::Error>,
+        F: FnOnce(&mut CachedStatement<'_>) -> Result<T, E>,
+    {
+        let start = Instant::now();
+        let res = self
+            .conn
+            .prepare_cached(sql)
+            .map_err(E::from)
+            .and_then(|mut prepped| f(&mut prepped));
+        self.state.record_statement(sql, start.elapsed());
+        res
+    }
+
+    pub(crate) fn record_statement(&self, sql: &str, elapsed: Duration) {
+        self.state.record_statement(sql, elapsed);
+    }
+}
+
+impl Deref for LocalConn {
+    type Target = rusqlite::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl DerefMut for LocalConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn
+    }
+}
+
+impl Drop for LocalConn {
+    fn drop(&mut self) {
+        let in_use = self.state.in_use.fetch_sub(1, Ordering::Relaxed) - 1;
+        gauge!("corro.client.pool.connections.in_use", in_use as f64);
+    }
+}
+
+// the first `SQL_LABEL_LEN` chars of `sql`, w/ collapsed whitespace
+fn sql_label(sql: &str) -> String {
+    let mut label = String::new();
+    for word in sql.split_whitespace() {
+        if !label.is_empty() {
+            label.push(' ');
+        }
+        label.push_str(word);
+        if label.len() >= SQL_LABEL_LEN {
+            break;
+        }
+    }
+    if let Some((end, _)) = label.char_indices().nth(SQL_LABEL_LEN) {
+        label.truncate(end);
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn records_contention_and_statements() {
+        let pool = LocalPool::new(
+            sqlite_pool::Config::new(":memory:")
+                .max_size(1)
+                .create_pool()
+                .unwrap(),
+        );
+
+        let conn = pool.get().await.unwrap();
+        let waiter = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.get().await.map(drop) }
+        });
+
+        while pool.stats().waiting == 0 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let stats = pool.stats();
+        assert_eq!((stats.max_size, stats.in_use, stats.waiting), (1, 1, 1));
+        assert_eq!(stats.acquired, 1);
+
+        drop(conn);
+        waiter.await.unwrap().unwrap();
+
+        let stats = pool.stats();
+        assert_eq!((stats.in_use, stats.waiting), (0, 0));
+        assert_eq!(stats.acquired, 2);
+        assert!(stats.max_wait >= Duration::from_millis(20), "{stats:?}");
+        assert!(stats.total_wait >= stats.max_wait);
+
+        let conn = pool.get().await.unwrap();
+        let n: i64 = conn
+            .timed("SELECT 1", |prepped| {
+                prepped.query_row([], |row| row.get(0))
+            })
+            .unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(pool.stats().slow_statements, 0);
+
+        pool.set_slow_threshold(Some(Duration::from_nanos(1)));
+        let err = conn
+            .timed("SELECT nope", |prepped| {
+                prepped.query_row([], |row| row.get::<_, i64>(0))
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("no such column"), "{err}");
+
+        let stats = pool.stats();
+        assert_eq!((stats.statements, stats.slow_statements), (2, 1));
+    }
+
+    #[test]
+    fn labels_statements_by_prefix() {
+        assert_eq!(
+            sql_label("SELECT id,\n    hash\n  FROM __corro_consul_services"),
+            "SELECT id, hash FROM __corro_consul_services"
+        );
+        let label = sql_label(&format!("SELECT {} FROM t", "x, ".repeat(100)));
+        assert_eq!(label.chars().count(), SQL_LABEL_LEN);
+        assert!(label.starts_with("SELECT x, x,"));
+    }
+}
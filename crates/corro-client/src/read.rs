@@ -0,0 +1,203 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use corro_api_types::{QueryEvent, SqliteValue, Statement};
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use sqlite_pool::rusqlite::{self, params_from_iter, Connection, ToSql};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{FramedRead, LinesCodec, LinesCodecError};
+use tracing::debug;
+
+use crate::{pool::LocalConn, sub::IoBodyStream, Error};
+
+/// Where `CorrosionClient::read` reads from
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPreference {
+    /// Only read from the local database file
+    Local,
+    /// Only read through the agent's HTTP API
+    Api,
+    /// Read from the local database file if it's readable, through the
+    /// agent's HTTP API otherwise
+    #[default]
+    LocalThenApi,
+}
+
+/// Events of a read, the same whether it was served from the local database
+/// file or through the HTTP API.
+pub struct RowStream {
+    local: bool,
+    inner: Pin<Box<dyn Stream<Item = Result<QueryEvent, Error>> + Send>>,
+}
+
+impl RowStream {
+    pub(crate) fn api(body: hyper::Body) -> Self {
+        Self {
+            local: false,
+            inner: Box::pin(ndjson_events(body)),
+        }
+    }
+
+    /// Whether the events come from the local database file
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+}
+
+impl Stream for RowStream {
+    type Item = Result<QueryEvent, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+/// Decodes a body of newline-delimited JSON events
+pub(crate) fn ndjson_events<T: DeserializeOwned>(
+    body: hyper::Body,
+) -> impl Stream<Item = Result<T, Error>> + Send {
+    let lines = FramedRead::new(
+        tokio_util::io::StreamReader::new(IoBodyStream::new(body)),
+        LinesCodec::new(),
+    );
+    lines.map(|res| match res {
+        Ok(line) => Ok(serde_json::from_str(&line)?),
+        Err(LinesCodecError::Io(e)) => Err(Error::Io(e)),
+        Err(LinesCodecError::MaxLineLengthExceeded) => {
+            Err(Error::ResponseError("max line length exceeded".into()))
+        }
+    })
+}
+
+/// Runs `stmt` against a local connection, streaming events like the
+/// `/v1/queries` endpoint does. Errors preparing or starting the query are
+/// returned directly, later ones are sent as `QueryEvent::Error`.
+pub(crate) async fn read_local(conn: LocalConn, stmt: Statement) -> Result<RowStream, Error> {
+    let (res_tx, res_rx) = oneshot::channel();
+    let (evt_tx, mut evt_rx) = mpsc::channel(512);
+
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        if let Err(e) = query_local(&conn, &stmt, res_tx, &evt_tx) {
+            debug!("local read failed: {e}");
+        }
+        conn.record_statement(stmt.query(), start.elapsed());
+    });
+
+    match res_rx.await {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::ResponseError("local read aborted".into())),
+    }
+
+    Ok(RowStream {
+        local: true,
+        inner: Box::pin(futures::stream::poll_fn(move |cx| {
+            evt_rx.poll_recv(cx).map(|evt| evt.map(Ok))
+        })),
+    })
+}
+
+fn query_local(
+    conn: &Connection,
+    stmt: &Statement,
+    res_tx: oneshot::Sender<rusqlite::Result<()>>,
+    evt_tx: &mpsc::Sender<QueryEvent>,
+) -> rusqlite::Result<()> {
+    let start = Instant::now();
+
+    let mut prepped = match conn.prepare(stmt.query()) {
+        Ok(prepped) if prepped.readonly() => prepped,
+        Ok(_) => {
+            _ = res_tx.send(Err(rusqlite::Error::InvalidQuery));
+            return Ok(());
+        }
+        Err(e) => {
+            _ = res_tx.send(Err(e));
+            return Ok(());
+        }
+    };
+
+    let columns = QueryEvent::columns(
+        prepped
+            .column_names()
+            .into_iter()
+            .map(|name| name.into())
+            .collect(),
+    );
+    let col_count = prepped.column_count();
+
+    let rows = match stmt {
+        Statement::Simple(_)
+        | Statement::Verbose {
+            params: None,
+            named_params: None,
+            ..
+        } => prepped.query(()),
+        Statement::WithParams(_, params)
+        | Statement::Registered { params, .. }
+        | Statement::Verbose {
+            params: Some(params),
+            ..
+        } => prepped.query(params_from_iter(params)),
+        Statement::WithNamedParams(_, params)
+        | Statement::Verbose {
+            named_params: Some(params),
+            ..
+        } => prepped.query(
+            params
+                .iter()
+                .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+                .collect::<Vec<(&str, &dyn ToSql)>>()
+                .as_slice(),
+        ),
+    };
+
+    let mut rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            _ = res_tx.send(Err(e));
+            return Ok(());
+        }
+    };
+    let elapsed = start.elapsed();
+
+    if res_tx.send(Ok(())).is_err() || evt_tx.blocking_send(columns).is_err() {
+        return Ok(());
+    }
+
+    let mut rowid = 1;
+    loop {
+        let cells = match rows.next() {
+            Ok(Some(row)) => (0..col_count)
+                .map(|i| row.get::<_, SqliteValue>(i))
+                .collect::<rusqlite::Result<Vec<_>>>(),
+            Ok(None) => break,
+            Err(e) => Err(e),
+        };
+
+        let evt = match cells {
+            Ok(cells) => QueryEvent::Row(rowid.into(), cells),
+            Err(e) => {
+                _ = evt_tx.blocking_send(QueryEvent::Error(e.to_string().into()));
+                return Err(e);
+            }
+        };
+
+        if evt_tx.blocking_send(evt).is_err() {
+            return Ok(());
+        }
+        rowid += 1;
+    }
+
+    _ = evt_tx.blocking_send(QueryEvent::EndOfQuery {
+        time: elapsed.as_secs_f64(),
+        change_id: None,
+        coerced: vec![],
+    });
+
+    Ok(())
+}
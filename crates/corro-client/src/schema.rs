@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use corro_api_types::{TableName, TableSchema, SCHEMA_VERSION_HEADER};
+use hyper::Body;
+use tokio::sync::Mutex;
+
+use crate::{CorrosionApiClient, Error};
+
+/// How long cached schemas are used w/o asking the agent for its schema
+/// version, unless a response in the meantime carried a different one
+pub const DEFAULT_SCHEMA_MAX_AGE: Duration = Duration::from_secs(5);
+
+/// Table schemas fetched from the agent, refetched once its schema version
+/// changes. Shared by a client's clones.
+#[derive(Debug)]
+pub(crate) struct SchemaCache {
+    /// Last version seen in a response header, 0 if none was
+    seen_version: AtomicU64,
+    max_age: Duration,
+    cached: Mutex<Option<CachedSchema>>,
+}
+
+#[derive(Debug)]
+struct CachedSchema {
+    version: u64,
+    tables: HashMap<TableName, TableSchema>,
+    checked_at: Instant,
+}
+
+impl SchemaCache {
+    pub(crate) fn new(max_age: Duration) -> Self {
+        Self {
+            seen_version: AtomicU64::new(0),
+            max_age,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Records the schema version of any response from the agent
+    pub(crate) fn observe(&self, res: &hyper::Response<Body>) {
+        if let Some(version) = res
+            .headers()
+            .get(SCHEMA_VERSION_HEADER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+        {
+            self.seen_version.store(version, Ordering::Relaxed);
+        }
+    }
+
+    /// The schema of `table`, from the cache unless the agent's schema
+    /// version changed since it was fetched
+    pub(crate) async fn get(
+        &self,
+        client: &CorrosionApiClient,
+        table: &TableName,
+    ) -> Result<Option<TableSchema>, Error> {
+        let mut cached = self.cached.lock().await;
+
+        let seen = self.seen_version.load(Ordering::Relaxed);
+        let fresh = match cached.as_mut() {
+            None => false,
+            Some(cached) if seen != 0 && seen != cached.version => false,
+            Some(cached) if cached.checked_at.elapsed() < self.max_age => true,
+            Some(cached) => {
+                let version = client.schema_version().await?;
+                cached.checked_at = Instant::now();
+                version == cached.version
+            }
+        };
+
+        if !fresh {
+            let schema = client.current_schema().await?;
+            *cached = Some(CachedSchema {
+                version: schema.version,
+                tables: schema
+                    .tables
+                    .into_iter()
+                    .map(|table| (table.name.clone(), table))
+                    .collect(),
+                checked_at: Instant::now(),
+            });
+        }
+
+        Ok(cached
+            .as_ref()
+            .and_then(|cached| cached.tables.get(table).cloned()))
+    }
+}
@@ -0,0 +1,455 @@
+//! Snapshots of a subscription's materialized rows, to hand a subscription
+//! over across restarts of its consumer w/o the agent streaming every row
+//! again.
+//!
+//! A snapshot file holds the subscription's id, the column names and rows
+//! received so far and the last change id applied to them. It's laid out as
+//! a magic, a format version and a checksum, followed by the speedy-encoded
+//! snapshot compressed w/ zstd.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use compact_str::CompactString;
+use corro_api_types::{ChangeId, ChangeType, QueryEvent, RowId, SqliteValue, Statement};
+use futures::{ready, Stream};
+use pin_project_lite::pin_project;
+use speedy::{Readable, Writable};
+use uuid::Uuid;
+
+use crate::sub::{SubscriptionError, SubscriptionStream};
+
+const MAGIC: &[u8; 8] = b"CORROSUB";
+const VERSION: u8 = 1;
+// magic, version and checksum
+const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+const COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Encode(#[from] speedy::Error),
+    #[error("corrupt snapshot: {0}")]
+    Corrupt(&'static str),
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u8),
+    #[error("snapshot is of another query")]
+    QueryMismatch,
+    /// Persisting before the end of the initial query would lose the rows
+    /// not received yet
+    #[error("subscription hasn't received its initial rows yet")]
+    Incomplete,
+}
+
+/// Hash of `statement`, which a snapshot has to match to be resumed from
+pub fn query_hash(statement: &Statement) -> serde_json::Result<u64> {
+    // through `Value`, whose objects are ordered by key
+    let value = serde_json::to_value(statement)?;
+    Ok(seahash::hash(&serde_json::to_vec(&value)?))
+}
+
+#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+struct SnapshotFile {
+    query_hash: u64,
+    id: Uuid,
+    change_id: i64,
+    columns: Vec<String>,
+    rows: Vec<(i64, Vec<SqliteValue>)>,
+}
+
+impl SnapshotFile {
+    fn new(query_hash: u64, id: Uuid, state: &Materialized) -> Self {
+        Self {
+            query_hash,
+            id,
+            change_id: state.change_id.0,
+            columns: state.columns.iter().map(|c| c.to_string()).collect(),
+            rows: state
+                .rows
+                .iter()
+                .map(|(rowid, cells)| (rowid.0, cells.clone()))
+                .collect(),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, SnapshotError> {
+        let payload = zstd::encode_all(self.write_to_vec()?.as_slice(), COMPRESSION_LEVEL)?;
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&seahash::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        if bytes.len() < HEADER_LEN || !bytes.starts_with(MAGIC) {
+            return Err(SnapshotError::Corrupt("not a subscription snapshot"));
+        }
+        let version = bytes[MAGIC.len()];
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let (checksum, payload) = bytes[MAGIC.len() + 1..].split_at(8);
+        let checksum = u64::from_le_bytes(checksum.try_into().expect("checksum is 8 bytes"));
+        if seahash::hash(payload) != checksum {
+            return Err(SnapshotError::Corrupt("checksum mismatch"));
+        }
+
+        let decompressed = zstd::decode_all(payload)?;
+        Ok(Self::read_from_buffer(&decompressed)?)
+    }
+}
+
+/// Rows of a subscription as of its last change, kept up to date by the
+/// events it receives
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Materialized {
+    columns: Vec<CompactString>,
+    rows: BTreeMap<RowId, Vec<SqliteValue>>,
+    change_id: ChangeId,
+    // whether the rows are a full snapshot
+    complete: bool,
+}
+
+impl Materialized {
+    pub fn columns(&self) -> &[CompactString] {
+        &self.columns
+    }
+
+    pub fn rows(&self) -> &BTreeMap<RowId, Vec<SqliteValue>> {
+        &self.rows
+    }
+
+    /// Last change applied to the rows
+    pub fn change_id(&self) -> ChangeId {
+        self.change_id
+    }
+
+    /// Whether the initial rows were all received
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Applies `evt` to the rows
+    pub fn apply(&mut self, evt: &QueryEvent) {
+        match evt {
+            QueryEvent::Columns { names, .. } => {
+                self.columns = names.clone();
+            }
+            QueryEvent::Row(rowid, cells) => {
+                self.rows.insert(*rowid, cells.clone());
+            }
+            QueryEvent::EndOfQuery { change_id, .. } => {
+                self.complete = true;
+                if let Some(change_id) = change_id {
+                    self.change_id = *change_id;
+                }
+            }
+            QueryEvent::Change(change_type, rowid, cells, change_id) => {
+                match change_type {
+                    ChangeType::Insert | ChangeType::Update => {
+                        self.rows.insert(*rowid, cells.clone());
+                    }
+                    ChangeType::Delete => {
+                        self.rows.remove(rowid);
+                    }
+                }
+                self.change_id = *change_id;
+            }
+            QueryEvent::Skipped { change_id } => {
+                self.change_id = *change_id;
+            }
+            QueryEvent::Rebound { change_id } => {
+                // a fresh snapshot follows
+                self.rows.clear();
+                self.complete = false;
+                self.change_id = *change_id;
+            }
+            QueryEvent::Rebootstrapped(_) => {
+                // changes were purged, a fresh snapshot follows
+                self.rows.clear();
+                self.complete = false;
+            }
+            _ => {}
+        }
+    }
+}
+
+pin_project! {
+    /// A subscription materializing its rows, which can be persisted to a
+    /// snapshot file and resumed from w/
+    /// `CorrosionApiClient::subscribe_with_snapshot` after a restart.
+    pub struct MaterializedSubscription {
+        #[pin]
+        stream: SubscriptionStream,
+        query_hash: u64,
+        state: Materialized,
+    }
+}
+
+impl MaterializedSubscription {
+    pub(crate) fn new(stream: SubscriptionStream, query_hash: u64, state: Materialized) -> Self {
+        Self {
+            stream,
+            query_hash,
+            state,
+        }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.stream.id()
+    }
+
+    /// Rows received so far, restored ones included
+    pub fn state(&self) -> &Materialized {
+        &self.state
+    }
+
+    /// Writes the rows and the last change applied to them to `path`,
+    /// replacing it atomically. Fails w/ `SnapshotError::Incomplete` until
+    /// the initial rows were all received.
+    pub async fn persist(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        if !self.state.complete {
+            return Err(SnapshotError::Incomplete);
+        }
+
+        let bytes = SnapshotFile::new(self.query_hash, self.id(), &self.state).encode()?;
+
+        let path = path.as_ref();
+        let tmp = tmp_path(path);
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, path).await?;
+
+        Ok(())
+    }
+}
+
+impl Stream for MaterializedSubscription {
+    type Item = Result<QueryEvent, SubscriptionError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let res = ready!(this.stream.poll_next(cx));
+        if let Some(Ok(evt)) = &res {
+            this.state.apply(evt);
+        }
+        Poll::Ready(res)
+    }
+}
+
+/// A snapshot read back from its file
+pub(crate) struct Restored {
+    pub id: Uuid,
+    pub state: Materialized,
+}
+
+/// Reads the snapshot at `path`, which has to be of the query hashed to
+/// `query_hash`
+pub(crate) async fn restore(path: &Path, query_hash: u64) -> Result<Restored, SnapshotError> {
+    let bytes = tokio::fs::read(path).await?;
+    decode(&bytes, query_hash)
+}
+
+fn decode(bytes: &[u8], query_hash: u64) -> Result<Restored, SnapshotError> {
+    let file = SnapshotFile::decode(bytes)?;
+    if file.query_hash != query_hash {
+        return Err(SnapshotError::QueryMismatch);
+    }
+
+    Ok(Restored {
+        id: file.id,
+        state: Materialized {
+            columns: file.columns.into_iter().map(CompactString::from).collect(),
+            rows: file
+                .rows
+                .into_iter()
+                .map(|(rowid, cells)| (RowId(rowid), cells))
+                .collect(),
+            change_id: ChangeId(file.change_id),
+            complete: true,
+        },
+    })
+}
+
+// written to first, then renamed over `path`
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events() -> Vec<QueryEvent> {
+        vec![
+            QueryEvent::Columns {
+                names: vec!["id".into(), "text".into()],
+                schema_generation: 0,
+            },
+            QueryEvent::Row(RowId(1), vec![1i64.into(), "one".into()]),
+            QueryEvent::Row(RowId(2), vec![2i64.into(), "two".into()]),
+            QueryEvent::EndOfQuery {
+                time: 0.0,
+                change_id: Some(ChangeId(3)),
+                coerced: vec![],
+            },
+            QueryEvent::Change(
+                ChangeType::Update,
+                RowId(1),
+                vec![1i64.into(), "uno".into()],
+                ChangeId(4),
+            ),
+            QueryEvent::Change(
+                ChangeType::Delete,
+                RowId(2),
+                vec![2i64.into(), "two".into()],
+                ChangeId(5),
+            ),
+            QueryEvent::Skipped {
+                change_id: ChangeId(6),
+            },
+        ]
+    }
+
+    fn materialized() -> Materialized {
+        let mut state = Materialized::default();
+        for evt in events() {
+            state.apply(&evt);
+        }
+        state
+    }
+
+    fn encoded(state: &Materialized, query_hash: u64) -> Vec<u8> {
+        SnapshotFile::new(query_hash, Uuid::nil(), state)
+            .encode()
+            .unwrap()
+    }
+
+    #[test]
+    fn materializes_events() {
+        let mut state = Materialized::default();
+        let mut events = events().into_iter();
+        for evt in events.by_ref().take(3) {
+            state.apply(&evt);
+        }
+        assert!(!state.is_complete());
+        assert_eq!(state.rows().len(), 2);
+
+        for evt in events {
+            state.apply(&evt);
+        }
+        assert!(state.is_complete());
+        assert_eq!(state.change_id(), ChangeId(6));
+        assert_eq!(state.columns(), ["id", "text"]);
+        assert_eq!(
+            state.rows().iter().collect::<Vec<_>>(),
+            [(&RowId(1), &vec![1i64.into(), "uno".into()])]
+        );
+
+        state.apply(&QueryEvent::Rebound {
+            change_id: ChangeId(7),
+        });
+        assert!(!state.is_complete());
+        assert!(state.rows().is_empty());
+        assert_eq!(state.change_id(), ChangeId(7));
+    }
+
+    #[test]
+    fn round_trips() -> Result<(), SnapshotError> {
+        let state = materialized();
+        let statement = Statement::Simple("SELECT id, text FROM tests".into());
+        let hash = query_hash(&statement).unwrap();
+
+        let restored = decode(&encoded(&state, hash), hash)?;
+        assert_eq!(restored.id, Uuid::nil());
+        assert_eq!(restored.state, state);
+
+        // another query's snapshot
+        let other = query_hash(&Statement::Simple("SELECT id FROM tests".into())).unwrap();
+        assert_ne!(hash, other);
+        assert!(matches!(
+            decode(&encoded(&state, hash), other),
+            Err(SnapshotError::QueryMismatch)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_corrupt_snapshots() {
+        let bytes = encoded(&materialized(), 1);
+
+        let mut flipped = bytes.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 0xff;
+        assert!(matches!(
+            decode(&flipped, 1),
+            Err(SnapshotError::Corrupt("checksum mismatch"))
+        ));
+
+        assert!(matches!(
+            decode(&bytes[..bytes.len() / 2], 1),
+            Err(SnapshotError::Corrupt(_))
+        ));
+        assert!(matches!(
+            decode(b"not a snapshot at all", 1),
+            Err(SnapshotError::Corrupt(_))
+        ));
+
+        let mut newer = bytes;
+        newer[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(
+            decode(&newer, 1),
+            Err(SnapshotError::UnsupportedVersion(v)) if v == VERSION + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn persists_complete_rows() -> Result<(), SnapshotError> {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let stream = SubscriptionStream::new(
+            Uuid::new_v4(),
+            None,
+            crate::CorrosionApiClient::new(addr),
+            addr,
+            hyper::Body::empty(),
+        );
+        let statement = Statement::Simple("SELECT id, text FROM tests".into());
+        let hash = query_hash(&statement).unwrap();
+        let mut sub = MaterializedSubscription::new(stream, hash, Materialized::default());
+
+        let dir = std::env::temp_dir().join(format!("corro-snapshot-{}", Uuid::new_v4()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join("sub.snap");
+
+        assert!(matches!(
+            sub.persist(&path).await,
+            Err(SnapshotError::Incomplete)
+        ));
+        assert!(!path.exists());
+
+        sub.state = materialized();
+        sub.persist(&path).await?;
+        assert!(!tmp_path(&path).exists());
+
+        let restored = restore(&path, hash).await?;
+        assert_eq!(restored.id, sub.id());
+        assert_eq!(restored.state, sub.state);
+
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
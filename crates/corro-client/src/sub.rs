@@ -1,6 +1,8 @@
 use std::{
+    collections::VecDeque,
     error::Error,
     io,
+    marker::PhantomData,
     net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
@@ -8,9 +10,13 @@ use std::{
 };
 
 use bytes::{Buf, Bytes, BytesMut};
-use corro_api_types::{ChangeId, QueryEvent};
+use compact_str::CompactString;
+use corro_api_types::{
+    row::{FromRow, RowError},
+    ChangeId, ChangeType, QueryEvent, ResumeGap, RowId, SqliteParam, Statement,
+};
 use futures::{ready, Future, Stream};
-use hyper::{client::HttpConnector, Body};
+use hyper::{Body, StatusCode};
 use pin_project_lite::pin_project;
 use tokio::time::{sleep, Sleep};
 use tokio_util::{
@@ -20,6 +26,12 @@ use tokio_util::{
 use tracing::error;
 use uuid::Uuid;
 
+use crate::{
+    crypto::Decrypted,
+    handshake::{self, UnsupportedCapabilities},
+    CorrosionApiClient,
+};
+
 pin_project! {
     pub struct IoBodyStream {
         #[pin]
@@ -27,6 +39,12 @@ pin_project! {
     }
 }
 
+impl IoBodyStream {
+    pub fn new(body: Body) -> Self {
+        Self { body }
+    }
+}
+
 impl Stream for IoBodyStream {
     type Item = io::Result<Bytes>;
 
@@ -52,10 +70,13 @@ impl Stream for IoBodyStream {
 
 type IoBodyStreamReader = StreamReader<IoBodyStream, Bytes>;
 type FramedBody = FramedRead<IoBodyStreamReader, LinesBytesCodec>;
+type BodyBytes = Pin<Box<dyn Future<Output = Result<Bytes, hyper::Error>> + Send>>;
 
 pub struct SubscriptionStream {
     id: Uuid,
-    client: hyper::Client<HttpConnector, Body>,
+    // the client which subscribed, for its connections and headers
+    client: CorrosionApiClient,
+    // resubscribed to at the same agent, which the subscription lives on
     api_addr: SocketAddr,
     observed_eoq: bool,
     last_change_id: ChangeId,
@@ -63,6 +84,19 @@ pub struct SubscriptionStream {
     backoff: Option<Pin<Box<Sleep>>>,
     backoff_count: u32,
     response: Option<hyper::client::ResponseFuture>,
+    // resubscribed to w/ its statement, sharing a matcher w/ other params
+    shared: Option<Statement>,
+    // passed along when resubscribing
+    skip_source_id: Option<Uuid>,
+    origins: bool,
+    // the body of a `410 Gone` response to resuming
+    gap_body: Option<BodyBytes>,
+    // emitted as `QueryEvent::Rebootstrapped` before the fresh snapshot
+    rebootstrapped: Option<ResumeGap>,
+    // resubscribed to w/o `from` until the fresh snapshot's end of query
+    rebootstrap: bool,
+    // changes of a `QueryEvent::ChangeBatch` not handed out yet
+    batched: VecDeque<QueryEvent>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -81,13 +115,23 @@ pub enum SubscriptionError {
     UnfinishedQuery,
     #[error("max retry attempts exceeded")]
     MaxRetryAttempts,
+    /// The agent ended the subscription to shut down, resubscribing to
+    /// another agent can be done right away
+    #[error("corrosion is shutting down")]
+    ShuttingDown,
+    /// The agent resubscribed to is too old for a capability the
+    /// subscription requires
+    #[error(transparent)]
+    UnsupportedCapabilities(#[from] UnsupportedCapabilities),
+    #[error(transparent)]
+    Row(#[from] RowError),
 }
 
 impl SubscriptionStream {
     pub fn new(
         id: Uuid,
         last_change_id: Option<ChangeId>,
-        client: hyper::Client<HttpConnector, Body>,
+        client: CorrosionApiClient,
         api_addr: SocketAddr,
         body: hyper::Body,
     ) -> Self {
@@ -95,7 +139,8 @@ impl SubscriptionStream {
             id,
             client,
             api_addr,
-            observed_eoq: false,
+            // resumed after a change, the initial query was already received
+            observed_eoq: last_change_id.is_some(),
             last_change_id: last_change_id.unwrap_or_default(),
             stream: Some(FramedRead::new(
                 StreamReader::new(IoBodyStream { body }),
@@ -104,49 +149,116 @@ impl SubscriptionStream {
             backoff: None,
             backoff_count: 0,
             response: None,
+            shared: None,
+            skip_source_id: None,
+            origins: false,
+            gap_body: None,
+            rebootstrapped: None,
+            rebootstrap: false,
+            batched: VecDeque::new(),
         }
     }
 
+    pub(crate) fn skip_source_id(mut self, source_id: Option<Uuid>) -> Self {
+        self.skip_source_id = source_id;
+        self
+    }
+
+    pub(crate) fn origins(mut self, origins: bool) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    pub(crate) fn shared(mut self, statement: Statement) -> Self {
+        self.shared = Some(statement);
+        self
+    }
+
+    /// Marks `body` as a fresh snapshot replacing a resume after `gap`
+    pub(crate) fn rebootstrapped(mut self, gap: Option<ResumeGap>) -> Self {
+        self.rebootstrap = gap.is_some();
+        self.rebootstrapped = gap;
+        self
+    }
+
     pub fn id(&self) -> Uuid {
         self.id
     }
 
+    /// Decrypts the values of encrypted columns w/ the client's keys, see
+    /// `CorrosionApiClient::with_column_crypto`
+    pub fn decrypted(self) -> Decrypted<Self> {
+        let crypto = self.client.column_crypto().cloned();
+        Decrypted::new(self, crypto)
+    }
+
+    // resumes after the last change, unless starting over
+    fn from_query(&self, sep: char) -> String {
+        let mut query = format!("{sep}batch=true");
+        if !self.rebootstrap {
+            query.push_str(&format!("&from={}", self.last_change_id));
+        }
+        if let Some(source_id) = self.skip_source_id {
+            query.push_str(&format!("&skip_source_id={source_id}"));
+        }
+        query
+    }
+
+    /// Decodes rows as `T` by column name, following the columns the agent
+    /// sends again when a schema change altered them
+    pub fn typed<T: FromRow>(self) -> TypedSubscription<T> {
+        TypedSubscription {
+            stream: self,
+            names: vec![],
+            _row: PhantomData,
+        }
+    }
+
+    /// Whether the stream shares its matcher w/ other params of its query,
+    /// and only gets changes for its own rows
+    pub fn is_shared(&self) -> bool {
+        self.shared.is_some()
+    }
+
     fn poll_stream(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<QueryEvent, SubscriptionError>>> {
-        let stream = loop {
-            match self.stream.as_mut() {
-                None => match ready!(self.as_mut().poll_request(cx)) {
-                    Ok(stream) => {
-                        self.stream = Some(stream);
-                    }
-                    Err(e) => return Poll::Ready(Some(Err(e))),
-                },
-                Some(stream) => {
-                    break stream;
+        // before resubscribing, which resumes after the last change handed out
+        if let Some(evt) = self.batched.pop_front() {
+            return Poll::Ready(Some(self.observe(evt)));
+        }
+
+        while self.stream.is_none() {
+            match ready!(self.as_mut().poll_request(cx)) {
+                Ok(stream) => {
+                    self.stream = Some(stream);
                 }
+                Err(e) => return Poll::Ready(Some(Err(e))),
             }
-        };
+        }
 
+        if let Some(gap) = self.rebootstrapped.take() {
+            // changes were purged, a fresh snapshot follows
+            self.observed_eoq = false;
+            return Poll::Ready(Some(Ok(QueryEvent::Rebootstrapped(gap))));
+        }
+
+        let stream = self.stream.as_mut().expect("stream was just set");
         let res = ready!(Pin::new(stream).poll_next(cx));
         match res {
-            Some(Ok(b)) => match serde_json::from_slice(&b) {
-                Ok(evt) => {
-                    if let QueryEvent::EndOfQuery { change_id, .. } = &evt {
-                        self.observed_eoq = true;
-                        if let Some(change_id) = change_id {
-                            self.last_change_id = *change_id;
-                        }
-                    }
-                    if let QueryEvent::Change(_, _, _, change_id) = &evt {
-                        if self.last_change_id.0 + 1 != change_id.0 {
-                            return Poll::Ready(Some(Err(SubscriptionError::MissedChange)));
-                        }
-                        self.last_change_id = *change_id;
-                    }
-                    Poll::Ready(Some(Ok(evt)))
+            Some(Ok(b)) => match serde_json::from_slice::<QueryEvent>(&b) {
+                Ok(QueryEvent::ChangeBatch(changes)) => {
+                    self.batched.extend(changes.into_iter().map(
+                        |(change_type, rowid, cells, change_id)| {
+                            QueryEvent::Change(change_type, rowid, cells, change_id)
+                        },
+                    ));
+                    self.poll_stream(cx)
                 }
+                // checked against the response's headers already
+                Ok(QueryEvent::Hello { .. }) => self.poll_stream(cx),
+                Ok(evt) => Poll::Ready(Some(self.observe(evt))),
                 Err(e) => Poll::Ready(Some(Err(e.into()))),
             },
             Some(Err(e)) => match e {
@@ -159,12 +271,55 @@ impl SubscriptionStream {
         }
     }
 
+    // tracks where the stream is at before handing out `evt`
+    fn observe(&mut self, evt: QueryEvent) -> Result<QueryEvent, SubscriptionError> {
+        if evt.is_shutting_down() {
+            // not retried like a reset connection, this agent is going away
+            return Err(SubscriptionError::ShuttingDown);
+        }
+        if let QueryEvent::EndOfQuery { change_id, .. } = &evt {
+            self.observed_eoq = true;
+            self.rebootstrap = false;
+            if let Some(change_id) = change_id {
+                self.last_change_id = *change_id;
+            }
+        }
+        if let QueryEvent::Rebound { change_id } = &evt {
+            // a fresh snapshot follows, changes resume after the marker
+            self.last_change_id = *change_id;
+        }
+        if let QueryEvent::Change(_, _, _, change_id) | QueryEvent::Skipped { change_id } = &evt {
+            // shared streams skip the changes of other params' rows
+            if self.shared.is_none() && self.last_change_id.0 + 1 != change_id.0 {
+                return Err(SubscriptionError::MissedChange);
+            }
+            self.last_change_id = *change_id;
+        }
+        Ok(evt)
+    }
+
     fn poll_request(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<FramedBody, SubscriptionError>> {
         loop {
-            if let Some(res_fut) = self.response.as_mut() {
+            if let Some(body) = self.gap_body.as_mut() {
+                let res = ready!(body.as_mut().poll(cx));
+                self.gap_body = None;
+
+                let gap = res
+                    .ok()
+                    .and_then(|bytes| serde_json::from_slice::<ResumeGap>(&bytes).ok());
+                match gap {
+                    Some(gap) => {
+                        // start over w/o `from`, see `poll_stream`
+                        self.rebootstrapped = Some(gap);
+                        self.rebootstrap = true;
+                    }
+                    None => return Poll::Ready(Err(SubscriptionError::MissedChange)),
+                }
+                // loop around!
+            } else if let Some(res_fut) = self.response.as_mut() {
                 // return early w/ Poll::Pending if response is not ready
                 let res = ready!(Pin::new(res_fut).poll(cx));
 
@@ -172,12 +327,20 @@ impl SubscriptionStream {
                 self.response = None;
 
                 return match res {
-                    Ok(res) => Poll::Ready(Ok(FramedRead::new(
-                        StreamReader::new(IoBodyStream {
-                            body: res.into_body(),
-                        }),
-                        LinesBytesCodec::default(),
-                    ))),
+                    Ok(res) if res.status() == StatusCode::GONE => {
+                        // changes after `last_change_id` were purged
+                        self.gap_body = Some(Box::pin(hyper::body::to_bytes(res.into_body())));
+                        continue;
+                    }
+                    Ok(res) => {
+                        handshake::check(res.headers(), handshake::required(self.origins))?;
+                        Poll::Ready(Ok(FramedRead::new(
+                            StreamReader::new(IoBodyStream {
+                                body: res.into_body(),
+                            }),
+                            LinesBytesCodec::default(),
+                        )))
+                    }
                     Err(e) => {
                         let io_err = match e
                             .source()
@@ -189,17 +352,37 @@ impl SubscriptionStream {
                         Poll::Ready(Err(io_err.into()))
                     }
                 };
-            } else if self.observed_eoq {
-                let req = hyper::Request::builder()
+            } else if let (Some(statement), true) =
+                (&self.shared, self.observed_eoq || self.rebootstrap)
+            {
+                let mut req = hyper::Request::builder()
+                    .method(hyper::Method::POST)
+                    .uri(self.client.url_at(
+                        self.api_addr,
+                        &format!("/v1/subscriptions?shared=true{}", self.from_query('&')),
+                    ))
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .header(hyper::header::ACCEPT, "application/json")
+                    .body(hyper::Body::from(serde_json::to_vec(statement)?))?;
+                handshake::request(req.headers_mut(), self.origins);
+                self.client.authorize(req.headers_mut())?;
+
+                let response = self.client.http().request(req);
+                self.response = Some(response);
+                // loop around!
+            } else if self.observed_eoq || self.rebootstrap {
+                let mut req = hyper::Request::builder()
                     .method(hyper::Method::GET)
-                    .uri(format!(
-                        "http://{}/v1/subscriptions/{}?from={}",
-                        self.api_addr, self.id, self.last_change_id
+                    .uri(self.client.url_at(
+                        self.api_addr,
+                        &format!("/v1/subscriptions/{}{}", self.id, self.from_query('?')),
                     ))
                     .header(hyper::header::ACCEPT, "application/json")
                     .body(hyper::Body::empty())?;
+                handshake::request(req.headers_mut(), self.origins);
+                self.client.authorize(req.headers_mut())?;
 
-                let response = self.client.request(req);
+                let response = self.client.http().request(req);
                 self.response = Some(response);
                 // loop around!
             } else {
@@ -248,6 +431,97 @@ impl Stream for SubscriptionStream {
     }
 }
 
+/// A subscription event w/ its row decoded as `T`, see
+/// `SubscriptionStream::typed`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypedEvent<T> {
+    Row(RowId, T),
+    Change(ChangeType, RowId, T, ChangeId),
+    /// Any other event, e.g. `QueryEvent::Columns` or `QueryEvent::Rebound`
+    Event(QueryEvent),
+}
+
+pin_project! {
+    /// Decodes the rows of a `SubscriptionStream` by the latest column names
+    /// received, which a schema change can reorder or extend
+    pub struct TypedSubscription<T> {
+        #[pin]
+        stream: SubscriptionStream,
+        names: Vec<CompactString>,
+        _row: PhantomData<fn() -> T>,
+    }
+}
+
+impl<T> TypedSubscription<T> {
+    pub fn id(&self) -> Uuid {
+        self.stream.id()
+    }
+
+    /// Column names the rows are currently decoded by
+    pub fn columns(&self) -> &[CompactString] {
+        &self.names
+    }
+}
+
+impl<T: FromRow> Stream for TypedSubscription<T> {
+    type Item = Result<TypedEvent<T>, SubscriptionError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let evt = match ready!(this.stream.poll_next(cx)) {
+            Some(Ok(evt)) => evt,
+            other => return Poll::Ready(other.map(|res| res.map(TypedEvent::Event))),
+        };
+
+        let typed = match evt {
+            QueryEvent::Row(rowid, cells) => T::from_values(&cells, this.names.as_slice())
+                .map(|row| TypedEvent::Row(rowid, row))
+                .map_err(SubscriptionError::from),
+            QueryEvent::Change(change_type, rowid, cells, change_id) => {
+                T::from_values(&cells, this.names.as_slice())
+                    .map(|row| TypedEvent::Change(change_type, rowid, row, change_id))
+                    .map_err(SubscriptionError::from)
+            }
+            evt => {
+                if let QueryEvent::Columns { names, .. } = &evt {
+                    // rows after this are laid out the new way
+                    *this.names = names.clone();
+                }
+                Ok(TypedEvent::Event(evt))
+            }
+        };
+        Poll::Ready(Some(typed))
+    }
+}
+
+/// An existing subscription, whose query can be rebound to new params w/o
+/// resubscribing. Streams of the subscription receive a `QueryEvent::Rebound`
+/// marker followed by a fresh snapshot.
+#[derive(Clone)]
+pub struct SubscriptionHandle {
+    id: Uuid,
+    query: String,
+    client: CorrosionApiClient,
+}
+
+impl SubscriptionHandle {
+    pub(crate) fn new(id: Uuid, query: String, client: CorrosionApiClient) -> Self {
+        Self { id, query, client }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Rebinds the subscription's query to `params`, returns the change id
+    /// of the `QueryEvent::Rebound` marker
+    pub async fn rebind(&self, params: Vec<SqliteParam>) -> Result<ChangeId, crate::Error> {
+        self.client
+            .rebind_subscription(self.id, &Statement::WithParams(self.query.clone(), params))
+            .await
+    }
+}
+
 pub struct LinesBytesCodec {
     // Stored index of the next index to examine for a `\n` character.
     // This is used to optimize searching.
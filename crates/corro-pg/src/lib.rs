@@ -2270,7 +2270,7 @@ fn extract_param<'a>(
                     SqliteName::Id(id) => {
                         // find the first one to match
                         for (_, table) in tables.iter() {
-                            if let Some(col) = table.columns.get(&id.0) {
+                            if let Some(col) = table.column(&id.0) {
                                 params.push(col.sql_type());
                                 break;
                             }
@@ -2288,7 +2288,7 @@ fn extract_param<'a>(
                                 &col_name.0
                             };
 
-                            if let Some(col) = table.columns.get(col_name) {
+                            if let Some(col) = table.column(col_name) {
                                 params.push(col.sql_type());
                             }
                         }
@@ -2349,7 +2349,7 @@ fn extract_param<'a>(
                             SqliteName::Id(id) => {
                                 // find the first one to match
                                 for (_, table) in tables.iter() {
-                                    if let Some(col) = table.columns.get(&id.0) {
+                                    if let Some(col) = table.column(&id.0) {
                                         params.push(col.sql_type());
                                         break;
                                     }
@@ -2359,7 +2359,7 @@ fn extract_param<'a>(
                             SqliteName::Qualified(tbl_name, col_name)
                             | SqliteName::DoublyQualified(_, tbl_name, col_name) => {
                                 if let Some(table) = tables.get(&tbl_name.0) {
-                                    if let Some(col) = table.columns.get(&col_name.0) {
+                                    if let Some(col) = table.column(&col_name.0) {
                                         params.push(col.sql_type());
                                     }
                                 }
@@ -2605,7 +2605,7 @@ fn parameter_types<'a>(schema: &'a Schema, cmd: &ParsedCmd) -> Vec<(SqliteType,
                                             let col = if let Some(columns) = columns {
                                                 columns
                                                     .get(i)
-                                                    .and_then(|name| table.columns.get(&name.0))
+                                                    .and_then(|name| table.column(&name.0))
                                             } else {
                                                 table.columns.get_index(i).map(|(_name, col)| col)
                                             };
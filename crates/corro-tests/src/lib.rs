@@ -3,7 +3,7 @@ use std::sync::Arc;
 use corro_agent::agent::start;
 use corro_types::{
     agent::Agent,
-    config::{Config, ConfigBuilder, ConfigBuilderError},
+    config::{AuthzConfig, Config, ConfigBuilder, ConfigBuilderError},
 };
 use tempfile::TempDir;
 use tripwire::Tripwire;
@@ -50,11 +50,15 @@ pub async fn launch_test_agent<F: FnOnce(ConfigBuilder) -> Result<Config, Config
     tokio::fs::write(schema_path.join("tests.sql"), TEST_SCHEMA.as_bytes()).await?;
 
     let schema_paths = conf.db.schema_paths.clone();
+    let authorization = conf.api.authorization.clone();
 
     let agent = start(conf, tripwire).await?;
 
     {
-        let client = corro_client::CorrosionApiClient::new(agent.api_addr());
+        let mut client = corro_client::CorrosionApiClient::new(agent.api_addr());
+        if let Some(AuthzConfig::BearerToken(token)) = authorization {
+            client = client.with_bearer_token(token.into_inner());
+        }
         client.schema_from_paths(&schema_paths).await?;
     }
 
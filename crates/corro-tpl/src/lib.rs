@@ -261,7 +261,7 @@ impl QueryResponseIter {
             };
             match res {
                 Some(Ok(evt)) => match evt {
-                    QueryEvent::Columns(cols) => {
+                    QueryEvent::Columns { names: cols, .. } => {
                         self.columns = Some(Arc::new(
                             cols.into_iter()
                                 .enumerate()
@@ -307,6 +307,19 @@ impl QueryResponseIter {
                             }
                         }
                     }
+                    QueryEvent::Hello { .. }
+                    | QueryEvent::Rebound { .. }
+                    | QueryEvent::Skipped { .. }
+                    | QueryEvent::Origin(_)
+                    | QueryEvent::NextResultSet { .. }
+                    | QueryEvent::Rebootstrapped(_) => {}
+                    // corro-client hands out batched changes one at a time
+                    QueryEvent::ChangeBatch(_) => {
+                        self.done = true;
+                        return Some(Err(Box::new(EvalAltResult::from(
+                            "unexpected change batch",
+                        ))));
+                    }
                     QueryEvent::Error(e) => {
                         self.done = true;
                         return Some(Err(Box::new(EvalAltResult::from(e))));
@@ -427,6 +440,20 @@ async fn wait_for_rows(
                 debug!("could not send back re-render command, channel must be closed!");
             }
         }
+        Some(Ok(QueryEvent::Rebound { change_id })) => {
+            trace!("subscription was rebound at change {change_id}");
+
+            if let Err(_e) = tx.send(TemplateCommand::Render).await {
+                debug!("could not send back re-render command, channel must be closed!");
+            }
+        }
+        Some(Ok(QueryEvent::Rebootstrapped(gap))) => {
+            trace!("subscription started over: {gap}");
+
+            if let Err(_e) = tx.send(TemplateCommand::Render).await {
+                debug!("could not send back re-render command, channel must be closed!");
+            }
+        }
         Some(Ok(evt)) => {
             warn!("unexpected event receive: {evt:?}")
         }
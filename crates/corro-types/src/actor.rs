@@ -6,7 +6,7 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use corro_api_types::SqliteValue;
+use corro_api_types::{SiteId, SqliteValue};
 use foca::Identity;
 use rusqlite::{
     types::{FromSql, ToSqlOutput},
@@ -37,6 +37,23 @@ impl ActorId {
     pub fn from_bytes(bytes: [u8; 16]) -> Self {
         Self(Uuid::from_bytes(bytes))
     }
+
+    /// The actor's cr-sqlite site id, which changes it made are tagged w/
+    pub fn site_id(&self) -> SiteId {
+        SiteId(self.to_bytes())
+    }
+}
+
+impl From<SiteId> for ActorId {
+    fn from(site_id: SiteId) -> Self {
+        Self::from_bytes(site_id.to_bytes())
+    }
+}
+
+impl From<ActorId> for SiteId {
+    fn from(actor_id: ActorId) -> Self {
+        actor_id.site_id()
+    }
 }
 
 impl TryFrom<ActorId> for uhlc::ID {
@@ -5,10 +5,10 @@ use std::{
     ops::{Deref, DerefMut, RangeInclusive},
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use arc_swap::ArcSwap;
@@ -37,11 +37,24 @@ use tripwire::Tripwire;
 
 use crate::{
     actor::ActorId,
+    api::{ExecErrorCode, HealthDetails, SiteId},
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput, Timestamp},
     config::Config,
-    pubsub::MatcherHandle,
+    exec::ExecRegistry,
+    hooks::{ChangeHooks, ChangeReceiver},
+    node_names::NodeNames,
+    probe::ProbeStats,
+    pubsub::{MatcherHandle, TableChanges},
+    query_cache::QueryCache,
+    quota::Quotas,
+    registry::QueryRegistry,
+    sampling::ErrorSampler,
     schema::Schema,
-    sqlite::{rusqlite_to_crsqlite, setup_conn, AttachMap, CrConn, SqlitePool, SqlitePoolError},
+    sqlite::{
+        rusqlite_to_crsqlite, rusqlite_to_crsqlite_reader, setup_conn, AttachMap, CrConn,
+        SqlitePool, SqlitePoolError,
+    },
+    usage::Usage,
 };
 
 use super::members::Members;
@@ -88,6 +101,20 @@ pub struct AgentInner {
     tx_foca: Sender<FocaInput>,
     schema: RwLock<Schema>,
     limits: Limits,
+    health: RwLock<Option<HealthDetails>>,
+    query_registry: QueryRegistry,
+    exec_registry: ExecRegistry,
+    query_cache: QueryCache,
+    quotas: Quotas,
+    change_hooks: ChangeHooks,
+    probe: ProbeStats,
+    node_names: NodeNames,
+    error_sampler: ErrorSampler,
+    usage: Usage,
+    schema_version: AtomicU64,
+    started_at: u64,
+    last_checkpoint_at: AtomicU64,
+    tripwire: Tripwire,
 }
 
 #[derive(Debug, Clone)]
@@ -116,6 +143,27 @@ impl Agent {
             limits: Limits {
                 sync: Arc::new(Semaphore::new(3)),
             },
+            health: RwLock::new(None),
+            query_registry: QueryRegistry::default(),
+            exec_registry: ExecRegistry::default(),
+            query_cache: QueryCache::default(),
+            quotas: Quotas::default(),
+            change_hooks: ChangeHooks::default(),
+            probe: ProbeStats::default(),
+            node_names: NodeNames::default(),
+            error_sampler: ErrorSampler::default(),
+            usage: Usage::default(),
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            // the schema may have changed while the agent was down
+            schema_version: AtomicU64::new(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_millis() as u64),
+            ),
+            last_checkpoint_at: AtomicU64::new(0),
+            tripwire: config.tripwire,
         }))
     }
 
@@ -132,6 +180,11 @@ impl Agent {
         self.0.actor_id
     }
 
+    /// Site id of the changes this agent makes, see `ActorId::site_id`
+    pub fn site_id(&self) -> SiteId {
+        self.0.actor_id.site_id()
+    }
+
     pub fn clock(&self) -> &Arc<uhlc::HLC> {
         &self.0.clock
     }
@@ -191,19 +244,159 @@ impl Agent {
         self.0.config.store(Arc::new(new_conf))
     }
 
+    /// Trips when the agent starts shutting down
+    pub fn tripwire(&self) -> &Tripwire {
+        &self.0.tripwire
+    }
+
     pub fn limits(&self) -> &Limits {
         &self.0.limits
     }
 
+    /// Last storage health check, if any ran yet
+    pub fn health(&self) -> Option<HealthDetails> {
+        self.0.health.read().clone()
+    }
+
+    pub fn set_health(&self, health: HealthDetails) {
+        *self.0.health.write() = Some(health);
+    }
+
+    pub fn query_registry(&self) -> &QueryRegistry {
+        &self.0.query_registry
+    }
+
+    /// `/v1/transactions` requests in progress
+    pub fn exec_registry(&self) -> &ExecRegistry {
+        &self.0.exec_registry
+    }
+
+    /// Results of `/v1/queries` requests made w/ `cache_ttl_ms`
+    pub fn query_cache(&self) -> &QueryCache {
+        &self.0.query_cache
+    }
+
+    pub fn quotas(&self) -> &Quotas {
+        &self.0.quotas
+    }
+
+    pub fn change_hooks(&self) -> &ChangeHooks {
+        &self.0.change_hooks
+    }
+
+    /// Propagation latencies of peers' heartbeats, see `gossip.probe`
+    pub fn probe(&self) -> &ProbeStats {
+        &self.0.probe
+    }
+
+    /// Names of the cluster's nodes, to attribute changes to their origin
+    pub fn node_names(&self) -> &NodeNames {
+        &self.0.node_names
+    }
+
+    /// Samples the errors queries and subscriptions respond w/, for logging
+    pub fn error_sampler(&self) -> &ErrorSampler {
+        &self.0.error_sampler
+    }
+
+    /// What subscriptions and clients consumed since it was last persisted
+    pub fn usage(&self) -> &Usage {
+        &self.0.usage
+    }
+
+    /// When the agent started, in milliseconds since the epoch
+    pub fn started_at(&self) -> u64 {
+        self.0.started_at
+    }
+
+    /// When the WAL was last checkpointed and truncated, in milliseconds
+    /// since the epoch, 0 if it wasn't yet
+    pub fn last_checkpoint_at(&self) -> u64 {
+        self.0.last_checkpoint_at.load(Ordering::Acquire)
+    }
+
+    pub fn set_last_checkpoint_at(&self, at: u64) {
+        self.0.last_checkpoint_at.store(at, Ordering::Release)
+    }
+
+    /// Receives batches of changes committed to `tables`, or to every table
+    /// if empty, as they're handed to subscriptions. For processes embedding
+    /// the agent, w/o going through the HTTP API.
+    ///
+    /// Receivers which fall too far behind get `ChangeRecvError::Lagged`
+    /// instead of holding up writes. Primary keys come packed, see
+    /// `pubsub::unpack_columns`:
+    ///
+    /// ```ignore
+    /// let mut changes = agent.changes(["services"]);
+    /// while let Ok(batch) = changes.recv().await {
+    ///     for change in batch.iter() {
+    ///         let pk = corro_types::pubsub::unpack_columns(&change.pk)?;
+    ///         println!("{}{pk:?}.{} = {:?}", change.table, change.cid, change.val);
+    ///     }
+    /// }
+    /// ```
+    pub fn changes<I, T>(&self, tables: I) -> ChangeReceiver
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        self.0.change_hooks.subscribe(tables)
+    }
+
+    /// Changes whenever the schema does, so clients know to refresh what they
+    /// cached about it. Only comparable for equality.
+    pub fn schema_version(&self) -> u64 {
+        self.0.schema_version.load(Ordering::Acquire)
+    }
+
+    /// Invalidates what depends on the schema after it changed: cached query
+    /// results and, through the schema version, clients' cached schemas
+    pub fn schema_changed(&self) {
+        self.query_cache().clear();
+        self.0.schema_version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Whether the last storage health check crossed a configured threshold
+    pub fn is_degraded(&self) -> bool {
+        self.0
+            .health
+            .read()
+            .as_ref()
+            .map_or(false, |health| health.degraded)
+    }
+
     pub fn process_subs_by_db_version(&self, conn: &Connection, db_version: i64) {
         trace!("process subs by db version...");
 
+        if let Err(e) = self.change_hooks().notify_db_version(conn, db_version) {
+            error!("could not notify change hooks for db_version {db_version}: {e}");
+        }
+
+        if self.query_cache().in_use() {
+            if let Err(e) = self.query_cache().invalidate_db_version(conn, db_version) {
+                error!("could not invalidate cached queries for db_version {db_version}: {e}");
+            }
+        }
+
+        if self.matchers().read().is_empty() {
+            return;
+        }
+
+        let changes = match TableChanges::from_db_version(conn, db_version) {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("could not read changes of db_version {db_version} for subscriptions: {e}");
+                return;
+            }
+        };
+
         let mut matchers_to_delete = vec![];
 
         {
             let matchers = self.matchers().read();
             for (id, matcher) in matchers.iter() {
-                if let Err(e) = matcher.process_changes_from_db_version(conn, db_version) {
+                if let Err(e) = matcher.process_changes(&changes, None) {
                     error!("could not process change w/ matcher {id}, it is probably defunct! {e}");
                     matchers_to_delete.push(*id);
                 }
@@ -248,6 +441,43 @@ pub enum ChangeError {
     Pool(#[from] PoolError),
     #[error("rusqlite: {0}")]
     Rusqlite(#[from] rusqlite::Error),
+    #[error("change for {table}.{cid} is {size} bytes, over the limit of {max} bytes")]
+    TooLarge {
+        table: String,
+        cid: String,
+        size: usize,
+        max: usize,
+    },
+    #[error("transaction aborted: {0}")]
+    Aborted(&'static str),
+    /// A statement's params don't match its placeholders, or it can't run in
+    /// a transaction
+    #[error("{0}")]
+    InvalidParams(String),
+    /// Killed w/ `ExecRegistry::kill`
+    #[error("transaction interrupted: it was killed and rolled back")]
+    Interrupted,
+    /// A migration's schema change can't be applied
+    #[error("{0}")]
+    Schema(String),
+    /// A statement streamed out of a request body couldn't be parsed, the
+    /// ones before it were rolled back
+    #[error("statement {index}: {error}")]
+    Malformed { index: usize, error: String },
+    /// A request body, or the JSON params it holds, is over its limit
+    #[error("{0}")]
+    BodyTooLarge(String),
+}
+
+impl ChangeError {
+    /// Condition the error can be attributed to, if any
+    pub fn exec_error_code(&self) -> Option<ExecErrorCode> {
+        match self {
+            ChangeError::Rusqlite(e) => ExecErrorCode::from_sqlite(e),
+            ChangeError::Interrupted => Some(ExecErrorCode::Interrupted),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -264,6 +494,7 @@ impl SplitPool {
     pub async fn create<P: AsRef<Path>, P2: AsRef<Path>>(
         path: P,
         subscriptions_path: P2,
+        read_pool_size: usize,
         tripwire: Tripwire,
     ) -> Result<Self, SplitPoolCreateError> {
         let rw_pool = sqlite_pool::Config::new(path.as_ref())
@@ -274,8 +505,8 @@ impl SplitPool {
 
         let ro_pool = sqlite_pool::Config::new(path.as_ref())
             .read_only()
-            .max_size(20)
-            .create_pool_transform(rusqlite_to_crsqlite)?;
+            .max_size(read_pool_size.max(1))
+            .create_pool_transform(rusqlite_to_crsqlite_reader)?;
         debug!("built RO pool");
 
         Ok(Self::new(
@@ -46,6 +46,18 @@ pub enum BiPayloadV1 {
         actor_id: ActorId,
         #[speedy(default_on_eof)]
         trace_ctx: SyncTraceContextV1,
+        /// Changes w/ compressed values can be sent back, older peers don't
+        /// send it and only get uncompressed changes
+        #[speedy(default_on_eof)]
+        accepts_compression: bool,
+        /// Changes can be sent back w/ their origin timestamp, in the
+        /// `Change` extensions record older peers can't read
+        #[speedy(default_on_eof)]
+        accepts_change_ts: bool,
+        /// Changesets can be sent back checksummed, older peers don't send
+        /// it and only get plain ones
+        #[speedy(default_on_eof)]
+        accepts_checksums: bool,
     },
 }
 
@@ -399,3 +411,239 @@ impl<T> DispatchRuntime<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::{ColumnName, SqliteValue, TableName};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::sync::{SyncMessage, SyncMessageV1};
+
+    // `BiPayload` as sent by peers predating compression
+    #[derive(Debug, Readable, Writable)]
+    enum OldBiPayload {
+        V1(OldBiPayloadV1),
+    }
+
+    #[derive(Debug, Readable, Writable)]
+    enum OldBiPayloadV1 {
+        SyncStart {
+            actor_id: ActorId,
+            trace_ctx: SyncTraceContextV1,
+        },
+    }
+
+    // `BiPayload` as sent by peers predating change timestamps
+    #[derive(Debug, Readable, Writable)]
+    enum PreTsBiPayload {
+        V1(PreTsBiPayloadV1),
+    }
+
+    #[derive(Debug, Readable, Writable)]
+    enum PreTsBiPayloadV1 {
+        SyncStart {
+            actor_id: ActorId,
+            trace_ctx: SyncTraceContextV1,
+            accepts_compression: bool,
+        },
+    }
+
+    // `BiPayload` as sent by peers predating checksums
+    #[derive(Debug, Readable, Writable)]
+    enum PreChecksumsBiPayload {
+        V1(PreChecksumsBiPayloadV1),
+    }
+
+    #[derive(Debug, Readable, Writable)]
+    enum PreChecksumsBiPayloadV1 {
+        SyncStart {
+            actor_id: ActorId,
+            trace_ctx: SyncTraceContextV1,
+            accepts_compression: bool,
+            accepts_change_ts: bool,
+        },
+    }
+
+    #[test]
+    fn compression_is_negotiated() -> Result<(), speedy::Error> {
+        let actor_id = ActorId(Uuid::new_v4());
+
+        // old peers don't accept compression
+        let old = OldBiPayload::V1(OldBiPayloadV1::SyncStart {
+            actor_id,
+            trace_ctx: Default::default(),
+        })
+        .write_to_vec()?;
+        match BiPayload::read_from_buffer(&old)? {
+            BiPayload::V1(BiPayloadV1::SyncStart {
+                actor_id: read_actor_id,
+                accepts_compression,
+                ..
+            }) => {
+                assert_eq!(read_actor_id, actor_id);
+                assert!(!accepts_compression);
+            }
+        }
+
+        // and can still read what newer peers send
+        let new = BiPayload::V1(BiPayloadV1::SyncStart {
+            actor_id,
+            trace_ctx: Default::default(),
+            accepts_compression: true,
+            accepts_change_ts: false,
+            accepts_checksums: false,
+        })
+        .write_to_vec()?;
+        let OldBiPayload::V1(OldBiPayloadV1::SyncStart {
+            actor_id: read_actor_id,
+            ..
+        }) = OldBiPayload::read_from_buffer(&new)?;
+        assert_eq!(read_actor_id, actor_id);
+
+        match BiPayload::read_from_buffer(&new)? {
+            BiPayload::V1(BiPayloadV1::SyncStart {
+                accepts_compression,
+                ..
+            }) => assert!(accepts_compression),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn change_ts_is_negotiated() -> Result<(), speedy::Error> {
+        let actor_id = ActorId(Uuid::new_v4());
+
+        let old = PreTsBiPayload::V1(PreTsBiPayloadV1::SyncStart {
+            actor_id,
+            trace_ctx: Default::default(),
+            accepts_compression: true,
+        })
+        .write_to_vec()?;
+        match BiPayload::read_from_buffer(&old)? {
+            BiPayload::V1(BiPayloadV1::SyncStart {
+                accepts_compression,
+                accepts_change_ts,
+                ..
+            }) => {
+                assert!(accepts_compression);
+                assert!(!accepts_change_ts);
+            }
+        }
+
+        let new = BiPayload::V1(BiPayloadV1::SyncStart {
+            actor_id,
+            trace_ctx: Default::default(),
+            accepts_compression: true,
+            accepts_change_ts: true,
+            accepts_checksums: false,
+        })
+        .write_to_vec()?;
+        let PreTsBiPayload::V1(PreTsBiPayloadV1::SyncStart {
+            actor_id: read_actor_id,
+            accepts_compression,
+            ..
+        }) = PreTsBiPayload::read_from_buffer(&new)?;
+        assert_eq!(read_actor_id, actor_id);
+        assert!(accepts_compression);
+
+        match BiPayload::read_from_buffer(&new)? {
+            BiPayload::V1(BiPayloadV1::SyncStart {
+                accepts_change_ts, ..
+            }) => assert!(accepts_change_ts),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn checksums_are_negotiated() -> Result<(), speedy::Error> {
+        let actor_id = ActorId(Uuid::new_v4());
+
+        let old = PreChecksumsBiPayload::V1(PreChecksumsBiPayloadV1::SyncStart {
+            actor_id,
+            trace_ctx: Default::default(),
+            accepts_compression: true,
+            accepts_change_ts: true,
+        })
+        .write_to_vec()?;
+        match BiPayload::read_from_buffer(&old)? {
+            BiPayload::V1(BiPayloadV1::SyncStart {
+                accepts_change_ts,
+                accepts_checksums,
+                ..
+            }) => {
+                assert!(accepts_change_ts);
+                assert!(!accepts_checksums);
+            }
+        }
+
+        let new = BiPayload::V1(BiPayloadV1::SyncStart {
+            actor_id,
+            trace_ctx: Default::default(),
+            accepts_compression: false,
+            accepts_change_ts: true,
+            accepts_checksums: true,
+        })
+        .write_to_vec()?;
+        let PreChecksumsBiPayload::V1(PreChecksumsBiPayloadV1::SyncStart {
+            actor_id: read_actor_id,
+            accepts_change_ts,
+            ..
+        }) = PreChecksumsBiPayload::read_from_buffer(&new)?;
+        assert_eq!(read_actor_id, actor_id);
+        assert!(accepts_change_ts);
+
+        match BiPayload::read_from_buffer(&new)? {
+            BiPayload::V1(BiPayloadV1::SyncStart {
+                accepts_checksums, ..
+            }) => assert!(accepts_checksums),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_changesets_round_trip() -> Result<(), speedy::Error> {
+        let output = "Get \"http://127.0.0.1:8080/health\": connection refused\n".repeat(100);
+        let change = Change {
+            table: TableName("consul_checks".into()),
+            pk: vec![1],
+            cid: ColumnName("output".into()),
+            val: SqliteValue::Text(output.as_str().into()),
+            col_version: 1,
+            db_version: 1,
+            seq: 0,
+            site_id: corro_api_types::SiteId([1; 16]),
+            cl: 1,
+            compressed: None,
+            ts: None,
+        };
+
+        let msg = |change: Change| {
+            SyncMessage::V1(SyncMessageV1::Changeset(ChangeV1 {
+                actor_id: ActorId(Uuid::nil()),
+                changeset: Changeset::Full {
+                    version: 1,
+                    changes: vec![change],
+                    seqs: 0..=0,
+                    last_seq: 0,
+                    ts: Timestamp::zero(),
+                },
+            }))
+        };
+
+        let uncompressed = msg(change.clone()).write_to_vec()?;
+
+        let mut compressed_change = change.clone();
+        assert!(compressed_change.compress(1024).unwrap());
+        let compressed = msg(compressed_change).write_to_vec()?;
+        assert!(compressed.len() * 5 < uncompressed.len());
+
+        // decompressed on receipt
+        assert_eq!(SyncMessage::read_from_buffer(&compressed)?, msg(change));
+
+        Ok(())
+    }
+}
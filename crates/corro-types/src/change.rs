@@ -1 +1,401 @@
-pub use corro_api_types::{row_to_change, Change, SqliteValue};
+//! Applying changes from other nodes to a local cr-sqlite database w/o the
+//! agent, e.g. to keep a read-replica embedded in another binary.
+//!
+//! ```no_run
+//! use corro_types::{change::ChangeApplier, sqlite::CrConn};
+//! # fn changes_from_somewhere() -> Vec<corro_types::change::Change> { vec![] }
+//!
+//! let mut conn = CrConn::init(rusqlite::Connection::open("replica.db")?)?;
+//! // the replica must have the same schema as the source
+//! conn.execute_batch(
+//!     "CREATE TABLE IF NOT EXISTS todos (id INTEGER NOT NULL PRIMARY KEY, title TEXT);
+//!      SELECT crsql_as_crr('todos');",
+//! )?;
+//!
+//! let mut applier = ChangeApplier::new(&mut conn)?;
+//! let report = applier.apply(changes_from_somewhere())?;
+//! println!("applied {} versions, {} already known", report.applied, report.skipped_known);
+//! # Ok::<_, rusqlite::Error>(())
+//! ```
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use rusqlite::{params, Connection};
+use tracing::{debug, warn};
+
+use crate::api::{sqlite::ChangeKindCounts, ApplyReport};
+pub use corro_api_types::{row_to_change, Change, InvalidChange, SiteId, SqliteValue};
+
+/// Groups changes into the versions they were made in, ordered by site and
+/// `db_version`. Each version's changes are ordered by `seq`.
+#[derive(Debug, Default)]
+pub struct ChangeSorter {
+    versions: BTreeMap<(SiteId, i64), Vec<Change>>,
+}
+
+impl ChangeSorter {
+    pub fn push(&mut self, change: Change) {
+        self.versions
+            .entry((change.site_id, change.db_version))
+            .or_default()
+            .push(change);
+    }
+
+    /// Versions as `((site_id, db_version), changes)`
+    pub fn into_versions(self) -> impl Iterator<Item = ((SiteId, i64), Vec<Change>)> {
+        self.versions.into_iter().map(|(key, mut changes)| {
+            changes.sort_by_key(|change| change.seq);
+            (key, changes)
+        })
+    }
+}
+
+impl Extend<Change> for ChangeSorter {
+    fn extend<T: IntoIterator<Item = Change>>(&mut self, iter: T) {
+        for change in iter {
+            self.push(change);
+        }
+    }
+}
+
+/// Applies batches of changes to a connection w/ the cr-sqlite extension
+/// loaded, one transaction per batch.
+///
+/// The highest version applied per site is recorded in the database along w/
+/// the changes, so versions applied before, even by an earlier applier, are
+/// skipped. Batches must follow each site's version order and can't split
+/// versions: changes for versions at or below a site's mark are dropped.
+pub struct ChangeApplier<'c> {
+    conn: &'c mut Connection,
+    marks: HashMap<SiteId, i64>,
+}
+
+impl<'c> ChangeApplier<'c> {
+    /// Creates the marks' bookkeeping table if needed and loads them
+    pub fn new(conn: &'c mut Connection) -> rusqlite::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS __corro_apply_marks (
+                site_id BLOB NOT NULL PRIMARY KEY,
+                db_version INTEGER NOT NULL
+            ) WITHOUT ROWID;",
+        )?;
+
+        let marks: HashMap<SiteId, i64> = conn
+            .prepare("SELECT site_id, db_version FROM __corro_apply_marks")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Self { conn, marks })
+    }
+
+    pub fn conn(&self) -> &Connection {
+        self.conn
+    }
+
+    /// Highest version applied from `site_id`, if any
+    pub fn high_water_mark(&self, site_id: &SiteId) -> Option<i64> {
+        self.marks.get(site_id).copied()
+    }
+
+    /// Validates, orders and applies `changes` in a single transaction.
+    ///
+    /// Invalid versions, and versions sqlite refuses (e.g. for a table the
+    /// replica doesn't have), are rejected along w/ the site's later versions
+    /// in the batch, so they can be applied again. Errors are only returned
+    /// when the transaction itself fails, nothing is applied then.
+    pub fn apply(&mut self, changes: Vec<Change>) -> rusqlite::Result<ApplyReport> {
+        let mut report = ApplyReport::default();
+        let mut sorter = ChangeSorter::default();
+        sorter.extend(changes);
+
+        let mut tx = self.conn.transaction()?;
+        let mut marks = BTreeMap::new();
+        let mut blocked = HashSet::new();
+
+        for ((site_id, db_version), changes) in sorter.into_versions() {
+            report.max_db_version_seen = report.max_db_version_seen.max(Some(db_version));
+
+            let mark = marks
+                .get(&site_id)
+                .or_else(|| self.marks.get(&site_id))
+                .copied();
+            if matches!(mark, Some(mark) if db_version <= mark) {
+                report.skipped_known += 1;
+                continue;
+            }
+
+            if blocked.contains(&site_id) {
+                report.rejected.push((
+                    changes[0].table.clone(),
+                    "follows a rejected version".into(),
+                ));
+                continue;
+            }
+
+            if let Some((change, e)) = changes
+                .iter()
+                .find_map(|change| change.validate().err().map(|e| (change, e)))
+            {
+                warn!(db_version, "rejecting invalid changes: {e}");
+                report.rejected.push((change.table.clone(), e.to_string()));
+                blocked.insert(site_id);
+                continue;
+            }
+
+            let sp = tx.savepoint()?;
+            let res = changes.iter().try_for_each(|change| {
+                insert_change(&sp, change).map_err(|e| (change.table.clone(), e))
+            });
+
+            match res {
+                Ok(()) => {
+                    sp.commit()?;
+                    marks.insert(site_id, db_version);
+                    report.applied += 1;
+                    report.kinds += ChangeKindCounts::tally(&changes);
+                }
+                Err((table, e)) => {
+                    // dropping the savepoint rolls the version back
+                    warn!(db_version, %table, "could not apply changes: {e}");
+                    report.rejected.push((table, e.to_string()));
+                    blocked.insert(site_id);
+                }
+            }
+        }
+
+        for (site_id, db_version) in marks.iter() {
+            tx.prepare_cached(
+                "INSERT INTO __corro_apply_marks (site_id, db_version) VALUES (?, ?)
+                    ON CONFLICT (site_id) DO UPDATE SET db_version = excluded.db_version",
+            )?
+            .execute(params![site_id, db_version])?;
+        }
+
+        tx.commit()?;
+        debug!(?report, "applied changes");
+
+        self.marks.extend(marks);
+        Ok(report)
+    }
+}
+
+fn insert_change(conn: &Connection, change: &Change) -> rusqlite::Result<()> {
+    conn.prepare_cached(
+        r#"
+            INSERT INTO crsql_changes
+                ("table", pk, cid, val, col_version, db_version, site_id, cl, seq)
+            VALUES
+                (?,       ?,  ?,   ?,   ?,           ?,          ?,       ?,  ?)
+        "#,
+    )?
+    .execute(params![
+        change.table.as_str(),
+        change.pk,
+        change.cid.as_str(),
+        &change.val,
+        change.col_version,
+        change.db_version,
+        &change.site_id,
+        change.cl,
+        change.seq,
+    ])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        api::{
+            sqlite::{change_kind, ChangeKind},
+            ChangeType,
+        },
+        sqlite::CrConn,
+    };
+
+    const SCHEMA: &str = "
+        CREATE TABLE todos (id INTEGER NOT NULL PRIMARY KEY, title TEXT, done INTEGER NOT NULL DEFAULT 0);
+        SELECT crsql_as_crr('todos');
+    ";
+
+    fn conn() -> rusqlite::Result<CrConn> {
+        let conn = CrConn::init(Connection::open_in_memory()?)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(conn)
+    }
+
+    fn changes(conn: &Connection) -> rusqlite::Result<Vec<Change>> {
+        conn.prepare(
+            r#"SELECT "table", pk, cid, val, col_version, db_version, seq, COALESCE(site_id, crsql_site_id()), cl
+                FROM crsql_changes ORDER BY db_version, seq"#,
+        )?
+        .query_map([], row_to_change)?
+        .collect()
+    }
+
+    fn todos(conn: &Connection) -> rusqlite::Result<Vec<(i64, Option<String>, i64)>> {
+        conn.prepare("SELECT id, title, done FROM todos ORDER BY id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect()
+    }
+
+    #[test]
+    fn applies_captured_change_log() -> rusqlite::Result<()> {
+        let source = conn()?;
+        source.execute_batch(
+            "
+            INSERT INTO todos (id, title) VALUES (1, 'write'), (2, 'test'), (3, 'ship');
+            UPDATE todos SET done = 1 WHERE id = 1;
+            DELETE FROM todos WHERE id = 2;
+            INSERT INTO todos (id, title) VALUES (4, 'rest');
+        ",
+        )?;
+        let log = changes(&source)?;
+        let versions: HashSet<i64> = log.iter().map(|c| c.db_version).collect();
+        assert_eq!(versions.len(), 4);
+
+        let mut replica = conn()?;
+        let mut applier = ChangeApplier::new(&mut replica)?;
+
+        // in 2 batches, each out of order
+        let (mut first, mut last): (Vec<_>, Vec<_>) =
+            log.iter().cloned().partition(|c| c.db_version <= 2);
+        first.reverse();
+        last.reverse();
+        let report = applier.apply(first)?;
+        assert_eq!((report.applied, report.rejected.len()), (2, 0));
+        let report = applier.apply(last)?;
+        assert_eq!((report.applied, report.rejected.len()), (2, 0));
+
+        let max_db_version = versions.iter().max().copied();
+        assert_eq!(applier.high_water_mark(&log[0].site_id), max_db_version);
+        assert_eq!(todos(applier.conn())?, todos(&source)?);
+
+        // repeats are skipped, even by a new applier
+        let mut applier = ChangeApplier::new(&mut replica)?;
+        let report = applier.apply(log)?;
+        assert_eq!(report.applied, 0);
+        assert_eq!(report.skipped_known, 4);
+        assert_eq!(report.max_db_version_seen, max_db_version);
+        assert_eq!(todos(applier.conn())?, todos(&source)?);
+
+        Ok(())
+    }
+
+    // kinds of the changes made by the last transaction
+    fn last_kinds(conn: &Connection) -> rusqlite::Result<Vec<ChangeKind>> {
+        let log = changes(conn)?;
+        let last = log.iter().map(|c| c.db_version).max();
+        Ok(log
+            .iter()
+            .filter(|c| Some(c.db_version) == last)
+            .map(change_kind)
+            .collect())
+    }
+
+    #[test]
+    fn classifies_cr_sqlite_changes() -> rusqlite::Result<()> {
+        let conn = conn()?;
+
+        conn.execute("INSERT INTO todos (id, title) VALUES (1, 'write')", [])?;
+        let kinds = last_kinds(&conn)?;
+        assert!(kinds.contains(&ChangeKind::Insert), "{kinds:?}");
+        assert!(
+            kinds
+                .iter()
+                .all(|kind| matches!(kind, ChangeKind::Insert | ChangeKind::PkOnly)),
+            "{kinds:?}"
+        );
+
+        conn.execute("UPDATE todos SET title = 'rewrite' WHERE id = 1", [])?;
+        assert_eq!(last_kinds(&conn)?, [ChangeKind::Update]);
+
+        conn.execute("DELETE FROM todos WHERE id = 1", [])?;
+        assert_eq!(last_kinds(&conn)?, [ChangeKind::Delete]);
+
+        conn.execute("INSERT INTO todos (id, title) VALUES (1, 'again')", [])?;
+        let kinds = last_kinds(&conn)?;
+        assert!(kinds.contains(&ChangeKind::Resurrect), "{kinds:?}");
+        assert!(
+            kinds
+                .iter()
+                .all(|kind| kind.change_type() != ChangeType::Delete),
+            "{kinds:?}"
+        );
+
+        conn.execute("UPDATE todos SET done = 1 WHERE id = 1", [])?;
+        assert_eq!(last_kinds(&conn)?, [ChangeKind::Update]);
+
+        conn.execute_batch(
+            "CREATE TABLE tags (id INTEGER NOT NULL PRIMARY KEY);
+            SELECT crsql_as_crr('tags');
+            INSERT INTO tags (id) VALUES (1);",
+        )?;
+        assert_eq!(last_kinds(&conn)?, [ChangeKind::PkOnly]);
+
+        // the applier counts the kinds it applied
+        let log = changes(&conn)?;
+        let mut replica = conn_with_tags()?;
+        let report = ChangeApplier::new(&mut replica)?.apply(log.clone())?;
+        assert_eq!(report.kinds, ChangeKindCounts::tally(&log));
+        assert_eq!(report.kinds.total(), log.len());
+        assert_eq!(report.kinds.deletes, 1);
+        assert_eq!(report.kinds.pk_only, 1);
+
+        Ok(())
+    }
+
+    fn conn_with_tags() -> rusqlite::Result<CrConn> {
+        let conn = conn()?;
+        conn.execute_batch(
+            "CREATE TABLE tags (id INTEGER NOT NULL PRIMARY KEY);
+            SELECT crsql_as_crr('tags');",
+        )?;
+        Ok(conn)
+    }
+
+    #[test]
+    fn rejects_invalid_versions() -> rusqlite::Result<()> {
+        let source = conn()?;
+        source.execute_batch(
+            "
+            INSERT INTO todos (id, title) VALUES (1, 'a'), (2, 'b');
+            INSERT INTO todos (id, title) VALUES (3, 'c');
+        ",
+        )?;
+        let log = changes(&source)?;
+
+        let mut invalid = log.clone();
+        invalid[0].cl = 0;
+        assert_eq!(invalid[0].validate(), Err(InvalidChange::CausalLength(0)));
+
+        let mut replica = conn()?;
+        let mut applier = ChangeApplier::new(&mut replica)?;
+
+        // the later version isn't applied either, it'd be skipped otherwise
+        let report = applier.apply(invalid)?;
+        assert_eq!(report.applied, 0);
+        assert_eq!(
+            report.rejected,
+            vec![
+                (log[0].table.clone(), "invalid causal length: 0".into()),
+                (log[0].table.clone(), "follows a rejected version".into()),
+            ]
+        );
+        assert_eq!(applier.high_water_mark(&log[0].site_id), None);
+        assert!(todos(applier.conn())?.is_empty());
+
+        // unknown tables only reject their own site's versions
+        let mut unknown = log[0].clone();
+        unknown.table = crate::api::TableName("nope".into());
+        unknown.site_id = SiteId([1; 16]);
+        let mut batch = log.clone();
+        batch.push(unknown);
+        let report = applier.apply(batch)?;
+        assert_eq!((report.applied, report.rejected.len()), (2, 1));
+        assert_eq!(todos(applier.conn())?, todos(&source)?);
+
+        Ok(())
+    }
+}
@@ -1,10 +1,90 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use camino::Utf8PathBuf;
-use serde::{Deserialize, Serialize};
+use corro_api_types::{compress::DEFAULT_COMPRESSION_THRESHOLD, ConfigResponse, DeniedObject};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::sqlite::StatementAccess;
 
 pub const DEFAULT_GOSSIP_PORT: u16 = 4001;
 const DEFAULT_GOSSIP_IDLE_TIMEOUT: u32 = 60;
+const DEFAULT_PROBE_INTERVAL_MS: u64 = 10_000;
+const DEFAULT_PROBE_RETENTION_SECS: u64 = 3600;
+const DEFAULT_JSON_MAX_PARAM_BYTES: usize = 1024 * 1024;
+const DEFAULT_JSON_MAX_TOTAL_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_LARGE_TABLE_ROWS: u64 = 10_000;
+const DEFAULT_CONSUL_MAX_TRACKED_IDS: usize = 10_000;
+const DEFAULT_CONSUL_PULL_INTERVAL_MS: u64 = 1000;
+const DEFAULT_CONSUL_CHURN_THRESHOLD: usize = 10;
+const DEFAULT_CONSUL_CHURN_WINDOW_SECS: u64 = 300;
+const DEFAULT_CONSUL_LOAD_BATCH_SIZE: usize = 10_000;
+const DEFAULT_CONSUL_SCHEMA_GRACE_SECS: u64 = 60;
+const DEFAULT_SINK_MAX_RETRIES: u32 = 5;
+const DEFAULT_SINK_PAUSE_SECS: u64 = 30;
+const DEFAULT_DB_MAX_WAL_BYTES: u64 = 1024 * 1024 * 1024;
+const DEFAULT_DB_MIN_FREE_DISK_BYTES: u64 = 512 * 1024 * 1024;
+const DEFAULT_DB_HEALTH_CHECK_INTERVAL_SECS: u64 = 10;
+const DEFAULT_DB_CHECKPOINT_INTERVAL_SECS: u64 = 60 * 15;
+const DEFAULT_SUB_CHANGES_RETENTION: u64 = 500;
+const DEFAULT_SUB_CHANGES_PURGE_INTERVAL_SECS: u64 = 300;
+const DEFAULT_DB_READ_POOL_SIZE: usize = 20;
+const DEFAULT_API_DRAIN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_API_SLOW_STATEMENT_MS: u64 = 1000;
+const DEFAULT_API_DIGEST_ROWS_PER_SEC: u64 = 100_000;
+const DEFAULT_QUOTA_BURST_SECS: f64 = 1.0;
+
+/// What `Secret` values are serialized as
+pub const REDACTED: &str = "[redacted]";
+
+/// Config value which deserializes as a `T` but always serializes (and
+/// debug prints) as `REDACTED`, so configs can be shown w/o leaking tokens
+/// or keys. Reading it takes an explicit `expose`.
+#[derive(Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&str> for Secret<String> {
+    fn from(value: &str) -> Self {
+        Self(value.to_owned())
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> Serialize for Secret<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -22,6 +102,22 @@ pub struct Config {
     pub log: LogConfig,
     #[serde(default)]
     pub consul: Option<ConsulConfig>,
+    #[serde(default)]
+    pub sinks: Vec<SinkConfig>,
+
+    /// File the config was loaded from, unset when built in-process
+    #[serde(skip)]
+    pub source: Option<ConfigSource>,
+}
+
+/// Where a `Config` was loaded from, to tell whether the file changed since
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigSource {
+    pub path: Utf8PathBuf,
+    /// Unix timestamp in milliseconds
+    pub loaded_at: u64,
+    /// Hex seahash of the file's raw contents
+    pub hash: String,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -65,9 +161,48 @@ pub struct DbConfig {
     pub schema_paths: Vec<Utf8PathBuf>,
     #[serde(default)]
     pub subscriptions_path: Option<Utf8PathBuf>,
+    /// Max estimated size of a single locally generated change, in bytes
+    #[serde(default)]
+    pub max_change_size: Option<usize>,
+    #[serde(default)]
+    pub health: DbHealthConfig,
+    #[serde(default)]
+    pub checkpoint: DbCheckpointConfig,
+    /// How long to retain the history of changes for as-of reads, in
+    /// seconds. History isn't recorded when unset.
+    #[serde(default)]
+    pub history_retention_secs: Option<u64>,
+    /// Tables which are never replicated, by name or glob pattern (e.g.
+    /// `tmp_*`). They're created as regular sqlite tables.
+    #[serde(default)]
+    pub local_only_tables: Vec<String>,
+    /// Tables which transactions may write to w/o replicating their changes,
+    /// by name or glob pattern. None by default.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_replication_tables: Vec<String>,
+    #[serde(default)]
+    pub subscription_changes: SubscriptionChangesConfig,
+    /// Read-only connections queries run on, separate from the single write
+    /// connection
+    #[serde(default = "default_db_read_pool_size")]
+    pub read_pool_size: usize,
 }
 
 impl DbConfig {
+    /// Whether `table` matches one of `local_only_tables`
+    pub fn is_local_only(&self, table: &str) -> bool {
+        self.local_only_tables
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), table.as_bytes()))
+    }
+
+    /// Whether `table` matches one of `no_replication_tables`
+    pub fn allows_no_replication(&self, table: &str) -> bool {
+        self.no_replication_tables
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), table.as_bytes()))
+    }
+
     pub fn subscriptions_db_path(&self) -> Utf8PathBuf {
         self.subscriptions_path
             .as_ref()
@@ -81,6 +216,143 @@ impl DbConfig {
     }
 }
 
+/// Thresholds past which the database's storage is reported as degraded
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DbHealthConfig {
+    /// Max size of the WAL file, in bytes
+    #[serde(default = "default_db_max_wal_bytes")]
+    pub max_wal_bytes: u64,
+    /// Min space left on the database's filesystem, in bytes
+    #[serde(default = "default_db_min_free_disk_bytes")]
+    pub min_free_disk_bytes: u64,
+    #[serde(default = "default_db_health_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+impl Default for DbHealthConfig {
+    fn default() -> Self {
+        Self {
+            max_wal_bytes: default_db_max_wal_bytes(),
+            min_free_disk_bytes: default_db_min_free_disk_bytes(),
+            check_interval_secs: default_db_health_check_interval_secs(),
+        }
+    }
+}
+
+/// When the WAL is checkpointed and truncated, and how long subscriptions
+/// may hold the read snapshots which keep it from being truncated
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DbCheckpointConfig {
+    /// How often the WAL is truncated, in seconds
+    #[serde(default = "default_db_checkpoint_interval_secs")]
+    pub interval_secs: u64,
+    /// Truncates the WAL as soon as it grows over this size, in bytes,
+    /// checked every second
+    #[serde(default)]
+    pub max_wal_bytes: Option<u64>,
+    /// Subscriptions holding a read snapshot for longer than this, in
+    /// seconds, are ended w/ a `snapshot held too long` error
+    #[serde(default)]
+    pub max_snapshot_secs: Option<u64>,
+}
+
+impl Default for DbCheckpointConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_db_checkpoint_interval_secs(),
+            max_wal_bytes: None,
+            max_snapshot_secs: None,
+        }
+    }
+}
+
+/// How many of its changes each subscription keeps for subscribers resuming
+/// from a past change id. Resuming from a purged change gets a `ResumeGap`.
+/// Also how idle subscriptions are paused.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SubscriptionChangesConfig {
+    /// Most recent changes kept, at least 1
+    #[serde(default = "default_sub_changes_retention")]
+    pub retention: u64,
+    /// How often older changes are purged, in seconds
+    #[serde(default = "default_sub_changes_purge_interval_secs")]
+    pub purge_interval_secs: u64,
+    /// Subscriptions w/o matching changes nor subscribers for this long, in
+    /// seconds, stop being evaluated until one of their tables changes
+    /// again. They're never paused if unset.
+    #[serde(default)]
+    pub idle_pause_secs: Option<u64>,
+}
+
+impl Default for SubscriptionChangesConfig {
+    fn default() -> Self {
+        Self {
+            retention: default_sub_changes_retention(),
+            purge_interval_secs: default_sub_changes_purge_interval_secs(),
+            idle_pause_secs: None,
+        }
+    }
+}
+
+fn default_db_read_pool_size() -> usize {
+    DEFAULT_DB_READ_POOL_SIZE
+}
+
+fn default_sub_changes_retention() -> u64 {
+    DEFAULT_SUB_CHANGES_RETENTION
+}
+
+fn default_sub_changes_purge_interval_secs() -> u64 {
+    DEFAULT_SUB_CHANGES_PURGE_INTERVAL_SECS
+}
+
+// matches `*` (any run of characters) and `?` (any single character),
+// ignoring ASCII case like sqlite does for table names
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and what it matched up to
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == b'?' || c.eq_ignore_ascii_case(&name[n]) => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn default_db_max_wal_bytes() -> u64 {
+    DEFAULT_DB_MAX_WAL_BYTES
+}
+
+fn default_db_min_free_disk_bytes() -> u64 {
+    DEFAULT_DB_MIN_FREE_DISK_BYTES
+}
+
+fn default_db_health_check_interval_secs() -> u64 {
+    DEFAULT_DB_HEALTH_CHECK_INTERVAL_SECS
+}
+
+fn default_db_checkpoint_interval_secs() -> u64 {
+    DEFAULT_DB_CHECKPOINT_INTERVAL_SECS
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ApiConfig {
     #[serde(alias = "addr")]
@@ -89,6 +361,167 @@ pub struct ApiConfig {
     pub authorization: Option<AuthzConfig>,
     #[serde(default)]
     pub pg: Option<PgConfig>,
+    #[serde(default)]
+    pub json_limits: JsonLimitsConfig,
+    #[serde(default)]
+    pub query_plan: QueryPlanConfig,
+    /// Tokens restricted to reading some tables, on top of `authorization`'s
+    /// token which can do everything
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<AccessPolicy>,
+    /// How long in-flight requests get to finish on shutdown, before open
+    /// queries are ended and connections closed
+    #[serde(default = "default_api_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+    /// Statements of a transaction taking at least this long are logged as
+    /// warnings, w/ the start of their query
+    #[serde(default = "default_api_slow_statement_ms")]
+    pub slow_statement_ms: u64,
+    /// Per-client limits on `/v1/transactions` requests, unlimited if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quotas: Option<QuotaConfig>,
+    /// Rows each `/v1/digests` request hashes per second at most, so
+    /// consistency checks don't starve the node
+    #[serde(default = "default_api_digest_rows_per_sec")]
+    pub digest_rows_per_sec: u64,
+    /// Max size of a `/v1/transactions` request body, in bytes, unlimited if
+    /// unset. Bodies are counted as they're received, statement arrays
+    /// executed while streaming in included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_bytes: Option<usize>,
+}
+
+impl ApiConfig {
+    pub fn policy(&self, token: &str) -> Option<&AccessPolicy> {
+        self.policies
+            .iter()
+            .find(|policy| policy.token.expose() == token)
+    }
+}
+
+/// Limits on the write volume of each client of the transactions API,
+/// identified by its bearer token or else its address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    /// Limits of clients w/o an override
+    #[serde(flatten)]
+    pub limits: QuotaLimits,
+    /// Seconds of each limit a client can use in a single burst
+    #[serde(default = "default_quota_burst_secs")]
+    pub burst_secs: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<QuotaOverride>,
+}
+
+/// Rates a client is limited to, unset ones are unlimited
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaLimits {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requests_per_sec: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statements_per_sec: Option<f64>,
+    /// Bytes of changes generated by the client's transactions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_bytes_per_sec: Option<f64>,
+}
+
+/// Limits of the client bearing `token`, or else connecting from `ip`. An
+/// override w/o limits exempts the client, e.g. the consul sync.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuotaOverride {
+    /// Listed next to the client's quota usage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Secret<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(flatten)]
+    pub limits: QuotaLimits,
+}
+
+fn default_quota_burst_secs() -> f64 {
+    DEFAULT_QUOTA_BURST_SECS
+}
+
+/// Limits on `SqliteParam::Json` params accepted by the transactions API
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct JsonLimitsConfig {
+    /// Max size of a single JSON param, in bytes
+    #[serde(default = "default_json_max_param_bytes")]
+    pub max_param_bytes: usize,
+    /// Max combined size of all JSON params in a request, in bytes
+    #[serde(default = "default_json_max_total_bytes")]
+    pub max_total_bytes: usize,
+    /// Max nesting depth of a JSON param, unchecked if unset
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+}
+
+impl Default for JsonLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_param_bytes: default_json_max_param_bytes(),
+            max_total_bytes: default_json_max_total_bytes(),
+            max_depth: None,
+        }
+    }
+}
+
+fn default_api_drain_timeout_secs() -> u64 {
+    DEFAULT_API_DRAIN_TIMEOUT_SECS
+}
+
+fn default_api_slow_statement_ms() -> u64 {
+    DEFAULT_API_SLOW_STATEMENT_MS
+}
+
+fn default_api_digest_rows_per_sec() -> u64 {
+    DEFAULT_API_DIGEST_ROWS_PER_SEC
+}
+
+fn default_json_max_param_bytes() -> usize {
+    DEFAULT_JSON_MAX_PARAM_BYTES
+}
+
+fn default_json_max_total_bytes() -> usize {
+    DEFAULT_JSON_MAX_TOTAL_BYTES
+}
+
+/// Checks run against the query plan of statements
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct QueryPlanConfig {
+    /// Full scans of tables with at least this many rows are flagged
+    #[serde(default = "default_large_table_rows")]
+    pub large_table_rows: u64,
+    /// What to do when a new subscription's query scans a large table
+    #[serde(default)]
+    pub subscription_scans: ScanPolicy,
+}
+
+impl Default for QueryPlanConfig {
+    fn default() -> Self {
+        Self {
+            large_table_rows: default_large_table_rows(),
+            subscription_scans: ScanPolicy::default(),
+        }
+    }
+}
+
+fn default_large_table_rows() -> u64 {
+    DEFAULT_LARGE_TABLE_ROWS
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanPolicy {
+    /// Don't check the query plan
+    #[default]
+    Ignore,
+    /// Log a warning
+    Warn,
+    /// Refuse to create the subscription
+    Reject,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -97,11 +530,59 @@ pub struct PgConfig {
     pub bind_addr: SocketAddr,
 }
 
+/// Read-only access granted to requests bearing `token`, checked against
+/// the tables and columns a query or subscription reads
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessPolicy {
+    pub token: Secret<String>,
+    /// Tables readable in full
+    #[serde(default)]
+    pub tables: Vec<String>,
+    /// Tables only readable through the listed columns
+    #[serde(default)]
+    pub columns: HashMap<String, Vec<String>>,
+}
+
+impl AccessPolicy {
+    /// First object `access` touches that this policy doesn't allow, in the
+    /// order sqlite reported them
+    pub fn first_denied(&self, access: &StatementAccess) -> Option<DeniedObject> {
+        if let Some(action) = access.actions.first() {
+            return Some(DeniedObject::Action {
+                action: action.clone(),
+            });
+        }
+
+        access.reads.iter().find_map(|(table, column)| {
+            if self.tables.iter().any(|t| t.eq_ignore_ascii_case(table)) {
+                return None;
+            }
+            let columns = self
+                .columns
+                .iter()
+                .find(|(t, _)| t.eq_ignore_ascii_case(table))
+                .map(|(_, columns)| columns);
+            match columns {
+                None => Some(DeniedObject::Table {
+                    table: table.clone(),
+                }),
+                // the table is read w/o any of its columns, e.g. `count(*)`
+                Some(_) if column.is_empty() => None,
+                Some(columns) if columns.iter().any(|c| c.eq_ignore_ascii_case(column)) => None,
+                Some(_) => Some(DeniedObject::Column {
+                    table: table.clone(),
+                    column: column.clone(),
+                }),
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum AuthzConfig {
     #[serde(alias = "bearer")]
-    BearerToken(String),
+    BearerToken(Secret<String>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -120,18 +601,92 @@ pub struct GossipConfig {
     pub idle_timeout_secs: u32,
     #[serde(default)]
     pub disable_gso: bool,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    /// Asks peers to checksum changesets they sync to this node
+    #[serde(default)]
+    pub checksums: bool,
+    /// Measures how long changes take to propagate, when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probe: Option<ProbeConfig>,
+    /// Name this node is known by in the cluster, e.g. in the origin of
+    /// subscriptions' changes. Unnamed nodes' changes have no name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
 }
 
 fn default_gossip_idle_timeout() -> u32 {
     DEFAULT_GOSSIP_IDLE_TIMEOUT
 }
 
+/// Compression of large change values synced to peers. Values are only
+/// compressed for peers which announce they accept it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// `Text` and `Blob` values at least this large are compressed, in bytes
+    #[serde(default = "default_compression_threshold_bytes")]
+    pub threshold_bytes: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_bytes: default_compression_threshold_bytes(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Threshold to compress values above, if enabled
+    pub fn threshold(&self) -> Option<usize> {
+        self.enabled.then_some(self.threshold_bytes)
+    }
+}
+
+fn default_compression_threshold_bytes() -> usize {
+    DEFAULT_COMPRESSION_THRESHOLD
+}
+
+/// Each node writes a heartbeat to the replicated `__corro_probe` table, the
+/// others record how long after its origin timestamp they applied it. Only
+/// as accurate as the nodes' clocks are in sync.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ProbeConfig {
+    /// How often heartbeats are written, in milliseconds
+    #[serde(default = "default_probe_interval_ms")]
+    pub interval_ms: u64,
+    /// Heartbeats of nodes which haven't written one for this long are
+    /// deleted, they're likely gone for good
+    #[serde(default = "default_probe_retention_secs")]
+    pub retention_secs: u64,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: default_probe_interval_ms(),
+            retention_secs: default_probe_retention_secs(),
+        }
+    }
+}
+
+fn default_probe_interval_ms() -> u64 {
+    DEFAULT_PROBE_INTERVAL_MS
+}
+
+fn default_probe_retention_secs() -> u64 {
+    DEFAULT_PROBE_RETENTION_SECS
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TlsConfig {
     /// Certificate file
     pub cert_file: Utf8PathBuf,
     /// Private key file
-    pub key_file: Utf8PathBuf,
+    pub key_file: Secret<Utf8PathBuf>,
 
     /// CA (Certificate Authority) file
     #[serde(default)]
@@ -150,7 +705,7 @@ pub struct TlsClientConfig {
     /// Certificate file
     pub cert_file: Utf8PathBuf,
     /// Private key file
-    pub key_file: Utf8PathBuf,
+    pub key_file: Secret<Utf8PathBuf>,
 }
 
 pub fn default_admin_path() -> Utf8PathBuf {
@@ -163,16 +718,69 @@ pub struct LogConfig {
     pub format: LogFormat,
     #[serde(default = "default_as_true")]
     pub colors: bool,
+    /// Logs the statements of every `/v1/transactions` request, when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<AuditLogConfig>,
 }
 
 fn default_as_true() -> bool {
     true
 }
 
+pub const DEFAULT_AUDIT_MAX_VALUE_LEN: usize = 256;
+
+/// Statements are logged to the `corro::audit` target, w/ their params
+/// rendered as SQL literals
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AuditLogConfig {
+    /// Longer param values are truncated, in chars (bytes for blobs)
+    #[serde(default = "default_audit_max_value_len")]
+    pub max_value_len: usize,
+    /// Columns whose params are masked, as `table.column` or `column` (in
+    /// any table), w/ `*` and `?` wildcards
+    #[serde(default)]
+    pub sensitive_columns: Vec<String>,
+}
+
+impl Default for AuditLogConfig {
+    fn default() -> Self {
+        Self {
+            max_value_len: DEFAULT_AUDIT_MAX_VALUE_LEN,
+            sensitive_columns: vec![],
+        }
+    }
+}
+
+impl AuditLogConfig {
+    /// Whether `column` of `table` matches one of `sensitive_columns`. Only
+    /// patterns w/o a table match when the table isn't known.
+    pub fn is_sensitive(&self, table: Option<&str>, column: &str) -> bool {
+        self.sensitive_columns
+            .iter()
+            .any(|pattern| match (pattern.split_once('.'), table) {
+                (Some((table_pattern, column_pattern)), Some(table)) => {
+                    glob_match(table_pattern.as_bytes(), table.as_bytes())
+                        && glob_match(column_pattern.as_bytes(), column.as_bytes())
+                }
+                (Some(_), None) => false,
+                (None, _) => glob_match(pattern.as_bytes(), column.as_bytes()),
+            })
+    }
+}
+
+fn default_audit_max_value_len() -> usize {
+    DEFAULT_AUDIT_MAX_VALUE_LEN
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum ConfigError {
     #[error(transparent)]
     Config(#[from] config::ConfigError),
+    #[error("could not read config file {path}: {source}")]
+    Read {
+        path: Utf8PathBuf,
+        source: std::io::Error,
+    },
 }
 
 impl Config {
@@ -183,11 +791,36 @@ impl Config {
     /// Reads configuration from a TOML file, given its path. Environment
     /// variables can override whatever is set in the config file.
     pub fn load(config_path: &str) -> Result<Self, ConfigError> {
+        // parsed from what's hashed, so the hash matches what was loaded
+        let raw = std::fs::read_to_string(config_path).map_err(|source| ConfigError::Read {
+            path: config_path.into(),
+            source,
+        })?;
         let config = config::Config::builder()
-            .add_source(config::File::new(config_path, config::FileFormat::Toml))
+            .add_source(config::File::from_str(&raw, config::FileFormat::Toml))
             .add_source(config::Environment::default().separator("__"))
             .build()?;
-        Ok(config.try_deserialize()?)
+
+        let mut config: Config = config.try_deserialize()?;
+        config.source = Some(ConfigSource {
+            path: config_path.into(),
+            loaded_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            hash: format!("{:016x}", seahash::hash(raw.as_bytes())),
+        });
+        Ok(config)
+    }
+
+    /// The effective config, defaults and environment overrides included,
+    /// w/ `Secret`s redacted
+    pub fn to_response(&self) -> Result<ConfigResponse, serde_json::Error> {
+        Ok(ConfigResponse {
+            path: self.source.as_ref().map(|source| source.path.to_string()),
+            loaded_at: self.source.as_ref().map(|source| source.loaded_at),
+            hash: self.source.as_ref().map(|source| source.hash.clone()),
+            config: serde_json::to_value(self)?,
+        })
     }
 }
 
@@ -201,9 +834,23 @@ pub struct ConfigBuilder {
     bootstrap: Option<Vec<String>>,
     log: Option<LogConfig>,
     schema_paths: Vec<Utf8PathBuf>,
-    max_change_size: Option<i64>,
+    max_change_size: Option<usize>,
+    db_health: Option<DbHealthConfig>,
+    db_checkpoint: Option<DbCheckpointConfig>,
+    history_retention_secs: Option<u64>,
+    local_only_tables: Vec<String>,
+    no_replication_tables: Vec<String>,
+    subscription_changes: Option<SubscriptionChangesConfig>,
+    read_pool_size: Option<usize>,
+    authorization: Option<AuthzConfig>,
+    policies: Vec<AccessPolicy>,
+    quotas: Option<QuotaConfig>,
     consul: Option<ConsulConfig>,
     tls: Option<TlsConfig>,
+    compression: Option<CompressionConfig>,
+    checksums: bool,
+    probe: Option<ProbeConfig>,
+    node_name: Option<String>,
 }
 
 impl ConfigBuilder {
@@ -247,11 +894,61 @@ impl ConfigBuilder {
         self
     }
 
-    pub fn max_change_size(mut self, size: i64) -> Self {
+    pub fn max_change_size(mut self, size: usize) -> Self {
         self.max_change_size = Some(size);
         self
     }
 
+    pub fn db_health(mut self, config: DbHealthConfig) -> Self {
+        self.db_health = Some(config);
+        self
+    }
+
+    pub fn db_checkpoint(mut self, config: DbCheckpointConfig) -> Self {
+        self.db_checkpoint = Some(config);
+        self
+    }
+
+    pub fn history_retention_secs(mut self, secs: u64) -> Self {
+        self.history_retention_secs = Some(secs);
+        self
+    }
+
+    pub fn add_local_only_table<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.local_only_tables.push(pattern.into());
+        self
+    }
+
+    pub fn add_no_replication_table<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.no_replication_tables.push(pattern.into());
+        self
+    }
+
+    pub fn subscription_changes(mut self, config: SubscriptionChangesConfig) -> Self {
+        self.subscription_changes = Some(config);
+        self
+    }
+
+    pub fn read_pool_size(mut self, size: usize) -> Self {
+        self.read_pool_size = Some(size);
+        self
+    }
+
+    pub fn api_authorization<S: Into<String>>(mut self, token: S) -> Self {
+        self.authorization = Some(AuthzConfig::BearerToken(Secret::new(token.into())));
+        self
+    }
+
+    pub fn add_access_policy(mut self, policy: AccessPolicy) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    pub fn quotas(mut self, config: QuotaConfig) -> Self {
+        self.quotas = Some(config);
+        self
+    }
+
     pub fn consul(mut self, config: ConsulConfig) -> Self {
         self.consul = Some(config);
         self
@@ -262,6 +959,26 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.compression = Some(config);
+        self
+    }
+
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    pub fn probe(mut self, config: ProbeConfig) -> Self {
+        self.probe = Some(config);
+        self
+    }
+
+    pub fn node_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.node_name = Some(name.into());
+        self
+    }
+
     pub fn build(self) -> Result<Config, ConfigBuilderError> {
         let db_path = self.db_path.ok_or(ConfigBuilderError::DbPathRequired)?;
 
@@ -277,11 +994,27 @@ impl ConfigBuilder {
                 path: db_path,
                 schema_paths: self.schema_paths,
                 subscriptions_path: None,
+                max_change_size: self.max_change_size,
+                health: self.db_health.unwrap_or_default(),
+                checkpoint: self.db_checkpoint.unwrap_or_default(),
+                history_retention_secs: self.history_retention_secs,
+                local_only_tables: self.local_only_tables,
+                no_replication_tables: self.no_replication_tables,
+                subscription_changes: self.subscription_changes.unwrap_or_default(),
+                read_pool_size: self.read_pool_size.unwrap_or(DEFAULT_DB_READ_POOL_SIZE),
             },
             api: ApiConfig {
                 bind_addr: self.api_addr.ok_or(ConfigBuilderError::ApiAddrRequired)?,
-                authorization: None,
+                authorization: self.authorization,
                 pg: None,
+                json_limits: Default::default(),
+                query_plan: Default::default(),
+                policies: self.policies,
+                drain_timeout_secs: default_api_drain_timeout_secs(),
+                slow_statement_ms: default_api_slow_statement_ms(),
+                quotas: self.quotas,
+                digest_rows_per_sec: default_api_digest_rows_per_sec(),
+                max_body_bytes: None,
             },
             gossip: GossipConfig {
                 bind_addr: self
@@ -293,6 +1026,10 @@ impl ConfigBuilder {
                 idle_timeout_secs: default_gossip_idle_timeout(),
                 max_mtu: None, // TODO: add a builder function for it
                 disable_gso: false,
+                compression: self.compression.unwrap_or_default(),
+                checksums: self.checksums,
+                probe: self.probe,
+                node_name: self.node_name,
             },
             admin: AdminConfig {
                 uds_path: self.admin_path.unwrap_or_else(default_admin_path),
@@ -301,6 +1038,8 @@ impl ConfigBuilder {
             log: self.log.unwrap_or_default(),
 
             consul: self.consul,
+            sinks: vec![],
+            source: None,
         })
     }
 }
@@ -325,8 +1064,404 @@ pub enum LogFormat {
     Json,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct ConsulConfig {
+    #[serde(serialize_with = "serialize_consul_client")]
     pub client: consul_client::Config,
+    /// Name stored in the `node` column of synced rows, the consul agent's
+    /// own node name if unset
+    #[serde(default)]
+    pub node_name: Option<String>,
+    /// Stored in the `datacenter` column of synced rows when the consul
+    /// tables have one, the consul agent's own datacenter if unset
+    #[serde(default)]
+    pub datacenter: Option<String>,
+    /// Rules applied in order to each service before it's hashed and stored
+    #[serde(default)]
+    pub rewrites: Vec<ServiceRewrite>,
+    /// Services (or checks) tracked past which a warning is logged, a sign of
+    /// runaway id churn
+    #[serde(default = "default_consul_max_tracked_ids")]
+    pub max_tracked_ids: usize,
+    /// Only services w/ these names (and their checks) are synced, all of
+    /// them if empty
+    #[serde(default)]
+    pub services: Vec<String>,
+    /// How often the local consul agent is polled, in milliseconds
+    #[serde(default = "default_consul_pull_interval_ms")]
+    pub pull_interval_ms: u64,
+    /// Number of ids upserted most often named in churn warnings, churn
+    /// isn't tracked when 0
+    #[serde(default)]
+    pub log_churners: usize,
+    /// Upserts of a single id within `churn-window-secs` past which it's
+    /// reported as churning
+    #[serde(default = "default_consul_churn_threshold")]
+    pub churn_threshold: usize,
+    #[serde(default = "default_consul_churn_window_secs")]
+    pub churn_window_secs: u64,
+    /// Whether node checks, which aren't bound to a service, count toward
+    /// the status of every service when consul_services has a `status`
+    /// column
+    #[serde(default)]
+    pub node_checks_affect_services: bool,
+    /// Services w/o any check get a synthetic passing check named
+    /// `registered`, w/ id `svc:<service id>:synthetic`, so every service
+    /// has a row in consul_checks. Flagged in consul_checks' `synthetic`
+    /// column when it has one.
+    #[serde(default)]
+    pub synthesize_missing_checks: bool,
+    /// Extra TEXT columns of both consul_services and consul_checks set to
+    /// the same value in every row this node writes, e.g. its region
+    #[serde(default, skip_serializing_if = "StaticColumns::is_empty")]
+    pub static_columns: StaticColumns,
+    /// Check outputs longer than this many bytes are stored truncated, w/ a
+    /// marker at the end. Changes past the limit are still synced.
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+    /// Bookkeeping rows read per query when loading the stored hashes at
+    /// startup
+    #[serde(default = "default_consul_load_batch_size")]
+    pub load_batch_size: usize,
+    /// How long the sync waits at startup for the consul tables to exist
+    /// and match what it expects, the agent may still be applying its schema
+    /// files
+    #[serde(default = "default_consul_schema_grace_secs")]
+    pub schema_grace_secs: u64,
+    /// How service and check ids are normalized before they're hashed and
+    /// stored, they're stored exactly as consul reports them by default
+    #[serde(default, skip_serializing_if = "IdNormalization::is_disabled")]
+    pub normalize_ids: IdNormalization,
+}
+
+/// Normalization of consul service and check ids, both off by default. Case
+/// is always preserved: ids only differing by case are distinct, but logged
+/// as conflicts.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct IdNormalization {
+    /// Strips leading and trailing whitespace
+    #[serde(default)]
+    pub trim: bool,
+    /// Composes ids to Unicode Normalization Form C, so an id typed w/
+    /// precomposed or combining characters is the same id
+    #[serde(default)]
+    pub nfc: bool,
+}
+
+impl IdNormalization {
+    pub fn is_disabled(&self) -> bool {
+        !self.trim && !self.nfc
+    }
+}
+
+/// Columns the consul sync writes itself, in either table
+pub const CONSUL_BUILTIN_COLUMNS: &[&str] = &[
+    "node",
+    "datacenter",
+    "id",
+    "name",
+    "tags",
+    "meta",
+    "port",
+    "address",
+    "updated_at",
+    "service_id",
+    "service_name",
+    "status",
+    "output",
+    "synthetic",
+];
+
+/// Column names and their values, by name. Names are plain identifiers which
+/// don't collide w/ `CONSUL_BUILTIN_COLUMNS`, nor each other, ignoring case.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    try_from = "BTreeMap<String, String>",
+    into = "BTreeMap<String, String>"
+)]
+pub struct StaticColumns(BTreeMap<String, String>);
+
+impl StaticColumns {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Names and values, ordered by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StaticColumnError {
+    #[error("static column '{0}' isn't a plain identifier")]
+    InvalidName(String),
+    #[error("static column '{0}' is already written by the consul sync")]
+    Builtin(String),
+    #[error("static columns '{0}' and '{1}' are the same column")]
+    Duplicate(String, String),
+}
+
+impl TryFrom<BTreeMap<String, String>> for StaticColumns {
+    type Error = StaticColumnError;
+
+    fn try_from(columns: BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        let mut names: Vec<&String> = vec![];
+        for name in columns.keys() {
+            let mut chars = name.chars();
+            let valid = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                return Err(StaticColumnError::InvalidName(name.clone()));
+            }
+            if CONSUL_BUILTIN_COLUMNS
+                .iter()
+                .any(|builtin| builtin.eq_ignore_ascii_case(name))
+            {
+                return Err(StaticColumnError::Builtin(name.clone()));
+            }
+            // sqlite column names are case-insensitive
+            if let Some(other) = names.iter().find(|other| other.eq_ignore_ascii_case(name)) {
+                return Err(StaticColumnError::Duplicate((*other).clone(), name.clone()));
+            }
+            names.push(name);
+        }
+        Ok(Self(columns))
+    }
+}
+
+impl From<StaticColumns> for BTreeMap<String, String> {
+    fn from(columns: StaticColumns) -> Self {
+        columns.0
+    }
+}
+
+// `consul_client::Config` w/ its TLS key redacted, that crate doesn't know
+// about `Secret`
+fn serialize_consul_client<S: Serializer>(
+    client: &consul_client::Config,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Tls<'a> {
+        ca_file: &'a Utf8PathBuf,
+        cert_file: &'a Utf8PathBuf,
+        key_file: Secret<&'a Utf8PathBuf>,
+    }
+
+    #[derive(Serialize)]
+    struct Client<'a> {
+        address: &'a str,
+        tls: Option<Tls<'a>>,
+    }
+
+    Client {
+        address: &client.address,
+        tls: client.tls.as_ref().map(|tls| Tls {
+            ca_file: &tls.ca_file,
+            cert_file: &tls.cert_file,
+            key_file: Secret(&tls.key_file),
+        }),
+    }
+    .serialize(serializer)
+}
+
+fn default_consul_max_tracked_ids() -> usize {
+    DEFAULT_CONSUL_MAX_TRACKED_IDS
+}
+
+fn default_consul_pull_interval_ms() -> u64 {
+    DEFAULT_CONSUL_PULL_INTERVAL_MS
+}
+
+fn default_consul_churn_threshold() -> usize {
+    DEFAULT_CONSUL_CHURN_THRESHOLD
+}
+
+fn default_consul_churn_window_secs() -> u64 {
+    DEFAULT_CONSUL_CHURN_WINDOW_SECS
+}
+
+fn default_consul_load_batch_size() -> usize {
+    DEFAULT_CONSUL_LOAD_BATCH_SIZE
+}
+
+fn default_consul_schema_grace_secs() -> u64 {
+    DEFAULT_CONSUL_SCHEMA_GRACE_SECS
+}
+
+/// Exports the changes of a subscription query to an external system
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SinkConfig {
+    /// Unique name, the sink's progress is persisted under it
+    pub name: String,
+    /// Subscription query whose changes are exported
+    pub query: String,
+    /// Table reported in exported events
+    pub table: String,
+    pub target: SinkTarget,
+    /// Retries delivering a single change before the sink is paused
+    #[serde(default = "default_sink_max_retries")]
+    pub max_retries: u32,
+    /// How long a paused sink waits before resuming, in seconds
+    #[serde(default = "default_sink_pause_secs")]
+    pub pause_secs: u64,
+}
+
+fn default_sink_max_retries() -> u32 {
+    DEFAULT_SINK_MAX_RETRIES
+}
+
+fn default_sink_pause_secs() -> u64 {
+    DEFAULT_SINK_PAUSE_SECS
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkTarget {
+    /// POSTs each change as JSON to an http:// URL
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, Secret<String>>,
+    },
+    /// Publishes each change as JSON to a NATS subject, requires the `nats`
+    /// feature
+    Nats { url: String, subject: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceRewrite {
+    /// Replaces the address w/ the value of a meta key, if present
+    RewriteAddress { from_meta: String },
+    /// Adds an offset to the port, if the result is a valid port
+    PortOffset(i32),
+    /// Regex replacement on the address, `replacement` can reference
+    /// capture groups (e.g. `$1`)
+    AddressReplace {
+        pattern: String,
+        replacement: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_local_only_tables() {
+        let conf = Config::builder()
+            .db_path("/tmp/corrosion.db")
+            .gossip_addr("127.0.0.1:0".parse().unwrap())
+            .api_addr("127.0.0.1:0".parse().unwrap())
+            .add_local_only_table("tmp_*")
+            .add_local_only_table("node_cache")
+            .add_local_only_table("q?_jobs")
+            .build()
+            .unwrap();
+
+        for table in [
+            "tmp_",
+            "tmp_scratch",
+            "TMP_Scratch",
+            "node_cache",
+            "q1_jobs",
+        ] {
+            assert!(conf.db.is_local_only(table), "{table}");
+        }
+        for table in ["tmp", "scratch_tmp_", "node_cache2", "q12_jobs", "tests"] {
+            assert!(!conf.db.is_local_only(table), "{table}");
+        }
+    }
+
+    #[test]
+    fn matches_sensitive_columns() {
+        let audit = AuditLogConfig {
+            sensitive_columns: vec!["users.password".into(), "*token".into()],
+            ..Default::default()
+        };
+
+        assert!(audit.is_sensitive(Some("users"), "password"));
+        assert!(audit.is_sensitive(Some("Users"), "PASSWORD"));
+        assert!(audit.is_sensitive(Some("sessions"), "refresh_token"));
+        assert!(audit.is_sensitive(None, "token"));
+        assert!(!audit.is_sensitive(None, "password"));
+        assert!(!audit.is_sensitive(Some("accounts"), "password"));
+    }
+
+    #[test]
+    fn access_policy_denies_first_disallowed_object() {
+        let policy = AccessPolicy {
+            token: "reader".into(),
+            tables: vec!["services".into()],
+            columns: HashMap::from([("checks".into(), vec!["id".into(), "status".into()])]),
+        };
+        let reads = |reads: &[(&str, &str)]| StatementAccess {
+            reads: reads
+                .iter()
+                .map(|(table, column)| (table.to_string(), column.to_string()))
+                .collect(),
+            writes: vec![],
+            actions: vec![],
+        };
+
+        assert_eq!(
+            policy.first_denied(&reads(&[
+                ("services", "name"),
+                ("CHECKS", "Status"),
+                ("checks", "")
+            ])),
+            None
+        );
+        assert_eq!(
+            policy.first_denied(&reads(&[("services", "name"), ("checks", "output")])),
+            Some(DeniedObject::Column {
+                table: "checks".into(),
+                column: "output".into()
+            })
+        );
+        assert_eq!(
+            policy.first_denied(&reads(&[("nodes", "id"), ("checks", "output")])),
+            Some(DeniedObject::Table {
+                table: "nodes".into()
+            })
+        );
+
+        let mut access = reads(&[("services", "name")]);
+        access.actions.push("Pragma".into());
+        assert!(matches!(
+            policy.first_denied(&access),
+            Some(DeniedObject::Action { .. })
+        ));
+    }
+
+    #[test]
+    fn validates_static_columns() {
+        let columns = |json: serde_json::Value| serde_json::from_value::<StaticColumns>(json);
+
+        let valid = columns(serde_json::json!({"region": "us-east", "_env2": "prod"})).unwrap();
+        assert_eq!(
+            valid.iter().collect::<Vec<_>>(),
+            vec![("_env2", "prod"), ("region", "us-east")]
+        );
+
+        for invalid in [
+            serde_json::json!({"Node": "a"}),
+            serde_json::json!({"updated_at": "0"}),
+            serde_json::json!({"service_name": "web"}),
+            serde_json::json!({"region": "a", "REGION": "b"}),
+            serde_json::json!({"2fast": "a"}),
+            serde_json::json!({"region\"; DROP TABLE x; --": "a"}),
+            serde_json::json!({"": "a"}),
+        ] {
+            assert!(columns(invalid.clone()).is_err(), "{invalid}");
+        }
+    }
 }
@@ -0,0 +1,271 @@
+//! `/v1/transactions` requests in progress, listed w/
+//! `GET /v1/transactions/active` and killed w/
+//! `POST /v1/transactions/active/{id}/kill`.
+//!
+//! Killing a transaction interrupts the statement it's running on the write
+//! connection, the transaction then rolls back instead of committing.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::{Mutex, RwLock};
+use rusqlite::{Connection, InterruptHandle};
+use uuid::Uuid;
+
+use crate::{agent::ChangeError, api::ActiveTransaction, quota::ClientIdentity};
+
+/// Listed SQL is cut to this many chars
+pub const SQL_PREFIX_LEN: usize = 128;
+
+/// Who a transaction is running for
+#[derive(Debug, Clone, Default)]
+pub struct ExecOrigin {
+    pub client_addr: Option<SocketAddr>,
+    pub idempotency_key: Option<String>,
+    /// Tags the transaction's changes for subscribers skipping their own
+    pub source_id: Option<Uuid>,
+    /// Charged for the changes the transaction generates, when quotas apply
+    pub quota: Option<ClientIdentity>,
+    /// Credited w/ the statements and changes the transaction commits
+    pub usage: Option<ClientIdentity>,
+}
+
+type Active = Arc<RwLock<BTreeMap<u64, Arc<ActiveExec>>>>;
+
+#[derive(Debug, Default)]
+pub struct ExecRegistry {
+    next_id: AtomicU64,
+    active: Active,
+}
+
+struct ActiveExec {
+    started_at: SystemTime,
+    started: Instant,
+    // grows as they're received, for streamed requests
+    statements: AtomicUsize,
+    origin: ExecOrigin,
+    current: Mutex<Option<(usize, String)>>,
+    killed: AtomicBool,
+    // set while the transaction runs on the write conn
+    interrupt: Mutex<Option<InterruptHandle>>,
+}
+
+impl std::fmt::Debug for ActiveExec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActiveExec")
+            .field("started_at", &self.started_at)
+            .field("statements", &self.statements)
+            .field("origin", &self.origin)
+            .field("current", &self.current)
+            .field("killed", &self.killed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ExecRegistry {
+    /// Lists a transaction of `statements` statements until the returned
+    /// tracker is dropped. Streamed requests start at 0, they're counted as
+    /// they run.
+    pub fn register(&self, origin: ExecOrigin, statements: usize) -> ExecTracker {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let exec = Arc::new(ActiveExec {
+            started_at: SystemTime::now(),
+            started: Instant::now(),
+            statements: AtomicUsize::new(statements),
+            origin,
+            current: Mutex::new(None),
+            killed: AtomicBool::new(false),
+            interrupt: Mutex::new(None),
+        });
+        self.active.write().insert(id, exec.clone());
+
+        ExecTracker {
+            id,
+            exec,
+            active: self.active.clone(),
+        }
+    }
+
+    /// Active transactions, oldest first
+    pub fn list(&self) -> Vec<ActiveTransaction> {
+        self.active
+            .read()
+            .iter()
+            .map(|(id, exec)| exec.describe(*id))
+            .collect()
+    }
+
+    /// Interrupts transaction `id`'s current statement and has it roll back
+    /// instead of committing. Returns `None` if it isn't active.
+    pub fn kill(&self, id: u64) -> Option<ActiveTransaction> {
+        let exec = self.active.read().get(&id).cloned()?;
+
+        exec.killed.store(true, Ordering::SeqCst);
+        if let Some(handle) = exec.interrupt.lock().as_ref() {
+            handle.interrupt();
+        }
+
+        Some(exec.describe(id))
+    }
+}
+
+impl ActiveExec {
+    fn describe(&self, id: u64) -> ActiveTransaction {
+        let current = self.current.lock().clone();
+        ActiveTransaction {
+            id,
+            started_at: self
+                .started_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            elapsed: self.started.elapsed().as_secs_f64(),
+            statements: self.statements.load(Ordering::Relaxed),
+            current_statement: current.as_ref().map(|(index, _)| *index),
+            sql: current.map(|(_, sql)| sql),
+            client_addr: self.origin.client_addr,
+            idempotency_key: self.origin.idempotency_key.clone(),
+            killed: self.killed.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// A registered transaction, unlisted when dropped
+#[derive(Debug)]
+pub struct ExecTracker {
+    id: u64,
+    exec: Arc<ActiveExec>,
+    active: Active,
+}
+
+impl ExecTracker {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn statements(&self) -> usize {
+        self.exec.statements.load(Ordering::Relaxed)
+    }
+
+    pub fn source_id(&self) -> Option<Uuid> {
+        self.exec.origin.source_id
+    }
+
+    /// Lets kills interrupt statements running on `conn` until the returned
+    /// guard is dropped
+    pub fn attach(&self, conn: &Connection) -> Attached<'_> {
+        *self.exec.interrupt.lock() = Some(conn.get_interrupt_handle());
+        Attached(self)
+    }
+
+    /// Records statement `index` as the one running
+    pub fn statement(&self, index: usize, sql: &str) {
+        self.exec.statements.fetch_max(index + 1, Ordering::Relaxed);
+        *self.exec.current.lock() = Some((index, sql_prefix(sql)));
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.exec.killed.load(Ordering::SeqCst)
+    }
+
+    /// Fails w/ `ChangeError::Interrupted` once killed, so the transaction
+    /// rolls back
+    pub fn check(&self) -> Result<(), ChangeError> {
+        if self.is_killed() {
+            return Err(ChangeError::Interrupted);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ExecTracker {
+    fn drop(&mut self) {
+        self.active.write().remove(&self.id);
+    }
+}
+
+/// Detaches an `ExecTracker` from its connection when dropped
+pub struct Attached<'a>(&'a ExecTracker);
+
+impl Drop for Attached<'_> {
+    fn drop(&mut self) {
+        // the write conn is shared, later kills must not interrupt whatever
+        // runs on it next
+        self.0.exec.interrupt.lock().take();
+    }
+}
+
+// the first `SQL_PREFIX_LEN` chars of `sql`, w/o surrounding whitespace
+fn sql_prefix(sql: &str) -> String {
+    let sql = sql.trim();
+    match sql.char_indices().nth(SQL_PREFIX_LEN) {
+        Some((end, _)) => sql[..end].to_owned(),
+        None => sql.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_and_kills_transactions() -> rusqlite::Result<()> {
+        let registry = ExecRegistry::default();
+
+        let first = registry.register(ExecOrigin::default(), 2);
+        let second = registry.register(
+            ExecOrigin {
+                client_addr: Some("127.0.0.1:4242".parse().unwrap()),
+                idempotency_key: Some("abc".into()),
+                source_id: None,
+                quota: None,
+                usage: None,
+            },
+            1,
+        );
+        first.statement(
+            1,
+            &format!("  INSERT INTO foo VALUES {}", "(1), ".repeat(100)),
+        );
+
+        let listed = registry.list();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].id, first.id());
+        assert_eq!(listed[0].current_statement, Some(1));
+        let sql = listed[0].sql.as_deref().unwrap();
+        assert!(sql.starts_with("INSERT INTO foo VALUES (1),"), "{sql}");
+        assert_eq!(sql.chars().count(), SQL_PREFIX_LEN);
+        assert_eq!(listed[1].statements, 1);
+        assert_eq!(listed[1].current_statement, None);
+        assert_eq!(listed[1].idempotency_key.as_deref(), Some("abc"));
+
+        // streamed requests are counted as their statements run
+        let streamed = registry.register(ExecOrigin::default(), 0);
+        streamed.statement(4, "SELECT 1");
+        assert_eq!(streamed.statements(), 5);
+        assert_eq!(registry.list()[2].statements, 5);
+        drop(streamed);
+
+        let conn = Connection::open_in_memory()?;
+        {
+            let _attached = second.attach(&conn);
+            assert!(second.check().is_ok());
+            assert!(registry.kill(second.id()).unwrap().killed);
+            assert!(matches!(second.check(), Err(ChangeError::Interrupted)));
+        }
+        assert!(second.exec.interrupt.lock().is_none());
+        assert!(!first.is_killed());
+
+        drop(second);
+        assert!(registry.kill(2).is_none());
+        assert_eq!(registry.list().len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,291 @@
+//! Retained change history, to read tables as of a past `db_version`.
+//!
+//! Triggers on each table record the row as it was before every insert,
+//! update or delete, along w/ the `db_version` of the change overwriting it.
+//! A table's state as of version `N` is its current state where rows changed
+//! after `N` are swapped for their earliest recorded previous state.
+//!
+//! History is pruned past a retention window. `__corro_history_bounds` keeps,
+//! per table, the version after which history is complete.
+
+use std::collections::HashSet;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::debug;
+
+use crate::schema::{Schema, Table};
+
+const DB_VERSION_COL: &str = "__corro_db_version";
+const TS_COL: &str = "__corro_ts";
+const EXISTED_COL: &str = "__corro_existed";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AsOfError {
+    #[error(
+        "change history isn't retained, set `db.history_retention_secs` to read as of a db_version"
+    )]
+    Disabled,
+    #[error("db_version {requested} is older than the retained history of table '{table}', which starts after db_version {since}")]
+    NotRetained {
+        requested: i64,
+        table: String,
+        since: i64,
+    },
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+}
+
+/// Name of the table holding `tbl_name`'s history
+pub fn history_table_name(tbl_name: &str) -> String {
+    format!("__corro_history__{tbl_name}")
+}
+
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn trigger_name(tbl_name: &str, op: &str) -> String {
+    quoted(&format!("__corro_history__{tbl_name}_{op}"))
+}
+
+/// Records the history of `table` from now on, or updates its triggers after
+/// columns were added to it.
+pub fn install(conn: &Connection, table: &Table) -> rusqlite::Result<()> {
+    let tbl = quoted(&table.name);
+    let hist = quoted(&history_table_name(&table.name));
+
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {hist} ({DB_VERSION_COL} INTEGER NOT NULL, {TS_COL} INTEGER NOT NULL, {EXISTED_COL} INTEGER NOT NULL);
+         CREATE INDEX IF NOT EXISTS {} ON {hist} ({DB_VERSION_COL});",
+        quoted(&format!("{}_db_version", history_table_name(&table.name))),
+    ))?;
+
+    let existing = conn
+        .prepare("SELECT name FROM pragma_table_info(?)")?
+        .query_map([history_table_name(&table.name)], |row| {
+            row.get::<_, String>(0)
+        })?
+        .collect::<rusqlite::Result<HashSet<_>>>()?;
+
+    for col in table.columns.keys().filter(|col| !existing.contains(*col)) {
+        conn.execute_batch(&format!("ALTER TABLE {hist} ADD COLUMN {}", quoted(col)))?;
+    }
+
+    // version of the change being made and when it's made
+    let meta = "crsql_next_db_version(), CAST(strftime('%s', 'now') AS INTEGER)";
+    let cols = table
+        .columns
+        .keys()
+        .map(|col| quoted(col))
+        .collect::<Vec<_>>();
+    let old_cols = table
+        .columns
+        .keys()
+        .map(|col| format!("OLD.{}", quoted(col)))
+        .collect::<Vec<_>>();
+    let pks = table.pk.iter().map(|col| quoted(col)).collect::<Vec<_>>();
+    let new_pks = table
+        .pk
+        .iter()
+        .map(|col| format!("NEW.{}", quoted(col)))
+        .collect::<Vec<_>>();
+    let pk_changed = table
+        .pk
+        .iter()
+        .map(|col| format!("NEW.{0} IS NOT OLD.{0}", quoted(col)))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+
+    let record_old = format!(
+        "INSERT INTO {hist} ({DB_VERSION_COL}, {TS_COL}, {EXISTED_COL}, {}) VALUES ({meta}, 1, {});",
+        cols.join(", "),
+        old_cols.join(", ")
+    );
+    let record_absent = format!(
+        "INSERT INTO {hist} ({DB_VERSION_COL}, {TS_COL}, {EXISTED_COL}, {}) VALUES ({meta}, 0, {});",
+        pks.join(", "),
+        new_pks.join(", ")
+    );
+
+    conn.execute_batch(&format!(
+        "DROP TRIGGER IF EXISTS {insert};
+         CREATE TRIGGER {insert} AFTER INSERT ON {tbl} BEGIN {record_absent} END;
+         DROP TRIGGER IF EXISTS {update};
+         CREATE TRIGGER {update} AFTER UPDATE ON {tbl} BEGIN {record_old} {record_moved} END;
+         DROP TRIGGER IF EXISTS {delete};
+         CREATE TRIGGER {delete} AFTER DELETE ON {tbl} BEGIN {record_old} END;",
+        insert = trigger_name(&table.name, "insert"),
+        update = trigger_name(&table.name, "update"),
+        delete = trigger_name(&table.name, "delete"),
+        // an update moving a row to another primary key creates that row
+        record_moved = format!(
+            "INSERT INTO {hist} ({DB_VERSION_COL}, {TS_COL}, {EXISTED_COL}, {}) SELECT {meta}, 0, {} WHERE {pk_changed};",
+            pks.join(", "),
+            new_pks.join(", ")
+        ),
+    ))?;
+
+    conn.execute(
+        "INSERT OR IGNORE INTO __corro_history_bounds (tbl_name, since) VALUES (?, crsql_db_version())",
+        [&table.name],
+    )?;
+
+    Ok(())
+}
+
+/// Stops recording the history of `tbl_name` and drops what was retained
+pub fn uninstall(conn: &Connection, tbl_name: &str) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "DROP TRIGGER IF EXISTS {};
+         DROP TRIGGER IF EXISTS {};
+         DROP TRIGGER IF EXISTS {};
+         DROP TABLE IF EXISTS {};",
+        trigger_name(tbl_name, "insert"),
+        trigger_name(tbl_name, "update"),
+        trigger_name(tbl_name, "delete"),
+        quoted(&history_table_name(tbl_name)),
+    ))?;
+    conn.execute(
+        "DELETE FROM __corro_history_bounds WHERE tbl_name = ?",
+        [tbl_name],
+    )?;
+    Ok(())
+}
+
+/// Records the history of every table of `schema` when `enabled`, drops all
+/// retained history otherwise
+pub fn reconcile(conn: &Connection, schema: &Schema, enabled: bool) -> rusqlite::Result<()> {
+    for (tbl_name, _) in history_bounds(conn)? {
+        if !enabled || !schema.tables.contains_key(&tbl_name) {
+            debug!("dropping change history of table '{tbl_name}'");
+            uninstall(conn, &tbl_name)?;
+        }
+    }
+
+    if enabled {
+        for table in schema.tables.values() {
+            install(conn, table)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Tables w/ a retained history, along w/ the version after which it's
+/// complete
+pub fn history_bounds(conn: &Connection) -> rusqlite::Result<Vec<(String, i64)>> {
+    conn.prepare_cached("SELECT tbl_name, since FROM __corro_history_bounds ORDER BY tbl_name")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+}
+
+/// Drops history recorded before `before_ts` (unix seconds), returning how
+/// many entries were dropped
+pub fn prune(conn: &Connection, before_ts: i64) -> rusqlite::Result<usize> {
+    let mut pruned = 0;
+    for (tbl_name, since) in history_bounds(conn)? {
+        let hist = quoted(&history_table_name(&tbl_name));
+
+        let Some(through): Option<i64> = conn
+            .query_row(
+                &format!("SELECT MAX({DB_VERSION_COL}) FROM {hist} WHERE {TS_COL} < ?"),
+                [before_ts],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten()
+        else {
+            continue;
+        };
+
+        pruned += conn.execute(
+            &format!("DELETE FROM {hist} WHERE {DB_VERSION_COL} <= ?"),
+            [through],
+        )?;
+        conn.execute(
+            "UPDATE __corro_history_bounds SET since = ? WHERE tbl_name = ?",
+            params![through.max(since), tbl_name],
+        )?;
+    }
+    Ok(pruned)
+}
+
+/// Shadows every table which changed after `db_version` w/ a temp table
+/// holding its state as of that version, so unqualified table names in
+/// queries resolve to it. Returns the names of the shadowed tables.
+///
+/// Meant for a dedicated connection, inside a read transaction so the
+/// shadowed and untouched tables are read from the same snapshot.
+pub fn shadow_as_of(
+    conn: &Connection,
+    schema: &Schema,
+    db_version: i64,
+) -> Result<Vec<String>, AsOfError> {
+    let bounds = history_bounds(conn)?;
+    if bounds.is_empty() {
+        return Err(AsOfError::Disabled);
+    }
+
+    let mut shadowed = vec![];
+    for (tbl_name, since) in bounds {
+        let Some(table) = schema.tables.get(&tbl_name) else {
+            continue;
+        };
+        if db_version < since {
+            return Err(AsOfError::NotRetained {
+                requested: db_version,
+                table: tbl_name,
+                since,
+            });
+        }
+
+        let tbl = quoted(&tbl_name);
+        let hist = quoted(&history_table_name(&tbl_name));
+
+        let changed: bool = conn.query_row(
+            &format!("SELECT EXISTS(SELECT 1 FROM {hist} WHERE {DB_VERSION_COL} > ?)"),
+            [db_version],
+            |row| row.get(0),
+        )?;
+        if !changed {
+            continue;
+        }
+
+        let cols = table
+            .columns
+            .keys()
+            .map(|col| quoted(col))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let pks = table
+            .pk
+            .iter()
+            .map(|col| quoted(col))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        debug!("shadowing table '{tbl_name}' as of db_version {db_version}");
+
+        conn.execute_batch(&format!(
+            "CREATE TEMP TABLE {tbl} AS SELECT {cols} FROM main.{tbl} WHERE 0"
+        ))?;
+        // rows untouched since
+        conn.execute(
+            &format!(
+                "INSERT INTO temp.{tbl} ({cols}) SELECT {cols} FROM main.{tbl} WHERE ({pks}) NOT IN (SELECT {pks} FROM main.{hist} WHERE {DB_VERSION_COL} > ?1)"
+            ),
+            [db_version],
+        )?;
+        // rows changed since, as they were before their first change
+        conn.execute(
+            &format!(
+                "INSERT INTO temp.{tbl} ({cols}) SELECT {cols} FROM main.{hist} WHERE rowid IN (SELECT MIN(rowid) FROM main.{hist} WHERE {DB_VERSION_COL} > ?1 GROUP BY {pks}) AND {EXISTED_COL}"
+            ),
+            [db_version],
+        )?;
+
+        shadowed.push(tbl_name);
+    }
+
+    Ok(shadowed)
+}
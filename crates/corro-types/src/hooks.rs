@@ -0,0 +1,200 @@
+use std::{collections::HashSet, sync::Arc};
+
+use compact_str::CompactString;
+use corro_api_types::{row_to_change, Change, TableName};
+use metrics::increment_counter;
+use rusqlite::Connection;
+use tokio::sync::broadcast;
+
+/// Batches of changes each receiver of `Agent::changes` can fall behind by
+/// before it misses some
+pub const CHANGE_HOOKS_CAPACITY: usize = 1024;
+
+/// In-process receivers of the changes committed to the agent's tables,
+/// local or not, fed alongside subscriptions
+#[derive(Debug)]
+pub struct ChangeHooks {
+    tx: broadcast::Sender<Arc<[Change]>>,
+}
+
+impl Default for ChangeHooks {
+    fn default() -> Self {
+        Self::new(CHANGE_HOOKS_CAPACITY)
+    }
+}
+
+impl ChangeHooks {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            tx: broadcast::channel(capacity).0,
+        }
+    }
+
+    /// Receives batches of changes to `tables`, or to every table if empty
+    pub fn subscribe<I, T>(&self, tables: I) -> ChangeReceiver
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        ChangeReceiver {
+            rx: self.tx.subscribe(),
+            tables: tables
+                .into_iter()
+                .map(|table| TableName(CompactString::new(table)))
+                .collect(),
+        }
+    }
+
+    pub fn has_receivers(&self) -> bool {
+        self.tx.receiver_count() > 0
+    }
+
+    /// Hands `changes` to every receiver as one batch. Never waits on them:
+    /// receivers too far behind miss the oldest batches instead.
+    pub fn notify(&self, changes: &[Change]) {
+        if changes.is_empty() || !self.has_receivers() {
+            return;
+        }
+        // only fails if the last receiver was just dropped
+        let _ = self.tx.send(changes.into());
+    }
+
+    /// Hands the changes committed in `db_version` to every receiver, for
+    /// writes which didn't go through `notify`
+    pub fn notify_db_version(&self, conn: &Connection, db_version: i64) -> rusqlite::Result<()> {
+        if !self.has_receivers() {
+            return Ok(());
+        }
+
+        let changes = conn
+            .prepare_cached(
+                r#"
+                SELECT "table", pk, cid, val, col_version, db_version, seq, COALESCE(site_id, crsql_site_id()), cl
+                    FROM crsql_changes
+                    WHERE db_version = ?
+                    ORDER BY seq ASC
+                "#,
+            )?
+            .query_map([db_version], row_to_change)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        self.notify(&changes);
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChangeRecvError {
+    /// The receiver fell too far behind and missed that many batches, it
+    /// keeps receiving from the oldest one still buffered
+    #[error("receiver lagged behind by {0} batches of changes")]
+    Lagged(u64),
+    #[error("agent is gone")]
+    Closed,
+}
+
+/// Batches of changes to some tables, see `Agent::changes`
+#[derive(Debug)]
+pub struct ChangeReceiver {
+    rx: broadcast::Receiver<Arc<[Change]>>,
+    tables: HashSet<TableName>,
+}
+
+impl ChangeReceiver {
+    /// The next batch w/ changes to the receiver's tables, w/o changes to
+    /// other tables
+    pub async fn recv(&mut self) -> Result<Arc<[Change]>, ChangeRecvError> {
+        loop {
+            let changes = match self.rx.recv().await {
+                Ok(changes) => changes,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    increment_counter!("corro.changes.hooks.lagged");
+                    return Err(ChangeRecvError::Lagged(missed));
+                }
+                Err(broadcast::error::RecvError::Closed) => return Err(ChangeRecvError::Closed),
+            };
+
+            if self.tables.is_empty()
+                || changes
+                    .iter()
+                    .all(|change| self.tables.contains(&change.table))
+            {
+                return Ok(changes);
+            }
+
+            let filtered: Vec<Change> = changes
+                .iter()
+                .filter(|change| self.tables.contains(&change.table))
+                .cloned()
+                .collect();
+            if !filtered.is_empty() {
+                return Ok(filtered.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::{ColumnName, SqliteValue};
+
+    use super::*;
+
+    fn change(table: &str, db_version: i64) -> Change {
+        Change {
+            table: TableName(table.into()),
+            pk: vec![1, 9, 1],
+            cid: ColumnName("text".into()),
+            val: SqliteValue::Text("hello".into()),
+            col_version: 1,
+            db_version,
+            seq: 0,
+            site_id: corro_api_types::SiteId::NIL,
+            cl: 1,
+            compressed: None,
+            ts: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn filters_batches_by_table() {
+        let hooks = ChangeHooks::default();
+        let mut all = hooks.subscribe(Vec::<String>::new());
+        let mut tests = hooks.subscribe(["tests"]);
+
+        hooks.notify(&[change("tests2", 1)]);
+        hooks.notify(&[change("tests", 2), change("tests2", 2)]);
+
+        let batch = all.recv().await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].table.as_str(), "tests2");
+        assert_eq!(all.recv().await.unwrap().len(), 2);
+
+        // the batch w/o changes to `tests` is skipped altogether
+        let batch = tests.recv().await.unwrap();
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].table.as_str(), "tests");
+        assert_eq!(batch[0].db_version, 2);
+    }
+
+    #[tokio::test]
+    async fn lags_slow_receivers_wo_blocking() {
+        let hooks = ChangeHooks::new(4);
+        let mut slow = hooks.subscribe(["tests"]);
+
+        // never waits on the receiver
+        for db_version in 1..=10 {
+            hooks.notify(&[change("tests", db_version)]);
+        }
+
+        assert!(matches!(slow.recv().await, Err(ChangeRecvError::Lagged(6))));
+        // then resumes w/ the oldest batch still buffered
+        assert_eq!(slow.recv().await.unwrap()[0].db_version, 7);
+
+        drop(hooks);
+        for _ in 0..3 {
+            slow.recv().await.unwrap();
+        }
+        assert!(matches!(slow.recv().await, Err(ChangeRecvError::Closed)));
+    }
+}
@@ -5,9 +5,19 @@ pub mod api;
 pub mod broadcast;
 pub mod change;
 pub mod config;
+pub mod exec;
+pub mod history;
+pub mod hooks;
 pub mod members;
+pub mod node_names;
+pub mod probe;
 pub mod pubsub;
+pub mod query_cache;
+pub mod quota;
+pub mod registry;
 pub mod schema;
+pub mod sampling;
 pub mod sqlite;
 pub mod sync;
 pub mod tls;
+pub mod usage;
@@ -1,4 +1,9 @@
-use std::{collections::BTreeMap, net::SocketAddr, ops::Range, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::SocketAddr,
+    ops::Range,
+    time::{Duration, SystemTime},
+};
 
 use circular_buffer::CircularBuffer;
 use tracing::{debug, trace};
@@ -14,6 +19,9 @@ pub struct MemberState {
     pub ts: Timestamp,
 
     pub ring: Option<u8>,
+    /// Last time the member was heard from, it's added or updated, or a
+    /// round-trip time is measured
+    pub last_seen: SystemTime,
 }
 
 impl MemberState {
@@ -22,6 +30,7 @@ impl MemberState {
             addr,
             ts,
             ring: None,
+            last_seen: SystemTime::now(),
         }
     }
 
@@ -40,6 +49,8 @@ pub struct Members {
     pub states: BTreeMap<ActorId, MemberState>,
     pub by_addr: BTreeMap<SocketAddr, ActorId>,
     pub rtts: BTreeMap<SocketAddr, Rtt>,
+    /// Members a sync is in flight w/
+    pub syncing: BTreeSet<ActorId>,
 }
 
 impl Members {
@@ -62,6 +73,7 @@ impl Members {
             debug!("older timestamp, ignoring");
             return (false, false);
         }
+        member.last_seen = SystemTime::now();
 
         // sometimes, this can be equal
         let newer = actor.ts().to_duration() > member.ts.to_duration();
@@ -97,6 +109,13 @@ impl Members {
     }
 
     pub fn add_rtt(&mut self, addr: SocketAddr, rtt: Duration) {
+        if let Some(state) = self
+            .by_addr
+            .get(&addr)
+            .and_then(|actor_id| self.states.get_mut(actor_id))
+        {
+            state.last_seen = SystemTime::now();
+        }
         self.rtts
             .entry(addr)
             .or_default()
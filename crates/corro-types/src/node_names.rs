@@ -0,0 +1,48 @@
+//! Names of the cluster's nodes by site id, to attribute changes to the node
+//! they originate from.
+//!
+//! Each node w/ a `gossip.node_name` upserts its own row of the replicated
+//! `__corro_node_names` table at startup. Names are cached in memory
+//! and reloaded whenever the table changes, lookups never hit the database.
+
+use std::collections::HashMap;
+
+use compact_str::CompactString;
+use parking_lot::RwLock;
+use rusqlite::Connection;
+
+use crate::api::SiteId;
+
+/// Table nodes write their name to, one row per node
+pub const NODE_NAMES_TABLE: &str = "__corro_node_names";
+
+/// Cached names of the nodes, see `Agent::node_names`
+#[derive(Debug, Default)]
+pub struct NodeNames {
+    names: RwLock<HashMap<SiteId, CompactString>>,
+}
+
+impl NodeNames {
+    /// Name of the node w/ `site_id`, `None` for nodes whose name wasn't
+    /// replicated to this one (yet)
+    pub fn get(&self, site_id: &SiteId) -> Option<CompactString> {
+        self.names.read().get(site_id).cloned()
+    }
+
+    /// Replaces the cached names w/ the ones stored in `conn`
+    pub fn reload(&self, conn: &Connection) -> rusqlite::Result<usize> {
+        let names = read_node_names(conn)?;
+        let count = names.len();
+        *self.names.write() = names;
+        Ok(count)
+    }
+}
+
+/// Names stored in the `__corro_node_names` table, by site id
+pub fn read_node_names(conn: &Connection) -> rusqlite::Result<HashMap<SiteId, CompactString>> {
+    conn.prepare_cached("SELECT site_id, name FROM __corro_node_names WHERE name != ''")?
+        .query_map([], |row| {
+            Ok((row.get(0)?, CompactString::from(row.get::<_, String>(1)?)))
+        })?
+        .collect()
+}
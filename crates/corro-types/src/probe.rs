@@ -0,0 +1,210 @@
+//! Change propagation probes, see `ProbeConfig`.
+//!
+//! Each node upserts its own row of the replicated `__corro_probe` table at
+//! an interval, bumping a counter and setting the wall-clock time it wrote
+//! it at. Other nodes record how long after that time they applied the
+//! change. Clocks are never perfectly in sync: latencies are off by however
+//! far apart both nodes' clocks are, negative ones are counted as 0.
+
+use std::collections::{HashMap, VecDeque};
+
+use parking_lot::Mutex;
+
+use crate::api::{Change, PeerLatency, SiteId, SqliteValue};
+
+/// Table heartbeats are written to, one row per node
+pub const PROBE_TABLE: &str = "__corro_probe";
+
+/// Latencies kept per peer to compute quantiles over
+pub const PROBE_SAMPLES: usize = 512;
+
+#[derive(Debug, Default)]
+struct PeerSamples {
+    latencies: VecDeque<f64>,
+    counter: i64,
+    last_origin_ts: i64,
+    last_seen_at: u64,
+}
+
+/// Latencies of the heartbeats received from each peer
+#[derive(Debug, Default)]
+pub struct ProbeStats {
+    peers: Mutex<HashMap<SiteId, PeerSamples>>,
+}
+
+impl ProbeStats {
+    /// Records the heartbeats among `changes`, applied at `now_ms` (since the
+    /// epoch), and returns their origin and latency in seconds. Heartbeats
+    /// from `own` site id, or older than the last one of their peer, are
+    /// skipped.
+    pub fn record(&self, own: SiteId, changes: &[Change], now_ms: u64) -> Vec<(SiteId, f64)> {
+        let mut recorded = vec![];
+        let mut peers = self.peers.lock();
+
+        for change in changes {
+            if change.site_id == own || change.table.as_str() != PROBE_TABLE {
+                continue;
+            }
+            let SqliteValue::Integer(value) = change.val else {
+                continue;
+            };
+
+            match &*change.cid {
+                "counter" => {
+                    let peer = peers.entry(change.site_id).or_default();
+                    peer.counter = peer.counter.max(value);
+                }
+                "origin_ts" => {
+                    let peer = peers.entry(change.site_id).or_default();
+                    // resent or reordered
+                    if value <= peer.last_origin_ts {
+                        continue;
+                    }
+                    peer.last_origin_ts = value;
+
+                    let latency = now_ms.saturating_sub(value.max(0) as u64) as f64 / 1000.0;
+                    if peer.latencies.len() == PROBE_SAMPLES {
+                        peer.latencies.pop_front();
+                    }
+                    peer.latencies.push_back(latency);
+                    peer.last_seen_at = now_ms;
+
+                    recorded.push((change.site_id, latency));
+                }
+                _ => {}
+            }
+        }
+
+        recorded
+    }
+
+    /// Forgets a peer, once its heartbeats were deleted
+    pub fn forget(&self, site_id: &SiteId) {
+        self.peers.lock().remove(site_id);
+    }
+
+    /// Latencies of every peer heard from, ordered by site id
+    pub fn latencies(&self) -> Vec<PeerLatency> {
+        let peers = self.peers.lock();
+        let mut latencies: Vec<PeerLatency> = peers
+            .iter()
+            .filter(|(_, peer)| !peer.latencies.is_empty())
+            .map(|(site_id, peer)| {
+                let mut sorted: Vec<f64> = peer.latencies.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                PeerLatency {
+                    site_id: *site_id,
+                    samples: sorted.len() as u64,
+                    counter: peer.counter,
+                    p50: quantile(&sorted, 0.5),
+                    p99: quantile(&sorted, 0.99),
+                    max: sorted[sorted.len() - 1],
+                    last_seen_at: peer.last_seen_at,
+                }
+            })
+            .collect();
+        latencies.sort_by_key(|latency| latency.site_id);
+        latencies
+    }
+}
+
+// nearest-rank quantile of sorted, non-empty samples
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use compact_str::CompactString;
+
+    use super::*;
+    use crate::api::{ColumnName, TableName};
+
+    fn heartbeat(site_id: SiteId, counter: i64, origin_ts: i64) -> Vec<Change> {
+        [("counter", counter), ("origin_ts", origin_ts)]
+            .into_iter()
+            .map(|(cid, val)| Change {
+                table: TableName(CompactString::new(PROBE_TABLE)),
+                cid: ColumnName(CompactString::new(cid)),
+                val: SqliteValue::Integer(val),
+                site_id,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn records_peer_latencies() {
+        let own = SiteId([1; 16]);
+        let peer = SiteId([2; 16]);
+        let stats = ProbeStats::default();
+
+        // our own heartbeats don't count
+        assert!(stats
+            .record(own, &heartbeat(own, 1, 1_000), 1_500)
+            .is_empty());
+
+        for i in 1..=100 {
+            let recorded =
+                stats.record(own, &heartbeat(peer, i, i * 1_000), (i * 1_000 + i) as u64);
+            assert_eq!(recorded, [(peer, i as f64 / 1000.0)]);
+        }
+        // already seen
+        assert!(stats
+            .record(own, &heartbeat(peer, 50, 50_000), 200_000)
+            .is_empty());
+        // the peer's clock is ahead of ours
+        assert_eq!(
+            stats.record(own, &heartbeat(peer, 101, 300_000), 200_000),
+            [(peer, 0.0)]
+        );
+
+        let latencies = stats.latencies();
+        assert_eq!(latencies.len(), 1);
+        let latency = &latencies[0];
+        assert_eq!(latency.site_id, peer);
+        assert_eq!(latency.samples, 101);
+        assert_eq!(latency.counter, 101);
+        assert_eq!(latency.p50, 0.05);
+        assert_eq!(latency.p99, 0.099);
+        assert_eq!(latency.max, 0.1);
+        assert_eq!(latency.last_seen_at, 200_000);
+
+        stats.forget(&peer);
+        assert!(stats.latencies().is_empty());
+    }
+
+    #[test]
+    fn keeps_last_samples() {
+        let own = SiteId([1; 16]);
+        let peer = SiteId([2; 16]);
+        let stats = ProbeStats::default();
+
+        for i in 1..=(PROBE_SAMPLES as i64 * 2) {
+            let latency = if i <= PROBE_SAMPLES as i64 {
+                60_000
+            } else {
+                10
+            };
+            stats.record(
+                own,
+                &heartbeat(peer, i, i * 100_000),
+                (i * 100_000 + latency) as u64,
+            );
+        }
+
+        let latencies = stats.latencies();
+        assert_eq!(latencies[0].samples, PROBE_SAMPLES as u64);
+        assert_eq!(latencies[0].max, 0.01);
+    }
+
+    #[test]
+    fn nearest_rank_quantiles() {
+        assert_eq!(quantile(&[1.0], 0.5), 1.0);
+        assert_eq!(quantile(&[1.0, 2.0], 0.5), 1.0);
+        assert_eq!(quantile(&[1.0, 2.0, 3.0], 0.5), 2.0);
+        assert_eq!(quantile(&[1.0, 2.0, 3.0], 0.99), 3.0);
+        assert_eq!(quantile(&[1.0, 2.0, 3.0], 0.0), 1.0);
+    }
+}
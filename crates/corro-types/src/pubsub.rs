@@ -1,16 +1,24 @@
 use std::{
     cmp,
-    collections::{HashMap, HashSet},
-    sync::Arc,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
 use bytes::{Buf, BufMut};
 use compact_str::{CompactString, ToCompactString};
-use corro_api_types::{Change, ChangeId, ColumnType, RowId, SqliteValue, SqliteValueRef};
+use corro_api_types::{
+    sqlite::{change_kind, row_change_kind, ChangeKind},
+    Change, ChangeId, ColumnType, ResumeGap, RowId, SiteId, SqliteValue, SqliteValueRef,
+};
 use enquote::unquote;
 use fallible_iterator::FallibleIterator;
 use indexmap::IndexMap;
+use metrics::increment_counter;
+use parking_lot::{Mutex, RwLock};
 use rusqlite::{params, params_from_iter, Connection, OptionalExtension, ToSql, Transaction};
 use sqlite3_parser::{
     ast::{
@@ -19,17 +27,21 @@ use sqlite3_parser::{
     },
     lexer::sql::Parser,
 };
-use tokio::{sync::mpsc, task::block_in_place};
+use tokio::{
+    sync::{mpsc, oneshot, Notify},
+    task::block_in_place,
+};
 use tracing::{debug, error, info, trace, warn};
 use uuid::Uuid;
 
 use crate::{
     api::QueryEvent,
+    config::SubscriptionChangesConfig,
     schema::{Schema, Table},
     sqlite::Migration,
 };
 
-pub use corro_api_types::sqlite::ChangeType;
+pub use corro_api_types::ChangeType;
 
 #[derive(Debug, thiserror::Error)]
 pub enum NormalizeStatementError {
@@ -220,8 +232,226 @@ pub fn unpack_columns(mut buf: &[u8]) -> Result<Vec<SqliteValueRef>, UnpackError
     Ok(ret)
 }
 
+/// Primary keys of changed rows, by table
+pub type Candidates = IndexMap<CompactString, Vec<Vec<SqliteValue>>>;
+
 pub enum MatcherCmd {
-    ProcessChange(IndexMap<CompactString, Vec<Vec<SqliteValue>>>),
+    ProcessChange {
+        candidates: Candidates,
+        /// Rows deleted and inserted again, which subscribers get as a
+        /// `Delete` followed by an `Insert`
+        resurrected: Candidates,
+        /// `source_id` of the transaction the change came from, if it was
+        /// tagged w/ one
+        source: Option<Uuid>,
+        /// Site id of the node the change originates from
+        origin: Option<SiteId>,
+    },
+    Rebind(Rebind, oneshot::Sender<Result<ChangeId, MatcherError>>),
+    /// Re-prepares the query against a new schema, replying w/ the change id
+    /// of the `Rebound` marker if its columns changed
+    Reshape(
+        Arc<Schema>,
+        oneshot::Sender<Result<Option<ChangeId>, MatcherError>>,
+    ),
+    /// Purges changes past the retention now, replying w/ how many were
+    PurgeChanges(oneshot::Sender<Result<usize, MatcherError>>),
+    /// Catches up on what changed while the matcher was paused
+    Resume,
+}
+
+/// A new binding for an existing subscription, see `MatcherHandle::prepare_rebind`
+#[derive(Debug)]
+pub struct Rebind {
+    sql: String,
+    query: MatcherQuery,
+}
+
+// no rebind happened yet
+const NOT_REBOUND: i64 = -1;
+
+/// Result columns of a subscription's query, which a schema change can alter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatcherColumns {
+    pub names: Vec<CompactString>,
+    /// Bumped every time a schema change altered `names`
+    pub schema_generation: u64,
+    // how many `col_N` columns the query tables have values in
+    count: usize,
+}
+
+impl MatcherColumns {
+    /// Number of columns of the rows stored for the subscription
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn to_event(&self) -> QueryEvent {
+        QueryEvent::Columns {
+            names: self.names.clone(),
+            schema_generation: self.schema_generation,
+        }
+    }
+}
+
+/// Sources of this many of the latest changes from tagged transactions are
+/// remembered, older changes can't be attributed to their source anymore
+pub const MAX_CHANGE_SOURCES: usize = 4096;
+
+// the latest changes emitted for tagged transactions w/ their source,
+// ordered by change id
+type ChangeSources = Arc<Mutex<VecDeque<(ChangeId, Uuid)>>>;
+
+/// Origins of this many of the latest changes are remembered, older changes
+/// can't be attributed to their origin anymore
+pub const MAX_CHANGE_ORIGINS: usize = 4096;
+
+// the latest changes emitted w/ the site id they originate from, ordered by
+// change id
+type ChangeOrigins = Arc<Mutex<VecDeque<(ChangeId, SiteId)>>>;
+
+// the read snapshot a matcher holds while it queries and matches changes,
+// keeping WAL checkpoints from truncating past it
+#[derive(Debug, Default)]
+struct Snapshot {
+    held_since: Mutex<Option<Instant>>,
+    expired: AtomicBool,
+    expire: Notify,
+}
+
+impl Snapshot {
+    fn hold(self: &Arc<Self>) -> SnapshotGuard {
+        *self.held_since.lock() = Some(Instant::now());
+        self.expired.store(false, Ordering::Release);
+        SnapshotGuard(self.clone())
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Acquire)
+    }
+
+    async fn expired(&self) {
+        loop {
+            // registered before checking, not to miss a notification
+            let notified = self.expire.notified();
+            if self.is_expired() {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+struct SnapshotGuard(Arc<Snapshot>);
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        *self.0.held_since.lock() = None;
+    }
+}
+
+// when a matcher last evaluated changes or had subscribers, and whether it
+// was paused for being idle since
+#[derive(Debug)]
+struct Activity {
+    last: Mutex<Instant>,
+    // only the matcher pauses itself, only whoever unpauses it sends it a
+    // `MatcherCmd::Resume`
+    paused: AtomicBool,
+    evaluations: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            last: Mutex::new(Instant::now()),
+            paused: AtomicBool::new(false),
+            evaluations: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last.lock() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last.lock().elapsed()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+}
+
+// a changed row's packed primary key, whether the change resurrected it and
+// the site id it originates from
+#[derive(Debug, Clone)]
+struct ChangedRow {
+    pk: Vec<u8>,
+    resurrected: bool,
+    site_id: SiteId,
+}
+
+/// Rows changed by a changeset or a db version, by table. They're read once
+/// and only handed to the subscriptions whose query references their table.
+#[derive(Debug, Default)]
+pub struct TableChanges(IndexMap<CompactString, Vec<ChangedRow>>);
+
+impl TableChanges {
+    pub fn from_changes(changes: &[Change]) -> Self {
+        let mut tables = Self::default();
+        for change in changes {
+            tables.push(
+                change.table.as_str(),
+                ChangedRow {
+                    pk: change.pk.clone(),
+                    resurrected: change_kind(change) == ChangeKind::Resurrect,
+                    site_id: change.site_id,
+                },
+            );
+        }
+        tables
+    }
+
+    /// Rows changed by local db version `db_version`
+    pub fn from_db_version(conn: &Connection, db_version: i64) -> rusqlite::Result<Self> {
+        let mut prepped = conn.prepare_cached(
+            "SELECT \"table\", pk, cid, cl, col_version, COALESCE(site_id, crsql_site_id()) FROM crsql_changes WHERE db_version = ? ORDER BY seq",
+        )?;
+
+        let rows = prepped.query_map([db_version], |row| {
+            let cid: String = row.get(2)?;
+            Ok((
+                row.get::<_, String>(0)?,
+                ChangedRow {
+                    pk: row.get(1)?,
+                    resurrected: row_change_kind(&cid, row.get(3)?, row.get(4)?)
+                        == ChangeKind::Resurrect,
+                    site_id: row.get(5)?,
+                },
+            ))
+        })?;
+
+        let mut tables = Self::default();
+        for row in rows {
+            let (table, row) = row?;
+            tables.push(&table, row);
+        }
+        Ok(tables)
+    }
+
+    fn push(&mut self, table: &str, row: ChangedRow) {
+        match self.0.get_mut(table) {
+            Some(rows) => rows.push(row),
+            None => {
+                self.0.insert(table.to_compact_string(), vec![row]);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
 #[derive(Clone)]
@@ -233,64 +463,140 @@ struct InnerMatcherHandle {
     parsed: ParsedSelect,
     qualified_table_name: String,
     qualified_changes_table_name: String,
-    col_names: Vec<CompactString>,
+    columns: Arc<RwLock<MatcherColumns>>,
+    pks: IndexMap<String, Vec<String>>,
+    rebound_at: Arc<AtomicI64>,
+    sources: ChangeSources,
+    origins: ChangeOrigins,
+    snapshot: Arc<Snapshot>,
+    activity: Arc<Activity>,
 }
 
 impl MatcherHandle {
-    fn process_changes_from_iter<I, T, P>(&self, iter: I) -> Result<(), MatcherError>
-    where
-        I: Iterator<Item = rusqlite::Result<(T, P)>>,
-        T: AsRef<str>,
-        P: AsRef<[u8]>,
-    {
-        let mut candidates: IndexMap<CompactString, Vec<Vec<SqliteValue>>> = IndexMap::new();
+    /// Evaluates the rows of `changes` in tables the query references,
+    /// attributing the resulting events to `source` when the transaction was
+    /// tagged w/ one. A paused subscription is resumed instead, catching up
+    /// on these along w/ whatever else it missed.
+    pub fn process_changes(
+        &self,
+        changes: &TableChanges,
+        source: Option<Uuid>,
+    ) -> Result<(), MatcherError> {
+        let mut tables = changes
+            .0
+            .iter()
+            .filter(|(table, _)| self.references(table))
+            .peekable();
+        if tables.peek().is_none() {
+            return Ok(());
+        }
 
-        let filtered = iter
-            .flatten()
-            .filter(|(table, _)| self.0.parsed.table_columns.contains_key(table.as_ref()));
+        if self.0.activity.is_paused() {
+            return self.resume();
+        }
 
-        for (table, pk) in filtered {
-            let pks = unpack_columns(pk.as_ref())?
-                .into_iter()
-                .map(|v| v.to_owned())
-                .collect();
-            if let Some(v) = candidates.get_mut(table.as_ref()) {
-                v.push(pks);
-            } else {
-                candidates.insert(table.as_ref().to_compact_string(), vec![pks]);
+        let mut candidates = Candidates::new();
+        let mut resurrected = Candidates::new();
+        // changes are processed a version at a time, all from the same node
+        let mut origin = None;
+
+        for (table, rows) in tables {
+            for row in rows {
+                origin.get_or_insert(row.site_id);
+                let pks: Vec<SqliteValue> = unpack_columns(&row.pk)?
+                    .into_iter()
+                    .map(|v| v.to_owned())
+                    .collect();
+                if row.resurrected {
+                    resurrected
+                        .entry(table.clone())
+                        .or_default()
+                        .push(pks.clone());
+                }
+                candidates.entry(table.clone()).or_default().push(pks);
             }
         }
 
         self.0
             .cmd_tx
-            .try_send(MatcherCmd::ProcessChange(candidates))
+            .try_send(MatcherCmd::ProcessChange {
+                candidates,
+                resurrected,
+                source,
+                origin,
+            })
             .map_err(|_| MatcherError::ChangeQueueClosedOrFull)?;
 
         Ok(())
     }
 
-    pub fn process_changes_from_db_version(
+    /// Evaluates `changes` against the query, see `process_changes`
+    pub fn process_change(
         &self,
-        conn: &Connection,
-        db_version: i64,
+        changes: &[Change],
+        source: Option<Uuid>,
     ) -> Result<(), MatcherError> {
-        let mut prepped = conn.prepare_cached(
-            "SELECT \"table\", DISTINCT(pk) FROM crsql_changes WHERE db_version = ? ORDER BY seq GROUP BY \"table\"",
-        )?;
+        self.process_changes(&TableChanges::from_changes(changes), source)
+    }
 
-        let rows = prepped.query_map([db_version], |row| {
-            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
-        })?;
+    /// Whether the query reads from `table`, as prepared
+    pub fn references(&self, table: &str) -> bool {
+        self.0.parsed.table_columns.contains_key(table)
+    }
 
-        self.process_changes_from_iter(rows)
+    /// Whether the subscription is paused for being idle, see
+    /// `SubscriptionChangesConfig::idle_pause_secs`
+    pub fn is_paused(&self) -> bool {
+        self.0.activity.is_paused()
     }
 
-    pub fn process_change(&self, changes: &[Change]) -> Result<(), MatcherError> {
-        self.process_changes_from_iter(
-            changes
-                .iter()
-                .map(|change| Ok((change.table.as_str(), change.pk.as_slice()))),
-        )
+    /// How many times the matcher evaluated changes or caught up on them
+    pub fn evaluations(&self) -> u64 {
+        self.0.activity.evaluations.load(Ordering::Relaxed)
+    }
+
+    /// Records a subscriber's activity, resuming the subscription if it was
+    /// paused
+    pub fn touch(&self) -> Result<(), MatcherError> {
+        self.0.activity.touch();
+        self.resume()
+    }
+
+    // unpauses the matcher, which then catches up on what it missed
+    fn resume(&self) -> Result<(), MatcherError> {
+        if self
+            .0
+            .activity
+            .paused
+            .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            self.0
+                .cmd_tx
+                .try_send(MatcherCmd::Resume)
+                .map_err(|_| MatcherError::ChangeQueueClosedOrFull)?;
+        }
+        Ok(())
+    }
+
+    /// `source_id` of the transaction change `change_id` came from, if it
+    /// was tagged w/ one and the change is recent enough to be remembered
+    pub fn change_source(&self, change_id: ChangeId) -> Option<Uuid> {
+        let sources = self.0.sources.lock();
+        sources
+            .binary_search_by_key(&change_id, |(id, _)| *id)
+            .ok()
+            .map(|i| sources[i].1)
+    }
+
+    /// Site id of the node change `change_id` originates from, if the change
+    /// is recent enough to be remembered
+    pub fn change_origin(&self, change_id: ChangeId) -> Option<SiteId> {
+        let origins = self.0.origins.lock();
+        origins
+            .binary_search_by_key(&change_id, |(id, _)| *id)
+            .ok()
+            .map(|i| origins[i].1)
     }
 
     pub fn id(&self) -> Uuid {
@@ -305,12 +611,121 @@ impl MatcherHandle {
         &self.0.qualified_changes_table_name
     }
 
-    pub fn parsed_columns(&self) -> &[ResultColumn] {
-        &self.0.parsed.columns
+    /// The query's current columns, see `reshape`
+    pub fn columns(&self) -> MatcherColumns {
+        self.0.columns.read().clone()
     }
 
-    pub fn col_names(&self) -> &[CompactString] {
-        &self.0.col_names
+    /// Last change id emitted before the subscription was last rebound
+    pub fn rebound_at(&self) -> Option<ChangeId> {
+        match self.0.rebound_at.load(Ordering::Acquire) {
+            NOT_REBOUND => None,
+            change_id => Some(ChangeId(change_id)),
+        }
+    }
+
+    /// How long the matcher has held its current read snapshot, if it holds
+    /// one. It does while querying and matching changes, for as long as it
+    /// waits on subscribers to receive the resulting events.
+    pub fn snapshot_age(&self) -> Option<Duration> {
+        self.0
+            .snapshot
+            .held_since
+            .lock()
+            .map(|since| since.elapsed())
+    }
+
+    /// Makes the matcher release the snapshot it holds, if any, ending the
+    /// subscription w/ a `SNAPSHOT_EXPIRED` error. Returns whether it held one.
+    pub fn expire_snapshot(&self) -> bool {
+        let held = self.0.snapshot.held_since.lock().is_some();
+        if held {
+            self.0.snapshot.expired.store(true, Ordering::Release);
+            self.0.snapshot.expire.notify_waiters();
+        }
+        held
+    }
+
+    /// Prepares rebinding the subscription to `sql`, which must select the
+    /// same columns from the same tables, only its parameters can differ.
+    pub fn prepare_rebind(
+        &self,
+        schema: &Schema,
+        conn: &Connection,
+        sql: &str,
+    ) -> Result<Rebind, MatcherError> {
+        let query = MatcherQuery::prepare(self.0.id, schema, conn, sql)?;
+
+        if query.col_names != self.0.columns.read().names || query.pks != self.0.pks {
+            return Err(MatcherError::RebindMismatch);
+        }
+
+        Ok(Rebind {
+            sql: sql.to_owned(),
+            query,
+        })
+    }
+
+    /// Rebinds the subscription: subscribers receive a `QueryEvent::Rebound`
+    /// marker followed by a fresh snapshot, changes are then evaluated
+    /// against the new binding. Returns the change id of the marker.
+    pub async fn rebind(&self, rebind: Rebind) -> Result<ChangeId, MatcherError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .cmd_tx
+            .send(MatcherCmd::Rebind(rebind, tx))
+            .await
+            .map_err(|_| MatcherError::MatcherGone)?;
+        rx.await.map_err(|_| MatcherError::MatcherGone)?
+    }
+
+    /// Re-prepares the subscription's query against `schema`, e.g. `*` now
+    /// expanding to a column added since it was last prepared. If its result
+    /// columns changed, subscribers get a `QueryEvent::Rebound` marker and
+    /// the new columns, w/ the next schema generation, followed by a fresh
+    /// snapshot. Returns the change id of the marker in that case.
+    pub async fn reshape(&self, schema: Arc<Schema>) -> Result<Option<ChangeId>, MatcherError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .cmd_tx
+            .send(MatcherCmd::Reshape(schema, tx))
+            .await
+            .map_err(|_| MatcherError::MatcherGone)?;
+        rx.await.map_err(|_| MatcherError::MatcherGone)?
+    }
+
+    /// Purges changes past the retention right away, instead of waiting for
+    /// the next periodic purge. Returns how many were deleted.
+    pub async fn purge_changes(&self) -> Result<usize, MatcherError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .cmd_tx
+            .send(MatcherCmd::PurgeChanges(tx))
+            .await
+            .map_err(|_| MatcherError::MatcherGone)?;
+        rx.await.map_err(|_| MatcherError::MatcherGone)?
+    }
+
+    /// The gap between `from` and the oldest change still retained, if
+    /// changes after `from` were purged
+    pub fn resume_gap(
+        &self,
+        conn: &Connection,
+        from: ChangeId,
+    ) -> rusqlite::Result<Option<ResumeGap>> {
+        let earliest: Option<ChangeId> = conn
+            .prepare_cached(&format!(
+                "SELECT MIN({CHANGE_ID_COL}) FROM {}",
+                self.changes_table_name()
+            ))?
+            .query_row([], |row| row.get(0))?;
+
+        Ok(earliest
+            .filter(|earliest| from.0 + 1 < earliest.0)
+            .map(|earliest_available| ResumeGap {
+                requested: from,
+                earliest_available,
+            }))
     }
 
     pub fn cleanup(self, mut conn: Connection) -> rusqlite::Result<()> {
@@ -340,8 +755,16 @@ pub struct Matcher {
     pub qualified_changes_table_name: String,
     pub evt_tx: mpsc::Sender<QueryEvent>,
     pub cmd_rx: mpsc::Receiver<MatcherCmd>,
-    pub col_names: Vec<CompactString>,
     pub last_rowid: i64,
+    pub rebound_at: Arc<AtomicI64>,
+    pub changes_config: SubscriptionChangesConfig,
+    sources: ChangeSources,
+    origins: ChangeOrigins,
+    snapshot: Arc<Snapshot>,
+    activity: Arc<Activity>,
+    // re-prepared when the schema changes
+    sql: String,
+    columns: Arc<RwLock<MatcherColumns>>,
 }
 
 #[derive(Debug, Clone)]
@@ -350,17 +773,23 @@ pub struct MatcherStmt {
     temp_query: String,
 }
 
-const CHANGE_ID_COL: &str = "id";
-const CHANGE_TYPE_COL: &str = "type";
+/// A subscription's query, rewritten to be evaluated against changes
+#[derive(Debug)]
+struct MatcherQuery {
+    query: Stmt,
+    statements: HashMap<String, MatcherStmt>,
+    pks: IndexMap<String, Vec<String>>,
+    parsed: ParsedSelect,
+    col_names: Vec<CompactString>,
+}
 
-impl Matcher {
-    fn new(
+impl MatcherQuery {
+    fn prepare(
         id: Uuid,
         schema: &Schema,
         conn: &Connection,
-        evt_tx: mpsc::Sender<QueryEvent>,
         sql: &str,
-    ) -> Result<(Matcher, MatcherHandle), MatcherError> {
+    ) -> Result<Self, MatcherError> {
         let col_names: Vec<CompactString> = {
             conn.prepare(sql)?
                 .column_names()
@@ -496,10 +925,51 @@ impl Matcher {
             );
         }
 
+        Ok(Self {
+            query: stmt,
+            statements,
+            pks,
+            parsed,
+            col_names,
+        })
+    }
+}
+
+const CHANGE_ID_COL: &str = "id";
+const CHANGE_TYPE_COL: &str = "type";
+
+impl Matcher {
+    fn new(
+        id: Uuid,
+        schema: &Schema,
+        conn: &Connection,
+        evt_tx: mpsc::Sender<QueryEvent>,
+        sql: &str,
+        changes_config: SubscriptionChangesConfig,
+    ) -> Result<(Matcher, MatcherHandle), MatcherError> {
+        let MatcherQuery {
+            query,
+            statements,
+            pks,
+            parsed,
+            col_names,
+        } = MatcherQuery::prepare(id, schema, conn, sql)?;
+
+        let query_table = format!("query_{}", id.as_simple());
         let qualified_table_name = format!("subscriptions.{query_table}");
         let qualified_changes_table_name = format!("subscriptions.changes_{}", id.as_simple());
 
         let (cmd_tx, cmd_rx) = mpsc::channel(512);
+        let rebound_at = Arc::new(AtomicI64::new(NOT_REBOUND));
+        let sources = ChangeSources::default();
+        let origins = ChangeOrigins::default();
+        let snapshot = Arc::new(Snapshot::default());
+        let activity = Arc::new(Activity::new());
+        let columns = Arc::new(RwLock::new(MatcherColumns {
+            names: col_names,
+            schema_generation: 0,
+            count: parsed.columns.len(),
+        }));
 
         let handle = MatcherHandle(Arc::new(InnerMatcherHandle {
             id,
@@ -507,12 +977,18 @@ impl Matcher {
             parsed: parsed.clone(),
             qualified_table_name: qualified_table_name.clone(),
             qualified_changes_table_name: qualified_changes_table_name.clone(),
-            col_names: col_names.clone(),
+            columns: columns.clone(),
+            pks: pks.clone(),
+            rebound_at: rebound_at.clone(),
+            sources: sources.clone(),
+            origins: origins.clone(),
+            snapshot: snapshot.clone(),
+            activity: activity.clone(),
         }));
 
         let matcher = Self {
             id,
-            query: stmt,
+            query,
             statements,
             pks,
             parsed,
@@ -521,8 +997,15 @@ impl Matcher {
             query_table,
             evt_tx,
             cmd_rx,
-            col_names,
             last_rowid: 0,
+            rebound_at,
+            changes_config,
+            sources,
+            origins,
+            snapshot,
+            activity,
+            sql: sql.to_owned(),
+            columns,
         };
 
         Ok((matcher, handle))
@@ -534,10 +1017,37 @@ impl Matcher {
         conn: Connection,
         evt_tx: mpsc::Sender<QueryEvent>,
         sql: &str,
+        changes_config: SubscriptionChangesConfig,
     ) -> Result<MatcherHandle, MatcherError> {
-        let (matcher, handle) = Self::new(id, schema, &conn, evt_tx, sql)?;
+        let (matcher, handle) = Self::new(id, schema, &conn, evt_tx, sql, changes_config)?;
+
+        let stored: (Option<String>, u64) = conn
+            .prepare("SELECT columns, schema_generation FROM subscriptions.subs WHERE id = ?")?
+            .query_row([id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        let stored_names = stored.0.and_then(|json| {
+            serde_json::from_str::<Vec<CompactString>>(&json)
+                .map_err(|e| warn!(%id, "could not read stored subscription columns: {e}"))
+                .ok()
+        });
 
-        tokio::spawn(matcher.run_restore(conn));
+        let reshape = {
+            let mut columns = matcher.columns.write();
+            columns.schema_generation = stored.1;
+            match stored_names {
+                // prepared against the schema the stored rows were queried w/
+                Some(names) if names != columns.names => {
+                    columns.names = names;
+                    columns.count = stored_column_count(&conn, &matcher.qualified_table_name)?;
+                    Some(Rebind {
+                        sql: sql.to_owned(),
+                        query: MatcherQuery::prepare(id, schema, &conn, sql)?,
+                    })
+                }
+                _ => None,
+            }
+        };
+
+        tokio::spawn(matcher.run_restore(conn, reshape));
 
         Ok(handle)
     }
@@ -548,8 +1058,9 @@ impl Matcher {
         mut conn: Connection,
         evt_tx: mpsc::Sender<QueryEvent>,
         sql: &str,
+        changes_config: SubscriptionChangesConfig,
     ) -> Result<MatcherHandle, MatcherError> {
-        let (matcher, handle) = Self::new(id, schema, &conn, evt_tx, sql)?;
+        let (matcher, handle) = Self::new(id, schema, &conn, evt_tx, sql, changes_config)?;
 
         let mut tmp_cols = matcher
             .pks
@@ -598,8 +1109,8 @@ impl Matcher {
             tx.execute_batch(&create_temp_table)?;
 
             let inserted = tx.execute(
-                "INSERT INTO subscriptions.subs (id, sql) VALUES (?, ?);",
-                params![id, sql],
+                "INSERT INTO subscriptions.subs (id, sql, columns) VALUES (?, ?, ?);",
+                params![id, sql, columns_json(&matcher.columns.read().names)],
             )?;
 
             tx.commit()?;
@@ -616,7 +1127,7 @@ impl Matcher {
         Ok(handle)
     }
 
-    async fn run_restore(mut self, conn: Connection) {
+    async fn run_restore(mut self, mut conn: Connection, reshape: Option<Rebind>) {
         let init_res = block_in_place(|| {
             let mut prepped = conn.prepare(&format!(
                 "SELECT MAX(__corro_rowid) FROM {}",
@@ -626,6 +1137,15 @@ impl Matcher {
                 .query_row((), |row| row.get(0))
                 .optional()?
                 .unwrap_or_default();
+
+            let rebound_at: Option<i64> = conn
+                .prepare("SELECT rebound_at FROM subscriptions.subs WHERE id = ?")?
+                .query_row([self.id], |row| row.get(0))
+                .optional()?
+                .flatten();
+            self.rebound_at
+                .store(rebound_at.unwrap_or(NOT_REBOUND), Ordering::Release);
+
             Ok::<_, rusqlite::Error>(())
         });
 
@@ -640,21 +1160,42 @@ impl Matcher {
             return;
         }
 
+        // the schema changed the query's columns while the agent was down
+        if let Some(rebind) = reshape {
+            match block_in_place(|| self.handle_rebind(&mut conn, rebind)) {
+                Ok(change_id) => {
+                    info!(id = %self.id, "columns of restored subscription changed w/ the schema, snapshot refreshed at change {change_id}")
+                }
+                Err(MatcherError::EventReceiverClosed) => return,
+                Err(e) => error!(id = %self.id, "could not refresh restored subscription: {e}"),
+            }
+        }
+
         self.cmd_loop(conn).await
     }
 
     async fn cmd_loop(mut self, mut conn: Connection) {
-        let mut purge_changes_interval = tokio::time::interval(Duration::from_secs(300));
+        let mut purge_changes_interval =
+            tokio::time::interval(Duration::from_secs(self.changes_config.purge_interval_secs));
+        let idle_pause = self
+            .changes_config
+            .idle_pause_secs
+            .map(|secs| Duration::from_secs(secs.max(1)));
+        let idle_period = idle_pause.unwrap_or(Duration::from_secs(1));
+        let mut idle_interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + idle_period, idle_period);
         loop {
             enum Branch {
                 Cmd(MatcherCmd),
                 PurgeOldChanges,
+                CheckIdle,
             }
 
             let branch = tokio::select! {
                 biased;
                 Some(req) = self.cmd_rx.recv() => Branch::Cmd(req),
                 _ = purge_changes_interval.tick() => Branch::PurgeOldChanges,
+                _ = idle_interval.tick(), if idle_pause.is_some() => Branch::CheckIdle,
                 else => {
                     break;
                 }
@@ -662,30 +1203,96 @@ impl Matcher {
 
             match branch {
                 Branch::Cmd(req) => match req {
-                    MatcherCmd::ProcessChange(candidates) => {
-                        if let Err(e) = block_in_place(|| self.handle_change(&mut conn, candidates))
-                        {
+                    MatcherCmd::ProcessChange {
+                        candidates,
+                        resurrected,
+                        source,
+                        origin,
+                    } => {
+                        self.activity.touch();
+                        self.activity.evaluations.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = block_in_place(|| {
+                            self.handle_change(&mut conn, candidates, resurrected, source, origin)
+                        }) {
                             if matches!(e, MatcherError::EventReceiverClosed) {
                                 break;
                             }
+                            if matches!(e, MatcherError::SnapshotExpired) {
+                                self.end_expired().await;
+                                break;
+                            }
                             error!("could not handle change: {e}");
                         }
                     }
+                    MatcherCmd::Rebind(rebind, res_tx) => {
+                        self.activity.touch();
+                        let res = block_in_place(|| self.handle_rebind(&mut conn, rebind));
+                        let closed = matches!(
+                            res,
+                            Err(MatcherError::EventReceiverClosed | MatcherError::SnapshotExpired)
+                        );
+                        if let Err(e) = &res {
+                            error!("could not rebind subscription: {e}");
+                        }
+                        let expired = matches!(res, Err(MatcherError::SnapshotExpired));
+                        _ = res_tx.send(res);
+                        if expired {
+                            self.end_expired().await;
+                        }
+                        if closed {
+                            break;
+                        }
+                    }
+                    MatcherCmd::Reshape(schema, res_tx) => {
+                        let res = block_in_place(|| self.handle_reshape(&mut conn, &schema));
+                        let closed = matches!(
+                            res,
+                            Err(MatcherError::EventReceiverClosed | MatcherError::SnapshotExpired)
+                        );
+                        if let Err(e) = &res {
+                            error!("could not reshape subscription: {e}");
+                        }
+                        let expired = matches!(res, Err(MatcherError::SnapshotExpired));
+                        _ = res_tx.send(res);
+                        if expired {
+                            self.end_expired().await;
+                        }
+                        if closed {
+                            break;
+                        }
+                    }
+                    MatcherCmd::PurgeChanges(res_tx) => {
+                        let res = block_in_place(|| self.purge_changes(&mut conn));
+                        _ = res_tx.send(res.map_err(MatcherError::from));
+                    }
+                    MatcherCmd::Resume => {
+                        self.activity.touch();
+                        self.activity.evaluations.fetch_add(1, Ordering::Relaxed);
+                        increment_counter!("corro.subs.resumed");
+                        debug!(id = %self.id, "resuming idle subscription");
+                        if let Err(e) = block_in_place(|| self.catch_up(&mut conn)) {
+                            if matches!(e, MatcherError::EventReceiverClosed) {
+                                break;
+                            }
+                            if matches!(e, MatcherError::SnapshotExpired) {
+                                self.end_expired().await;
+                                break;
+                            }
+                            error!("could not catch up on changes: {e}");
+                        }
+                    }
                 },
+                Branch::CheckIdle => {
+                    if let Some(idle_pause) = idle_pause {
+                        if !self.activity.is_paused() && self.activity.idle_for() >= idle_pause {
+                            self.pause(&conn);
+                        }
+                    }
+                }
+                // nothing changed since it was paused
+                Branch::PurgeOldChanges if self.activity.is_paused() => {}
                 Branch::PurgeOldChanges => {
-                    let res = block_in_place(|| {
-                        let tx = conn.transaction()?;
-
-                        let deleted = tx
-                            .prepare_cached(&format!(
-                                "DELETE FROM {} WHERE id < (SELECT COALESCE(MAX(id),0) - 500 FROM {})",
-                                self.qualified_changes_table_name,
-                                self.qualified_changes_table_name
-                            ))?
-                            .execute([])?;
-
-                        tx.commit().map(|_| deleted)
-                    });
+                    let res = block_in_place(|| self.purge_changes(&mut conn));
 
                     match res {
                         Ok(deleted) => info!(
@@ -703,85 +1310,65 @@ impl Matcher {
         debug!(id = %self.id, "matcher loop is done");
     }
 
-    async fn run(mut self, mut conn: Connection) {
-        if let Err(e) = self
-            .evt_tx
-            .send(QueryEvent::Columns(self.col_names.clone()))
-            .await
-        {
-            error!("could not send back columns, probably means no receivers! {e}");
-            return;
-        }
-
-        let mut query_cols = vec![];
-        for i in 0..(self.parsed.columns.len()) {
-            query_cols.push(format!("col_{i}"));
+    // stops evaluating changes until one of the query's tables changes again
+    // or a subscriber shows up, releasing the memory its connection cached
+    fn pause(&self, conn: &Connection) {
+        self.activity.paused.store(true, Ordering::Release);
+        if let Err(e) = conn.execute_batch("PRAGMA shrink_memory") {
+            warn!(id = %self.id, "could not release memory of paused subscription: {e}");
         }
+        debug!(id = %self.id, "paused idle subscription");
+    }
 
-        let res = block_in_place(|| {
-            let tx = conn.transaction()?;
-
-            let mut stmt_str = Cmd::Stmt(self.query.clone()).to_string();
-            stmt_str.pop();
-
-            let mut tmp_cols = self
-                .pks
-                .values()
-                .flatten()
-                .cloned()
-                .collect::<Vec<String>>();
+    // the matcher stops after releasing an expired snapshot, its query table
+    // may be missing changes it was matching
+    async fn end_expired(&self) {
+        warn!(id = %self.id, "subscription held its snapshot for too long, ending it");
+        _ = self.evt_tx.send(QueryEvent::snapshot_expired()).await;
+    }
 
-            for i in 0..(self.parsed.columns.len()) {
-                let col_name = format!("col_{i}");
-                tmp_cols.push(col_name.clone());
+    // sends back an event while holding the snapshot, giving up if it expires
+    // while waiting on subscribers
+    fn send_holding(&self, evt: QueryEvent) -> Result<(), MatcherError> {
+        if self.snapshot.is_expired() {
+            return Err(MatcherError::SnapshotExpired);
+        }
+        futures::executor::block_on(async {
+            tokio::select! {
+                res = self.evt_tx.send(evt) => res.map_err(|_| MatcherError::EventReceiverClosed),
+                _ = self.snapshot.expired() => Err(MatcherError::SnapshotExpired),
             }
+        })
+    }
 
-            let insert_into = format!(
-                "INSERT INTO {} ({}) {} RETURNING __corro_rowid,{}",
-                self.qualified_table_name,
-                tmp_cols.join(","),
-                stmt_str,
-                query_cols.join(","),
-            );
+    // keeps the most recent `retention` changes, at least the last one so
+    // the earliest available change id is always known
+    fn purge_changes(&self, conn: &mut Connection) -> rusqlite::Result<usize> {
+        let tx = conn.transaction()?;
 
-            let mut last_rowid = 0;
+        let deleted = tx
+            .prepare_cached(&format!(
+                "DELETE FROM {table} WHERE {CHANGE_ID_COL} <= (SELECT COALESCE(MAX({CHANGE_ID_COL}),0) - ? FROM {table})",
+                table = self.qualified_changes_table_name,
+            ))?
+            .execute([self.changes_config.retention.max(1) as i64])?;
 
-            let elapsed = {
-                let mut prepped = tx.prepare(&insert_into)?;
+        tx.commit().map(|_| deleted)
+    }
 
-                let start = Instant::now();
-                let mut rows = prepped.query(())?;
-                let elapsed = start.elapsed();
+    async fn run(mut self, mut conn: Connection) {
+        let columns = self.columns.read().to_event();
+        if let Err(e) = self.evt_tx.send(columns).await {
+            error!("could not send back columns, probably means no receivers! {e}");
+            return;
+        }
 
-                loop {
-                    match rows.next() {
-                        Ok(Some(row)) => {
-                            let rowid: i64 = row.get(0)?;
-                            let cells = (1..=query_cols.len())
-                                .map(|i| row.get::<_, SqliteValue>(i))
-                                .collect::<rusqlite::Result<Vec<_>>>()?;
-
-                            if let Err(e) = self
-                                .evt_tx
-                                .blocking_send(QueryEvent::Row(RowId(rowid), cells))
-                            {
-                                error!("could not send back row: {e}");
-                                return Err(MatcherError::EventReceiverClosed);
-                            }
+        let res = block_in_place(|| {
+            let tx = conn.transaction()?;
+            let _snapshot = self.snapshot.hold();
 
-                            last_rowid = cmp::max(rowid, last_rowid);
-                        }
-                        Ok(None) => {
-                            // done!
-                            break;
-                        }
-                        Err(e) => {
-                            return Err(e.into());
-                        }
-                    }
-                }
-                elapsed
-            };
+            let (elapsed, last_rowid) =
+                self.insert_snapshot(&tx, &self.query, self.parsed.columns.len())?;
 
             tx.commit()?;
 
@@ -797,6 +1384,7 @@ impl Matcher {
                     .send(QueryEvent::EndOfQuery {
                         time: elapsed.as_secs_f64(),
                         change_id: Some(ChangeId(0)),
+                        coerced: vec![],
                     })
                     .await
                 {
@@ -804,6 +1392,10 @@ impl Matcher {
                     return;
                 }
             }
+            Err(MatcherError::SnapshotExpired) => {
+                self.end_expired().await;
+                return;
+            }
             Err(e) => {
                 _ = self
                     .evt_tx
@@ -824,18 +1416,259 @@ impl Matcher {
         self.cmd_loop(conn).await
     }
 
+    /// Inserts the results of `query` in the query table, sending them back
+    /// as rows. Returns how long the query took and the last inserted rowid.
+    fn insert_snapshot(
+        &self,
+        tx: &Transaction,
+        query: &Stmt,
+        col_count: usize,
+    ) -> Result<(Duration, i64), MatcherError> {
+        let mut query_cols = vec![];
+        for i in 0..col_count {
+            query_cols.push(format!("col_{i}"));
+        }
+
+        let mut stmt_str = Cmd::Stmt(query.clone()).to_string();
+        stmt_str.pop();
+
+        let mut tmp_cols = self
+            .pks
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<String>>();
+
+        for i in 0..col_count {
+            let col_name = format!("col_{i}");
+            tmp_cols.push(col_name.clone());
+        }
+
+        let insert_into = format!(
+            "INSERT INTO {} ({}) {} RETURNING __corro_rowid,{}",
+            self.qualified_table_name,
+            tmp_cols.join(","),
+            stmt_str,
+            query_cols.join(","),
+        );
+
+        let mut last_rowid = 0;
+
+        let elapsed = {
+            let mut prepped = tx.prepare(&insert_into)?;
+
+            let start = Instant::now();
+            let mut rows = prepped.query(())?;
+            let elapsed = start.elapsed();
+
+            loop {
+                match rows.next() {
+                    Ok(Some(row)) => {
+                        let rowid: i64 = row.get(0)?;
+                        let cells = (1..=query_cols.len())
+                            .map(|i| row.get::<_, SqliteValue>(i))
+                            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+                        if let Err(e) = self.send_holding(QueryEvent::Row(RowId(rowid), cells)) {
+                            error!("could not send back row: {e}");
+                            return Err(e);
+                        }
+
+                        last_rowid = cmp::max(rowid, last_rowid);
+                    }
+                    Ok(None) => {
+                        // done!
+                        break;
+                    }
+                    Err(e) => {
+                        return Err(e.into());
+                    }
+                }
+            }
+            elapsed
+        };
+
+        Ok((elapsed, last_rowid))
+    }
+
+    fn handle_rebind(
+        &mut self,
+        conn: &mut Connection,
+        rebind: Rebind,
+    ) -> Result<ChangeId, MatcherError> {
+        let tx = conn.transaction()?;
+        let snapshot = self.snapshot.hold();
+
+        // change ids keep increasing across bindings, the old changes are not
+        // deleted and subscribers catching up from before the rebind get a
+        // fresh snapshot anyway
+        let change_id: ChangeId = tx
+            .prepare(&format!(
+                "SELECT COALESCE(MAX({CHANGE_ID_COL}), 0) FROM {}",
+                self.qualified_changes_table_name
+            ))?
+            .query_row([], |row| row.get(0))?;
+
+        tx.prepare(&format!("DELETE FROM {}", self.qualified_table_name))?
+            .execute(())?;
+
+        // rebinding keeps the same columns, a schema change can alter them
+        let columns = {
+            let current = self.columns.read();
+            let names = rebind.query.col_names.clone();
+            let schema_generation = if names == current.names {
+                current.schema_generation
+            } else {
+                current.schema_generation + 1
+            };
+            MatcherColumns {
+                names,
+                schema_generation,
+                count: rebind.query.parsed.columns.len(),
+            }
+        };
+        let stored = stored_column_count(&tx, &self.qualified_table_name)?;
+        for i in stored..columns.count {
+            tx.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN col_{i}; ALTER TABLE {} ADD COLUMN col_{i};",
+                self.qualified_table_name, self.qualified_changes_table_name
+            ))?;
+        }
+
+        tx.prepare_cached(
+            "UPDATE subscriptions.subs SET sql = ?, rebound_at = ?, columns = ?, schema_generation = ? WHERE id = ?",
+        )?
+        .execute(params![
+            rebind.sql,
+            change_id,
+            columns_json(&columns.names),
+            columns.schema_generation,
+            self.id
+        ])?;
+
+        for evt in [QueryEvent::Rebound { change_id }, columns.to_event()] {
+            if let Err(e) = self.send_holding(evt) {
+                debug!("could not send back rebind event: {e}");
+                return Err(e);
+            }
+        }
+
+        let (elapsed, last_rowid) =
+            self.insert_snapshot(&tx, &rebind.query.query, columns.count)?;
+
+        tx.commit()?;
+        drop(snapshot);
+
+        let MatcherQuery {
+            query,
+            statements,
+            parsed,
+            ..
+        } = rebind.query;
+        self.query = query;
+        self.statements = statements;
+        self.parsed = parsed;
+        self.sql = rebind.sql;
+        self.last_rowid = cmp::max(self.last_rowid, last_rowid);
+        *self.columns.write() = columns;
+        self.rebound_at.store(change_id.0, Ordering::Release);
+
+        if let Err(e) = self.evt_tx.blocking_send(QueryEvent::EndOfQuery {
+            time: elapsed.as_secs_f64(),
+            change_id: Some(change_id),
+            coerced: vec![],
+        }) {
+            debug!("could not send back end of query event: {e}");
+            return Err(MatcherError::EventReceiverClosed);
+        }
+
+        debug!(id = %self.id, "rebound subscription at change {change_id}");
+
+        Ok(change_id)
+    }
+
+    fn handle_reshape(
+        &mut self,
+        conn: &mut Connection,
+        schema: &Schema,
+    ) -> Result<Option<ChangeId>, MatcherError> {
+        let query = MatcherQuery::prepare(self.id, schema, conn, &self.sql)?;
+
+        if query.col_names == self.columns.read().names
+            && query.parsed.columns.len() == self.parsed.columns.len()
+        {
+            return Ok(None);
+        }
+        if query.pks != self.pks {
+            return Err(MatcherError::ReshapeMismatch);
+        }
+
+        let rebind = Rebind {
+            sql: self.sql.clone(),
+            query,
+        };
+        self.handle_rebind(conn, rebind).map(Some)
+    }
+
+    // recorded before subscribers can see the change
+    fn record_source(&self, change_id: ChangeId, source: Option<Uuid>) {
+        let mut sources = self.sources.lock();
+        // ids of rolled back changes are handed out again
+        while matches!(sources.back(), Some((id, _)) if *id >= change_id) {
+            sources.pop_back();
+        }
+        if let Some(source) = source {
+            if sources.len() >= MAX_CHANGE_SOURCES {
+                sources.pop_front();
+            }
+            sources.push_back((change_id, source));
+        }
+    }
+
+    // like `record_source`, every change has an origin
+    fn record_origin(&self, change_id: ChangeId, origin: Option<SiteId>) {
+        let mut origins = self.origins.lock();
+        while matches!(origins.back(), Some((id, _)) if *id >= change_id) {
+            origins.pop_back();
+        }
+        if let Some(origin) = origin {
+            if origins.len() >= MAX_CHANGE_ORIGINS {
+                origins.pop_front();
+            }
+            origins.push_back((change_id, origin));
+        }
+    }
+
     fn handle_change(
         &mut self,
         conn: &mut Connection,
-        candidates: IndexMap<CompactString, Vec<Vec<SqliteValue>>>,
+        candidates: Candidates,
+        resurrected: Candidates,
+        source: Option<Uuid>,
+        origin: Option<SiteId>,
     ) -> Result<(), MatcherError> {
         let tx = conn.transaction()?;
+        let _snapshot = self.snapshot.hold();
 
         let tables = candidates.keys().cloned().collect::<Vec<_>>();
+        let resurrected_tables = resurrected.keys().cloned().collect::<Vec<_>>();
 
-        for (table, pks) in candidates {
-            let tmp_table_name = format!("subscription_{}_{table}", self.id.as_simple());
+        let candidates = candidates.into_iter().map(|(table, pks)| {
+            (
+                format!("subscription_{}_{table}", self.id.as_simple()),
+                table,
+                pks,
+            )
+        });
+        let resurrected = resurrected.into_iter().map(|(table, pks)| {
+            (
+                format!("subscription_{}_{table}_resurrected", self.id.as_simple()),
+                table,
+                pks,
+            )
+        });
 
+        for (tmp_table_name, table, pks) in candidates.chain(resurrected) {
             // create a temporary table to mix and match the data
             tx.prepare_cached(
                 // TODO: cache the statement's string somewhere, it's always the same!
@@ -869,166 +1702,487 @@ impl Matcher {
                 }
             };
 
-            let mut actual_cols = vec![];
-            let mut tmp_cols = self
-                .pks
+            // resurrected rows still in the results are deleted first, so
+            // they're inserted again as new rows
+            let resurrected = if resurrected_tables.contains(table) {
+                let table_pks = self
+                    .pks
+                    .get(table.as_str())
+                    .ok_or(MatcherError::MissingPrimaryKeys)?
+                    .join(",");
+                Some(format!(
+                    "({table_pks}) IN (SELECT {table_pks} FROM subscription_{}_{table}_resurrected)",
+                    self.id.as_simple(),
+                ))
+            } else {
+                None
+            };
+
+            if !self.apply_diff(
+                &tx,
+                &stmt.new_query,
+                &stmt.temp_query,
+                resurrected.as_deref(),
+                source,
+                origin,
+                &mut new_last_rowid,
+            )? {
+                return Ok(());
+            }
+        }
+
+        // clean up temporary tables immediately
+        for table in tables {
+            // TODO: reduce mistakes by computing this table name once
+            tx.prepare_cached(&format!(
+                "DROP TABLE subscription_{}_{table}",
+                self.id.as_simple(),
+            ))?
+            .execute(())?;
+            trace!("cleaned up subscription_{}_{table}", self.id.as_simple());
+        }
+        for table in resurrected_tables {
+            tx.prepare_cached(&format!(
+                "DROP TABLE subscription_{}_{table}_resurrected",
+                self.id.as_simple(),
+            ))?
+            .execute(())?;
+        }
+
+        tx.commit()?;
+
+        self.last_rowid = new_last_rowid;
+
+        Ok(())
+    }
+
+    // re-runs the whole query against the rows last sent back, which is as
+    // far as the matcher got before it was paused, sending back whatever
+    // changed since
+    fn catch_up(&mut self, conn: &mut Connection) -> Result<(), MatcherError> {
+        let tx = conn.transaction()?;
+        let _snapshot = self.snapshot.hold();
+
+        let mut query = Cmd::Stmt(self.query.clone()).to_string();
+        query.pop();
+
+        let mut stored_cols = self
+            .pks
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<String>>();
+        for i in 0..(self.parsed.columns.len()) {
+            stored_cols.push(format!("col_{i}"));
+        }
+        let stored = format!(
+            "SELECT {} FROM {}",
+            stored_cols.join(","),
+            self.qualified_table_name
+        );
+
+        let mut new_last_rowid = self.last_rowid;
+        if !self.apply_diff(&tx, &query, &stored, None, None, None, &mut new_last_rowid)? {
+            return Ok(());
+        }
+
+        tx.commit()?;
+
+        self.last_rowid = new_last_rowid;
+
+        Ok(())
+    }
+
+    // applies the difference between the results of `new_query` and the
+    // stored rows `temp_query` selects to the query table, recording and
+    // sending back each change. Rows matching `resurrected` are deleted
+    // first. Returns `false` when a row couldn't be read, the changes are
+    // then left uncommitted.
+    #[allow(clippy::too_many_arguments)]
+    fn apply_diff(
+        &self,
+        tx: &Transaction,
+        new_query: &str,
+        temp_query: &str,
+        resurrected: Option<&str>,
+        source: Option<Uuid>,
+        origin: Option<SiteId>,
+        new_last_rowid: &mut i64,
+    ) -> Result<bool, MatcherError> {
+        let mut actual_cols = vec![];
+        let mut tmp_cols = self
+            .pks
+            .values()
+            .flatten()
+            .cloned()
+            .collect::<Vec<String>>();
+        for i in 0..(self.parsed.columns.len()) {
+            let col_name = format!("col_{i}");
+            tmp_cols.push(col_name.clone());
+            actual_cols.push(col_name);
+        }
+
+        let sql = format!(
+            "INSERT INTO {} ({})
+                        SELECT * FROM (
+                            {}
+                            EXCEPT
+                            {}
+                        ) WHERE 1
+                    ON CONFLICT({})
+                        DO UPDATE SET
+                            {}
+                    RETURNING __corro_rowid,{}",
+            // insert into
+            self.qualified_table_name,
+            tmp_cols.join(","),
+            new_query,
+            temp_query,
+            self.pks
                 .values()
                 .flatten()
                 .cloned()
-                .collect::<Vec<String>>();
-            for i in 0..(self.parsed.columns.len()) {
-                let col_name = format!("col_{i}");
-                tmp_cols.push(col_name.clone());
-                actual_cols.push(col_name);
-            }
-
-            let sql = format!(
-                "INSERT INTO {} ({})
-                            SELECT * FROM (
-                                {}
-                                EXCEPT
-                                {}
-                            ) WHERE 1
-                        ON CONFLICT({})
-                            DO UPDATE SET
-                                {}
-                        RETURNING __corro_rowid,{}",
-                // insert into
+                .collect::<Vec<String>>()
+                .join(","),
+            (0..(self.parsed.columns.len()))
+                .map(|i| format!("col_{i} = excluded.col_{i}"))
+                .collect::<Vec<_>>()
+                .join(","),
+            actual_cols.join(",")
+        );
+
+        // println!("sql: {sql}");
+
+        let insert_prepped = tx.prepare_cached(&sql)?;
+
+        let sql = format!(
+            "
+                DELETE FROM {} WHERE ({}) in (SELECT {} FROM (
+                    {}
+                    EXCEPT
+                    {}
+                )) RETURNING __corro_rowid,{}
+            ",
+            // delete from
+            self.qualified_table_name,
+            self.pks
+                .values()
+                .flatten()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(","),
+            self.pks
+                .values()
+                .flatten()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(","),
+            temp_query,
+            new_query,
+            actual_cols.join(",")
+        );
+
+        let delete_prepped = tx.prepare_cached(&sql)?;
+
+        let resurrect_prepped = match resurrected {
+            Some(resurrected) => Some(tx.prepare_cached(&format!(
+                "DELETE FROM {} WHERE {resurrected} RETURNING __corro_rowid,{}",
                 self.qualified_table_name,
-                tmp_cols.join(","),
-                stmt.new_query,
-                stmt.temp_query,
-                self.pks
-                    .values()
-                    .flatten()
-                    .cloned()
-                    .collect::<Vec<String>>()
-                    .join(","),
-                (0..(self.parsed.columns.len()))
-                    .map(|i| format!("col_{i} = excluded.col_{i}"))
-                    .collect::<Vec<_>>()
-                    .join(","),
                 actual_cols.join(",")
-            );
+            ))?),
+            None => None,
+        };
+
+        let mut change_insert_stmt = tx.prepare_cached(&format!(
+            "INSERT INTO {} (__corro_rowid, {CHANGE_TYPE_COL}, {}) VALUES (?, ?, {}) RETURNING {CHANGE_ID_COL}",
+            self.qualified_changes_table_name,
+            actual_cols.join(","),
+            (0..actual_cols.len())
+                .map(|_i| "?")
+                .collect::<Vec<_>>()
+                .join(",")
+        ))?;
+
+        let statements = resurrect_prepped
+            .map(|prepped| (Some(ChangeType::Delete), prepped))
+            .into_iter()
+            .chain([
+                (None, insert_prepped),
+                (Some(ChangeType::Delete), delete_prepped),
+            ]);
+
+        for (change_type, mut prepped) in statements {
+            let col_count = prepped.column_count();
+
+            let mut rows = prepped.raw_query();
+
+            while let Ok(Some(row)) = rows.next() {
+                let rowid: RowId = row.get(0)?;
+
+                let change_type = change_type.unwrap_or({
+                    if rowid.0 > self.last_rowid {
+                        ChangeType::Insert
+                    } else {
+                        ChangeType::Update
+                    }
+                });
+
+                let change_type_u8 = change_type as u8;
+
+                *new_last_rowid = cmp::max(*new_last_rowid, rowid.0);
+
+                match (1..col_count)
+                    .map(|i| row.get::<_, SqliteValue>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()
+                {
+                    Ok(cells) => {
+                        let mut changes_cells: Vec<&dyn ToSql> = vec![&rowid, &change_type_u8];
+                        for cell in cells.iter() {
+                            trace!("inserting event cell: {cell:?}");
+                            changes_cells.push(cell);
+                        }
+                        trace!("inserting changes... cols: {}", changes_cells.len());
 
-            // println!("sql: {sql}");
+                        let change_id: ChangeId = change_insert_stmt
+                            .query_row(params_from_iter(changes_cells), |row| row.get(0))?;
 
-            let insert_prepped = tx.prepare_cached(&sql)?;
+                        trace!("got change id: {change_id}");
 
-            let sql = format!(
-                "
-                    DELETE FROM {} WHERE ({}) in (SELECT {} FROM (
-                        {}
-                        EXCEPT
-                        {}
-                    )) RETURNING __corro_rowid,{}
-                ",
-                // delete from
-                self.qualified_table_name,
-                self.pks
-                    .values()
-                    .flatten()
-                    .cloned()
-                    .collect::<Vec<String>>()
-                    .join(","),
-                self.pks
-                    .values()
-                    .flatten()
-                    .cloned()
-                    .collect::<Vec<String>>()
-                    .join(","),
-                stmt.temp_query,
-                stmt.new_query,
-                actual_cols.join(",")
-            );
+                        self.record_source(change_id, source);
+                        self.record_origin(change_id, origin);
+
+                        if let Err(e) = self.send_holding(QueryEvent::Change(
+                            change_type,
+                            rowid,
+                            cells,
+                            change_id,
+                        )) {
+                            debug!("could not send back row to matcher sub sender: {e}");
+                            return Err(e);
+                        }
+                    }
+                    Err(e) => {
+                        error!("could not deserialize row's cells: {e}");
+                        return Ok(false);
+                    }
+                }
+            }
+        }
 
-            let delete_prepped = tx.prepare_cached(&sql)?;
+        Ok(true)
+    }
+}
 
-            let mut change_insert_stmt = tx.prepare_cached(&format!(
-                "INSERT INTO {} (__corro_rowid, {CHANGE_TYPE_COL}, {}) VALUES (?, ?, {}) RETURNING {CHANGE_ID_COL}",
-                self.qualified_changes_table_name,
-                actual_cols.join(","),
-                (0..actual_cols.len())
-                    .map(|_i| "?")
-                    .collect::<Vec<_>>()
-                    .join(",")
-            ))?;
+/// Tables read by a `SELECT` in its `FROM` and `JOIN` clauses
+pub fn select_tables(sql: &str) -> Result<Vec<String>, MatcherError> {
+    let mut parser = Parser::new(sql.as_bytes());
 
-            for (mut change_type, mut prepped) in [
-                (None, insert_prepped),
-                (Some(ChangeType::Delete), delete_prepped),
-            ] {
-                let col_count = prepped.column_count();
+    let select = match parser.next()?.ok_or(MatcherError::StatementRequired)? {
+        Cmd::Stmt(Stmt::Select(select)) => select,
+        Cmd::Stmt(_) => return Err(MatcherError::UnsupportedStatement),
+        _ => return Err(MatcherError::StatementRequired),
+    };
+
+    let mut tables = vec![];
+    if let OneSelect::Select {
+        from: Some(ref from),
+        ..
+    } = select.body.select
+    {
+        let joined = from.joins.iter().flatten().map(|join| &join.table);
+        for table in from.select.as_deref().into_iter().chain(joined) {
+            if let SelectTable::Table(name, _, _) = table {
+                tables.push(name.name.0.clone());
+            }
+        }
+    }
 
-                let mut rows = prepped.raw_query();
+    Ok(tables)
+}
 
-                while let Ok(Some(row)) = rows.next() {
-                    let rowid: RowId = row.get(0)?;
+/// A subscription query whose positional params all appear in `pk = ?` terms
+/// of its top-level `WHERE` conjunction, e.g. `SELECT * FROM services WHERE
+/// node = ?`.
+///
+/// Subscriptions to such a query can share a single matcher for the query
+/// w/o those terms, each subscriber's params are then checked against the
+/// matched rows' constrained columns. Only primary key columns qualify, so a
+/// row never moves from a param set to another.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedPlan {
+    /// The query w/o its param terms, all param sets share it
+    pub sql: String,
+    /// Index of the result column constrained by each positional param
+    pub param_columns: Vec<usize>,
+}
 
-                    let change_type = change_type.take().unwrap_or({
-                        if rowid.0 > self.last_rowid {
-                            ChangeType::Insert
-                        } else {
-                            ChangeType::Update
-                        }
-                    });
+/// Extracts the shared plan of `sql`, if its shape is supported: a plain
+/// `SELECT` of columns w/o aggregation or `LIMIT`, filtered by `col = ?`
+/// terms on selected primary key columns.
+///
+/// Other params aren't looked for, the returned `sql` must be checked to not
+/// have any left.
+pub fn shared_plan(schema: &Schema, sql: &str) -> Result<Option<SharedPlan>, MatcherError> {
+    let mut parser = Parser::new(sql.as_bytes());
 
-                    let change_type_u8 = change_type as u8;
+    let mut select = match parser.next()?.ok_or(MatcherError::StatementRequired)? {
+        Cmd::Stmt(Stmt::Select(select)) => select,
+        Cmd::Stmt(_) => return Err(MatcherError::UnsupportedStatement),
+        _ => return Err(MatcherError::StatementRequired),
+    };
 
-                    new_last_rowid = cmp::max(new_last_rowid, rowid.0);
+    // rows of these depend on the rows other param sets filter out
+    if select.with.is_some() || select.body.compounds.is_some() || select.limit.is_some() {
+        return Ok(None);
+    }
 
-                    match (1..col_count)
-                        .map(|i| row.get::<_, SqliteValue>(i))
-                        .collect::<rusqlite::Result<Vec<_>>>()
-                    {
-                        Ok(cells) => {
-                            let mut changes_cells: Vec<&dyn ToSql> = vec![&rowid, &change_type_u8];
-                            for cell in cells.iter() {
-                                trace!("inserting event cell: {cell:?}");
-                                changes_cells.push(cell);
-                            }
-                            trace!("inserting changes... cols: {}", changes_cells.len());
+    let (columns, from, where_clause) = match &mut select.body.select {
+        OneSelect::Select {
+            columns,
+            from: Some(from),
+            where_clause,
+            group_by: None,
+            ..
+        } => (columns, from, where_clause),
+        _ => return Ok(None),
+    };
 
-                            let change_id: ChangeId = change_insert_stmt
-                                .query_row(params_from_iter(changes_cells), |row| row.get(0))?;
+    // tables by name or alias, in FROM / JOIN order
+    let mut tables: Vec<(&str, &Table)> = vec![];
+    let joined = from.joins.iter().flatten().map(|join| &join.table);
+    for table in from.select.as_deref().into_iter().chain(joined) {
+        let (name, alias) = match table {
+            SelectTable::Table(name, alias, _) => (name, alias),
+            _ => return Ok(None),
+        };
+        let table = schema
+            .tables
+            .get(name.name.0.as_str())
+            .ok_or_else(|| MatcherError::TableNotFound(name.name.0.clone()))?;
+        match alias {
+            Some(As::As(alias) | As::Elided(alias)) => tables.push((alias.0.as_str(), table)),
+            None => tables.push((name.name.0.as_str(), table)),
+        }
+    }
 
-                            trace!("got change id: {change_id}");
+    // resolves a column reference to its table and column names
+    let resolve = |expr: &Expr| -> Option<(String, String)> {
+        let (qualifier, name) = match expr {
+            Expr::Id(name) | Expr::Name(name) => (None, name),
+            Expr::Qualified(tbl_name, name) => (Some(tbl_name.0.as_str()), name),
+            _ => return None,
+        };
+        let name = unquote(&name.0).ok().unwrap_or(name.0.clone());
+        let mut found = tables.iter().filter(|(tbl_name, table)| {
+            qualifier.map_or(true, |qualifier| qualifier == *tbl_name)
+                && table.column(&name).is_some()
+        });
+        let (_, table) = found.next()?;
+        if found.next().is_some() {
+            // ambiguous
+            return None;
+        }
+        let column = table.column(&name)?;
+        Some((table.name.clone(), column.name.clone()))
+    };
 
-                            if let Err(e) = self.evt_tx.blocking_send(QueryEvent::Change(
-                                change_type,
-                                rowid,
-                                cells,
-                                change_id,
-                            )) {
-                                debug!("could not send back row to matcher sub sender: {e}");
-                                return Err(MatcherError::EventReceiverClosed);
-                            }
-                        }
-                        Err(e) => {
-                            error!("could not deserialize row's cells: {e}");
-                            return Ok(());
-                        }
-                    }
+    let mut result_columns = vec![];
+    for col in columns.iter() {
+        match col {
+            ResultColumn::Expr(expr, alias) => {
+                // anything but a column is a computed value
+                match resolve(expr) {
+                    Some(resolved) if alias.is_none() => result_columns.push(resolved),
+                    _ => return Ok(None),
+                }
+            }
+            // like the matcher, only expands the FROM table
+            ResultColumn::Star if tables.len() == 1 => {
+                let table = tables[0].1;
+                for name in table.columns.keys() {
+                    result_columns.push((table.name.clone(), name.clone()));
+                }
+            }
+            ResultColumn::TableStar(tbl_name) => {
+                let table = match tables.iter().find(|(name, _)| *name == tbl_name.0) {
+                    Some((_, table)) => table,
+                    None => return Ok(None),
+                };
+                for name in table.columns.keys() {
+                    result_columns.push((table.name.clone(), name.clone()));
                 }
             }
+            ResultColumn::Star => return Ok(None),
         }
+    }
 
-        // clean up temporary tables immediately
-        for table in tables {
-            // TODO: reduce mistakes by computing this table name once
-            tx.prepare_cached(&format!(
-                "DROP TABLE subscription_{}_{table}",
-                self.id.as_simple(),
-            ))?
-            .execute(())?;
-            trace!("cleaned up subscription_{}_{table}", self.id.as_simple());
+    let mut terms = vec![];
+    if let Some(expr) = where_clause.take() {
+        split_conjunction(expr, &mut terms);
+    }
+
+    let mut param_columns = vec![];
+    let mut rest = vec![];
+    for term in terms {
+        let column = match param_term(&term) {
+            Some(column) => column,
+            None => {
+                rest.push(term);
+                continue;
+            }
+        };
+        let resolved = match resolve(column) {
+            Some(resolved) => resolved,
+            None => return Ok(None),
+        };
+        let is_pk = schema
+            .tables
+            .get(&resolved.0)
+            .map_or(false, |table| table.pk.contains(&resolved.1));
+        if !is_pk {
+            return Ok(None);
         }
+        match result_columns.iter().position(|col| *col == resolved) {
+            Some(i) => param_columns.push(i),
+            None => return Ok(None),
+        }
+    }
 
-        tx.commit()?;
+    if param_columns.is_empty() {
+        return Ok(None);
+    }
 
-        self.last_rowid = new_last_rowid;
+    *where_clause = rest
+        .into_iter()
+        .reduce(|lhs, rhs| Expr::Binary(Box::new(lhs), Operator::And, Box::new(rhs)));
 
-        Ok(())
+    let mut sql = Cmd::Stmt(Stmt::Select(select)).to_string();
+    sql.pop();
+
+    Ok(Some(SharedPlan { sql, param_columns }))
+}
+
+fn split_conjunction(expr: Expr, terms: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary(lhs, Operator::And, rhs) => {
+            split_conjunction(*lhs, terms);
+            split_conjunction(*rhs, terms);
+        }
+        Expr::Parenthesized(mut exprs) if exprs.len() == 1 => {
+            split_conjunction(exprs.remove(0), terms)
+        }
+        expr => terms.push(expr),
+    }
+}
+
+// the column of a `col = ?` or `? = col` term
+fn param_term(expr: &Expr) -> Option<&Expr> {
+    let is_param =
+        |expr: &Expr| matches!(expr, Expr::Variable(var) if var.is_empty() || var == "?");
+    match expr {
+        Expr::Binary(lhs, Operator::Equals, rhs) if is_param(rhs) => Some(lhs.as_ref()),
+        Expr::Binary(lhs, Operator::Equals, rhs) if is_param(lhs) => Some(rhs.as_ref()),
+        _ => None,
     }
 }
 
@@ -1168,7 +2322,7 @@ fn extract_expr_columns(
             let mut found = None;
             for tbl in parsed.table_columns.keys() {
                 if let Some(tbl) = schema.tables.get(tbl) {
-                    if tbl.columns.contains_key(&check_col_name) {
+                    if tbl.column(&check_col_name).is_some() {
                         if found.is_some() {
                             return Err(MatcherError::QualificationRequired {
                                 col_name: check_col_name,
@@ -1198,7 +2352,7 @@ fn extract_expr_columns(
             let mut found = None;
             for tbl in parsed.table_columns.keys() {
                 if let Some(tbl) = schema.tables.get(tbl) {
-                    if tbl.columns.contains_key(&check_col_name) {
+                    if tbl.column(&check_col_name).is_some() {
                         if found.is_some() {
                             return Err(MatcherError::QualificationRequired {
                                 col_name: check_col_name,
@@ -1442,12 +2596,38 @@ pub enum MatcherError {
     Unpack(#[from] UnpackError),
     #[error("did not insert subscription")]
     InsertSub,
+    #[error("a rebound query must select the same columns from the same tables")]
+    RebindMismatch,
+    #[error("subscription matcher is gone")]
+    MatcherGone,
+    #[error("the subscription's primary keys changed w/ the schema")]
+    ReshapeMismatch,
+    #[error("{}", corro_api_types::SNAPSHOT_EXPIRED)]
+    SnapshotExpired,
+}
+
+// how many `col_N` columns a subscription's query table has, which can be
+// more than its query's current columns
+fn stored_column_count(conn: &Connection, qualified_table_name: &str) -> rusqlite::Result<usize> {
+    let table = qualified_table_name
+        .strip_prefix("subscriptions.")
+        .unwrap_or(qualified_table_name);
+    conn.prepare_cached(
+        "SELECT COUNT(*) FROM pragma_table_info(?, 'subscriptions') WHERE name GLOB 'col_*'",
+    )?
+    .query_row([table], |row| row.get(0))
+}
+
+fn columns_json(names: &[CompactString]) -> String {
+    serde_json::to_string(names).expect("column names always serialize")
 }
 
 pub fn migrate_subs(conn: &mut Connection) -> rusqlite::Result<()> {
-    let migrations: Vec<Box<dyn Migration>> = vec![Box::new(
-        init_subs_migration as fn(&Transaction) -> rusqlite::Result<()>,
-    )];
+    let migrations: Vec<Box<dyn Migration>> = vec![
+        Box::new(init_subs_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(subs_rebound_at_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(subs_columns_migration as fn(&Transaction) -> rusqlite::Result<()>),
+    ];
 
     crate::sqlite::migrate(conn, migrations)
 }
@@ -1471,6 +2651,22 @@ fn init_subs_migration(tx: &Transaction) -> rusqlite::Result<()> {
     Ok(())
 }
 
+fn subs_rebound_at_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    // last change id before the subscription was rebound to new params
+    tx.execute_batch("ALTER TABLE subs ADD COLUMN rebound_at INTEGER;")
+}
+
+fn subs_columns_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    // result columns as of the last snapshot, as a JSON array of names, to
+    // notice when the schema changed them while the agent was down
+    tx.execute_batch(
+        r#"
+            ALTER TABLE subs ADD COLUMN columns TEXT;
+            ALTER TABLE subs ADD COLUMN schema_generation INTEGER NOT NULL DEFAULT 0;
+        "#,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
@@ -1530,7 +2726,14 @@ mod tests {
         )?;
 
         let (tx, _rx) = mpsc::channel(1);
-        let handle = Matcher::create(id, &schema, matcher_conn, tx, sql)?;
+        let handle = Matcher::create(
+            id,
+            &schema,
+            matcher_conn,
+            tx,
+            sql,
+            SubscriptionChangesConfig::default(),
+        )?;
 
         let mut cleanup_conn = rusqlite::Connection::open(&db_path).expect("could not open conn");
 
@@ -1544,6 +2747,399 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_expire_snapshot() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        let schema_sql = "CREATE TABLE sw (pk TEXT NOT NULL PRIMARY KEY, sandwich TEXT);";
+        let mut schema = parse_sql(schema_sql)?;
+
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("test.db");
+        let subscriptions_db_path: Utf8PathBuf = tmpdir
+            .path()
+            .join("subscriptions.db")
+            .display()
+            .to_string()
+            .into();
+
+        {
+            let mut conn = Connection::open(&subscriptions_db_path)?;
+            migrate_subs(&mut conn)?;
+        }
+
+        let mut conn = CrConn::init(rusqlite::Connection::open(&db_path)?)?;
+        setup_conn(
+            &mut conn,
+            &[(subscriptions_db_path.clone(), "subscriptions".into())].into(),
+        )?;
+
+        {
+            let tx = conn.transaction()?;
+            apply_schema(&tx, &Schema::default(), &mut schema)?;
+            for i in 0..5 {
+                tx.execute(
+                    "INSERT INTO sw (pk, sandwich) VALUES (?, 'blt')",
+                    [format!("pk-{i}")],
+                )?;
+            }
+            tx.commit()?;
+        }
+
+        let mut matcher_conn = rusqlite::Connection::open(&db_path)?;
+        setup_conn(
+            &mut matcher_conn,
+            &[(subscriptions_db_path, "subscriptions".into())].into(),
+        )?;
+
+        // nothing is received, the matcher holds its snapshot while sending
+        // the first rows
+        let (tx, mut rx) = mpsc::channel(1);
+        let handle = Matcher::create(
+            Uuid::new_v4(),
+            &schema,
+            matcher_conn,
+            tx,
+            "SELECT sandwich FROM sw",
+            SubscriptionChangesConfig::default(),
+        )?;
+
+        let mut age = handle.snapshot_age();
+        for _ in 0..100 {
+            if age.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            age = handle.snapshot_age();
+        }
+        assert!(age.is_some());
+
+        assert!(handle.expire_snapshot());
+
+        let mut events = vec![];
+        while let Some(evt) = rx.recv().await {
+            events.push(evt);
+        }
+        assert!(matches!(events.first(), Some(QueryEvent::Columns { .. })));
+        assert!(
+            events.last().map_or(false, QueryEvent::is_snapshot_expired),
+            "{events:?}"
+        );
+        assert!(!events
+            .iter()
+            .any(|evt| matches!(evt, QueryEvent::EndOfQuery { .. })));
+
+        assert_eq!(handle.snapshot_age(), None);
+        assert!(!handle.expire_snapshot());
+
+        Ok(())
+    }
+
+    type TestResult = Result<(), Box<dyn std::error::Error + Send + Sync + 'static>>;
+
+    // a fresh db w/ `schema_sql` applied and the path of its subscriptions db
+    fn setup_db(
+        tmpdir: &tempfile::TempDir,
+        schema_sql: &str,
+    ) -> Result<(CrConn, Schema, Utf8PathBuf), Box<dyn std::error::Error + Send + Sync + 'static>>
+    {
+        let mut schema = parse_sql(schema_sql)?;
+
+        let subscriptions_db_path: Utf8PathBuf = tmpdir
+            .path()
+            .join("subscriptions.db")
+            .display()
+            .to_string()
+            .into();
+        {
+            let mut conn = Connection::open(&subscriptions_db_path)?;
+            migrate_subs(&mut conn)?;
+        }
+
+        let mut conn = CrConn::init(rusqlite::Connection::open(tmpdir.path().join("test.db"))?)?;
+        setup_conn(
+            &mut conn,
+            &[(subscriptions_db_path.clone(), "subscriptions".into())].into(),
+        )?;
+
+        {
+            let tx = conn.transaction()?;
+            apply_schema(&tx, &Schema::default(), &mut schema)?;
+            tx.commit()?;
+        }
+
+        Ok((conn, schema, subscriptions_db_path))
+    }
+
+    fn matcher_conn(
+        tmpdir: &tempfile::TempDir,
+        subscriptions_db_path: &Utf8PathBuf,
+    ) -> rusqlite::Result<Connection> {
+        let mut conn = rusqlite::Connection::open(tmpdir.path().join("test.db"))?;
+        setup_conn(
+            &mut conn,
+            &[(subscriptions_db_path.clone(), "subscriptions".into())].into(),
+        )?;
+        Ok(conn)
+    }
+
+    // runs `sql` in its own transaction, returning its db version
+    fn write(conn: &mut CrConn, sql: &str) -> rusqlite::Result<i64> {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.commit()?;
+        conn.query_row("SELECT crsql_db_version()", [], |row| row.get(0))
+    }
+
+    fn route(
+        conn: &CrConn,
+        db_version: i64,
+        handles: &[&MatcherHandle],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let changes = TableChanges::from_db_version(conn, db_version)?;
+        for handle in handles {
+            handle.process_changes(&changes, None)?;
+        }
+        Ok(())
+    }
+
+    async fn wait_for(mut cond: impl FnMut() -> bool) -> bool {
+        for _ in 0..100 {
+            if cond() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        cond()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_route_and_pause() -> TestResult {
+        let tmpdir = tempfile::tempdir()?;
+        let (mut conn, schema, subscriptions_db_path) = setup_db(
+            &tmpdir,
+            "CREATE TABLE t0 (id INTEGER NOT NULL PRIMARY KEY, v TEXT);
+            CREATE TABLE t1 (id INTEGER NOT NULL PRIMARY KEY, v TEXT);",
+        )?;
+
+        let config = SubscriptionChangesConfig {
+            idle_pause_secs: Some(1),
+            ..Default::default()
+        };
+
+        let mut subs = vec![];
+        for table in ["t0", "t1"] {
+            let (tx, mut rx) = mpsc::channel(10);
+            let handle = Matcher::create(
+                Uuid::new_v4(),
+                &schema,
+                matcher_conn(&tmpdir, &subscriptions_db_path)?,
+                tx,
+                &format!("SELECT id, v FROM {table}"),
+                config,
+            )?;
+            assert!(handle.references(table));
+            assert!(matches!(rx.recv().await, Some(QueryEvent::Columns { .. })));
+            assert!(matches!(
+                rx.recv().await,
+                Some(QueryEvent::EndOfQuery { .. })
+            ));
+            subs.push((handle, rx));
+        }
+        let (t0_sub, mut t0_rx) = subs.remove(0);
+        let (t1_sub, mut t1_rx) = subs.remove(0);
+
+        // only the subscription reading from t0 evaluates its changes
+        let db_version = write(&mut conn, "INSERT INTO t0 VALUES (1, 'a')")?;
+        route(&conn, db_version, &[&t0_sub, &t1_sub])?;
+        assert_eq!(
+            t0_rx.recv().await,
+            Some(QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(1),
+                vec![SqliteValue::Integer(1), SqliteValue::Text("a".into())],
+                ChangeId(1)
+            ))
+        );
+        assert_eq!(t0_sub.evaluations(), 1);
+        assert_eq!(t1_sub.evaluations(), 0);
+
+        assert!(wait_for(|| t0_sub.is_paused() && t1_sub.is_paused()).await);
+
+        // the insert never reaches the paused subscription, the update resumes
+        // it and it catches up on both
+        write(&mut conn, "INSERT INTO t0 VALUES (2, 'b')")?;
+        let db_version = write(&mut conn, "UPDATE t0 SET v = 'c' WHERE id = 1")?;
+        route(&conn, db_version, &[&t0_sub, &t1_sub])?;
+        assert!(!t0_sub.is_paused());
+
+        let mut caught_up = vec![];
+        for _ in 0..2 {
+            match t0_rx.recv().await {
+                Some(QueryEvent::Change(change_type, rowid, cells, _)) => {
+                    caught_up.push((change_type, rowid, cells))
+                }
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+        caught_up.sort_by_key(|(_, rowid, _)| rowid.0);
+        assert_eq!(
+            caught_up,
+            [
+                (
+                    ChangeType::Update,
+                    RowId(1),
+                    vec![SqliteValue::Integer(1), SqliteValue::Text("c".into())]
+                ),
+                (
+                    ChangeType::Insert,
+                    RowId(2),
+                    vec![SqliteValue::Integer(2), SqliteValue::Text("b".into())]
+                ),
+            ]
+        );
+        assert_eq!(t0_sub.evaluations(), 2);
+
+        // a subscriber showing up resumes it too, nothing changed
+        assert!(t1_sub.is_paused());
+        assert_eq!(t1_sub.evaluations(), 0);
+        t1_sub.touch()?;
+        assert!(!t1_sub.is_paused());
+        assert!(wait_for(|| t1_sub.evaluations() == 1).await);
+        assert!(t1_rx.try_recv().is_err());
+
+        Ok(())
+    }
+
+    // each matcher holds a connection w/ a few file descriptors open, this
+    // may need a higher limit than the default
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run w/ --ignored --nocapture"]
+    async fn bench_routing_500_subs_50_tables() -> TestResult {
+        const TABLES: usize = 50;
+        const SUBS_PER_TABLE: usize = 10;
+        const WRITES: usize = 200;
+
+        let tmpdir = tempfile::tempdir()?;
+        let schema_sql = (0..TABLES)
+            .map(|i| format!("CREATE TABLE t{i} (id INTEGER NOT NULL PRIMARY KEY, v TEXT);"))
+            .collect::<String>();
+        let (mut conn, schema, subscriptions_db_path) = setup_db(&tmpdir, &schema_sql)?;
+
+        let mut subs = vec![];
+        for table in 0..TABLES {
+            for n in 0..SUBS_PER_TABLE {
+                let (tx, mut rx) = mpsc::channel(WRITES * 2);
+                let handle = Matcher::create(
+                    Uuid::new_v4(),
+                    &schema,
+                    matcher_conn(&tmpdir, &subscriptions_db_path)?,
+                    tx,
+                    &format!("SELECT id, v FROM t{table} WHERE v != '{n}'"),
+                    SubscriptionChangesConfig::default(),
+                )?;
+                assert!(matches!(rx.recv().await, Some(QueryEvent::Columns { .. })));
+                assert!(matches!(
+                    rx.recv().await,
+                    Some(QueryEvent::EndOfQuery { .. })
+                ));
+                subs.push((table, handle, rx));
+            }
+        }
+        let handles: Vec<&MatcherHandle> = subs.iter().map(|(_, handle, _)| handle).collect();
+
+        let start = Instant::now();
+        for i in 0..WRITES {
+            let db_version = write(&mut conn, &format!("INSERT INTO t0 VALUES ({i}, 'x')"))?;
+            route(&conn, db_version, &handles)?;
+        }
+        let routed = start.elapsed();
+
+        for (table, handle, rx) in subs.iter_mut() {
+            if *table != 0 {
+                continue;
+            }
+            for _ in 0..WRITES {
+                assert!(matches!(rx.recv().await, Some(QueryEvent::Change(..))));
+            }
+            assert_eq!(handle.evaluations(), WRITES as u64);
+        }
+        let evaluated = start.elapsed();
+
+        for (table, handle, rx) in subs.iter_mut() {
+            if *table != 0 {
+                assert_eq!(handle.evaluations(), 0);
+                assert!(rx.try_recv().is_err());
+            }
+        }
+
+        println!(
+            "{WRITES} writes to 1 of {TABLES} tables w/ {} subscriptions: routed in {routed:?}, evaluated by the {SUBS_PER_TABLE} subscriptions reading from it in {evaluated:?}",
+            TABLES * SUBS_PER_TABLE
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_plan() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+        let schema = parse_sql(
+            "CREATE TABLE services (node TEXT NOT NULL, id TEXT NOT NULL, name TEXT, status TEXT, PRIMARY KEY (node, id));
+            CREATE TABLE checks (node TEXT NOT NULL, id TEXT NOT NULL, service_id TEXT, PRIMARY KEY (node, id));",
+        )?;
+
+        let plan = |sql: &str| shared_plan(&schema, sql);
+        let normalized = |sql: &str| {
+            normalize_sql(sql).map(|mut sql| {
+                sql.pop();
+                sql
+            })
+        };
+
+        assert_eq!(
+            plan("SELECT * FROM services WHERE node = ?")?,
+            Some(SharedPlan {
+                sql: normalized("SELECT * FROM services")?,
+                param_columns: vec![0],
+            })
+        );
+
+        // params on either side, among other terms
+        assert_eq!(
+            plan("SELECT name, id, node FROM services WHERE status = 'passing' AND ? = node AND (id = ?)")?,
+            Some(SharedPlan {
+                sql: normalized("SELECT name, id, node FROM services WHERE status = 'passing'")?,
+                param_columns: vec![2, 1],
+            })
+        );
+
+        assert_eq!(
+            plan("SELECT s.node, s.name, c.id FROM services s JOIN checks c ON c.node = s.node AND c.service_id = s.id WHERE s.node = ?")?,
+            Some(SharedPlan {
+                sql: normalized("SELECT s.node, s.name, c.id FROM services s JOIN checks c ON c.node = s.node AND c.service_id = s.id")?,
+                param_columns: vec![0],
+            })
+        );
+
+        for sql in [
+            // not a primary key
+            "SELECT * FROM services WHERE name = ?",
+            // not selected
+            "SELECT name FROM services WHERE node = ?",
+            "SELECT node AS n FROM services WHERE node = ?",
+            // rows depend on the other param sets
+            "SELECT node, count(*) FROM services WHERE node = ? GROUP BY node",
+            "SELECT * FROM services WHERE node = ? LIMIT 10",
+            // not a `col = ?` conjunction
+            "SELECT * FROM services WHERE node = ? OR id = ?",
+            "SELECT * FROM services WHERE node > ?",
+            "SELECT * FROM services",
+        ] {
+            assert_eq!(plan(sql)?, None, "{sql}");
+        }
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_diff() {
         let sql = "SELECT json_object(
@@ -1747,11 +3343,22 @@ mod tests {
 
         {
             let (tx, mut rx) = mpsc::channel(1);
-            let matcher = Matcher::create(id, &schema, matcher_conn, tx, sql).unwrap();
+            let matcher = Matcher::create(
+                id,
+                &schema,
+                matcher_conn,
+                tx,
+                sql,
+                SubscriptionChangesConfig::default(),
+            )
+            .unwrap();
 
             println!("matcher created w/ id: {}", id.as_simple());
 
-            assert!(matches!(rx.recv().await.unwrap(), QueryEvent::Columns(_)));
+            assert!(matches!(
+                rx.recv().await.unwrap(),
+                QueryEvent::Columns { .. }
+            ));
 
             let cells = vec![SqliteValue::Text("{\"targets\":[\"127.0.0.1:1\"],\"labels\":{\"__metrics_path__\":\"/1\",\"app\":null,\"vm_account_id\":null,\"instance\":\"m-1\"}}".into())];
 
@@ -1791,7 +3398,7 @@ mod tests {
             };
 
             println!("processing change...");
-            matcher.process_change(changes.as_slice()).unwrap();
+            matcher.process_change(changes.as_slice(), None).unwrap();
             println!("processed changes");
 
             let cells = vec![SqliteValue::Text("{\"targets\":[\"127.0.0.1:1\"],\"labels\":{\"__metrics_path__\":\"/1\",\"app\":null,\"vm_account_id\":null,\"instance\":\"m-3\"}}".into())];
@@ -1825,7 +3432,7 @@ mod tests {
                 changes
             };
 
-            matcher.process_change(changes.as_slice()).unwrap();
+            matcher.process_change(changes.as_slice(), None).unwrap();
 
             let cells = vec![SqliteValue::Text("{\"targets\":[\"127.0.0.1:1\"],\"labels\":{\"__metrics_path__\":\"/1\",\"app\":null,\"vm_account_id\":null,\"instance\":\"m-1\"}}".into())];
 
@@ -1857,7 +3464,7 @@ mod tests {
                 changes
             };
 
-            matcher.process_change(changes.as_slice()).unwrap();
+            matcher.process_change(changes.as_slice(), None).unwrap();
 
             let cells = vec![SqliteValue::Text("{\"targets\":[\"127.0.0.2:1\"],\"labels\":{\"__metrics_path__\":\"/1\",\"app\":null,\"vm_account_id\":null,\"instance\":\"m-3\"}}".into())];
 
@@ -1919,7 +3526,7 @@ mod tests {
                 changes
             };
 
-            matcher.process_change(changes.as_slice()).unwrap();
+            matcher.process_change(changes.as_slice(), None).unwrap();
 
             let start = Instant::now();
             for _ in range {
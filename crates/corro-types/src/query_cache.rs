@@ -0,0 +1,333 @@
+//! Results of `/v1/queries` requests made w/ `cache_ttl_ms`, served again
+//! to identical queries until their TTL runs out.
+//!
+//! Any change to a table a cached query reads drops it right away, local or
+//! replicated, so the TTL is only an upper bound on how long it's kept.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use metrics::{counter, increment_counter};
+use parking_lot::RwLock;
+use rusqlite::Connection;
+
+use crate::{api::Statement, pubsub::normalize_sql};
+
+/// Cached queries kept at most, the ones closest to expiring are evicted
+/// first
+pub const MAX_ENTRIES: usize = 256;
+/// Results serialized to more bytes aren't cached
+pub const MAX_ENTRY_BYTES: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Default)]
+pub struct QueryCache {
+    inner: RwLock<Inner>,
+    // set once a query asked to be cached, until then changes don't need to
+    // be looked at
+    used: AtomicBool,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, CachedQuery>,
+    // bumped by every invalidation
+    generation: u64,
+    // generation of the last invalidation of each table
+    invalidated: HashMap<String, u64>,
+    // generation of the last `clear`
+    cleared: u64,
+}
+
+#[derive(Debug)]
+struct CachedQuery {
+    body: Bytes,
+    tables: Vec<String>,
+    expires_at: Instant,
+}
+
+/// Taken before running a query, its result is only stored if none of the
+/// tables it read changed since
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Generation(u64);
+
+impl QueryCache {
+    /// Identifies `stmt` regardless of its SQL's formatting and the order of
+    /// its named params, `None` if its SQL doesn't parse as 1 statement
+    pub fn key(stmt: &Statement) -> Option<String> {
+        let sql = normalize_sql(stmt.query()).ok()?;
+        let params = match stmt {
+            Statement::Simple(_) => serde_json::Value::Null,
+            Statement::WithParams(_, params) | Statement::Registered { params, .. } => {
+                serde_json::to_value(params).ok()?
+            }
+            Statement::WithNamedParams(_, named) => {
+                serde_json::to_value(named.iter().collect::<BTreeMap<_, _>>()).ok()?
+            }
+            Statement::Verbose {
+                params,
+                named_params,
+                ..
+            } => serde_json::to_value((
+                params,
+                named_params
+                    .as_ref()
+                    .map(|named| named.iter().collect::<BTreeMap<_, _>>()),
+            ))
+            .ok()?,
+        };
+        Some(format!("{sql}\n{params}"))
+    }
+
+    /// The serialized events of the query cached under `key`, unless it
+    /// expired
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let now = Instant::now();
+        let hit = {
+            let inner = self.inner.read();
+            inner
+                .entries
+                .get(key)
+                .map(|cached| (cached.expires_at > now).then(|| cached.body.clone()))
+        };
+
+        match hit {
+            Some(Some(body)) => {
+                increment_counter!("corro.api.queries.cache.hits");
+                Some(body)
+            }
+            expired => {
+                if expired.is_some() {
+                    let mut inner = self.inner.write();
+                    if matches!(inner.entries.get(key), Some(cached) if cached.expires_at <= now) {
+                        inner.entries.remove(key);
+                    }
+                }
+                increment_counter!("corro.api.queries.cache.misses");
+                None
+            }
+        }
+    }
+
+    pub fn generation(&self) -> Generation {
+        self.used.store(true, Ordering::Relaxed);
+        Generation(self.inner.read().generation)
+    }
+
+    /// Whether any query was ever cached, or is about to be
+    pub fn in_use(&self) -> bool {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Caches `body` under `key` for `ttl`, unless one of `tables` changed
+    /// since `generation` was taken or `body` is too big. Returns whether it
+    /// was cached.
+    pub fn insert(
+        &self,
+        key: String,
+        generation: Generation,
+        tables: Vec<String>,
+        body: Bytes,
+        ttl: Duration,
+    ) -> bool {
+        if body.len() > MAX_ENTRY_BYTES || ttl.is_zero() {
+            return false;
+        }
+
+        let mut inner = self.inner.write();
+        let stale = inner.cleared > generation.0
+            || tables.iter().any(|table| {
+                inner
+                    .invalidated
+                    .get(table)
+                    .map_or(false, |invalidated| *invalidated > generation.0)
+            });
+        if stale {
+            return false;
+        }
+
+        let now = Instant::now();
+        if inner.entries.len() >= MAX_ENTRIES && !inner.entries.contains_key(&key) {
+            inner.entries.retain(|_, cached| cached.expires_at > now);
+            if inner.entries.len() >= MAX_ENTRIES {
+                if let Some(evicted) = inner
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, cached)| cached.expires_at)
+                    .map(|(key, _)| key.clone())
+                {
+                    inner.entries.remove(&evicted);
+                }
+            }
+        }
+
+        inner.entries.insert(
+            key,
+            CachedQuery {
+                body,
+                tables,
+                expires_at: now + ttl,
+            },
+        );
+        true
+    }
+
+    /// Drops the cached queries reading any of `tables`, returns how many
+    pub fn invalidate<'a, I>(&self, tables: I) -> usize
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut inner = self.inner.write();
+        inner.generation += 1;
+        let generation = inner.generation;
+
+        let mut dropped = 0;
+        for table in tables {
+            inner.invalidated.insert(table.to_owned(), generation);
+            let before = inner.entries.len();
+            inner
+                .entries
+                .retain(|_, cached| !cached.tables.iter().any(|read| read == table));
+            dropped += before - inner.entries.len();
+        }
+
+        if dropped > 0 {
+            counter!("corro.api.queries.cache.invalidations", dropped as u64);
+        }
+        dropped
+    }
+
+    /// Drops the cached queries reading tables changed by `db_version`
+    pub fn invalidate_db_version(
+        &self,
+        conn: &Connection,
+        db_version: i64,
+    ) -> rusqlite::Result<usize> {
+        let tables = conn
+            .prepare_cached(r#"SELECT DISTINCT "table" FROM crsql_changes WHERE db_version = ?"#)?
+            .query_map([db_version], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(self.invalidate(tables.iter().map(String::as_str)))
+    }
+
+    /// Drops every cached query, e.g. once the schema changed
+    pub fn clear(&self) {
+        let mut inner = self.inner.write();
+        inner.generation += 1;
+        inner.cleared = inner.generation;
+        inner.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_ignore_formatting_and_named_params_order() {
+        let key = |stmt: Statement| QueryCache::key(&stmt).unwrap();
+
+        assert_eq!(
+            key(Statement::Simple("SELECT count(*)  FROM tests".into())),
+            key(Statement::Simple("select count(*)\nfrom tests".into()))
+        );
+        assert_ne!(
+            key(Statement::WithParams(
+                "SELECT * FROM tests WHERE id = ?".into(),
+                vec![1i64.into()]
+            )),
+            key(Statement::WithParams(
+                "SELECT * FROM tests WHERE id = ?".into(),
+                vec![2i64.into()]
+            ))
+        );
+        assert_eq!(
+            key(Statement::WithNamedParams(
+                "SELECT * FROM tests WHERE id = :a AND text = :b".into(),
+                [
+                    (":a".to_owned(), 1i64.into()),
+                    (":b".to_owned(), "x".into())
+                ]
+                .into()
+            )),
+            key(Statement::WithNamedParams(
+                "SELECT * FROM tests WHERE id = :a AND text = :b".into(),
+                [
+                    (":b".to_owned(), "x".into()),
+                    (":a".to_owned(), 1i64.into())
+                ]
+                .into()
+            ))
+        );
+        assert!(QueryCache::key(&Statement::Simple("SELECT 1; SELECT 2".into())).is_none());
+    }
+
+    #[test]
+    fn invalidates_by_table() {
+        let cache = QueryCache::default();
+        let ttl = Duration::from_secs(60);
+
+        let generation = cache.generation();
+        assert!(cache.insert(
+            "a".into(),
+            generation,
+            vec!["tests".into()],
+            Bytes::from_static(b"a"),
+            ttl
+        ));
+        assert!(cache.insert(
+            "b".into(),
+            generation,
+            vec!["tests2".into()],
+            Bytes::from_static(b"b"),
+            ttl
+        ));
+        assert_eq!(cache.get("a").as_deref(), Some(&b"a"[..]));
+
+        assert_eq!(cache.invalidate(["tests"]), 1);
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.get("b").as_deref(), Some(&b"b"[..]));
+
+        // ran before the invalidation, its result might be stale
+        assert!(!cache.insert(
+            "a".into(),
+            generation,
+            vec!["tests".into()],
+            Bytes::from_static(b"a"),
+            ttl
+        ));
+        assert!(cache.insert(
+            "a".into(),
+            cache.generation(),
+            vec!["tests".into()],
+            Bytes::from_static(b"a"),
+            ttl
+        ));
+
+        assert!(cache.insert(
+            "c".into(),
+            cache.generation(),
+            vec![],
+            Bytes::from_static(b"c"),
+            Duration::from_nanos(1)
+        ));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(cache.get("c").is_none());
+        assert_eq!(cache.len(), 2);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+//! Per-client quotas on `/v1/transactions` requests, see `QuotaConfig`.
+//!
+//! Each client gets a token bucket per limit, holding up to `burst_secs` of
+//! its rate. Requests and statements are taken from their buckets before a
+//! transaction runs. The bytes of changes it generated are only known once
+//! it committed, they're charged afterwards and can leave the bucket in
+//! debt: the client's next requests are throttled until it's paid back.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::{
+    api::{QuotaKind, QuotaUsage, Throttled},
+    config::{QuotaConfig, QuotaLimits},
+};
+
+/// Past this many tracked clients, idle ones are forgotten
+const MAX_TRACKED_CLIENTS: usize = 1024;
+/// Clients w/o requests for this long have full buckets again
+const IDLE_CLIENT_SECS: u64 = 600;
+
+/// Who a request is accounted to: its bearer token, or else its source
+/// address. Tokens are only kept hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClientIdentity {
+    Token(u64),
+    Ip(IpAddr),
+    Unknown,
+}
+
+impl ClientIdentity {
+    pub fn new(token: Option<&str>, ip: Option<IpAddr>) -> Self {
+        match (token, ip) {
+            (Some(token), _) => ClientIdentity::Token(seahash::hash(token.as_bytes())),
+            (None, Some(ip)) => ClientIdentity::Ip(ip),
+            (None, None) => ClientIdentity::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientIdentity::Token(hash) => write!(f, "token:{hash:016x}"),
+            ClientIdentity::Ip(ip) => write!(f, "ip:{ip}"),
+            ClientIdentity::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+impl QuotaConfig {
+    /// Limits applying to `identity` and the name of the override they come
+    /// from, if any. Overrides match by token first, then by address.
+    pub fn limits_for(&self, identity: &ClientIdentity) -> (&QuotaLimits, Option<&str>) {
+        let found = match identity {
+            ClientIdentity::Token(hash) => self.overrides.iter().find(|o| {
+                o.token
+                    .as_ref()
+                    .is_some_and(|token| seahash::hash(token.expose().as_bytes()) == *hash)
+            }),
+            ClientIdentity::Ip(ip) => self
+                .overrides
+                .iter()
+                .find(|o| o.token.is_none() && o.ip == Some(*ip)),
+            ClientIdentity::Unknown => None,
+        };
+
+        match found {
+            Some(o) => (&o.limits, o.name.as_deref()),
+            None => (&self.limits, None),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Quotas {
+    clients: Mutex<HashMap<ClientIdentity, ClientQuota>>,
+}
+
+#[derive(Debug)]
+struct ClientQuota {
+    requests: Bucket,
+    statements: Bucket,
+    change_bytes: Bucket,
+    throttled: u64,
+    last_seen: Instant,
+}
+
+impl ClientQuota {
+    fn new(now: Instant) -> Self {
+        Self {
+            requests: Bucket::new(now),
+            statements: Bucket::new(now),
+            change_bytes: Bucket::new(now),
+            throttled: 0,
+            last_seen: now,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // None until first refilled, then starts out full
+    tokens: Option<f64>,
+    updated_at: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: None,
+            updated_at: now,
+        }
+    }
+
+    /// Refills at `rate` per second up to `burst_secs` of it, returning the
+    /// tokens available and the bucket's capacity
+    fn refill(&mut self, rate: f64, burst_secs: f64, now: Instant) -> (f64, f64) {
+        // a single request must always fit
+        let capacity = (rate * burst_secs).max(1.0);
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        let tokens = match self.tokens {
+            Some(tokens) => (tokens + elapsed * rate).min(capacity),
+            None => capacity,
+        };
+        self.tokens = Some(tokens);
+        self.updated_at = now;
+        (tokens, capacity)
+    }
+
+    /// How long until `n` tokens can be taken. More than the capacity can
+    /// be taken at once from a full bucket, leaving it in debt.
+    fn wait(&mut self, n: f64, rate: f64, burst_secs: f64, now: Instant) -> Option<Duration> {
+        let (tokens, capacity) = self.refill(rate, burst_secs, now);
+        let needed = n.min(capacity);
+        // a rate of 0 never refills
+        (tokens < needed)
+            .then(|| Duration::try_from_secs_f64((needed - tokens) / rate).unwrap_or(Duration::MAX))
+    }
+
+    fn take(&mut self, n: f64) {
+        if let Some(tokens) = self.tokens.as_mut() {
+            *tokens -= n;
+        }
+    }
+}
+
+impl Quotas {
+    /// Takes a request of `statements` statements from `identity`'s
+    /// buckets, unless one of them is short: nothing is taken then, and the
+    /// error says how long to wait.
+    pub fn admit(
+        &self,
+        config: &QuotaConfig,
+        identity: &ClientIdentity,
+        statements: usize,
+    ) -> Result<(), Throttled> {
+        self.admit_at(config, identity, statements, Instant::now())
+    }
+
+    fn admit_at(
+        &self,
+        config: &QuotaConfig,
+        identity: &ClientIdentity,
+        statements: usize,
+        now: Instant,
+    ) -> Result<(), Throttled> {
+        let (limits, _) = config.limits_for(identity);
+        let burst = config.burst_secs;
+
+        let mut clients = self.clients.lock();
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(identity) {
+            let idle = Duration::from_secs(IDLE_CLIENT_SECS);
+            clients.retain(|_, client| now.saturating_duration_since(client.last_seen) < idle);
+        }
+        let client = clients
+            .entry(*identity)
+            .or_insert_with(|| ClientQuota::new(now));
+        client.last_seen = now;
+
+        let checks = [
+            (QuotaKind::Requests, limits.requests_per_sec, 1.0),
+            (
+                QuotaKind::Statements,
+                limits.statements_per_sec,
+                statements as f64,
+            ),
+            // only checked for debt, the request's changes are charged later
+            (QuotaKind::ChangeBytes, limits.change_bytes_per_sec, 0.0),
+        ];
+
+        let mut throttled: Option<(QuotaKind, Duration)> = None;
+        for (kind, rate, n) in checks {
+            let Some(rate) = rate else { continue };
+            let bucket = match kind {
+                QuotaKind::Requests => &mut client.requests,
+                QuotaKind::Statements => &mut client.statements,
+                QuotaKind::ChangeBytes => &mut client.change_bytes,
+            };
+            if let Some(wait) = bucket.wait(n, rate, burst, now) {
+                // the longest wait is the one worth reporting
+                if throttled.map_or(true, |(_, longest)| wait > longest) {
+                    throttled = Some((kind, wait));
+                }
+            }
+        }
+
+        if let Some((kind, wait)) = throttled {
+            client.throttled += 1;
+            return Err(Throttled {
+                throttled: kind,
+                // never 0, the client would retry right away
+                retry_after_ms: u64::try_from(wait.as_millis()).unwrap_or(u64::MAX).max(1),
+            });
+        }
+
+        if limits.requests_per_sec.is_some() {
+            client.requests.take(1.0);
+        }
+        if limits.statements_per_sec.is_some() {
+            client.statements.take(statements as f64);
+        }
+
+        Ok(())
+    }
+
+    /// Charges `bytes` of changes generated by one of `identity`'s requests
+    pub fn charge_change_bytes(&self, config: &QuotaConfig, identity: &ClientIdentity, bytes: u64) {
+        let (limits, _) = config.limits_for(identity);
+        let Some(rate) = limits.change_bytes_per_sec else {
+            return;
+        };
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        if let Some(client) = clients.get_mut(identity) {
+            client.change_bytes.refill(rate, config.burst_secs, now);
+            client.change_bytes.take(bytes as f64);
+        }
+    }
+
+    /// What's left of each tracked client's quotas, negative when it's in debt
+    pub fn usage(&self, config: &QuotaConfig) -> Vec<QuotaUsage> {
+        self.usage_at(config, Instant::now())
+    }
+
+    fn usage_at(&self, config: &QuotaConfig, now: Instant) -> Vec<QuotaUsage> {
+        let burst = config.burst_secs;
+        let mut clients = self.clients.lock();
+
+        let mut usage: Vec<QuotaUsage> = clients
+            .iter_mut()
+            .map(|(identity, client)| {
+                let (limits, name) = config.limits_for(identity);
+                let mut left = |bucket: &mut Bucket, rate: Option<f64>| {
+                    rate.map(|rate| bucket.refill(rate, burst, now).0)
+                };
+                QuotaUsage {
+                    identity: identity.to_string(),
+                    name: name.map(str::to_owned),
+                    requests: left(&mut client.requests, limits.requests_per_sec),
+                    statements: left(&mut client.statements, limits.statements_per_sec),
+                    change_bytes: left(&mut client.change_bytes, limits.change_bytes_per_sec),
+                    throttled: client.throttled,
+                }
+            })
+            .collect();
+
+        usage.sort_by(|a, b| a.identity.cmp(&b.identity));
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::QuotaOverride;
+
+    fn config() -> QuotaConfig {
+        QuotaConfig {
+            limits: QuotaLimits {
+                requests_per_sec: None,
+                statements_per_sec: Some(10.0),
+                change_bytes_per_sec: Some(1000.0),
+            },
+            burst_secs: 1.0,
+            overrides: vec![QuotaOverride {
+                name: Some("consul".into()),
+                token: None,
+                ip: Some("127.0.0.1".parse().unwrap()),
+                limits: QuotaLimits::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn throttles_statements_until_refilled() {
+        let quotas = Quotas::default();
+        let config = config();
+        let client = ClientIdentity::new(Some("secret"), Some("10.0.0.1".parse().unwrap()));
+        let start = Instant::now();
+
+        assert!(quotas.admit_at(&config, &client, 6, start).is_ok());
+        assert!(quotas.admit_at(&config, &client, 4, start).is_ok());
+
+        let throttled = quotas.admit_at(&config, &client, 2, start).unwrap_err();
+        assert_eq!(throttled.throttled, QuotaKind::Statements);
+        assert_eq!(throttled.retry_after_ms, 200);
+
+        // nothing was taken while throttled
+        let later = start + Duration::from_millis(200);
+        assert!(quotas.admit_at(&config, &client, 2, later).is_ok());
+
+        // more statements than the bucket holds pass once it's full
+        let full = later + Duration::from_secs(1);
+        assert!(quotas.admit_at(&config, &client, 25, full).is_ok());
+        let throttled = quotas.admit_at(&config, &client, 1, full).unwrap_err();
+        assert_eq!(throttled.retry_after_ms, 1600);
+
+        let usage = quotas.usage_at(&config, full);
+        assert_eq!(usage.len(), 1);
+        assert!(usage[0].identity.starts_with("token:"), "{:?}", usage[0]);
+        assert!(!usage[0].identity.contains("secret"));
+        assert_eq!(usage[0].statements, Some(-15.0));
+        assert_eq!(usage[0].requests, None);
+        assert_eq!(usage[0].throttled, 2);
+    }
+
+    #[test]
+    fn throttles_change_bytes_in_debt() {
+        let quotas = Quotas::default();
+        let config = config();
+        let client = ClientIdentity::new(None, Some("10.0.0.2".parse().unwrap()));
+        let start = Instant::now();
+
+        assert!(quotas.admit_at(&config, &client, 1, start).is_ok());
+        quotas.charge_change_bytes(&config, &client, 1500);
+
+        let throttled = quotas
+            .admit_at(&config, &client, 1, Instant::now())
+            .unwrap_err();
+        assert_eq!(throttled.throttled, QuotaKind::ChangeBytes);
+        assert!(throttled.retry_after_ms <= 500, "{throttled:?}");
+
+        let paid_back = Instant::now() + Duration::from_millis(500);
+        assert!(quotas.admit_at(&config, &client, 1, paid_back).is_ok());
+    }
+
+    #[test]
+    fn overrides_exempt_clients() {
+        let quotas = Quotas::default();
+        let config = config();
+        let local = ClientIdentity::new(None, Some("127.0.0.1".parse().unwrap()));
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            assert!(quotas.admit_at(&config, &local, 100, now).is_ok());
+        }
+        quotas.charge_change_bytes(&config, &local, 1 << 30);
+        assert!(quotas.admit_at(&config, &local, 1, now).is_ok());
+
+        let usage = quotas.usage_at(&config, now);
+        assert_eq!(usage[0].identity, "ip:127.0.0.1");
+        assert_eq!(usage[0].name.as_deref(), Some("consul"));
+        assert_eq!(usage[0].statements, None);
+
+        // a token takes precedence over the address it comes from
+        let (limits, name) = config.limits_for(&ClientIdentity::new(
+            Some("t"),
+            Some("127.0.0.1".parse().unwrap()),
+        ));
+        assert_eq!(limits.statements_per_sec, Some(10.0));
+        assert_eq!(name, None);
+    }
+}
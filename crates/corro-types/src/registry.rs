@@ -0,0 +1,171 @@
+//! Named query templates, registered w/ `PUT /v1/queries/registered/{name}`
+//! and run by name w/ `Statement::Registered`.
+//!
+//! Templates are resolved when a query or subscription is created: updating
+//! a template doesn't affect subscriptions created from its previous text
+//! until they're resubscribed to.
+
+use std::{collections::HashMap, sync::Arc};
+
+use parking_lot::RwLock;
+use rusqlite::Connection;
+
+use crate::api::{QueryLimits, RegisteredQuery, Statement};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("no query registered as '{0}'")]
+    UnknownName(String),
+    #[error("registered query '{name}' expects {expected} params, got {got}")]
+    ParamCount {
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+    #[error("registered queries must be readonly")]
+    NotReadonly,
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryTemplate {
+    pub query: String,
+    pub limits: QueryLimits,
+    /// Number of placeholders in `query`
+    pub param_count: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct QueryRegistry(RwLock<HashMap<String, Arc<QueryTemplate>>>);
+
+impl QueryRegistry {
+    /// Validates `registered` by preparing it w/ `conn` and stores it under
+    /// `name`. Returns true if it replaced a previous template.
+    pub fn register(
+        &self,
+        conn: &Connection,
+        name: String,
+        registered: RegisteredQuery,
+    ) -> Result<bool, RegistryError> {
+        let param_count = {
+            let prepped = conn.prepare(&registered.query)?;
+            if !prepped.readonly() {
+                return Err(RegistryError::NotReadonly);
+            }
+            prepped.parameter_count()
+        };
+
+        let template = QueryTemplate {
+            query: registered.query,
+            limits: registered.limits,
+            param_count,
+        };
+
+        Ok(self.0.write().insert(name, Arc::new(template)).is_some())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<QueryTemplate>> {
+        self.0.read().get(name).cloned()
+    }
+
+    /// Swaps a `Statement::Registered` for its template's query bound to its
+    /// params, along w/ the template's limits. Other statements are returned
+    /// as-is, w/o limits.
+    pub fn resolve(
+        &self,
+        stmt: Statement,
+    ) -> Result<(Statement, Option<QueryLimits>), RegistryError> {
+        let (name, params) = match stmt {
+            Statement::Registered { name, params } => (name, params),
+            stmt => return Ok((stmt, None)),
+        };
+
+        let template = self
+            .get(&name)
+            .ok_or_else(|| RegistryError::UnknownName(name.clone()))?;
+
+        if params.len() != template.param_count {
+            return Err(RegistryError::ParamCount {
+                name,
+                expected: template.param_count,
+                got: params.len(),
+            });
+        }
+
+        Ok((
+            Statement::WithParams(template.query.clone(), params),
+            Some(template.limits),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::SqliteParam;
+
+    use super::*;
+
+    #[test]
+    fn registers_and_resolves_templates() -> Result<(), RegistryError> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch("CREATE TABLE foo (a INTEGER NOT NULL PRIMARY KEY, b TEXT);")?;
+
+        let registry = QueryRegistry::default();
+        let replaced = registry.register(
+            &conn,
+            "foo_by_a".into(),
+            RegisteredQuery::new("SELECT a, b FROM foo WHERE a = ?"),
+        )?;
+        assert!(!replaced);
+
+        let (stmt, limits) = registry.resolve(Statement::Registered {
+            name: "foo_by_a".into(),
+            params: vec![SqliteParam::Integer(1)],
+        })?;
+        assert_eq!(stmt.query(), "SELECT a, b FROM foo WHERE a = ?");
+        assert_eq!(limits, Some(QueryLimits::default()));
+
+        assert!(matches!(
+            registry.resolve(Statement::Registered {
+                name: "foo_by_a".into(),
+                params: vec![],
+            }),
+            Err(RegistryError::ParamCount {
+                expected: 1,
+                got: 0,
+                ..
+            })
+        ));
+        assert!(matches!(
+            registry.resolve(Statement::Registered {
+                name: "nope".into(),
+                params: vec![],
+            }),
+            Err(RegistryError::UnknownName(_))
+        ));
+
+        // other statements pass through
+        let (stmt, limits) = registry.resolve("SELECT 1".into())?;
+        assert_eq!(stmt.query(), "SELECT 1");
+        assert_eq!(limits, None);
+
+        assert!(matches!(
+            registry.register(
+                &conn,
+                "delete_foo".into(),
+                RegisteredQuery::new("DELETE FROM foo")
+            ),
+            Err(RegistryError::NotReadonly)
+        ));
+
+        assert!(registry.register(
+            &conn,
+            "foo_by_a".into(),
+            RegisteredQuery::new("SELECT a FROM foo WHERE a = ? AND b = ?"),
+        )?);
+        assert_eq!(registry.get("foo_by_a").unwrap().param_count, 2);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,316 @@
+//! Sampling of identical errors, not to flood logs when a misconfigured
+//! client retries a bad query in a loop, or a dependency stays down.
+//!
+//! Errors are identical when they have the same code, the same message
+//! prefix and come from the same client. Within a window, the first one is
+//! logged in full, then only one every `every`. The others are counted, and
+//! summarized once the window ends, along w/ the last of them in full.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use compact_str::CompactString;
+use parking_lot::Mutex;
+
+use crate::quota::ClientIdentity;
+
+/// Chars of a message errors are compared by, past them they only differ by
+/// details (ids, values...)
+const MESSAGE_PREFIX_CHARS: usize = 64;
+/// Past this many tracked errors, new ones are logged w/o sampling
+const MAX_TRACKED_ERRORS: usize = 1024;
+
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_EVERY: u64 = 100;
+
+/// What identical errors share
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ErrorKey {
+    pub code: CompactString,
+    pub prefix: CompactString,
+    pub client: Option<ClientIdentity>,
+}
+
+impl ErrorKey {
+    pub fn new(
+        code: impl Into<CompactString>,
+        message: &str,
+        client: Option<ClientIdentity>,
+    ) -> Self {
+        Self {
+            code: code.into(),
+            prefix: message.chars().take(MESSAGE_PREFIX_CHARS).collect(),
+            client,
+        }
+    }
+}
+
+/// Whether an occurrence should be logged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sample {
+    /// In full: the first of its window, or a sampled one
+    Log,
+    /// Only counted, until its window's summary
+    Suppressed,
+}
+
+/// Errors suppressed in a window which ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub key: ErrorKey,
+    pub suppressed: u64,
+    /// Last occurrence of the window, unless it was logged already
+    pub last: Option<CompactString>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "suppressed {} identical errors", self.suppressed)?;
+        if let Some(client) = self.key.client {
+            write!(f, " from {client}")?;
+        }
+        match &self.last {
+            Some(last) => write!(f, ", last one: {last}"),
+            None => write!(f, ": {}", self.key.prefix),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    started_at: Instant,
+    count: u64,
+    suppressed: u64,
+    last: Option<CompactString>,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Self {
+            started_at: now,
+            count: 1,
+            suppressed: 0,
+            last: None,
+        }
+    }
+
+    fn summary(self, key: ErrorKey) -> Option<Summary> {
+        (self.suppressed > 0).then(|| Summary {
+            key,
+            suppressed: self.suppressed,
+            last: self.last,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ErrorSampler {
+    window: Duration,
+    every: u64,
+    windows: Mutex<HashMap<ErrorKey, Window>>,
+}
+
+impl Default for ErrorSampler {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW, DEFAULT_EVERY)
+    }
+}
+
+impl ErrorSampler {
+    /// Logs the first error of each `window`, then one every `every`
+    pub fn new(window: Duration, every: u64) -> Self {
+        Self {
+            window,
+            every: every.max(1),
+            windows: Default::default(),
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// Counts an occurrence of `message`, returning whether to log it and
+    /// the summary of the previous window of identical errors if it ended.
+    /// The summary should be logged first.
+    pub fn record(&self, key: ErrorKey, message: &str, now: Instant) -> (Sample, Option<Summary>) {
+        let mut windows = self.windows.lock();
+
+        let Some(window) = windows.get_mut(&key) else {
+            if windows.len() < MAX_TRACKED_ERRORS {
+                windows.insert(key, Window::new(now));
+            }
+            return (Sample::Log, None);
+        };
+
+        if now.saturating_duration_since(window.started_at) >= self.window {
+            let ended = std::mem::replace(window, Window::new(now));
+            return (Sample::Log, ended.summary(key));
+        }
+
+        window.count += 1;
+        if (window.count - 1) % self.every == 0 {
+            window.last = None;
+            (Sample::Log, None)
+        } else {
+            window.suppressed += 1;
+            window.last = Some(message.into());
+            (Sample::Suppressed, None)
+        }
+    }
+
+    /// Ends the windows which lasted long enough, returning the summaries of
+    /// the ones which suppressed errors. To be called periodically, the last
+    /// occurrences of errors which stopped would never be logged otherwise.
+    pub fn flush(&self, now: Instant) -> Vec<Summary> {
+        let mut summaries = vec![];
+        self.windows.lock().retain(|key, window| {
+            if now.saturating_duration_since(window.started_at) < self.window {
+                return true;
+            }
+            if window.suppressed > 0 {
+                summaries.push(Summary {
+                    key: key.clone(),
+                    suppressed: window.suppressed,
+                    last: window.last.take(),
+                });
+            }
+            false
+        });
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(message: &str) -> ErrorKey {
+        ErrorKey::new("400", message, Some(ClientIdentity::new(Some("t"), None)))
+    }
+
+    #[test]
+    fn samples_within_a_window() {
+        let sampler = ErrorSampler::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+
+        let samples: Vec<_> = (0..25)
+            .map(|i| sampler.record(key("no such table: foo"), &format!("#{i}"), now))
+            .collect();
+        let logged: Vec<_> = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, (sample, _))| *sample == Sample::Log)
+            .map(|(i, _)| i)
+            .collect();
+        // first, then every 10th
+        assert_eq!(logged, [0, 10, 20]);
+        assert!(samples.iter().all(|(_, summary)| summary.is_none()));
+
+        // other messages, codes or clients aren't identical
+        let now = now + Duration::from_secs(1);
+        assert_eq!(
+            sampler.record(key("no such column: bar"), "", now).0,
+            Sample::Log
+        );
+        assert_eq!(
+            sampler
+                .record(ErrorKey::new("500", "no such table: foo", None), "", now)
+                .0,
+            Sample::Log
+        );
+        // ... unless they only differ after their prefix
+        let long = "x".repeat(MESSAGE_PREFIX_CHARS);
+        assert_eq!(
+            sampler.record(key(&format!("{long}1")), "", now).0,
+            Sample::Log
+        );
+        assert_eq!(
+            sampler.record(key(&format!("{long}2")), "", now).0,
+            Sample::Suppressed
+        );
+    }
+
+    #[test]
+    fn summarizes_and_resets_when_the_window_ends() {
+        let sampler = ErrorSampler::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+
+        for i in 0..15 {
+            sampler.record(key("boom"), &format!("boom #{i}"), now);
+        }
+
+        let (sample, summary) =
+            sampler.record(key("boom"), "boom again", now + Duration::from_secs(60));
+        // first of a new window
+        assert_eq!(sample, Sample::Log);
+        assert_eq!(
+            summary,
+            Some(Summary {
+                key: key("boom"),
+                suppressed: 13,
+                last: Some("boom #14".into()),
+            })
+        );
+        assert_eq!(
+            summary.unwrap().to_string(),
+            format!(
+                "suppressed 13 identical errors from {}, last one: boom #14",
+                ClientIdentity::new(Some("t"), None)
+            )
+        );
+
+        // counting again from the start of the window
+        let now = now + Duration::from_secs(61);
+        assert_eq!(sampler.record(key("boom"), "", now).0, Sample::Suppressed);
+
+        // nothing suppressed, nothing to summarize
+        let (sample, summary) = sampler.record(key("boom"), "", now + Duration::from_secs(120));
+        assert_eq!(sample, Sample::Log);
+        assert_eq!(summary.map(|s| s.suppressed), Some(1));
+        let (_, summary) = sampler.record(key("boom"), "", now + Duration::from_secs(240));
+        assert_eq!(summary, None);
+    }
+
+    #[test]
+    fn flushes_ended_windows() {
+        let sampler = ErrorSampler::new(Duration::from_secs(60), 10);
+        let now = Instant::now();
+
+        for i in 0..10 {
+            sampler.record(key("boom"), &format!("boom #{i}"), now);
+        }
+        sampler.record(key("once"), "once", now);
+        sampler.record(key("later"), "later", now + Duration::from_secs(30));
+
+        assert!(sampler.flush(now + Duration::from_secs(59)).is_empty());
+
+        // the last one was suppressed, it's logged w/ the summary
+        let summaries = sampler.flush(now + Duration::from_secs(60));
+        assert_eq!(
+            summaries,
+            [Summary {
+                key: key("boom"),
+                suppressed: 9,
+                last: Some("boom #9".into()),
+            }]
+        );
+        // forgotten, logged in full again
+        assert_eq!(
+            sampler
+                .record(key("once"), "", now + Duration::from_secs(61))
+                .0,
+            Sample::Log
+        );
+        // still within its window
+        assert_eq!(
+            sampler
+                .record(key("later"), "", now + Duration::from_secs(61))
+                .0,
+            Sample::Suppressed
+        );
+    }
+}
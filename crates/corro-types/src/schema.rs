@@ -9,8 +9,9 @@ use indexmap::{IndexMap, IndexSet};
 use rusqlite::{Connection, Transaction};
 use serde::{Deserialize, Serialize};
 use sqlite3_parser::ast::{
-    Cmd, ColumnConstraint, ColumnDefinition, CreateTableBody, Expr, Name, NamedTableConstraint,
-    QualifiedName, SortedColumn, Stmt, TableConstraint, TableOptions, ToTokens,
+    AlterTableBody, Cmd, ColumnConstraint, ColumnDefinition, CreateTableBody, Expr, Name,
+    NamedTableConstraint, QualifiedName, SortedColumn, Stmt, TableConstraint, TableOptions,
+    ToTokens,
 };
 use tracing::{debug, info, trace};
 
@@ -76,6 +77,18 @@ pub struct Table {
     pub raw: CreateTableBody,
 }
 
+impl Table {
+    /// Looks up a column the way sqlite resolves identifiers: ignoring ASCII
+    /// case, preferring an exact match
+    pub fn column(&self, name: &str) -> Option<&Column> {
+        self.columns.get(name).or_else(|| {
+            self.columns
+                .values()
+                .find(|col| col.name.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
 impl fmt::Display for Table {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         Cmd::Stmt(Stmt::CreateTable {
@@ -624,6 +637,37 @@ pub fn apply_schema(
     Ok(())
 }
 
+/// Creates a table which isn't replicated, along w/ its indexes, if it
+/// doesn't exist yet. Existing local tables are left as-is.
+pub fn create_local_table(tx: &Transaction, table: &Table) -> rusqlite::Result<()> {
+    info!("creating local-only table '{}'", table.name);
+    tx.execute_batch(
+        &Cmd::Stmt(Stmt::CreateTable {
+            temporary: false,
+            if_not_exists: true,
+            tbl_name: QualifiedName::single(Name(table.name.clone())),
+            body: table.raw.clone(),
+        })
+        .to_string(),
+    )?;
+
+    for (idx_name, index) in table.indexes.iter() {
+        tx.execute_batch(
+            &Cmd::Stmt(Stmt::CreateIndex {
+                unique: index.unique,
+                if_not_exists: true,
+                idx_name: QualifiedName::single(Name(idx_name.clone())),
+                tbl_name: Name(index.tbl_name.clone()),
+                columns: index.columns.clone(),
+                where_clause: index.where_clause.clone(),
+            })
+            .to_string(),
+        )?;
+    }
+
+    Ok(())
+}
+
 #[allow(clippy::result_large_err)]
 pub fn parse_sql_to_schema(schema: &mut Schema, sql: &str) -> Result<(), SchemaError> {
     trace!("parsing {sql}");
@@ -696,6 +740,78 @@ pub fn parse_sql_to_schema(schema: &mut Schema, sql: &str) -> Result<(), SchemaE
     Ok(())
 }
 
+/// The schema once the DDL statement `sql` is applied on top of `schema`,
+/// along w/ the name of the table it changes. `CREATE TABLE`, `CREATE INDEX`
+/// and `ALTER TABLE ... ADD COLUMN` are supported, dropping or otherwise
+/// altering tables of `schema` errors.
+///
+/// `None` if `sql` doesn't change `schema`, it can run as-is.
+#[allow(clippy::result_large_err)]
+pub fn schema_after(schema: &Schema, sql: &str) -> Result<Option<(String, Schema)>, SchemaError> {
+    let mut parser = sqlite3_parser::lexer::sql::Parser::new(sql.as_bytes());
+    // sqlite reports whatever doesn't parse when running it
+    let Ok(Some(cmd)) = parser.next() else {
+        return Ok(None);
+    };
+    let Cmd::Stmt(stmt) = &cmd else {
+        return Ok(None);
+    };
+
+    match stmt {
+        Stmt::CreateTable { tbl_name, .. } => {
+            let mut new_schema = schema.clone();
+            parse_sql_to_schema(&mut new_schema, sql)?;
+            Ok(Some((tbl_name.name.0.clone(), new_schema)))
+        }
+        // indexes of local-only tables
+        Stmt::CreateIndex { tbl_name, .. } if !schema.tables.contains_key(&tbl_name.0) => Ok(None),
+        Stmt::CreateIndex { tbl_name, .. } => {
+            let mut new_schema = schema.clone();
+            parse_sql_to_schema(&mut new_schema, sql)?;
+            Ok(Some((tbl_name.0.clone(), new_schema)))
+        }
+        Stmt::AlterTable(tbl_name, body) => {
+            let Some(table) = schema.tables.get(&tbl_name.name.0) else {
+                return Ok(None);
+            };
+            let (AlterTableBody::AddColumn(def), CreateTableBody::ColumnsAndConstraints { .. }) =
+                (body, &table.raw)
+            else {
+                return Err(SchemaError::UnsupportedCmd(cmd.clone()));
+            };
+
+            let mut raw = table.raw.clone();
+            if let CreateTableBody::ColumnsAndConstraints { columns, .. } = &mut raw {
+                columns.push(def.clone());
+            }
+            let altered = Table {
+                raw,
+                ..table.clone()
+            };
+
+            let mut new_schema = schema.clone();
+            parse_sql_to_schema(&mut new_schema, &altered.to_string())?;
+            // re-parsing the table dropped its indexes
+            if let Some(new_table) = new_schema.tables.get_mut(&table.name) {
+                new_table.indexes = table.indexes.clone();
+            }
+            Ok(Some((table.name.clone(), new_schema)))
+        }
+        Stmt::DropTable { tbl_name, .. } if schema.tables.contains_key(&tbl_name.name.0) => {
+            Err(SchemaError::UnsupportedCmd(cmd.clone()))
+        }
+        Stmt::DropIndex { idx_name, .. }
+            if schema
+                .tables
+                .values()
+                .any(|table| table.indexes.contains_key(&idx_name.name.0)) =>
+        {
+            Err(SchemaError::UnsupportedCmd(cmd.clone()))
+        }
+        _ => Ok(None),
+    }
+}
+
 #[allow(clippy::result_large_err)]
 pub fn parse_sql(sql: &str) -> Result<Schema, SchemaError> {
     let mut schema = Schema::default();
@@ -721,7 +837,13 @@ fn prepare_table(
                         columns
                             .iter()
                             .filter_map(|col| match &col.expr {
-                                Expr::Id(id) => Some(id.0.clone()),
+                                // use the declared name, sqlite ignores case
+                                Expr::Id(id) => Some(
+                                    columns
+                                        .iter()
+                                        .find(|def| def.col_name.0.eq_ignore_ascii_case(&id.0))
+                                        .map_or_else(|| id.0.clone(), |def| def.col_name.0.clone()),
+                                ),
                                 _ => None,
                             })
                             .collect::<IndexSet<_>>(),
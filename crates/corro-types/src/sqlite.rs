@@ -1,6 +1,8 @@
 use std::{
     collections::HashMap,
     ops::{Deref, DerefMut},
+    sync::Arc,
+    time::Duration,
 };
 
 // use bb8::ManageConnection;
@@ -8,11 +10,18 @@ use camino::Utf8PathBuf;
 use compact_str::CompactString;
 use enquote::enquote;
 use once_cell::sync::Lazy;
-use rusqlite::{params, Connection, Transaction};
+use parking_lot::Mutex;
+use rusqlite::{
+    ffi,
+    hooks::{AuthAction, AuthContext, Authorization},
+    params, Connection, OptionalExtension, Transaction,
+};
 use sqlite_pool::SqliteConn;
 use tempfile::TempDir;
 use tracing::{error, trace};
 
+use crate::api::{QueryPlan, QueryPlanStep, TableName};
+
 pub type SqlitePool = sqlite_pool::Pool<CrConn>;
 pub type SqlitePoolError = sqlite_pool::PoolError;
 
@@ -42,12 +51,28 @@ static CRSQL_EXT_DIR: Lazy<TempDir> = Lazy::new(|| {
     dir
 });
 
+// how long readers wait on a lock (e.g. during a WAL checkpoint or recovery)
+// before giving up w/ SQLITE_BUSY
+const READ_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub fn rusqlite_to_crsqlite(mut conn: rusqlite::Connection) -> rusqlite::Result<CrConn> {
     init_cr_conn(&mut conn)?;
     setup_conn(&mut conn, &HashMap::new())?;
     Ok(CrConn(conn))
 }
 
+/// Same as `rusqlite_to_crsqlite`, for connections only used to read
+pub fn rusqlite_to_crsqlite_reader(conn: rusqlite::Connection) -> rusqlite::Result<CrConn> {
+    conn.busy_timeout(READ_BUSY_TIMEOUT)?;
+    rusqlite_to_crsqlite(conn)
+}
+
+/// Whether a read failed because its WAL snapshot went stale while it was
+/// starting. Waiting doesn't help w/ these, running it again does.
+pub fn is_busy_snapshot(e: &rusqlite::Error) -> bool {
+    matches!(e, rusqlite::Error::SqliteFailure(err, _) if err.extended_code == ffi::SQLITE_BUSY_SNAPSHOT)
+}
+
 #[derive(Debug)]
 pub struct CrConn(Connection);
 
@@ -184,6 +209,214 @@ pub fn migrate(conn: &mut Connection, migrations: Vec<Box<dyn Migration>>) -> ru
     Ok(())
 }
 
+/// Runs `EXPLAIN QUERY PLAN` for `sql` and flags full scans of tables holding
+/// at least `large_table_rows` rows.
+///
+/// Parameters are never bound: sqlite plans the statement the same way
+/// regardless of their values.
+pub fn explain_query_plan(
+    conn: &Connection,
+    sql: &str,
+    large_table_rows: u64,
+) -> rusqlite::Result<QueryPlan> {
+    let steps = conn
+        .prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?
+        .query_map([], |row| {
+            Ok(QueryPlanStep::new(row.get(0)?, row.get(1)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut large_scans: Vec<String> = vec![];
+    for table in steps.iter().filter_map(|step| step.scanned_table.as_ref()) {
+        if large_scans.contains(table) {
+            continue;
+        }
+        if matches!(
+            table_row_count(conn, table, large_table_rows)?,
+            Some(count) if count >= large_table_rows
+        ) {
+            large_scans.push(table.clone());
+        }
+    }
+
+    Ok(QueryPlan { steps, large_scans })
+}
+
+// Row count from `sqlite_stat1` when the table was analyzed, otherwise counts
+// rows up to `limit`. `None` if `table` isn't a table: views, CTEs and
+// subqueries show up as scans too.
+fn table_row_count(conn: &Connection, table: &str, limit: u64) -> rusqlite::Result<Option<u64>> {
+    let is_table: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ?)",
+        [table],
+        |row| row.get(0),
+    )?;
+    if !is_table {
+        return Ok(None);
+    }
+
+    let has_stats: bool = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = 'sqlite_stat1')",
+        [],
+        |row| row.get(0),
+    )?;
+
+    if has_stats {
+        let stat: Option<String> = conn
+            .query_row(
+                "SELECT stat FROM sqlite_stat1 WHERE tbl = ? LIMIT 1",
+                [table],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(count) = stat
+            .as_deref()
+            .and_then(|stat| stat.split_whitespace().next())
+            .and_then(|count| count.parse().ok())
+        {
+            return Ok(Some(count));
+        }
+    }
+
+    conn.query_row(
+        &format!(
+            "SELECT COUNT(*) FROM (SELECT 1 FROM \"{}\" LIMIT ?)",
+            table.replace('"', "\"\"")
+        ),
+        [limit.saturating_add(1) as i64],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| Some(count as u64))
+}
+
+// statements whose column names are interned, the map is cleared once full
+const MAX_INTERNED_COLUMN_NAMES: usize = 1024;
+
+static COLUMN_NAMES: Lazy<Mutex<HashMap<String, Arc<[CompactString]>>>> =
+    Lazy::new(Default::default);
+
+/// `prepped`'s column names, shared w/ earlier statements prepared from the
+/// same `sql` as long as they're the same, so queries run over and over don't
+/// allocate them every time
+pub fn interned_column_names(sql: &str, prepped: &rusqlite::Statement<'_>) -> Arc<[CompactString]> {
+    let count = prepped.column_count();
+    let same = |names: &[CompactString]| {
+        names.len() == count
+            && names
+                .iter()
+                .enumerate()
+                .all(|(i, name)| prepped.column_name(i).map_or(false, |col| col == name))
+    };
+
+    let mut interned = COLUMN_NAMES.lock();
+    if let Some(names) = interned.get(sql) {
+        // the schema might have changed since, e.g. for `SELECT *`
+        if same(names) {
+            return names.clone();
+        }
+    }
+
+    let names: Arc<[CompactString]> = prepped
+        .column_names()
+        .into_iter()
+        .map(CompactString::from)
+        .collect();
+    if interned.len() >= MAX_INTERNED_COLUMN_NAMES && !interned.contains_key(sql) {
+        interned.clear();
+    }
+    interned.insert(sql.to_owned(), names.clone());
+    names
+}
+
+/// What a statement accesses, as reported by sqlite's authorizer while
+/// preparing it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StatementAccess {
+    /// `(table, column)` pairs read, in the order they were reported. The
+    /// column is empty when a table is read w/o any of its columns, e.g. for
+    /// `SELECT count(*)`.
+    pub reads: Vec<(String, String)>,
+    /// Tables inserted into, updated or deleted from, directly or by
+    /// triggers. cr-sqlite's own tables aren't included. Writes are listed in
+    /// `actions` too.
+    pub writes: Vec<String>,
+    /// Actions besides reading tables and calling functions (writes, pragmas,
+    /// attaching databases, etc.), debug-formatted
+    pub actions: Vec<String>,
+}
+
+impl StatementAccess {
+    fn record(&mut self, action: AuthAction<'_>) {
+        match action {
+            AuthAction::Read {
+                table_name,
+                column_name,
+            } => {
+                if !self
+                    .reads
+                    .iter()
+                    .any(|(table, column)| table == table_name && column == column_name)
+                {
+                    self.reads
+                        .push((table_name.to_owned(), column_name.to_owned()));
+                }
+            }
+            AuthAction::Select | AuthAction::Function { .. } | AuthAction::Recursive => {}
+            action => {
+                if let AuthAction::Insert { table_name }
+                | AuthAction::Update { table_name, .. }
+                | AuthAction::Delete { table_name } = action
+                {
+                    if !table_name.contains("__crsql_")
+                        && !self.writes.iter().any(|table| table == table_name)
+                    {
+                        self.writes.push(table_name.to_owned());
+                    }
+                }
+                self.actions.push(format!("{action:?}"))
+            }
+        }
+    }
+
+    /// The first table written, or else read, which a statement's cost can
+    /// be attributed to
+    pub fn primary_table(&self) -> Option<TableName> {
+        self.writes
+            .first()
+            .or_else(|| self.reads.first().map(|(table, _)| table))
+            .map(|table| TableName(table.as_str().into()))
+    }
+}
+
+/// Prepares `sql` w/o running it to find out which tables and columns it
+/// reads and which tables it writes, looking through views and triggers.
+pub fn statement_access(conn: &Connection, sql: &str) -> rusqlite::Result<StatementAccess> {
+    prepare_with_access(conn, sql).map(|(_, access)| access)
+}
+
+/// Prepares `sql` (uncached) along w/ what it accesses, like `statement_access`
+pub fn prepare_with_access<'c>(
+    conn: &'c Connection,
+    sql: &str,
+) -> rusqlite::Result<(rusqlite::Statement<'c>, StatementAccess)> {
+    let access = Arc::new(Mutex::new(StatementAccess::default()));
+    {
+        let access = access.clone();
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            access.lock().record(ctx.action);
+            Authorization::Allow
+        }));
+    }
+
+    // a cached statement would skip the authorizer, it only runs when preparing
+    let prepared = conn.prepare(sql);
+    conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+    let prepared = prepared?;
+
+    let access = std::mem::take(&mut *access.lock());
+    Ok((prepared, access))
+}
+
 #[cfg(test)]
 mod tests {
     use futures::{stream::FuturesUnordered, TryStreamExt};
@@ -251,6 +484,123 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn explain_flags_large_scans() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "
+            CREATE TABLE foo (a INTEGER NOT NULL PRIMARY KEY, b INTEGER, c INTEGER);
+            CREATE INDEX foo_b ON foo (b);
+            INSERT INTO foo VALUES (1, 1, 1), (2, 2, 2), (3, 3, 3);
+        ",
+        )?;
+
+        let plan = explain_query_plan(&conn, "SELECT * FROM foo WHERE a = ?", 1)?;
+        assert!(plan.steps.iter().all(|step| step.scanned_table.is_none()));
+        assert!(plan.large_scans.is_empty());
+
+        let plan = explain_query_plan(&conn, "SELECT * FROM foo WHERE b = ?", 1)?;
+        assert!(plan.steps.iter().any(|step| step.uses_index));
+        assert!(plan.large_scans.is_empty());
+
+        let plan = explain_query_plan(&conn, "SELECT * FROM foo WHERE c = ?", 1)?;
+        assert_eq!(plan.large_scans, vec!["foo".to_string()]);
+
+        // below the threshold
+        let plan = explain_query_plan(&conn, "SELECT * FROM foo WHERE c = ?", 4)?;
+        assert!(plan.large_scans.is_empty());
+
+        // analyzed tables use sqlite_stat1
+        conn.execute_batch("ANALYZE")?;
+        let plan = explain_query_plan(&conn, "SELECT * FROM foo WHERE c = ?", 3)?;
+        assert_eq!(plan.large_scans, vec!["foo".to_string()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn collects_statement_access() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            "
+            CREATE TABLE foo (a INTEGER NOT NULL PRIMARY KEY, b INTEGER, c INTEGER);
+            CREATE TABLE bar (id INTEGER NOT NULL PRIMARY KEY, foo_a INTEGER);
+            CREATE VIEW foo_view AS SELECT a, c FROM foo;
+        ",
+        )?;
+
+        let access = statement_access(&conn, "SELECT a, b FROM foo WHERE c = ?")?;
+        assert_eq!(
+            access.reads,
+            vec![
+                ("foo".to_owned(), "a".to_owned()),
+                ("foo".to_owned(), "b".to_owned()),
+                ("foo".to_owned(), "c".to_owned()),
+            ]
+        );
+        assert!(access.actions.is_empty());
+
+        let access = statement_access(
+            &conn,
+            "SELECT bar.id FROM bar JOIN foo_view ON foo_view.a = bar.foo_a",
+        )?;
+        let mut tables = access
+            .reads
+            .iter()
+            .map(|(table, _)| table.as_str())
+            .collect::<Vec<_>>();
+        tables.sort();
+        tables.dedup();
+        assert_eq!(tables, vec!["bar", "foo"]);
+
+        let access = statement_access(&conn, "SELECT count(*) FROM bar")?;
+        assert_eq!(access.reads, vec![("bar".to_owned(), "".to_owned())]);
+
+        let access = statement_access(&conn, "DELETE FROM bar")?;
+        assert_eq!(access.actions.len(), 1);
+        assert_eq!(access.writes, vec!["bar".to_owned()]);
+
+        conn.execute_batch(
+            "CREATE TRIGGER bar_to_foo AFTER INSERT ON bar BEGIN UPDATE foo SET b = 1 WHERE a = NEW.foo_a; END;",
+        )?;
+        let access = statement_access(&conn, "INSERT INTO bar (id, foo_a) VALUES (1, 1)")?;
+        assert_eq!(access.writes, vec!["bar".to_owned(), "foo".to_owned()]);
+        assert_eq!(access.primary_table(), Some(TableName("bar".into())));
+        assert_eq!(
+            statement_access(&conn, "SELECT a FROM foo")?.primary_table(),
+            Some(TableName("foo".into()))
+        );
+        assert_eq!(statement_access(&conn, "SELECT 1")?.primary_table(), None);
+        assert!(statement_access(&conn, "SELECT a FROM foo")?
+            .writes
+            .is_empty());
+
+        assert!(statement_access(&conn, "SELECT * FROM nope").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn interns_column_names() -> rusqlite::Result<()> {
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch("CREATE TABLE interned (a INTEGER PRIMARY KEY, b INTEGER);")?;
+
+        let sql = "SELECT * FROM interned";
+        let names = interned_column_names(sql, &conn.prepare(sql)?);
+        assert_eq!(&names[..], &["a", "b"]);
+
+        let again = interned_column_names(sql, &conn.prepare(sql)?);
+        assert!(Arc::ptr_eq(&names, &again));
+
+        // `*` expands to the new column
+        conn.execute_batch("ALTER TABLE interned ADD COLUMN c INTEGER;")?;
+        let altered = interned_column_names(sql, &conn.prepare(sql)?);
+        assert_eq!(&altered[..], &["a", "b", "c"]);
+        assert!(!Arc::ptr_eq(&names, &altered));
+
+        Ok(())
+    }
+
     #[derive(Debug, thiserror::Error)]
     enum TestError {
         #[error(transparent)]
@@ -26,6 +26,36 @@ pub enum SyncMessageV1 {
     Clock(Timestamp),
     Rejection(SyncRejectionV1),
     Request(SyncRequestV1),
+    /// Sent instead of `Changeset` to peers accepting checksums
+    ChecksummedChangeset(ChecksummedChangeV1),
+}
+
+/// A serialized `ChangeV1` w/ the CRC32 of its bytes, as they went out
+/// (values compressed and all), so corruption in transit is caught before
+/// decoding the changes
+#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+pub struct ChecksummedChangeV1 {
+    pub checksum: u32,
+    pub payload: Vec<u8>,
+}
+
+impl ChecksummedChangeV1 {
+    pub fn seal(change: &ChangeV1) -> Result<Self, speedy::Error> {
+        let payload = change.write_to_vec()?;
+        Ok(Self {
+            checksum: crc32fast::hash(&payload),
+            payload,
+        })
+    }
+
+    /// Decodes the change after checking it wasn't corrupted
+    pub fn open(&self) -> Result<ChangeV1, SyncMessageDecodeError> {
+        let checksum = crc32fast::hash(&self.payload);
+        if checksum != self.checksum {
+            return Err(SyncMessageDecodeError::Corrupted(checksum, self.checksum));
+        }
+        Ok(ChangeV1::read_from_buffer(&self.payload)?)
+    }
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Readable, Writable)]
@@ -440,4 +470,67 @@ mod tests {
             .into()
         );
     }
+
+    #[test]
+    fn checksummed_changesets_detect_corruption() -> Result<(), SyncMessageDecodeError> {
+        use corro_api_types::{Change, ColumnName, SqliteValue, TableName};
+
+        use crate::broadcast::Changeset;
+
+        let mut change = Change {
+            table: TableName("consul_checks".into()),
+            pk: vec![1, 9, 1],
+            cid: ColumnName("output".into()),
+            val: SqliteValue::Text("connection refused\n".repeat(100).into()),
+            col_version: 1,
+            db_version: 1,
+            seq: 0,
+            site_id: corro_api_types::SiteId([1; 16]),
+            cl: 1,
+            compressed: None,
+            ts: None,
+        };
+        let changeset = |change: Change| ChangeV1 {
+            actor_id: ActorId(Uuid::nil()),
+            changeset: Changeset::Full {
+                version: 1,
+                changes: vec![change],
+                seqs: 0..=0,
+                last_seq: 0,
+                ts: Timestamp::zero(),
+            },
+        };
+        let expected = changeset(change.clone());
+
+        // covers the changes as sent, compressed
+        assert!(change.compress(64).unwrap());
+        let msg = SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(
+            ChecksummedChangeV1::seal(&changeset(change))?,
+        ));
+        let mut buf = msg.write_to_vec()?;
+
+        let SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(sealed)) =
+            SyncMessage::from_slice(&buf)?
+        else {
+            panic!("not a checksummed changeset");
+        };
+        assert_eq!(sealed.open()?, expected);
+
+        // a flipped bit in the changeset's timestamp still decodes fine, but
+        // doesn't match its checksum
+        let last = buf.len() - 1;
+        buf[last] ^= 0x01;
+        let SyncMessage::V1(SyncMessageV1::ChecksummedChangeset(sealed)) =
+            SyncMessage::from_slice(&buf)?
+        else {
+            panic!("not a checksummed changeset");
+        };
+        assert!(ChangeV1::read_from_buffer(&sealed.payload).is_ok());
+        assert!(matches!(
+            sealed.open(),
+            Err(SyncMessageDecodeError::Corrupted(..))
+        ));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,225 @@
+//! Usage attribution: what each subscription and each `/v1/transactions`
+//! client consumed.
+//!
+//! Counters are bumped where events and results are serialized, a few
+//! atomics each, and taken periodically to be added to the local
+//! `__corro_usage` table in hourly buckets.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+use uuid::Uuid;
+
+use crate::quota::ClientIdentity;
+
+/// Local table usage is persisted to, never replicated
+pub const USAGE_TABLE: &str = "__corro_usage";
+
+/// Usage is bucketed by the hour
+pub const USAGE_BUCKET_SECS: u64 = 3600;
+
+/// Start of the bucket `secs` (since the epoch) falls in
+pub fn usage_bucket(secs: u64) -> u64 {
+    secs - secs % USAGE_BUCKET_SECS
+}
+
+/// Identity of a subscription's usage
+pub fn subscription_identity(id: Uuid) -> String {
+    format!("sub:{id}")
+}
+
+/// Counts of what an identity consumed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageCounts {
+    /// Rows streamed to subscribers
+    pub rows: u64,
+    /// Change events delivered to subscribers
+    pub changes: u64,
+    /// Bytes of events serialized for subscribers, or of changes generated
+    /// by transactions
+    pub bytes: u64,
+    /// Statements executed by committed transactions
+    pub statements: u64,
+    /// Changes generated by committed transactions
+    pub changes_generated: u64,
+}
+
+impl UsageCounts {
+    pub fn is_empty(&self) -> bool {
+        *self == UsageCounts::default()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct UsageCounters {
+    rows: AtomicU64,
+    changes: AtomicU64,
+    bytes: AtomicU64,
+    statements: AtomicU64,
+    changes_generated: AtomicU64,
+}
+
+impl UsageCounters {
+    /// A row of `bytes` was serialized for subscribers
+    pub fn row(&self, bytes: usize) {
+        self.rows.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A change event of `bytes` was serialized for subscribers
+    pub fn change(&self, bytes: usize) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A transaction of `statements` committed, generating `changes` worth
+    /// `bytes`
+    pub fn exec(&self, statements: usize, changes: u64, bytes: u64) {
+        self.statements
+            .fetch_add(statements as u64, Ordering::Relaxed);
+        self.changes_generated.fetch_add(changes, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> UsageCounts {
+        UsageCounts {
+            rows: self.rows.swap(0, Ordering::Relaxed),
+            changes: self.changes.swap(0, Ordering::Relaxed),
+            bytes: self.bytes.swap(0, Ordering::Relaxed),
+            statements: self.statements.swap(0, Ordering::Relaxed),
+            changes_generated: self.changes_generated.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, counts: &UsageCounts) {
+        self.rows.fetch_add(counts.rows, Ordering::Relaxed);
+        self.changes.fetch_add(counts.changes, Ordering::Relaxed);
+        self.bytes.fetch_add(counts.bytes, Ordering::Relaxed);
+        self.statements
+            .fetch_add(counts.statements, Ordering::Relaxed);
+        self.changes_generated
+            .fetch_add(counts.changes_generated, Ordering::Relaxed);
+    }
+}
+
+/// Counters of every identity which consumed something since usage was
+/// last taken
+#[derive(Debug, Default)]
+pub struct Usage {
+    counters: Mutex<HashMap<String, Arc<UsageCounters>>>,
+}
+
+impl Usage {
+    /// Counters of `identity`, hold on to them to count w/o looking them up
+    /// again
+    pub fn counters(&self, identity: &str) -> Arc<UsageCounters> {
+        let mut counters = self.counters.lock();
+        match counters.get(identity) {
+            Some(found) => found.clone(),
+            None => counters.entry(identity.to_owned()).or_default().clone(),
+        }
+    }
+
+    pub fn subscription(&self, id: Uuid) -> Arc<UsageCounters> {
+        self.counters(&subscription_identity(id))
+    }
+
+    pub fn client(&self, identity: &ClientIdentity) -> Arc<UsageCounters> {
+        self.counters(&identity.to_string())
+    }
+
+    /// Takes what was counted since usage was last taken, ordered by
+    /// identity. Identities nothing counts for anymore are forgotten.
+    pub fn take(&self) -> Vec<(String, UsageCounts)> {
+        let mut counters = self.counters.lock();
+        let mut taken: Vec<(String, UsageCounts)> = counters
+            .iter()
+            .map(|(identity, counters)| (identity.clone(), counters.take()))
+            .filter(|(_, counts)| !counts.is_empty())
+            .collect();
+        counters.retain(|_, counters| Arc::strong_count(counters) > 1);
+
+        taken.sort_by(|a, b| a.0.cmp(&b.0));
+        taken
+    }
+
+    /// Puts back usage which was taken but couldn't be persisted
+    pub fn restore(&self, taken: &[(String, UsageCounts)]) {
+        for (identity, counts) in taken {
+            self.counters(identity).add(counts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    #[test]
+    fn takes_usage() {
+        let usage = Usage::default();
+        let sub_id = Uuid::new_v4();
+
+        let sub = usage.subscription(sub_id);
+        sub.row(10);
+        sub.row(20);
+        sub.change(5);
+
+        let client = ClientIdentity::new(None, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        usage.client(&client).exec(3, 4, 100);
+        usage.client(&client).exec(1, 0, 0);
+
+        let taken = usage.take();
+        assert_eq!(
+            taken,
+            [
+                (
+                    "ip:127.0.0.1".to_owned(),
+                    UsageCounts {
+                        bytes: 100,
+                        statements: 4,
+                        changes_generated: 4,
+                        ..Default::default()
+                    }
+                ),
+                (
+                    format!("sub:{sub_id}"),
+                    UsageCounts {
+                        rows: 2,
+                        changes: 1,
+                        bytes: 35,
+                        ..Default::default()
+                    }
+                ),
+            ]
+        );
+
+        // nothing counted since, and the client isn't held anymore
+        assert!(usage.take().is_empty());
+        assert_eq!(usage.counters.lock().len(), 1);
+
+        // usage which couldn't be persisted is counted again
+        usage.restore(&taken);
+        assert_eq!(usage.take(), taken);
+
+        drop(sub);
+        usage.take();
+        assert!(usage.counters.lock().is_empty());
+    }
+
+    #[test]
+    fn buckets_by_the_hour() {
+        assert_eq!(usage_bucket(0), 0);
+        assert_eq!(usage_bucket(3599), 0);
+        assert_eq!(usage_bucket(3600), 3600);
+        assert_eq!(usage_bucket(1_700_000_123), 1_699_999_200);
+    }
+}
@@ -1,13 +1,18 @@
 use std::{net::SocketAddr, time::Duration};
 
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use corro_admin::AdminConfig;
-use corro_types::config::{Config, PrometheusConfig};
+use corro_types::{
+    agent::Agent,
+    config::{Config, PrometheusConfig},
+};
 use metrics::gauge;
-use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
 use spawn::wait_for_all_pending_handles;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tokio_metrics::RuntimeMonitor;
 use tracing::{error, info};
+use tripwire::Tripwire;
 
 use crate::VERSION;
 
@@ -28,6 +33,14 @@ pub async fn run(config: Config, config_path: &Utf8PathBuf) -> eyre::Result<()>
         .await
         .expect("could not start agent");
 
+    let hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(reload_on_hangup(
+        agent.clone(),
+        config_path.clone(),
+        hangups,
+        tripwire.clone(),
+    ));
+
     corro_admin::start_server(
         agent,
         AdminConfig {
@@ -62,6 +75,44 @@ pub async fn run(config: Config, config_path: &Utf8PathBuf) -> eyre::Result<()>
     Ok(())
 }
 
+/// Re-reads the config file on SIGHUP and applies what can be changed while
+/// running: the API's authorization token and access policies.
+async fn reload_on_hangup(
+    agent: Agent,
+    config_path: Utf8PathBuf,
+    mut hangups: Signal,
+    mut tripwire: Tripwire,
+) {
+    loop {
+        tokio::select! {
+            Some(()) = hangups.recv() => {
+                info!("Reloading config from {config_path}");
+                if let Err(e) = reload_api_config(&agent, &config_path) {
+                    error!("could not reload config: {e}");
+                }
+            },
+            _ = &mut tripwire => break,
+            else => break,
+        }
+    }
+}
+
+fn reload_api_config(agent: &Agent, config_path: &Utf8Path) -> eyre::Result<()> {
+    let new = Config::load(config_path.as_str())?;
+
+    let mut config = Config::clone(&agent.config());
+    config.api.authorization = new.api.authorization;
+    config.api.policies = new.api.policies;
+    config.source = new.source;
+    info!(
+        "Applied api authorization w/ {} access policies",
+        config.api.policies.len()
+    );
+    agent.set_config(config);
+
+    Ok(())
+}
+
 fn setup_prometheus(addr: SocketAddr) -> eyre::Result<()> {
     PrometheusBuilder::new()
         .with_http_listener(addr)
@@ -80,6 +131,11 @@ fn setup_prometheus(addr: SocketAddr) -> eyre::Result<()> {
             10.0,  // 10s :screaming:
             30.0, 60.0,
         ])?
+        .set_buckets_for_metric(
+            Matcher::Full("corro.changes.size.bytes".into()),
+            // exponential, from 64B to 16MiB
+            &(0..10).map(|i| 64.0 * 4f64.powi(i)).collect::<Vec<_>>(),
+        )?
         .install()?;
     Ok(())
 }
@@ -211,3 +267,46 @@ fn start_tokio_runtime_reporter() {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use corro_tests::launch_test_agent;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn reloads_access_policies() -> eyre::Result<()> {
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire).await?;
+        assert!(ta.agent.config().api.policies.is_empty());
+
+        let path = Utf8PathBuf::try_from(ta.tmpdir.path().join("config.toml"))?;
+        std::fs::write(
+            &path,
+            r#"
+[db]
+path = "/var/lib/corrosion/state.db"
+
+[api]
+addr = "127.0.0.1:8080"
+
+[[api.policies]]
+token = "reader"
+tables = ["tests"]
+
+[gossip]
+addr = "127.0.0.1:8787"
+"#,
+        )?;
+
+        reload_api_config(&ta.agent, &path)?;
+
+        let config = ta.agent.config();
+        let policy = config.api.policy("reader").expect("policy was not applied");
+        assert_eq!(policy.tables, vec!["tests".to_string()]);
+        // the rest requires a restart
+        assert_ne!(config.db.path.as_str(), "/var/lib/corrosion/state.db");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use corro_admin::backup::{self, BackupManifest};
+use rusqlite::Connection;
+use tracing::{info, warn};
+
+use crate::admin::AdminConn;
+
+/// Snapshots the database into `path`, through the running agent when there's
+/// one so the snapshot is taken w/ the agent's own locking.
+pub async fn backup(
+    admin_path: &Utf8Path,
+    db_path: &Utf8Path,
+    path: &Utf8Path,
+) -> eyre::Result<BackupManifest> {
+    // the agent doesn't share our working directory
+    let path = if path.is_relative() {
+        Utf8PathBuf::try_from(std::env::current_dir()?)?.join(path)
+    } else {
+        path.to_owned()
+    };
+
+    // don't mistake a previous backup's manifest for this one's
+    if let Err(e) = std::fs::remove_file(backup::manifest_path(&path)) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+
+    match AdminConn::connect(admin_path).await {
+        Ok(mut conn) => {
+            conn.send_command(corro_admin::Command::Backup { path: path.clone() })
+                .await?;
+            backup::read_manifest(&path)?
+                .ok_or_else(|| eyre::eyre!("agent could not back up the database to {path}"))
+        }
+        Err(_) => {
+            info!("corrosion isn't running, backing up {db_path} directly");
+            let conn = Connection::open(db_path)?;
+            Ok(backup::backup(&conn, &path)?)
+        }
+    }
+}
+
+/// Replaces the database at `db_path` w/ the snapshot at `path`.
+///
+/// The node keeps its site id when restoring one of its own snapshots and
+/// comes up as a new site otherwise. Restoring over a database of another
+/// site is refused unless `force_new_site` is set.
+pub fn restore(
+    path: &Utf8Path,
+    db_path: &Utf8Path,
+    self_actor_id: bool,
+    force_new_site: bool,
+) -> eyre::Result<()> {
+    let current_site_id = if db_path.exists() {
+        backup::local_site_id(&Connection::open(db_path)?)?
+    } else {
+        None
+    };
+
+    let db_dir = match db_path.parent() {
+        Some(parent) if !parent.as_str().is_empty() => parent,
+        _ => Utf8Path::new("."),
+    };
+    std::fs::create_dir_all(db_dir)?;
+
+    // work on a copy, so the backup can be restored again
+    let staged = tempfile::Builder::new()
+        .prefix(".corrosion-restore")
+        .tempfile_in(db_dir)?;
+    std::fs::copy(path, staged.path())?;
+
+    {
+        let conn = Connection::open(staged.path())?;
+
+        match backup::read_manifest(path)? {
+            Some(manifest) => {
+                backup::validate(&conn, &manifest)?;
+
+                match current_site_id {
+                    Some(site_id) if site_id == manifest.site_id => {
+                        backup::adopt_site_id(&conn, site_id)?;
+                    }
+                    Some(site_id) if !force_new_site => {
+                        eyre::bail!("refusing to restore a snapshot of site {} over the database of site {site_id}, pass --force-new-site to restore it as a new site", manifest.site_id);
+                    }
+                    _ => {
+                        info!("restoring as a new site");
+                    }
+                }
+
+                info!(
+                    "restoring snapshot of site {} at db_version {}, newer changes will be synced from the cluster",
+                    manifest.site_id, manifest.db_version
+                );
+            }
+            None => {
+                warn!("no manifest found for {path}, the snapshot can't be validated");
+
+                if self_actor_id {
+                    let Some(site_id) = current_site_id else {
+                        eyre::bail!(
+                            "path to current database is required when passing --self-actor-id"
+                        );
+                    };
+                    backup::adopt_site_id(&conn, site_id)?;
+                }
+            }
+        }
+
+        conn.execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+    }
+
+    let restored =
+        sqlite3_restore::restore(staged.path(), db_path, std::time::Duration::from_secs(30))?;
+
+    info!(
+        "successfully restored! old size: {}, new size: {}",
+        restored.old_len, restored.new_len
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use corro_admin::AdminConfig;
+    use corro_tests::launch_test_agent;
+    use corro_types::api::Statement;
+    use spawn::wait_for_all_pending_handles;
+    use tokio::time::{sleep, timeout};
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    async fn insert(client: &corro_client::CorrosionApiClient, ids: std::ops::Range<i64>) {
+        let statements = ids
+            .map(|id| {
+                Statement::WithParams(
+                    "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                    vec![id.into(), format!("hello {id}").into()],
+                )
+            })
+            .collect::<Vec<_>>();
+        client.execute(&statements).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn restored_node_catches_up() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let admin_path = Utf8PathBuf::try_from(ta1.tmpdir.path().join("admin.sock"))?;
+        corro_admin::start_server(
+            ta1.agent.clone(),
+            AdminConfig {
+                listen_path: admin_path.clone(),
+                config_path: "corrosion.toml".into(),
+            },
+            tripwire.clone(),
+        )?;
+
+        let client = corro_client::CorrosionApiClient::new(ta1.agent.api_addr());
+        insert(&client, 1..11).await;
+
+        let tmpdir = tempfile::tempdir()?;
+        let snapshot = Utf8PathBuf::try_from(tmpdir.path().join("backup.db"))?;
+
+        let manifest = backup(&admin_path, ta1.agent.db_path().as_path(), &snapshot).await?;
+        assert_eq!(manifest.site_id, ta1.agent.actor_id());
+        assert_eq!(manifest.db_version, 1);
+
+        // changes made after the snapshot
+        insert(&client, 11..21).await;
+
+        // can't be restored over a database of another site
+        let ta_other = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let other_db_path = ta_other.agent.db_path();
+        assert!(restore(&snapshot, &other_db_path, false, false).is_err());
+
+        let db_path = Utf8PathBuf::try_from(tmpdir.path().join("restored/corrosion.db"))?;
+        restore(&snapshot, &db_path, false, false)?;
+
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.db_path(db_path.as_str())
+                    .bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        assert_ne!(ta2.agent.actor_id(), ta1.agent.actor_id());
+
+        timeout(Duration::from_secs(10), async {
+            loop {
+                let count: i64 = ta2.agent.pool().read().await?.query_row(
+                    "SELECT COUNT(*) FROM tests",
+                    [],
+                    |row| row.get(0),
+                )?;
+                if count == 20 {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,609 @@
+use std::{io::Write, net::SocketAddr};
+
+use corro_api_types::{
+    diff::{DiffOptions, RowDiff},
+    digest::{Digest, DigestRequest, TableDigest},
+    SqliteValue,
+};
+use corro_client::CorrosionApiClient;
+use serde::Serialize;
+
+/// Ranges w/ at most this many rows on both nodes are compared row by row
+const LEAF_ROWS: u64 = 16;
+
+/// Diverging rows listed per table and peer, drilling down stops there
+pub const DEFAULT_MAX_KEYS: usize = 100;
+
+/// A node digests are fetched from
+pub struct Node {
+    pub name: String,
+    pub client: CorrosionApiClient,
+}
+
+impl Node {
+    pub fn new(name: impl Into<String>, client: CorrosionApiClient) -> Self {
+        Self {
+            name: name.into(),
+            client,
+        }
+    }
+}
+
+/// Resolves a peer's API address, `default_port` being used for peers
+/// given w/o one
+pub async fn resolve_peer(peer: &str, default_port: u16) -> eyre::Result<SocketAddr> {
+    if let Ok(addr) = peer.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = peer.parse() {
+        return Ok(SocketAddr::new(ip, default_port));
+    }
+
+    let mut addrs = match peer.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => {
+            tokio::net::lookup_host((host, port.parse::<u16>()?)).await?
+        }
+        _ => tokio::net::lookup_host((peer, default_port)).await?,
+    };
+    addrs
+        .next()
+        .ok_or_else(|| eyre::eyre!("could not resolve peer {peer}"))
+}
+
+#[derive(Debug, Serialize)]
+pub struct CheckReport {
+    pub reference: String,
+    pub tables: Vec<TableCheck>,
+}
+
+impl CheckReport {
+    /// Whether every peer has the same rows as the reference, for every table
+    pub fn converged(&self) -> bool {
+        self.tables.iter().all(|table| {
+            table.error.is_none()
+                && table
+                    .peers
+                    .iter()
+                    .all(|peer| peer.status == PeerStatus::Match)
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableCheck {
+    pub table: String,
+    /// The reference's digest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub peers: Vec<PeerCheck>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerStatus {
+    Match,
+    Mismatch,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerCheck {
+    pub peer: String,
+    pub status: PeerStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<Digest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Only when drilling down, in the reference's primary key order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rows: Vec<DivergingRow>,
+    /// More rows diverge than are listed
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl PeerCheck {
+    fn new(peer: &Node, status: PeerStatus) -> Self {
+        Self {
+            peer: peer.name.clone(),
+            status,
+            digest: None,
+            error: None,
+            rows: vec![],
+            truncated: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Divergence {
+    /// Both nodes have the row, w/ different values
+    Differs,
+    /// Only the reference has the row
+    Missing,
+    /// Only the peer has the row
+    Extra,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DivergingRow {
+    pub pk: Vec<SqliteValue>,
+    pub divergence: Divergence,
+    /// From the reference's values to the peer's
+    #[serde(skip_serializing_if = "RowDiff::is_empty")]
+    pub diff: RowDiff,
+}
+
+/// Compares the digests of `tables` (every table of the reference's schema
+/// if empty) on `peers` w/ the reference's. Mismatched tables are drilled
+/// down to the rows which diverged if `drill_down` is set, listing at most
+/// `max_keys` of them per peer.
+pub async fn run(
+    reference: &Node,
+    peers: &[Node],
+    tables: &[String],
+    drill_down: bool,
+    max_keys: usize,
+) -> eyre::Result<CheckReport> {
+    let tables = if tables.is_empty() {
+        reference
+            .client
+            .current_schema()
+            .await?
+            .tables
+            .into_iter()
+            .map(|table| table.name.to_string())
+            .collect()
+    } else {
+        tables.to_vec()
+    };
+
+    let mut checks = vec![];
+    for table in tables {
+        checks.push(check_table(reference, peers, table, drill_down, max_keys).await);
+    }
+
+    Ok(CheckReport {
+        reference: reference.name.clone(),
+        tables: checks,
+    })
+}
+
+async fn check_table(
+    reference: &Node,
+    peers: &[Node],
+    table: String,
+    drill_down: bool,
+    max_keys: usize,
+) -> TableCheck {
+    let req = DigestRequest::new(table.as_str());
+    let expected = match reference.client.digest(&req).await {
+        Ok(expected) => expected,
+        Err(e) => {
+            return TableCheck {
+                table,
+                digest: None,
+                error: Some(e.to_string()),
+                peers: vec![],
+            }
+        }
+    };
+
+    let mut checks = vec![];
+    for peer in peers {
+        let actual = match peer.client.digest(&req).await {
+            Ok(actual) => actual,
+            Err(e) => {
+                let mut check = PeerCheck::new(peer, PeerStatus::Error);
+                check.error = Some(e.to_string());
+                checks.push(check);
+                continue;
+            }
+        };
+
+        if actual.columns != expected.columns || actual.pk != expected.pk {
+            let mut check = PeerCheck::new(peer, PeerStatus::Error);
+            check.digest = Some(actual.digest);
+            check.error = Some(format!(
+                "columns differ, the schemas haven't converged: {} vs {}",
+                column_list(&expected),
+                column_list(&actual)
+            ));
+            checks.push(check);
+            continue;
+        }
+
+        if actual.digest.matches(&expected.digest) {
+            let mut check = PeerCheck::new(peer, PeerStatus::Match);
+            check.digest = Some(actual.digest);
+            checks.push(check);
+            continue;
+        }
+
+        let mut check = PeerCheck::new(peer, PeerStatus::Mismatch);
+        check.digest = Some(actual.digest);
+        if drill_down {
+            match diverging_rows(
+                reference,
+                peer,
+                &table,
+                expected.digest,
+                actual.digest,
+                max_keys,
+            )
+            .await
+            {
+                Ok((rows, truncated)) => {
+                    check.rows = rows;
+                    check.truncated = truncated;
+                }
+                Err(e) => check.error = Some(format!("could not drill down: {e}")),
+            }
+        }
+        checks.push(check);
+    }
+
+    TableCheck {
+        table,
+        digest: Some(expected.digest),
+        error: None,
+        peers: checks,
+    }
+}
+
+fn column_list(digest: &TableDigest) -> String {
+    let names: Vec<&str> = digest.columns.iter().map(|name| name.as_str()).collect();
+    format!("({})", names.join(", "))
+}
+
+// a range of primary keys known to differ, w/ each node's digest of it
+struct Range {
+    from: Option<Vec<SqliteValue>>,
+    to: Option<Vec<SqliteValue>>,
+    expected: Digest,
+    actual: Digest,
+}
+
+/// Bisects the ranges of keys which differ between both nodes, down to ranges
+/// small enough to compare their rows
+async fn diverging_rows(
+    reference: &Node,
+    peer: &Node,
+    table: &str,
+    expected: Digest,
+    actual: Digest,
+    max_keys: usize,
+) -> eyre::Result<(Vec<DivergingRow>, bool)> {
+    let mut rows = vec![];
+    // depth first, so rows are found in primary key order
+    let mut ranges = vec![Range {
+        from: None,
+        to: None,
+        expected,
+        actual,
+    }];
+
+    while let Some(range) = ranges.pop() {
+        if rows.len() >= max_keys {
+            rows.truncate(max_keys);
+            return Ok((rows, true));
+        }
+
+        if range.expected.rows <= LEAF_ROWS && range.actual.rows <= LEAF_ROWS {
+            let req = DigestRequest::new(table)
+                .range(range.from, range.to)
+                .with_rows();
+            let (expected, actual) =
+                tokio::try_join!(reference.client.digest(&req), peer.client.digest(&req))?;
+            rows.extend(compare_rows(&expected, &actual));
+            continue;
+        }
+
+        // halves the range along the keys of the node w/ the most rows in it,
+        // its chunks' hashes are the halves' so only the other node is asked
+        let reference_splits = range.expected.rows >= range.actual.rows;
+        let (splitter, other) = if reference_splits {
+            (reference, peer)
+        } else {
+            (peer, reference)
+        };
+        let n = range.expected.rows.max(range.actual.rows);
+        let halves = splitter
+            .client
+            .digest(
+                &DigestRequest::new(table)
+                    .range(range.from.clone(), range.to.clone())
+                    .chunk_rows((n + 1) / 2),
+            )
+            .await?;
+        if halves.chunks.len() < 2 {
+            eyre::bail!("{table} changed on {} while checking it", splitter.name);
+        }
+
+        let mut halves_differing = vec![];
+        for (i, chunk) in halves.chunks.iter().enumerate() {
+            let from = match i {
+                0 => range.from.clone(),
+                _ => Some(chunk.first.clone()),
+            };
+            let to = match halves.chunks.get(i + 1) {
+                Some(next) => Some(next.first.clone()),
+                None => range.to.clone(),
+            };
+
+            let split = Digest {
+                rows: chunk.rows,
+                hash: chunk.hash,
+                time: 0.0,
+            };
+            let other_digest = other
+                .client
+                .digest(&DigestRequest::new(table).range(from.clone(), to.clone()))
+                .await?
+                .digest;
+            if split.matches(&other_digest) {
+                continue;
+            }
+
+            let (expected, actual) = if reference_splits {
+                (split, other_digest)
+            } else {
+                (other_digest, split)
+            };
+            halves_differing.push(Range {
+                from,
+                to,
+                expected,
+                actual,
+            });
+        }
+        ranges.extend(halves_differing.into_iter().rev());
+    }
+
+    Ok((rows, false))
+}
+
+/// Rows which differ between digests fetched w/ their rows, matched by
+/// primary key
+fn compare_rows(expected: &TableDigest, actual: &TableDigest) -> Vec<DivergingRow> {
+    let pk = expected.pk;
+    let mut rows = vec![];
+
+    for row in expected.rows.iter() {
+        match actual.rows.iter().find(|other| other[..pk] == row[..pk]) {
+            Some(other) => {
+                let diff = RowDiff::between(
+                    (expected.columns.as_slice(), row.as_slice()),
+                    (actual.columns.as_slice(), other.as_slice()),
+                    &DiffOptions::default(),
+                );
+                if !diff.is_empty() {
+                    rows.push(DivergingRow {
+                        pk: row[..pk].to_vec(),
+                        divergence: Divergence::Differs,
+                        diff,
+                    });
+                }
+            }
+            None => rows.push(DivergingRow {
+                pk: row[..pk].to_vec(),
+                divergence: Divergence::Missing,
+                diff: RowDiff::default(),
+            }),
+        }
+    }
+
+    for row in actual.rows.iter() {
+        if !expected.rows.iter().any(|other| other[..pk] == row[..pk]) {
+            rows.push(DivergingRow {
+                pk: row[..pk].to_vec(),
+                divergence: Divergence::Extra,
+                diff: RowDiff::default(),
+            });
+        }
+    }
+
+    rows
+}
+
+fn digest_summary(digest: &Digest) -> String {
+    format!("{} rows, digest {:016x}", digest.rows, digest.hash)
+}
+
+fn pk_literal(pk: &[SqliteValue]) -> String {
+    let values: Vec<String> = pk.iter().map(|value| value.to_sql_literal()).collect();
+    format!("({})", values.join(", "))
+}
+
+pub fn render<W: Write>(report: &CheckReport, json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(
+            &mut *out,
+            &serde_json::json!({
+                "ok": report.converged(),
+                "reference": report.reference,
+                "tables": report.tables,
+            }),
+        )?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    for table in report.tables.iter() {
+        match (&table.digest, &table.error) {
+            (_, Some(error)) => {
+                writeln!(
+                    out,
+                    "{}: error on {}: {error}",
+                    table.table, report.reference
+                )?;
+                continue;
+            }
+            (Some(digest), None) => writeln!(
+                out,
+                "{}: {} on {}",
+                table.table,
+                digest_summary(digest),
+                report.reference
+            )?,
+            (None, None) => writeln!(out, "{}:", table.table)?,
+        }
+
+        for peer in table.peers.iter() {
+            let status = match peer.status {
+                PeerStatus::Match => "ok",
+                PeerStatus::Mismatch => "MISMATCH",
+                PeerStatus::Error => "ERROR",
+            };
+            let detail = match (&peer.digest, &peer.error) {
+                (_, Some(error)) if peer.status == PeerStatus::Error => error.clone(),
+                (Some(digest), _) => digest_summary(digest),
+                (None, _) => String::new(),
+            };
+            writeln!(out, "  {status:<8} {}  {detail}", peer.peer)?;
+            if peer.status != PeerStatus::Error {
+                if let Some(error) = peer.error.as_ref() {
+                    writeln!(out, "    {error}")?;
+                }
+            }
+
+            for row in peer.rows.iter() {
+                let pk = pk_literal(&row.pk);
+                match row.divergence {
+                    Divergence::Differs => {
+                        writeln!(out, "    {pk} differs")?;
+                        for line in row.diff.to_string().lines() {
+                            writeln!(out, "      {line}")?;
+                        }
+                    }
+                    Divergence::Missing => writeln!(out, "    {pk} missing on {}", peer.peer)?,
+                    Divergence::Extra => writeln!(out, "    {pk} only on {}", peer.peer)?,
+                }
+            }
+            if peer.truncated {
+                writeln!(out, "    ... more rows diverge")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use corro_api_types::{ExecRequest, Statement};
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    const TOKEN: &str = "admin-secret";
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pinpoints_diverging_rows() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(
+            |conf| {
+                conf.api_authorization(TOKEN)
+                    .add_no_replication_table("tests")
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .api_authorization(TOKEN)
+                    .add_no_replication_table("tests")
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let reference = Node::new(
+            "ta1",
+            CorrosionApiClient::new(ta1.agent.api_addr()).with_bearer_token(TOKEN),
+        );
+        let peer = Node::new(
+            "ta2",
+            CorrosionApiClient::new(ta2.agent.api_addr()).with_bearer_token(TOKEN),
+        );
+
+        let statements: Vec<Statement> = (1..=40i64)
+            .map(|id| {
+                Statement::WithParams(
+                    "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                    vec![id.into(), format!("row {id}").into()],
+                )
+            })
+            .collect();
+        reference.client.execute(&statements).await?;
+
+        let tables = vec!["tests".to_string()];
+        let start = Instant::now();
+        loop {
+            let report = run(&reference, std::slice::from_ref(&peer), &tables, false, 1).await?;
+            if report.converged() {
+                break;
+            }
+            if start.elapsed() > Duration::from_secs(10) {
+                eyre::bail!("ta2 never caught up w/ ta1");
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        // only written on ta2
+        peer.client
+            .execute_request(
+                &ExecRequest::Statements(vec![Statement::Simple(
+                    "UPDATE tests SET text = 'diverged' WHERE id = 27".into(),
+                )])
+                .no_replication(),
+            )
+            .await?;
+
+        // w/o drilling down, only the table is reported
+        let report = run(&reference, std::slice::from_ref(&peer), &tables, false, 10).await?;
+        assert!(!report.converged());
+        assert_eq!(report.tables[0].peers[0].status, PeerStatus::Mismatch);
+        assert!(report.tables[0].peers[0].rows.is_empty());
+
+        let report = run(&reference, std::slice::from_ref(&peer), &tables, true, 10).await?;
+        let check = &report.tables[0].peers[0];
+        assert_eq!(check.status, PeerStatus::Mismatch);
+        assert_eq!(check.error, None);
+        assert_eq!(check.rows.len(), 1, "{:?}", check.rows);
+        assert_eq!(check.rows[0].pk, vec![SqliteValue::Integer(27)]);
+        assert_eq!(check.rows[0].divergence, Divergence::Differs);
+        assert_eq!(check.rows[0].diff.columns.len(), 1);
+        assert_eq!(check.rows[0].diff.columns[0].column.as_str(), "text");
+        assert!(!check.truncated);
+
+        let mut out = vec![];
+        render(&report, false, &mut out)?;
+        let rendered = String::from_utf8(out)?;
+        assert!(rendered.contains("(27) differs"), "{rendered}");
+        assert!(rendered.contains("'diverged'"), "{rendered}");
+
+        let mut out = vec![];
+        render(&report, true, &mut out)?;
+        let json: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(json["ok"], false);
+        assert_eq!(json["tables"][0]["peers"][0]["rows"][0]["pk"][0], 27);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::io::Write;
+
+use corro_types::api::ConfigResponse;
+
+/// Prints where the config was loaded from, then its settings as JSON.
+/// Secrets are already redacted in `config`.
+pub fn render<W: Write>(config: &ConfigResponse, json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, config)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    writeln!(out, "path:      {}", config.path.as_deref().unwrap_or("-"))?;
+    match config.loaded_at {
+        Some(loaded_at) => writeln!(out, "loaded at: {loaded_at}")?,
+        None => writeln!(out, "loaded at: -")?,
+    }
+    writeln!(out, "hash:      {}", config.hash.as_deref().unwrap_or("-"))?;
+    serde_json::to_writer_pretty(&mut *out, &config.config)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use camino::Utf8PathBuf;
+    use corro_tests::launch_test_agent;
+    use corro_types::config::{AuthzConfig, Config, SinkTarget, REDACTED};
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    const SECRETS: &[&str] = &[
+        "admin-secret",
+        "reader-secret",
+        "quota-secret",
+        "sink-secret",
+        "gossip-secret-key",
+        "client-secret-key",
+        "consul-secret-key",
+    ];
+
+    const CONFIG: &str = r#"
+[db]
+path = "/var/lib/corrosion/state.db"
+
+[api]
+addr = "127.0.0.1:8080"
+authorization = { bearer-token = "admin-secret" }
+
+[[api.policies]]
+token = "reader-secret"
+tables = ["tests"]
+
+[api.quotas]
+statements_per_sec = 10.0
+overrides = [{ name = "sync", token = "quota-secret" }]
+
+[gossip]
+addr = "127.0.0.1:8787"
+
+[gossip.tls]
+cert_file = "/etc/corrosion/tls/cert.pem"
+key_file = "/etc/corrosion/tls/gossip-secret-key.pem"
+
+[gossip.tls.client]
+cert_file = "/etc/corrosion/tls/client.pem"
+key_file = "/etc/corrosion/tls/client-secret-key.pem"
+
+[consul.client]
+address = "127.0.0.1:8501"
+
+[consul.client.tls]
+ca_file = "/etc/consul/ca.pem"
+cert_file = "/etc/consul/cert.pem"
+key_file = "/etc/consul/consul-secret-key.pem"
+
+[[sinks]]
+name = "audit"
+query = "SELECT * FROM tests"
+table = "tests"
+target = { type = "webhook", url = "http://127.0.0.1:9999/", headers = { authorization = "Bearer sink-secret" } }
+"#;
+
+    fn assert_redacted(rendered: &str) {
+        for secret in SECRETS {
+            assert!(!rendered.contains(secret), "{secret} leaked: {rendered}");
+        }
+        assert!(rendered.contains(REDACTED), "{rendered}");
+    }
+
+    #[test]
+    fn never_renders_secrets() -> eyre::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = Utf8PathBuf::try_from(tmpdir.path().join("config.toml"))?;
+        std::fs::write(&path, CONFIG)?;
+
+        let config = Config::load(path.as_str())?;
+        // still usable by the agent
+        assert!(matches!(
+            config.api.authorization.as_ref(),
+            Some(AuthzConfig::BearerToken(token)) if token.expose() == "admin-secret"
+        ));
+        assert!(config.api.policy("reader-secret").is_some());
+        let SinkTarget::Webhook { headers, .. } = &config.sinks[0].target else {
+            panic!("not a webhook: {:?}", config.sinks[0].target);
+        };
+        assert_eq!(headers["authorization"].expose(), "Bearer sink-secret");
+        assert_redacted(&format!("{config:?}"));
+
+        let res = config.to_response()?;
+        assert_eq!(res.path.as_deref(), Some(path.as_str()));
+        assert_eq!(
+            res.hash,
+            Some(format!("{:016x}", seahash::hash(CONFIG.as_bytes())))
+        );
+        assert!(res.loaded_at.is_some());
+        // defaults are filled in
+        assert_eq!(res.config["api"]["drain_timeout_secs"], 10);
+        assert_eq!(res.config["consul"]["client"]["tls"]["key_file"], REDACTED);
+
+        for json in [false, true] {
+            let mut out = vec![];
+            render(&res, json, &mut out)?;
+            let rendered = String::from_utf8(out)?;
+            assert_redacted(&rendered);
+            assert!(rendered.contains(path.as_str()), "{rendered}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn shows_remote_config() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(
+            |conf| conf.api_authorization("admin-secret").build(),
+            tripwire.clone(),
+        )
+        .await?;
+
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+        assert!(client.config().await.is_err());
+
+        let res = client.with_bearer_token("admin-secret").config().await?;
+        // built in-process, w/o a file
+        assert_eq!(res.path, None);
+        assert_eq!(res.config["api"]["authorization"]["bearer-token"], REDACTED);
+
+        let mut out = vec![];
+        render(&res, true, &mut out)?;
+        assert_redacted(&String::from_utf8(out)?);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! The `__corro_consul_*` tables in which the sync records the hash of each
+//! service and check it wrote, to only write changed ones. They're local to
+//! each node's database, never replicated.
+
+use std::{collections::HashMap, time::Instant};
+
+use compact_str::CompactString;
+use corro_api_types::{row::FromRow, QueryEvent, Statement, TableName};
+use corro_client::{read::ReadPreference, CorrosionClient};
+use futures::StreamExt;
+use tracing::{debug, info, warn};
+
+/// Hashes of the services synced, keyed by `bookkeeping_id`
+pub const SERVICES: TableName = TableName(CompactString::new_inline("__corro_consul_services"));
+/// Hashes of the checks synced, keyed by `bookkeeping_id`
+pub const CHECKS: TableName = TableName(CompactString::new_inline("__corro_consul_checks"));
+
+/// Stands in for a stored hash which couldn't be read, so the id is
+/// rewritten on the next sync
+pub const UNREADABLE_HASH: u64 = 0;
+
+/// Creates a hash table if it doesn't exist yet
+pub fn create_hash_table_sql(table: &TableName) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            id TEXT NOT NULL PRIMARY KEY,
+            hash BLOB NOT NULL
+        );",
+        table.as_str()
+    )
+}
+
+/// Key of `id` in the bookkeeping tables, prefixed w/ the datacenter if any so
+/// the same id synced for different datacenters doesn't collide
+pub fn bookkeeping_id(datacenter: Option<&str>, id: &str) -> String {
+    match datacenter {
+        Some(datacenter) => format!("{datacenter}/{id}"),
+        None => id.to_owned(),
+    }
+}
+
+/// The id a bookkeeping key is for, `None` if it's for another datacenter
+pub fn strip_bookkeeping_id(datacenter: Option<&str>, key: String) -> Option<String> {
+    match datacenter {
+        Some(datacenter) => key
+            .strip_prefix(datacenter)?
+            .strip_prefix('/')
+            .map(str::to_owned),
+        None => Some(key),
+    }
+}
+
+/// Records `hash` for the bookkeeping key `id`, run by corrosion w/ the
+/// statements writing the row so both are part of the same transaction
+pub fn upsert_hash_stmt(table: &TableName, id: &str, hash: u64) -> Statement {
+    Statement::WithParams(
+        format!(
+            "INSERT INTO {} ( id, hash )
+    VALUES (?, ?)
+    ON CONFLICT (id) DO UPDATE SET
+        hash = excluded.hash;",
+            table.as_str()
+        ),
+        vec![id.into(), hash.to_be_bytes().to_vec().into()],
+    )
+}
+
+/// Forgets the hash of the bookkeeping key `id`
+pub fn delete_hash_stmt(table: &TableName, id: &str) -> Statement {
+    Statement::WithParams(
+        format!("DELETE FROM {} WHERE id = ?;", table.as_str()),
+        vec![id.into()],
+    )
+}
+
+/// Reads the next `batch_size` hashes after the bookkeeping key `after`
+fn load_hashes_stmt(table: &TableName, after: &str, batch_size: usize) -> Statement {
+    Statement::WithParams(
+        format!(
+            "SELECT id, hash FROM {} WHERE id > ? ORDER BY id LIMIT ?",
+            table.as_str()
+        ),
+        vec![after.into(), (batch_size.max(1) as i64).into()],
+    )
+}
+
+/// Loads the hashes recorded in a bookkeeping table for `datacenter`,
+/// preferably from the local database file, `batch_size` rows at a time
+pub async fn load_hashes(
+    corrosion: &CorrosionClient,
+    table: &TableName,
+    datacenter: Option<&str>,
+    batch_size: usize,
+) -> eyre::Result<HashMap<String, u64>> {
+    let start = Instant::now();
+    let table_name = table.as_str();
+    let mut hashes = HashMap::new();
+    let mut loaded = 0;
+    let mut unreadable = 0;
+    let mut last_id = String::new();
+
+    loop {
+        let mut rows = corrosion
+            .read(
+                &load_hashes_stmt(table, &last_id, batch_size),
+                ReadPreference::LocalThenApi,
+            )
+            .await?;
+
+        let mut columns = vec![];
+        let mut batch = 0;
+
+        while let Some(evt) = rows.next().await {
+            match evt? {
+                QueryEvent::Columns { names: cols, .. } => columns = cols,
+                QueryEvent::Row(_, cells) => {
+                    let (id, hash) = <(String, Vec<u8>)>::from_values(&cells, &columns)
+                        .map_err(|e| eyre::eyre!("unexpected row in {table_name}: {e}"))?;
+                    batch += 1;
+                    last_id.clone_from(&id);
+
+                    let hash = match <[u8; 8]>::try_from(hash.as_slice()) {
+                        Ok(hash) => u64::from_be_bytes(hash),
+                        Err(_) => {
+                            warn!(
+                                "hash of '{id}' in {table_name} is {} bytes instead of 8, it will be rewritten",
+                                hash.len()
+                            );
+                            unreadable += 1;
+                            UNREADABLE_HASH
+                        }
+                    };
+                    if let Some(id) = strip_bookkeeping_id(datacenter, id) {
+                        hashes.insert(id, hash);
+                    }
+                }
+                QueryEvent::Error(e) => {
+                    eyre::bail!("could not load hashes from {table_name}: {e}")
+                }
+                _ => {}
+            }
+        }
+
+        loaded += batch;
+        if batch < batch_size.max(1) {
+            break;
+        }
+        debug!("loaded {loaded} rows from {table_name} so far");
+    }
+
+    info!(
+        "Loaded {} hashes out of {loaded} rows from {table_name} in {:?}, {unreadable} unreadable",
+        hashes.len(),
+        start.elapsed()
+    );
+
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // params as the SQL literals they bind
+    fn params(stmt: &Statement) -> (&str, Vec<String>) {
+        match stmt {
+            Statement::WithParams(query, params) => (
+                query.as_str(),
+                params.iter().map(|param| param.to_sql_literal()).collect(),
+            ),
+            stmt => panic!("unexpected statement: {stmt:?}"),
+        }
+    }
+
+    #[test]
+    fn table_names() {
+        assert_eq!(SERVICES.as_str(), "__corro_consul_services");
+        assert_eq!(CHECKS.as_str(), "__corro_consul_checks");
+        assert!(create_hash_table_sql(&CHECKS)
+            .starts_with("CREATE TABLE IF NOT EXISTS __corro_consul_checks ("));
+    }
+
+    #[test]
+    fn builds_hash_statements() {
+        let upsert = upsert_hash_stmt(&SERVICES, "dc1/web", 42);
+        let (query, values) = params(&upsert);
+        assert!(query.starts_with("INSERT INTO __corro_consul_services ( id, hash )"));
+        assert!(query.contains("ON CONFLICT (id) DO UPDATE SET"));
+        assert_eq!(values, ["'dc1/web'", "X'000000000000002A'"]);
+
+        let delete = delete_hash_stmt(&CHECKS, "dc1/web-check");
+        let (query, values) = params(&delete);
+        assert_eq!(query, "DELETE FROM __corro_consul_checks WHERE id = ?;");
+        assert_eq!(values, ["'dc1/web-check'"]);
+
+        let load = load_hashes_stmt(&SERVICES, "web", 0);
+        let (query, values) = params(&load);
+        assert_eq!(
+            query,
+            "SELECT id, hash FROM __corro_consul_services WHERE id > ? ORDER BY id LIMIT ?"
+        );
+        // at least a row per batch
+        assert_eq!(values, ["'web'", "1"]);
+    }
+
+    #[test]
+    fn bookkeeping_ids() {
+        assert_eq!(bookkeeping_id(Some("dc1"), "web"), "dc1/web");
+        assert_eq!(bookkeeping_id(None, "web"), "web");
+        assert_eq!(
+            strip_bookkeeping_id(Some("dc1"), "dc1/web".into()).as_deref(),
+            Some("web")
+        );
+        assert_eq!(strip_bookkeeping_id(Some("dc1"), "dc10/web".into()), None);
+        assert_eq!(strip_bookkeeping_id(Some("dc2"), "dc1/web".into()), None);
+        assert_eq!(
+            strip_bookkeeping_id(None, "dc1/web".into()).as_deref(),
+            Some("dc1/web")
+        );
+    }
+}
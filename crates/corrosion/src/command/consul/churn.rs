@@ -0,0 +1,156 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+// the window is split into this many buckets of counts
+const BUCKETS: u64 = 10;
+
+/// Keeps a rolling count of upserts per id to find ids upserted over and
+/// over, e.g. a check whose output changes on every pull.
+///
+/// Upserts are counted in buckets spanning a tenth of the window each, so an
+/// id costs at most `BUCKETS` counts however often it's upserted.
+#[derive(Debug)]
+pub struct ChurnDetector {
+    threshold: usize,
+    bucket_nanos: u128,
+    origin: Instant,
+    upserts: HashMap<(&'static str, String), VecDeque<(u64, usize)>>,
+}
+
+impl ChurnDetector {
+    /// Ids upserted more than `threshold` times within `window` are churning
+    pub fn new(threshold: usize, window: Duration) -> Self {
+        Self {
+            threshold,
+            bucket_nanos: (window.as_nanos() / BUCKETS as u128).max(1),
+            origin: Instant::now(),
+            upserts: HashMap::new(),
+        }
+    }
+
+    fn bucket(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.origin).as_nanos() / self.bucket_nanos) as u64
+    }
+
+    pub fn record(&mut self, kind: &'static str, id: &str, at: Instant) {
+        let bucket = self.bucket(at);
+        let counts = self.upserts.entry((kind, id.to_owned())).or_default();
+        match counts.back_mut() {
+            Some((last, count)) if *last >= bucket => *count += 1,
+            _ => counts.push_back((bucket, 1)),
+        }
+        while counts.len() as u64 > BUCKETS {
+            counts.pop_front();
+        }
+    }
+
+    /// Churning ids as of `now` w/ their upserts count, most upserted first
+    /// and at most `limit` of them. Forgets upserts which fell out of the
+    /// window.
+    pub fn churners(&mut self, now: Instant, limit: usize) -> Vec<(&'static str, String, usize)> {
+        let current = self.bucket(now);
+        self.upserts.retain(|_, counts| {
+            while counts
+                .front()
+                .map_or(false, |(bucket, _)| bucket + BUCKETS <= current)
+            {
+                counts.pop_front();
+            }
+            !counts.is_empty()
+        });
+
+        let mut churners = self
+            .upserts
+            .iter()
+            .map(|((kind, id), counts)| (*kind, id, counts.iter().map(|(_, n)| n).sum::<usize>()))
+            .filter(|(_, _, count)| *count > self.threshold)
+            .map(|(kind, id, count)| (kind, id.clone(), count))
+            .collect::<Vec<_>>();
+        churners.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+        churners.truncate(limit);
+
+        churners
+    }
+
+    /// Number of ids w/ upserts in the window, as of the last `churners` call
+    pub fn tracked_ids(&self) -> usize {
+        self.upserts.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_churning_ids() {
+        let mut churn = ChurnDetector::new(3, Duration::from_secs(60));
+        let start = Instant::now();
+
+        for i in 0..10 {
+            let at = start + Duration::from_secs(i);
+            churn.record("checks", "flappy", at);
+            if i % 2 == 0 {
+                churn.record("services", "busy", at);
+            }
+            if i == 0 {
+                churn.record("services", "stable", at);
+            }
+        }
+
+        let now = start + Duration::from_secs(10);
+        assert_eq!(
+            churn.churners(now, 5),
+            vec![
+                ("checks", "flappy".to_owned(), 10),
+                ("services", "busy".to_owned(), 5),
+            ]
+        );
+        assert_eq!(
+            churn.churners(now, 1),
+            vec![("checks", "flappy".to_owned(), 10)]
+        );
+    }
+
+    #[test]
+    fn kinds_are_counted_separately() {
+        let mut churn = ChurnDetector::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        for i in 0..2 {
+            let at = start + Duration::from_secs(i);
+            churn.record("services", "web", at);
+            churn.record("checks", "web", at);
+        }
+
+        assert!(churn.churners(start + Duration::from_secs(2), 5).is_empty());
+    }
+
+    #[test]
+    fn forgets_upserts_out_of_the_window() {
+        let mut churn = ChurnDetector::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        for i in 0..5 {
+            churn.record("services", "web", start + Duration::from_secs(i));
+        }
+        churn.record("services", "web", start + Duration::from_secs(50));
+
+        let now = start + Duration::from_secs(55);
+        assert_eq!(
+            churn.churners(now, 5),
+            vec![("services", "web".to_owned(), 6)]
+        );
+
+        // only the last upsert is left
+        let now = start + Duration::from_secs(90);
+        assert!(churn.churners(now, 5).is_empty());
+        assert_eq!(churn.tracked_ids(), 1);
+
+        let now = start + Duration::from_secs(180);
+        assert!(churn.churners(now, 5).is_empty());
+        assert_eq!(churn.tracked_ids(), 0);
+    }
+}
@@ -0,0 +1,284 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use consul_client::{AgentCheck, AgentService};
+use corro_types::config::IdNormalization;
+use metrics::increment_counter;
+use tracing::warn;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// Ids consul reported which can't all be synced as-is
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conflict {
+    /// Ids which normalize to the same `id`, only `kept` is synced under it
+    Merged {
+        kind: &'static str,
+        id: String,
+        kept: String,
+        dropped: Vec<String>,
+    },
+    /// Ids only differing by case, they're all synced
+    Case {
+        kind: &'static str,
+        ids: Vec<String>,
+    },
+}
+
+/// Normalizes the ids of services and checks reported by consul, before
+/// they're hashed, looked up in the bookkeeping tables and stored. Does
+/// nothing unless enabled in `consul.normalize-ids`.
+#[derive(Debug, Default)]
+pub struct IdNormalizer {
+    trim: bool,
+    nfc: bool,
+    /// Logged already, not to warn on every tick
+    reported: HashSet<Conflict>,
+}
+
+impl IdNormalizer {
+    pub fn new(config: &IdNormalization) -> Self {
+        Self {
+            trim: config.trim,
+            nfc: config.nfc,
+            reported: HashSet::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.trim || self.nfc
+    }
+
+    pub fn normalize<'a>(&self, id: &'a str) -> Cow<'a, str> {
+        let id = if self.trim { id.trim() } else { id };
+        if self.nfc && !is_nfc(id) {
+            Cow::Owned(id.nfc().collect())
+        } else {
+            Cow::Borrowed(id)
+        }
+    }
+
+    /// Normalizes services' ids, and the keys they're listed under
+    pub fn services(
+        &self,
+        services: HashMap<String, AgentService>,
+    ) -> (HashMap<String, AgentService>, Vec<Conflict>) {
+        self.normalize_all("service", services, |svc| &mut svc.id)
+    }
+
+    /// Normalizes checks' ids, and the keys they're listed under, along w/
+    /// the ids of the services they're bound to
+    pub fn checks(
+        &self,
+        checks: HashMap<String, AgentCheck>,
+    ) -> (HashMap<String, AgentCheck>, Vec<Conflict>) {
+        let (mut checks, conflicts) = self.normalize_all("check", checks, |check| &mut check.id);
+        if self.is_enabled() {
+            for check in checks.values_mut() {
+                if let Cow::Owned(service_id) = self.normalize(&check.service_id) {
+                    check.service_id = service_id;
+                }
+            }
+        }
+        (checks, conflicts)
+    }
+
+    fn normalize_all<T>(
+        &self,
+        kind: &'static str,
+        entries: HashMap<String, T>,
+        id_of: impl Fn(&mut T) -> &mut String,
+    ) -> (HashMap<String, T>, Vec<Conflict>) {
+        if !self.is_enabled() {
+            return (entries, vec![]);
+        }
+
+        // sorted, which one is kept doesn't depend on consul's ordering
+        let mut groups: BTreeMap<String, BTreeMap<String, T>> = BTreeMap::new();
+        for (id, entry) in entries {
+            groups
+                .entry(self.normalize(&id).into_owned())
+                .or_default()
+                .insert(id, entry);
+        }
+
+        let mut conflicts = vec![];
+        let mut by_case: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut normalized = HashMap::with_capacity(groups.len());
+        for (id, mut group) in groups {
+            // the id already normalized, if consul has it, or the smallest
+            let kept = if group.contains_key(&id) {
+                id.clone()
+            } else {
+                group.keys().next().cloned().unwrap_or_default()
+            };
+            let Some(mut entry) = group.remove(&kept) else {
+                continue;
+            };
+            if !group.is_empty() {
+                conflicts.push(Conflict::Merged {
+                    kind,
+                    id: id.clone(),
+                    kept,
+                    dropped: group.into_keys().collect(),
+                });
+            }
+
+            *id_of(&mut entry) = id.clone();
+            by_case
+                .entry(id.to_lowercase())
+                .or_default()
+                .push(id.clone());
+            normalized.insert(id, entry);
+        }
+
+        conflicts.extend(
+            by_case
+                .into_values()
+                .filter(|ids| ids.len() > 1)
+                .map(|ids| Conflict::Case { kind, ids }),
+        );
+
+        (normalized, conflicts)
+    }
+
+    /// Warns about the conflicts of a tick, unless they were already in the
+    /// previous one
+    pub fn report(&mut self, conflicts: Vec<Conflict>) {
+        for conflict in conflicts.iter() {
+            if self.reported.contains(conflict) {
+                continue;
+            }
+            match conflict {
+                Conflict::Merged {
+                    kind,
+                    id,
+                    kept,
+                    dropped,
+                } => {
+                    increment_counter!("corro_consul.ids.conflicts", "type" => *kind, "conflict" => "merged");
+                    warn!("consul {kind} ids {kept:?} and {dropped:?} all normalize to {id:?}, only {kept:?} is synced");
+                }
+                Conflict::Case { kind, ids } => {
+                    increment_counter!("corro_consul.ids.conflicts", "type" => *kind, "conflict" => "case");
+                    warn!("consul {kind} ids {ids:?} only differ by case, they're synced as distinct ids");
+                }
+            }
+        }
+        self.reported = conflicts.into_iter().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use consul_client::ConsulCheckStatus;
+
+    use super::*;
+
+    fn service(id: &str, port: u16) -> (String, AgentService) {
+        (
+            id.to_string(),
+            AgentService {
+                id: id.into(),
+                name: "web".into(),
+                tags: vec![],
+                meta: Default::default(),
+                port,
+                address: "127.0.0.1".into(),
+            },
+        )
+    }
+
+    #[test]
+    fn normalizes_ids() {
+        let normalizer = IdNormalizer::default();
+        assert!(!normalizer.is_enabled());
+        assert_eq!(normalizer.normalize(" web "), " web ");
+
+        let normalizer = IdNormalizer::new(&IdNormalization {
+            trim: true,
+            nfc: false,
+        });
+        assert_eq!(normalizer.normalize(" web\t"), "web");
+        assert_eq!(normalizer.normalize("cafe\u{301}"), "cafe\u{301}");
+
+        let normalizer = IdNormalizer::new(&IdNormalization {
+            trim: true,
+            nfc: true,
+        });
+        assert_eq!(normalizer.normalize(" cafe\u{301}"), "caf\u{e9}");
+        // case is preserved
+        assert_eq!(normalizer.normalize("Web "), "Web");
+        assert!(matches!(normalizer.normalize("web"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn detects_conflicts() {
+        let mut normalizer = IdNormalizer::new(&IdNormalization {
+            trim: true,
+            nfc: false,
+        });
+
+        let services = HashMap::from([
+            service("web ", 1),
+            service(" web", 2),
+            service("db ", 3),
+            service("Db", 4),
+        ]);
+        let (services, conflicts) = normalizer.services(services);
+
+        let mut ids: Vec<_> = services
+            .iter()
+            .map(|(key, svc)| (key.as_str(), svc.id.as_str(), svc.port))
+            .collect();
+        ids.sort();
+        // neither " web" nor "web " is normalized, the smallest is kept
+        assert_eq!(
+            ids,
+            vec![("Db", "Db", 4), ("db", "db", 3), ("web", "web", 2)]
+        );
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict::Merged {
+                    kind: "service",
+                    id: "web".into(),
+                    kept: " web".into(),
+                    dropped: vec!["web ".into()],
+                },
+                Conflict::Case {
+                    kind: "service",
+                    ids: vec!["Db".into(), "db".into()],
+                },
+            ]
+        );
+
+        // the normalized id wins if consul has it
+        let (services, _) =
+            normalizer.services(HashMap::from([service("web ", 1), service("web", 2)]));
+        assert_eq!(services["web"].port, 2);
+
+        normalizer.report(conflicts.clone());
+        assert_eq!(normalizer.reported.len(), 2);
+        normalizer.report(vec![]);
+        assert!(normalizer.reported.is_empty());
+
+        let checks = HashMap::from([(
+            "web-check ".to_string(),
+            AgentCheck {
+                id: "web-check ".into(),
+                name: "web-check".into(),
+                status: ConsulCheckStatus::Passing,
+                output: "".into(),
+                service_id: " web".into(),
+                service_name: "web".into(),
+                notes: None,
+            },
+        )]);
+        let (checks, conflicts) = normalizer.checks(checks);
+        assert!(conflicts.is_empty());
+        assert_eq!(checks["web-check"].id, "web-check");
+        assert_eq!(checks["web-check"].service_id, "web");
+    }
+}
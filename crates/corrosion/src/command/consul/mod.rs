@@ -1 +1,10 @@
+pub mod bookkeeping;
+pub mod churn;
+pub mod ids;
+pub mod recording;
+pub mod rewrite;
+pub mod rows;
+pub mod simulate;
+pub mod source;
 pub mod sync;
+pub mod verify;
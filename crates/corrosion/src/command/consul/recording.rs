@@ -0,0 +1,417 @@
+//! Consul workloads recorded by `consul sync --record` and replayed by
+//! `consul simulate`. A recording is a directory holding `meta.json`, the node
+//! and datacenter it was recorded for, and one gzipped JSON file per tick w/
+//! consul's responses as it sent them.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter},
+    sync::Mutex,
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use camino::{Utf8Path, Utf8PathBuf};
+use consul_client::{AgentCheck, AgentService, ConsulCheckStatus, ConsulResult, Error};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+use super::source::ConsulSource;
+
+const META_FILE: &str = "meta.json";
+const TICK_SUFFIX: &str = ".json.gz";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordingMeta {
+    pub node: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datacenter: Option<String>,
+}
+
+/// Consul's responses to one tick, `null` when a request failed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedTick {
+    /// Since the first tick, in milliseconds
+    pub at_ms: u64,
+    pub services: Value,
+    pub checks: Value,
+}
+
+// consul's own shape, which is what `AgentService` deserializes from
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulService<'a> {
+    #[serde(rename = "ID")]
+    id: &'a str,
+    #[serde(rename = "Service")]
+    name: &'a str,
+    tags: &'a [String],
+    meta: &'a BTreeMap<String, String>,
+    port: u16,
+    address: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConsulCheck<'a> {
+    #[serde(rename = "CheckID")]
+    id: &'a str,
+    name: &'a str,
+    status: ConsulCheckStatus,
+    output: &'a str,
+    #[serde(rename = "ServiceID")]
+    service_id: &'a str,
+    service_name: &'a str,
+    notes: &'a str,
+}
+
+fn services_value(services: &HashMap<String, AgentService>) -> serde_json::Result<Value> {
+    let services: BTreeMap<&str, ConsulService> = services
+        .iter()
+        .map(|(id, svc)| {
+            (
+                id.as_str(),
+                ConsulService {
+                    id: &svc.id,
+                    name: &svc.name,
+                    tags: &svc.tags,
+                    meta: &svc.meta,
+                    port: svc.port,
+                    address: &svc.address,
+                },
+            )
+        })
+        .collect();
+    serde_json::to_value(services)
+}
+
+fn checks_value(checks: &HashMap<String, AgentCheck>) -> serde_json::Result<Value> {
+    let checks: BTreeMap<&str, ConsulCheck> = checks
+        .iter()
+        .map(|(id, check)| {
+            (
+                id.as_str(),
+                ConsulCheck {
+                    id: &check.id,
+                    name: &check.name,
+                    status: check.status,
+                    output: &check.output,
+                    service_id: &check.service_id,
+                    service_name: &check.service_name,
+                    notes: check.notes.as_deref().unwrap_or(""),
+                },
+            )
+        })
+        .collect();
+    serde_json::to_value(checks)
+}
+
+fn tick_path(dir: &Utf8Path, index: usize) -> Utf8PathBuf {
+    dir.join(format!("{index:08}{TICK_SUFFIX}"))
+}
+
+/// Writes `tick` gzipped to `path`
+pub fn write_tick(path: &Utf8Path, tick: &RecordedTick) -> eyre::Result<()> {
+    let mut encoder = GzEncoder::new(BufWriter::new(File::create(path)?), Compression::default());
+    serde_json::to_writer(&mut encoder, tick)?;
+    encoder.finish()?.into_inner().map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Reads the recording in `dir`, w/ its ticks in the order they were recorded
+pub fn read_recording(dir: &Utf8Path) -> eyre::Result<(RecordingMeta, Vec<RecordedTick>)> {
+    let meta_path = dir.join(META_FILE);
+    let meta: RecordingMeta = serde_json::from_reader(BufReader::new(
+        File::open(&meta_path).map_err(|e| eyre::eyre!("could not open {meta_path}: {e}"))?,
+    ))?;
+
+    let mut paths = vec![];
+    for entry in dir.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.file_name().ends_with(TICK_SUFFIX) {
+            paths.push(entry.into_path());
+        }
+    }
+    // zero-padded, sorting by name sorts by index
+    paths.sort();
+
+    let ticks = paths
+        .iter()
+        .map(|path| {
+            serde_json::from_reader(GzDecoder::new(BufReader::new(File::open(path)?)))
+                .map_err(|e| eyre::eyre!("could not read tick {path}: {e}"))
+        })
+        .collect::<eyre::Result<Vec<RecordedTick>>>()?;
+
+    Ok((meta, ticks))
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    at_ms: u64,
+    services: Option<Value>,
+    checks: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Half {
+    Services,
+    Checks,
+}
+
+impl Pending {
+    fn half(&mut self, half: Half) -> &mut Option<Value> {
+        match half {
+            Half::Services => &mut self.services,
+            Half::Checks => &mut self.checks,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RecordingState {
+    started: Option<Instant>,
+    next: usize,
+    pending: Pending,
+}
+
+/// Records what `inner` replies to each tick to a directory, once both its
+/// services and checks are in. A half which never came in, because the tick
+/// timed out waiting on it, is recorded as failed once the next tick asks for
+/// it again.
+pub struct Recording<C> {
+    inner: C,
+    dir: Utf8PathBuf,
+    state: Mutex<RecordingState>,
+}
+
+impl<C> Recording<C> {
+    /// Records to `dir`, which can't already hold a recording
+    pub fn new(inner: C, dir: Utf8PathBuf, meta: &RecordingMeta) -> eyre::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        for entry in dir.read_dir_utf8()? {
+            if entry?.file_name().ends_with(TICK_SUFFIX) {
+                eyre::bail!("{dir} already holds a recording");
+            }
+        }
+        serde_json::to_writer_pretty(File::create(dir.join(META_FILE))?, meta)?;
+
+        Ok(Self {
+            inner,
+            dir,
+            state: Mutex::new(RecordingState {
+                started: None,
+                next: 0,
+                pending: Pending::default(),
+            }),
+        })
+    }
+
+    fn record(&self, half: Half, value: Value) {
+        let mut state = self.state.lock().unwrap();
+        let started = *state.started.get_or_insert_with(Instant::now);
+
+        if state.pending.half(half).is_some() {
+            self.flush(&mut state);
+        }
+        if state.pending.services.is_none() && state.pending.checks.is_none() {
+            state.pending.at_ms = started.elapsed().as_millis() as u64;
+        }
+        *state.pending.half(half) = Some(value);
+
+        if state.pending.services.is_some() && state.pending.checks.is_some() {
+            self.flush(&mut state);
+        }
+    }
+
+    // writes the pending tick, w/ whichever half is missing as failed
+    fn flush(&self, state: &mut RecordingState) {
+        let pending = std::mem::take(&mut state.pending);
+        let tick = RecordedTick {
+            at_ms: pending.at_ms,
+            services: pending.services.unwrap_or(Value::Null),
+            checks: pending.checks.unwrap_or(Value::Null),
+        };
+
+        let path = tick_path(&self.dir, state.next);
+        state.next += 1;
+        if let Err(e) = write_tick(&path, &tick) {
+            warn!("could not record consul tick to {path}: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl<C: ConsulSource> ConsulSource for Recording<C> {
+    async fn agent_services(&self) -> ConsulResult<HashMap<String, AgentService>> {
+        let res = self.inner.agent_services().await;
+        let value = match &res {
+            Ok(services) => services_value(services).unwrap_or(Value::Null),
+            Err(_) => Value::Null,
+        };
+        self.record(Half::Services, value);
+        res
+    }
+
+    async fn agent_checks(&self) -> ConsulResult<HashMap<String, AgentCheck>> {
+        let res = self.inner.agent_checks().await;
+        let value = match &res {
+            Ok(checks) => checks_value(checks).unwrap_or(Value::Null),
+            Err(_) => Value::Null,
+        };
+        self.record(Half::Checks, value);
+        res
+    }
+}
+
+/// Replies w/ the tick being played. Failed requests fail again, as consul
+/// being unavailable.
+#[derive(Debug, Default)]
+pub struct Replay {
+    tick: Mutex<(Value, Value)>,
+}
+
+impl Replay {
+    pub fn play(&self, tick: RecordedTick) {
+        *self.tick.lock().unwrap() = (tick.services, tick.checks);
+    }
+}
+
+fn replay<T: serde::de::DeserializeOwned>(value: Value) -> ConsulResult<T> {
+    if value.is_null() {
+        return Err(Error::BadStatusCode(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+#[async_trait]
+impl ConsulSource for Replay {
+    async fn agent_services(&self) -> ConsulResult<HashMap<String, AgentService>> {
+        let value = self.tick.lock().unwrap().0.clone();
+        replay(value)
+    }
+
+    async fn agent_checks(&self) -> ConsulResult<HashMap<String, AgentCheck>> {
+        let value = self.tick.lock().unwrap().1.clone();
+        replay(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::consul::source::faulty::{FaultyConsul, Reply};
+
+    #[tokio::test]
+    async fn records_what_replays() -> eyre::Result<()> {
+        let tmpdir = tempfile::TempDir::new()?;
+        let dir = Utf8PathBuf::try_from(tmpdir.path().join("recording"))?;
+
+        let svc = AgentService {
+            id: "web-1".into(),
+            name: "web".into(),
+            tags: vec!["primary".into()],
+            meta: [("app_id".to_string(), "12".to_string())].into(),
+            port: 8080,
+            address: "10.0.0.2".into(),
+        };
+        let check = || AgentCheck {
+            id: "service:web-1".into(),
+            name: "web health".into(),
+            status: ConsulCheckStatus::Warning,
+            output: "slow".into(),
+            service_id: "web-1".into(),
+            service_name: "web".into(),
+            notes: None,
+        };
+
+        let consul = FaultyConsul::default();
+        consul
+            .push_services(Reply::ok([(svc.id.clone(), svc.clone())].into()))
+            .push_checks(Reply::ok([("service:web-1".to_string(), check())].into()))
+            .push_services(Reply::err(StatusCode::INTERNAL_SERVER_ERROR));
+
+        let meta = RecordingMeta {
+            node: "node-1".into(),
+            datacenter: Some("dc1".into()),
+        };
+        let recording = Recording::new(consul, dir.clone(), &meta)?;
+        for _ in 0..2 {
+            _ = tokio::join!(recording.agent_services(), recording.agent_checks());
+        }
+        // the first half of a tick which never finished
+        recording.agent_services().await?;
+        recording.agent_services().await?;
+
+        let (read_meta, ticks) = read_recording(&dir)?;
+        assert_eq!(read_meta, meta);
+        assert_eq!(ticks.len(), 3);
+        assert!(ticks.windows(2).all(|w| w[0].at_ms <= w[1].at_ms));
+        assert!(ticks[1].services.is_null());
+        assert!(ticks[2].checks.is_null());
+
+        let replay = Replay::default();
+        let mut ticks = ticks.into_iter();
+        replay.play(ticks.next().unwrap());
+        let services = replay.agent_services().await?;
+        assert_eq!(services.len(), 1);
+        let replayed = &services["web-1"];
+        assert_eq!(
+            (
+                &replayed.id,
+                &replayed.name,
+                &replayed.tags,
+                &replayed.meta,
+                replayed.port,
+                &replayed.address
+            ),
+            (
+                &svc.id,
+                &svc.name,
+                &svc.tags,
+                &svc.meta,
+                svc.port,
+                &svc.address
+            )
+        );
+        let checks = replay.agent_checks().await?;
+        let replayed = &checks["service:web-1"];
+        let expected = check();
+        assert_eq!(
+            (
+                &replayed.id,
+                &replayed.name,
+                replayed.status,
+                &replayed.output,
+                &replayed.service_id,
+                &replayed.service_name,
+                &replayed.notes
+            ),
+            (
+                &expected.id,
+                &expected.name,
+                expected.status,
+                &expected.output,
+                &expected.service_id,
+                &expected.service_name,
+                &expected.notes
+            )
+        );
+
+        replay.play(ticks.next().unwrap());
+        assert!(matches!(
+            replay.agent_services().await,
+            Err(Error::BadStatusCode(StatusCode::SERVICE_UNAVAILABLE))
+        ));
+        assert!(replay.agent_checks().await?.is_empty());
+
+        // never over an existing recording
+        assert!(Recording::new(FaultyConsul::default(), dir, &meta).is_err());
+
+        Ok(())
+    }
+}
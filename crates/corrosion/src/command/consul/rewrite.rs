@@ -0,0 +1,181 @@
+use consul_client::AgentService;
+use corro_types::config::ServiceRewrite;
+use regex::Regex;
+use tracing::trace;
+
+enum Rule {
+    AddressFromMeta(String),
+    PortOffset(i32),
+    AddressReplace(Regex, String),
+}
+
+/// Applies the configured rewrite rules, in order, to services reported by
+/// consul. Rewriting happens before hashing so only changes to the rewritten
+/// values trigger an update.
+#[derive(Default)]
+pub struct ServiceRewriter {
+    rules: Vec<Rule>,
+}
+
+impl ServiceRewriter {
+    pub fn new(rewrites: &[ServiceRewrite]) -> Result<Self, regex::Error> {
+        let rules = rewrites
+            .iter()
+            .map(|rewrite| {
+                Ok(match rewrite {
+                    ServiceRewrite::RewriteAddress { from_meta } => {
+                        Rule::AddressFromMeta(from_meta.clone())
+                    }
+                    ServiceRewrite::PortOffset(offset) => Rule::PortOffset(*offset),
+                    ServiceRewrite::AddressReplace {
+                        pattern,
+                        replacement,
+                    } => Rule::AddressReplace(Regex::new(pattern)?, replacement.clone()),
+                })
+            })
+            .collect::<Result<Vec<_>, regex::Error>>()?;
+
+        Ok(Self { rules })
+    }
+
+    pub fn apply(&self, svc: &mut AgentService) {
+        for rule in self.rules.iter() {
+            match rule {
+                Rule::AddressFromMeta(key) => {
+                    if let Some(address) = svc.meta.get(key) {
+                        trace!("rewriting service '{}' address from meta '{key}'", svc.id);
+                        svc.address = address.clone();
+                    }
+                }
+                Rule::PortOffset(offset) => {
+                    if let Ok(port) = u16::try_from(svc.port as i32 + offset) {
+                        svc.port = port;
+                    }
+                }
+                Rule::AddressReplace(re, replacement) => {
+                    svc.address = re
+                        .replace_all(&svc.address, replacement.as_str())
+                        .into_owned();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use corro_types::config::StaticColumns;
+
+    use crate::command::consul::sync::hash_service;
+
+    fn service() -> AgentService {
+        AgentService {
+            id: "service-id".into(),
+            name: "service-name".into(),
+            tags: vec![],
+            meta: vec![("external_ip".to_string(), "1.2.3.4".to_string())]
+                .into_iter()
+                .collect(),
+            port: 8080,
+            address: "10.0.0.1".into(),
+        }
+    }
+
+    fn rewritten(rewrites: &[ServiceRewrite], mut svc: AgentService) -> AgentService {
+        ServiceRewriter::new(rewrites).unwrap().apply(&mut svc);
+        svc
+    }
+
+    #[test]
+    fn address_from_meta() {
+        let rewrites = [ServiceRewrite::RewriteAddress {
+            from_meta: "external_ip".into(),
+        }];
+        assert_eq!(rewritten(&rewrites, service()).address, "1.2.3.4");
+
+        let mut svc = service();
+        svc.meta.clear();
+        assert_eq!(rewritten(&rewrites, svc).address, "10.0.0.1");
+    }
+
+    #[test]
+    fn port_offset() {
+        assert_eq!(
+            rewritten(&[ServiceRewrite::PortOffset(1000)], service()).port,
+            9080
+        );
+        assert_eq!(
+            rewritten(&[ServiceRewrite::PortOffset(-80)], service()).port,
+            8000
+        );
+        // out of range, left unchanged
+        assert_eq!(
+            rewritten(&[ServiceRewrite::PortOffset(60000)], service()).port,
+            8080
+        );
+    }
+
+    #[test]
+    fn address_replace() {
+        let rewrites = [ServiceRewrite::AddressReplace {
+            pattern: r"^10\.0\.(\d+)\.(\d+)$".into(),
+            replacement: "172.16.$1.$2".into(),
+        }];
+        assert_eq!(rewritten(&rewrites, service()).address, "172.16.0.1");
+
+        assert!(ServiceRewriter::new(&[ServiceRewrite::AddressReplace {
+            pattern: "(".into(),
+            replacement: "".into(),
+        }])
+        .is_err());
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let rewrites = [
+            ServiceRewrite::RewriteAddress {
+                from_meta: "external_ip".into(),
+            },
+            ServiceRewrite::AddressReplace {
+                pattern: r"\.4$".into(),
+                replacement: ".5".into(),
+            },
+            ServiceRewrite::PortOffset(1),
+            ServiceRewrite::PortOffset(1),
+        ];
+        let svc = rewritten(&rewrites, service());
+        assert_eq!(svc.address, "1.2.3.5");
+        assert_eq!(svc.port, 8082);
+    }
+
+    #[test]
+    fn hash_follows_rewritten_values() {
+        let rewrites = [ServiceRewrite::RewriteAddress {
+            from_meta: "external_ip".into(),
+        }];
+
+        let base = hash_service(
+            &rewritten(&rewrites, service()),
+            None,
+            &StaticColumns::default(),
+        );
+
+        // the pod-internal address changed, but it's rewritten away
+        let mut svc = service();
+        svc.address = "10.0.0.2".into();
+        assert_eq!(
+            hash_service(&rewritten(&rewrites, svc), None, &StaticColumns::default()),
+            base
+        );
+
+        // the rewritten address changed
+        let mut svc = service();
+        svc.meta.insert("external_ip".into(), "4.3.2.1".into());
+        assert_ne!(
+            hash_service(&rewritten(&rewrites, svc), None, &StaticColumns::default()),
+            base
+        );
+    }
+}
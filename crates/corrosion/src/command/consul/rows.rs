@@ -0,0 +1,589 @@
+//! Rows of `consul_services` and `consul_checks` as they're upserted. Each
+//! column is listed once, w/ the field its value comes from: the upsert's
+//! SQL and its params are generated from the same list and can't drift.
+
+use std::borrow::Cow;
+
+use consul_client::{AgentCheck, AgentService, ConsulCheckStatus};
+use corro_api_types::SqliteParam;
+use corro_types::{api::Statement, config::StaticColumns};
+use metrics::increment_counter;
+
+use super::sync::stored_output;
+
+/// Optional columns of the consul tables, only written when they have them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColumnSet {
+    /// `consul_services.status`, the aggregate status of a service's checks
+    pub status: bool,
+    /// `consul_checks.synthetic`
+    pub synthetic: bool,
+    /// `datacenter` of both tables, part of their primary key
+    pub datacenter: bool,
+}
+
+impl ColumnSet {
+    fn contains(&self, optional: Option<OptionalColumn>) -> bool {
+        match optional {
+            None => true,
+            Some(OptionalColumn::Status) => self.status,
+            Some(OptionalColumn::Synthetic) => self.synthetic,
+            Some(OptionalColumn::Datacenter) => self.datacenter,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionalColumn {
+    Status,
+    Synthetic,
+    Datacenter,
+}
+
+/// How a column is set when the row already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Part of the primary key, the conflict target
+    Key,
+    Replace,
+    /// Kept if greater, it never goes back
+    Max,
+}
+
+#[derive(Debug)]
+pub struct Column<F> {
+    pub name: &'static str,
+    pub field: F,
+    pub on_conflict: OnConflict,
+    pub optional: Option<OptionalColumn>,
+}
+
+const fn column<F>(name: &'static str, field: F, on_conflict: OnConflict) -> Column<F> {
+    Column {
+        name,
+        field,
+        on_conflict,
+        optional: None,
+    }
+}
+
+const fn optional<F>(
+    name: &'static str,
+    field: F,
+    on_conflict: OnConflict,
+    optional: OptionalColumn,
+) -> Column<F> {
+    Column {
+        name,
+        field,
+        on_conflict,
+        optional: Some(optional),
+    }
+}
+
+pub trait Row {
+    type Field: Copy + 'static;
+
+    const TABLE: &'static str;
+
+    /// Every column, optional ones included, in the order they're written
+    fn columns() -> &'static [Column<Self::Field>];
+
+    fn value(&self, field: Self::Field) -> SqliteParam;
+
+    /// Values of the `enabled_columns`, in the order they're written
+    fn to_params(&self, enabled_columns: &ColumnSet) -> Vec<SqliteParam> {
+        Self::columns()
+            .iter()
+            .filter(|column| enabled_columns.contains(column.optional))
+            .map(|column| self.value(column.field))
+            .collect()
+    }
+
+    /// Inserts the row or updates the existing one, w/ `static_columns`
+    /// written after the `enabled_columns`
+    fn upsert(&self, enabled_columns: &ColumnSet, static_columns: &StaticColumns) -> Statement {
+        let mut names = vec![];
+        let mut keys = vec![];
+        let mut updates = vec![];
+        let enabled = Self::columns()
+            .iter()
+            .filter(|column| enabled_columns.contains(column.optional));
+        for column in enabled {
+            let name = column.name;
+            names.push(Cow::Borrowed(name));
+            match column.on_conflict {
+                OnConflict::Key => keys.push(name),
+                OnConflict::Replace => updates.push(format!("{name} = excluded.{name}")),
+                OnConflict::Max => updates.push(format!(
+                    "{name} = MAX(excluded.{name}, {}.{name})",
+                    Self::TABLE
+                )),
+            }
+        }
+
+        let mut params = self.to_params(enabled_columns);
+        // names are plain identifiers, quoted in case they're keywords
+        for (name, value) in static_columns.iter() {
+            names.push(Cow::Owned(format!("\"{name}\"")));
+            updates.push(format!("\"{name}\" = excluded.\"{name}\""));
+            params.push(value.into());
+        }
+
+        Statement::WithParams(
+            format!(
+                "INSERT INTO {} ( {} )\n    VALUES ({})\n    ON CONFLICT({}) DO UPDATE SET\n        {};",
+                Self::TABLE,
+                names.join(", "),
+                vec!["?"; params.len()].join(","),
+                keys.join(", "),
+                updates.join(",\n        "),
+            ),
+            params,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceField {
+    Node,
+    Datacenter,
+    Id,
+    Name,
+    Tags,
+    Meta,
+    Port,
+    Address,
+    UpdatedAt,
+    Status,
+}
+
+/// A `consul_services` row
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceRow {
+    pub node: String,
+    pub datacenter: Option<String>,
+    pub id: String,
+    pub name: String,
+    pub tags_json: String,
+    pub meta_json: String,
+    pub port: u16,
+    pub address: String,
+    pub updated_at: i64,
+    /// Aggregate status of the service's checks, if stored
+    pub status: Option<ConsulCheckStatus>,
+}
+
+const SERVICE_COLUMNS: &[Column<ServiceField>] = &[
+    column("node", ServiceField::Node, OnConflict::Key),
+    optional(
+        "datacenter",
+        ServiceField::Datacenter,
+        OnConflict::Key,
+        OptionalColumn::Datacenter,
+    ),
+    column("id", ServiceField::Id, OnConflict::Key),
+    column("name", ServiceField::Name, OnConflict::Replace),
+    column("tags", ServiceField::Tags, OnConflict::Replace),
+    column("meta", ServiceField::Meta, OnConflict::Replace),
+    column("port", ServiceField::Port, OnConflict::Replace),
+    column("address", ServiceField::Address, OnConflict::Replace),
+    column("updated_at", ServiceField::UpdatedAt, OnConflict::Max),
+    optional(
+        "status",
+        ServiceField::Status,
+        OnConflict::Replace,
+        OptionalColumn::Status,
+    ),
+];
+
+impl ServiceRow {
+    pub fn new(svc: AgentService, node: &str, updated_at: i64) -> Self {
+        Self {
+            node: node.to_owned(),
+            datacenter: None,
+            tags_json: serde_json::to_string(&svc.tags).unwrap_or_else(|_| "[]".to_string()),
+            meta_json: serde_json::to_string(&svc.meta).unwrap_or_else(|_| "{}".to_string()),
+            id: svc.id,
+            name: svc.name,
+            port: svc.port,
+            address: svc.address,
+            updated_at,
+            status: None,
+        }
+    }
+
+    pub fn with_datacenter(mut self, datacenter: Option<&str>) -> Self {
+        self.datacenter = datacenter.map(str::to_owned);
+        self
+    }
+
+    pub fn with_status(mut self, status: Option<ConsulCheckStatus>) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// The optional columns this row has values for
+    pub fn column_set(&self) -> ColumnSet {
+        ColumnSet {
+            status: self.status.is_some(),
+            datacenter: self.datacenter.is_some(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Row for ServiceRow {
+    type Field = ServiceField;
+
+    const TABLE: &'static str = "consul_services";
+
+    fn columns() -> &'static [Column<ServiceField>] {
+        SERVICE_COLUMNS
+    }
+
+    fn value(&self, field: ServiceField) -> SqliteParam {
+        match field {
+            ServiceField::Node => self.node.as_str().into(),
+            ServiceField::Datacenter => self
+                .datacenter
+                .as_deref()
+                .map_or(SqliteParam::Null, Into::into),
+            ServiceField::Id => self.id.as_str().into(),
+            ServiceField::Name => self.name.as_str().into(),
+            ServiceField::Tags => self.tags_json.as_str().into(),
+            ServiceField::Meta => self.meta_json.as_str().into(),
+            ServiceField::Port => self.port.into(),
+            ServiceField::Address => self.address.as_str().into(),
+            ServiceField::UpdatedAt => self.updated_at.into(),
+            ServiceField::Status => self
+                .status
+                .map_or(SqliteParam::Null, |status| status.as_str().into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckField {
+    Node,
+    Datacenter,
+    Id,
+    ServiceId,
+    ServiceName,
+    Name,
+    Status,
+    Output,
+    UpdatedAt,
+    Synthetic,
+}
+
+/// A `consul_checks` row
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckRow {
+    pub node: String,
+    pub datacenter: Option<String>,
+    pub id: String,
+    pub service_id: String,
+    pub service_name: String,
+    pub name: String,
+    pub status: ConsulCheckStatus,
+    /// Stored output, possibly truncated, see `with_max_output_bytes`
+    pub output: String,
+    pub updated_at: i64,
+    /// Whether the check was synthesized for a service w/o any, if stored
+    pub synthetic: Option<bool>,
+}
+
+const CHECK_COLUMNS: &[Column<CheckField>] = &[
+    column("node", CheckField::Node, OnConflict::Key),
+    optional(
+        "datacenter",
+        CheckField::Datacenter,
+        OnConflict::Key,
+        OptionalColumn::Datacenter,
+    ),
+    column("id", CheckField::Id, OnConflict::Key),
+    column("service_id", CheckField::ServiceId, OnConflict::Replace),
+    column("service_name", CheckField::ServiceName, OnConflict::Replace),
+    column("name", CheckField::Name, OnConflict::Replace),
+    column("status", CheckField::Status, OnConflict::Replace),
+    column("output", CheckField::Output, OnConflict::Replace),
+    column("updated_at", CheckField::UpdatedAt, OnConflict::Max),
+    optional(
+        "synthetic",
+        CheckField::Synthetic,
+        OnConflict::Replace,
+        OptionalColumn::Synthetic,
+    ),
+];
+
+impl CheckRow {
+    pub fn new(check: AgentCheck, node: &str, updated_at: i64) -> Self {
+        Self {
+            node: node.to_owned(),
+            datacenter: None,
+            id: check.id,
+            service_id: check.service_id,
+            service_name: check.service_name,
+            name: check.name,
+            status: check.status,
+            output: check.output,
+            updated_at,
+            synthetic: None,
+        }
+    }
+
+    pub fn with_datacenter(mut self, datacenter: Option<&str>) -> Self {
+        self.datacenter = datacenter.map(str::to_owned);
+        self
+    }
+
+    pub fn with_synthetic(mut self, synthetic: Option<bool>) -> Self {
+        self.synthetic = synthetic;
+        self
+    }
+
+    /// Truncates the output past `max_bytes`, see `stored_output`
+    pub fn with_max_output_bytes(mut self, max_bytes: Option<usize>) -> Self {
+        if let Cow::Owned(truncated) = stored_output(&self.output, max_bytes) {
+            increment_counter!("corro_consul.check.output.truncated");
+            self.output = truncated;
+        }
+        self
+    }
+
+    /// The optional columns this row has values for
+    pub fn column_set(&self) -> ColumnSet {
+        ColumnSet {
+            synthetic: self.synthetic.is_some(),
+            datacenter: self.datacenter.is_some(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Row for CheckRow {
+    type Field = CheckField;
+
+    const TABLE: &'static str = "consul_checks";
+
+    fn columns() -> &'static [Column<CheckField>] {
+        CHECK_COLUMNS
+    }
+
+    fn value(&self, field: CheckField) -> SqliteParam {
+        match field {
+            CheckField::Node => self.node.as_str().into(),
+            CheckField::Datacenter => self
+                .datacenter
+                .as_deref()
+                .map_or(SqliteParam::Null, Into::into),
+            CheckField::Id => self.id.as_str().into(),
+            CheckField::ServiceId => self.service_id.as_str().into(),
+            CheckField::ServiceName => self.service_name.as_str().into(),
+            CheckField::Name => self.name.as_str().into(),
+            CheckField::Status => self.status.as_str().into(),
+            CheckField::Output => self.output.as_str().into(),
+            CheckField::UpdatedAt => self.updated_at.into(),
+            CheckField::Synthetic => self
+                .synthetic
+                .map_or(SqliteParam::Null, |synthetic| (synthetic as i64).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use rusqlite::{params_from_iter, Connection};
+
+    use super::*;
+    use crate::command::consul::sync::TRUNCATED_OUTPUT_MARKER;
+
+    fn column_sets() -> Vec<ColumnSet> {
+        let mut sets = vec![];
+        for status in [false, true] {
+            for synthetic in [false, true] {
+                for datacenter in [false, true] {
+                    sets.push(ColumnSet {
+                        status,
+                        synthetic,
+                        datacenter,
+                    });
+                }
+            }
+        }
+        sets
+    }
+
+    // consul tables w/ only the enabled optional columns, and the static ones
+    fn setup(enabled: &ColumnSet, static_columns: &StaticColumns) -> rusqlite::Result<Connection> {
+        let (datacenter, pk) = if enabled.datacenter {
+            ("datacenter TEXT NOT NULL,", "node, datacenter, id")
+        } else {
+            ("", "node, id")
+        };
+        let status = if enabled.status { "status TEXT," } else { "" };
+        let synthetic = if enabled.synthetic {
+            "synthetic INTEGER,"
+        } else {
+            ""
+        };
+        let statics: String = static_columns
+            .iter()
+            .map(|(name, _)| format!("\"{name}\" TEXT,"))
+            .collect();
+
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(&format!(
+            "CREATE TABLE consul_services (
+                node TEXT NOT NULL, {datacenter} id TEXT NOT NULL, name TEXT, tags TEXT,
+                meta TEXT, port INTEGER, address TEXT, updated_at INTEGER, {status} {statics}
+                PRIMARY KEY ({pk})
+            );
+            CREATE TABLE consul_checks (
+                node TEXT NOT NULL, {datacenter} id TEXT NOT NULL, service_id TEXT,
+                service_name TEXT, name TEXT, status TEXT, output TEXT, updated_at INTEGER,
+                {synthetic} {statics}
+                PRIMARY KEY ({pk})
+            );"
+        ))?;
+        Ok(conn)
+    }
+
+    fn execute(conn: &Connection, statement: Statement) -> rusqlite::Result<usize> {
+        match statement {
+            Statement::WithParams(sql, params) => conn.execute(&sql, params_from_iter(params)),
+            statement => panic!("unexpected statement: {statement:?}"),
+        }
+    }
+
+    // each enabled column was written w/ its own field's value
+    fn assert_stored<R: Row>(conn: &Connection, stored: &R, enabled: &ColumnSet) {
+        let columns = R::columns()
+            .iter()
+            .filter(|column| enabled.contains(column.optional));
+        for column in columns {
+            let value = stored.value(column.field);
+            let count: i64 = conn
+                .query_row(
+                    &format!(
+                        "SELECT COUNT(*) FROM {} WHERE {} IS ?",
+                        R::TABLE,
+                        column.name
+                    ),
+                    [&value],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(
+                count,
+                1,
+                "{}.{} = {value:?}, {enabled:?}",
+                R::TABLE,
+                column.name
+            );
+        }
+    }
+
+    fn service(enabled: &ColumnSet, updated_at: i64) -> ServiceRow {
+        ServiceRow::new(
+            AgentService {
+                id: "web-1".into(),
+                name: "web".into(),
+                tags: vec!["a".into(), "b".into()],
+                meta: [("k".to_string(), "v".to_string())].into(),
+                port: 8080,
+                address: "10.0.0.1".into(),
+            },
+            "node-1",
+            updated_at,
+        )
+        .with_datacenter(enabled.datacenter.then_some("ams"))
+        .with_status(enabled.status.then_some(ConsulCheckStatus::Warning))
+    }
+
+    fn check(enabled: &ColumnSet, updated_at: i64) -> CheckRow {
+        CheckRow::new(
+            AgentCheck {
+                id: "web-1-check".into(),
+                name: "http".into(),
+                status: ConsulCheckStatus::Critical,
+                output: "connection refused".into(),
+                service_id: "web-1".into(),
+                service_name: "web".into(),
+                notes: None,
+            },
+            "node-1",
+            updated_at,
+        )
+        .with_datacenter(enabled.datacenter.then_some("ams"))
+        .with_synthetic(enabled.synthetic.then_some(true))
+    }
+
+    #[test]
+    fn aligns_columns_and_params() -> eyre::Result<()> {
+        let region =
+            StaticColumns::try_from(BTreeMap::from([("region".to_string(), "eu".to_string())]))?;
+
+        for enabled in column_sets() {
+            for static_columns in [StaticColumns::default(), region.clone()] {
+                let conn = setup(&enabled, &static_columns)?;
+
+                let svc = service(&enabled, 10);
+                assert_eq!(
+                    svc.column_set(),
+                    ColumnSet {
+                        synthetic: false,
+                        ..enabled
+                    }
+                );
+                let Statement::WithParams(sql, params) = svc.upsert(&enabled, &static_columns)
+                else {
+                    unreachable!()
+                };
+                assert_eq!(sql.matches('?').count(), params.len(), "{sql}");
+                execute(&conn, Statement::WithParams(sql, params))?;
+                assert_stored(&conn, &svc, &enabled);
+
+                let check = check(&enabled, 10);
+                execute(&conn, check.upsert(&enabled, &static_columns))?;
+                assert_stored(&conn, &check, &enabled);
+
+                for table in ["consul_services", "consul_checks"] {
+                    for (name, value) in static_columns.iter() {
+                        let stored: String = conn.query_row(
+                            &format!("SELECT \"{name}\" FROM {table}"),
+                            [],
+                            |row| row.get(0),
+                        )?;
+                        assert_eq!(stored, value);
+                    }
+                }
+
+                // updated in place, updated_at never goes back
+                let mut older = service(&enabled, 5);
+                older.name = "web-renamed".into();
+                execute(&conn, older.upsert(&enabled, &static_columns))?;
+                let (count, name, updated_at): (i64, String, i64) = conn.query_row(
+                    "SELECT COUNT(*), name, updated_at FROM consul_services",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?;
+                assert_eq!((count, name.as_str(), updated_at), (1, "web-renamed", 10));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncates_check_output() {
+        let enabled = ColumnSet::default();
+        let row = check(&enabled, 1).with_max_output_bytes(Some(4));
+        assert_eq!(row.output, format!("conn{TRUNCATED_OUTPUT_MARKER}"));
+        let row = check(&enabled, 1).with_max_output_bytes(None);
+        assert_eq!(row.output, "connection refused");
+    }
+}
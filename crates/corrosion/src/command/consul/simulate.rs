@@ -0,0 +1,355 @@
+//! Replays a recording made by `consul sync --record` through the sync's own
+//! pipeline, against any corrosion agent and at any speed, so consul
+//! workloads can be reproduced.
+
+use std::{io::Write, net::SocketAddr, path::Path, str::FromStr, time::Duration};
+
+use camino::Utf8Path;
+use corro_client::CorrosionClient;
+use corro_types::config::ConsulConfig;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    recording::{read_recording, RecordedTick, Replay},
+    sync::{sync_context, update_consul, ApplyStats, SyncContext},
+};
+
+/// How much faster than recorded ticks are replayed, e.g. `10x`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Speed(f64);
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let factor: f64 = s
+            .strip_suffix('x')
+            .unwrap_or(s)
+            .parse()
+            .map_err(|_| format!("invalid speed {s:?}, expected something like 10x"))?;
+        if !factor.is_finite() || factor <= 0.0 {
+            return Err(format!("invalid speed {s:?}, it must be over 0"));
+        }
+        Ok(Self(factor))
+    }
+}
+
+impl Speed {
+    /// When a tick recorded `at_ms` into the recording is replayed
+    fn scale(&self, at_ms: u64) -> Duration {
+        Duration::from_millis(at_ms).div_f64(self.0)
+    }
+}
+
+/// What replaying a tick applied, or why it failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TickReport {
+    pub at_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<ApplyStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checks: Option<ApplyStats>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SimulationReport {
+    pub ticks: Vec<TickReport>,
+    /// Totals over every tick, w/ their durations added up
+    pub services: ApplyStats,
+    pub checks: ApplyStats,
+    pub failed_ticks: usize,
+}
+
+// unlike services and checks, ticks are applied one after the other
+fn accumulate(total: &mut ApplyStats, stats: &ApplyStats) {
+    let duration = total.duration + stats.duration;
+    total.merge(stats);
+    total.duration = duration;
+}
+
+/// Applies `ticks` w/ `ctx` as the sync would have, each one once its
+/// recorded time comes at `speed`. Ticks which fail are reported and the
+/// replay goes on, as the sync would retry on the next one.
+pub async fn replay(
+    ctx: &mut SyncContext,
+    ticks: Vec<RecordedTick>,
+    speed: Speed,
+) -> SimulationReport {
+    let consul = Replay::default();
+    let start = tokio::time::Instant::now();
+    let mut report = SimulationReport::default();
+
+    for (index, tick) in ticks.into_iter().enumerate() {
+        let at_ms = tick.at_ms;
+        tokio::time::sleep_until(start + speed.scale(at_ms)).await;
+
+        consul.play(tick);
+        let tick = match update_consul(&consul, ctx, false).await {
+            Ok((services, checks)) => {
+                info!(
+                    "tick {index}: {} services upserted, {} deleted, {} checks upserted, {} deleted, {} statements",
+                    services.upserted,
+                    services.deleted,
+                    checks.upserted,
+                    checks.deleted,
+                    services.statements + checks.statements
+                );
+                accumulate(&mut report.services, &services);
+                accumulate(&mut report.checks, &checks);
+                TickReport {
+                    at_ms,
+                    services: Some(services),
+                    checks: Some(checks),
+                    error: None,
+                }
+            }
+            Err(e) => {
+                warn!("tick {index} failed: {e}");
+                report.failed_ticks += 1;
+                TickReport {
+                    at_ms,
+                    services: None,
+                    checks: None,
+                    error: Some(e.to_string()),
+                }
+            }
+        };
+        report.ticks.push(tick);
+    }
+
+    report
+}
+
+/// Replays the recording in `dir` against the agent at `api_addr`, as the
+/// node it was recorded for unless `node` is set
+pub async fn run<P: AsRef<Path>>(
+    config: &ConsulConfig,
+    api_addr: SocketAddr,
+    db_path: P,
+    dir: &Utf8Path,
+    speed: Speed,
+    node: Option<String>,
+) -> eyre::Result<SimulationReport> {
+    let (meta, ticks) = read_recording(dir)?;
+    info!("Replaying {} consul ticks from {dir}", ticks.len());
+
+    let corrosion = CorrosionClient::new(api_addr, db_path);
+    let node = node.unwrap_or(meta.node);
+    let datacenter = config.datacenter.clone().or(meta.datacenter);
+    let mut ctx = sync_context(config, corrosion, node, |has_column| async move {
+        match (has_column, datacenter) {
+            (false, _) => Ok(None),
+            (true, Some(datacenter)) => Ok(Some(datacenter)),
+            (true, None) => eyre::bail!(
+                "consul_services and consul_checks have a datacenter column but the recording has no datacenter, set consul.datacenter"
+            ),
+        }
+    })
+    .await?;
+
+    Ok(replay(&mut ctx, ticks, speed).await)
+}
+
+/// Prints what each tick applied, then the totals
+pub fn render<W: Write>(report: &SimulationReport, json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, report)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{:>6}  {:>8}  {:>9}  {:>9}  {:>9}  {:>9}  {:>6}  {:>10}  {:>8}",
+        "tick",
+        "at (s)",
+        "services+",
+        "services-",
+        "checks+",
+        "checks-",
+        "errors",
+        "statements",
+        "ms"
+    )?;
+    for (index, tick) in report.ticks.iter().enumerate() {
+        let at = tick.at_ms as f64 / 1000.0;
+        match (&tick.services, &tick.checks) {
+            (Some(services), Some(checks)) => writeln!(
+                out,
+                "{:>6}  {:>8.1}  {:>9}  {:>9}  {:>9}  {:>9}  {:>6}  {:>10}  {:>8}",
+                index,
+                at,
+                services.upserted,
+                services.deleted,
+                checks.upserted,
+                checks.deleted,
+                services.errors + checks.errors,
+                services.statements + checks.statements,
+                services.duration.max(checks.duration).as_millis()
+            )?,
+            _ => writeln!(
+                out,
+                "{:>6}  {:>8.1}  failed: {}",
+                index,
+                at,
+                tick.error.as_deref().unwrap_or("unknown error")
+            )?,
+        }
+    }
+
+    writeln!(
+        out,
+        "{} ticks, {} failed: {} services upserted, {} deleted, {} checks upserted, {} deleted, {} errors, {} statements",
+        report.ticks.len(),
+        report.failed_ticks,
+        report.services.upserted,
+        report.services.deleted,
+        report.checks.upserted,
+        report.checks.deleted,
+        report.services.errors + report.checks.errors,
+        report.services.statements + report.checks.statements
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    // a node w/ two services, one of which goes critical, then consul fails
+    // and the other service goes away
+    const RECORDING: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/command/consul/testdata/recording"
+    );
+    // the consul tables, declared WITHOUT ROWID: nothing in the sync relies
+    // on rowids
+    const SCHEMA: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/command/consul/testdata/schema"
+    );
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!("10x".parse(), Ok(Speed(10.0)));
+        assert_eq!("0.5x".parse(), Ok(Speed(0.5)));
+        assert_eq!("2".parse(), Ok(Speed(2.0)));
+        for invalid in ["0x", "-1x", "x", "fast", "infx"] {
+            assert!(invalid.parse::<Speed>().is_err(), "{invalid}");
+        }
+        assert_eq!(Speed(10.0).scale(1500), Duration::from_millis(150));
+    }
+
+    // upserted, deleted, errors and statements of services and checks
+    fn counts(tick: &TickReport) -> Option<[usize; 8]> {
+        let (services, checks) = (tick.services.as_ref()?, tick.checks.as_ref()?);
+        Some([
+            services.upserted,
+            services.deleted,
+            services.errors,
+            services.statements,
+            checks.upserted,
+            checks.deleted,
+            checks.errors,
+            checks.statements,
+        ])
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn replays_a_recording() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let config: ConsulConfig = serde_json::from_str(r#"{"client": {}}"#)?;
+        let mut reports = vec![];
+        // against fresh agents, replays apply exactly the same
+        for _ in 0..2 {
+            let ta = launch_test_agent(
+                |conf| conf.add_schema_path(SCHEMA.into()).build(),
+                tripwire.clone(),
+            )
+            .await?;
+            let report = run(
+                &config,
+                ta.agent.api_addr(),
+                ta.agent.db_path(),
+                Utf8Path::new(RECORDING),
+                "1000x".parse().unwrap(),
+                None,
+            )
+            .await?;
+            reports.push(report);
+        }
+
+        let report = &reports[0];
+        assert_eq!(
+            report.ticks.iter().map(counts).collect::<Vec<_>>(),
+            [
+                Some([2, 0, 0, 4, 2, 0, 0, 4]),
+                Some([0, 0, 0, 0, 1, 0, 0, 2]),
+                None,
+                Some([0, 1, 0, 2, 0, 1, 0, 2]),
+            ]
+        );
+        assert!(report.ticks[2].error.is_some());
+        assert_eq!(report.failed_ticks, 1);
+        assert_eq!(
+            (
+                report.services.upserted,
+                report.services.deleted,
+                report.services.statements
+            ),
+            (2, 1, 6)
+        );
+        assert_eq!(
+            (
+                report.checks.upserted,
+                report.checks.deleted,
+                report.checks.statements
+            ),
+            (3, 1, 8)
+        );
+        assert_eq!(
+            reports[1].ticks.iter().map(counts).collect::<Vec<_>>(),
+            report.ticks.iter().map(counts).collect::<Vec<_>>()
+        );
+
+        let mut out = vec![];
+        render(report, false, &mut out)?;
+        let rendered = String::from_utf8(out)?;
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 6, "{rendered}");
+        assert_eq!(
+            lines[1].split_whitespace().take(8).collect::<Vec<_>>(),
+            ["0", "0.0", "2", "0", "2", "0", "0", "8"]
+        );
+        assert!(lines[3].contains("failed: "), "{rendered}");
+        assert_eq!(
+            lines[5],
+            "4 ticks, 1 failed: 2 services upserted, 1 deleted, 3 checks upserted, 1 deleted, 0 errors, 14 statements"
+        );
+
+        let mut out = vec![];
+        render(report, true, &mut out)?;
+        let parsed: SimulationReport = serde_json::from_slice(&out)?;
+        assert_eq!(
+            parsed.ticks.iter().map(counts).collect::<Vec<_>>(),
+            report.ticks.iter().map(counts).collect::<Vec<_>>()
+        );
+        assert_eq!(parsed.failed_ticks, 1);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,140 @@
+//! Where the consul sync reads services and checks from: the local consul
+//! agent in production, scripted replies in tests.
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use consul_client::{AgentCheck, AgentService, Client, ConsulResult};
+
+#[async_trait]
+pub trait ConsulSource: Send + Sync {
+    async fn agent_services(&self) -> ConsulResult<HashMap<String, AgentService>>;
+    async fn agent_checks(&self) -> ConsulResult<HashMap<String, AgentCheck>>;
+}
+
+#[async_trait]
+impl ConsulSource for Client {
+    async fn agent_services(&self) -> ConsulResult<HashMap<String, AgentService>> {
+        Client::agent_services(self).await
+    }
+
+    async fn agent_checks(&self) -> ConsulResult<HashMap<String, AgentCheck>> {
+        Client::agent_checks(self).await
+    }
+}
+
+#[async_trait]
+impl<T: ConsulSource + ?Sized> ConsulSource for Arc<T> {
+    async fn agent_services(&self) -> ConsulResult<HashMap<String, AgentService>> {
+        (**self).agent_services().await
+    }
+
+    async fn agent_checks(&self) -> ConsulResult<HashMap<String, AgentCheck>> {
+        (**self).agent_checks().await
+    }
+}
+
+#[cfg(test)]
+pub mod faulty {
+    use std::{
+        collections::{HashMap, VecDeque},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
+
+    use async_trait::async_trait;
+    use consul_client::{AgentCheck, AgentService, ConsulResult, Error};
+    use hyper::StatusCode;
+
+    use super::ConsulSource;
+
+    /// One scripted reply: `result` after waiting for `delay` (in tokio time,
+    /// so paused test clocks skip right past it)
+    #[derive(Debug, Clone)]
+    pub struct Reply<T> {
+        pub delay: Duration,
+        pub result: Result<T, StatusCode>,
+    }
+
+    impl<T> Reply<T> {
+        pub fn ok(value: T) -> Self {
+            Self {
+                delay: Duration::ZERO,
+                result: Ok(value),
+            }
+        }
+
+        /// Consul responding w/ a `status` error
+        pub fn err(status: StatusCode) -> Self {
+            Self {
+                delay: Duration::ZERO,
+                result: Err(status),
+            }
+        }
+
+        pub fn delayed(mut self, delay: Duration) -> Self {
+            self.delay = delay;
+            self
+        }
+    }
+
+    /// A consul agent replying from a script, one reply per call. Once a
+    /// script runs out, calls get empty responses.
+    #[derive(Debug, Default)]
+    pub struct FaultyConsul {
+        services: Mutex<VecDeque<Reply<HashMap<String, AgentService>>>>,
+        checks: Mutex<VecDeque<Reply<HashMap<String, AgentCheck>>>>,
+        services_calls: AtomicUsize,
+        checks_calls: AtomicUsize,
+    }
+
+    impl FaultyConsul {
+        pub fn push_services(&self, reply: Reply<HashMap<String, AgentService>>) -> &Self {
+            self.services.lock().unwrap().push_back(reply);
+            self
+        }
+
+        pub fn push_checks(&self, reply: Reply<HashMap<String, AgentCheck>>) -> &Self {
+            self.checks.lock().unwrap().push_back(reply);
+            self
+        }
+
+        /// Calls to `agent_services` and `agent_checks` so far
+        pub fn calls(&self) -> (usize, usize) {
+            (
+                self.services_calls.load(Ordering::SeqCst),
+                self.checks_calls.load(Ordering::SeqCst),
+            )
+        }
+    }
+
+    async fn reply<T: Default>(
+        script: &Mutex<VecDeque<Reply<T>>>,
+        calls: &AtomicUsize,
+    ) -> ConsulResult<T> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        let next = script.lock().unwrap().pop_front();
+        let Some(reply) = next else {
+            return Ok(T::default());
+        };
+
+        if !reply.delay.is_zero() {
+            tokio::time::sleep(reply.delay).await;
+        }
+        reply.result.map_err(Error::BadStatusCode)
+    }
+
+    #[async_trait]
+    impl ConsulSource for FaultyConsul {
+        async fn agent_services(&self) -> ConsulResult<HashMap<String, AgentService>> {
+            reply(&self.services, &self.services_calls).await
+        }
+
+        async fn agent_checks(&self) -> ConsulResult<HashMap<String, AgentCheck>> {
+            reply(&self.checks, &self.checks_calls).await
+        }
+    }
+}
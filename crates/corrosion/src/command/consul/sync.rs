@@ -1,153 +1,1001 @@
-use consul_client::{AgentCheck, AgentService, Client};
-use corro_api_types::ColumnType;
-use corro_client::CorrosionClient;
-use corro_types::{api::Statement, config::ConsulConfig};
-use metrics::{histogram, increment_counter};
+use camino::{Utf8Path, Utf8PathBuf};
+use consul_client::{AgentCheck, AgentService, Client, ConsulCheckStatus};
+use corro_api_types::{row::QueryMapInto, ColumnName, ColumnType, QueryEvent, SqliteParam};
+use corro_client::{read::ReadPreference, CorrosionClient};
+use corro_types::{api::{ExecErrorCode, ExecResult, Statement}, config::{Config, ConsulConfig, StaticColumns}, sampling::{ErrorKey, ErrorSampler, Sample}};
+use futures::{Future, Stream, StreamExt};
+use metrics::{gauge, histogram, increment_counter};
+use rusqlite::{Connection, OptionalExtension, TransactionBehavior};
 use serde::{Deserialize, Serialize};
 use spawn::{spawn_counted, wait_for_all_pending_handles};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
     hash::{Hash, Hasher},
     net::SocketAddr,
     path::Path,
+    sync::Arc,
     time::{Duration, Instant, SystemTime},
 };
-use tokio::time::{interval, timeout};
-use tracing::{debug, error, info, trace};
-
-const CONSUL_PULL_INTERVAL: Duration = Duration::from_secs(1);
+use tokio::{signal::unix::{signal, SignalKind}, sync::watch, time::{interval, timeout}};
+use tracing::{debug, error, info, trace, warn};
+use tripwire::Tripwire;
+
+use super::{bookkeeping::{self, bookkeeping_id, load_hashes}, churn::ChurnDetector, ids::IdNormalizer, recording::{Recording, RecordingMeta}, rewrite::ServiceRewriter, rows::{CheckRow, Row, ServiceRow}, source::ConsulSource};
+
+const MAX_APPLY_ATTEMPTS: u32 = 5;
+/// Appended to check outputs truncated to `max-output-bytes`
+pub(super) const TRUNCATED_OUTPUT_MARKER: &str = "... [truncated]";
+// ids listed per kind of change in a tick's debug summary
+const MAX_LOGGED_IDS: usize = 10;
+// services or checks hashed on blocking threads from this many on
+const PARALLEL_HASH_THRESHOLD: usize = 1024;
+/// Identifies the sync's requests in the agent's logs
+const SYNC_USER_AGENT: &str = concat!("corrosion-consul-sync/", env!("CARGO_PKG_VERSION"));
+const CORROSION_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// generous, the sync's transactions can wait on a busy agent's write lock
+const CORROSION_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const HASHES_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+const DEGRADED_WARN_INTERVAL: Duration = Duration::from_secs(60);
+// how long consul gets to list services or checks before a tick gives up
+const CONSUL_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// how far back the clock can step before it's warned about, in millis
+const CLOCK_REGRESSION_WARN_MS: i64 = 1000;
+// attempts at creating the bookkeeping tables while the database is busy
+const SETUP_DDL_ATTEMPTS: u32 = 8;
+// how often the consul tables are checked again while they aren't ready
+const SCHEMA_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 pub async fn run<P: AsRef<Path>>(
-    config: &ConsulConfig,
+    config: &Config,
+    config_path: &Utf8Path,
     api_addr: SocketAddr,
     db_path: P,
+    record: Option<Utf8PathBuf>,
 ) -> eyre::Result<()> {
-    let (mut tripwire, tripwire_worker) = tripwire::Tripwire::new_signals();
+    let (tripwire, tripwire_worker) = Tripwire::new_signals();
 
-    let node: &'static str = Box::leak(
-        hostname::get()?
-            .into_string()
-            .expect("could not convert hostname to string")
-            .into_boxed_str(),
-    );
+    run_with_tripwire(config, config_path, api_addr, db_path, record, tripwire).await?;
+
+    tripwire_worker.await;
+
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+/// Syncs until `tripwire` trips. Unlike `run`, this doesn't listen for
+/// shutdown signals nor wait on unrelated tasks, so it can run several times
+/// from the same process. Consul's responses are recorded to `record` when
+/// set, see `consul simulate`.
+pub async fn run_with_tripwire<P: AsRef<Path>>(
+    config: &Config,
+    config_path: &Utf8Path,
+    api_addr: SocketAddr,
+    db_path: P,
+    record: Option<Utf8PathBuf>,
+    tripwire: Tripwire,
+) -> eyre::Result<()> {
+    let Some(consul_config) = config.consul.clone() else {
+        eyre::bail!("missing `consul` block in corrosion config");
+    };
+
+    let corrosion = CorrosionClient::builder()
+        .endpoint(api_addr)
+        .db_path(db_path.as_ref())
+        .user_agent(SYNC_USER_AGENT)
+        .connect_timeout(CORROSION_CONNECT_TIMEOUT)
+        .request_timeout(CORROSION_REQUEST_TIMEOUT)
+        .build()?;
+    let consul = consul_client::Client::new(consul_config.client.clone())?;
+
+    let node = node_name(&consul_config, &consul).await?;
+    info!("Syncing consul services and checks as node {node}");
+
+    let ctx = sync_context(&consul_config, corrosion, node, |has_column| datacenter(&consul_config, &consul, has_column)).await?;
+
+    let consul: Arc<dyn ConsulSource> = match record {
+        Some(dir) => {
+            info!("Recording consul's responses to {dir}");
+            let meta = RecordingMeta { node: ctx.node.to_string(), datacenter: ctx.datacenter.as_deref().map(str::to_owned) };
+            Arc::new(Recording::new(consul, dir, &meta)?)
+        }
+        None => Arc::new(consul),
+    };
+
+    let (config_tx, config_rx) = watch::channel(consul_config.clone());
+    let hangups = Box::pin(futures::stream::unfold(signal(SignalKind::hangup())?, |mut hangup| async move {
+        hangup.recv().await.map(|_| ((), hangup))
+    }));
+    let watcher = spawn_counted(watch_config(config_path.to_owned(), config.clone(), hangups, config_tx, tripwire.clone()));
+
+    let syncer = spawn_counted(sync_loop(consul, ctx, consul_config, config_rx, tripwire));
+
+    let (watched, synced) = tokio::join!(watcher, syncer);
+    watched?;
+    synced?;
 
-    let corrosion = CorrosionClient::new(api_addr, db_path);
-    let consul = consul_client::Client::new(config.client.clone())?;
+    Ok(())
+}
+
+/// Sets corrosion up to sync `node`'s services and checks and loads what was
+/// synced for it before. `datacenter` resolves the datacenter rows are synced
+/// for, given whether the consul tables have a column for it.
+pub(super) async fn sync_context<F, Fut>(consul_config: &ConsulConfig, corrosion: CorrosionClient, node: String, datacenter: F) -> eyre::Result<SyncContext>
+where
+    F: FnOnce(bool) -> Fut,
+    Fut: Future<Output = eyre::Result<Option<String>>>,
+{
+    let rewriter = ServiceRewriter::new(&consul_config.rewrites)?;
 
     info!("Setting up corrosion for consul sync");
-    setup(
-        &corrosion
+    let tables = setup(
+        &corrosion,
+        &consul_config.static_columns,
+        Duration::from_secs(consul_config.schema_grace_secs),
     )
     .await?;
+    record_node_name(&corrosion, &node).await?;
+
+    let datacenter = datacenter(tables.datacenter).await?;
+    if let Some(datacenter) = datacenter.as_deref() {
+        info!("Syncing consul services and checks for datacenter {datacenter}");
+        let backfilled = backfill_datacenter(&corrosion, &node, datacenter).await?;
+        if backfilled > 0 {
+            info!("Backfilled datacenter {datacenter} for {backfilled} services and checks rows");
+        }
+    }
 
-    let mut consul_services: HashMap<String, u64> = HashMap::new();
-    let mut consul_checks: HashMap<String, u64> = HashMap::new();
+    let ids = IdNormalizer::new(&consul_config.normalize_ids);
+    if ids.is_enabled() {
+        let merged = merge_unnormalized_ids(&corrosion, &node, datacenter.as_deref(), &ids, tables.service_tags).await?;
+        if merged > 0 {
+            info!("Merged {merged} services and checks rows stored under ids which aren't normalized");
+        }
+    }
 
-    {
-        let conn = corrosion.pool().get().await?;
+    let mut ctx = SyncContext::new(node, corrosion);
+    ctx.rewriter = rewriter;
+    ctx.ids = ids;
+    ctx.service_names = consul_config.services.clone();
+    ctx.service_status = tables.service_status.then_some(ServiceStatusConfig { include_node_checks: consul_config.node_checks_affect_services });
+    ctx.churn = churn_detector(consul_config);
+    ctx.static_columns = consul_config.static_columns.clone();
+    ctx.datacenter = datacenter.map(Arc::from);
+    ctx.max_output_bytes = consul_config.max_output_bytes;
+    ctx.synthesize_checks = consul_config.synthesize_missing_checks;
+    ctx.synthetic_column = tables.synthetic_checks;
+
+    info!("Populating initial service hashes");
+    ctx.service_hashes = load_hashes(&ctx.corrosion, &bookkeeping::SERVICES, ctx.datacenter.as_deref(), consul_config.load_batch_size).await?;
+
+    info!("Populating initial checks hashes");
+    ctx.check_hashes = load_hashes(&ctx.corrosion, &bookkeeping::CHECKS, ctx.datacenter.as_deref(), consul_config.load_batch_size).await?;
+
+    ctx.last_updated_at = load_last_updated_at(&ctx.corrosion, &ctx.node).await?;
+
+    if tables.service_tags {
+        info!("Populating initial service tags");
+        ctx.service_tags = Some(load_service_tags(&ctx.corrosion, &ctx.node).await?);
+    }
 
-        info!("Populating initial service hashes");
-        let mut prepped = conn.prepare("SELECT id, hash FROM __corro_consul_services")?;
-        let mut rows = prepped.query([])?;
+    Ok(ctx)
+}
 
-        loop {
-            let row = match rows.next()? {
-                Some(row) => row,
-                None => {
-                    break;
-                }
-            };
+/// Everything a tick needs besides consul itself: where and as which node to
+/// write, what to sync and the state kept from one tick to the next
+pub struct SyncContext {
+    pub node: Arc<str>,
+    pub corrosion: CorrosionClient,
+    pub rewriter: ServiceRewriter,
+    /// Applied to ids before anything else, see `consul.normalize-ids`
+    pub ids: IdNormalizer,
+    /// Names of the services to sync, all of them when empty
+    pub service_names: Vec<String>,
+    /// Set when consul_services has a `status` column
+    pub service_status: Option<ServiceStatusConfig>,
+    /// Set when the consul_service_tags table exists: the tags stored for
+    /// each service, so only the ones which changed are written
+    pub service_tags: Option<HashMap<String, BTreeSet<String>>>,
+    pub service_hashes: HashMap<String, u64>,
+    pub check_hashes: HashMap<String, u64>,
+    /// Ids upserted on the next tick even if their hash didn't change, see
+    /// `reconcile_hashes`
+    pub forced_services: HashSet<String>,
+    pub forced_checks: HashSet<String>,
+    pub failures: ApplyFailures,
+    pub churn: Option<ChurnDetector>,
+    /// Highest `updated_at` written, so it never goes backwards when the
+    /// clock does
+    pub last_updated_at: i64,
+    /// Written to every row alongside consul's own fields
+    pub static_columns: StaticColumns,
+    /// Set when the consul tables have a `datacenter` column: written to
+    /// every row and part of the bookkeeping ids
+    pub datacenter: Option<Arc<str>>,
+    /// Check outputs are stored truncated past this many bytes
+    pub max_output_bytes: Option<usize>,
+    /// Services w/o checks get a synthetic one, see `synthesize_checks`
+    pub synthesize_checks: bool,
+    /// Set when consul_checks has a `synthetic` column, flagging synthetic
+    /// checks
+    pub synthetic_column: bool,
+}
 
-            consul_services.insert(row.get(0)?, u64::from_be_bytes(row.get(1)?));
+impl SyncContext {
+    /// Syncs everything as-is for `node`, w/o any hashes loaded
+    pub fn new(node: impl Into<Arc<str>>, corrosion: CorrosionClient) -> Self {
+        Self {
+            node: node.into(),
+            corrosion,
+            rewriter: ServiceRewriter::default(),
+            ids: IdNormalizer::default(),
+            service_names: vec![],
+            service_status: None,
+            service_tags: None,
+            service_hashes: HashMap::new(),
+            check_hashes: HashMap::new(),
+            forced_services: HashSet::new(),
+            forced_checks: HashSet::new(),
+            failures: ApplyFailures::default(),
+            churn: None,
+            last_updated_at: 0,
+            static_columns: StaticColumns::default(),
+            datacenter: None,
+            max_output_bytes: None,
+            synthesize_checks: false,
+            synthetic_column: false,
         }
+    }
+}
 
-        info!("Populating initial checks hashes");
-        let mut prepped = conn.prepare("SELECT id, hash FROM __corro_consul_checks")?;
-        let mut rows = prepped.query([])?;
+/// Notices the agent restarted while the sync's updates were failing, by
+/// comparing when it started before and after. It may have been restored
+/// from an older backup in the meantime, see `reconcile_hashes`.
+#[derive(Debug, Default)]
+struct RestartDetector {
+    started_at: Option<u64>,
+    failing: bool,
+}
 
-        loop {
-            let row = match rows.next()? {
-                Some(row) => row,
-                None => {
-                    break;
-                }
-            };
+impl RestartDetector {
+    /// Records when the agent started, 0 if it didn't say
+    fn seen(&mut self, started_at: u64) {
+        if started_at != 0 {
+            self.started_at = Some(started_at);
+        }
+    }
+
+    fn failed(&mut self) {
+        self.failing = true;
+    }
+
+    fn is_failing(&self) -> bool {
+        self.failing
+    }
+
+    /// Whether an agent which started at `started_at` isn't the one seen
+    /// before the failures
+    fn restarted(&self, started_at: u64) -> bool {
+        started_at != 0 && self.started_at.map_or(false, |seen| seen != started_at)
+    }
+
+    /// Updates went through again, from an agent which started at `started_at`
+    fn recovered(&mut self, started_at: u64) {
+        self.seen(started_at);
+        self.failing = false;
+    }
+}
+
+/// Logs a failed update, sampling identical errors. The first and last of a
+/// window are logged in full, the ones in between only counted.
+fn log_update_error(sampler: &ErrorSampler, e: &eyre::Report, retryable: bool) {
+    let message = e.to_string();
+    let code = if retryable { "retryable" } else { "fatal" };
+    let (sample, summary) = sampler.record(ErrorKey::new(code, &message, None), &message, Instant::now());
+    if let Some(summary) = summary {
+        warn!("could not update consul, {summary}");
+    }
+    if sample == Sample::Suppressed {
+        increment_counter!("corro_consul.update.suppressed_errors");
+        return;
+    }
+
+    if retryable {
+        warn!("could not update consul, will retry next pull: {e}");
+    } else {
+        error!("could not update consul: {e}");
+    }
+}
+
+/// Checks whether the agent restarted during a streak of failed updates
+/// which just ended and reconciles the hashes if it did. Returns whether ids
+/// were forced, to be upserted right away. Stays failing if it couldn't tell
+/// or reconcile, to try again after the next successful update.
+async fn catch_up_restart(ctx: &mut SyncContext, restarts: &mut RestartDetector, batch_size: usize) -> bool {
+    let started_at = match ctx.corrosion.health_details().await {
+        Ok(health) => health.started_at,
+        Err(e) => {
+            debug!("could not check whether corrosion restarted: {e}");
+            return false;
+        }
+    };
+
+    if !restarts.restarted(started_at) {
+        restarts.recovered(started_at);
+        return false;
+    }
 
-            consul_checks.insert(row.get::<_, String>(0)?, u64::from_be_bytes(row.get(1)?));
+    warn!("corrosion restarted while consul updates were failing, reconciling stored hashes");
+    increment_counter!("corro_consul.agent.restarts");
+    match reconcile_hashes(ctx, batch_size).await {
+        Ok(forced) => {
+            restarts.recovered(started_at);
+            if forced > 0 {
+                info!("forcing {forced} consul services and checks whose stored hashes didn't match");
+            }
+            forced > 0
+        }
+        Err(e) => {
+            error!("could not reconcile stored hashes, will retry after the next update: {e}");
+            false
         }
     }
+}
 
-    let mut pull_interval = interval(CONSUL_PULL_INTERVAL);
+/// Compares the hashes stored in the bookkeeping tables to the ones in
+/// memory. An agent restored from an older backup has older rows than the
+/// in-memory hashes claim, and nothing would rewrite them until they change
+/// in consul. Ids whose stored hash is missing or different are forced, so
+/// the next tick upserts them regardless of their hash, or deletes them if
+/// consul doesn't list them anymore. Returns how many ids were forced.
+pub async fn reconcile_hashes(ctx: &mut SyncContext, batch_size: usize) -> eyre::Result<usize> {
+    let stored = load_hashes(&ctx.corrosion, &bookkeeping::SERVICES, ctx.datacenter.as_deref(), batch_size).await?;
+    let services = force_mismatched(&mut ctx.service_hashes, stored, &mut ctx.forced_services);
 
-    spawn_counted(async move {
-        info!("Starting consul pull interval");
-        loop {
-            tokio::select! {
-                _ = pull_interval.tick() => {
-                    let res = update_consul(&consul, node, &corrosion, &mut consul_services, &mut consul_checks, false).await;
-                    debug!("got results: {res:?}");
-
-                    match res {
-                        Ok((svc_stats, check_stats)) => {
-                            if !svc_stats.is_zero() {
-                                info!("updated consul services: {svc_stats:?}");    
-                            }
-                            if !check_stats.is_zero() {
-                                info!("updated consul checks: {check_stats:?}");    
+    let stored = load_hashes(&ctx.corrosion, &bookkeeping::CHECKS, ctx.datacenter.as_deref(), batch_size).await?;
+    let checks = force_mismatched(&mut ctx.check_hashes, stored, &mut ctx.forced_checks);
+
+    Ok(services + checks)
+}
+
+/// Forces the ids whose `stored` hash is missing or different from the one
+/// in `hashes`. Ids only stored are tracked w/ their stored hash.
+fn force_mismatched(hashes: &mut HashMap<String, u64>, mut stored: HashMap<String, u64>, forced: &mut HashSet<String>) -> usize {
+    let mut count = 0;
+    for (id, hash) in hashes.iter() {
+        if stored.remove(id) != Some(*hash) && forced.insert(id.clone()) {
+            count += 1;
+        }
+    }
+
+    for (id, hash) in stored {
+        if forced.insert(id.clone()) {
+            count += 1;
+        }
+        hashes.insert(id, hash);
+    }
+
+    count
+}
+
+/// Pulls services and checks from `consul` every pull interval and applies
+/// their changes to corrosion, until `tripwire` trips. Errors are logged and
+/// retried on the next pull.
+async fn sync_loop<C: ConsulSource>(
+    consul: C,
+    mut ctx: SyncContext,
+    mut consul_config: ConsulConfig,
+    mut config_rx: watch::Receiver<ConsulConfig>,
+    mut tripwire: Tripwire,
+) {
+    let mut pull_interval = interval(Duration::from_millis(consul_config.pull_interval_ms));
+    let mut maintenance_interval = interval(HASHES_MAINTENANCE_INTERVAL);
+    let mut last_degraded_warn: Option<Instant> = None;
+    // a consul outage fails every pull, w/ the same error
+    let update_errors = ErrorSampler::default();
+
+    let mut restarts = RestartDetector::default();
+    match ctx.corrosion.health_details().await {
+        Ok(health) => restarts.seen(health.started_at),
+        Err(e) => debug!("could not get when corrosion started: {e}"),
+    }
+
+    info!("Starting consul pull interval");
+    loop {
+        tokio::select! {
+            _ = pull_interval.tick() => {
+                // back off writes until the agent's storage recovers
+                if ctx.corrosion.is_degraded() {
+                    match ctx.corrosion.health_details().await {
+                        Ok(health) if health.degraded => {
+                            increment_counter!("corro_consul.update.degraded_skips");
+                            if last_degraded_warn.map_or(true, |at| at.elapsed() >= DEGRADED_WARN_INTERVAL) {
+                                warn!("corrosion storage is degraded, holding off consul updates: {}", health.reasons.join(", "));
+                                last_degraded_warn = Some(Instant::now());
                             }
+                            continue;
+                        }
+                        Ok(_) => {
+                            info!("corrosion storage recovered, resuming consul updates");
+                            last_degraded_warn = None;
                         }
                         Err(e) => {
-                            error!("could not update consul: {e}");
+                            debug!("could not check corrosion health, updating anyway: {e}");
                         }
                     }
-                },
-                _ = &mut tripwire => {
-                    debug!("tripped consul loop");
-                    break;
                 }
+
+                let res = update_consul(&consul, &mut ctx, false).await;
+                debug!("got results: {res:?}");
+                if res.is_err() {
+                    restarts.failed();
+                }
+
+                match res {
+                    Ok((svc_stats, check_stats)) => {
+                        if !svc_stats.is_zero() {
+                            info!("updated consul services: {svc_stats:?}");    
+                        }
+                        if !check_stats.is_zero() {
+                            info!("updated consul checks: {check_stats:?}");    
+                        }
+
+                        if restarts.is_failing() && catch_up_restart(&mut ctx, &mut restarts, consul_config.load_batch_size).await {
+                            pull_interval.reset_immediately();
+                        }
+                    }
+                    Err(e) => match e.downcast_ref::<corro_client::Error>() {
+                        Some(client_err) if client_err.is_retryable() => {
+                            increment_counter!("corro_consul.update.retryable_errors");
+                            log_update_error(&update_errors, &e, true);
+                        }
+                        _ => {
+                            log_update_error(&update_errors, &e, false);
+                        }
+                    },
+                }
+            },
+            _ = maintenance_interval.tick() => {
+                maintain_hashes("services", &mut ctx.service_hashes, consul_config.max_tracked_ids);
+                maintain_hashes("checks", &mut ctx.check_hashes, consul_config.max_tracked_ids);
+                if let Some(churn) = ctx.churn.as_mut() {
+                    warn_churners(churn, &consul_config);
+                }
+                for summary in update_errors.flush(Instant::now()) {
+                    warn!("could not update consul, {summary}");
+                }
+            },
+            Ok(()) = config_rx.changed() => {
+                let new_config = config_rx.borrow_and_update().clone();
+
+                if new_config.rewrites != consul_config.rewrites {
+                    match ServiceRewriter::new(&new_config.rewrites) {
+                        Ok(new_rewriter) => ctx.rewriter = new_rewriter,
+                        Err(e) => {
+                            error!("could not apply reloaded rewrites, keeping the current ones: {e}");
+                            continue;
+                        }
+                    }
+                }
+
+                if new_config.pull_interval_ms != consul_config.pull_interval_ms {
+                    pull_interval = interval(Duration::from_millis(new_config.pull_interval_ms));
+                } else if new_config.services != consul_config.services || new_config.rewrites != consul_config.rewrites || new_config.synthesize_missing_checks != consul_config.synthesize_missing_checks {
+                    // reconcile right away: newly excluded services get deleted,
+                    // newly included or rewritten ones upserted
+                    pull_interval.reset_immediately();
+                }
+
+                if (new_config.log_churners, new_config.churn_threshold, new_config.churn_window_secs) != (consul_config.log_churners, consul_config.churn_threshold, consul_config.churn_window_secs) {
+                    ctx.churn = churn_detector(&new_config);
+                }
+
+                ctx.service_names = new_config.services.clone();
+                ctx.max_output_bytes = new_config.max_output_bytes;
+                ctx.synthesize_checks = new_config.synthesize_missing_checks;
+                if let Some(status) = ctx.service_status.as_mut() {
+                    status.include_node_checks = new_config.node_checks_affect_services;
+                }
+                consul_config = new_config;
+            },
+            _ = &mut tripwire => {
+                debug!("tripped consul loop");
+                break;
             }
         }
-    });
+    }
+}
 
-    tripwire_worker.await;
+/// Re-reads the config file each time `reloads` yields and sends the consul
+/// config to the sync loop when one of its reloadable fields changed.
+async fn watch_config<S: Stream<Item = ()> + Unpin>(
+    config_path: Utf8PathBuf,
+    mut current: Config,
+    mut reloads: S,
+    config_tx: watch::Sender<ConsulConfig>,
+    mut tripwire: Tripwire,
+) {
+    loop {
+        tokio::select! {
+            Some(()) = reloads.next() => {
+                info!("Reloading config from {config_path}");
+                match reload_config(&config_path, &mut current) {
+                    Ok(Some(consul_config)) => {
+                        if config_tx.send(consul_config).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("could not reload config: {e}"),
+                }
+            },
+            _ = &mut tripwire => break,
+            else => break,
+        }
+    }
+}
 
-    wait_for_all_pending_handles().await;
+/// Loads the config file, logging what changed compared to `current`.
+/// Returns the consul config if a field which can be applied w/o restarting
+/// changed, it then becomes `current`.
+fn reload_config(config_path: &Utf8Path, current: &mut Config) -> eyre::Result<Option<ConsulConfig>> {
+    let new = Config::load(config_path.as_str())?;
+
+    let (Some(old_consul), Some(new_consul)) = (current.consul.as_ref(), new.consul.as_ref()) else {
+        warn!("the `consul` block was added or removed, restart to apply");
+        return Ok(None);
+    };
+
+    if new.api.bind_addr != current.api.bind_addr {
+        warn!("api.addr changed, restart to apply");
+    }
+    if new.db.path != current.db.path {
+        warn!("db.path changed, restart to apply");
+    }
+    if new_consul.client != old_consul.client {
+        warn!("consul.client changed, restart to apply");
+    }
+    if new_consul.node_name != old_consul.node_name {
+        warn!("consul.node-name changed, restart to apply");
+    }
+    if new_consul.static_columns != old_consul.static_columns {
+        warn!("consul.static-columns changed, restart to apply");
+    }
+    if new_consul.datacenter != old_consul.datacenter {
+        warn!("consul.datacenter changed, restart to apply");
+    }
+    if new_consul.normalize_ids != old_consul.normalize_ids {
+        // stored ids are only merged at startup
+        warn!("consul.normalize-ids changed, restart to apply");
+    }
+
+    let mut changed = vec![];
+    if new_consul.rewrites != old_consul.rewrites {
+        // don't swap configs if the sync loop can't apply it
+        ServiceRewriter::new(&new_consul.rewrites)?;
+        changed.push("rewrites");
+    }
+    if new_consul.services != old_consul.services {
+        changed.push("services");
+    }
+    if new_consul.pull_interval_ms != old_consul.pull_interval_ms {
+        changed.push("pull-interval-ms");
+    }
+    if new_consul.max_tracked_ids != old_consul.max_tracked_ids {
+        changed.push("max-tracked-ids");
+    }
+    if new_consul.log_churners != old_consul.log_churners {
+        changed.push("log-churners");
+    }
+    if new_consul.churn_threshold != old_consul.churn_threshold {
+        changed.push("churn-threshold");
+    }
+    if new_consul.churn_window_secs != old_consul.churn_window_secs {
+        changed.push("churn-window-secs");
+    }
+    if new_consul.node_checks_affect_services != old_consul.node_checks_affect_services {
+        changed.push("node-checks-affect-services");
+    }
+    if new_consul.max_output_bytes != old_consul.max_output_bytes {
+        // stored outputs only follow once their checks change
+        changed.push("max-output-bytes");
+    }
+    if new_consul.synthesize_missing_checks != old_consul.synthesize_missing_checks {
+        changed.push("synthesize-missing-checks");
+    }
+
+    if changed.is_empty() {
+        info!("no reloadable consul settings changed");
+        return Ok(None);
+    }
+
+    info!("applying reloaded consul settings: {}", changed.join(", "));
+
+    // the running consul client, node and api/db settings are kept as-is
+    let mut consul_config = new_consul.clone();
+    consul_config.client = old_consul.client.clone();
+    consul_config.node_name = old_consul.node_name.clone();
+    consul_config.static_columns = old_consul.static_columns.clone();
+    consul_config.datacenter = old_consul.datacenter.clone();
+    current.consul = Some(consul_config.clone());
+
+    Ok(Some(consul_config))
+}
+
+/// Name of the node rows are synced for: the configured one, or else the
+/// consul agent's own node name, w/ the hostname as a last resort
+pub(super) async fn node_name(config: &ConsulConfig, consul: &Client) -> eyre::Result<String> {
+    if let Some(name) = config.node_name.as_ref() {
+        return Ok(name.clone());
+    }
+
+    match timeout(Duration::from_secs(5), consul.agent_self()).await {
+        Ok(Ok(agent)) if !agent.config.node_name.is_empty() => return Ok(agent.config.node_name),
+        Ok(Ok(_)) => warn!("consul agent has no node name, falling back to the hostname"),
+        Ok(Err(e)) => warn!("could not get the consul agent's node name, falling back to the hostname: {e}"),
+        Err(_) => warn!("timed out getting the consul agent's node name, falling back to the hostname"),
+    }
+
+    hostname::get()?
+        .into_string()
+        .map_err(|name| eyre::eyre!("hostname {name:?} isn't valid utf-8"))
+}
+
+/// Datacenter rows are synced for when the consul tables have a `datacenter`
+/// column: the configured one, or else the consul agent's own
+pub(super) async fn datacenter(config: &ConsulConfig, consul: &Client, has_column: bool) -> eyre::Result<Option<String>> {
+    if !has_column {
+        if config.datacenter.is_some() {
+            eyre::bail!("consul.datacenter is set but consul_services and consul_checks have no datacenter column");
+        }
+        return Ok(None);
+    }
+
+    if let Some(datacenter) = config.datacenter.as_ref() {
+        // consul doesn't allow it either, bookkeeping ids rely on it
+        if datacenter.is_empty() || datacenter.contains('/') {
+            eyre::bail!("invalid consul.datacenter {datacenter:?}");
+        }
+        return Ok(Some(datacenter.clone()));
+    }
+
+    match timeout(Duration::from_secs(5), consul.agent_self()).await {
+        Ok(Ok(agent)) if !agent.config.datacenter.is_empty() => Ok(Some(agent.config.datacenter)),
+        Ok(Ok(_)) => eyre::bail!("consul agent has no datacenter, set consul.datacenter"),
+        Ok(Err(e)) => eyre::bail!("could not get the consul agent's datacenter, set consul.datacenter: {e}"),
+        Err(_) => eyre::bail!("timed out getting the consul agent's datacenter, set consul.datacenter"),
+    }
+}
+
+/// Sets `datacenter` on `node`'s rows synced before the consul tables had a
+/// datacenter column and prefixes the bookkeeping ids w/ it. Only runs once
+/// per database, the backfilled datacenter is recorded. Returns the number
+/// of rows backfilled.
+async fn backfill_datacenter(corrosion: &CorrosionClient, node: &str, datacenter: &str) -> eyre::Result<usize> {
+    let previous: Option<String> = corrosion.pool().get().await?.query_row("SELECT name FROM __corro_consul_datacenter WHERE id = 1", [], |row| row.get(0)).optional()?;
+    if let Some(previous) = previous {
+        if previous != datacenter {
+            debug!("rows were backfilled for datacenter {previous} already");
+        }
+        return Ok(0);
+    }
+
+    // rows are replicated, unlike the bookkeeping tables: written through corrosion
+    let res = corrosion.execute_strict(&[
+        Statement::WithParams("UPDATE consul_services SET datacenter = ? WHERE node = ? AND COALESCE(datacenter, '') = '';".into(), vec![datacenter.into(), node.into()]),
+        Statement::WithParams("UPDATE consul_checks SET datacenter = ? WHERE node = ? AND COALESCE(datacenter, '') = '';".into(), vec![datacenter.into(), node.into()]),
+    ]).await?;
+    let backfilled = res.results.iter().map(|res| match res {
+        ExecResult::Execute { rows_affected, .. } => *rows_affected,
+        ExecResult::Error { .. } => 0,
+    }).sum();
+
+    // every id recorded before the flag was set lacks a datacenter
+    let mut conn = corrosion.pool().get().await?;
+    let tx = conn.transaction()?;
+    for table in [bookkeeping::SERVICES, bookkeeping::CHECKS] {
+        tx.execute(&format!("UPDATE {} SET id = ? || '/' || id", table.as_str()), [datacenter])?;
+    }
+    tx.execute("INSERT INTO __corro_consul_datacenter (id, name) VALUES (1, ?)", [datacenter])?;
+    tx.commit()?;
+
+    Ok(backfilled)
+}
+
+/// Merges `node`'s rows stored under ids which don't normalize to themselves,
+/// e.g. synced before `consul.normalize-ids` was set, into the normalized id.
+/// When several rows normalize to the same id, the one updated last is kept.
+/// Their hashes are forgotten, so the next tick writes what consul reports
+/// now. Returns the number of rows merged.
+async fn merge_unnormalized_ids(corrosion: &CorrosionClient, node: &str, datacenter: Option<&str>, ids: &IdNormalizer, service_tags: bool) -> eyre::Result<usize> {
+    let (scope, scope_params): (&str, Vec<&str>) = match datacenter {
+        Some(datacenter) => ("node = ? AND datacenter = ?", vec![node, datacenter]),
+        None => ("node = ?", vec![node]),
+    };
+
+    let mut statements = vec![];
+    let mut forgotten = vec![];
+    let mut merged = 0;
+    for (table, hashes) in [("consul_services", bookkeeping::SERVICES), ("consul_checks", bookkeeping::CHECKS)] {
+        let rows: Vec<(String, i64)> = corrosion.pool().get().await?
+            .prepare(&format!("SELECT id, updated_at FROM {table} WHERE {scope}"))?
+            .query_map(rusqlite::params_from_iter(scope_params.iter()), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut groups: HashMap<String, Vec<(String, i64)>> = HashMap::new();
+        for (id, updated_at) in rows {
+            groups.entry(ids.normalize(&id).into_owned()).or_default().push((id, updated_at));
+        }
+
+        for (normalized, mut rows) in groups {
+            if rows.iter().all(|(id, _)| *id == normalized) {
+                continue;
+            }
+            // updated last first, the normalized id wins ties
+            rows.sort_by(|(a, a_at), (b, b_at)| b_at.cmp(a_at).then_with(|| (*b == normalized).cmp(&(*a == normalized))).then_with(|| a.cmp(b)));
+
+            let param = |id: &str| scope_params.iter().map(|value| SqliteParam::from(*value)).chain([SqliteParam::from(id)]).collect::<Vec<_>>();
+            let (kept, _) = &rows[0];
+            // deleted first, the normalized id may be one of them
+            for (id, _) in rows[1..].iter() {
+                statements.push(Statement::WithParams(format!("DELETE FROM {table} WHERE {scope} AND id = ?;"), param(id)));
+            }
+            if *kept != normalized {
+                let mut params = vec![SqliteParam::from(normalized.as_str())];
+                params.extend(param(kept));
+                statements.push(Statement::WithParams(format!("UPDATE {table} SET id = ? WHERE {scope} AND id = ?;"), params));
+            }
+
+            for (id, _) in rows.iter().filter(|(id, _)| *id != normalized) {
+                if service_tags && table == "consul_services" {
+                    append_delete_service_tags_statements(&mut statements, node, id);
+                }
+                forgotten.push((hashes.clone(), bookkeeping_id(datacenter, id)));
+                merged += 1;
+            }
+            forgotten.push((hashes.clone(), bookkeeping_id(datacenter, &normalized)));
+        }
+    }
+
+    if statements.is_empty() {
+        return Ok(0);
+    }
+
+    // rows are replicated, unlike the bookkeeping tables: written through corrosion
+    corrosion.execute_strict(&statements).await?;
+
+    let mut conn = corrosion.pool().get().await?;
+    let tx = conn.transaction()?;
+    for (hashes, id) in forgotten {
+        tx.execute(&format!("DELETE FROM {} WHERE id = ?", hashes.as_str()), [id])?;
+    }
+    tx.commit()?;
+
+    Ok(merged)
+}
+
+/// Stores the node name rows are synced for, warning when it changed since
+/// the last run: the rows stored under the previous name aren't updated
+/// anymore. Returns the previous name and its orphaned rows' count.
+async fn record_node_name(corrosion: &CorrosionClient, node: &str) -> eyre::Result<Option<(String, i64)>> {
+    let conn = corrosion.pool().get().await?;
+
+    let previous: Option<String> = conn.query_row("SELECT name FROM __corro_consul_node WHERE id = 1", [], |row| row.get(0)).optional()?;
+
+    let orphaned = match previous {
+        Some(previous) if previous != node => {
+            let orphaned: i64 = conn.query_row(
+                "SELECT (SELECT COUNT(*) FROM consul_services WHERE node = ?1) + (SELECT COUNT(*) FROM consul_checks WHERE node = ?1)",
+                [&previous],
+                |row| row.get(0),
+            )?;
+            warn!("consul node name changed from {previous} to {node}! {orphaned} services and checks rows stored for {previous} won't be updated or deleted anymore, set consul.node-name to {previous} to keep syncing them");
+            Some((previous, orphaned))
+        }
+        _ => None,
+    };
+
+    conn.execute("INSERT INTO __corro_consul_node (id, name) VALUES (1, ?) ON CONFLICT (id) DO UPDATE SET name = excluded.name", [node])?;
+
+    Ok(orphaned)
+}
+
+/// Loads the highest `updated_at` written for `node`, from the bookkeeping
+/// table or from the rows themselves if it wasn't recorded yet
+async fn load_last_updated_at(corrosion: &CorrosionClient, node: &str) -> eyre::Result<i64> {
+    let conn = corrosion.pool().get().await?;
+
+    let last: Option<i64> = conn.query_row(
+        "SELECT MAX(
+            COALESCE((SELECT updated_at FROM __corro_consul_clock WHERE id = 1), 0),
+            COALESCE((SELECT MAX(updated_at) FROM consul_services WHERE node = ?1), 0),
+            COALESCE((SELECT MAX(updated_at) FROM consul_checks WHERE node = ?1), 0)
+        )",
+        [node],
+        |row| row.get(0),
+    )?;
+
+    Ok(last.unwrap_or_default())
+}
 
+/// Records the highest `updated_at` written, it's only kept locally
+async fn record_last_updated_at(corrosion: &CorrosionClient, updated_at: i64) -> eyre::Result<()> {
+    let conn = corrosion.pool().get().await?;
+    conn.execute("INSERT INTO __corro_consul_clock (id, updated_at) VALUES (1, ?) ON CONFLICT (id) DO UPDATE SET updated_at = MAX(updated_at, excluded.updated_at)", [updated_at])?;
     Ok(())
 }
 
+/// `now` unless the clock went back to (or before) the `last` written
+/// `updated_at`, then right after it. Also returns how far back it went.
+fn monotonic_updated_at(now: i64, last: i64) -> (i64, Option<i64>) {
+    if now > last {
+        (now, None)
+    } else {
+        (last + 1, Some(last - now))
+    }
+}
+
+/// Loads the tags stored in consul_service_tags for `node`'s services,
+/// preferably from the local database file
+async fn load_service_tags(
+    corrosion: &CorrosionClient,
+    node: &str,
+) -> eyre::Result<HashMap<String, BTreeSet<String>>> {
+    let mut rows = corrosion
+        .read(
+            &Statement::WithParams("SELECT service_id, tag FROM consul_service_tags WHERE node = ?".into(), vec![node.into()]),
+            ReadPreference::LocalThenApi,
+        )
+        .await?;
+
+    let mut tags: HashMap<String, BTreeSet<String>> = HashMap::new();
+    let mut columns = vec![];
+
+    while let Some(evt) = rows.next().await {
+        match evt? {
+            QueryEvent::Columns { names: cols, .. } => columns = cols,
+            QueryEvent::Row(_, cells) => {
+                let (service_id, tag) = <(String, String)>::from_values(&cells, &columns)
+                    .map_err(|e| eyre::eyre!("unexpected row in consul_service_tags: {e}"))?;
+                tags.entry(service_id).or_default().insert(tag);
+            }
+            QueryEvent::Error(e) => eyre::bail!("could not load tags from consul_service_tags: {e}"),
+            _ => {}
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Optional parts of the consul schema, detected by `setup`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConsulTables {
+    /// consul_services has a `status` column
+    pub service_status: bool,
+    /// The consul_service_tags table exists
+    pub service_tags: bool,
+    /// Both consul tables have a `datacenter` column
+    pub datacenter: bool,
+    /// consul_checks has a `synthetic` column
+    pub synthetic_checks: bool,
+}
+
+/// Creates the bookkeeping tables and checks the consul tables' schema,
+/// detecting the optional ones and checking `static_columns` exist.
+///
+/// Safe to run concurrently w/ the agent applying its schema files, or w/
+/// another sync's setup: the tables are created if they don't exist, retrying
+/// while the database is busy, and the consul tables are checked again until
+/// they're ready or `schema_grace` elapsed.
 async fn setup(
     corrosion: &CorrosionClient,
-) -> eyre::Result<()> {
+    static_columns: &StaticColumns,
+    schema_grace: Duration,
+) -> eyre::Result<ConsulTables> {
+    // the same connection throughout, not interleaved w/ other checkouts
     let mut conn = corrosion.pool().get().await?;
-    {
-        let tx = conn.transaction()?;
-
-        info!("Creating internal tables");
-        tx.execute_batch(
-            "
-            CREATE TABLE IF NOT EXISTS __corro_consul_services (
-                id TEXT NOT NULL PRIMARY KEY,
-                hash BLOB NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS __corro_consul_checks (
-                id TEXT NOT NULL PRIMARY KEY,
-                hash BLOB NOT NULL
-            );
-            ",
-        )?;
 
-        tx.commit()?;
+    info!("Creating internal tables");
+    let mut backoff = backoff::Backoff::new(SETUP_DDL_ATTEMPTS - 1)
+        .timeout_range(Duration::from_millis(50), Duration::from_secs(2))
+        .iter();
+    loop {
+        match create_bookkeeping_tables(&mut conn) {
+            Ok(()) => break,
+            Err(e) if is_busy(&e) => match backoff.next() {
+                Some(delay) => {
+                    warn!("database is busy, creating internal tables again in {delay:?}: {e}");
+                    tokio::time::sleep(delay).await;
+                }
+                None => eyre::bail!("could not create internal tables after {SETUP_DDL_ATTEMPTS} attempts: {e}"),
+            },
+            Err(e) => return Err(e.into()),
+        }
     }
+
     info!("Ensuring schema...");
+    let deadline = Instant::now() + schema_grace;
+    let mut waiting_for = None;
+    loop {
+        let res = check_schema(&conn).and_then(|tables| {
+            check_static_columns(&conn, static_columns)?;
+            Ok(tables)
+        });
+        let e = match res {
+            Ok(tables) => {
+                if waiting_for.is_some() {
+                    info!("consul tables are ready");
+                }
+                return Ok(tables);
+            }
+            Err(e) => e.to_string(),
+        };
+
+        let now = Instant::now();
+        if now >= deadline {
+            eyre::bail!("consul tables still aren't ready after {schema_grace:?}: {e}");
+        }
+        // only logged when it changed, the schema may be applied gradually
+        if waiting_for.as_ref() != Some(&e) {
+            info!("waiting up to {:?} for the agent to apply its schema: {e}", deadline - now);
+            waiting_for = Some(e);
+        }
+        tokio::time::sleep(SCHEMA_POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+/// Creates the bookkeeping tables if they don't exist. Takes the write lock
+/// upfront so a busy database is waited on, instead of failing to upgrade a
+/// read lock.
+fn create_bookkeeping_tables(conn: &mut Connection) -> rusqlite::Result<()> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    for table in [bookkeeping::SERVICES, bookkeeping::CHECKS] {
+        tx.execute_batch(&bookkeeping::create_hash_table_sql(&table))?;
+    }
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS __corro_consul_node (
+            id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+            name TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS __corro_consul_clock (
+            id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+            updated_at INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS __corro_consul_datacenter (
+            id INTEGER NOT NULL PRIMARY KEY CHECK (id = 1),
+            name TEXT NOT NULL
+        );
+        ",
+    )?;
+    tx.commit()
+}
+
+fn is_busy(e: &rusqlite::Error) -> bool {
+    ExecErrorCode::from_sqlite(e) == Some(ExecErrorCode::Busy)
+}
+
+/// Checks both consul tables have a TEXT column for each static column
+pub(super) fn check_static_columns(conn: &Connection, static_columns: &StaticColumns) -> eyre::Result<()> {
+    for table in ["consul_services", "consul_checks"] {
+        let col_infos: Vec<(ColumnName, ColumnType)> = conn.query_map_into(&format!("SELECT name, type FROM pragma_table_info('{table}')"), []).map_err(|e| eyre::eyre!("could not query {table}' table_info: {e}"))?;
+        for (name, _) in static_columns.iter() {
+            if !col_infos.iter().any(|(col_name, col_kind)| col_name.eq_ignore_ascii_case(name) && *col_kind == ColumnType::Text) {
+                eyre::bail!("expected a column {table}.{name} w/ type Text for static column '{name}'");
+            }
+        }
+    }
+    Ok(())
+}
 
-    struct ColumnInfo {
-        name: String,
-        kind: corro_api_types::ColumnType
+/// Checks the consul tables' schema, detecting the optional ones
+pub(crate) fn check_schema(conn: &Connection) -> eyre::Result<ConsulTables> {
+    let col_infos: Vec<(ColumnName, ColumnType)> = conn.query_map_into("SELECT name, type FROM pragma_table_info('consul_services')", []).map_err(|e| eyre::eyre!("could not query consul_services' table_info: {e}"))?;
+    if col_infos.is_empty() {
+        eyre::bail!("table consul_services doesn't exist");
     }
 
-    let col_infos: Vec<ColumnInfo> = conn.prepare("PRAGMA table_info(consul_services)")?.query_map([], |row| Ok(ColumnInfo { name: row.get(1)?, kind: row.get(2)? })).map_err(|e| eyre::eyre!("could not query consul_services' table_info: {e}"))?.collect::<Result<Vec<_>, _>>()?;
-    
     let expected_cols = [
         ("node", vec![ColumnType::Text]), 
         ("id", vec![ColumnType::Text]),
@@ -160,13 +1008,17 @@ async fn setup(
     ];
 
     for (name, kind) in expected_cols {
-        if !col_infos.iter().any(|info| info.name == name && kind.contains(&info.kind)) {
+        // sqlite identifiers are case-insensitive, e.g. `Node TEXT` works too
+        if !col_infos.iter().any(|(col_name, col_kind)| col_name.eq_ignore_ascii_case(name) && kind.contains(col_kind)) {
             eyre::bail!("expected a column consul_services.{name} w/ type {kind:?}");
         }
     }
 
-    let col_infos: Vec<ColumnInfo> = conn.prepare("PRAGMA table_info(consul_checks)")?.query_map([], |row| Ok(ColumnInfo { name: row.get(1)?, kind: row.get(2)? })).map_err(|e| eyre::eyre!("could not query consul_checks' table_info: {e}"))?.collect::<Result<Vec<_>, _>>()?;
-    
+    let col_infos: Vec<(ColumnName, ColumnType)> = conn.query_map_into("SELECT name, type FROM pragma_table_info('consul_checks')", []).map_err(|e| eyre::eyre!("could not query consul_checks' table_info: {e}"))?;
+    if col_infos.is_empty() {
+        eyre::bail!("table consul_checks doesn't exist");
+    }
+
     let expected_cols = [
         ("node", vec![ColumnType::Text]), 
         ("id", vec![ColumnType::Text]),
@@ -179,13 +1031,186 @@ async fn setup(
     ];
 
     for (name, kind) in expected_cols {
-        if !col_infos.iter().any(|info| info.name == name && kind.contains(&info.kind)) {
+        // sqlite identifiers are case-insensitive, e.g. `Node TEXT` works too
+        if !col_infos.iter().any(|(col_name, col_kind)| col_name.eq_ignore_ascii_case(name) && kind.contains(col_kind)) {
             eyre::bail!("expected a column consul_checks.{name} w/ type {kind:?}");
         }
     }
 
-    Ok(())
-}
+    let service_status = has_service_status(conn)?;
+    if service_status {
+        info!("consul_services has a status column, storing the worst status of each service's checks");
+    }
+
+    let service_tags = has_service_tags(conn)?;
+    if service_tags {
+        info!("consul_service_tags exists, storing a row per tag of each service");
+    }
+
+    let datacenter = has_datacenter(conn)?;
+    if datacenter {
+        info!("consul tables have a datacenter column, storing the datacenter of each row");
+    }
+
+    let synthetic_checks = has_synthetic_checks(conn)?;
+    if synthetic_checks {
+        info!("consul_checks has a synthetic column, flagging the checks synthesized for services w/o any");
+    }
+
+    Ok(ConsulTables { service_status, service_tags, datacenter, synthetic_checks })
+}
+
+/// Whether consul_checks has the optional `synthetic INTEGER` column, set for
+/// the checks synthesized for services w/o any
+pub(super) fn has_synthetic_checks(conn: &Connection) -> eyre::Result<bool> {
+    let col_infos: Vec<(ColumnName, ColumnType)> = conn.query_map_into("SELECT name, type FROM pragma_table_info('consul_checks')", []).map_err(|e| eyre::eyre!("could not query consul_checks' table_info: {e}"))?;
+    let Some((_, col_kind)) = col_infos.iter().find(|(col_name, _)| col_name.eq_ignore_ascii_case("synthetic")) else {
+        return Ok(false);
+    };
+    if *col_kind != ColumnType::Integer {
+        eyre::bail!("expected consul_checks.synthetic to have type Integer");
+    }
+    Ok(true)
+}
+
+/// Id of the check synthesized for service `service_id`
+pub fn synthetic_check_id(service_id: &str) -> String {
+    format!("svc:{service_id}:synthetic")
+}
+
+/// Adds a passing `registered` check to `checks` for each of `services` w/o
+/// any check, so every service has a row in consul_checks. Synthetic checks
+/// are removed like any other once the service gets a real check or goes
+/// away. A real check w/ the synthetic id is left alone. Returns the ids of
+/// the checks synthesized.
+pub(super) fn synthesize_checks(services: &HashMap<String, AgentService>, checks: &mut HashMap<String, AgentCheck>) -> HashSet<String> {
+    let checked: HashSet<&str> = checks.values().map(|check| check.service_id.as_str()).collect();
+
+    let mut synthetic = HashSet::new();
+    for svc in services.values() {
+        if checked.contains(svc.id.as_str()) {
+            continue;
+        }
+        let id = synthetic_check_id(&svc.id);
+        if checks.contains_key(&id) {
+            continue;
+        }
+        checks.insert(id.clone(), AgentCheck {
+            id: id.clone(),
+            name: "registered".into(),
+            status: ConsulCheckStatus::Passing,
+            output: String::new(),
+            service_id: svc.id.clone(),
+            service_name: svc.name.clone(),
+            notes: None,
+        });
+        synthetic.insert(id);
+    }
+
+    synthetic
+}
+
+/// Whether both consul tables have the optional `datacenter TEXT` column. It
+/// must be part of their primary keys, so the same ids synced for different
+/// datacenters are different rows.
+pub(super) fn has_datacenter(conn: &Connection) -> eyre::Result<bool> {
+    let mut found = vec![];
+    for table in ["consul_services", "consul_checks"] {
+        let col_infos: Vec<(ColumnName, ColumnType, i64)> = conn.query_map_into(&format!("SELECT name, type, pk FROM pragma_table_info('{table}')"), []).map_err(|e| eyre::eyre!("could not query {table}' table_info: {e}"))?;
+        if let Some((_, col_kind, pk)) = col_infos.iter().find(|(col_name, _, _)| col_name.eq_ignore_ascii_case("datacenter")) {
+            if *col_kind != ColumnType::Text || *pk == 0 {
+                eyre::bail!("expected {table}.datacenter to have type Text and be part of the primary key");
+            }
+            found.push(table);
+        }
+    }
+
+    match found.as_slice() {
+        [] => Ok(false),
+        [_, _] => Ok(true),
+        [table, ..] => eyre::bail!("expected a datacenter column in both consul_services and consul_checks, only {table} has one"),
+    }
+}
+
+/// Whether the optional consul_service_tags table exists, w/ a row per tag of
+/// each service so services can be looked up by tag through its primary key
+pub(super) fn has_service_tags(conn: &Connection) -> eyre::Result<bool> {
+    let col_infos: Vec<(ColumnName, ColumnType)> = conn.query_map_into("SELECT name, type FROM pragma_table_info('consul_service_tags')", []).map_err(|e| eyre::eyre!("could not query consul_service_tags' table_info: {e}"))?;
+    if col_infos.is_empty() {
+        return Ok(false);
+    }
+
+    for name in ["node", "service_id", "tag"] {
+        if !col_infos.iter().any(|(col_name, col_kind)| col_name.eq_ignore_ascii_case(name) && *col_kind == ColumnType::Text) {
+            eyre::bail!("expected a column consul_service_tags.{name} w/ type Text");
+        }
+    }
+
+    Ok(true)
+}
+
+/// Whether consul_services has the optional `status TEXT` column, filled w/
+/// the worst status of each service's checks
+pub(super) fn has_service_status(conn: &Connection) -> eyre::Result<bool> {
+    let col_infos: Vec<(ColumnName, ColumnType)> = conn.query_map_into("SELECT name, type FROM pragma_table_info('consul_services')", []).map_err(|e| eyre::eyre!("could not query consul_services' table_info: {e}"))?;
+
+    Ok(col_infos.iter().any(|(col_name, col_kind)| col_name.eq_ignore_ascii_case("status") && *col_kind == ColumnType::Text))
+}
+
+/// How services get their aggregate status, when consul_services has a
+/// `status` column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceStatusConfig {
+    /// Node checks, not bound to a service, count toward every service
+    pub include_node_checks: bool,
+}
+
+/// The worst status (critical > warning > passing) among the checks of each
+/// service, by service id
+#[derive(Debug, Default)]
+pub struct ServiceStatuses {
+    services: HashMap<String, ConsulCheckStatus>,
+    node: ConsulCheckStatus,
+}
+
+impl ServiceStatuses {
+    pub fn new<'a>(checks: impl IntoIterator<Item = &'a AgentCheck>, config: ServiceStatusConfig) -> Self {
+        let mut statuses = Self::default();
+        for check in checks {
+            let status = if check.service_id.is_empty() {
+                if !config.include_node_checks {
+                    continue;
+                }
+                &mut statuses.node
+            } else {
+                statuses.services.entry(check.service_id.clone()).or_default()
+            };
+            *status = worst_status(*status, check.status);
+        }
+        statuses
+    }
+
+    /// Status of service `id`, passing if it has no checks
+    pub fn get(&self, id: &str) -> ConsulCheckStatus {
+        worst_status(self.node, self.services.get(id).copied().unwrap_or_default())
+    }
+}
+
+fn worst_status(a: ConsulCheckStatus, b: ConsulCheckStatus) -> ConsulCheckStatus {
+    fn severity(status: ConsulCheckStatus) -> u8 {
+        match status {
+            ConsulCheckStatus::Passing => 0,
+            ConsulCheckStatus::Warning => 1,
+            ConsulCheckStatus::Critical => 2,
+        }
+    }
+
+    if severity(b) > severity(a) {
+        b
+    } else {
+        a
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ConsulCheckNotesDirectives {
@@ -199,26 +1224,117 @@ enum ConsulCheckField {
     Output,
 }
 
-#[derive(Debug, Default)]
+/// What a pass applied for services or checks. The serialized field names
+/// are relied upon by dashboards, keep them stable.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ApplyStats {
     pub upserted: usize,
     pub deleted: usize,
+    /// Ops which failed to apply, they're retried on the next pass
+    pub errors: usize,
+    /// Statements sent to corrosion, bookkeeping included
+    pub statements: usize,
+    /// Time spent applying, serialized as milliseconds
+    #[serde(with = "duration_millis")]
+    pub duration: Duration,
+    /// When applying started and finished, in milliseconds since the unix epoch
+    pub started_at: i64,
+    pub finished_at: i64,
 }
 
 impl ApplyStats {
     fn is_zero(&self) -> bool {
-        self.upserted == 0 && self.deleted == 0
+        self.upserted == 0 && self.deleted == 0 && self.errors == 0
+    }
+
+    /// Adds up `other`'s counts. Services and checks are applied together, so
+    /// the merged stats span both passes instead of summing their durations.
+    pub fn merge(&mut self, other: &ApplyStats) {
+        self.upserted += other.upserted;
+        self.deleted += other.deleted;
+        self.errors += other.errors;
+        self.statements += other.statements;
+        self.duration = self.duration.max(other.duration);
+        self.started_at = match (self.started_at, other.started_at) {
+            (0, at) | (at, 0) => at,
+            (a, b) => a.min(b),
+        };
+        self.finished_at = self.finished_at.max(other.finished_at);
+    }
+}
+
+mod duration_millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+/// Consecutive apply failures per id, along w/ the hash (`None` for deletes)
+/// which failed to apply. An op failing `MAX_APPLY_ATTEMPTS` times in a row
+/// is dead-lettered: it's recorded as applied so it isn't retried until
+/// consul reports something different for it.
+#[derive(Debug, Default)]
+pub struct ApplyFailures {
+    services: HashMap<String, (Option<u64>, u32)>,
+    checks: HashMap<String, (Option<u64>, u32)>,
+}
+
+impl ApplyFailures {
+    /// Records a failure, returns true if the op should be dead-lettered
+    fn record(&mut self, service: bool, id: &str, hash: Option<u64>) -> bool {
+        let map = if service { &mut self.services } else { &mut self.checks };
+        let attempts = match map.get_mut(id) {
+            Some((failed_hash, attempts)) if *failed_hash == hash => {
+                *attempts += 1;
+                *attempts
+            }
+            _ => {
+                map.insert(id.to_owned(), (hash, 1));
+                1
+            }
+        };
+        if attempts >= MAX_APPLY_ATTEMPTS {
+            map.remove(id);
+            true
+        } else {
+            false
+        }
     }
 }
 
-pub fn hash_service(svc: &AgentService) -> u64 {
+/// Hashes `svc` w/ its aggregate `status`, if stored, so status changes are
+/// synced even when the service itself is unchanged
+pub fn hash_service(svc: &AgentService, status: Option<ConsulCheckStatus>, static_columns: &StaticColumns) -> u64 {
     let mut hasher = seahash::SeaHasher::new();
     svc.hash(&mut hasher);
+    if let Some(status) = status {
+        hasher.write(status.as_str().as_bytes());
+    }
+    hash_static_columns(&mut hasher, static_columns);
     hasher.finish()
 }
 
-pub fn hash_check(check: &AgentCheck) -> u64 {
+// changing them rewrites every row once, w/o any the hashes are unchanged
+fn hash_static_columns(hasher: &mut impl Hasher, static_columns: &StaticColumns) {
+    for (name, value) in static_columns.iter() {
+        hasher.write(name.as_bytes());
+        hasher.write_u8(0);
+        hasher.write(value.as_bytes());
+        hasher.write_u8(0);
+    }
+}
+
+pub fn hash_check(check: &AgentCheck, static_columns: &StaticColumns) -> u64 {
     let mut hasher = seahash::SeaHasher::new();
+    hash_static_columns(&mut hasher, static_columns);
     hasher.write(check.service_name.as_bytes());
     hasher.write(check.service_id.as_bytes());
     if let Some(notes) = check
@@ -245,183 +1361,322 @@ pub fn hash_check(check: &AgentCheck) -> u64 {
     hasher.finish()
 }
 
-fn append_upsert_service_statements(
+#[allow(clippy::too_many_arguments)]
+pub(super) fn append_upsert_service_statements(
     statements: &mut Vec<Statement>,
-    node: &'static str,
+    node: &str,
+    datacenter: Option<&str>,
     svc: AgentService,
+    status: Option<ConsulCheckStatus>,
+    static_columns: &StaticColumns,
     hash: u64,
     updated_at: i64,
 ) {
     // run this by corrosion so it's part of the same transaction
-    statements.push(Statement::WithParams("INSERT INTO __corro_consul_services ( id, hash )
-    VALUES (?, ?)
-    ON CONFLICT (id) DO UPDATE SET
-        hash = excluded.hash;"
-    .into(),vec![
-        
-        svc.id.clone().into(),
-        hash.to_be_bytes().to_vec().into(),
-    ]));
+    statements.push(bookkeeping::upsert_hash_stmt(&bookkeeping::SERVICES, &bookkeeping_id(datacenter, &svc.id), hash));
 
+    let row = ServiceRow::new(svc, node, updated_at).with_datacenter(datacenter).with_status(status);
     // upsert!
-    statements.push(Statement::WithParams("INSERT INTO consul_services ( node, id, name, tags, meta, port, address, updated_at )
-    VALUES (?,?,?,?,?,?,?,?)
-    ON CONFLICT(node, id) DO UPDATE SET
-        name = excluded.name,
-        tags = excluded.tags,
-        meta = excluded.meta,
-        port = excluded.port,
-        address = excluded.address,
-        updated_at = excluded.updated_at;"
-        .into(),vec![
-        
-        node.into(),
-        svc.id.into(),
-        svc.name.into(),
-        serde_json::to_string(&svc.tags).unwrap_or_else(|_| "[]".to_string()).into(),
-        serde_json::to_string(&svc.meta).unwrap_or_else(|_| "{}".to_string()).into(),
-        svc.port.into(),
-        svc.address.into(),
-        updated_at.into(),
-    ]));
+    statements.push(row.upsert(&row.column_set(), static_columns));
+}
+
+/// What's stored of a check's `output`: cut at the last char boundary
+/// within `max_bytes`, followed by `TRUNCATED_OUTPUT_MARKER`. Checks are
+/// hashed w/ their full output, so changes past the cut are still synced.
+pub(super) fn stored_output(output: &str, max_bytes: Option<usize>) -> Cow<'_, str> {
+    match max_bytes {
+        Some(max_bytes) if output.len() > max_bytes => {
+            let end = (0..=max_bytes).rev().find(|i| output.is_char_boundary(*i)).unwrap_or(0);
+            Cow::Owned(format!("{}{TRUNCATED_OUTPUT_MARKER}", &output[..end]))
+        }
+        _ => Cow::Borrowed(output),
+    }
 }
 
-fn append_upsert_check_statements(
+#[allow(clippy::too_many_arguments)]
+pub(super) fn append_upsert_check_statements(
     statements: &mut Vec<Statement>,
-    node: &'static str,
+    node: &str,
+    datacenter: Option<&str>,
     check: AgentCheck,
+    synthetic: Option<bool>,
+    static_columns: &StaticColumns,
+    max_output_bytes: Option<usize>,
     hash: u64,
     updated_at: i64,
 ) {
     // run this by corrosion so it's part of the same transaction
-    statements.push(Statement::WithParams("INSERT INTO __corro_consul_checks ( id, hash )
-    VALUES (?, ?)
-    ON CONFLICT (id) DO UPDATE SET
-        hash = excluded.hash;"
-    .into(),vec![
-        
-        check.id.clone().into(),
-        hash.to_be_bytes().to_vec().into(),
-    ]));
+    statements.push(bookkeeping::upsert_hash_stmt(&bookkeeping::CHECKS, &bookkeeping_id(datacenter, &check.id), hash));
 
+    let row = CheckRow::new(check, node, updated_at).with_datacenter(datacenter).with_synthetic(synthetic).with_max_output_bytes(max_output_bytes);
     // upsert!
-    statements.push(Statement::WithParams("INSERT INTO consul_checks ( node, id, service_id, service_name, name, status, output, updated_at )
-    VALUES (?,?,?,?,?,?,?,?)
-    ON CONFLICT(node, id) DO UPDATE SET
-        service_id = excluded.service_id,
-        service_name = excluded.service_name,
-        name = excluded.name,
-        status = excluded.status,
-        output = excluded.output,
-        updated_at = excluded.updated_at;"
-        .into(),vec![
-        
+    statements.push(row.upsert(&row.column_set(), static_columns));
+}
+
+fn append_delete_service_statements(
+    statements: &mut Vec<Statement>,
+    node: &str,
+    datacenter: Option<&str>,
+    id: String,
+) {
+    statements.push(bookkeeping::delete_hash_stmt(&bookkeeping::SERVICES, &bookkeeping_id(datacenter, &id)));
+    statements.push(match datacenter {
+        Some(datacenter) => Statement::WithParams("DELETE FROM consul_services WHERE node = ? AND datacenter = ? AND id = ?;".into(),vec![
+            node.into(),
+            datacenter.into(),
+            id.into(),
+        ]),
+        None => Statement::WithParams("DELETE FROM consul_services WHERE node = ? AND id = ?;".into(),vec![
+            node.into(),
+            id.into(),
+        ]),
+    });
+}
+
+/// Inserts the tags in `new` and deletes the ones in `old` which aren't, so
+/// unchanged tags aren't written (nor replicated) again
+pub(super) fn append_service_tags_statements(
+    statements: &mut Vec<Statement>,
+    node: &str,
+    service_id: &str,
+    old: &BTreeSet<String>,
+    new: &BTreeSet<String>,
+) {
+    for tag in old.difference(new) {
+        statements.push(Statement::WithParams("DELETE FROM consul_service_tags WHERE node = ? AND service_id = ? AND tag = ?;".into(),vec![
+            node.into(),
+            service_id.into(),
+            tag.as_str().into(),
+        ]));
+    }
+    for tag in new.difference(old) {
+        statements.push(Statement::WithParams("INSERT INTO consul_service_tags ( node, service_id, tag )
+    VALUES (?,?,?)
+    ON CONFLICT(node, service_id, tag) DO NOTHING;".into(),vec![
+            node.into(),
+            service_id.into(),
+            tag.as_str().into(),
+        ]));
+    }
+}
+
+pub(super) fn append_delete_service_tags_statements(
+    statements: &mut Vec<Statement>,
+    node: &str,
+    service_id: &str,
+) {
+    statements.push(Statement::WithParams("DELETE FROM consul_service_tags WHERE node = ? AND service_id = ?;".into(),vec![
         node.into(),
-        check.id.into(),
-        check.service_id.into(),
-        check.service_name.into(),
-        check.name.into(),
-        check.status.as_str().into(),
-        check.output.into(),
-        updated_at.into(),
+        service_id.into(),
     ]));
+}
 
+fn append_delete_check_statements(
+    statements: &mut Vec<Statement>,
+    node: &str,
+    datacenter: Option<&str>,
+    id: String,
+) {
+    statements.push(bookkeeping::delete_hash_stmt(&bookkeeping::CHECKS, &bookkeeping_id(datacenter, &id)));
+    statements.push(match datacenter {
+        Some(datacenter) => Statement::WithParams("DELETE FROM consul_checks WHERE node = ? AND datacenter = ? AND id = ?;".into(),vec![
+            node.into(),
+            datacenter.into(),
+            id.into(),
+        ]),
+        None => Statement::WithParams("DELETE FROM consul_checks WHERE node = ? AND id = ?;".into(),vec![
+            node.into(),
+            id.into(),
+        ]),
+    });
 }
 
 enum ConsulServiceOp {
-    Upsert { svc: AgentService, hash: u64 },
+    Upsert { svc: AgentService, status: Option<ConsulCheckStatus>, hash: u64, old_hash: Option<u64> },
     Delete { id: String },
 }
 
+impl ConsulServiceOp {
+    fn id(&self) -> &str {
+        match self {
+            ConsulServiceOp::Upsert { svc, .. } => &svc.id,
+            ConsulServiceOp::Delete { id } => id,
+        }
+    }
+}
+
 enum ConsulCheckOp {
-    Upsert { check: AgentCheck, hash: u64 },
+    Upsert { check: AgentCheck, hash: u64, old_hash: Option<u64>, synthetic: bool },
     Delete { id: String }
 }
 
-fn update_services(
-    mut services: HashMap<String, AgentService>,
+impl ConsulCheckOp {
+    fn id(&self) -> &str {
+        match self {
+            ConsulCheckOp::Upsert { check, .. } => &check.id,
+            ConsulCheckOp::Delete { id } => id,
+        }
+    }
+}
+
+/// Hashes `items` w/ `hash`, spread over blocking threads once there are at
+/// least `threshold` of them. Hashes are in the same order as `items`.
+async fn hash_all<T, F>(items: Vec<T>, hash: F, threshold: usize) -> eyre::Result<Vec<(T, u64)>>
+where
+    T: Send + 'static,
+    F: Fn(&T) -> u64 + Clone + Send + 'static,
+{
+    fn hash_chunk<T, F: Fn(&T) -> u64>(chunk: Vec<T>, hash: &F) -> Vec<(T, u64)> {
+        chunk.into_iter().map(|item| {
+            let h = hash(&item);
+            (item, h)
+        }).collect()
+    }
+
+    if items.len() < threshold {
+        // not worth handing off, typical ticks only change a few ids
+        return Ok(hash_chunk(items, &hash));
+    }
+
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = (items.len() + threads - 1) / threads;
+
+    let len = items.len();
+    let mut items = items.into_iter();
+    let mut handles = vec![];
+    loop {
+        let chunk: Vec<T> = items.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let hash = hash.clone();
+        handles.push(tokio::task::spawn_blocking(move || hash_chunk(chunk, &hash)));
+    }
+
+    let mut hashed = Vec::with_capacity(len);
+    for handle in handles {
+        hashed.extend(handle.await?);
+    }
+    Ok(hashed)
+}
+
+async fn update_services(
+    services: HashMap<String, AgentService>,
+    statuses: Option<&ServiceStatuses>,
+    static_columns: &StaticColumns,
     hashes: &HashMap<String, u64>,
+    forced: &HashSet<String>,
     skip_hash_check: bool,
-) -> Vec<ConsulServiceOp> {
+) -> eyre::Result<Vec<ConsulServiceOp>> {
     let mut ops = vec![];
 
-    {
-        for (id, old_hash) in hashes.iter() {
-            if let Some(svc) = services.remove(id) {
-                let hash = hash_service(&svc);
-                if skip_hash_check || *old_hash != hash {
-                    info!("updating service '{id}'");
+    for id in hashes.keys() {
+        if !services.contains_key(id) {
+            info!("deleting service: {id}");
+            ops.push(ConsulServiceOp::Delete { id: id.clone() });
+        }
+    }
 
-                    ops.push(ConsulServiceOp::Upsert { svc, hash });
+    let services: Vec<_> = services.into_iter().map(|(id, svc)| {
+        let status = statuses.map(|statuses| statuses.get(&id));
+        (id, svc, status)
+    }).collect();
+    let static_columns = Arc::new(static_columns.clone());
+    let hashed = hash_all(services, move |(_, svc, status)| hash_service(svc, *status, &static_columns), PARALLEL_HASH_THRESHOLD).await?;
+
+    for ((id, svc, status), hash) in hashed {
+        match hashes.get(&id) {
+            Some(old_hash) => {
+                if skip_hash_check || *old_hash != hash || forced.contains(&id) {
+                    info!("updating service '{id}'");
+                    ops.push(ConsulServiceOp::Upsert { svc, status, hash, old_hash: Some(*old_hash) });
                 }
-            } else {
-                info!("deleting service: {id}");
-                ops.push(ConsulServiceOp::Delete { id: id.clone() });
+            }
+            None => {
+                info!("inserting service '{id}'");
+                ops.push(ConsulServiceOp::Upsert { svc, status, hash, old_hash: None });
             }
         }
     }
 
-    // new services
-    for (id, svc) in services {
-        info!("inserting service '{id}'");
-
-        let hash = hash_service(&svc);
-        ops.push(ConsulServiceOp::Upsert { svc, hash });
-    }
+    // the same inventory always makes the same statements
+    ops.sort_unstable_by(|a, b| a.id().cmp(b.id()));
 
-    ops
+    Ok(ops)
 }
 
-fn update_checks(
-    mut checks: HashMap<String, AgentCheck>,
+async fn update_checks(
+    checks: HashMap<String, AgentCheck>,
+    synthetic: &HashSet<String>,
+    static_columns: &StaticColumns,
     hashes: &HashMap<String, u64>,
+    forced: &HashSet<String>,
     skip_hash_check: bool,
-) -> Vec<ConsulCheckOp> {
+) -> eyre::Result<Vec<ConsulCheckOp>> {
     let mut ops = vec![];
 
-    {
-        for (id, old_hash) in hashes.iter() {
-            if let Some(check) = checks.remove(id) {
-                let hash = hash_check(&check);
-                if skip_hash_check || *old_hash != hash {
-                    info!("updating check '{id}'");
+    for id in hashes.keys() {
+        if !checks.contains_key(id) {
+            info!("deleting check: {id}");
+            ops.push(ConsulCheckOp::Delete { id: id.clone() });
+        }
+    }
+
+    let static_columns = Arc::new(static_columns.clone());
+    let hashed = hash_all(checks.into_iter().collect(), move |(_, check)| hash_check(check, &static_columns), PARALLEL_HASH_THRESHOLD).await?;
 
-                    ops.push(ConsulCheckOp::Upsert { check, hash });
+    for ((id, check), hash) in hashed {
+        let synthetic = synthetic.contains(&id);
+        match hashes.get(&id) {
+            Some(old_hash) => {
+                if skip_hash_check || *old_hash != hash || forced.contains(&id) {
+                    info!("updating check '{id}'");
+                    ops.push(ConsulCheckOp::Upsert { check, hash, old_hash: Some(*old_hash), synthetic });
                 }
-            } else {
-                info!("deleting check: {id}");
-                ops.push(ConsulCheckOp::Delete { id: id.clone() });
+            }
+            None => {
+                info!("upserting check '{id}'");
+                ops.push(ConsulCheckOp::Upsert { check, hash, old_hash: None, synthetic });
             }
         }
     }
 
-    // new checks
-    for (id, check) in checks {
-        info!("upserting check '{id}'");
-        let hash = hash_check(&check);
-        ops.push(ConsulCheckOp::Upsert { check, hash });
-    }
-    
-    ops
+    // the same inventory always makes the same statements
+    ops.sort_unstable_by(|a, b| a.id().cmp(b.id()));
+
+    Ok(ops)
 }
 
-pub async fn update_consul(
-    consul: &Client,
-    node: &'static str,
-    corrosion: &CorrosionClient,
-    service_hashes: &mut HashMap<String, u64>,
-    check_hashes: &mut HashMap<String, u64>,
+pub async fn update_consul<C: ConsulSource + ?Sized>(
+    consul: &C,
+    ctx: &mut SyncContext,
     skip_hash_check: bool,
 ) -> eyre::Result<(ApplyStats, ApplyStats)> {
+    let tick_start = Instant::now();
+
+    let service_names = &ctx.service_names;
+    let service_status = ctx.service_status;
+    let rewriter = &ctx.rewriter;
+    let ids = &ctx.ids;
+
     let fut_services = async {
         let start = Instant::now();
-            match timeout(Duration::from_secs(5), consul.agent_services()).await {
-                Ok(Ok(services)) => {
+            match timeout(CONSUL_REQUEST_TIMEOUT, consul.agent_services()).await {
+                Ok(Ok(mut services)) => {
                     histogram!(
                         "corro_consul.consul.response.time.seconds",
                         start.elapsed().as_secs_f64()
                     );
-                    Ok::<_, eyre::Report>(update_services(services, service_hashes, skip_hash_check))
+                    if !service_names.is_empty() {
+                        services.retain(|_, svc| service_names.contains(&svc.name));
+                    }
+                    let fetch_elapsed = start.elapsed();
+                    let hash_start = Instant::now();
+                    let (mut services, conflicts) = ids.services(services);
+                    for svc in services.values_mut() {
+                        rewriter.apply(svc);
+                    }
+                    Ok::<_, eyre::Report>((services, conflicts, fetch_elapsed, hash_start.elapsed()))
                 }
                 Ok(Err(e)) => {
                     increment_counter!("corro_consul.consul.response.errors", "error" => e.to_string(), "type" => "services");
@@ -437,13 +1692,21 @@ pub async fn update_consul(
 
     let fut_checks = async {
         let start = Instant::now();
-            match timeout(Duration::from_secs(5), consul.agent_checks()).await {
+            match timeout(CONSUL_REQUEST_TIMEOUT, consul.agent_checks()).await {
                 Ok(Ok(checks)) => {
                     histogram!(
                         "corro_consul.consul.response.time.seconds",
                         start.elapsed().as_secs_f64()
                     );
-                    Ok::<_, eyre::Report>(update_checks(checks, check_hashes, skip_hash_check))
+                    let fetch_elapsed = start.elapsed();
+                    let hash_start = Instant::now();
+                    let (mut checks, conflicts) = ids.checks(checks);
+                    // node checks count too, aggregate before filtering
+                    let statuses = service_status.map(|config| ServiceStatuses::new(checks.values(), config));
+                    if !service_names.is_empty() {
+                        checks.retain(|_, check| service_names.contains(&check.service_name));
+                    }
+                    Ok::<_, eyre::Report>((checks, conflicts, statuses, fetch_elapsed, hash_start.elapsed()))
                 }
                 Ok(Err(e)) => {
                     increment_counter!("corro_consul.consul.response.errors", "error" => e.to_string(), "type" => "checks");
@@ -456,123 +1719,327 @@ pub async fn update_consul(
             }
     };
 
-    let (svcs, checks) = tokio::try_join!(fut_services, fut_checks)?;
+    let ((services, svc_conflicts, svcs_fetch, svcs_rewrite), (mut checks, check_conflicts, statuses, checks_fetch, checks_statuses)) = tokio::try_join!(fut_services, fut_checks)?;
+    ctx.ids.report(svc_conflicts.into_iter().chain(check_conflicts).collect());
+
+    // synthetic checks depend on the services
+    let hash_start = Instant::now();
+    let synthetic = if ctx.synthesize_checks { synthesize_checks(&services, &mut checks) } else { HashSet::new() };
+    let checks = update_checks(checks, &synthetic, &ctx.static_columns, &ctx.check_hashes, &ctx.forced_checks, skip_hash_check).await?;
+    histogram!("corro_consul.hash.time.seconds", hash_start.elapsed().as_secs_f64(), "type" => "checks");
+    let checks_hash = checks_statuses + hash_start.elapsed();
+
+    // services' statuses depend on their checks, hash them once both are in
+    let hash_start = Instant::now();
+    let svcs = update_services(services, statuses.as_ref(), &ctx.static_columns, &ctx.service_hashes, &ctx.forced_services, skip_hash_check).await?;
+    histogram!("corro_consul.hash.time.seconds", hash_start.elapsed().as_secs_f64(), "type" => "services");
+    let svcs_hash = svcs_rewrite + hash_start.elapsed();
+
+    log_diff("services", svcs.iter().map(|op| match op {
+        ConsulServiceOp::Upsert { svc, hash, old_hash, .. } => (svc.id.as_str(), Some(*hash), *old_hash),
+        ConsulServiceOp::Delete { id } => (id.as_str(), None, None),
+    }));
+    log_diff("checks", checks.iter().map(|op| match op {
+        ConsulCheckOp::Upsert { check, hash, old_hash, .. } => (check.id.as_str(), Some(*hash), *old_hash),
+        ConsulCheckOp::Delete { id } => (id.as_str(), None, None),
+    }));
+
+    let res = execute(ctx, svcs, checks).await;
+
+    if let Ok((svc_stats, check_stats)) = &res {
+        let mut applied = svc_stats.clone();
+        applied.merge(check_stats);
+
+        // both kinds are fetched concurrently, but hashed one after the other
+        debug!(
+            fetch_secs = svcs_fetch.max(checks_fetch).as_secs_f64(),
+            hash_secs = (svcs_hash + checks_hash).as_secs_f64(),
+            execute_secs = applied.duration.as_secs_f64(),
+            total_secs = tick_start.elapsed().as_secs_f64(),
+            statements = applied.statements,
+            errors = applied.errors,
+            "consul tick timings"
+        );
+    }
+
+    res
+}
+
+/// Logs the ids a tick upserts and deletes, w/ the old and new hashes of
+/// updated ids. Takes `(id, new hash, old hash)`, w/o a new hash for deletes.
+fn log_diff<'a>(kind: &'static str, ops: impl Iterator<Item = (&'a str, Option<u64>, Option<u64>)>) {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+
+    let mut upserted = vec![];
+    let mut updated = vec![];
+    let mut deleted = vec![];
+    for (id, hash, old_hash) in ops {
+        match (hash, old_hash) {
+            (Some(hash), Some(old_hash)) => {
+                upserted.push(id.to_owned());
+                updated.push(format!("{id} ({old_hash:016x} -> {hash:016x})"));
+            }
+            (Some(_), None) => upserted.push(id.to_owned()),
+            (None, _) => deleted.push(id.to_owned()),
+        }
+    }
+
+    if upserted.is_empty() && deleted.is_empty() {
+        return;
+    }
+
+    debug!(
+        kind,
+        upserted_count = upserted.len(),
+        deleted_count = deleted.len(),
+        upserted = %capped_ids(&upserted),
+        deleted = %capped_ids(&deleted),
+        updated = %capped_ids(&updated),
+        "consul diff"
+    );
+}
+
+/// Joins the first `MAX_LOGGED_IDS` ids, noting how many were left out
+fn capped_ids(ids: &[String]) -> String {
+    let mut s = ids.iter().take(MAX_LOGGED_IDS).map(String::as_str).collect::<Vec<_>>().join(", ");
+    if ids.len() > MAX_LOGGED_IDS {
+        s.push_str(&format!(" +{} more", ids.len() - MAX_LOGGED_IDS));
+    }
+    s
+}
+
+fn churn_detector(config: &ConsulConfig) -> Option<ChurnDetector> {
+    (config.log_churners > 0).then(|| ChurnDetector::new(config.churn_threshold, Duration::from_secs(config.churn_window_secs)))
+}
+
+/// Warns about the churning ids upserted most often
+fn warn_churners(churn: &mut ChurnDetector, config: &ConsulConfig) {
+    let churners = churn.churners(Instant::now(), config.log_churners);
+    if churners.is_empty() {
+        return;
+    }
 
-    execute(node, corrosion, svcs, service_hashes, checks, check_hashes).await
+    increment_counter!("corro_consul.churn.warnings");
+    warn!(
+        "consul ids upserted more than {} times in the last {}s: {}",
+        config.churn_threshold,
+        config.churn_window_secs,
+        churners.iter().map(|(kind, id, count)| format!("{kind} '{id}' ({count} times)")).collect::<Vec<_>>().join(", ")
+    );
 }
 
 async fn execute(
-    node: &'static str,
-    corrosion: &CorrosionClient,
+    ctx: &mut SyncContext,
     svcs: Vec<ConsulServiceOp>,
-    service_hashes: &mut HashMap<String, u64>,
     checks: Vec<ConsulCheckOp>,
-    check_hashes: &mut HashMap<String, u64>,
-    ) -> eyre::Result<(ApplyStats, ApplyStats)> {
-        let updated_at = SystemTime::now()
+) -> eyre::Result<(ApplyStats, ApplyStats)> {
+    let node = &*ctx.node;
+    let datacenter = ctx.datacenter.as_deref();
+    let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("could not get system time")
         .as_millis() as i64;
 
-    let mut statements = Vec::with_capacity(svcs.len() + checks.len());
+    // consumers rely on updated_at as a freshness signal, it can't go back
+    let (updated_at, regression) = monotonic_updated_at(now, ctx.last_updated_at);
+    if let Some(regression) = regression.filter(|ms| *ms >= CLOCK_REGRESSION_WARN_MS) {
+        increment_counter!("corro_consul.clock.regressions");
+        warn!("system clock is {regression}ms behind the last updated_at written, writing {updated_at} instead of {now}");
+    }
+
+    // each op is its own group: the bookkeeping and data statements are
+    // applied together or not at all, without affecting the other ops
+    let mut groups = Vec::with_capacity(svcs.len() + checks.len());
 
-    let mut svc_to_upsert = vec![];
-    let mut svc_to_delete = vec![];
+    let mut svc_applied = Vec::with_capacity(svcs.len());
+    let mut svc_stats = ApplyStats { started_at: updated_at, ..Default::default() };
 
         for op in svcs {
+            let mut statements = vec![];
             match op {
-                ConsulServiceOp::Upsert { svc, hash } => {
-                    svc_to_upsert.push((svc.id.clone(), hash));
-                    append_upsert_service_statements(&mut statements, node, svc, hash, updated_at);
+                ConsulServiceOp::Upsert { svc, status, hash, .. } => {
+                    let tags = ctx.service_tags.as_ref().map(|stored| {
+                        let tags: BTreeSet<String> = svc.tags.iter().cloned().collect();
+                        let empty = BTreeSet::new();
+                        append_service_tags_statements(&mut statements, node, &svc.id, stored.get(&svc.id).unwrap_or(&empty), &tags);
+                        tags
+                    });
+                    svc_applied.push((svc.id.clone(), Some(hash), tags));
+                    append_upsert_service_statements(&mut statements, node, datacenter, svc, status, &ctx.static_columns, hash, updated_at);
                 },
                 ConsulServiceOp::Delete { id } => {
-                    svc_to_delete.push(id.clone());
-
-                    statements.push(Statement::WithParams("DELETE FROM __corro_consul_services WHERE id = ?;".into(),vec![
-            
-            id.clone().into(),
-        ]));
-        statements.push(Statement::WithParams("DELETE FROM consul_services WHERE node = ? AND id = ?;".into(),vec![
-            
-            node.into(),
-            id.into(),
-        ]));
+                    if ctx.service_tags.is_some() {
+                        append_delete_service_tags_statements(&mut statements, node, &id);
+                    }
+                    svc_applied.push((id.clone(), None, None));
+                    append_delete_service_statements(&mut statements, node, datacenter, id);
                 },
             }
+            svc_stats.statements += statements.len();
+            groups.push(statements);
         }
-    
 
-    let mut check_to_upsert = vec![];
-    let mut check_to_delete = vec![];
+    let mut check_applied = Vec::with_capacity(checks.len());
+    let mut check_stats = ApplyStats { started_at: updated_at, ..Default::default() };
 
         for op in checks {
+            let mut statements = vec![];
             match op {
-                ConsulCheckOp::Upsert { check, hash } => {
-                    check_to_upsert.push((check.id.clone(), hash));
-                    append_upsert_check_statements(&mut statements, node, check, hash, updated_at);
+                ConsulCheckOp::Upsert { check, hash, synthetic, .. } => {
+                    check_applied.push((check.id.clone(), Some(hash)));
+                    append_upsert_check_statements(&mut statements, node, datacenter, check, ctx.synthetic_column.then_some(synthetic), &ctx.static_columns, ctx.max_output_bytes, hash, updated_at);
                 },
                 ConsulCheckOp::Delete { id } => {
-                    check_to_delete.push(id.clone());
-                    statements.push(Statement::WithParams("DELETE FROM __corro_consul_checks WHERE id = ?;".into(),vec![
-            
-            id.clone().into(),
-        ]));
-        statements.push(Statement::WithParams("DELETE FROM consul_checks WHERE node = ? AND id = ?;".into(),vec![
-            
-            node.into(),
-            id.into(),
-        ]));
+                    check_applied.push((id.clone(), None));
+                    append_delete_check_statements(&mut statements, node, datacenter, id);
                 },
             }
+            check_stats.statements += statements.len();
+            groups.push(statements);
         }
-    
 
-    if !statements.is_empty() {
-        corrosion.execute(&statements).await?;
-        info!("updated consul services");
+    if groups.is_empty() {
+        svc_stats.finished_at = updated_at;
+        check_stats.finished_at = updated_at;
+        return Ok((svc_stats, check_stats));
     }
 
-    let mut svc_stats = ApplyStats::default();
+    let start = Instant::now();
+    let res = ctx.corrosion.execute_grouped(groups).await?;
+    info!("updated consul services");
 
-    for (id, hash) in svc_to_upsert {
-        service_hashes.insert(id, hash);
-        svc_stats.upserted +=1 ;
+    ctx.last_updated_at = updated_at;
+    if let Err(e) = record_last_updated_at(&ctx.corrosion, updated_at).await {
+        warn!("could not record the last updated_at written: {e}");
     }
-    for id in svc_to_delete {
-        service_hashes.remove(&id);
-        svc_stats.deleted += 1;
+
+    let duration = start.elapsed();
+    let finished_at = updated_at + duration.as_millis() as i64;
+    for stats in [&mut svc_stats, &mut check_stats] {
+        stats.duration = duration;
+        stats.finished_at = finished_at;
     }
 
-    let mut check_stats = ApplyStats::default();
+    let mut results = res.results.into_iter();
 
-    for (id, hash) in check_to_upsert {
-        check_hashes.insert(id, hash);
-        check_stats.upserted +=1 ;
+    for (id, hash, tags) in svc_applied {
+        match results.next() {
+            Some(ExecResult::Execute { .. }) => {
+                ctx.failures.services.remove(&id);
+                ctx.forced_services.remove(&id);
+                if let (Some(churn), Some(_)) = (ctx.churn.as_mut(), hash) {
+                    churn.record("service", &id, Instant::now());
+                }
+                if let Some(stored) = ctx.service_tags.as_mut() {
+                    match tags {
+                        Some(tags) => {
+                            stored.insert(id.clone(), tags);
+                        }
+                        None => {
+                            stored.remove(&id);
+                        }
+                    }
+                }
+                apply_hash(&mut ctx.service_hashes, id, hash, &mut svc_stats);
+            }
+            Some(ExecResult::Error { error, .. }) => {
+                error!("could not apply service '{id}': {error}");
+                increment_counter!("corro_consul.apply.errors", "type" => "services");
+                svc_stats.errors += 1;
+                if ctx.failures.record(true, &id, hash) {
+                    warn!("dead-lettering service '{id}' after {MAX_APPLY_ATTEMPTS} failed attempts");
+                    increment_counter!("corro_consul.apply.dead_letters", "type" => "services");
+                    ctx.forced_services.remove(&id);
+                    apply_hash(&mut ctx.service_hashes, id, hash, &mut ApplyStats::default());
+                }
+            }
+            None => eyre::bail!("missing result for service '{id}'"),
+        }
     }
-    for id in check_to_delete {
-        check_hashes.remove(&id);
-        check_stats.deleted += 1;
+
+    for (id, hash) in check_applied {
+        match results.next() {
+            Some(ExecResult::Execute { .. }) => {
+                ctx.failures.checks.remove(&id);
+                ctx.forced_checks.remove(&id);
+                if let (Some(churn), Some(_)) = (ctx.churn.as_mut(), hash) {
+                    churn.record("check", &id, Instant::now());
+                }
+                apply_hash(&mut ctx.check_hashes, id, hash, &mut check_stats);
+            }
+            Some(ExecResult::Error { error, .. }) => {
+                error!("could not apply check '{id}': {error}");
+                increment_counter!("corro_consul.apply.errors", "type" => "checks");
+                check_stats.errors += 1;
+                if ctx.failures.record(false, &id, hash) {
+                    warn!("dead-lettering check '{id}' after {MAX_APPLY_ATTEMPTS} failed attempts");
+                    increment_counter!("corro_consul.apply.dead_letters", "type" => "checks");
+                    ctx.forced_checks.remove(&id);
+                    apply_hash(&mut ctx.check_hashes, id, hash, &mut ApplyStats::default());
+                }
+            }
+            None => eyre::bail!("missing result for check '{id}'"),
+        }
     }
 
     Ok((svc_stats, check_stats))
 }
 
+/// Reports the size of a hashes map, warns when it tracks more than
+/// `max_tracked_ids` ids and gives back memory left over by deleted ids.
+///
+/// Ids missing from consul's listing are deleted on the next pull, including
+/// ids loaded at startup which were deleted while the sync wasn't running.
+fn maintain_hashes(kind: &'static str, hashes: &mut HashMap<String, u64>, max_tracked_ids: usize) {
+    gauge!("corro_consul.hashes.count", hashes.len() as f64, "type" => kind);
+
+    if hashes.len() > max_tracked_ids {
+        warn!("tracking {} consul {kind}, more than the configured max of {max_tracked_ids}: are ids churning?", hashes.len());
+    }
+
+    if hashes.capacity() > hashes.len() * 2 {
+        hashes.shrink_to_fit();
+    }
+}
+
+/// Records an applied upsert (`Some(hash)`) or delete (`None`)
+fn apply_hash(hashes: &mut HashMap<String, u64>, id: String, hash: Option<u64>, stats: &mut ApplyStats) {
+    match hash {
+        Some(hash) => {
+            hashes.insert(id, hash);
+            stats.upserted += 1;
+        }
+        None => {
+            hashes.remove(&id);
+            stats.deleted += 1;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::{
+        collections::BTreeMap,
+        future::Future,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+    };
+
     use corro_tests::launch_test_agent;
-    use rusqlite::OptionalExtension;
+    use hyper::StatusCode;
+    use metrics::{Counter, Gauge, Histogram, Key, KeyName, Recorder, SharedString, Unit};
+    use once_cell::sync::Lazy;
     use tokio::time::sleep;
-    use tripwire::Tripwire;
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn basic_operations() -> eyre::Result<()> {
-        _ = tracing_subscriber::fmt::try_init();
-        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    use crate::command::consul::source::faulty::{FaultyConsul, Reply};
 
-        let tmpdir = tempfile::TempDir::new()?;
-        tokio::fs::write(tmpdir.path().join("consul.sql"), b"
+    const CONSUL_SCHEMA: &[u8] = b"
             CREATE TABLE consul_services (
                 node TEXT NOT NULL,
                 id TEXT NOT NULL,
@@ -598,26 +2065,460 @@ mod tests {
                 updated_at INTEGER NOT NULL DEFAULT 0,
                 PRIMARY KEY (node, id)
             );
-        ").await?;
+        ";
 
-        let ta1 = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
-        let ta2 = launch_test_agent(
-            |conf| {
-                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()]).add_schema_path(tmpdir.path().display().to_string())
-                    .build()
-            },
-            tripwire.clone(),
-        )
-        .await?;
+    fn write_config(path: &Utf8Path, consul: &str) {
+        std::fs::write(path, format!("
+[db]
+path = \"/var/lib/corrosion/state.db\"
 
-        let ta1_client = CorrosionClient::new(ta1.agent.api_addr(), ta1.agent.db_path());
+[api]
+addr = \"127.0.0.1:8080\"
 
-        setup(
-            &ta1_client,
-        )
-        .await?;
+[gossip]
+addr = \"127.0.0.1:8787\"
 
-        let mut services = HashMap::new();
+[consul]
+{consul}
+")).unwrap();
+    }
+
+    #[test]
+    fn apply_stats_merge() {
+        let mut stats = ApplyStats {
+            upserted: 2,
+            deleted: 1,
+            errors: 0,
+            statements: 9,
+            duration: Duration::from_millis(40),
+            started_at: 1_000,
+            finished_at: 1_040,
+        };
+        stats.merge(&ApplyStats {
+            upserted: 1,
+            deleted: 0,
+            errors: 1,
+            statements: 4,
+            duration: Duration::from_millis(30),
+            started_at: 990,
+            finished_at: 1_020,
+        });
+
+        assert_eq!(stats, ApplyStats {
+            upserted: 3,
+            deleted: 1,
+            errors: 1,
+            statements: 13,
+            duration: Duration::from_millis(40),
+            started_at: 990,
+            finished_at: 1_040,
+        });
+
+        // empty stats don't reset the start
+        let mut merged = ApplyStats::default();
+        merged.merge(&stats);
+        assert_eq!(merged, stats);
+    }
+
+    #[test]
+    fn apply_stats_serde_is_stable() -> eyre::Result<()> {
+        let stats = ApplyStats {
+            upserted: 3,
+            deleted: 1,
+            errors: 1,
+            statements: 13,
+            duration: Duration::from_millis(1_500),
+            started_at: 1_700_000_000_000,
+            finished_at: 1_700_000_001_500,
+        };
+
+        let value = serde_json::to_value(&stats)?;
+        assert_eq!(value, serde_json::json!({
+            "upserted": 3,
+            "deleted": 1,
+            "errors": 1,
+            "statements": 13,
+            "duration": 1500,
+            "started_at": 1_700_000_000_000i64,
+            "finished_at": 1_700_000_001_500i64,
+        }));
+        assert_eq!(serde_json::from_value::<ApplyStats>(value)?, stats);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reload_config_applies_reloadable_fields() -> eyre::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = Utf8PathBuf::try_from(tmpdir.path().join("config.toml"))?;
+
+        write_config(&path, "client.address = \"127.0.0.1:8500\"");
+        let mut current = Config::load(path.as_str())?;
+
+        // nothing changed
+        assert_eq!(reload_config(&path, &mut current)?, None);
+
+        write_config(&path, "
+client.address = \"127.0.0.1:8600\"
+services = [\"web\"]
+pull-interval-ms = 250
+");
+        let consul_config = reload_config(&path, &mut current)?.expect("reloadable fields changed");
+        assert_eq!(consul_config.services, vec!["web".to_string()]);
+        assert_eq!(consul_config.pull_interval_ms, 250);
+        // the client can't be swapped at runtime
+        assert_eq!(consul_config.client.address, "127.0.0.1:8500");
+        assert_eq!(current.consul.as_ref(), Some(&consul_config));
+
+        // invalid rewrites aren't applied
+        write_config(&path, "
+client.address = \"127.0.0.1:8500\"
+services = [\"web\"]
+pull-interval-ms = 250
+rewrites = [{ address_replace = { pattern = \"(\", replacement = \"\" } }]
+");
+        assert!(reload_config(&path, &mut current).is_err());
+        assert!(current.consul.as_ref().unwrap().rewrites.is_empty());
+
+        Ok(())
+    }
+
+    // serves `/v1/agent/self` w/ `body`, or fails if there's none
+    async fn fake_consul(body: Option<&'static str>) -> eyre::Result<SocketAddr> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let server = hyper::Server::from_tcp(listener)?.serve(hyper::service::make_service_fn(move |_| async move {
+            Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |req: hyper::Request<hyper::Body>| async move {
+                let res = match body {
+                    Some(body) if req.uri().path() == "/v1/agent/self" => hyper::Response::new(hyper::Body::from(body)),
+                    _ => hyper::Response::builder().status(hyper::StatusCode::INTERNAL_SERVER_ERROR).body(hyper::Body::empty()).unwrap(),
+                };
+                Ok::<_, std::convert::Infallible>(res)
+            }))
+        }));
+        tokio::spawn(server);
+        Ok(addr)
+    }
+
+    fn consul_config(tmpdir: &tempfile::TempDir, consul: &str) -> eyre::Result<(ConsulConfig, Client)> {
+        let path = Utf8PathBuf::try_from(tmpdir.path().join("config.toml"))?;
+        write_config(&path, consul);
+        let config = Config::load(path.as_str())?.consul.expect("consul block");
+        let client = Client::new(config.client.clone())?;
+        Ok((config, client))
+    }
+
+    #[tokio::test]
+    async fn node_name_resolution() -> eyre::Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let addr = fake_consul(Some(r#"{"Config": {"NodeName": "consul-node", "Datacenter": "dc1"}}"#)).await?;
+
+        // configured
+        let (config, client) = consul_config(&tmpdir, &format!("client.address = \"{addr}\"\nnode-name = \"configured-node\""))?;
+        assert_eq!(node_name(&config, &client).await?, "configured-node");
+
+        // from the consul agent
+        let (config, client) = consul_config(&tmpdir, &format!("client.address = \"{addr}\""))?;
+        assert_eq!(node_name(&config, &client).await?, "consul-node");
+
+        // the hostname, when consul can't tell
+        let hostname = hostname::get()?.into_string().unwrap();
+        let failing = fake_consul(None).await?;
+        let (config, client) = consul_config(&tmpdir, &format!("client.address = \"{failing}\""))?;
+        assert_eq!(node_name(&config, &client).await?, hostname);
+
+        let no_name = fake_consul(Some(r#"{"Config": {"NodeName": ""}}"#)).await?;
+        let (config, client) = consul_config(&tmpdir, &format!("client.address = \"{no_name}\""))?;
+        assert_eq!(node_name(&config, &client).await?, hostname);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn watch_config_sends_changes() -> eyre::Result<()> {
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::tempdir()?;
+        let path = Utf8PathBuf::try_from(tmpdir.path().join("config.toml"))?;
+
+        write_config(&path, "client.address = \"127.0.0.1:8500\"");
+        let config = Config::load(path.as_str())?;
+
+        let (config_tx, mut config_rx) = watch::channel(config.consul.clone().unwrap());
+        let (reload_tx, reloads) = futures::channel::mpsc::unbounded();
+        let watcher = tokio::spawn(watch_config(path.clone(), config, reloads, config_tx, tripwire));
+
+        write_config(&path, "client.address = \"127.0.0.1:8500\"\nservices = [\"web\", \"db\"]");
+        reload_tx.unbounded_send(())?;
+
+        timeout(Duration::from_secs(5), config_rx.changed()).await??;
+        assert_eq!(config_rx.borrow_and_update().services, vec!["web".to_string(), "db".to_string()]);
+
+        write_config(&path, "client.address = \"127.0.0.1:8500\"\nservices = []");
+        reload_tx.unbounded_send(())?;
+
+        timeout(Duration::from_secs(5), config_rx.changed()).await??;
+        assert!(config_rx.borrow_and_update().services.is_empty());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        watcher.await?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn dead_letters_after_max_attempts() {
+        let mut failures = ApplyFailures::default();
+
+        for _ in 1..MAX_APPLY_ATTEMPTS {
+            assert!(!failures.record(true, "svc", Some(1)));
+        }
+        // a different hash resets the attempts
+        assert!(!failures.record(true, "svc", Some(2)));
+        // checks are tracked separately
+        assert!(!failures.record(false, "svc", Some(2)));
+
+        for _ in 2..MAX_APPLY_ATTEMPTS {
+            assert!(!failures.record(true, "svc", Some(2)));
+        }
+        assert!(failures.record(true, "svc", Some(2)));
+        assert!(failures.services.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn read_local_and_api() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        {
+            let conn = client.pool().get().await?;
+            conn.execute(
+                "INSERT INTO __corro_consul_services (id, hash) VALUES (?, ?), (?, ?)",
+                rusqlite::params!["a", 1u64.to_be_bytes().to_vec(), "b", 2u64.to_be_bytes().to_vec()],
+            )?;
+        }
+
+        let stmt = Statement::WithParams(
+            "SELECT id, hash FROM __corro_consul_services WHERE id >= ? ORDER BY id".into(),
+            vec!["a".into()],
+        );
+
+        async fn collect(mut rows: corro_client::read::RowStream) -> eyre::Result<Vec<QueryEvent>> {
+            let mut events = vec![];
+            while let Some(evt) = rows.next().await {
+                let evt = evt?;
+                // timings differ
+                if let QueryEvent::EndOfQuery { .. } = evt {
+                    continue;
+                }
+                events.push(evt);
+            }
+            Ok(events)
+        }
+
+        let local = client.read(&stmt, ReadPreference::Local).await?;
+        assert!(local.is_local());
+        let api = client.read(&stmt, ReadPreference::Api).await?;
+        assert!(!api.is_local());
+
+        let local = collect(local).await?;
+        assert_eq!(local.len(), 3);
+        assert_eq!(local, collect(api).await?);
+
+        assert_eq!(
+            load_hashes(&client, &bookkeeping::SERVICES, None, 1).await?,
+            HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)])
+        );
+
+        // falls back to the api, without creating the file
+        let missing = ta.tmpdir.path().join("missing.db");
+        let client = CorrosionClient::new(ta.agent.api_addr(), &missing);
+        let rows = client.read(&stmt, ReadPreference::LocalThenApi).await?;
+        assert!(!rows.is_local());
+        assert_eq!(collect(rows).await?, local);
+        assert!(client.read(&stmt, ReadPreference::Local).await.is_err());
+        assert!(!missing.exists());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn load_hashes_in_batches() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let tmpdir = tempfile::tempdir()?;
+        let db_path = tmpdir.path().join("corrosion.db");
+
+        {
+            let mut conn = Connection::open(&db_path)?;
+            conn.execute_batch("CREATE TABLE __corro_consul_services (id TEXT NOT NULL PRIMARY KEY, hash BLOB NOT NULL DEFAULT '');")?;
+            let tx = conn.transaction()?;
+            {
+                let mut insert = tx.prepare("INSERT INTO __corro_consul_services (id, hash) VALUES (?, ?)")?;
+                for i in 0..50_000u64 {
+                    insert.execute(rusqlite::params![format!("svc-{i:05}"), i.to_be_bytes().to_vec()])?;
+                }
+                // not a hash the sync wrote
+                insert.execute(rusqlite::params!["svc-corrupt", vec![1u8, 2, 3]])?;
+            }
+            tx.commit()?;
+        }
+
+        // nothing listens there, everything is read from the file
+        let client = CorrosionClient::new("127.0.0.1:1".parse()?, &db_path);
+
+        let hashes = load_hashes(&client, &bookkeeping::SERVICES, None, 4096).await?;
+        assert_eq!(hashes.len(), 50_001);
+        assert_eq!(hashes["svc-00000"], 0);
+        assert_eq!(hashes["svc-12345"], 12345);
+        assert_eq!(hashes["svc-49999"], 49999);
+        assert_eq!(hashes["svc-corrupt"], bookkeeping::UNREADABLE_HASH);
+
+        // a batch size dividing the row count exactly ends w/ an empty batch
+        assert_eq!(load_hashes(&client, &bookkeeping::SERVICES, None, 50_001).await?, hashes);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn setup_ignores_column_case() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema = std::str::from_utf8(CONSUL_SCHEMA)?
+            .replace("node TEXT", "Node TEXT")
+            .replace("updated_at INTEGER", "UPDATED_AT INTEGER")
+            .replace("service_id TEXT", "Service_Id TEXT");
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), schema).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn setup_waits_for_schema() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        // gives up once the grace period elapsed
+        let err = setup(&client, &StaticColumns::default(), Duration::from_millis(200)).await.unwrap_err();
+        assert!(err.to_string().contains("table consul_services doesn't exist"), "unexpected error: {err}");
+
+        let waiting = tokio::spawn({
+            let client = client.clone();
+            async move { setup(&client, &StaticColumns::default(), Duration::from_secs(30)).await }
+        });
+        sleep(Duration::from_millis(600)).await;
+        assert!(!waiting.is_finished());
+
+        // as if the agent only got to its schema files now
+        corro_client::CorrosionApiClient::new(ta.agent.api_addr())
+            .schema(&[Statement::Simple(std::str::from_utf8(CONSUL_SCHEMA)?.into())])
+            .await?;
+        timeout(Duration::from_secs(10), waiting).await???;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn concurrent_setups() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        // two syncs against the same database
+        let first = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        let second = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        // something else holds the write lock while both start
+        let blocker = rusqlite::Connection::open(ta.agent.db_path())?;
+        blocker.execute_batch("BEGIN IMMEDIATE;")?;
+
+        let setups = tokio::spawn({
+            let (first, second) = (first.clone(), second.clone());
+            async move {
+                let grace = Duration::from_secs(10);
+                let (first, second) = tokio::join!(setup(&first, &StaticColumns::default(), grace), setup(&second, &StaticColumns::default(), grace));
+                Ok::<_, eyre::Report>((first?, second?))
+            }
+        });
+        sleep(Duration::from_millis(300)).await;
+        blocker.execute_batch("COMMIT;")?;
+
+        let (first_tables, second_tables) = timeout(Duration::from_secs(10), setups).await???;
+        assert_eq!(first_tables, second_tables);
+
+        // and again, once everything exists
+        for _ in 0..3 {
+            let (first_res, second_res) = tokio::join!(setup(&first, &StaticColumns::default(), Duration::ZERO), setup(&second, &StaticColumns::default(), Duration::ZERO));
+            assert_eq!(first_res?, first_tables);
+            assert_eq!(second_res?, first_tables);
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_operations() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta1 = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()]).add_schema_path(tmpdir.path().display().to_string())
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let ta1_client = CorrosionClient::new(ta1.agent.api_addr(), ta1.agent.db_path());
+
+        setup(
+            &ta1_client,
+            &StaticColumns::default(),
+            Duration::ZERO,
+        )
+        .await?;
+
+        let mut services = HashMap::new();
 
         let svc = AgentService {
             id: "service-id".into(),
@@ -632,19 +2533,20 @@ mod tests {
 
         services.insert("service-id".into(), svc.clone());
 
-        let mut svc_hashes = HashMap::new();
-        let mut check_hashes = HashMap::new();
+        let mut ctx = SyncContext::new("node-1", ta1_client.clone());
 
-        let (applied, check_applied) = execute("node-1", &ta1_client, update_services(services.clone(), &svc_hashes, false), &mut svc_hashes, Default::default(), &mut check_hashes).await?;
+        let (applied, check_applied) = apply(&mut ctx, services.clone(), HashMap::new()).await?;
 
         assert!(check_applied.is_zero());
 
         assert_eq!(applied.upserted, 1);
         assert_eq!(applied.deleted, 0);
+        assert!(applied.statements > 0);
+        assert!(applied.finished_at >= applied.started_at);
 
-        let svc_hash = hash_service(&svc);
+        let svc_hash = hash_service(&svc, None, &StaticColumns::default());
 
-        assert_eq!(svc_hashes.get("service-id"), Some(&svc_hash));
+        assert_eq!(ctx.service_hashes.get("service-id"), Some(&svc_hash));
 
         {
             let conn = ta1_client.pool().get().await?;
@@ -658,41 +2560,42 @@ mod tests {
             assert_eq!(svc_hash, hash);
         }
 
-        let (applied, _check_applied) = execute("node-1", &ta1_client, update_services(services, &svc_hashes, false), &mut svc_hashes, Default::default(), &mut check_hashes).await?;
+        let (applied, _check_applied) = apply(&mut ctx, services, HashMap::new()).await?;
 
         assert!(check_applied.is_zero());
 
         assert_eq!(applied.upserted, 0);
         assert_eq!(applied.deleted, 0);
 
-        assert_eq!(svc_hashes.get("service-id"), Some(&hash_service(&svc)));
+        assert_eq!(ctx.service_hashes.get("service-id"), Some(&hash_service(&svc, None, &StaticColumns::default())));
 
         let ta2_client = CorrosionClient::new(ta2.agent.api_addr(), ta2.agent.db_path());
 
         setup(
             &ta2_client,
+            &StaticColumns::default(),
+            Duration::ZERO,
         )
         .await?;
 
-        sleep(Duration::from_secs(2)).await;
-
-        {
-            let conn = ta2_client.pool().get().await?;
-            let app_id: i64 =
-                conn.query_row("SELECT app_id FROM consul_services LIMIT 1", (), |row| {
-                    row.get(0)
-                })?;
-            assert_eq!(app_id, 123);
-        }
+        let ta2 = &ta2_client;
+        eventually(|| async move {
+            let conn = ta2.pool().get().await?;
+            let app_id: Option<i64> = conn
+                .query_row("SELECT app_id FROM consul_services LIMIT 1", (), |row| row.get(0))
+                .optional()?;
+            Ok(app_id == Some(123))
+        })
+        .await?;
 
-        let (applied, _check_applied) = execute("node-1", &ta1_client, update_services(HashMap::new(), &svc_hashes, false), &mut svc_hashes, Default::default(), &mut check_hashes).await?;
+        let (applied, _check_applied) = apply(&mut ctx, HashMap::new(), HashMap::new()).await?;
 
         assert!(check_applied.is_zero());
 
         assert_eq!(applied.upserted, 0);
         assert_eq!(applied.deleted, 1);
 
-        assert_eq!(svc_hashes.get("service-id"), None);
+        assert_eq!(ctx.service_hashes.get("service-id"), None);
 
         {
             let conn = ta1_client.pool().get().await?;
@@ -707,18 +2610,1094 @@ mod tests {
             assert_eq!(hash_bytes, None);
         }
 
-        sleep(Duration::from_secs(1)).await;
-
-        {
-            let conn = ta2_client.pool().get().await?;
+        eventually(|| async move {
+            let conn = ta2.pool().get().await?;
             let app_id: Option<i64> = conn
-                .query_row("SELECT app_id FROM consul_services LIMIT 1", (), |row| {
-                    row.get(0)
-                })
+                .query_row("SELECT app_id FROM consul_services LIMIT 1", (), |row| row.get(0))
                 .optional()?;
-            assert_eq!(app_id, None);
+            Ok(app_id.is_none())
+        })
+        .await?;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    // polls `check` until it holds, instead of sleeping for a fixed time
+    async fn eventually<F, Fut>(mut check: F) -> eyre::Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = eyre::Result<bool>>,
+    {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if check().await? {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                eyre::bail!("condition still didn't hold after 10s");
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // counts `increment_counter!` calls by name and labels. It's installed for
+    // the whole test binary, tests running concurrently share it: compare
+    // counts before and after instead of asserting absolute values.
+    #[derive(Default)]
+    struct CountingRecorder(Mutex<HashMap<String, Arc<AtomicU64>>>);
+
+    fn counter_key(name: &str, mut labels: Vec<(&str, &str)>) -> String {
+        labels.sort();
+        let labels = labels.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>();
+        format!("{name}{{{}}}", labels.join(","))
+    }
+
+    impl Recorder for CountingRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key) -> Counter {
+            let key = counter_key(key.name(), key.labels().map(|l| (l.key(), l.value())).collect());
+            Counter::from_arc(self.0.lock().unwrap().entry(key).or_default().clone())
+        }
+
+        fn register_gauge(&self, _: &Key) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    static COUNTERS: Lazy<&'static CountingRecorder> = Lazy::new(|| {
+        let recorder: &'static CountingRecorder = Box::leak(Box::default());
+        metrics::set_recorder(recorder).expect("no other metrics recorder installed in tests");
+        recorder
+    });
+
+    fn counter(name: &str, labels: &[(&str, &str)]) -> u64 {
+        COUNTERS.0.lock().unwrap().get(&counter_key(name, labels.to_vec())).map_or(0, |c| c.load(Ordering::SeqCst))
+    }
+
+    // never reached: the tests using it fail before applying anything
+    fn unreachable_corrosion() -> CorrosionClient {
+        CorrosionClient::new("127.0.0.1:1".parse().unwrap(), "/nonexistent/corrosion.db")
+    }
+
+    async fn update_from(consul: &FaultyConsul) -> eyre::Result<(ApplyStats, ApplyStats)> {
+        update_consul(consul, &mut SyncContext::new("node-1", unreachable_corrosion()), false).await
+    }
+
+    // applies what changed in `services` and `checks` since `ctx`'s hashes
+    async fn apply(ctx: &mut SyncContext, services: HashMap<String, AgentService>, checks: HashMap<String, AgentCheck>) -> eyre::Result<(ApplyStats, ApplyStats)> {
+        let svcs = update_services(services, None, &ctx.static_columns, &ctx.service_hashes, &ctx.forced_services, false).await?;
+        let checks = update_checks(checks, &HashSet::new(), &ctx.static_columns, &ctx.check_hashes, &ctx.forced_checks, false).await?;
+        execute(ctx, svcs, checks).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_slow_consul() -> eyre::Result<()> {
+        let labels = [("error", "timed out"), ("type", "services")];
+        let before = counter("corro_consul.consul.response.errors", &labels);
+
+        let consul = FaultyConsul::default();
+        consul.push_services(Reply::ok(HashMap::new()).delayed(CONSUL_REQUEST_TIMEOUT * 2));
+
+        let start = tokio::time::Instant::now();
+        let err = update_from(&consul).await.unwrap_err();
+        assert!(err.downcast_ref::<tokio::time::error::Elapsed>().is_some(), "{err}");
+        assert!(start.elapsed() >= CONSUL_REQUEST_TIMEOUT && start.elapsed() < CONSUL_REQUEST_TIMEOUT * 2);
+
+        assert_eq!(counter("corro_consul.consul.response.errors", &labels), before + 1);
+
+        // consul answering in time gets through, w/ nothing to apply
+        consul.push_services(Reply::ok(HashMap::new()).delayed(CONSUL_REQUEST_TIMEOUT / 2));
+        let (svc_stats, check_stats) = update_from(&consul).await?;
+        assert!(svc_stats.is_zero() && check_stats.is_zero());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_consul_errors() -> eyre::Result<()> {
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        let error = consul_client::Error::BadStatusCode(status).to_string();
+        let labels = [("error", error.as_str()), ("type", "checks")];
+        let before = counter("corro_consul.consul.response.errors", &labels);
+
+        let consul = FaultyConsul::default();
+        consul.push_checks(Reply::err(status));
+        consul.push_checks(Reply::err(status));
+
+        for _ in 0..2 {
+            assert!(update_from(&consul).await.is_err());
+        }
+        assert_eq!(consul.calls().1, 2);
+        assert_eq!(counter("corro_consul.consul.response.errors", &labels), before + 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sync_loop_retries_and_stops_on_shutdown() -> eyre::Result<()> {
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::tempdir()?;
+        let (config, _) = consul_config(&tmpdir, "client.address = \"127.0.0.1:1\"\npull-interval-ms = 10000")?;
+
+        // every pull fails, w/ consul taking a while to answer
+        let consul = Arc::new(FaultyConsul::default());
+        for _ in 0..10 {
+            consul.push_services(Reply::err(StatusCode::INTERNAL_SERVER_ERROR).delayed(Duration::from_millis(100)));
+        }
+
+        let (config_tx, config_rx) = watch::channel(config.clone());
+        let handle = tokio::spawn(sync_loop(consul.clone(), SyncContext::new("node-1", unreachable_corrosion()), config.clone(), config_rx, tripwire));
+
+        // the first pull is right away, failures are retried on the next ones
+        sleep(Duration::from_millis(1)).await;
+        assert_eq!(consul.calls().0, 1);
+        sleep(Duration::from_secs(25)).await;
+        assert_eq!(consul.calls().0, 3);
+
+        // changing the synced services reconciles right away
+        config_tx.send(ConsulConfig { services: vec!["web".into()], ..config.clone() })?;
+        sleep(Duration::from_millis(1)).await;
+        assert_eq!(consul.calls().0, 4);
+
+        // shutting down mid-pull lets it finish, then stops the loop
+        sleep(Duration::from_secs(10)).await;
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        timeout(Duration::from_secs(1), handle).await??;
+        assert_eq!(consul.calls().0, 5);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn warns_about_orphans_when_the_node_name_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        assert_eq!(record_node_name(&client, "old-node").await?, None);
+
+        let services = HashMap::from([("service-id".to_string(), AgentService { id: "service-id".into(), name: "service-name".into(), tags: vec![], meta: Default::default(), port: 1337, address: "127.0.0.1".into() })]);
+        let checks = HashMap::from([("check-id".to_string(), AgentCheck { id: "check-id".into(), name: "check-name".into(), status: consul_client::ConsulCheckStatus::Passing, output: "ok".into(), service_id: "service-id".into(), service_name: "service-name".into(), notes: None })]);
+        apply(&mut SyncContext::new("old-node", client.clone()), services, checks).await?;
+
+        // same name, nothing's orphaned
+        assert_eq!(record_node_name(&client, "old-node").await?, None);
+
+        assert_eq!(record_node_name(&client, "new-node").await?, Some(("old-node".to_string(), 2)));
+        assert_eq!(record_node_name(&client, "new-node").await?, None);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn runs_repeatedly_in_process() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        for node in ["node-1", "node-2"] {
+            let path = Utf8PathBuf::try_from(tmpdir.path().join(format!("{node}.toml")))?;
+            // consul is unreachable, pulls fail and get retried
+            write_config(&path, &format!("client.address = \"127.0.0.1:1\"\nnode-name = \"{node}\""));
+            let config = Config::load(path.as_str())?;
+
+            let (run_tripwire, run_tripwire_worker, run_tripwire_tx) = Tripwire::new_simple();
+            let (api_addr, db_path) = (ta.agent.api_addr(), ta.agent.db_path());
+            let handle = tokio::spawn(async move { run_with_tripwire(&config, &path, api_addr, db_path, None, run_tripwire).await });
+
+            let client = &client;
+            eventually(|| async move {
+                let conn = client.pool().get().await?;
+                let name: Option<String> = conn.query_row("SELECT name FROM __corro_consul_node WHERE id = 1", [], |row| row.get(0)).optional()?;
+                Ok(name.as_deref() == Some(node))
+            })
+            .await?;
+
+            // stopping one run leaves the agent and later runs alone
+            run_tripwire_tx.send(()).await.ok();
+            run_tripwire_worker.await;
+            timeout(Duration::from_secs(5), handle).await???;
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn aggregates_service_statuses() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema = String::from_utf8(CONSUL_SCHEMA.to_vec())?.replacen("address TEXT NOT NULL DEFAULT '',", "address TEXT NOT NULL DEFAULT '',\n                status TEXT NOT NULL DEFAULT '',", 1);
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), schema).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        assert!(setup(&client, &StaticColumns::default(), Duration::ZERO).await?.service_status);
+
+        let service = |id: &str| AgentService { id: id.into(), name: id.into(), tags: vec![], meta: Default::default(), port: 1337, address: "127.0.0.1".into() };
+        let services = || HashMap::from([("web".to_string(), service("web")), ("api".to_string(), service("api")), ("db".to_string(), service("db"))]);
+        let check = |id: &str, service_id: &str, status| AgentCheck { id: id.into(), name: id.into(), status, output: "".into(), service_id: service_id.into(), service_name: service_id.into(), notes: None };
+
+        let consul = FaultyConsul::default();
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(HashMap::from([
+            ("web-check".to_string(), check("web-check", "web", ConsulCheckStatus::Passing)),
+            ("api-check".to_string(), check("api-check", "api", ConsulCheckStatus::Warning)),
+        ])));
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(HashMap::from([
+            ("web-check".to_string(), check("web-check", "web", ConsulCheckStatus::Critical)),
+            ("api-check".to_string(), check("api-check", "api", ConsulCheckStatus::Warning)),
+        ])));
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(HashMap::from([
+            ("web-check".to_string(), check("web-check", "web", ConsulCheckStatus::Critical)),
+            ("api-check".to_string(), check("api-check", "api", ConsulCheckStatus::Warning)),
+            ("serf".to_string(), check("serf", "", ConsulCheckStatus::Warning)),
+        ])));
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.service_status = Some(ServiceStatusConfig { include_node_checks: false });
+        let client = &client;
+        let statuses = || async move {
+            let conn = client.pool().get().await?;
+            let statuses = conn
+                .prepare("SELECT id, status, updated_at FROM consul_services ORDER BY id")?
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, eyre::Report>(statuses)
+        };
+
+        let (svc_applied, _) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!(svc_applied.upserted, 3);
+        let before = statuses().await?;
+        assert_eq!(before.iter().map(|(id, status, _)| (id.as_str(), status.as_str())).collect::<Vec<_>>(), vec![("api", "warning"), ("db", "passing"), ("web", "passing")]);
+
+        // only the owning service is updated
+        let (svc_applied, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((svc_applied.upserted, check_applied.upserted), (1, 1));
+        let after = statuses().await?;
+        assert_eq!(after[..2], before[..2]);
+        assert_eq!(after[2].1, "critical");
+        assert!(after[2].2 >= before[2].2);
+
+        // a warning node check degrades every passing service
+        ctx.service_status = Some(ServiceStatusConfig { include_node_checks: true });
+        let (svc_applied, _) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!(svc_applied.upserted, 1);
+        let after = statuses().await?;
+        assert_eq!(after.iter().map(|(id, status, _)| (id.as_str(), status.as_str())).collect::<Vec<_>>(), vec![("api", "warning"), ("db", "warning"), ("web", "critical")]);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn synthesizes_missing_checks() {
+        let service = |id: &str| (id.to_string(), AgentService { id: id.into(), name: format!("{id}-name"), tags: vec![], meta: Default::default(), port: 1337, address: "127.0.0.1".into() });
+        let check = |id: &str, service_id: &str| (id.to_string(), AgentCheck { id: id.into(), name: "real".into(), status: ConsulCheckStatus::Critical, output: "".into(), service_id: service_id.into(), service_name: format!("{service_id}-name"), notes: None });
+
+        let services = HashMap::from([service("checked"), service("unchecked"), service("taken")]);
+        // a real check for another service already has the synthetic id
+        let mut checks = HashMap::from([check("checked-check", "checked"), check("svc:taken:synthetic", "checked"), check("node-check", "")]);
+
+        let synthetic = synthesize_checks(&services, &mut checks);
+        assert_eq!(synthetic, HashSet::from(["svc:unchecked:synthetic".to_string()]));
+        assert_eq!(checks.len(), 4);
+
+        let synthesized = &checks["svc:unchecked:synthetic"];
+        assert_eq!(synthesized.name, "registered");
+        assert_eq!(synthesized.status, ConsulCheckStatus::Passing);
+        assert_eq!(synthesized.service_id, "unchecked");
+        assert_eq!(synthesized.service_name, "unchecked-name");
+        assert_eq!(checks["svc:taken:synthetic"].name, "real");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn syncs_synthetic_checks() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema = String::from_utf8(CONSUL_SCHEMA.to_vec())?
+            .replacen("output TEXT NOT NULL DEFAULT '',", "output TEXT NOT NULL DEFAULT '',\n                synthetic INTEGER NOT NULL DEFAULT 0,", 1);
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), schema).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        let tables = setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+        assert!(tables.synthetic_checks);
+
+        let services = || HashMap::from([("web".to_string(), AgentService { id: "web".into(), name: "web".into(), tags: vec![], meta: Default::default(), port: 1337, address: "127.0.0.1".into() })]);
+        let real_checks = || HashMap::from([("web-check".to_string(), AgentCheck { id: "web-check".into(), name: "web-check".into(), status: ConsulCheckStatus::Passing, output: "".into(), service_id: "web".into(), service_name: "web".into(), notes: None })]);
+
+        let consul = FaultyConsul::default();
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(HashMap::new()));
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(HashMap::new()));
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(real_checks()));
+        consul.push_services(Reply::ok(HashMap::new())).push_checks(Reply::ok(HashMap::new()));
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.synthesize_checks = true;
+        ctx.synthetic_column = tables.synthetic_checks;
+        let client = &client;
+        let checks = || async move {
+            let conn = client.pool().get().await?;
+            let checks = conn
+                .prepare("SELECT id, name, status, synthetic FROM consul_checks ORDER BY id")?
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, bool>(3)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, eyre::Report>(checks)
+        };
+
+        // the service has no checks
+        let (_, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((check_applied.upserted, check_applied.deleted), (1, 0));
+        assert_eq!(checks().await?, vec![("svc:web:synthetic".to_string(), "registered".to_string(), "passing".to_string(), true)]);
+
+        // hashed like any check
+        let (_, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert!(check_applied.is_zero());
+
+        // a real check replaces it
+        let (_, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((check_applied.upserted, check_applied.deleted), (1, 1));
+        assert_eq!(checks().await?, vec![("web-check".to_string(), "web-check".to_string(), "passing".to_string(), false)]);
+
+        let (_, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((check_applied.upserted, check_applied.deleted), (0, 1));
+        assert!(checks().await?.is_empty());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn writes_static_columns() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        // `region` is on both tables, `zone` only on consul_services
+        let schema = String::from_utf8(CONSUL_SCHEMA.to_vec())?
+            .replacen("address TEXT NOT NULL DEFAULT '',", "address TEXT NOT NULL DEFAULT '',\n                region TEXT NOT NULL DEFAULT '',\n                zone TEXT NOT NULL DEFAULT '',", 1)
+            .replacen("output TEXT NOT NULL DEFAULT '',", "output TEXT NOT NULL DEFAULT '',\n                region TEXT NOT NULL DEFAULT '',", 1);
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), schema).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        let static_columns = |name: &str, value: &str| StaticColumns::try_from(BTreeMap::from([(name.to_string(), value.to_string())]));
+
+        let err = setup(&client, &static_columns("zone", "a")?, Duration::ZERO).await.unwrap_err();
+        assert!(err.to_string().contains("consul_checks.zone"), "unexpected error: {err}");
+        setup(&client, &static_columns("region", "east")?, Duration::ZERO).await?;
+
+        let services = || HashMap::from([("web".to_string(), AgentService { id: "web".into(), name: "web".into(), tags: vec![], meta: Default::default(), port: 1337, address: "127.0.0.1".into() })]);
+        let checks = || HashMap::from([("web-check".to_string(), AgentCheck { id: "web-check".into(), name: "web-check".into(), status: ConsulCheckStatus::Passing, output: "".into(), service_id: "web".into(), service_name: "web".into(), notes: None })]);
+
+        let consul = FaultyConsul::default();
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(checks()));
+        consul.push_services(Reply::ok(services())).push_checks(Reply::ok(checks()));
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.static_columns = static_columns("region", "east")?;
+        let client = &client;
+        let regions = || async move {
+            let conn = client.pool().get().await?;
+            let regions = conn.query_row("SELECT (SELECT region FROM consul_services WHERE id = 'web'), (SELECT region FROM consul_checks WHERE id = 'web-check')", [], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            Ok::<_, eyre::Report>(regions)
+        };
+
+        let (svc_applied, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((svc_applied.upserted, check_applied.upserted), (1, 1));
+        assert_eq!(regions().await?, ("east".to_string(), "east".to_string()));
+
+        // a new value changes the hashes, so every row is rewritten once
+        ctx.static_columns = static_columns("region", "west")?;
+        let (svc_applied, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((svc_applied.upserted, check_applied.upserted), (1, 1));
+        assert_eq!(regions().await?, ("west".to_string(), "west".to_string()));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn normalizes_reregistered_ids() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        let ids = || IdNormalizer::new(&corro_types::config::IdNormalization { trim: true, nfc: true });
+        let services = |id: &str, port: u16| HashMap::from([(id.to_string(), AgentService { id: id.into(), name: "web".into(), tags: vec![], meta: Default::default(), port, address: "127.0.0.1".into() })]);
+        let checks = |service_id: &str| HashMap::from([("web-check".to_string(), AgentCheck { id: "web-check".into(), name: "web-check".into(), status: ConsulCheckStatus::Passing, output: "".into(), service_id: service_id.into(), service_name: "web".into(), notes: None })]);
+
+        let client = &client;
+        let stored = || async move {
+            let conn = client.pool().get().await?;
+            let services = conn.prepare("SELECT id, port FROM consul_services ORDER BY id")?
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u16>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let service_ids = conn.prepare("SELECT service_id FROM consul_checks ORDER BY id")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok::<_, eyre::Report>((services, service_ids))
+        };
+
+        // re-registered w/ a trailing space, it's still the same service
+        let consul = FaultyConsul::default();
+        consul.push_services(Reply::ok(services("web", 1111))).push_checks(Reply::ok(checks("web")));
+        consul.push_services(Reply::ok(services("web ", 1111))).push_checks(Reply::ok(checks("web ")));
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.ids = ids();
+        let (svc_applied, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((svc_applied.upserted, check_applied.upserted), (1, 1));
+        let (svc_applied, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert!(svc_applied.is_zero() && check_applied.is_zero());
+        assert_eq!(stored().await?, (vec![("web".into(), 1111)], vec!["web".into()]));
+
+        // both were synced before ids were normalized, the one updated last wins
+        client.execute_strict(&[
+            Statement::Simple("DELETE FROM consul_services;".into()),
+            Statement::Simple("INSERT INTO consul_services (node, id, name, port, updated_at) VALUES ('node-1', 'web', 'web', 1111, 1), ('node-1', 'web ', 'web', 2222, 2), ('node-2', 'db ', 'db', 3333, 1);".into()),
+        ]).await?;
+        client.pool().get().await?.execute_batch("DELETE FROM __corro_consul_services; INSERT INTO __corro_consul_services (id, hash) VALUES ('web', x'0000000000000001'), ('web ', x'0000000000000002');")?;
+
+        assert_eq!(merge_unnormalized_ids(client, "node-1", None, &ids(), false).await?, 1);
+        // other nodes' rows are left alone
+        let (services_stored, _) = stored().await?;
+        assert_eq!(services_stored, vec![("db ".into(), 3333), ("web".into(), 2222)]);
+        assert!(load_hashes(client, &bookkeeping::SERVICES, None, 100).await?.is_empty());
+        // nothing left to merge
+        assert_eq!(merge_unnormalized_ids(client, "node-1", None, &ids(), false).await?, 0);
+
+        // the hash was forgotten, consul's current service is written again
+        consul.push_services(Reply::ok(services("web ", 1111))).push_checks(Reply::ok(checks("web ")));
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.ids = ids();
+        ctx.check_hashes = load_hashes(client, &bookkeeping::CHECKS, None, 100).await?;
+        let (svc_applied, check_applied) = update_consul(&consul, &mut ctx, false).await?;
+        assert_eq!((svc_applied.upserted, svc_applied.deleted), (1, 0));
+        assert!(check_applied.is_zero());
+        assert_eq!(stored().await?, (vec![("db ".into(), 3333), ("web".into(), 1111)], vec!["web".into()]));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn keeps_datacenters_apart() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let schema = String::from_utf8(CONSUL_SCHEMA.to_vec())?
+            .replace("node TEXT NOT NULL,", "node TEXT NOT NULL,\n                datacenter TEXT NOT NULL DEFAULT '',")
+            .replace("PRIMARY KEY (node, id)", "PRIMARY KEY (node, datacenter, id)");
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), schema).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        let tables = setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+        assert!(tables.datacenter);
+
+        // synced before the datacenter column existed
+        client.execute_strict(&[Statement::Simple("INSERT INTO consul_services (node, id, name) VALUES ('node-1', 'web', 'web');".into())]).await?;
+        client.pool().get().await?.execute("INSERT INTO __corro_consul_services (id, hash) VALUES ('web', ?)", [0u64.to_be_bytes().to_vec()])?;
+
+        assert_eq!(backfill_datacenter(&client, "node-1", "dc1").await?, 1);
+        assert_eq!(load_hashes(&client, &bookkeeping::SERVICES, Some("dc1"), 100).await?, HashMap::from([("web".to_string(), 0)]));
+        assert!(load_hashes(&client, &bookkeeping::SERVICES, Some("dc2"), 100).await?.is_empty());
+        // only once
+        assert_eq!(backfill_datacenter(&client, "node-2", "dc2").await?, 0);
+
+        // both agents call themselves node-1 and register `web`
+        let services = |port: u16| HashMap::from([("web".to_string(), AgentService { id: "web".into(), name: "web".into(), tags: vec![], meta: Default::default(), port, address: "127.0.0.1".into() })]);
+        let dc1 = FaultyConsul::default();
+        dc1.push_services(Reply::ok(services(1111))).push_checks(Reply::ok(HashMap::new()));
+        dc1.push_services(Reply::ok(HashMap::new())).push_checks(Reply::ok(HashMap::new()));
+        let dc2 = FaultyConsul::default();
+        dc2.push_services(Reply::ok(services(2222))).push_checks(Reply::ok(HashMap::new()));
+
+        let mut ctx1 = SyncContext::new("node-1", client.clone());
+        ctx1.datacenter = Some("dc1".into());
+        ctx1.service_hashes = load_hashes(&client, &bookkeeping::SERVICES, Some("dc1"), 100).await?;
+        let mut ctx2 = SyncContext::new("node-1", client.clone());
+        ctx2.datacenter = Some("dc2".into());
+
+        assert_eq!(update_consul(&dc1, &mut ctx1, false).await?.0.upserted, 1);
+        assert_eq!(update_consul(&dc2, &mut ctx2, false).await?.0.upserted, 1);
+
+        let client = &client;
+        let stored = || async move {
+            let conn = client.pool().get().await?;
+            let rows = conn.prepare("SELECT datacenter, port FROM consul_services WHERE node = 'node-1' AND id = 'web' ORDER BY datacenter")?
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u16>(1)?)))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let ids = conn.prepare("SELECT id FROM __corro_consul_services ORDER BY id")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok::<_, eyre::Report>((rows, ids))
+        };
+        assert_eq!(stored().await?, (vec![("dc1".into(), 1111), ("dc2".into(), 2222)], vec!["dc1/web".into(), "dc2/web".into()]));
+
+        // deregistering from dc1 leaves dc2's service alone
+        assert_eq!(update_consul(&dc1, &mut ctx1, false).await?.0.deleted, 1);
+        assert_eq!(stored().await?, (vec![("dc2".into(), 2222)], vec!["dc2/web".into()]));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn truncates_outputs_on_char_boundaries() {
+        assert_eq!(stored_output("short", Some(5)), Cow::Borrowed("short"));
+        assert_eq!(stored_output("longer", None), Cow::Borrowed("longer"));
+        assert_eq!(stored_output("longer", Some(4)), format!("long{TRUNCATED_OUTPUT_MARKER}"));
+
+        // 'é' is 2 bytes, 🦀 is 4: the limit falls in the middle of both
+        assert_eq!(stored_output("aé", Some(2)), format!("a{TRUNCATED_OUTPUT_MARKER}"));
+        assert_eq!(stored_output("ab🦀c", Some(4)), format!("ab{TRUNCATED_OUTPUT_MARKER}"));
+        assert_eq!(stored_output("🦀", Some(3)), TRUNCATED_OUTPUT_MARKER);
+        assert_eq!(stored_output("ab🦀c", Some(6)), format!("ab🦀{TRUNCATED_OUTPUT_MARKER}"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn syncs_output_changes_past_truncation() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        // the output is part of the hash
+        let check = |output: &str| HashMap::from([("web-check".to_string(), AgentCheck { id: "web-check".into(), name: "web-check".into(), status: ConsulCheckStatus::Critical, output: output.into(), service_id: "web".into(), service_name: "web".into(), notes: Some(r#"{"hash_include":["status","output"]}"#.into()) })]);
+        let client = &client;
+        let output = || async move {
+            let conn = client.pool().get().await?;
+            let output: String = conn.query_row("SELECT output FROM consul_checks WHERE id = 'web-check'", [], |row| row.get(0))?;
+            Ok::<_, eyre::Report>(output)
+        };
+        let truncated = counter("corro_consul.check.output.truncated", &[]);
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.max_output_bytes = Some(17);
+
+        // a multibyte char straddles the limit
+        let body = format!("HTTP 500: {}", "é".repeat(100));
+        let (_, applied) = apply(&mut ctx, HashMap::new(), check(&format!("{body} at 1"))).await?;
+        assert_eq!(applied.upserted, 1);
+        assert_eq!(output().await?, format!("HTTP 500: ééé{TRUNCATED_OUTPUT_MARKER}"));
+        assert!(counter("corro_consul.check.output.truncated", &[]) > truncated);
+
+        // the same output isn't written again
+        let (_, applied) = apply(&mut ctx, HashMap::new(), check(&format!("{body} at 1"))).await?;
+        assert_eq!(applied.upserted, 0);
+
+        // a change past the limit is, even though it's stored the same
+        let (_, applied) = apply(&mut ctx, HashMap::new(), check(&format!("{body} at 2"))).await?;
+        assert_eq!(applied.upserted, 1);
+        assert_eq!(output().await?, format!("HTTP 500: ééé{TRUNCATED_OUTPUT_MARKER}"));
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn updated_at_is_monotonic() {
+        assert_eq!(monotonic_updated_at(100, 50), (100, None));
+        assert_eq!(monotonic_updated_at(100, 100), (101, Some(0)));
+        assert_eq!(monotonic_updated_at(100, 5_000), (5_001, Some(4_900)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn updated_at_survives_clock_steps_back() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let regressions = counter("corro_consul.clock.regressions", &[]);
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        let service = |port| HashMap::from([("web".to_string(), AgentService { id: "web".into(), name: "web".into(), tags: vec![], meta: Default::default(), port, address: "127.0.0.1".into() })]);
+        let client = &client;
+        let updated_at = || async move {
+            let conn = client.pool().get().await?;
+            let updated_at: i64 = conn.query_row("SELECT updated_at FROM consul_services WHERE id = 'web'", [], |row| row.get(0))?;
+            Ok::<_, eyre::Report>(updated_at)
+        };
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        apply(&mut ctx, service(1), HashMap::new()).await?;
+
+        // the row was written an hour ahead of the current clock, i.e. the
+        // clock has since stepped back an hour
+        let ahead = updated_at().await? + 3_600_000;
+        client.execute(&[Statement::WithParams("UPDATE consul_services SET updated_at = ?".into(), vec![ahead.into()])]).await?;
+
+        // after a restart, the high-water mark is backfilled from the rows
+        assert_eq!(load_last_updated_at(client, "node-1").await?, ahead);
+        let mut restarted = SyncContext::new("node-1", client.clone());
+        restarted.service_hashes = ctx.service_hashes.clone();
+        restarted.last_updated_at = load_last_updated_at(client, "node-1").await?;
+
+        let (applied, _) = apply(&mut restarted, service(2), HashMap::new()).await?;
+        assert_eq!(applied.upserted, 1);
+        assert_eq!(updated_at().await?, ahead + 1);
+        assert_eq!(restarted.last_updated_at, ahead + 1);
+        assert!(counter("corro_consul.clock.regressions", &[]) > regressions);
+
+        // and recorded
+        {
+            let conn = client.pool().get().await?;
+            let recorded: i64 = conn.query_row("SELECT updated_at FROM __corro_consul_clock WHERE id = 1", [], |row| row.get(0))?;
+            assert_eq!(recorded, ahead + 1);
+        }
+
+        // the upsert itself doesn't go back either, w/o any high-water mark
+        ctx.service_hashes = restarted.service_hashes.clone();
+        ctx.last_updated_at = 0;
+        apply(&mut ctx, service(3), HashMap::new()).await?;
+        assert_eq!(updated_at().await?, ahead + 1);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn maintain_hashes_shrinks() {
+        let mut hashes = HashMap::with_capacity(1000);
+        hashes.insert("a".to_string(), 1);
+
+        maintain_hashes("services", &mut hashes, 1);
+        assert!(hashes.capacity() < 1000);
+        assert_eq!(hashes.get("a"), Some(&1));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn prunes_stale_ids_on_first_pass() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        let service = |id: &str| AgentService {
+            id: id.into(),
+            name: "service-name".into(),
+            tags: vec![],
+            meta: Default::default(),
+            port: 1337,
+            address: "127.0.0.1".into(),
+        };
+        let check = |id: &str| AgentCheck {
+            id: id.into(),
+            name: "check-name".into(),
+            status: consul_client::ConsulCheckStatus::Passing,
+            output: "".into(),
+            service_id: "live".into(),
+            service_name: "service-name".into(),
+            notes: None,
+        };
+
+        let mut services = HashMap::from([("live".to_string(), service("live")), ("stale".to_string(), service("stale"))]);
+        let mut checks = HashMap::from([("live".to_string(), check("live")), ("stale".to_string(), check("stale"))]);
+
+        // a previous run of the sync recorded everything
+        apply(&mut SyncContext::new("node-1", client.clone()), services.clone(), checks.clone()).await?;
+
+        // "stale" was deregistered while the sync wasn't running
+        services.remove("stale");
+        checks.remove("stale");
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.service_hashes = load_hashes(&client, &bookkeeping::SERVICES, None, 100).await?;
+        ctx.check_hashes = load_hashes(&client, &bookkeeping::CHECKS, None, 100).await?;
+        assert_eq!(ctx.service_hashes.len(), 2);
+        assert_eq!(ctx.check_hashes.len(), 2);
+
+        let (svc_applied, check_applied) = apply(&mut ctx, services, checks).await?;
+
+        assert_eq!((svc_applied.upserted, svc_applied.deleted), (0, 1));
+        assert_eq!((check_applied.upserted, check_applied.deleted), (0, 1));
+        assert_eq!(ctx.service_hashes.keys().collect::<Vec<_>>(), vec!["live"]);
+        assert_eq!(ctx.check_hashes.keys().collect::<Vec<_>>(), vec!["live"]);
+
+        {
+            let conn = client.pool().get().await?;
+            for table in ["consul_services", "__corro_consul_services", "consul_checks", "__corro_consul_checks"] {
+                let ids = conn
+                    .prepare(&format!("SELECT id FROM {table}"))?
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                assert_eq!(ids, vec!["live"], "unexpected ids in {table}");
+            }
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn detects_restarts_after_failures() {
+        let mut restarts = RestartDetector::default();
+        // never saw the agent start
+        assert!(!restarts.restarted(1000));
+
+        restarts.seen(1000);
+        assert!(!restarts.is_failing());
+        assert!(!restarts.restarted(1000));
+        // the agent didn't say
+        assert!(!restarts.restarted(0));
+
+        restarts.failed();
+        assert!(restarts.is_failing());
+        assert!(restarts.restarted(2000));
+        restarts.recovered(2000);
+        assert!(!restarts.is_failing());
+        assert!(!restarts.restarted(2000));
+
+        // an agent which doesn't say when it started doesn't forget the last one
+        restarts.recovered(0);
+        assert!(restarts.restarted(3000));
+    }
+
+    // `count` services w/ meta maps of `meta` entries, the size of big
+    // inventories
+    fn synthetic_services(count: usize, meta: usize) -> HashMap<String, AgentService> {
+        (0..count).map(|i| {
+            let id = format!("svc-{i:05}");
+            let meta = (0..meta).map(|j| (format!("key-{j}"), format!("value-{i}-{j}"))).collect();
+            (id.clone(), AgentService { id, name: "service-name".into(), tags: vec!["a".into(), "b".into()], meta, port: 1337, address: "127.0.0.1".into() })
+        }).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn hashes_in_parallel_above_threshold() -> eyre::Result<()> {
+        let static_columns = StaticColumns::default();
+        let services: Vec<AgentService> = synthetic_services(100, 4).into_values().collect();
+        let hash = |svc: &AgentService| hash_service(svc, None, &StaticColumns::default());
+
+        let serial = hash_all(services.clone(), hash, usize::MAX).await?;
+        let parallel = hash_all(services.clone(), hash, 1).await?;
+        assert_eq!(serial.iter().map(|(svc, h)| (svc.id.as_str(), *h)).collect::<Vec<_>>(), parallel.iter().map(|(svc, h)| (svc.id.as_str(), *h)).collect::<Vec<_>>());
+        // in the order they were passed in
+        assert!(parallel.iter().map(|(svc, _)| &svc.id).eq(services.iter().map(|svc| &svc.id)));
+        assert!(parallel.iter().all(|(svc, h)| *h == hash_service(svc, None, &static_columns)));
+
+        assert!(hash_all(Vec::<AgentService>::new(), hash, 0).await?.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn orders_ops_by_id() -> eyre::Result<()> {
+        let services = synthetic_services(PARALLEL_HASH_THRESHOLD + 10, 1);
+        let mut hashes: HashMap<String, u64> = services.keys().map(|id| (id.clone(), 0)).collect();
+        hashes.insert("deleted".into(), 0);
+
+        let ops = update_services(services, None, &StaticColumns::default(), &hashes, &HashSet::new(), false).await?;
+        assert_eq!(ops.len(), PARALLEL_HASH_THRESHOLD + 11);
+        assert!(ops.windows(2).all(|pair| pair[0].id() < pair[1].id()));
+        assert!(matches!(&ops[0], ConsulServiceOp::Delete { id } if id == "deleted"));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run w/ --ignored --nocapture"]
+    async fn bench_hashing_10k_services() -> eyre::Result<()> {
+        let services: Vec<AgentService> = synthetic_services(10_000, 64).into_values().collect();
+        let hash = |svc: &AgentService| hash_service(svc, None, &StaticColumns::default());
+
+        for (name, threshold) in [("serial", usize::MAX), ("parallel", PARALLEL_HASH_THRESHOLD)] {
+            let start = Instant::now();
+            let hashed = hash_all(services.clone(), hash, threshold).await?;
+            println!("{name}: hashed {} services in {:?}", hashed.len(), start.elapsed());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn forces_mismatched_hashes() {
+        let mut hashes = HashMap::from([("same".to_string(), 1), ("changed".to_string(), 2), ("missing".to_string(), 3)]);
+        let stored = HashMap::from([("same".to_string(), 1), ("changed".to_string(), 20), ("stored-only".to_string(), 4)]);
+        let mut forced = HashSet::new();
+
+        assert_eq!(force_mismatched(&mut hashes, stored, &mut forced), 3);
+        assert_eq!(forced, HashSet::from(["changed".to_string(), "missing".to_string(), "stored-only".to_string()]));
+        // in-memory hashes win, ids only stored are tracked
+        assert_eq!(hashes, HashMap::from([("same".to_string(), 1), ("changed".to_string(), 2), ("missing".to_string(), 3), ("stored-only".to_string(), 4)]));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn reconciles_restored_backup() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        tokio::fs::write(tmpdir.path().join("consul.sql"), CONSUL_SCHEMA).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+
+        let service = |id: &str, port: u16| AgentService {
+            id: id.into(),
+            name: "service-name".into(),
+            tags: vec![],
+            meta: Default::default(),
+            port,
+            address: "127.0.0.1".into(),
+        };
+        let check = |id: &str, status: ConsulCheckStatus| AgentCheck {
+            id: id.into(),
+            name: "check-name".into(),
+            status,
+            output: "".into(),
+            service_id: id.into(),
+            service_name: "service-name".into(),
+            notes: None,
+        };
+
+        let services = HashMap::from([
+            ("unchanged".to_string(), service("unchanged", 1)),
+            ("reverted".to_string(), service("reverted", 2)),
+            ("lost".to_string(), service("lost", 3)),
+        ]);
+        let checks = HashMap::from([("reverted".to_string(), check("reverted", ConsulCheckStatus::Passing))]);
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        apply(&mut ctx, services.clone(), checks.clone()).await?;
+
+        // the agent is restored from a backup taken before: "reverted" had an
+        // older port and status, "lost" wasn't registered yet and "gone" was
+        // since deregistered
+        client.execute(&[
+            Statement::Simple("UPDATE consul_services SET port = 20 WHERE id = 'reverted'".into()),
+            Statement::Simple("UPDATE consul_checks SET status = 'critical' WHERE id = 'reverted'".into()),
+            Statement::Simple("DELETE FROM consul_services WHERE id = 'lost'".into()),
+            Statement::Simple("INSERT INTO consul_services (node, id, name, port) VALUES ('node-1', 'gone', 'service-name', 4)".into()),
+        ]).await?;
+        {
+            let conn = client.pool().get().await?;
+            let restored = hash_service(&service("reverted", 20), None, &ctx.static_columns);
+            conn.execute("UPDATE __corro_consul_services SET hash = ? WHERE id = 'reverted'", [restored.to_be_bytes().to_vec()])?;
+            let restored = hash_check(&check("reverted", ConsulCheckStatus::Critical), &ctx.static_columns);
+            conn.execute("UPDATE __corro_consul_checks SET hash = ? WHERE id = 'reverted'", [restored.to_be_bytes().to_vec()])?;
+            conn.execute("DELETE FROM __corro_consul_services WHERE id = 'lost'", [])?;
+            conn.execute("INSERT INTO __corro_consul_services (id, hash) VALUES ('gone', ?)", [4u64.to_be_bytes().to_vec()])?;
         }
 
+        // consul didn't change, so nothing gets written w/o reconciling
+        assert_eq!(apply(&mut ctx, services.clone(), checks.clone()).await?.0.upserted, 0);
+
+        assert_eq!(reconcile_hashes(&mut ctx, 100).await?, 4);
+        let (svc_applied, check_applied) = apply(&mut ctx, services.clone(), checks.clone()).await?;
+        assert_eq!((svc_applied.upserted, svc_applied.deleted), (2, 1));
+        assert_eq!((check_applied.upserted, check_applied.deleted), (1, 0));
+        assert!(ctx.forced_services.is_empty());
+        assert!(ctx.forced_checks.is_empty());
+
+        {
+            let conn = client.pool().get().await?;
+            let ports = conn
+                .prepare("SELECT id, port FROM consul_services ORDER BY id")?
+                .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, u16>(1)?)))?
+                .collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(ports, vec![("lost".to_string(), 3), ("reverted".to_string(), 2), ("unchanged".to_string(), 1)]);
+
+            let status: String = conn.query_row("SELECT status FROM consul_checks WHERE id = 'reverted'", [], |row| row.get(0))?;
+            assert_eq!(status, "passing");
+        }
+        assert_eq!(load_hashes(&client, &bookkeeping::SERVICES, None, 100).await?, ctx.service_hashes);
+        assert_eq!(load_hashes(&client, &bookkeeping::CHECKS, None, 100).await?, ctx.check_hashes);
+
+        // converged: nothing left to write
+        assert_eq!(reconcile_hashes(&mut ctx, 100).await?, 0);
+        let (svc_applied, check_applied) = apply(&mut ctx, services, checks).await?;
+        assert!(svc_applied.is_zero() && check_applied.is_zero());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[test]
+    fn diffs_service_tags() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<BTreeSet<_>>();
+        let diff = |old: &[&str], new: &[&str]| {
+            let mut statements = vec![];
+            append_service_tags_statements(&mut statements, "node-1", "web", &tags(old), &tags(new));
+            statements
+                .into_iter()
+                .map(|stmt| match stmt {
+                    Statement::WithParams(query, params) => match params.last() {
+                        Some(SqliteParam::Text(tag)) => (query.split_whitespace().next().unwrap().to_owned(), tag.to_string()),
+                        param => panic!("unexpected last param {param:?}"),
+                    },
+                    stmt => panic!("unexpected statement {stmt:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert!(diff(&["a", "b"], &["b", "a"]).is_empty());
+        assert_eq!(diff(&["a"], &["a", "b"]), vec![("INSERT".to_owned(), "b".to_owned())]);
+        assert_eq!(diff(&["a", "b"], &["b", "c"]), vec![("DELETE".to_owned(), "a".to_owned()), ("INSERT".to_owned(), "c".to_owned())]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn maintains_service_tags() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+        let tmpdir = tempfile::TempDir::new()?;
+        let mut schema = CONSUL_SCHEMA.to_vec();
+        schema.extend_from_slice(b"
+            CREATE TABLE consul_service_tags (
+                node TEXT NOT NULL,
+                service_id TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (node, service_id, tag)
+            );
+        ");
+        tokio::fs::write(tmpdir.path().join("consul.sql"), schema).await?;
+
+        let ta = launch_test_agent(|conf| conf.add_schema_path(tmpdir.path().display().to_string()).build(), tripwire.clone()).await?;
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+
+        let tables = setup(&client, &StaticColumns::default(), Duration::ZERO).await?;
+        assert_eq!(tables, ConsulTables { service_status: false, service_tags: true, datacenter: false, synthetic_checks: false });
+
+        let service = |tags: &[&str], port: u16| AgentService {
+            id: "web".into(),
+            name: "web".into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            meta: Default::default(),
+            port,
+            address: "127.0.0.1".into(),
+        };
+        let stored_tags = || async {
+            let conn = client.pool().get().await?;
+            let tags = conn
+                .prepare("SELECT tag FROM consul_service_tags WHERE node = 'node-1' AND service_id = 'web' ORDER BY tag")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok::<_, eyre::Report>(tags)
+        };
+
+        let mut ctx = SyncContext::new("node-1", client.clone());
+        ctx.service_tags = Some(load_service_tags(&client, "node-1").await?);
+
+        // 2 bookkeeping and data statements, 1 per tag
+        let (svc_applied, _) = apply(&mut ctx, HashMap::from([("web".to_string(), service(&["a", "b"], 1337))]), HashMap::new()).await?;
+        assert_eq!(svc_applied.statements, 4);
+        assert_eq!(stored_tags().await?, vec!["a", "b"]);
+
+        // tag added
+        let (svc_applied, _) = apply(&mut ctx, HashMap::from([("web".to_string(), service(&["a", "b", "c"], 1337))]), HashMap::new()).await?;
+        assert_eq!(svc_applied.statements, 3);
+        assert_eq!(stored_tags().await?, vec!["a", "b", "c"]);
+
+        // tag removed
+        let (svc_applied, _) = apply(&mut ctx, HashMap::from([("web".to_string(), service(&["c", "a"], 1337))]), HashMap::new()).await?;
+        assert_eq!(svc_applied.statements, 3);
+        assert_eq!(stored_tags().await?, vec!["a", "c"]);
+
+        // unchanged tags, no tag statements
+        let (svc_applied, _) = apply(&mut ctx, HashMap::from([("web".to_string(), service(&["a", "c"], 1338))]), HashMap::new()).await?;
+        assert_eq!((svc_applied.upserted, svc_applied.statements), (1, 2));
+        assert_eq!(stored_tags().await?, vec!["a", "c"]);
+
+        // tags loaded by a new run match
+        assert_eq!(load_service_tags(&client, "node-1").await?, ctx.service_tags.clone().unwrap());
+
+        let (svc_applied, _) = apply(&mut ctx, HashMap::new(), HashMap::new()).await?;
+        assert_eq!(svc_applied.deleted, 1);
+        assert!(stored_tags().await?.is_empty());
+        assert!(ctx.service_tags.as_ref().unwrap().is_empty());
+
         tripwire_tx.send(()).await.ok();
         tripwire_worker.await;
         wait_for_all_pending_handles().await;
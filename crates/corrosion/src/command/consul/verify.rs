@@ -0,0 +1,800 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use consul_client::{AgentCheck, AgentService, Client, ConsulCheckStatus};
+use corro_api_types::{
+    diff::{DiffOptions, RowDiff},
+    ColumnName, SqliteParam, SqliteValue, TableName,
+};
+use corro_client::{pool::LocalConn, CorrosionClient};
+use corro_types::{
+    api::Statement,
+    config::{ConsulConfig, StaticColumns},
+};
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use tokio::time::timeout;
+use tracing::info;
+
+use super::bookkeeping::{self, bookkeeping_id, strip_bookkeeping_id};
+use super::rewrite::ServiceRewriter;
+use super::sync::{
+    append_upsert_check_statements, append_upsert_service_statements, datacenter, has_datacenter,
+    has_service_status, has_synthetic_checks, hash_check, hash_service, node_name, stored_output,
+    synthesize_checks, ServiceStatusConfig, ServiceStatuses,
+};
+
+// expanded to the ids deleted by `delete_statements`
+const IDS_MARKER: &str = "{{ids}}";
+
+/// What corrosion currently knows about a single consul service or check
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StoredEntry {
+    /// hash recorded in the bookkeeping table, if any
+    pub hash: Option<u64>,
+    /// `updated_at` of the node's row in the consul table, if the row exists
+    pub updated_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Divergence {
+    /// consul reports it, corrosion has no row for it
+    Missing { id: String },
+    /// corrosion has a row (or a hash) for it, consul doesn't report it
+    Extra { id: String },
+    /// both sides have it, but the recorded hash doesn't match consul's
+    HashMismatch {
+        id: String,
+        expected: u64,
+        actual: Option<u64>,
+    },
+    /// the row hasn't been written since before the staleness cutoff
+    Stale { id: String, updated_at: i64 },
+}
+
+impl Divergence {
+    pub fn id(&self) -> &str {
+        match self {
+            Divergence::Missing { id }
+            | Divergence::Extra { id }
+            | Divergence::HashMismatch { id, .. }
+            | Divergence::Stale { id, .. } => id,
+        }
+    }
+
+    /// Whether repairing this requires (re-)upserting the entry from consul
+    fn needs_upsert(&self) -> bool {
+        matches!(
+            self,
+            Divergence::Missing { .. } | Divergence::HashMismatch { .. } | Divergence::Stale { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Divergence::Missing { id } => write!(f, "missing: {id}"),
+            Divergence::Extra { id } => write!(f, "extra: {id}"),
+            Divergence::HashMismatch {
+                id,
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "hash mismatch: {id} (consul: {expected:016x}, corrosion: {actual:016x})"
+            ),
+            Divergence::HashMismatch {
+                id,
+                expected,
+                actual: None,
+            } => write!(
+                f,
+                "hash mismatch: {id} (consul: {expected:016x}, corrosion: none)"
+            ),
+            Divergence::Stale { id, updated_at } => {
+                write!(f, "stale: {id} (updated_at: {updated_at})")
+            }
+        }
+    }
+}
+
+/// Compares the hashes computed from what consul reports (`expected`) with
+/// what corrosion has stored for this node.
+///
+/// Rows with an `updated_at` strictly older than `stale_before` (unix millis)
+/// are reported as stale. Results are ordered by id.
+pub fn compare(
+    expected: &HashMap<String, u64>,
+    stored: &HashMap<String, StoredEntry>,
+    stale_before: Option<i64>,
+) -> Vec<Divergence> {
+    let ids: BTreeSet<&String> = expected.keys().chain(stored.keys()).collect();
+
+    let mut divergences = vec![];
+
+    for id in ids {
+        match (expected.get(id), stored.get(id)) {
+            (Some(_), None)
+            | (
+                Some(_),
+                Some(StoredEntry {
+                    updated_at: None, ..
+                }),
+            ) => divergences.push(Divergence::Missing { id: id.clone() }),
+            (Some(expected), Some(entry)) => {
+                if entry.hash != Some(*expected) {
+                    divergences.push(Divergence::HashMismatch {
+                        id: id.clone(),
+                        expected: *expected,
+                        actual: entry.hash,
+                    });
+                } else if let (Some(updated_at), Some(cutoff)) = (entry.updated_at, stale_before) {
+                    if updated_at < cutoff {
+                        divergences.push(Divergence::Stale {
+                            id: id.clone(),
+                            updated_at,
+                        });
+                    }
+                }
+            }
+            (None, Some(_)) => divergences.push(Divergence::Extra { id: id.clone() }),
+            (None, None) => unreachable!("id comes from one of the maps"),
+        }
+    }
+
+    divergences
+}
+
+/// A divergence, w/ how the stored row differs from what consul reports
+/// for hash mismatches
+#[derive(Debug, Serialize)]
+pub struct Report {
+    #[serde(flatten)]
+    pub divergence: Divergence,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<RowDiff>,
+}
+
+type Row = (Vec<ColumnName>, Vec<SqliteValue>);
+
+fn row(columns: Vec<(&str, SqliteValue)>) -> Row {
+    columns
+        .into_iter()
+        .map(|(name, value)| (ColumnName(name.into()), value))
+        .unzip()
+}
+
+/// What the sync stores for `svc`, except `updated_at` which always differs
+fn service_row(
+    svc: &AgentService,
+    status: Option<ConsulCheckStatus>,
+    static_columns: &StaticColumns,
+) -> Row {
+    let mut columns = vec![
+        ("name", svc.name.as_str().into()),
+        (
+            "tags",
+            serde_json::to_string(&svc.tags)
+                .unwrap_or_else(|_| "[]".to_string())
+                .into(),
+        ),
+        (
+            "meta",
+            serde_json::to_string(&svc.meta)
+                .unwrap_or_else(|_| "{}".to_string())
+                .into(),
+        ),
+        ("port", svc.port.into()),
+        ("address", svc.address.as_str().into()),
+    ];
+    if let Some(status) = status {
+        columns.push(("status", status.as_str().into()));
+    }
+    columns.extend(
+        static_columns
+            .iter()
+            .map(|(name, value)| (name, value.into())),
+    );
+    row(columns)
+}
+
+/// What the sync stores for `check`, except `updated_at`
+fn check_row(
+    check: &AgentCheck,
+    static_columns: &StaticColumns,
+    max_output_bytes: Option<usize>,
+) -> Row {
+    let mut columns = vec![
+        ("service_id", check.service_id.as_str().into()),
+        ("service_name", check.service_name.as_str().into()),
+        ("name", check.name.as_str().into()),
+        ("status", check.status.as_str().into()),
+        (
+            "output",
+            stored_output(&check.output, max_output_bytes)
+                .as_ref()
+                .into(),
+        ),
+    ];
+    columns.extend(
+        static_columns
+            .iter()
+            .map(|(name, value)| (name, value.into())),
+    );
+    row(columns)
+}
+
+/// `WHERE` clause selecting the node's rows, in `datacenter` if any
+fn node_predicate(datacenter: Option<&str>) -> &'static str {
+    match datacenter {
+        Some(_) => "node = ? AND datacenter = ?",
+        None => "node = ?",
+    }
+}
+
+/// Deletes the node's rows for `ids` from `table` and their hashes from
+/// `bookkeeping_table`, in as many statements as it takes to bind all the ids
+fn delete_statements(
+    table: &str,
+    bookkeeping_table: &TableName,
+    node: &str,
+    datacenter: Option<&str>,
+    ids: Vec<String>,
+) -> eyre::Result<Vec<Statement>> {
+    let mut statements = Statement::with_in_clause(
+        &format!(
+            "DELETE FROM {} WHERE id IN ({IDS_MARKER})",
+            bookkeeping_table.as_str()
+        ),
+        IDS_MARKER,
+        vec![],
+        ids.iter()
+            .map(|id| bookkeeping_id(datacenter, id).into())
+            .collect(),
+    )?;
+    statements.extend(Statement::with_in_clause(
+        &format!(
+            "DELETE FROM {table} WHERE {} AND id IN ({IDS_MARKER})",
+            node_predicate(datacenter)
+        ),
+        IDS_MARKER,
+        [node]
+            .into_iter()
+            .chain(datacenter)
+            .map(SqliteParam::from)
+            .collect(),
+        ids.into_iter().map(SqliteParam::from).collect(),
+    )?);
+    Ok(statements)
+}
+
+/// Diffs the node's row for `id` in `table` w/ the `expected` one, `None` if
+/// there's no such row
+fn diff_stored(
+    conn: &Connection,
+    table: &str,
+    node: &str,
+    datacenter: Option<&str>,
+    id: &str,
+    expected: &Row,
+) -> eyre::Result<Option<RowDiff>> {
+    let columns = expected
+        .0
+        .iter()
+        .map(|name| format!("\"{}\"", name.as_str()))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let stored = conn
+        .query_row(
+            &format!(
+                "SELECT {columns} FROM {table} WHERE {} AND id = ?",
+                node_predicate(datacenter)
+            ),
+            rusqlite::params_from_iter([node].into_iter().chain(datacenter).chain([id])),
+            |row| {
+                (0..expected.0.len())
+                    .map(|i| row.get::<_, SqliteValue>(i))
+                    .collect::<Result<Vec<_>, _>>()
+            },
+        )
+        .optional()?;
+
+    // tags and meta are stored as JSON, and ports may have been read back
+    // from a REAL column
+    let options = DiffOptions::default()
+        .numeric_coercion(true)
+        .json_column("tags")
+        .json_column("meta");
+
+    Ok(stored.map(|values| {
+        RowDiff::between((&expected.0, &values), (&expected.0, &expected.1), &options)
+    }))
+}
+
+/// Loads the node's rows from `table` merged with the hashes from `bookkeeping_table`
+fn load_stored(
+    conn: &LocalConn,
+    table: &str,
+    bookkeeping_table: &TableName,
+    node: &str,
+    datacenter: Option<&str>,
+) -> eyre::Result<HashMap<String, StoredEntry>> {
+    let mut stored: HashMap<String, StoredEntry> = HashMap::new();
+
+    conn.timed(
+        &format!("SELECT id, hash FROM {}", bookkeeping_table.as_str()),
+        |prepped| {
+            let mut rows = prepped.query([])?;
+            while let Some(row) = rows.next()? {
+                if let Some(id) = strip_bookkeeping_id(datacenter, row.get(0)?) {
+                    stored.entry(id).or_default().hash = Some(u64::from_be_bytes(row.get(1)?));
+                }
+            }
+            Ok::<_, rusqlite::Error>(())
+        },
+    )?;
+
+    conn.timed(
+        &format!(
+            "SELECT id, updated_at FROM {table} WHERE {}",
+            node_predicate(datacenter)
+        ),
+        |prepped| {
+            let mut rows = prepped.query(rusqlite::params_from_iter(
+                [node].into_iter().chain(datacenter),
+            ))?;
+            while let Some(row) = rows.next()? {
+                stored.entry(row.get(0)?).or_default().updated_at = Some(row.get(1)?);
+            }
+            Ok::<_, rusqlite::Error>(())
+        },
+    )?;
+
+    Ok(stored)
+}
+
+fn print_reports(kind: &str, reports: &[Report]) {
+    if reports.is_empty() {
+        println!("{kind}: ok");
+        return;
+    }
+    println!("{kind}: {} difference(s)", reports.len());
+    for report in reports {
+        println!("  {}", report.divergence);
+        for column in report.diff.iter().flat_map(|diff| diff.columns.iter()) {
+            println!("    {column}");
+        }
+    }
+}
+
+/// Compares the local consul agent's services and checks against corrosion's
+/// tables for this node. Returns `true` if no differences were found (or
+/// they were all repaired when `fix` is set).
+///
+/// Hash mismatches are reported w/ the columns which differ, from what
+/// corrosion stores to what consul reports. Printed as JSON w/ `json`.
+pub async fn run<P: AsRef<Path>>(
+    config: &ConsulConfig,
+    api_addr: SocketAddr,
+    db_path: P,
+    stale_after: Option<Duration>,
+    fix: bool,
+    json: bool,
+) -> eyre::Result<bool> {
+    let corrosion = CorrosionClient::new(api_addr, db_path);
+    let consul = Client::new(config.client.clone())?;
+    let node = node_name(config, &consul).await?;
+    let datacenter_column = has_datacenter(&corrosion.pool().get().await?)?;
+    let datacenter = datacenter(config, &consul, datacenter_column).await?;
+    let datacenter = datacenter.as_deref();
+
+    let rewriter = ServiceRewriter::new(&config.rewrites)?;
+
+    let (mut services, mut checks) = tokio::try_join!(
+        async {
+            Ok::<_, eyre::Report>(timeout(Duration::from_secs(5), consul.agent_services()).await??)
+        },
+        async {
+            Ok::<_, eyre::Report>(timeout(Duration::from_secs(5), consul.agent_checks()).await??)
+        }
+    )?;
+
+    for svc in services.values_mut() {
+        rewriter.apply(svc);
+    }
+
+    // checks the sync would synthesize aren't extra
+    let synthetic = if config.synthesize_missing_checks {
+        synthesize_checks(&services, &mut checks)
+    } else {
+        Default::default()
+    };
+
+    let (stored_svcs, stored_checks, service_status, synthetic_column) = {
+        let conn = corrosion.pool().get().await?;
+        (
+            load_stored(
+                &conn,
+                "consul_services",
+                &bookkeeping::SERVICES,
+                &node,
+                datacenter,
+            )?,
+            load_stored(
+                &conn,
+                "consul_checks",
+                &bookkeeping::CHECKS,
+                &node,
+                datacenter,
+            )?,
+            has_service_status(&conn)?,
+            has_synthetic_checks(&conn)?,
+        )
+    };
+
+    // hashed like the sync does, w/ the statuses it would store
+    let statuses = service_status.then(|| {
+        ServiceStatuses::new(
+            checks.values(),
+            ServiceStatusConfig {
+                include_node_checks: config.node_checks_affect_services,
+            },
+        )
+    });
+    let status = |id: &str| statuses.as_ref().map(|statuses| statuses.get(id));
+
+    let svc_hashes: HashMap<String, u64> = services
+        .iter()
+        .map(|(id, svc)| {
+            (
+                id.clone(),
+                hash_service(svc, status(id), &config.static_columns),
+            )
+        })
+        .collect();
+    let check_hashes: HashMap<String, u64> = checks
+        .iter()
+        .map(|(id, check)| (id.clone(), hash_check(check, &config.static_columns)))
+        .collect();
+
+    let stale_before = stale_after.map(|d| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("could not get system time")
+            .saturating_sub(d)
+            .as_millis() as i64
+    });
+
+    let svc_diffs = compare(&svc_hashes, &stored_svcs, stale_before);
+    let check_diffs = compare(&check_hashes, &stored_checks, stale_before);
+
+    let (svc_reports, check_reports) = {
+        let conn = corrosion.pool().get().await?;
+        let report = |d: &Divergence, expected: Option<Row>, table: &str| {
+            let diff = match (d, expected) {
+                (Divergence::HashMismatch { id, .. }, Some(expected)) => {
+                    diff_stored(&conn, table, &node, datacenter, id, &expected)?
+                }
+                _ => None,
+            };
+            Ok::<_, eyre::Report>(Report {
+                divergence: d.clone(),
+                diff,
+            })
+        };
+        (
+            svc_diffs
+                .iter()
+                .map(|d| {
+                    let expected = services
+                        .get(d.id())
+                        .map(|svc| service_row(svc, status(d.id()), &config.static_columns));
+                    report(d, expected, "consul_services")
+                })
+                .collect::<eyre::Result<Vec<_>>>()?,
+            check_diffs
+                .iter()
+                .map(|d| {
+                    report(
+                        d,
+                        checks.get(d.id()).map(|check| {
+                            check_row(check, &config.static_columns, config.max_output_bytes)
+                        }),
+                        "consul_checks",
+                    )
+                })
+                .collect::<eyre::Result<Vec<_>>>()?,
+        )
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "services": svc_reports,
+                "checks": check_reports,
+            }))?
+        );
+    } else {
+        print_reports("services", &svc_reports);
+        print_reports("checks", &check_reports);
+    }
+
+    if svc_diffs.is_empty() && check_diffs.is_empty() {
+        return Ok(true);
+    }
+
+    if !fix {
+        return Ok(false);
+    }
+
+    let updated_at = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("could not get system time")
+        .as_millis() as i64;
+
+    let mut statements: Vec<Statement> = vec![];
+    let mut extra_svcs = vec![];
+    let mut extra_checks = vec![];
+
+    for d in svc_diffs {
+        let id = d.id().to_owned();
+        if d.needs_upsert() {
+            if let Some(svc) = services.remove(&id) {
+                append_upsert_service_statements(
+                    &mut statements,
+                    &node,
+                    datacenter,
+                    svc,
+                    status(&id),
+                    &config.static_columns,
+                    svc_hashes[&id],
+                    updated_at,
+                );
+            }
+        } else {
+            extra_svcs.push(id);
+        }
+    }
+
+    for d in check_diffs {
+        let id = d.id().to_owned();
+        if d.needs_upsert() {
+            if let Some(check) = checks.remove(&id) {
+                append_upsert_check_statements(
+                    &mut statements,
+                    &node,
+                    datacenter,
+                    check,
+                    synthetic_column.then(|| synthetic.contains(&id)),
+                    &config.static_columns,
+                    config.max_output_bytes,
+                    check_hashes[&id],
+                    updated_at,
+                );
+            }
+        } else {
+            extra_checks.push(id);
+        }
+    }
+
+    statements.extend(delete_statements(
+        "consul_services",
+        &bookkeeping::SERVICES,
+        &node,
+        datacenter,
+        extra_svcs,
+    )?);
+    statements.extend(delete_statements(
+        "consul_checks",
+        &bookkeeping::CHECKS,
+        &node,
+        datacenter,
+        extra_checks,
+    )?);
+
+    corrosion.execute(&statements).await?;
+    info!(
+        "repaired consul differences ({} statements)",
+        statements.len()
+    );
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(hash: Option<u64>, updated_at: Option<i64>) -> StoredEntry {
+        StoredEntry { hash, updated_at }
+    }
+
+    #[test]
+    fn diffs_stored_rows() -> eyre::Result<()> {
+        let svc = AgentService {
+            id: "web-1".into(),
+            name: "web".into(),
+            tags: vec!["a".into(), "b".into()],
+            meta: [("k".to_string(), "v".to_string())].into(),
+            port: 80,
+            address: "10.0.0.2".into(),
+        };
+        let expected = service_row(&svc, None, &StaticColumns::default());
+
+        let conn = Connection::open_in_memory()?;
+        conn.execute_batch(
+            r#"
+            CREATE TABLE consul_services (node TEXT, id TEXT, name TEXT, tags TEXT, meta TEXT, port INTEGER, address TEXT, updated_at INTEGER);
+            INSERT INTO consul_services VALUES ('n1', 'web-1', 'web', '["a", "b"]', '{ "k": "v" }', 80.0, '10.0.0.1', 0);
+            "#,
+        )?;
+
+        let diff = diff_stored(&conn, "consul_services", "n1", None, "web-1", &expected)?.unwrap();
+        assert_eq!(diff.to_string(), "address: '10.0.0.1' → '10.0.0.2'");
+
+        assert!(diff_stored(&conn, "consul_services", "n2", None, "web-1", &expected)?.is_none());
+
+        let report = Report {
+            divergence: Divergence::HashMismatch {
+                id: "web-1".into(),
+                expected: 1,
+                actual: None,
+            },
+            diff: Some(diff),
+        };
+        assert_eq!(
+            serde_json::to_value(&report)?,
+            serde_json::json!({
+                "kind": "hash_mismatch",
+                "id": "web-1",
+                "expected": 1,
+                "actual": null,
+                "diff": [{
+                    "column": "address",
+                    "old": {"type": "TEXT", "value": "10.0.0.1"},
+                    "new": {"type": "TEXT", "value": "10.0.0.2"},
+                }],
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compare_in_sync() {
+        let expected = HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]);
+        let stored = HashMap::from([
+            ("a".to_string(), stored(Some(1), Some(100))),
+            ("b".to_string(), stored(Some(2), Some(100))),
+        ]);
+
+        assert!(compare(&expected, &stored, None).is_empty());
+        assert!(compare(&expected, &stored, Some(100)).is_empty());
+    }
+
+    #[test]
+    fn compare_divergences() {
+        let expected = HashMap::from([
+            ("missing".to_string(), 1),
+            ("orphan-hash".to_string(), 2),
+            ("mismatch".to_string(), 3),
+            ("no-hash".to_string(), 4),
+            ("stale".to_string(), 5),
+        ]);
+        let stored = HashMap::from([
+            // bookkeeping knows about it, but the row is gone
+            ("orphan-hash".to_string(), stored(Some(2), None)),
+            ("mismatch".to_string(), stored(Some(30), Some(100))),
+            ("no-hash".to_string(), stored(None, Some(100))),
+            ("stale".to_string(), stored(Some(5), Some(10))),
+            ("extra".to_string(), stored(Some(6), Some(100))),
+            ("extra-hash-only".to_string(), stored(Some(7), None)),
+        ]);
+
+        let diffs = compare(&expected, &stored, Some(50));
+
+        assert_eq!(
+            diffs,
+            vec![
+                Divergence::Extra { id: "extra".into() },
+                Divergence::Extra {
+                    id: "extra-hash-only".into()
+                },
+                Divergence::HashMismatch {
+                    id: "mismatch".into(),
+                    expected: 3,
+                    actual: Some(30)
+                },
+                Divergence::Missing {
+                    id: "missing".into()
+                },
+                Divergence::HashMismatch {
+                    id: "no-hash".into(),
+                    expected: 4,
+                    actual: None
+                },
+                Divergence::Missing {
+                    id: "orphan-hash".into()
+                },
+                Divergence::Stale {
+                    id: "stale".into(),
+                    updated_at: 10
+                },
+            ]
+        );
+
+        assert!(diffs
+            .iter()
+            .filter(|d| d.needs_upsert())
+            .all(|d| expected.contains_key(d.id())));
+        assert!(diffs
+            .iter()
+            .filter(|d| !d.needs_upsert())
+            .all(|d| !expected.contains_key(d.id())));
+    }
+
+    #[test]
+    fn deletes_extra_ids_in_bulk() -> eyre::Result<()> {
+        let statements = delete_statements(
+            "consul_services",
+            &bookkeeping::SERVICES,
+            "node-1",
+            Some("dc1"),
+            vec!["a".into(), "b".into()],
+        )?;
+
+        let queries: Vec<(&str, Vec<String>)> = statements
+            .iter()
+            .map(|statement| {
+                (
+                    statement.query(),
+                    statement
+                        .params()
+                        .map(|param| match param {
+                            SqliteParam::Text(text) => text.to_string(),
+                            other => panic!("unexpected param {other:?}"),
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            queries,
+            [
+                (
+                    "DELETE FROM __corro_consul_services WHERE id IN (?, ?)",
+                    vec!["dc1/a".to_string(), "dc1/b".to_string()]
+                ),
+                (
+                    "DELETE FROM consul_services WHERE node = ? AND datacenter = ? AND id IN (?, ?)",
+                    vec![
+                        "node-1".to_string(),
+                        "dc1".to_string(),
+                        "a".to_string(),
+                        "b".to_string()
+                    ]
+                ),
+            ]
+        );
+
+        // nothing to delete
+        assert!(delete_statements(
+            "consul_checks",
+            &bookkeeping::CHECKS,
+            "node-1",
+            None,
+            vec![]
+        )?
+        .is_empty());
+
+        Ok(())
+    }
+}
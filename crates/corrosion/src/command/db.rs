@@ -0,0 +1,102 @@
+use std::io::Write;
+
+use corro_types::api::CheckpointResult;
+
+/// Prints whether the WAL was truncated and, if not, the subscriptions which
+/// held a snapshot at the time
+pub fn render<W: Write>(result: &CheckpointResult, json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, result)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    if result.busy {
+        writeln!(
+            out,
+            "WAL not truncated, readers are still using it: {} of {} frames checkpointed, {} bytes left",
+            result.checkpointed_frames, result.wal_frames, result.wal_size_bytes
+        )?;
+    } else {
+        writeln!(
+            out,
+            "WAL truncated: {} frames checkpointed",
+            result.checkpointed_frames
+        )?;
+    }
+
+    if result.held_snapshots.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "{:<36}  {:>10}", "subscription", "held (s)")?;
+    for held in result.held_snapshots.iter() {
+        writeln!(
+            out,
+            "{:<36}  {:>10.1}",
+            held.subscription_id.to_string(),
+            held.held_secs
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::api::HeldSnapshot;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn renders_checkpoints() -> eyre::Result<()> {
+        let result = CheckpointResult {
+            busy: true,
+            wal_frames: 120,
+            checkpointed_frames: 100,
+            wal_size_bytes: 494_472,
+            held_snapshots: vec![HeldSnapshot {
+                subscription_id: Uuid::nil(),
+                held_secs: 42.3,
+            }],
+        };
+
+        let mut out = vec![];
+        render(&result, false, &mut out)?;
+        let rendered = String::from_utf8(out)?;
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 3, "{rendered}");
+        assert_eq!(
+            lines[0],
+            "WAL not truncated, readers are still using it: 100 of 120 frames checkpointed, 494472 bytes left"
+        );
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            ["00000000-0000-0000-0000-000000000000", "42.3"]
+        );
+
+        let mut out = vec![];
+        render(&result, true, &mut out)?;
+        assert_eq!(serde_json::from_slice::<CheckpointResult>(&out)?, result);
+
+        let mut out = vec![];
+        render(
+            &CheckpointResult {
+                busy: false,
+                wal_frames: 120,
+                checkpointed_frames: 120,
+                wal_size_bytes: 0,
+                held_snapshots: vec![],
+            },
+            false,
+            &mut out,
+        )?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "WAL truncated: 120 frames checkpointed\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,578 @@
+use std::{fmt, io::Write, path::Path, time::Duration};
+
+use clap::Args;
+use consul_client::Client;
+use corro_api_types::{ChangeType, ExecResult, QueryEvent, Statement};
+use corro_client::{pool::LocalConn, sub::SubscriptionStream, CorrosionApiClient, CorrosionClient};
+use corro_types::{actor::ActorId, broadcast::Timestamp, config::ConsulConfig};
+use futures::StreamExt;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use super::consul::sync::check_schema;
+
+/// Scratch table of the write round-trip, created through the schema API
+const DOCTOR_TABLE_SQL: &str =
+    "CREATE TABLE IF NOT EXISTS __corro_doctor (id TEXT NOT NULL PRIMARY KEY, at INTEGER NOT NULL DEFAULT 0)";
+
+#[derive(Args, Debug, Clone)]
+pub struct DoctorFlags {
+    /// Skip checking the API is reachable and ready
+    #[arg(long, default_value = "false")]
+    pub skip_api: bool,
+    /// Skip checking the schema's tables are instrumented by cr-sqlite
+    #[arg(long, default_value = "false")]
+    pub skip_schema: bool,
+    /// Skip the write round-trip through the `__corro_doctor` table
+    #[arg(long, default_value = "false")]
+    pub skip_write: bool,
+    /// Skip checking the database and WAL sizes
+    #[arg(long, default_value = "false")]
+    pub skip_storage: bool,
+    /// Skip comparing the clocks of peers w/ the local one
+    #[arg(long, default_value = "false")]
+    pub skip_clock: bool,
+    /// Skip checking the consul agent and the consul tables' schema
+    #[arg(long, default_value = "false")]
+    pub skip_consul: bool,
+    /// Fail the storage check if the database is larger than this
+    #[arg(long)]
+    pub max_db_bytes: Option<u64>,
+    /// Fail the storage check if the WAL is larger than this
+    #[arg(long)]
+    pub max_wal_bytes: Option<u64>,
+    /// Fail the clock check if a peer's latest change is timestamped this
+    /// many seconds ahead of the local clock
+    #[arg(long, default_value = "5")]
+    pub max_clock_skew: u64,
+    /// How long the write round-trip waits for its change event, in seconds
+    #[arg(long, default_value = "5")]
+    pub write_timeout: u64,
+}
+
+impl Default for DoctorFlags {
+    fn default() -> Self {
+        Self {
+            skip_api: false,
+            skip_schema: false,
+            skip_write: false,
+            skip_storage: false,
+            skip_clock: false,
+            skip_consul: false,
+            max_db_bytes: None,
+            max_wal_bytes: None,
+            max_clock_skew: 5,
+            write_timeout: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    Skip,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckStatus::Pass => "PASS",
+            CheckStatus::Fail => "FAIL",
+            CheckStatus::Skip => "SKIP",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl CheckResult {
+    fn skip(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: CheckStatus::Skip,
+            detail: detail.into(),
+        }
+    }
+
+    // a passing check w/ `Ok`'s detail, a failing one w/ the error
+    fn from_result(name: &'static str, res: eyre::Result<String>) -> Self {
+        match res {
+            Ok(detail) => Self {
+                name,
+                status: CheckStatus::Pass,
+                detail,
+            },
+            Err(e) => Self {
+                name,
+                status: CheckStatus::Fail,
+                detail: format!("{e:#}"),
+            },
+        }
+    }
+}
+
+/// Runs the checks not skipped by `flags` against the agent, in order. A
+/// failing check doesn't stop the next ones from running.
+pub async fn run(
+    api: &CorrosionApiClient,
+    corrosion: &CorrosionClient,
+    db_path: &Path,
+    consul: Option<&ConsulConfig>,
+    flags: &DoctorFlags,
+) -> Vec<CheckResult> {
+    let mut results = vec![];
+
+    results.push(if flags.skip_api {
+        CheckResult::skip("api", "skipped")
+    } else {
+        CheckResult::from_result("api", check_api(api).await)
+    });
+
+    results.push(if flags.skip_schema {
+        CheckResult::skip("schema", "skipped")
+    } else {
+        CheckResult::from_result("schema", check_schema_instrumented(api).await)
+    });
+
+    results.push(if flags.skip_write {
+        CheckResult::skip("write", "skipped")
+    } else {
+        CheckResult::from_result(
+            "write",
+            check_write(api, Duration::from_secs(flags.write_timeout)).await,
+        )
+    });
+
+    results.push(if flags.skip_storage {
+        CheckResult::skip("storage", "skipped")
+    } else {
+        CheckResult::from_result("storage", check_storage(api, flags).await)
+    });
+
+    results.push(if flags.skip_clock {
+        CheckResult::skip("clock", "skipped")
+    } else {
+        CheckResult::from_result(
+            "clock",
+            check_clock(
+                corrosion,
+                db_path,
+                Duration::from_secs(flags.max_clock_skew),
+            )
+            .await,
+        )
+    });
+
+    results.push(match consul {
+        _ if flags.skip_consul => CheckResult::skip("consul", "skipped"),
+        None => CheckResult::skip("consul", "no `consul` block in the config"),
+        Some(consul) => {
+            CheckResult::from_result("consul", check_consul(corrosion, db_path, consul).await)
+        }
+    });
+
+    results
+}
+
+/// Whether any check failed
+pub fn failed(results: &[CheckResult]) -> bool {
+    results
+        .iter()
+        .any(|result| result.status == CheckStatus::Fail)
+}
+
+/// Renders the results as a JSON document, or a line per check
+pub fn render<W: Write>(results: &[CheckResult], json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(
+            &mut *out,
+            &serde_json::json!({ "ok": !failed(results), "checks": results }),
+        )?;
+        writeln!(out)?;
+    } else {
+        for result in results {
+            writeln!(
+                out,
+                "{}  {:<8} {}",
+                result.status, result.name, result.detail
+            )?;
+        }
+    }
+    Ok(())
+}
+
+async fn check_api(api: &CorrosionApiClient) -> eyre::Result<String> {
+    let health = api.health_details().await?;
+    Ok(format!(
+        "reachable, {} bytes free on disk",
+        health.free_disk_bytes
+    ))
+}
+
+async fn check_schema_instrumented(api: &CorrosionApiClient) -> eyre::Result<String> {
+    // each table of the schema must have its cr-sqlite clock table
+    let (tables, missing) = api
+        .query_one::<(i64, Option<String>)>(&Statement::Simple(
+            "SELECT COUNT(*), group_concat(CASE WHEN c.name IS NULL THEN s.tbl_name END, ', ')
+                FROM (SELECT DISTINCT tbl_name FROM __corro_schema WHERE type = 'table') s
+                LEFT JOIN sqlite_schema c
+                    ON c.type = 'table' AND c.name = s.tbl_name || '__crsql_clock'"
+                .into(),
+        ))
+        .await?;
+
+    if let Some(missing) = missing {
+        eyre::bail!("tables not instrumented by cr-sqlite: {missing}");
+    }
+
+    Ok(format!("{tables} table(s) instrumented"))
+}
+
+async fn check_write(api: &CorrosionApiClient, wait: Duration) -> eyre::Result<String> {
+    api.schema(&[Statement::Simple(DOCTOR_TABLE_SQL.into())])
+        .await?;
+
+    let id = Uuid::new_v4().to_string();
+
+    let mut sub = api
+        .subscribe(
+            &Statement::WithParams(
+                "SELECT id FROM __corro_doctor WHERE id = ?".into(),
+                vec![id.clone().into()],
+            ),
+            None,
+        )
+        .await?;
+
+    // the initial (empty) result set comes first
+    timeout(wait, async {
+        while let Some(event) = sub.next().await {
+            match event? {
+                QueryEvent::EndOfQuery { .. } => return Ok(()),
+                QueryEvent::Error(e) => eyre::bail!("subscription failed: {e}"),
+                _ => {}
+            }
+        }
+        eyre::bail!("subscription ended before its initial result set")
+    })
+    .await
+    .map_err(|_| eyre::eyre!("timed out waiting for the subscription's initial result set"))??;
+
+    let res = api
+        .execute(&[Statement::WithParams(
+            "INSERT INTO __corro_doctor (id, at) VALUES (?, unixepoch())".into(),
+            vec![id.clone().into()],
+        )])
+        .await?;
+    exec_result(res.results.into_iter().next())?;
+
+    let res = write_round_trip(api, &mut sub, &id, wait).await;
+
+    // cleaned up whether the round-trip went through or not
+    let deleted = api
+        .execute(&[Statement::WithParams(
+            "DELETE FROM __corro_doctor WHERE id = ?".into(),
+            vec![id.into()],
+        )])
+        .await
+        .map_err(eyre::Report::from)
+        .and_then(|res| exec_result(res.results.into_iter().next()));
+
+    let detail = res?;
+    deleted.map_err(|e| eyre::eyre!("could not delete the scratch row: {e}"))?;
+
+    Ok(detail)
+}
+
+async fn write_round_trip(
+    api: &CorrosionApiClient,
+    sub: &mut SubscriptionStream,
+    id: &str,
+    wait: Duration,
+) -> eyre::Result<String> {
+    let read: Option<String> = api
+        .query_scalar(&Statement::WithParams(
+            "SELECT id FROM __corro_doctor WHERE id = ?".into(),
+            vec![id.into()],
+        ))
+        .await?;
+    if read.as_deref() != Some(id) {
+        eyre::bail!("the inserted row could not be read back");
+    }
+
+    timeout(wait, async {
+        while let Some(event) = sub.next().await {
+            match event? {
+                QueryEvent::Change(ChangeType::Insert, ..) => return Ok(()),
+                QueryEvent::Error(e) => eyre::bail!("subscription failed: {e}"),
+                _ => {}
+            }
+        }
+        eyre::bail!("subscription ended before the insert's change event")
+    })
+    .await
+    .map_err(|_| eyre::eyre!("timed out waiting for the insert's change event"))??;
+
+    Ok("inserted, read back and observed by a subscription".into())
+}
+
+fn exec_result(result: Option<ExecResult>) -> eyre::Result<()> {
+    match result {
+        Some(ExecResult::Execute { rows_affected, .. }) if rows_affected == 1 => Ok(()),
+        Some(ExecResult::Execute { rows_affected, .. }) => {
+            eyre::bail!("expected 1 row affected, got {rows_affected}")
+        }
+        Some(ExecResult::Error { error, .. }) => eyre::bail!("{error}"),
+        None => eyre::bail!("no result for the statement"),
+    }
+}
+
+async fn check_storage(api: &CorrosionApiClient, flags: &DoctorFlags) -> eyre::Result<String> {
+    let health = api.health_details().await?;
+
+    let mut problems = health.reasons;
+    if health.degraded && problems.is_empty() {
+        problems.push("degraded".into());
+    }
+    if let Some(max) = flags.max_db_bytes.filter(|max| health.db_size_bytes > *max) {
+        problems.push(format!(
+            "database is {} bytes, over {max}",
+            health.db_size_bytes
+        ));
+    }
+    if let Some(max) = flags
+        .max_wal_bytes
+        .filter(|max| health.wal_size_bytes > *max)
+    {
+        problems.push(format!(
+            "WAL is {} bytes, over {max}",
+            health.wal_size_bytes
+        ));
+    }
+
+    if !problems.is_empty() {
+        eyre::bail!("{}", problems.join(", "));
+    }
+
+    Ok(format!(
+        "database is {} bytes, WAL is {} bytes",
+        health.db_size_bytes, health.wal_size_bytes
+    ))
+}
+
+async fn check_clock(
+    corrosion: &CorrosionClient,
+    db_path: &Path,
+    max_skew: Duration,
+) -> eyre::Result<String> {
+    let conn = local_conn(corrosion, db_path).await?;
+
+    // NTP64 timestamps are stored as decimal strings of the same length
+    let latest = conn
+        .prepare_cached(
+            "SELECT actor_id, MAX(ts) FROM __corro_bookkeeping WHERE ts IS NOT NULL GROUP BY actor_id",
+        )?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, ActorId>(0)?,
+                row.get::<_, Timestamp>(1)?.to_time(),
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if latest.is_empty() {
+        return Ok("no timestamped changes to compare w/".into());
+    }
+
+    let ahead = clock_skews(&latest, OffsetDateTime::now_utc(), max_skew);
+    if !ahead.is_empty() {
+        eyre::bail!(
+            "changes timestamped ahead of the local clock: {}",
+            ahead
+                .iter()
+                .map(|(actor_id, skew)| format!("{actor_id} by {skew:?}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(format!(
+        "{} actor(s) within {max_skew:?} of the local clock",
+        latest.len()
+    ))
+}
+
+/// Actors whose latest change is timestamped more than `max_skew` after `now`
+fn clock_skews(
+    latest: &[(ActorId, OffsetDateTime)],
+    now: OffsetDateTime,
+    max_skew: Duration,
+) -> Vec<(ActorId, Duration)> {
+    latest
+        .iter()
+        .filter_map(|(actor_id, ts)| {
+            let skew: Duration = (*ts - now).try_into().ok()?;
+            (skew > max_skew).then_some((*actor_id, skew))
+        })
+        .collect()
+}
+
+async fn check_consul(
+    corrosion: &CorrosionClient,
+    db_path: &Path,
+    config: &ConsulConfig,
+) -> eyre::Result<String> {
+    let consul = Client::new(config.client.clone())?;
+    let services = timeout(Duration::from_secs(5), consul.agent_services())
+        .await
+        .map_err(|_| eyre::eyre!("timed out reaching the consul agent"))?
+        .map_err(|e| eyre::eyre!("could not reach the consul agent: {e}"))?;
+
+    let conn = local_conn(corrosion, db_path).await?;
+    let tables = check_schema(&conn)?;
+
+    let mut optional = vec![];
+    if tables.service_status {
+        optional.push("consul_services.status");
+    }
+    if tables.service_tags {
+        optional.push("consul_service_tags");
+    }
+
+    Ok(format!(
+        "agent reachable w/ {} service(s), schema valid{}",
+        services.len(),
+        if optional.is_empty() {
+            String::new()
+        } else {
+            format!(" (w/ {})", optional.join(", "))
+        }
+    ))
+}
+
+// opening a connection of the pool would create a missing database file
+async fn local_conn(corrosion: &CorrosionClient, db_path: &Path) -> eyre::Result<LocalConn> {
+    let meta = tokio::fs::metadata(db_path)
+        .await
+        .map_err(|e| eyre::eyre!("could not open {}: {e}", db_path.display()))?;
+    if !meta.is_file() {
+        eyre::bail!("{} is not a file", db_path.display());
+    }
+    Ok(corrosion.pool().get().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    #[test]
+    fn renders_results() -> eyre::Result<()> {
+        let results = vec![
+            CheckResult::from_result("api", Ok("reachable".into())),
+            CheckResult::from_result("write", Err(eyre::eyre!("boom"))),
+            CheckResult::skip("consul", "skipped"),
+        ];
+        assert!(failed(&results));
+        assert!(!failed(&results[..1]));
+
+        let mut out = vec![];
+        render(&results, false, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "PASS  api      reachable\nFAIL  write    boom\nSKIP  consul   skipped\n"
+        );
+
+        let mut out = vec![];
+        render(&results, true, &mut out)?;
+        let value: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(value["ok"], false);
+        assert_eq!(value["checks"][1]["name"], "write");
+        assert_eq!(value["checks"][1]["status"], "fail");
+        assert_eq!(value["checks"][2]["status"], "skip");
+
+        Ok(())
+    }
+
+    #[test]
+    fn flags_clocks_ahead() {
+        let now = OffsetDateTime::now_utc();
+        let behind = ActorId(Uuid::new_v4());
+        let ahead = ActorId(Uuid::new_v4());
+
+        let skews = clock_skews(
+            &[
+                (behind, now - Duration::from_secs(60)),
+                (ahead, now + Duration::from_secs(60)),
+            ],
+            now,
+            Duration::from_secs(5),
+        );
+        assert_eq!(skews.len(), 1);
+        assert_eq!(skews[0].0, ahead);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn passes_against_an_agent() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let api = CorrosionApiClient::new(ta.agent.api_addr());
+        let db_path = ta.agent.config().db.path.clone();
+        let corrosion = CorrosionClient::new(ta.agent.api_addr(), &db_path);
+
+        let results = run(
+            &api,
+            &corrosion,
+            db_path.as_std_path(),
+            None,
+            &DoctorFlags::default(),
+        )
+        .await;
+        assert!(!failed(&results), "{results:?}");
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[5].status, CheckStatus::Skip);
+
+        // the scratch row was cleaned up
+        let count: Option<i64> = api
+            .query_scalar(&Statement::Simple(
+                "SELECT COUNT(*) FROM __corro_doctor".into(),
+            ))
+            .await?;
+        assert_eq!(count, Some(0));
+
+        let results = run(
+            &api,
+            &corrosion,
+            db_path.as_std_path(),
+            None,
+            &DoctorFlags {
+                skip_write: true,
+                max_db_bytes: Some(0),
+                ..Default::default()
+            },
+        )
+        .await;
+        assert_eq!(results[2].status, CheckStatus::Skip);
+        assert_eq!(results[3].status, CheckStatus::Fail);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
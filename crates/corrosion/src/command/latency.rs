@@ -0,0 +1,99 @@
+use std::io::Write;
+
+use corro_types::api::ProbeLatencies;
+
+/// Prints the p50/p99 propagation latency of each peer's heartbeats, in
+/// milliseconds
+pub fn render<W: Write>(latencies: &ProbeLatencies, json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, latencies)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    if !latencies.enabled {
+        writeln!(out, "not probing, set gossip.probe in the agent's config")?;
+    }
+    if latencies.peers.is_empty() {
+        writeln!(out, "no heartbeats received from peers")?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{:<36}  {:>8}  {:>10}  {:>10}  {:>10}",
+        "peer", "samples", "p50 (ms)", "p99 (ms)", "max (ms)"
+    )?;
+    for peer in latencies.peers.iter() {
+        writeln!(
+            out,
+            "{:<36}  {:>8}  {:>10.1}  {:>10.1}  {:>10.1}",
+            peer.site_id.to_string(),
+            peer.samples,
+            peer.p50 * 1000.0,
+            peer.p99 * 1000.0,
+            peer.max * 1000.0
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::api::{PeerLatency, SiteId};
+
+    use super::*;
+
+    #[test]
+    fn renders_latencies() -> eyre::Result<()> {
+        let latencies = ProbeLatencies {
+            enabled: true,
+            peers: vec![PeerLatency {
+                site_id: SiteId([0xab; 16]),
+                samples: 42,
+                counter: 100,
+                p50: 0.0125,
+                p99: 0.25,
+                max: 1.5,
+                last_seen_at: 1_700_000_000_000,
+            }],
+        };
+
+        let mut out = vec![];
+        render(&latencies, false, &mut out)?;
+        let rendered = String::from_utf8(out)?;
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 2, "{rendered}");
+        assert!(lines[0].starts_with("peer "), "{rendered}");
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(
+            fields,
+            [
+                "abababab-abab-abab-abab-abababababab",
+                "42",
+                "12.5",
+                "250.0",
+                "1500.0"
+            ]
+        );
+
+        let mut out = vec![];
+        render(&latencies, true, &mut out)?;
+        assert_eq!(serde_json::from_slice::<ProbeLatencies>(&out)?, latencies);
+
+        let mut out = vec![];
+        render(
+            &ProbeLatencies {
+                enabled: false,
+                peers: vec![],
+            },
+            false,
+            &mut out,
+        )?;
+        let rendered = String::from_utf8(out)?;
+        assert!(rendered.contains("gossip.probe"), "{rendered}");
+
+        Ok(())
+    }
+}
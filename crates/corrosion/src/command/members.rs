@@ -0,0 +1,139 @@
+use std::io::Write;
+
+use corro_types::api::MemberState;
+
+/// Prints each peer w/ how long ago it was last heard from, relative to
+/// `now_ms`, and the versions of its changes the agent still needs
+pub fn render<W: Write>(
+    members: &[MemberState],
+    json: bool,
+    now_ms: u64,
+    out: &mut W,
+) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, members)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    if members.is_empty() {
+        writeln!(out, "no peers")?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{:<36}  {:<21}  {:>4}  {:>10}  {:>10}  {:>8}  {:>17}  {:>7}  {:>7}",
+        "peer", "addr", "ring", "seen (s)", "known", "needed", "missing", "partial", "syncing"
+    )?;
+    for member in members.iter() {
+        let ring = member
+            .ring
+            .map_or_else(|| "-".to_string(), |ring| ring.to_string());
+        let known = member
+            .max_db_version_known
+            .map_or_else(|| "-".to_string(), |version| version.to_string());
+        let missing = match (member.needed.min, member.needed.max) {
+            (Some(min), Some(max)) => format!("{min}..={max}"),
+            _ => "-".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<36}  {:<21}  {:>4}  {:>10.1}  {:>10}  {:>8}  {:>17}  {:>7}  {:>7}",
+            member.site_id.to_string(),
+            member.addr.to_string(),
+            ring,
+            now_ms.saturating_sub(member.last_seen) as f64 / 1000.0,
+            known,
+            member.needed.count,
+            missing,
+            member.needed.partial,
+            if member.sync_in_flight { "yes" } else { "no" },
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_types::api::{NeededVersions, SiteId};
+
+    use super::*;
+
+    #[test]
+    fn renders_members() -> eyre::Result<()> {
+        let members = vec![
+            MemberState {
+                site_id: SiteId([0xab; 16]),
+                addr: "10.0.0.2:8787".parse()?,
+                last_seen: 1_700_000_000_000,
+                ring: Some(0),
+                max_db_version_known: Some(1204),
+                sync_in_flight: true,
+                needed: NeededVersions {
+                    count: 3,
+                    min: Some(1190),
+                    max: Some(1200),
+                    partial: 1,
+                },
+            },
+            MemberState {
+                site_id: SiteId([0xcd; 16]),
+                addr: "10.0.0.3:8787".parse()?,
+                last_seen: 1_700_000_000_000,
+                ring: None,
+                max_db_version_known: None,
+                sync_in_flight: false,
+                needed: NeededVersions::default(),
+            },
+        ];
+
+        let mut out = vec![];
+        render(&members, false, 1_700_000_002_500, &mut out)?;
+        let rendered = String::from_utf8(out)?;
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 3, "{rendered}");
+        assert!(lines[0].starts_with("peer "), "{rendered}");
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(
+            fields,
+            [
+                "abababab-abab-abab-abab-abababababab",
+                "10.0.0.2:8787",
+                "0",
+                "2.5",
+                "1204",
+                "3",
+                "1190..=1200",
+                "1",
+                "yes"
+            ]
+        );
+        let fields: Vec<&str> = lines[2].split_whitespace().collect();
+        assert_eq!(
+            fields,
+            [
+                "cdcdcdcd-cdcd-cdcd-cdcd-cdcdcdcdcdcd",
+                "10.0.0.3:8787",
+                "-",
+                "2.5",
+                "-",
+                "0",
+                "-",
+                "0",
+                "no"
+            ]
+        );
+
+        let mut out = vec![];
+        render(&members, true, 0, &mut out)?;
+        assert_eq!(serde_json::from_slice::<Vec<MemberState>>(&out)?, members);
+
+        let mut out = vec![];
+        render(&[], false, 0, &mut out)?;
+        assert_eq!(String::from_utf8(out)?, "no peers\n");
+
+        Ok(())
+    }
+}
@@ -1,5 +1,15 @@
 pub mod agent;
+pub mod backup;
+pub mod check;
+pub mod config;
 pub mod consul;
+pub mod db;
+pub mod doctor;
+pub mod latency;
+pub mod members;
+pub mod query;
 pub mod reload;
+pub mod sink;
 pub mod tls;
 pub mod tpl;
+pub mod usage;
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, future::Future, io::Write};
+
+use bytes::BytesMut;
+use clap::{Args, ValueEnum};
+use corro_api_types::{ChangeId, ChangeType, QueryEvent, RowId, SqliteValue, Statement};
+use corro_client::CorrosionApiClient;
+use futures::StreamExt;
+use serde_json::json;
+use tokio_util::codec::{Decoder, LinesCodec};
+use tracing::debug;
+
+#[derive(Args, Default)]
+pub struct QueryFlags {
+    /// Print the column names before the rows
+    #[arg(long, default_value = "false")]
+    pub columns: bool,
+    /// Print how long the query took
+    #[arg(long, default_value = "false")]
+    pub timer: bool,
+    /// Keep watching for changes after the initial result set
+    #[arg(long, default_value = "false")]
+    pub watch: bool,
+    /// Read tables as they were right after this db_version was applied,
+    /// within the agent's change history retention
+    #[arg(long, conflicts_with = "watch")]
+    pub as_of_version: Option<i64>,
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    pub format: QueryFormat,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum QueryFormat {
+    /// `|`-separated cells, one row per line
+    #[default]
+    Table,
+    /// One JSON object per line
+    Json,
+}
+
+/// Runs a query, rendering its events to `out`. In watch mode, changes are
+/// rendered until `stop` resolves or the subscription errors out.
+pub async fn run<W: Write, F: Future>(
+    client: &CorrosionApiClient,
+    stmt: &Statement,
+    flags: &QueryFlags,
+    tty: bool,
+    out: &mut W,
+    stop: F,
+) -> eyre::Result<()> {
+    let mut renderer = Renderer::new(out, flags, tty);
+
+    if flags.watch {
+        // the subscription stream resumes from the last seen change on reconnect
+        let mut sub = client.subscribe(stmt, None).await?;
+        debug!("watching query w/ subscription id: {}", sub.id());
+
+        tokio::pin!(stop);
+
+        loop {
+            tokio::select! {
+                evt = sub.next() => match evt {
+                    Some(evt) => renderer.render(evt?)?,
+                    None => break,
+                },
+                _ = &mut stop => {
+                    debug!("stopped watching subscription {}", sub.id());
+                    break;
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    let mut body = match flags.as_of_version {
+        Some(db_version) => client.query_as_of(stmt, db_version).await?,
+        None => client.query(stmt).await?,
+    };
+
+    let mut lines = LinesCodec::new();
+    let mut buf = BytesMut::new();
+
+    loop {
+        while let Some(line) = lines.decode(&mut buf)? {
+            let evt: QueryEvent = serde_json::from_str(&line)?;
+            if let QueryEvent::Change(..) = evt {
+                return Ok(());
+            }
+            renderer.render(evt)?;
+        }
+
+        match body.next().await {
+            Some(bytes) => buf.extend_from_slice(&bytes?),
+            None => break,
+        }
+    }
+
+    Ok(())
+}
+
+struct Renderer<'w, W> {
+    out: &'w mut W,
+    format: QueryFormat,
+    show_columns: bool,
+    timer: bool,
+    tty: bool,
+    columns: Vec<String>,
+    // line index of each rendered row, to re-render in place on a TTY
+    row_lines: HashMap<RowId, usize>,
+    printed_lines: usize,
+}
+
+impl<'w, W: Write> Renderer<'w, W> {
+    fn new(out: &'w mut W, flags: &QueryFlags, tty: bool) -> Self {
+        Self {
+            out,
+            format: flags.format,
+            show_columns: flags.columns,
+            timer: flags.timer,
+            tty,
+            columns: vec![],
+            row_lines: HashMap::new(),
+            printed_lines: 0,
+        }
+    }
+
+    fn render(&mut self, evt: QueryEvent) -> eyre::Result<()> {
+        match evt {
+            QueryEvent::Columns { names: cols, .. } => {
+                if self.show_columns && self.format == QueryFormat::Table {
+                    self.line(&cols.join("|"))?;
+                }
+                self.columns = cols.into_iter().map(|col| col.to_string()).collect();
+            }
+            QueryEvent::Row(rowid, cells) => match self.format {
+                QueryFormat::Table => {
+                    self.row_lines.insert(rowid, self.printed_lines);
+                    self.line(&table_cells(&cells))?;
+                }
+                QueryFormat::Json => {
+                    let obj = json!({"type": "row", "row": self.json_row(&cells)});
+                    self.line(&obj.to_string())?;
+                }
+            },
+            QueryEvent::EndOfQuery { time, .. } => {
+                if self.timer {
+                    match self.format {
+                        QueryFormat::Table => self.line(&format!("time: {time}s"))?,
+                        QueryFormat::Json => {
+                            self.line(&json!({"type": "eoq", "time": time}).to_string())?
+                        }
+                    }
+                }
+            }
+            QueryEvent::Change(change_type, rowid, cells, change_id) => {
+                self.render_change(change_type, rowid, cells, change_id)?
+            }
+            QueryEvent::ChangeBatch(changes) => {
+                for (change_type, rowid, cells, change_id) in changes {
+                    self.render_change(change_type, rowid, cells, change_id)?
+                }
+            }
+            QueryEvent::Rebound { change_id } => {
+                // a fresh snapshot follows, rendered rows can't be updated in place anymore
+                self.row_lines.clear();
+                match self.format {
+                    QueryFormat::Table => self.line(&format!("rebound|{change_id}"))?,
+                    QueryFormat::Json => {
+                        self.line(&json!({"type": "rebound", "change_id": change_id}).to_string())?
+                    }
+                }
+            }
+            // stands in for a change the subscription left out, nothing to render
+            QueryEvent::Skipped { .. } => {}
+            // only sent when asked for
+            QueryEvent::Hello { .. } | QueryEvent::Origin(_) | QueryEvent::NextResultSet { .. } => {
+            }
+            QueryEvent::Rebootstrapped(gap) => {
+                // like a rebind, a fresh snapshot follows
+                self.row_lines.clear();
+                match self.format {
+                    QueryFormat::Table => self.line(&format!(
+                        "rebootstrapped|{}|{}",
+                        gap.requested, gap.earliest_available
+                    ))?,
+                    QueryFormat::Json => self.line(
+                        &json!({
+                            "type": "rebootstrapped",
+                            "requested": gap.requested,
+                            "earliest_available": gap.earliest_available
+                        })
+                        .to_string(),
+                    )?,
+                }
+            }
+            QueryEvent::Error(e) => {
+                eyre::bail!("{e}");
+            }
+        }
+        Ok(())
+    }
+
+    fn render_change(
+        &mut self,
+        change_type: ChangeType,
+        rowid: RowId,
+        cells: Vec<SqliteValue>,
+        change_id: ChangeId,
+    ) -> eyre::Result<()> {
+        match self.format {
+            QueryFormat::Json => {
+                let obj = json!({
+                    "type": change_type,
+                    "change_id": change_id,
+                    "row": self.json_row(&cells),
+                });
+                self.line(&obj.to_string())
+            }
+            QueryFormat::Table if self.tty => {
+                let rendered = table_cells(&cells);
+                match (change_type, self.row_lines.get(&rowid).copied()) {
+                    (ChangeType::Delete, Some(idx)) => {
+                        self.row_lines.remove(&rowid);
+                        // strike through deleted rows
+                        self.rewrite_line(idx, &format!("\x1b[9m{rendered}\x1b[0m"))
+                    }
+                    (ChangeType::Delete, None) => Ok(()),
+                    (_, Some(idx)) => self.rewrite_line(idx, &rendered),
+                    (_, None) => {
+                        self.row_lines.insert(rowid, self.printed_lines);
+                        self.line(&rendered)
+                    }
+                }
+            }
+            QueryFormat::Table => {
+                let change_type = match change_type {
+                    ChangeType::Insert => "insert",
+                    ChangeType::Update => "update",
+                    ChangeType::Delete => "delete",
+                };
+                self.line(&format!("{change_type}|{}", table_cells(&cells)))
+            }
+        }
+    }
+
+    fn json_row(&self, cells: &[SqliteValue]) -> serde_json::Map<String, serde_json::Value> {
+        self.columns
+            .iter()
+            .cloned()
+            .zip(cells.iter().map(|v| json!(v)))
+            .collect()
+    }
+
+    fn line(&mut self, s: &str) -> eyre::Result<()> {
+        writeln!(self.out, "{s}")?;
+        self.out.flush()?;
+        self.printed_lines += 1;
+        Ok(())
+    }
+
+    /// Moves the cursor up to a previously printed line, replaces it and
+    /// moves back down.
+    fn rewrite_line(&mut self, idx: usize, s: &str) -> eyre::Result<()> {
+        let up = self.printed_lines - idx;
+        write!(self.out, "\x1b[{up}A\r\x1b[2K{s}\x1b[{up}B\r")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// blobs are written out whole, unlike w/ `Display`
+fn table_cells(cells: &[SqliteValue]) -> String {
+    let mut out = String::new();
+    for (i, v) in cells.iter().enumerate() {
+        if i > 0 {
+            out.push('|');
+        }
+        // writing to a `String` can't fail
+        _ = v.fmt_full(&mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+    use tokio::{sync::oneshot, time::sleep};
+    use tripwire::Tripwire;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn watch_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let client = CorrosionApiClient::new(ta.agent.api_addr());
+
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+        let watch = tokio::spawn({
+            let client = client.clone();
+            async move {
+                let flags = QueryFlags {
+                    watch: true,
+                    format: QueryFormat::Json,
+                    ..Default::default()
+                };
+                let mut out = vec![];
+                run(
+                    &client,
+                    &Statement::Simple("SELECT id, text FROM tests".into()),
+                    &flags,
+                    false,
+                    &mut out,
+                    stop_rx,
+                )
+                .await?;
+                Ok::<_, eyre::Report>(String::from_utf8(out)?)
+            }
+        });
+
+        sleep(Duration::from_secs(1)).await;
+
+        client
+            .execute(&[Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                vec![1i64.into(), "hello".into()],
+            )])
+            .await?;
+        client
+            .execute(&[Statement::WithParams(
+                "DELETE FROM tests WHERE id = ?".into(),
+                vec![1i64.into()],
+            )])
+            .await?;
+
+        sleep(Duration::from_secs(1)).await;
+
+        stop_tx.send(()).ok();
+
+        let out = watch.await??;
+        let events = out
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<serde_json::Value>, _>>()?;
+
+        assert_eq!(
+            events,
+            vec![
+                json!({"type": "insert", "change_id": 1, "row": {"id": 1, "text": "hello"}}),
+                json!({"type": "delete", "change_id": 2, "row": {"id": 1, "text": "hello"}}),
+            ]
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
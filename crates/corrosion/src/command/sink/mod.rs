@@ -0,0 +1,580 @@
+//! Exports a subscription's changes to external systems.
+//!
+//! Each configured sink subscribes to its query and delivers every change,
+//! in order, as a JSON object. Progress is persisted after each delivery so
+//! a restarted sink resumes from the last delivered change (at-least-once).
+//! When a change can't be delivered within the configured retries, the sink
+//! is paused (its subscription stream dropped, nothing buffered) and resumed
+//! from its watermark later.
+
+#[cfg(feature = "nats")]
+mod nats;
+mod webhook;
+
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use corro_api_types::{ChangeId, ChangeType, QueryEvent, SqliteValue, Statement};
+use corro_client::{pool::LocalPool, sub::SubscriptionStream, CorrosionClient};
+use corro_types::config::{SinkConfig, SinkTarget};
+use futures::StreamExt;
+use hyper::StatusCode;
+use metrics::{gauge, increment_counter};
+use rusqlite::OptionalExtension;
+use serde::Serialize;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+use tripwire::Tripwire;
+use uuid::Uuid;
+
+use self::webhook::WebhookSink;
+
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Runs all `sinks` until interrupted
+pub async fn run<P: AsRef<Path>>(
+    sinks: &[SinkConfig],
+    api_addr: SocketAddr,
+    db_path: P,
+) -> eyre::Result<()> {
+    for (i, sink) in sinks.iter().enumerate() {
+        if sinks[..i].iter().any(|other| other.name == sink.name) {
+            eyre::bail!("duplicate sink name: '{}'", sink.name);
+        }
+    }
+
+    let targets = sinks
+        .iter()
+        .map(|config| Target::new(&config.target))
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    let (tripwire, tripwire_worker) = Tripwire::new_signals();
+
+    let client = CorrosionClient::new(api_addr, db_path);
+    let store = WatermarkStore::new(&client).await?;
+
+    let running = sinks
+        .iter()
+        .zip(targets)
+        .map(|(config, target)| run_sink(&client, &store, config, target, tripwire.clone()));
+
+    tokio::join!(futures::future::join_all(running), tripwire_worker);
+
+    Ok(())
+}
+
+/// Where a sink left off
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Watermark {
+    query: String,
+    sub_id: Uuid,
+    change_id: ChangeId,
+    columns: Vec<String>,
+}
+
+/// Persists each sink's watermark in a local table of the database
+struct WatermarkStore {
+    pool: LocalPool,
+}
+
+impl WatermarkStore {
+    async fn new(client: &CorrosionClient) -> eyre::Result<Self> {
+        let pool = client.pool().clone();
+
+        pool.get().await?.execute_batch(
+            "CREATE TABLE IF NOT EXISTS __corro_sink_watermarks (
+                name TEXT NOT NULL PRIMARY KEY,
+                query TEXT NOT NULL,
+                sub_id TEXT NOT NULL,
+                change_id INTEGER NOT NULL,
+                columns TEXT NOT NULL
+            );",
+        )?;
+
+        Ok(Self { pool })
+    }
+
+    async fn load(&self, name: &str) -> eyre::Result<Option<Watermark>> {
+        let conn = self.pool.get().await?;
+        let row = conn
+            .query_row(
+                "SELECT query, sub_id, change_id, columns FROM __corro_sink_watermarks WHERE name = ?",
+                [name],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                        row.get::<_, String>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        row.map(|(query, sub_id, change_id, columns)| {
+            Ok(Watermark {
+                query,
+                sub_id: sub_id.parse()?,
+                change_id: ChangeId(change_id),
+                columns: serde_json::from_str(&columns)?,
+            })
+        })
+        .transpose()
+    }
+
+    async fn save(&self, name: &str, watermark: &Watermark) -> eyre::Result<()> {
+        let conn = self.pool.get().await?;
+        conn.execute(
+            "INSERT OR REPLACE INTO __corro_sink_watermarks (name, query, sub_id, change_id, columns) VALUES (?, ?, ?, ?, ?)",
+            rusqlite::params![
+                name,
+                watermark.query,
+                watermark.sub_id.to_string(),
+                watermark.change_id.0,
+                serde_json::to_string(&watermark.columns)?,
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+enum Target {
+    Webhook(WebhookSink),
+    #[cfg(feature = "nats")]
+    Nats(nats::NatsSink),
+}
+
+impl Target {
+    fn new(target: &SinkTarget) -> eyre::Result<Self> {
+        Ok(match target {
+            SinkTarget::Webhook { url, headers } => {
+                Target::Webhook(WebhookSink::new(url, headers)?)
+            }
+            #[cfg(feature = "nats")]
+            SinkTarget::Nats { url, subject } => Target::Nats(nats::NatsSink::new(url, subject)?),
+            #[cfg(not(feature = "nats"))]
+            SinkTarget::Nats { .. } => {
+                eyre::bail!("nats sinks require corrosion to be built with the `nats` feature")
+            }
+        })
+    }
+
+    async fn send(&mut self, payload: &[u8]) -> eyre::Result<()> {
+        match self {
+            Target::Webhook(sink) => sink.send(payload).await,
+            #[cfg(feature = "nats")]
+            Target::Nats(sink) => sink.send(payload).await,
+        }
+    }
+}
+
+/// A change, as delivered to a sink
+#[derive(Serialize)]
+struct ExportedChange<'a> {
+    sink: &'a str,
+    table: &'a str,
+    #[serde(rename = "type")]
+    change_type: ChangeType,
+    change_id: ChangeId,
+    row: serde_json::Map<String, serde_json::Value>,
+}
+
+/// Exports changes until `tripwire` is tripped, pausing whenever the target
+/// is unreachable.
+async fn run_sink(
+    client: &CorrosionClient,
+    store: &WatermarkStore,
+    config: &SinkConfig,
+    mut target: Target,
+    mut tripwire: Tripwire,
+) {
+    loop {
+        gauge!("corro.sink.paused", 0.0, "sink" => config.name.clone());
+
+        let res = tokio::select! {
+            res = export(client, store, config, &mut target) => res,
+            _ = &mut tripwire => {
+                info!("stopping sink '{}'", config.name);
+                return;
+            }
+        };
+
+        match res {
+            Ok(()) => warn!(
+                "subscription of sink '{}' ended, resuming in {}s",
+                config.name, config.pause_secs
+            ),
+            Err(e) => warn!(
+                "sink '{}' paused for {}s: {e}",
+                config.name, config.pause_secs
+            ),
+        }
+        gauge!("corro.sink.paused", 1.0, "sink" => config.name.clone());
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(config.pause_secs)) => {},
+            _ = &mut tripwire => {
+                info!("stopping sink '{}'", config.name);
+                return;
+            }
+        }
+    }
+}
+
+async fn subscribe(
+    client: &CorrosionClient,
+    config: &SinkConfig,
+) -> eyre::Result<(SubscriptionStream, Watermark)> {
+    let stream = client
+        .subscribe(&Statement::Simple(config.query.clone()), None)
+        .await?;
+    debug!("sink '{}' subscribed as {}", config.name, stream.id());
+
+    let watermark = Watermark {
+        query: config.query.clone(),
+        sub_id: stream.id(),
+        change_id: ChangeId(0),
+        columns: vec![],
+    };
+    Ok((stream, watermark))
+}
+
+/// Subscribes (or resumes the persisted subscription) and delivers changes
+/// until the stream ends or a change can't be delivered.
+async fn export(
+    client: &CorrosionClient,
+    store: &WatermarkStore,
+    config: &SinkConfig,
+    target: &mut Target,
+) -> eyre::Result<()> {
+    let watermark = store.load(&config.name).await?.filter(|watermark| {
+        if watermark.query != config.query {
+            warn!("query of sink '{}' changed, starting over", config.name);
+            return false;
+        }
+        true
+    });
+
+    let (mut stream, mut watermark) = match watermark {
+        Some(watermark) => {
+            match client
+                .subscription(watermark.sub_id, Some(watermark.change_id))
+                .await
+            {
+                Ok(stream) => {
+                    info!(
+                        "sink '{}' resuming from change {}",
+                        config.name, watermark.change_id
+                    );
+                    (stream, watermark)
+                }
+                Err(corro_client::Error::Http {
+                    status: StatusCode::NOT_FOUND,
+                    ..
+                }) => {
+                    warn!(
+                        "subscription {} of sink '{}' is gone, changes after {} were not exported",
+                        watermark.sub_id, config.name, watermark.change_id
+                    );
+                    subscribe(client, config).await?
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        None => subscribe(client, config).await?,
+    };
+
+    while let Some(evt) = stream.next().await {
+        match evt? {
+            QueryEvent::Columns { names: cols, .. } => {
+                watermark.columns = cols.into_iter().map(|col| col.to_string()).collect();
+            }
+            // the initial state is not exported, only changes
+            QueryEvent::Row(..) => {}
+            QueryEvent::EndOfQuery { change_id, .. } => {
+                watermark.change_id = change_id.unwrap_or_default();
+                store.save(&config.name, &watermark).await?;
+            }
+            QueryEvent::Change(change_type, _, cells, change_id) => {
+                let payload = serde_json::to_vec(&ExportedChange {
+                    sink: &config.name,
+                    table: &config.table,
+                    change_type,
+                    change_id,
+                    row: json_row(&watermark.columns, cells),
+                })?;
+
+                deliver(config, target, &payload).await?;
+                increment_counter!("corro.sink.delivered", "sink" => config.name.clone());
+
+                watermark.change_id = change_id;
+                store.save(&config.name, &watermark).await?;
+            }
+            // like the initial state, the new binding's snapshot is not exported
+            QueryEvent::Rebound { change_id } => {
+                watermark.change_id = change_id;
+            }
+            QueryEvent::Skipped { change_id } => {
+                watermark.change_id = change_id;
+            }
+            // sinks don't ask for origins, result sets are for multi-statement queries
+            QueryEvent::Hello { .. } | QueryEvent::Origin(_) | QueryEvent::NextResultSet { .. } => {}
+            // a fresh snapshot follows, the purged changes can't be exported
+            QueryEvent::Rebootstrapped(gap) => {
+                warn!("sink '{}' missed changes: {gap}", config.name);
+            }
+            // corro-client hands out batched changes one at a time
+            QueryEvent::ChangeBatch(_) => eyre::bail!("unexpected change batch"),
+            QueryEvent::Error(e) => eyre::bail!("subscription error: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends `payload`, retrying w/ exponential backoff up to `max_retries` times
+async fn deliver(config: &SinkConfig, target: &mut Target, payload: &[u8]) -> eyre::Result<()> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 0;
+
+    loop {
+        match target.send(payload).await {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                increment_counter!("corro.sink.delivery.errors", "sink" => config.name.clone());
+                if attempt >= config.max_retries {
+                    return Err(e.wrap_err(format!(
+                        "could not deliver change after {} attempts",
+                        attempt + 1
+                    )));
+                }
+                debug!("sink '{}' delivery failed, retrying: {e}", config.name);
+            }
+        }
+
+        sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, MAX_RETRY_BACKOFF);
+        attempt += 1;
+    }
+}
+
+fn json_row(
+    columns: &[String],
+    cells: Vec<SqliteValue>,
+) -> serde_json::Map<String, serde_json::Value> {
+    columns
+        .iter()
+        .cloned()
+        .zip(cells.into_iter().map(|v| serde_json::json!(v)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use corro_api_types::SqliteParam;
+    use corro_tests::launch_test_agent;
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Request, Response,
+    };
+    use serde_json::json;
+    use spawn::wait_for_all_pending_handles;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    type Captured = Arc<Mutex<Vec<serde_json::Value>>>;
+
+    /// Records the JSON bodies it receives, answers 503 while `fail` is set
+    fn capture_server(fail: Arc<AtomicBool>) -> eyre::Result<(SocketAddr, Captured)> {
+        let captured: Captured = Default::default();
+
+        let make_svc = make_service_fn({
+            let captured = captured.clone();
+            move |_| {
+                let captured = captured.clone();
+                let fail = fail.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                        let captured = captured.clone();
+                        let fail = fail.clone();
+                        async move {
+                            if fail.load(Ordering::SeqCst) {
+                                return Ok::<_, Infallible>(
+                                    Response::builder()
+                                        .status(StatusCode::SERVICE_UNAVAILABLE)
+                                        .body(Body::empty())
+                                        .unwrap(),
+                                );
+                            }
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            captured
+                                .lock()
+                                .unwrap()
+                                .push(serde_json::from_slice(&body).unwrap());
+                            Ok(Response::new(Body::empty()))
+                        }
+                    }))
+                }
+            }
+        });
+
+        let server = hyper::Server::try_bind(&"127.0.0.1:0".parse()?)?.serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Ok((addr, captured))
+    }
+
+    async fn wait_for(captured: &Captured, n: usize) -> eyre::Result<Vec<serde_json::Value>> {
+        timeout(Duration::from_secs(10), async {
+            loop {
+                {
+                    let captured = captured.lock().unwrap();
+                    if captured.len() >= n {
+                        return captured.clone();
+                    }
+                }
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .map_err(|_| eyre::eyre!("timed out waiting for {n} captured changes"))
+    }
+
+    fn change_ids(captured: &[serde_json::Value]) -> Vec<i64> {
+        captured
+            .iter()
+            .map(|v| v["change_id"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn webhook_ordering_and_resume() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let fail = Arc::new(AtomicBool::new(false));
+        let (addr, captured) = capture_server(fail.clone())?;
+
+        let config = SinkConfig {
+            name: "test".into(),
+            query: "SELECT id, text FROM tests".into(),
+            table: "tests".into(),
+            target: SinkTarget::Webhook {
+                url: format!("http://{addr}/"),
+                headers: Default::default(),
+            },
+            max_retries: 2,
+            pause_secs: 1,
+        };
+
+        let start_sink = || {
+            let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+            let config = config.clone();
+            let (sink_tripwire, sink_worker, sink_tx) = Tripwire::new_simple();
+            let handle = tokio::spawn(async move {
+                let target = Target::new(&config.target)?;
+                let store = WatermarkStore::new(&client).await?;
+                run_sink(&client, &store, &config, target, sink_tripwire).await;
+                Ok::<_, eyre::Report>(())
+            });
+            (handle, sink_worker, sink_tx)
+        };
+
+        let client = CorrosionClient::new(ta.agent.api_addr(), ta.agent.db_path());
+        let exec = |query: &'static str, params: Vec<SqliteParam>| {
+            let client = &client;
+            async move {
+                client
+                    .execute(&[Statement::WithParams(query.into(), params)])
+                    .await
+            }
+        };
+
+        let (handle, sink_worker, sink_tx) = start_sink();
+        sleep(Duration::from_secs(1)).await;
+
+        exec(
+            "INSERT INTO tests (id, text) VALUES (?, ?)",
+            vec![1i64.into(), "hello".into()],
+        )
+        .await?;
+        exec(
+            "INSERT INTO tests (id, text) VALUES (?, ?)",
+            vec![2i64.into(), "world".into()],
+        )
+        .await?;
+
+        let got = wait_for(&captured, 2).await?;
+        assert_eq!(change_ids(&got), vec![1, 2]);
+        assert_eq!(
+            got[0],
+            json!({"sink": "test", "table": "tests", "type": "insert", "change_id": 1, "row": {"id": 1, "text": "hello"}})
+        );
+
+        // the sink goes down, changes pile up in the subscription, not in memory
+        fail.store(true, Ordering::SeqCst);
+        exec(
+            "UPDATE tests SET text = ? WHERE id = ?",
+            vec!["hello again".into(), 1i64.into()],
+        )
+        .await?;
+        exec("DELETE FROM tests WHERE id = ?", vec![2i64.into()]).await?;
+
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(captured.lock().unwrap().len(), 2);
+        fail.store(false, Ordering::SeqCst);
+
+        let got = wait_for(&captured, 4).await?;
+        assert_eq!(change_ids(&got), vec![1, 2, 3, 4]);
+        assert_eq!(
+            got[2],
+            json!({"sink": "test", "table": "tests", "type": "update", "change_id": 3, "row": {"id": 1, "text": "hello again"}})
+        );
+        assert_eq!(got[3]["type"], "delete");
+
+        // stop the sink altogether, the watermark survives it
+        sink_tx.send(()).await.ok();
+        sink_worker.await;
+        handle.await??;
+
+        exec(
+            "INSERT INTO tests (id, text) VALUES (?, ?)",
+            vec![3i64.into(), "while stopped".into()],
+        )
+        .await?;
+        sleep(Duration::from_millis(500)).await;
+        assert_eq!(captured.lock().unwrap().len(), 4);
+
+        let (handle, sink_worker, sink_tx) = start_sink();
+
+        let got = wait_for(&captured, 5).await?;
+        assert_eq!(change_ids(&got), vec![1, 2, 3, 4, 5]);
+        assert_eq!(got[4]["row"], json!({"id": 3, "text": "while stopped"}));
+
+        let store = WatermarkStore::new(&client).await?;
+        let watermark = store.load("test").await?.expect("watermark was saved");
+        assert_eq!(watermark.change_id, ChangeId(5));
+        assert_eq!(watermark.columns, vec!["id", "text"]);
+
+        sink_tx.send(()).await.ok();
+        sink_worker.await;
+        handle.await??;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
+    net::TcpStream,
+    time::timeout,
+};
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PORT: u16 = 4222;
+
+/// Publishes each exported change to a NATS subject using the plain text
+/// protocol. A publish is only considered delivered once the server answered
+/// the `PING` following it, which it does after processing the `PUB`.
+pub struct NatsSink {
+    addr: String,
+    subject: String,
+    conn: Option<BufStream<TcpStream>>,
+}
+
+impl NatsSink {
+    pub fn new(url: &str, subject: &str) -> eyre::Result<Self> {
+        let addr = url.strip_prefix("nats://").unwrap_or(url);
+        let addr = if addr.contains(':') {
+            addr.to_owned()
+        } else {
+            format!("{addr}:{DEFAULT_PORT}")
+        };
+
+        if subject.is_empty() || subject.contains(char::is_whitespace) {
+            eyre::bail!("invalid nats subject: '{subject}'");
+        }
+
+        Ok(Self {
+            addr,
+            subject: subject.to_owned(),
+            conn: None,
+        })
+    }
+
+    pub async fn send(&mut self, payload: &[u8]) -> eyre::Result<()> {
+        let res = match timeout(SEND_TIMEOUT, self.publish(payload)).await {
+            Ok(res) => res,
+            Err(e) => Err(e.into()),
+        };
+        if res.is_err() {
+            // start from a clean connection on the next attempt
+            self.conn = None;
+        }
+        res
+    }
+
+    async fn publish(&mut self, payload: &[u8]) -> eyre::Result<()> {
+        if self.conn.is_none() {
+            self.conn = Some(connect(&self.addr).await?);
+        }
+        let conn = self.conn.as_mut().expect("connection was just set");
+
+        conn.write_all(format!("PUB {} {}\r\n", self.subject, payload.len()).as_bytes())
+            .await?;
+        conn.write_all(payload).await?;
+        conn.write_all(b"\r\nPING\r\n").await?;
+        conn.flush().await?;
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if conn.read_line(&mut line).await? == 0 {
+                eyre::bail!("nats server closed the connection");
+            }
+            match line.trim_end() {
+                "PONG" => return Ok(()),
+                "PING" => {
+                    conn.write_all(b"PONG\r\n").await?;
+                    conn.flush().await?;
+                }
+                l if l.starts_with("-ERR") => eyre::bail!("nats server error: {l}"),
+                // +OK, INFO updates
+                _ => {}
+            }
+        }
+    }
+}
+
+async fn connect(addr: &str) -> eyre::Result<BufStream<TcpStream>> {
+    let mut conn = BufStream::new(TcpStream::connect(addr).await?);
+
+    let mut line = String::new();
+    conn.read_line(&mut line).await?;
+    if !line.starts_with("INFO ") {
+        eyre::bail!("unexpected greeting from nats server: {}", line.trim_end());
+    }
+
+    conn.write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")
+        .await?;
+    conn.flush().await?;
+
+    Ok(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_waits_for_pong() -> eyre::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (conn, _) = listener.accept().await?;
+            let mut conn = BufStream::new(conn);
+            conn.write_all(b"INFO {}\r\n").await?;
+            conn.flush().await?;
+
+            let mut lines = vec![];
+            let mut line = String::new();
+            loop {
+                line.clear();
+                conn.read_line(&mut line).await?;
+                let l = line.trim_end().to_owned();
+                if l == "PING" {
+                    conn.write_all(b"+OK\r\nPONG\r\n").await?;
+                    conn.flush().await?;
+                    break;
+                }
+                lines.push(l);
+            }
+            Ok::<_, eyre::Report>(lines)
+        });
+
+        let mut sink = NatsSink::new(&format!("nats://{addr}"), "corro.changes")?;
+        sink.send(b"{\"a\":1}").await?;
+
+        assert_eq!(
+            server.await??,
+            vec![
+                "CONNECT {\"verbose\":false,\"pedantic\":false}".to_string(),
+                "PUB corro.changes 7".to_string(),
+                "{\"a\":1}".to_string(),
+            ]
+        );
+
+        assert!(NatsSink::new("localhost", "has space").is_err());
+        assert_eq!(NatsSink::new("localhost", "x")?.addr, "localhost:4222");
+
+        Ok(())
+    }
+}
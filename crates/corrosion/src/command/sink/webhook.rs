@@ -0,0 +1,61 @@
+use std::{collections::HashMap, time::Duration};
+
+use corro_types::config::Secret;
+use hyper::{
+    client::HttpConnector,
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    Body, Method, Request, Uri,
+};
+use tokio::time::timeout;
+
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POSTs each exported change to an HTTP endpoint, any 2xx response counts
+/// as delivered.
+pub struct WebhookSink {
+    client: hyper::Client<HttpConnector, Body>,
+    uri: Uri,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, headers: &HashMap<String, Secret<String>>) -> eyre::Result<Self> {
+        let uri: Uri = url.parse()?;
+        if uri.scheme_str() != Some("http") {
+            eyre::bail!("only http:// webhook urls are supported, got: {url}");
+        }
+
+        let headers = headers
+            .iter()
+            .map(|(k, v)| Ok((HeaderName::try_from(k)?, HeaderValue::try_from(v.expose())?)))
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            client: hyper::Client::new(),
+            uri,
+            headers,
+        })
+    }
+
+    pub async fn send(&self, payload: &[u8]) -> eyre::Result<()> {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, "application/json");
+        for (k, v) in self.headers.iter() {
+            req = req.header(k, v);
+        }
+
+        let res = timeout(
+            SEND_TIMEOUT,
+            self.client.request(req.body(Body::from(payload.to_vec()))?),
+        )
+        .await??;
+
+        if !res.status().is_success() {
+            eyre::bail!("webhook responded with {}", res.status());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::Write;
+
+use corro_types::api::UsageBucket;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+/// Parses `--since`: a duration back from `now` w/ an `s`, `m`, `h` or `d`
+/// suffix, or a unix timestamp in seconds
+pub fn parse_since(since: &str, now: u64) -> eyre::Result<u64> {
+    let since = since.trim();
+    let unit = match since.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3600,
+        Some('d') => 24 * 3600,
+        _ => {
+            return since.parse().map_err(|_| {
+                eyre::eyre!("invalid --since '{since}', expected e.g. 6h, 7d or a unix timestamp")
+            })
+        }
+    };
+
+    let amount: u64 = since[..since.len() - 1].parse().map_err(|_| {
+        eyre::eyre!("invalid --since '{since}', expected e.g. 6h, 7d or a unix timestamp")
+    })?;
+    Ok(now.saturating_sub(amount.saturating_mul(unit)))
+}
+
+/// Prints the usage of each identity per hour
+pub fn render<W: Write>(buckets: &[UsageBucket], json: bool, out: &mut W) -> eyre::Result<()> {
+    if json {
+        serde_json::to_writer_pretty(&mut *out, buckets)?;
+        writeln!(out)?;
+        return Ok(());
+    }
+
+    if buckets.is_empty() {
+        writeln!(out, "no usage recorded")?;
+        return Ok(());
+    }
+
+    writeln!(
+        out,
+        "{:<20}  {:<40}  {:>10}  {:>10}  {:>12}  {:>10}  {:>10}",
+        "hour", "identity", "rows", "changes", "bytes", "statements", "generated"
+    )?;
+    for bucket in buckets {
+        let hour = OffsetDateTime::from_unix_timestamp(bucket.hour as i64)?.format(&Rfc3339)?;
+        writeln!(
+            out,
+            "{:<20}  {:<40}  {:>10}  {:>10}  {:>12}  {:>10}  {:>10}",
+            hour,
+            bucket.identity,
+            bucket.rows,
+            bucket.changes,
+            bucket.bytes,
+            bucket.statements,
+            bucket.changes_generated
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_since() -> eyre::Result<()> {
+        let now = 1_700_000_000;
+        assert_eq!(parse_since("90s", now)?, now - 90);
+        assert_eq!(parse_since("30m", now)?, now - 1800);
+        assert_eq!(parse_since("6h", now)?, now - 6 * 3600);
+        assert_eq!(parse_since("7d", now)?, now - 7 * 24 * 3600);
+        assert_eq!(parse_since("1690000000", now)?, 1_690_000_000);
+        assert!(parse_since("h", now).is_err());
+        assert!(parse_since("yesterday", now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn renders_usage() -> eyre::Result<()> {
+        let buckets = vec![UsageBucket {
+            identity: "token:00000000000000ab".into(),
+            hour: 1_699_999_200,
+            rows: 0,
+            changes: 0,
+            bytes: 2048,
+            statements: 12,
+            changes_generated: 30,
+        }];
+
+        let mut out = vec![];
+        render(&buckets, false, &mut out)?;
+        let rendered = String::from_utf8(out)?;
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), 2, "{rendered}");
+        assert!(lines[0].starts_with("hour "), "{rendered}");
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(
+            fields,
+            [
+                "2023-11-14T22:00:00Z",
+                "token:00000000000000ab",
+                "0",
+                "0",
+                "2048",
+                "12",
+                "30"
+            ]
+        );
+
+        let mut out = vec![];
+        render(&buckets, true, &mut out)?;
+        assert_eq!(serde_json::from_slice::<Vec<UsageBucket>>(&out)?, buckets);
+
+        let mut out = vec![];
+        render(&[], false, &mut out)?;
+        assert_eq!(String::from_utf8(out)?, "no usage recorded\n");
+
+        Ok(())
+    }
+}
@@ -1,24 +1,25 @@
 use std::{
+    collections::HashMap,
+    io::IsTerminal,
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use admin::AdminConn;
-use bytes::BytesMut;
 use camino::Utf8PathBuf;
 use clap::{Parser, Subcommand};
 use command::{
+    doctor::DoctorFlags,
+    query::QueryFlags,
     tls::{generate_ca, generate_client_cert, generate_server_cert},
     tpl::TemplateFlags,
 };
-use corro_api_types::SqliteParam;
-use corro_client::CorrosionApiClient;
+use corro_api_types::{SqliteParam, SqliteValue};
+use corro_client::{CorrosionApiClient, CorrosionClient};
 use corro_types::{
-    api::{ExecResult, QueryEvent, Statement},
-    config::{default_admin_path, Config, ConfigError, LogFormat, OtelConfig},
+    api::{ExecResult, MigrateRequest, MigrateResponse, Statement},
+    config::{default_admin_path, AuthzConfig, Config, ConfigError, LogFormat, OtelConfig},
 };
-use futures::StreamExt;
 use once_cell::sync::OnceCell;
 use opentelemetry::{
     global,
@@ -30,9 +31,7 @@ use opentelemetry::{
     KeyValue,
 };
 use opentelemetry_otlp::WithExportConfig;
-use rusqlite::{Connection, OptionalExtension};
-use tokio_util::codec::{Decoder, LinesCodec};
-use tracing::{debug, error, info, warn};
+use tracing::{error, info};
 use tracing_subscriber::{
     fmt::format::Format, prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt,
     EnvFilter,
@@ -151,127 +150,34 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
     match &cli.command {
         Command::Agent => command::agent::run(cli.config()?, &cli.config_path).await?,
 
-        Command::Backup { path } => {
-            let db_path = cli.db_path()?;
-
-            {
-                let conn = Connection::open(&db_path)?;
-                conn.execute("VACUUM INTO ?;", [&path])?;
-            }
-
-            {
-                let path = PathBuf::from(path);
-
-                // make sure parent path exists
-                if let Some(parent) = path.parent() {
-                    _ = tokio::fs::create_dir_all(parent).await;
-                }
-
-                let conn = Connection::open(&path)?;
-
-                let site_id: [u8; 16] = conn.query_row(
-                    "DELETE FROM crsql_site_id WHERE ordinal = 0 RETURNING site_id;",
-                    [],
-                    |row| row.get(0),
-                )?;
-
-                let ordinal: i64 = conn.query_row(
-                    "INSERT INTO crsql_site_id (site_id) VALUES (?) RETURNING ordinal;",
-                    [&site_id],
-                    |row| row.get(0),
-                )?;
-
-                let tables: Vec<String> = conn.prepare("SELECT name FROM sqlite_schema WHERE type = 'table' AND name LIKE '%__crsql_clock'")?.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
-
-                for table in tables {
-                    let n = conn.execute(
-                        &format!("UPDATE \"{table}\" SET site_id = ? WHERE site_id IS NULL"),
-                        [ordinal],
-                    )?;
-                    debug!("updated {n} rows in {table}");
-                }
-
-                // clear __corro_members, this state is per actor
-                conn.execute("DELETE FROM __corro_members;", [])?;
+        Command::Backup { path, output } => {
+            let Some(path) = output.as_ref().or(path.as_ref()) else {
+                eyre::bail!("a path to back up the database to is required");
+            };
 
-                conn.execute_batch(
-                    r#"
-                    PRAGMA journal_mode = WAL; -- so the restore can be done online
-                    PRAGMA wal_checkpoint(TRUNCATE);
-                    "#,
-                )?;
-            }
+            let manifest =
+                command::backup::backup(&cli.admin_path(), &cli.db_path()?, path).await?;
 
-            info!("Successfully cleaned for restoration and backed up database to {path}");
+            info!(
+                "Successfully backed up database to {path} (site id: {}, db_version: {})",
+                manifest.site_id, manifest.db_version
+            );
         }
         Command::Restore {
             path,
+            input,
             self_actor_id,
+            force_new_site,
         } => {
             if AdminConn::connect(cli.admin_path()).await.is_ok() {
                 eyre::bail!("corrosion is currently running, shut it down before restoring!");
             }
 
-            if *self_actor_id {
-                let db_path = match cli.db_path() {
-                    Ok(db_path) => db_path,
-                    Err(_e) => {
-                        eyre::bail!(
-                            "path to current database is required when passing --self-actor-id"
-                        );
-                    }
-                };
-
-                let site_id: [u8; 16] = {
-                    let conn = Connection::open(&db_path)?;
-                    conn.query_row(
-                        "SELECT site_id FROM crsql_site_id WHERE ordinal = 0;",
-                        [],
-                        |row| row.get(0),
-                    )?
-                };
-
-                let conn = Connection::open(path)?;
-
-                let ordinal: Option<i64> = conn
-                    .query_row(
-                        "DELETE FROM crsql_site_id WHERE site_id = ? RETURNING ordinal",
-                        [site_id],
-                        |row| row.get(0),
-                    )
-                    .optional()?;
-                if ordinal.is_none() {
-                    warn!("snapshot database did not know about the current actor id");
-                }
-
-                let inserted = conn.execute(
-                    "INSERT INTO crsql_site_id (ordinal, site_id) VALUES (0, ?)",
-                    [site_id],
-                )?;
-                if inserted != 1 {
-                    eyre::bail!("could not insert old site id into crsql_site_id table");
-                }
-
-                if let Some(ordinal) = ordinal {
-                    let tables: Vec<String> = conn.prepare("SELECT name FROM sqlite_schema WHERE type = 'table' AND name LIKE '%__crsql_clock'")?.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
-
-                    for table in tables {
-                        let n = conn.execute(
-                            &format!("UPDATE \"{table}\" SET site_id = NULL WHERE site_id = ?"),
-                            [ordinal],
-                        )?;
-                        info!("Updated {n} rows in {table}");
-                    }
-                }
-            }
-
-            let restored =
-                sqlite3_restore::restore(path, cli.config()?.db.path, Duration::from_secs(30))?;
+            let Some(path) = input.as_ref().or(path.as_ref()) else {
+                eyre::bail!("a path to restore the database from is required");
+            };
 
-            info!(
-                "successfully restored! old size: {}, new size: {}",
-                restored.old_len, restored.new_len
-            );
+            command::backup::restore(path, &cli.db_path()?, *self_actor_id, *force_new_site)?;
         }
         Command::Cluster(ClusterCommand::MembershipStates) => {
             let mut conn = AdminConn::connect(cli.admin_path()).await?;
@@ -280,90 +186,223 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             ))
             .await?;
         }
+        Command::Cluster(ClusterCommand::Check {
+            tables,
+            peers,
+            diff,
+            max_keys,
+        }) => {
+            let api_addr = cli.api_addr()?;
+            let reference =
+                command::check::Node::new(api_addr.to_string(), cli.admin_api_client()?);
+            let mut nodes = vec![];
+            for peer in peers {
+                let addr = command::check::resolve_peer(peer, api_addr.port()).await?;
+                let mut client = CorrosionApiClient::new(addr);
+                if let Some(token) = cli.admin_token() {
+                    client = client.with_bearer_token(token);
+                }
+                nodes.push(command::check::Node::new(peer.as_str(), client));
+            }
+
+            let report = command::check::run(&reference, &nodes, tables, *diff, *max_keys).await?;
+            command::check::render(&report, cli.json, &mut std::io::stdout().lock())?;
+            if !report.converged() {
+                std::process::exit(1);
+            }
+        }
+        Command::Cluster(ClusterCommand::Latency) => {
+            let latencies = cli.admin_api_client()?.cluster_latency().await?;
+            command::latency::render(&latencies, cli.json, &mut std::io::stdout().lock())?;
+        }
+        Command::Cluster(ClusterCommand::Members) => {
+            let members = cli.admin_api_client()?.cluster_members().await?;
+            let now_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64);
+            command::members::render(&members, cli.json, now_ms, &mut std::io::stdout().lock())?;
+        }
+        Command::Db(DbCommand::Checkpoint) => {
+            let result = cli.admin_api_client()?.checkpoint().await?;
+            command::db::render(&result, cli.json, &mut std::io::stdout().lock())?;
+            if result.busy {
+                std::process::exit(1);
+            }
+        }
+        Command::Config(ConfigCommand::Show { remote }) => {
+            let config = if *remote {
+                cli.admin_api_client()?.config().await?
+            } else {
+                cli.config()?.to_response()?
+            };
+            command::config::render(&config, cli.json, &mut std::io::stdout().lock())?;
+        }
         Command::Consul(cmd) => match cmd {
-            ConsulCommand::Sync => match cli.config()?.consul.as_ref() {
+            ConsulCommand::Sync { record } => {
+                command::consul::sync::run(
+                    &cli.config()?,
+                    &cli.config_path,
+                    cli.api_addr()?,
+                    cli.db_path()?,
+                    record.clone(),
+                )
+                .await?
+            }
+            ConsulCommand::Simulate {
+                recording,
+                speed,
+                node,
+            } => match cli.config()?.consul.as_ref() {
                 Some(consul) => {
-                    command::consul::sync::run(consul, cli.api_addr()?, cli.db_path()?).await?
+                    let report = command::consul::simulate::run(
+                        consul,
+                        cli.api_addr()?,
+                        cli.db_path()?,
+                        recording,
+                        *speed,
+                        node.clone(),
+                    )
+                    .await?;
+                    command::consul::simulate::render(
+                        &report,
+                        cli.json,
+                        &mut std::io::stdout().lock(),
+                    )?;
+                }
+                None => {
+                    error!("missing `consul` block in corrosion config");
+                }
+            },
+            ConsulCommand::Verify { stale_after, fix } => match cli.config()?.consul.as_ref() {
+                Some(consul) => {
+                    let ok = command::consul::verify::run(
+                        consul,
+                        cli.api_addr()?,
+                        cli.db_path()?,
+                        stale_after.map(Duration::from_secs),
+                        *fix,
+                        cli.json,
+                    )
+                    .await?;
+                    if !ok {
+                        std::process::exit(1);
+                    }
                 }
                 None => {
                     error!("missing `consul` block in corrosion config");
                 }
             },
         },
+        Command::Doctor(flags) => {
+            let db_path = cli.db_path()?;
+            let consul = cli.config().ok().and_then(|config| config.consul);
+            let results = command::doctor::run(
+                &cli.admin_api_client()?,
+                &CorrosionClient::new(cli.api_addr()?, &db_path),
+                db_path.as_std_path(),
+                consul.as_ref(),
+                flags,
+            )
+            .await;
+            command::doctor::render(&results, cli.json, &mut std::io::stdout().lock())?;
+            if command::doctor::failed(&results) {
+                std::process::exit(1);
+            }
+        }
+        Command::Sink { name } => {
+            let config = cli.config()?;
+            let sinks: Vec<_> = config
+                .sinks
+                .into_iter()
+                .filter(|sink| name.is_empty() || name.contains(&sink.name))
+                .collect();
+            if sinks.is_empty() {
+                error!("no matching `sinks` in corrosion config");
+            } else {
+                command::sink::run(&sinks, cli.api_addr()?, cli.db_path()?).await?;
+            }
+        }
         Command::Query {
             query,
-            columns: show_columns,
-            timer,
             param,
+            flags,
         } => {
             let stmt = if param.is_empty() {
                 Statement::Simple(query.clone())
             } else {
                 Statement::WithParams(
                     query.clone(),
-                    param.iter().map(|p| SqliteParam::Text(p.into())).collect(),
+                    param
+                        .iter()
+                        .map(|p| parse_param(p))
+                        .collect::<eyre::Result<_>>()?,
                 )
             };
 
-            let mut body = cli.api_client()?.query(&stmt).await?;
-
-            let mut lines = LinesCodec::new();
+            command::query::run(
+                &cli.api_client()?,
+                &stmt,
+                flags,
+                std::io::stdout().is_terminal(),
+                &mut std::io::stdout().lock(),
+                tokio::signal::ctrl_c(),
+            )
+            .await?;
+        }
+        Command::Explain { query, param } => {
+            let stmt = if param.is_empty() {
+                Statement::Simple(query.clone())
+            } else {
+                Statement::WithParams(
+                    query.clone(),
+                    param
+                        .iter()
+                        .map(|p| parse_param(p))
+                        .collect::<eyre::Result<_>>()?,
+                )
+            };
 
-            let mut buf = BytesMut::new();
+            let plan = cli.api_client()?.explain(&stmt).await?;
 
-            loop {
-                buf.extend_from_slice(&body.next().await.unwrap()?);
-                let s = match lines.decode(&mut buf)? {
-                    Some(s) => s,
-                    None => break,
-                };
-                let res: QueryEvent = serde_json::from_str(&s)?;
+            // steps are ordered so parents always come before their children
+            let mut depths: HashMap<i64, usize> = HashMap::new();
+            for step in plan.steps.iter() {
+                let depth = depths.get(&step.parent).map_or(0, |d| d + 1);
+                depths.insert(step.id, depth);
+                println!("{}{}", "  ".repeat(depth), step.detail);
+            }
 
-                match res {
-                    QueryEvent::Columns(cols) => {
-                        if *show_columns {
-                            println!("{}", cols.join("|"));
-                        }
-                    }
-                    QueryEvent::Row(_, cells) => {
-                        println!(
-                            "{}",
-                            cells
-                                .iter()
-                                .map(|v| v.to_string())
-                                .collect::<Vec<_>>()
-                                .join("|")
-                        );
-                    }
-                    QueryEvent::EndOfQuery { time, .. } => {
-                        if *timer {
-                            println!("time: {time}s");
-                        }
-                    }
-                    QueryEvent::Change(_, _, _, _) => {
-                        break;
-                    }
-                    QueryEvent::Error(e) => {
-                        eyre::bail!("{e}");
-                    }
-                }
+            if !plan.large_scans.is_empty() {
+                println!(
+                    "full scan of large table(s): {}",
+                    plan.large_scans.join(", ")
+                );
             }
         }
         Command::Exec {
             query,
             param,
             timer,
+            dry_run,
         } => {
             let stmt = if param.is_empty() {
                 Statement::Simple(query.clone())
             } else {
                 Statement::WithParams(
                     query.clone(),
-                    param.iter().map(|p| SqliteParam::Text(p.into())).collect(),
+                    param
+                        .iter()
+                        .map(|p| parse_param(p))
+                        .collect::<eyre::Result<_>>()?,
                 )
             };
 
-            let res = cli.api_client()?.execute(&[stmt]).await?;
+            let client = cli.api_client()?;
+            let res = if *dry_run {
+                client.execute_dry_run(&[stmt]).await?
+            } else {
+                client.execute(&[stmt]).await?
+            };
 
             for res in res.results {
                 match res {
@@ -376,15 +415,52 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
                             println!("Run Time: real {time}");
                         }
                     }
-                    ExecResult::Error { error } => {
+                    ExecResult::Error { error, .. } => {
                         error!("{error}");
                     }
                 }
             }
+
+            if res.dry_run {
+                if let Some(generated) = res.changes_generated {
+                    info!(
+                        "Would generate {} change(s), {} byte(s)",
+                        generated.changes, generated.bytes
+                    );
+                }
+                info!("Dry run, rolled back");
+            }
         }
         Command::Reload => {
             command::reload::run(cli.api_addr()?, &cli.config()?.db.schema_paths).await?
         }
+        Command::Migrate(MigrateCommand::Apply { path }) => {
+            let req: MigrateRequest = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+            match cli.api_client()?.migrate(&req).await {
+                Ok(res) => print_migration(&req, &res),
+                Err(corro_client::Error::Migration(res)) => {
+                    print_migration(&req, &res);
+                    eyre::bail!(
+                        "migration step '{}' failed: {}",
+                        res.failed.unwrap_or_default(),
+                        res.error.unwrap_or_default()
+                    );
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Command::Migrate(MigrateCommand::Status) => {
+            let applied = cli.api_client()?.applied_migrations().await?;
+            if applied.is_empty() {
+                info!("No migration steps applied");
+            }
+            for step in applied {
+                println!(
+                    "{}\t{}\t{} statement(s)",
+                    step.name, step.applied_at, step.statements
+                );
+            }
+        }
         Command::Sync(SyncCommand::Generate) => {
             let mut conn = AdminConn::connect(cli.admin_path()).await?;
             conn.send_command(corro_admin::Command::Sync(
@@ -397,6 +473,44 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             conn.send_command(corro_admin::Command::Locks { top: *top })
                 .await?;
         }
+        Command::Ops(OpsCommand::List) => {
+            let active = cli.admin_api_client()?.active_transactions().await?;
+            if active.is_empty() {
+                info!("No active transactions");
+            }
+            for tx in active {
+                let origin = tx
+                    .idempotency_key
+                    .clone()
+                    .or_else(|| tx.client_addr.map(|addr| addr.to_string()))
+                    .unwrap_or_else(|| "-".into());
+                let progress = match tx.current_statement {
+                    Some(index) => format!("{}/{}", index + 1, tx.statements),
+                    None => format!("-/{}", tx.statements),
+                };
+                println!(
+                    "{}\t{:.3}s\t{progress}\t{origin}\t{}{}",
+                    tx.id,
+                    tx.elapsed,
+                    if tx.killed { "(killed) " } else { "" },
+                    tx.sql.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Command::Ops(OpsCommand::Kill { id }) => {
+            let killed = cli.admin_api_client()?.kill_transaction(*id).await?;
+            info!(
+                "Killed transaction {id} at statement {}/{}, it's rolling back",
+                killed.current_statement.map_or(0, |index| index + 1),
+                killed.statements
+            );
+        }
+        Command::Usage(UsageCommand::Report { since }) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let since = command::usage::parse_since(since, now)?;
+            let buckets = cli.admin_api_client()?.usage(since).await?;
+            command::usage::render(&buckets, cli.json, &mut std::io::stdout().lock())?;
+        }
         Command::Template { template, flags } => {
             command::tpl::run(cli.api_addr()?, template, flags).await?;
         }
@@ -416,6 +530,18 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
     Ok(())
 }
 
+/// `base64:` and `hex:` prefixed params are bound as blobs, others as text
+fn parse_param(param: &str) -> eyre::Result<SqliteParam> {
+    let blob = if let Some(encoded) = param.strip_prefix("base64:") {
+        SqliteValue::blob_from_base64(encoded)?
+    } else if let Some(encoded) = param.strip_prefix("hex:") {
+        SqliteValue::blob_from_hex(encoded)?
+    } else {
+        return Ok(SqliteParam::Text(param.into()));
+    };
+    Ok(blob.into())
+}
+
 fn main() {
     let cli: Cli = Cli::parse();
 
@@ -451,10 +577,30 @@ struct Cli {
     #[clap(long, global = true)]
     admin_path: Option<Utf8PathBuf>,
 
+    /// Print machine readable output, for the commands supporting it
+    #[clap(long, global = true)]
+    json: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
+// each of the request's steps, w/ what became of it
+fn print_migration(req: &MigrateRequest, res: &MigrateResponse) {
+    for step in req.steps.iter() {
+        let status = if res.applied.contains(&step.name) {
+            "applied"
+        } else if res.skipped.contains(&step.name) {
+            "already applied"
+        } else if res.failed.as_ref() == Some(&step.name) {
+            "failed"
+        } else {
+            "not attempted"
+        };
+        println!("{}\t{status}", step.name);
+    }
+}
+
 impl Cli {
     fn api_client(&self) -> Result<CorrosionApiClient, ConfigError> {
         API_CLIENT
@@ -462,6 +608,24 @@ impl Cli {
             .cloned()
     }
 
+    /// `api_client` authenticating w/ the config's `api.authorization` token,
+    /// if it has one
+    fn admin_api_client(&self) -> Result<CorrosionApiClient, ConfigError> {
+        let client = self.api_client()?;
+        Ok(match self.admin_token() {
+            Some(token) => client.with_bearer_token(token),
+            None => client,
+        })
+    }
+
+    /// The config's `api.authorization` token, if it has one
+    fn admin_token(&self) -> Option<String> {
+        match self.config().ok()?.api.authorization {
+            Some(AuthzConfig::BearerToken(token)) => Some(token.into_inner()),
+            None => None,
+        }
+    }
+
     fn api_addr(&self) -> Result<SocketAddr, ConfigError> {
         Ok(if let Some(api_addr) = self.api_addr {
             api_addr
@@ -505,32 +669,71 @@ enum Command {
 
     /// Backup the Corrosion DB
     Backup {
-        path: String,
+        #[arg(required_unless_present = "output", conflicts_with = "output")]
+        path: Option<Utf8PathBuf>,
+        /// Where to write the snapshot, its manifest is written next to it
+        #[arg(short, long)]
+        output: Option<Utf8PathBuf>,
     },
 
     /// Restore the Corrosion DB from a backup
     Restore {
-        path: PathBuf,
+        #[arg(required_unless_present = "input", conflicts_with = "input")]
+        path: Option<Utf8PathBuf>,
+        /// Snapshot made by `corrosion backup`
+        #[arg(short, long)]
+        input: Option<Utf8PathBuf>,
+        /// Keep the current site id, for snapshots w/o a manifest
         #[arg(long, default_value = "false")]
         self_actor_id: bool,
+        /// Restore a snapshot of another site over the current database, as a new site
+        #[arg(long, default_value = "false", conflicts_with = "self_actor_id")]
+        force_new_site: bool,
     },
 
     /// Cluster interactions
     #[command(subcommand)]
     Cluster(ClusterCommand),
 
+    /// Inspect the config, w/ its tokens and keys redacted
+    #[command(subcommand)]
+    Config(ConfigCommand),
+
     /// Consul interactions
     #[command(subcommand)]
     Consul(ConsulCommand),
 
+    /// Database maintenance
+    #[command(subcommand)]
+    Db(DbCommand),
+
+    /// Check a running agent end-to-end, from its API to its storage
+    Doctor(DoctorFlags),
+
+    /// Export subscription changes to the configured sinks
+    Sink {
+        /// Only run the sinks w/ these names
+        #[arg(long)]
+        name: Vec<String>,
+    },
+
     /// Query data from Corrosion w/ a SQL statement
     Query {
         query: String,
-        #[arg(long, default_value = "false")]
-        columns: bool,
-        #[arg(long, default_value = "false")]
-        timer: bool,
 
+        /// Positional param, bound as a blob w/ a `base64:` or `hex:` prefix
+        #[arg(long)]
+        param: Vec<String>,
+
+        #[command(flatten)]
+        flags: QueryFlags,
+    },
+
+    /// Show the query plan of a SQL statement, flagging full scans of large tables
+    Explain {
+        query: String,
+
+        /// Positional param, bound as a blob w/ a `base64:` or `hex:` prefix
         #[arg(long)]
         param: Vec<String>,
     },
@@ -538,15 +741,24 @@ enum Command {
     /// Execute a SQL statement that mutates the state of Corrosion
     Exec {
         query: String,
+        /// Positional param, bound as a blob w/ a `base64:` or `hex:` prefix
         #[arg(long)]
         param: Vec<String>,
         #[arg(long, default_value = "false")]
         timer: bool,
+        /// Roll the transaction back after running the statement, reporting
+        /// what it would have done
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
     },
 
     /// Reload the config
     Reload,
 
+    /// Apply named migration steps once, or list the ones applied
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+
     /// Sync-related commands
     #[command(subcommand)]
     Sync(SyncCommand),
@@ -555,6 +767,10 @@ enum Command {
         top: usize,
     },
 
+    /// Inspect and kill transactions running on the agent
+    #[command(subcommand)]
+    Ops(OpsCommand),
+
     Template {
         template: Vec<String>,
         #[command(flatten)]
@@ -564,18 +780,114 @@ enum Command {
     /// Tls-related commands
     #[command(subcommand)]
     Tls(TlsCommand),
+
+    /// Usage attributed to subscriptions and clients
+    #[command(subcommand)]
+    Usage(UsageCommand),
 }
 
 #[derive(Subcommand)]
 enum ClusterCommand {
     /// Dumps the current member states
     MembershipStates,
+    /// Compares the digests of tables on peers w/ the local agent's
+    Check {
+        /// Tables to compare, every table of the schema if unset
+        #[arg(long, value_delimiter = ',')]
+        tables: Vec<String>,
+        /// API addresses of the peers, the local agent's port is used for
+        /// hosts given w/o one
+        #[arg(long, value_delimiter = ',', required = true)]
+        peers: Vec<String>,
+        /// Drill down mismatched tables to the rows which diverged
+        #[arg(long, default_value = "false")]
+        diff: bool,
+        /// Diverging rows listed per table and peer
+        #[arg(long, default_value_t = command::check::DEFAULT_MAX_KEYS)]
+        max_keys: usize,
+    },
+    /// Shows how long the local agent took to apply each peer's heartbeats,
+    /// see `gossip.probe`
+    Latency,
+    /// Lists the local agent's peers and how far behind it is on each one's
+    /// changes
+    Members,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Prints the effective config, after defaults and environment overrides
+    Show {
+        /// Show the config the running agent loaded, instead of the file's
+        #[arg(long, default_value = "false")]
+        remote: bool,
+    },
 }
 
 #[derive(Subcommand)]
 enum ConsulCommand {
     /// Synchronizes the local consul agent with Corrosion
-    Sync,
+    Sync {
+        /// Record consul's responses to each tick to this directory, to
+        /// replay them w/ `consul simulate`
+        #[arg(long)]
+        record: Option<Utf8PathBuf>,
+    },
+    /// Replays a recording made by `consul sync --record` against Corrosion
+    Simulate {
+        /// Directory the recording was made to
+        #[arg(long)]
+        recording: Utf8PathBuf,
+        /// How much faster than recorded ticks are replayed
+        #[arg(long, default_value = "1x")]
+        speed: command::consul::simulate::Speed,
+        /// Sync as this node instead of the recorded one
+        #[arg(long)]
+        node: Option<String>,
+    },
+    /// Compares the local consul agent's state with Corrosion's tables
+    Verify {
+        /// Also report rows not updated in this many seconds
+        #[arg(long)]
+        stale_after: Option<u64>,
+        /// Repair the differing services and checks
+        #[arg(long, default_value = "false")]
+        fix: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum OpsCommand {
+    /// Lists transactions running or waiting for the write connection
+    List,
+    /// Interrupts a transaction and rolls it back, requires the admin token
+    Kill { id: u64 },
+}
+
+#[derive(Subcommand)]
+enum MigrateCommand {
+    /// Applies the steps of a JSON file, skipping the ones already applied
+    Apply { path: Utf8PathBuf },
+    /// Lists the steps applied on the agent
+    Status,
+}
+
+#[derive(Subcommand)]
+enum UsageCommand {
+    /// Lists the rows, changes and bytes each subscription and client
+    /// consumed per hour
+    Report {
+        /// How far back to list, e.g. 6h or 7d, or a unix timestamp
+        #[arg(long, default_value = "24h")]
+        since: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum DbCommand {
+    /// Checkpoints the WAL and truncates it, exits w/ 1 if readers kept it
+    /// from being truncated
+    Checkpoint,
 }
 
 #[derive(Subcommand)]